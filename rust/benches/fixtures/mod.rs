@@ -0,0 +1,112 @@
+//! Generators for large stores used by the benchmarks: a history with
+//! thousands of entries and a config whose items have hundreds of choices.
+
+use chrono::{Duration, NaiveDate};
+use image_prompt_generator::history_store::{HistoryEntry, HISTORY_SCHEMA_VERSION};
+use image_prompt_generator::NO_SELECTION;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const HISTORY_ENTRIES: usize = 10_000;
+pub const ITEM_CHOICES: usize = 500;
+pub const ITEMS: usize = 8;
+
+const SUBJECTS: [&str; 6] = [
+    "ロボット",
+    "成人女性（日本人）",
+    "Cyberpunk City",
+    "猫",
+    "sunset glow",
+    "水彩の街並み",
+];
+
+/// An empty directory under the system temp dir, unique to this process.
+pub fn fixture_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ipg_bench_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create fixture dir");
+    dir
+}
+
+/// `count` entries, oldest first, spread ten per day with varied prompts,
+/// tags, ratings and selections so searches and page rendering do real work.
+pub fn history_entries(count: usize) -> Vec<HistoryEntry> {
+    let start = NaiveDate::from_ymd_opt(2025, 1, 1).expect("valid date");
+    (0..count)
+        .map(|index| {
+            let day = start + Duration::days((index / 10) as i64);
+            let subject = SUBJECTS[index % SUBJECTS.len()];
+            let prompt = format!(
+                "[被写体]：{subject}\n[服装]：choice {:03}\n[背景]：背景 {}",
+                index % ITEM_CHOICES,
+                index % 97
+            );
+            HistoryEntry {
+                id: format!("{}_{:06}_0001", day.format("%Y%m%d"), index),
+                ts: format!(
+                    "{} {:02}:{:02}:00",
+                    day.format("%Y-%m-%d"),
+                    index % 24,
+                    index % 60
+                ),
+                prompt,
+                images: Vec::new(),
+                sample: false,
+                locked: index % 50 == 0,
+                tags: if index % 3 == 0 {
+                    vec!["draft".to_string()]
+                } else {
+                    Vec::new()
+                },
+                note: if index % 7 == 0 {
+                    format!("note {index}")
+                } else {
+                    String::new()
+                },
+                rating: (index % 6) as u8,
+                count: 1,
+                selections: [("subject".to_string(), subject.to_string())]
+                    .into_iter()
+                    .collect(),
+                translation: String::new(),
+                score: None,
+                image_metadata: Default::default(),
+            }
+        })
+        .collect()
+}
+
+/// Writes `entries` as the active `history.json` under `dir`.
+pub fn write_history(dir: &Path, entries: &[HistoryEntry]) {
+    let payload = json!({
+        "schema_version": HISTORY_SCHEMA_VERSION,
+        "entries": entries,
+    });
+    fs::write(
+        dir.join("history.json"),
+        serde_json::to_string_pretty(&payload).expect("serialize history"),
+    )
+    .expect("write history.json");
+}
+
+/// Writes a `config.txt` with `items` prompt items of `choices` choices each
+/// and returns its path.
+pub fn write_config(dir: &Path, items: usize, choices: usize) -> PathBuf {
+    let mut text = String::from(
+        "schema_version = 1\n\n[app]\nhistory_max_entries = 10000\n\n[[sections]]\nlabel = \"Prompt\"\nname = \"prompt\"\n",
+    );
+    for item in 0..items {
+        let values: Vec<String> = std::iter::once(NO_SELECTION.to_string())
+            .chain((0..choices).map(|choice| format!("項目{item} choice {choice:03}")))
+            .map(|value| format!("    {value:?},\n"))
+            .collect();
+        text.push_str(&format!(
+            "\n[[sections.items]]\nallow_free_text = true\nchoices = [\n{}]\nkey = \"item{item}\"\nlabel = \"項目{item}\"\ntemplate = \"[項目{item}]：{{value}}\"\n",
+            values.concat()
+        ));
+    }
+    let path = dir.join("config.txt");
+    fs::write(&path, text).expect("write config.txt");
+    path
+}
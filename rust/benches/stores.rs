@@ -0,0 +1,64 @@
+//! Latency of the store operations the UI waits on, against large fixtures.
+//! Run with `cargo bench`; compare against a saved baseline with
+//! `cargo bench -- --save-baseline before` / `--baseline before`.
+
+mod fixtures;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fixtures::{
+    fixture_dir, history_entries, write_config, write_history, HISTORY_ENTRIES, ITEMS, ITEM_CHOICES,
+};
+use image_prompt_generator::config_store::ConfigStore;
+use image_prompt_generator::history_store::{ExportRange, HistoryStore};
+use image_prompt_generator::server::build_ui_snapshot;
+
+fn bench_ui_snapshot(c: &mut Criterion) {
+    let dir = fixture_dir("snapshot");
+    let mut config =
+        ConfigStore::new(write_config(&dir, ITEMS, ITEM_CHOICES)).expect("load config");
+    for item in 0..ITEMS {
+        let selected = format!("項目{item} choice {:03}", ITEM_CHOICES - 1);
+        config
+            .set_item_state("prompt", &format!("item{item}"), &selected, "")
+            .expect("select choice");
+    }
+
+    c.bench_function("ui_snapshot/8x500_choices", |b| {
+        b.iter(|| black_box(build_ui_snapshot(&config)))
+    });
+}
+
+fn bench_history(c: &mut Criterion) {
+    let dir = fixture_dir("history");
+    write_history(&dir, &history_entries(HISTORY_ENTRIES));
+    let store = HistoryStore::new(dir, HISTORY_ENTRIES).expect("open history");
+
+    let mut group = c.benchmark_group("history_10k");
+    group.sample_size(10);
+    group.bench_function("regenerate_html", |b| {
+        b.iter(|| store.regenerate_html(3000).expect("regenerate"))
+    });
+    group.bench_function("search/plain", |b| {
+        b.iter(|| {
+            black_box(
+                store
+                    .search(ExportRange::default(), "choice 123", 200)
+                    .expect("search"),
+            )
+        })
+    });
+    // Romaji queries go through the reading-insensitive fallback.
+    group.bench_function("search/romaji", |b| {
+        b.iter(|| {
+            black_box(
+                store
+                    .search(ExportRange::default(), "robotto", 200)
+                    .expect("search"),
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ui_snapshot, bench_history);
+criterion_main!(benches);
@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+/// Version of the JSON shapes the server speaks. Generated pages send the
+/// version they were built for in `X-IPG-API-Version`; every response carries
+/// the server's.
+pub const API_VERSION: u32 = 2;
+/// Oldest page version still served, through the shims in `downgrade`.
+/// Pages generated before the header existed send none and count as this one.
+pub const MIN_API_VERSION: u32 = 1;
+pub const API_VERSION_HEADER: &str = "x-ipg-api-version";
+
+/// What the server does with a request, given its version header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Negotiated {
+    /// Same version; respond as is.
+    Current,
+    /// An older page; reshape the response with `downgrade`.
+    Legacy(u32),
+    /// A page from a newer app; refuse with a message instead of guessing.
+    Unsupported(u32),
+}
+
+pub fn negotiate(header: Option<&str>) -> Negotiated {
+    let version = header
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(MIN_API_VERSION)
+        .max(MIN_API_VERSION);
+    match version {
+        API_VERSION => Negotiated::Current,
+        v if v > API_VERSION => Negotiated::Unsupported(v),
+        v => Negotiated::Legacy(v),
+    }
+}
+
+/// Whether `downgrade` changes anything for this endpoint, so other responses
+/// can skip buffering their body.
+pub fn needs_downgrade(version: u32, path: &str) -> bool {
+    version < 2 && path == "/history"
+}
+
+/// Reshapes a current response body into the one a `version` page expects.
+pub fn downgrade(version: u32, path: &str, body: &mut Value) {
+    if version < 2 {
+        downgrade_to_v1(path, body);
+    }
+}
+
+/// v2 renamed the search results of `GET /history` from `entries` to `hits`
+/// and added `truncated`.
+fn downgrade_to_v1(path: &str, body: &mut Value) {
+    let Some(object) = body.as_object_mut() else {
+        return;
+    };
+    if path == "/history" {
+        if let Some(hits) = object.remove("hits") {
+            object.insert("entries".to_string(), hits);
+        }
+        object.remove("truncated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{downgrade, needs_downgrade, negotiate, Negotiated, API_VERSION};
+    use serde_json::json;
+
+    #[test]
+    fn negotiates_versions_and_downgrades_search_for_v1_pages() {
+        assert_eq!(
+            negotiate(Some(&API_VERSION.to_string())),
+            Negotiated::Current
+        );
+        assert_eq!(negotiate(None), Negotiated::Legacy(1));
+        assert_eq!(negotiate(Some("garbage")), Negotiated::Legacy(1));
+        assert_eq!(negotiate(Some("0")), Negotiated::Legacy(1));
+        assert_eq!(negotiate(Some("99")), Negotiated::Unsupported(99));
+
+        assert!(needs_downgrade(1, "/history"));
+        assert!(!needs_downgrade(1, "/update"));
+        assert!(!needs_downgrade(API_VERSION, "/history"));
+        let mut body =
+            json!({ "ok": true, "hits": [{ "id": "a" }], "total": 3, "truncated": true });
+        downgrade(1, "/history", &mut body);
+        assert_eq!(
+            body,
+            json!({ "ok": true, "entries": [{ "id": "a" }], "total": 3 })
+        );
+    }
+}
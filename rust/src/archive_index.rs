@@ -0,0 +1,166 @@
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use std::collections::BTreeMap;
+
+use crate::history_store::HistoryEntry;
+
+/// File name of the overview page written next to `History.html`.
+pub const ARCHIVE_INDEX_HTML: &str = "ArchiveIndex.html";
+/// Characters of a prompt shown in the first/last columns.
+const PROMPT_PREVIEW_CHARS: usize = 60;
+
+/// One archived day: how many entries it has and its oldest/newest prompt.
+#[derive(Debug, Clone, Default)]
+struct ArchiveDay {
+    count: usize,
+    page: String,
+    first: (String, String),
+    last: (String, String),
+}
+
+/// Per-day summary of every archive, collected while the archive pages render.
+#[derive(Debug, Default)]
+pub struct ArchiveIndex {
+    days: BTreeMap<String, ArchiveDay>,
+}
+
+impl ArchiveIndex {
+    /// Counts `entry` under `date_key` (`YYYYMMDD`); `page` is the archive page
+    /// that shows it. The day links to the page of its newest entry.
+    pub fn add(&mut self, date_key: &str, page: &str, entry: &HistoryEntry) {
+        let day = self.days.entry(date_key.to_string()).or_default();
+        let id_prompt = (entry.id.clone(), entry.prompt.clone());
+        if day.count == 0 || entry.id < day.first.0 {
+            day.first = id_prompt.clone();
+        }
+        if day.count == 0 || entry.id > day.last.0 {
+            day.last = id_prompt;
+            day.page = page.to_string();
+        }
+        day.count += 1;
+    }
+
+    pub fn to_html(&self) -> String {
+        let total: usize = self.days.values().map(|day| day.count).sum();
+        let body = if self.days.is_empty() {
+            "<p>アーカイブはまだありません。</p>".to_string()
+        } else {
+            let rows = self
+                .days
+                .iter()
+                .rev()
+                .map(|(date_key, day)| {
+                    format!(
+                        "<tr><th><a href=\"{}\">{}</a></th><td class=\"count\">{}</td><td>{}</td><td>{}</td></tr>",
+                        encode_double_quoted_attribute(&day.page),
+                        encode_text(&format_date_key(date_key)),
+                        day.count,
+                        encode_text(&prompt_preview(&day.first.1)),
+                        encode_text(&prompt_preview(&day.last.1))
+                    )
+                })
+                .collect::<String>();
+            format!(
+                "<p>{}日・{total}件</p><table><tr><th>日付</th><th>件数</th><th>最初のプロンプト</th><th>最後のプロンプト</th></tr>{rows}</table>",
+                self.days.len()
+            )
+        };
+
+        format!(
+            r#"<!doctype html>
+<html lang="ja">
+<head>
+  <meta charset="utf-8" />
+  <title>アーカイブ一覧</title>
+  <style>
+    body {{ margin: 24px; font-family: "Yu Gothic UI", sans-serif; font-size: 14px; color: #1e1e1e; background: #f7f5ec; }}
+    table {{ border-collapse: collapse; width: 100%; background: #fff; }}
+    th, td {{ border: 1px solid #1f2a44; padding: 4px 8px; text-align: left; }}
+    th {{ font-weight: 500; white-space: nowrap; }}
+    td.count {{ width: 64px; text-align: right; }}
+    a {{ color: #174c7a; }}
+  </style>
+</head>
+<body>
+  <h1>アーカイブ一覧</h1>
+  {body}
+</body>
+</html>
+"#
+        )
+    }
+}
+
+fn format_date_key(date_key: &str) -> String {
+    if date_key.len() == 8 {
+        format!("{}-{}-{}", &date_key[..4], &date_key[4..6], &date_key[6..])
+    } else {
+        date_key.to_string()
+    }
+}
+
+/// First line of the prompt, cut to `PROMPT_PREVIEW_CHARS`.
+fn prompt_preview(prompt: &str) -> String {
+    let line = prompt.lines().next().unwrap_or_default();
+    if line.chars().count() > PROMPT_PREVIEW_CHARS {
+        let cut: String = line.chars().take(PROMPT_PREVIEW_CHARS).collect();
+        format!("{cut}…")
+    } else {
+        line.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArchiveIndex;
+    use crate::history_store::HistoryEntry;
+
+    fn entry(id: &str, prompt: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: id.to_string(),
+            ts: String::new(),
+            prompt: prompt.to_string(),
+            images: Vec::new(),
+            sample: false,
+            locked: false,
+            tags: Vec::new(),
+            note: String::new(),
+            rating: 0,
+            count: 1,
+            selections: Default::default(),
+            translation: String::new(),
+            score: None,
+            image_metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn summarizes_days_newest_first_with_first_and_last_prompts() {
+        let mut index = ArchiveIndex::default();
+        index.add(
+            "20260102",
+            "History_202601.html",
+            &entry("20260102_120000_0001", "noon"),
+        );
+        index.add(
+            "20260102",
+            "History_202601_p2.html",
+            &entry("20260102_080000_0001", "morning\n<second line>"),
+        );
+        index.add(
+            "20260101",
+            "History_20260101.html",
+            &entry("20260101_090000_0001", &"長".repeat(80)),
+        );
+
+        let html = index.to_html();
+        assert!(html.contains("<p>2日・3件</p>"));
+        assert!(html.contains(
+            "<tr><th><a href=\"History_202601.html\">2026-01-02</a></th><td class=\"count\">2</td><td>morning</td><td>noon</td></tr>"
+        ));
+        assert!(html.find("2026-01-02") < html.find("2026-01-01"));
+        assert!(html.contains(&format!("{}…", "長".repeat(60))));
+        assert!(ArchiveIndex::default()
+            .to_html()
+            .contains("アーカイブはまだありません。"));
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Tries to swap the new file in before giving up. Antivirus scanners and the
+/// search indexer briefly open fresh files on Windows, which fails the swap.
+pub const REPLACE_ATTEMPTS: u32 = 5;
+/// Wait after the first failed swap; doubles with every further attempt.
+const REPLACE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// `history.json.bak` for `history.json`: the content before the last write.
+pub fn backup_path(target: &Path) -> PathBuf {
+    with_suffix(target, ".bak")
+}
+
+/// Writes `payload` to a temp file next to `target`, flushes it to disk and
+/// swaps it in, so `target` always holds either the old or the new content.
+/// With `keep_backup` the old content is copied to `backup_path` first.
+pub fn write_file_atomic(target: &Path, payload: &[u8], keep_backup: bool) -> Result<()> {
+    let tmp_path = with_suffix(target, ".tmp");
+    let mut tmp = File::create(&tmp_path)
+        .with_context(|| format!("failed to create temp file: {}", tmp_path.display()))?;
+    tmp.write_all(payload)
+        .and_then(|_| tmp.sync_all())
+        .with_context(|| format!("failed to write temp file: {}", tmp_path.display()))?;
+    drop(tmp);
+
+    if keep_backup && target.exists() {
+        let backup = backup_path(target);
+        fs::copy(target, &backup)
+            .with_context(|| format!("failed to write backup: {}", backup.display()))?;
+    }
+
+    let mut delay = REPLACE_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match replace_file(&tmp_path, target) {
+            Ok(()) => return Ok(()),
+            Err(_) if attempt < REPLACE_ATTEMPTS => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err).with_context(|| {
+                    format!(
+                        "failed to replace file after {REPLACE_ATTEMPTS} attempts: {}",
+                        target.display()
+                    )
+                });
+            }
+        }
+    }
+}
+
+fn with_suffix(target: &Path, suffix: &str) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    target.with_file_name(format!("{name}{suffix}"))
+}
+
+/// `ReplaceFileW` keeps the attributes and ACL of the file it replaces; a
+/// missing target (first write, or a swap that failed halfway) is a rename.
+#[cfg(target_os = "windows")]
+fn replace_file(source: &Path, target: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{ReplaceFileW, REPLACEFILE_IGNORE_MERGE_ERRORS};
+
+    if !target.exists() {
+        return fs::rename(source, target);
+    }
+    let wide = |path: &Path| -> Vec<u16> {
+        path.as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    };
+    let (target_wide, source_wide) = (wide(target), wide(source));
+    let ok = unsafe {
+        ReplaceFileW(
+            target_wide.as_ptr(),
+            source_wide.as_ptr(),
+            std::ptr::null(),
+            REPLACEFILE_IGNORE_MERGE_ERRORS,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `rename` replaces the target atomically on every other platform.
+#[cfg(not(target_os = "windows"))]
+fn replace_file(source: &Path, target: &Path) -> io::Result<()> {
+    fs::rename(source, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_path, write_file_atomic};
+    use std::fs;
+
+    #[test]
+    fn replaces_content_and_keeps_the_previous_file_as_backup() {
+        let base = std::env::temp_dir().join(format!("ipg_atomic_write_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("create base");
+        let target = base.join("history.json");
+        assert_eq!(backup_path(&target), base.join("history.json.bak"));
+
+        write_file_atomic(&target, b"first", true).expect("first write");
+        assert!(!backup_path(&target).exists());
+        write_file_atomic(&target, b"second", true).expect("second write");
+        assert_eq!(fs::read(&target).expect("read"), b"second");
+        assert_eq!(
+            fs::read(backup_path(&target)).expect("read backup"),
+            b"first"
+        );
+        write_file_atomic(&target, b"third", false).expect("write without backup");
+        assert_eq!(
+            fs::read(backup_path(&target)).expect("read backup"),
+            b"first"
+        );
+        assert!(!base.join("history.json.tmp").exists());
+
+        // A target that cannot be replaced fails after the retries and
+        // leaves no temp file behind.
+        let dir_target = base.join("dir");
+        fs::create_dir_all(dir_target.join("inner")).expect("create dir");
+        assert!(write_file_atomic(&dir_target, b"x", false).is_err());
+        assert!(!base.join("dir.tmp").exists());
+
+        fs::remove_dir_all(base).ok();
+    }
+}
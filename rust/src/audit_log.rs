@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// One line of `audit.log`. Prompts are stored only as hashes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub ts: String,
+    pub who: String,
+    pub action: String,
+    pub history_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+/// Append-only JSON-lines log of history mutations.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn record(
+        &self,
+        action: &str,
+        history_id: &str,
+        before: Option<&str>,
+        after: Option<&str>,
+        detail: &str,
+    ) -> Result<()> {
+        let record = AuditRecord {
+            ts: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            who: "local".to_string(),
+            action: action.to_string(),
+            history_id: history_id.to_string(),
+            before: before.map(prompt_hash),
+            after: after.map(prompt_hash),
+            detail: detail.to_string(),
+        };
+        let line = serde_json::to_string(&record).context("failed to serialize audit record")?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open audit log: {}", self.path.display()))?;
+        writeln!(file, "{line}")
+            .with_context(|| format!("failed to append audit log: {}", self.path.display()))
+    }
+
+    /// Newest records first; lines that fail to parse are skipped.
+    pub fn read_recent(&self, limit: usize) -> Result<Vec<AuditRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read audit log: {}", self.path.display()))?;
+        Ok(text
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .take(limit)
+            .collect())
+    }
+}
+
+/// FNV-1a, so hashes stay comparable across builds and machines.
+pub fn prompt_hash(prompt: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in prompt.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("fnv1a:{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prompt_hash, AuditLog};
+    use std::fs;
+
+    #[test]
+    fn appends_records_and_reads_newest_first() {
+        let base = std::env::temp_dir().join(format!("ipg_audit_{}", std::process::id()));
+        fs::create_dir_all(&base).expect("create base");
+        let log = AuditLog::new(base.join("audit.log"));
+
+        log.record("create", "id1", None, Some("a"), "")
+            .expect("record create");
+        log.record("update", "id1", Some("a"), Some("b"), "")
+            .expect("record update");
+
+        let records = log.read_recent(10).expect("read");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].action, "update");
+        assert_eq!(
+            records[0].before.as_deref(),
+            Some(prompt_hash("a").as_str())
+        );
+        assert_eq!(records[1].after, records[0].before);
+        assert_eq!(log.read_recent(1).expect("read one").len(), 1);
+        assert_eq!(prompt_hash(""), "fnv1a:cbf29ce484222325");
+
+        fs::remove_dir_all(base).ok();
+    }
+}
@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Upper bound for an uploaded backup archive.
+pub const MAX_BACKUP_BYTES: usize = 1024 * 1024 * 1024;
+/// Upper bound for a single unpacked file, so a crafted archive cannot balloon in memory.
+const MAX_UNPACKED_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A file to put into a backup archive, addressed by its `/`-separated name
+/// relative to the history folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Collects every regular file under `dir` as backup entries named
+/// `<prefix>/<relative path>`, sorted by name.
+pub fn collect_dir(dir: &Path, prefix: &str) -> Result<Vec<BackupFile>> {
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        collect_dir_into(dir, prefix, &mut files)?;
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+fn collect_dir_into(dir: &Path, prefix: &str, files: &mut Vec<BackupFile>) -> Result<()> {
+    for item in fs::read_dir(dir).with_context(|| format!("failed to list: {}", dir.display()))? {
+        let path = item?.path();
+        let Some(file_name) = path.file_name().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        let name = format!("{prefix}/{file_name}");
+        if path.is_dir() {
+            collect_dir_into(&path, &name, files)?;
+        } else if path.is_file() {
+            files.push(BackupFile { name, path });
+        }
+    }
+    Ok(())
+}
+
+/// A folder under the system temp dir that is deleted with everything in it
+/// when dropped, for a backup made outside the history lock.
+#[derive(Debug)]
+pub struct StagingDir(PathBuf);
+
+impl StagingDir {
+    pub fn create(label: &str) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ipg_{label}_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create staging dir: {}", dir.display()))?;
+        Ok(Self(dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes `files` into a ZIP archive. Images are already compressed, so they
+/// are stored as-is; everything else (history JSON) is deflated.
+pub fn write_backup_zip<W: Write + Seek>(files: &[BackupFile], writer: W) -> Result<W> {
+    let mut zip = ZipWriter::new(writer);
+    for file in files {
+        let method = if is_compressed_image(&file.name) {
+            CompressionMethod::Stored
+        } else {
+            CompressionMethod::Deflated
+        };
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(fs::metadata(&file.path).map(|m| m.len()).unwrap_or(0) >= u32::MAX as u64);
+        zip.start_file(file.name.as_str(), options)
+            .with_context(|| format!("failed to add to backup: {}", file.name))?;
+        let mut source = File::open(&file.path)
+            .with_context(|| format!("failed to read: {}", file.path.display()))?;
+        io::copy(&mut source, &mut zip)
+            .with_context(|| format!("failed to add to backup: {}", file.name))?;
+    }
+    zip.finish().context("failed to finish backup archive")
+}
+
+/// Contents of a backup archive, validated but not yet applied.
+#[derive(Debug, Default)]
+pub struct BackupContents {
+    /// `(file name, text)` of `history.json` and the `History_YYYYMMDD.json` /
+    /// `History_YYYYMM.json` archives.
+    /// Kept as bytes since an encrypted store backs them up encrypted.
+    pub history_files: Vec<(String, Vec<u8>)>,
+    /// `(path relative to the history folder, bytes)` of files under `images/`.
+    pub images: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Reads a ZIP written by `write_backup_zip`. Entries outside the backup layout
+/// (or with unsafe paths) are rejected rather than skipped, so a wrong file is
+/// reported before anything is written.
+pub fn read_backup_zip(data: &[u8]) -> Result<BackupContents> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).context("file is not a ZIP archive")?;
+    let mut contents = BackupContents::default();
+    for index in 0..archive.len() {
+        let mut file = archive
+            .by_index(index)
+            .context("failed to read ZIP entry")?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let Some(path) = file
+            .enclosed_name()
+            .filter(|path| path.components().all(|c| matches!(c, Component::Normal(_))))
+        else {
+            return Err(anyhow!("unsafe path in backup: {name}"));
+        };
+        if file.size() > MAX_UNPACKED_FILE_BYTES {
+            return Err(anyhow!("file too large in backup: {name}"));
+        }
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("failed to unpack: {name}"))?;
+        if path.starts_with("images") && path.components().count() > 1 {
+            if !is_compressed_image(&name) {
+                return Err(anyhow!("unexpected file in backup: {name}"));
+            }
+            contents.images.push((path, bytes));
+        } else if is_history_json_name(&name) {
+            contents.history_files.push((name, bytes));
+        } else {
+            return Err(anyhow!("unexpected file in backup: {name}"));
+        }
+    }
+
+    if contents.history_files.is_empty() {
+        return Err(anyhow!("backup has no history.json or History_*.json"));
+    }
+    Ok(contents)
+}
+
+fn is_history_json_name(name: &str) -> bool {
+    if name == "history.json" {
+        return true;
+    }
+    name.strip_prefix("History_")
+        .and_then(|v| v.strip_suffix(".json"))
+        .is_some_and(|date_key| {
+            matches!(date_key.len(), 6 | 8) && date_key.chars().all(|ch| ch.is_ascii_digit())
+        })
+}
+
+fn is_compressed_image(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    [".png", ".jpg", ".jpeg", ".webp", ".gif"]
+        .iter()
+        .any(|ext| lower.ends_with(ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_dir, read_backup_zip, write_backup_zip, BackupFile};
+    use std::fs;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn zips_files_under_their_relative_names_and_reads_them_back() {
+        let dir = std::env::temp_dir().join(format!("ipg_backup_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("images/20250101_000000")).expect("fixture dir");
+        fs::write(dir.join("history.json"), "{\"entries\":[]}").expect("history write");
+        fs::write(dir.join("images/20250101_000000/01.png"), b"png").expect("image write");
+
+        let mut files = vec![BackupFile {
+            name: "history.json".to_string(),
+            path: dir.join("history.json"),
+        }];
+        files.extend(collect_dir(&dir.join("images"), "images").expect("collect images"));
+        assert_eq!(files[1].name, "images/20250101_000000/01.png");
+
+        let bytes = write_backup_zip(&files, Cursor::new(Vec::new()))
+            .expect("write zip")
+            .into_inner();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).expect("read zip");
+        assert_eq!(archive.len(), 2);
+        let mut text = String::new();
+        archive
+            .by_name("history.json")
+            .expect("history entry")
+            .read_to_string(&mut text)
+            .expect("read entry");
+        assert_eq!(text, "{\"entries\":[]}");
+        let image = archive
+            .by_name("images/20250101_000000/01.png")
+            .expect("image entry");
+        assert_eq!(image.compression(), zip::CompressionMethod::Stored);
+        drop(image);
+
+        let bytes = archive.into_inner().into_inner();
+        let contents = read_backup_zip(&bytes).expect("read backup");
+        assert_eq!(contents.history_files[0].0, "history.json");
+        assert_eq!(contents.images[0].1, b"png");
+
+        let stray = vec![BackupFile {
+            name: "../evil.json".to_string(),
+            path: dir.join("history.json"),
+        }];
+        let bytes = write_backup_zip(&stray, Cursor::new(Vec::new()))
+            .expect("write zip")
+            .into_inner();
+        assert!(read_backup_zip(&bytes).is_err());
+        assert!(read_backup_zip(b"not a zip").is_err());
+
+        assert!(collect_dir(&dir.join("missing"), "missing")
+            .expect("missing dir")
+            .is_empty());
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveDateTime};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Folder next to `config.txt` holding its backups.
+pub const CONFIG_BACKUPS_DIR: &str = "config_backups";
+/// Backups kept; older ones are deleted when a new one is written.
+pub const CONFIG_BACKUP_LIMIT: usize = 20;
+
+/// Length of the `YYYYMMDD_HHMMSS_mmm` name prefix.
+const STAMP_LEN: usize = 19;
+
+/// A backup under `config_backups/`, named `<YYYYMMDD_HHMMSS_mmm>_<reason>.<ext>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigBackup {
+    pub file: String,
+    /// Local time the backup was written, `YYYY-MM-DD HH:MM:SS`.
+    pub created: String,
+    /// The operation it was written before, such as `remove_choice`.
+    pub reason: String,
+    pub bytes: u64,
+}
+
+/// `config_backups/` in the folder holding `config_path`.
+pub fn backups_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(CONFIG_BACKUPS_DIR)
+}
+
+/// Copies the file at `config_path` into the backups folder before `reason`
+/// changes it, then deletes all but the newest `limit` backups. Nothing is
+/// written when the file matches the newest backup, so repeated operations
+/// do not push older versions out.
+pub fn write_backup(config_path: &Path, reason: &str, limit: usize) -> Result<Option<PathBuf>> {
+    let Ok(current) = fs::read(config_path) else {
+        return Ok(None);
+    };
+    let dir = backups_dir(config_path);
+    let newest = list_backups(config_path)?.into_iter().next();
+    if let Some(newest) = newest {
+        if fs::read(dir.join(&newest.file)).is_ok_and(|bytes| bytes == current) {
+            return Ok(None);
+        }
+    }
+
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create backup folder: {}", dir.display()))?;
+    let ext = config_path
+        .extension()
+        .and_then(|v| v.to_str())
+        .unwrap_or("txt");
+    // Names sort by time, so two backups in the same millisecond wait for the next.
+    let path = loop {
+        let stamp = Local::now().format("%Y%m%d_%H%M%S_%3f").to_string();
+        let path = dir.join(format!("{stamp}_{reason}.{ext}"));
+        if !path.exists() {
+            break path;
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    fs::write(&path, &current)
+        .with_context(|| format!("failed to write config backup: {}", path.display()))?;
+
+    for old in list_backups(config_path)?.iter().skip(limit.max(1)) {
+        fs::remove_file(dir.join(&old.file)).ok();
+    }
+    Ok(Some(path))
+}
+
+/// The backups of `config_path`, newest first.
+pub fn list_backups(config_path: &Path) -> Result<Vec<ConfigBackup>> {
+    let dir = backups_dir(config_path);
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut backups = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(file) = path.file_name().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        let Some((created, reason)) = parse_backup_name(file) else {
+            continue;
+        };
+        backups.push(ConfigBackup {
+            file: file.to_string(),
+            created,
+            reason,
+            bytes: entry.metadata().map(|m| m.len()).unwrap_or(0),
+        });
+    }
+    backups.sort_by(|a, b| b.file.cmp(&a.file));
+    Ok(backups)
+}
+
+/// Path of the backup named `file`, which must be a plain name in the folder.
+pub fn backup_path(config_path: &Path, file: &str) -> Result<PathBuf> {
+    let file = file.trim();
+    if file.contains(['/', '\\']) || parse_backup_name(file).is_none() {
+        return Err(anyhow!("invalid backup name: {file}"));
+    }
+    let path = backups_dir(config_path).join(file);
+    if !path.is_file() {
+        return Err(anyhow!("backup not found: {file}"));
+    }
+    Ok(path)
+}
+
+fn parse_backup_name(file: &str) -> Option<(String, String)> {
+    let stem = Path::new(file).file_stem()?.to_str()?;
+    let stamp = stem.get(..STAMP_LEN)?;
+    let reason = stem.get(STAMP_LEN..)?.strip_prefix('_')?;
+    let created = NaiveDateTime::parse_from_str(stamp, "%Y%m%d_%H%M%S_%3f").ok()?;
+    Some((
+        created.format("%Y-%m-%d %H:%M:%S").to_string(),
+        reason.to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_path, backups_dir, list_backups, write_backup};
+    use std::fs;
+
+    #[test]
+    fn backups_rotate_and_skip_unchanged_content() {
+        let dir = std::env::temp_dir().join(format!("ipg_config_backups_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("fixture dir");
+        let config = dir.join("config.txt");
+
+        assert!(write_backup(&config, "missing", 3)
+            .expect("no file")
+            .is_none());
+        for version in 0..5 {
+            fs::write(&config, format!("version = {version}\n")).expect("write config");
+            assert!(write_backup(&config, "remove_choice", 3)
+                .expect("backup")
+                .is_some());
+        }
+        assert!(
+            write_backup(&config, "remove_choice", 3)
+                .expect("same content")
+                .is_none(),
+            "unchanged content is not backed up again"
+        );
+
+        let backups = list_backups(&config).expect("list");
+        assert_eq!(backups.len(), 3);
+        assert_eq!(backups[0].reason, "remove_choice");
+        let newest = backup_path(&config, &backups[0].file).expect("newest path");
+        assert_eq!(
+            fs::read_to_string(newest).expect("read newest"),
+            "version = 4\n"
+        );
+        assert!(backup_path(&config, "../config.txt").is_err());
+        fs::write(backups_dir(&config).join("notes.txt"), "x").expect("other file");
+        assert_eq!(list_backups(&config).expect("list again").len(), 3);
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use toml::map::Map;
+use toml::Value;
+
+use crate::NO_SELECTION;
+
+/// File format of `config.txt` (`config.json` / `config.yaml`), of
+/// `GET /app/config/export` and of `POST /app/config/import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ExchangeFormat {
+    /// `toml`, `json` or `yaml`; an empty name means TOML.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "toml" | "txt" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            other => Err(anyhow!("unsupported config format: {other}")),
+        }
+    }
+
+    /// By extension: `.json`, `.yaml` / `.yml`, and TOML for anything else
+    /// (`config.txt` included).
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Self::parse(ext).ok())
+            .unwrap_or(Self::Toml)
+    }
+
+    /// JSON when the text starts with `{`, YAML when it starts with `---`,
+    /// TOML otherwise. A line such as `app:` is not enough for YAML, since a
+    /// TOML multi-line string can hold one.
+    pub fn detect(text: &str) -> Self {
+        let text = text.trim_start();
+        if text.starts_with('{') {
+            Self::Json
+        } else if text.starts_with("---") {
+            Self::Yaml
+        } else {
+            Self::Toml
+        }
+    }
+
+    /// By the extension of an uploaded file's `name` when it is a known one,
+    /// otherwise `detect`.
+    pub fn detect_file(name: &str, text: &str) -> Self {
+        Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .filter(|ext| !ext.is_empty())
+            .and_then(|ext| Self::parse(ext).ok())
+            .unwrap_or_else(|| Self::detect(text))
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Toml => "application/toml; charset=utf-8",
+            Self::Json => "application/json; charset=utf-8",
+            Self::Yaml => "application/yaml; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Toml => "toml",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+        }
+    }
+}
+
+/// A whole document written in `format`, as the TOML value the config code
+/// works with.
+pub fn parse_document(text: &str, format: ExchangeFormat) -> Result<Value> {
+    match format {
+        ExchangeFormat::Toml => toml::from_str(text).context("failed to parse TOML"),
+        ExchangeFormat::Json => serde_json::from_str(text).context("failed to parse JSON"),
+        ExchangeFormat::Yaml => serde_norway::from_str(text).context("failed to parse YAML"),
+    }
+}
+
+/// `doc` written in `format`.
+pub fn write_document(doc: &Value, format: ExchangeFormat) -> Result<String> {
+    match format {
+        ExchangeFormat::Toml => toml::to_string_pretty(doc).context("failed to serialize TOML"),
+        ExchangeFormat::Json => serde_json::to_string_pretty(doc)
+            .map(|text| text + "\n")
+            .context("failed to serialize JSON"),
+        ExchangeFormat::Yaml => serde_norway::to_string(doc).context("failed to serialize YAML"),
+    }
+}
+
+/// What `merge_missing` added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MergeSummary {
+    pub sections_added: usize,
+    pub items_added: usize,
+    pub choices_added: usize,
+}
+
+impl MergeSummary {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The `[[sections]]` of a config written in `format`; everything else in it
+/// (`[app]`, `[state]`, ...) is ignored.
+pub fn parse_sections(text: &str, format: ExchangeFormat) -> Result<Vec<Value>> {
+    let doc = parse_document(text, format)?;
+    let sections = doc
+        .get("sections")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    if sections.is_empty() {
+        return Err(anyhow!("no [[sections]] to import"));
+    }
+    Ok(sections)
+}
+
+/// `sections` as a document holding only `[[sections]]`.
+pub fn write_sections(sections: &[Value], format: ExchangeFormat) -> Result<String> {
+    let mut doc = Map::new();
+    doc.insert("sections".to_string(), Value::Array(sections.to_vec()));
+    write_document(&Value::Table(doc), format)
+}
+
+/// Adds what `incoming` has and `sections` lacks: unknown sections and items
+/// are appended whole, and an item both have gains the choices it is
+/// missing. Nothing already in `sections` is changed or removed. Sections
+/// match by `name`, items by `key`; entries without one are skipped.
+pub fn merge_missing(sections: &mut Vec<Value>, incoming: &[Value]) -> MergeSummary {
+    let mut summary = MergeSummary::default();
+    for section in incoming.iter().filter_map(Value::as_table) {
+        let Some(name) = text_field(section, "name") else {
+            continue;
+        };
+        let items: Vec<&Map<String, Value>> = section
+            .get("items")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_table)
+            .filter(|item| text_field(item, "key").is_some())
+            .collect();
+
+        let existing = sections
+            .iter_mut()
+            .filter_map(Value::as_table_mut)
+            .find(|known| text_field(known, "name") == Some(name));
+        let Some(existing) = existing else {
+            let mut added = section.clone();
+            added.insert(
+                "items".to_string(),
+                Value::Array(
+                    items
+                        .iter()
+                        .map(|item| Value::Table((*item).clone()))
+                        .collect(),
+                ),
+            );
+            summary.sections_added += 1;
+            summary.items_added += items.len();
+            sections.push(Value::Table(added));
+            continue;
+        };
+
+        let known_items = existing
+            .entry("items".to_string())
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if !known_items.is_array() {
+            *known_items = Value::Array(Vec::new());
+        }
+        let known_items = known_items
+            .as_array_mut()
+            .expect("items should be array after normalization");
+        for item in items {
+            let key = text_field(item, "key");
+            let known = known_items
+                .iter_mut()
+                .filter_map(Value::as_table_mut)
+                .find(|known| text_field(known, "key") == key);
+            match known {
+                Some(known) => summary.choices_added += add_missing_choices(known, item),
+                None => {
+                    known_items.push(Value::Table(item.clone()));
+                    summary.items_added += 1;
+                }
+            }
+        }
+    }
+    summary
+}
+
+fn add_missing_choices(known: &mut Map<String, Value>, item: &Map<String, Value>) -> usize {
+    let incoming: Vec<&str> = item
+        .get("choices")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::trim)
+        .filter(|choice| !choice.is_empty() && *choice != NO_SELECTION)
+        .collect();
+    let choices = known
+        .entry("choices".to_string())
+        .or_insert_with(|| Value::Array(vec![Value::String(NO_SELECTION.to_string())]));
+    if !choices.is_array() {
+        *choices = Value::Array(vec![Value::String(NO_SELECTION.to_string())]);
+    }
+    let choices = choices
+        .as_array_mut()
+        .expect("choices should be array after normalization");
+
+    let mut added = 0;
+    for choice in incoming {
+        if !choices
+            .iter()
+            .any(|known| known.as_str().map(str::trim) == Some(choice))
+        {
+            choices.push(Value::String(choice.to_string()));
+            added += 1;
+        }
+    }
+    added
+}
+
+fn text_field<'a>(table: &'a Map<String, Value>, field: &str) -> Option<&'a str> {
+    table
+        .get(field)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_missing, parse_sections, write_sections, ExchangeFormat, MergeSummary};
+
+    #[test]
+    fn merge_adds_missing_sections_items_and_choices_only() {
+        let mut sections = parse_sections(
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  label = "被写体"
+  choices = ["指定なし", "cat"]
+"#,
+            ExchangeFormat::Toml,
+        )
+        .expect("parse local");
+        let incoming = parse_sections(
+            r#"{"sections": [
+                {"name": "prompt", "items": [
+                    {"key": "subject", "label": "subject", "choices": ["dog", " cat ", "指定なし"]},
+                    {"key": "style", "choices": ["oil"]},
+                    {"label": "no key"}
+                ]},
+                {"name": "negative", "items": [{"key": "avoid", "choices": ["blur"]}]}
+            ]}"#,
+            ExchangeFormat::Json,
+        )
+        .expect("parse incoming");
+
+        let summary = merge_missing(&mut sections, &incoming);
+        assert_eq!(
+            summary,
+            MergeSummary {
+                sections_added: 1,
+                items_added: 2,
+                choices_added: 1,
+            }
+        );
+        assert!(merge_missing(&mut sections, &incoming).is_empty());
+
+        let text = write_sections(&sections, ExchangeFormat::Toml).expect("write");
+        let written = parse_sections(&text, ExchangeFormat::Toml).expect("reparse");
+        let subject = &written[0]["items"][0];
+        assert_eq!(subject["label"].as_str(), Some("被写体"));
+        assert_eq!(
+            subject["choices"].as_array().map(Vec::len),
+            Some(3),
+            "dog is added after the existing choices"
+        );
+        assert_eq!(written[0]["items"].as_array().map(Vec::len), Some(2));
+        assert_eq!(written[1]["name"].as_str(), Some("negative"));
+        assert!(parse_sections("[app]\nport = 1\n", ExchangeFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn yaml_is_detected_from_the_file_name_or_a_leading_marker_only() {
+        let toml = r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "memo"
+  template = """
+app: {value}
+sections: none
+"""
+"#;
+        assert_eq!(ExchangeFormat::detect(toml), ExchangeFormat::Toml);
+        let sections = parse_sections(toml, ExchangeFormat::detect(toml)).expect("parse as TOML");
+        assert_eq!(sections.len(), 1);
+        assert_eq!(
+            ExchangeFormat::detect("---\nsections: []\n"),
+            ExchangeFormat::Yaml
+        );
+        assert_eq!(
+            ExchangeFormat::detect(" {\"sections\": []}"),
+            ExchangeFormat::Json
+        );
+        assert_eq!(
+            ExchangeFormat::detect_file("team.yml", "sections:\n  - name: prompt\n"),
+            ExchangeFormat::Yaml
+        );
+        assert_eq!(
+            ExchangeFormat::detect_file("team.txt", "---"),
+            ExchangeFormat::Toml
+        );
+        assert_eq!(ExchangeFormat::detect_file("", "---"), ExchangeFormat::Yaml);
+        assert_eq!(
+            ExchangeFormat::detect_file("README", "{}"),
+            ExchangeFormat::Json
+        );
+    }
+}
@@ -0,0 +1,705 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::map::Map;
+use toml::Value;
+
+use crate::config_exchange::{parse_document, ExchangeFormat};
+
+/// Root key naming the shared base file, relative to the including file.
+pub const INCLUDE_KEY: &str = "include";
+/// Item key listing base choices hidden by the local layer.
+const REMOVED_CHOICES_KEY: &str = "removed_choices";
+/// Item key with the choice order, written when base choices were moved.
+const CHOICE_ORDER_KEY: &str = "choice_order";
+/// Section key listing base items hidden by the local layer.
+const REMOVED_ITEMS_KEY: &str = "removed_items";
+/// Section key with the item order, written when base items were moved.
+const ITEM_ORDER_KEY: &str = "item_order";
+/// Root key listing base sections hidden by the local layer.
+const REMOVED_SECTIONS_KEY: &str = "removed_sections";
+/// Root key with the section order, written when base sections were moved.
+const SECTION_ORDER_KEY: &str = "section_order";
+/// Root key listing base `[presets]` deleted in the local layer.
+const REMOVED_PRESETS_KEY: &str = "removed_presets";
+/// Root key naming arrays such as `housekeeping` that the local layer
+/// replaces as a whole, because base entries were edited, moved or removed.
+const REPLACED_ARRAYS_KEY: &str = "replaced_arrays";
+const ROOT_LAYER_KEYS: [&str; 4] = [
+    REMOVED_SECTIONS_KEY,
+    SECTION_ORDER_KEY,
+    REMOVED_PRESETS_KEY,
+    REPLACED_ARRAYS_KEY,
+];
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Reads the base chain named by `doc`'s `include` (bases may include further
+/// bases) and returns it merged, or `None` when `doc` has no include.
+pub fn load_base(doc: &Value, doc_path: &Path) -> Result<Option<(PathBuf, Value)>> {
+    load_base_at_depth(doc, doc_path, 0)
+}
+
+fn load_base_at_depth(
+    doc: &Value,
+    doc_path: &Path,
+    depth: usize,
+) -> Result<Option<(PathBuf, Value)>> {
+    let Some(include) = doc
+        .get(INCLUDE_KEY)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    else {
+        return Ok(None);
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(anyhow!(
+            "config include chain is too deep (circular include?)"
+        ));
+    }
+
+    let base_path = doc_path
+        .parent()
+        .map(|dir| dir.join(include))
+        .unwrap_or_else(|| PathBuf::from(include));
+    let text = fs::read_to_string(&base_path)
+        .with_context(|| format!("failed to read base config: {}", base_path.display()))?;
+    let base = parse_document(&text, ExchangeFormat::from_path(&base_path))
+        .with_context(|| format!("invalid base config: {}", base_path.display()))?;
+
+    let merged = match load_base_at_depth(&base, &base_path, depth + 1)? {
+        Some((_, parent)) => merge_layers(&parent, &base),
+        None => base,
+    };
+    Ok(Some((base_path, merged)))
+}
+
+/// Layers `local` over `base`. Tables merge key by key with local values winning;
+/// `[[sections]]` merge by section `name` and item `key`, where item choices are the
+/// base choices (minus the local `removed_choices`) followed by local additions;
+/// other root arrays such as `[[housekeeping]]` are concatenated unless listed in
+/// `replaced_arrays`. The local `removed_*` lists hide base sections, items and
+/// presets, and `section_order` / `item_order` / `choice_order` put them back in
+/// the order the local layer left them; entries an order does not name follow.
+pub fn merge_layers(base: &Value, local: &Value) -> Value {
+    let (Some(base_root), Some(local_root)) = (base.as_table(), local.as_table()) else {
+        return local.clone();
+    };
+
+    let mut merged = base_root.clone();
+    merged.remove(INCLUDE_KEY);
+    let removed_sections = string_list(local.get(REMOVED_SECTIONS_KEY));
+    if let Some(Value::Array(sections)) = merged.get_mut("sections") {
+        sections.retain(|section| {
+            !name_of(section, "name").is_some_and(|name| removed_sections.iter().any(|r| r == name))
+        });
+    }
+    let removed_presets = string_list(local.get(REMOVED_PRESETS_KEY));
+    if let Some(Value::Table(presets)) = merged.get_mut("presets") {
+        presets.retain(|name, _| !removed_presets.iter().any(|removed| removed == name));
+    }
+    for key in string_list(local.get(REPLACED_ARRAYS_KEY)) {
+        merged.remove(&key);
+    }
+
+    for (key, local_value) in local_root {
+        if ROOT_LAYER_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        let value = match (key.as_str(), merged.get(key)) {
+            ("sections", Some(base_sections)) => merge_sections(base_sections, local_value),
+            (_, Some(Value::Array(base_items))) if local_value.is_array() => {
+                let mut items = base_items.clone();
+                for item in local_value.as_array().into_iter().flatten() {
+                    if !items.contains(item) {
+                        items.push(item.clone());
+                    }
+                }
+                Value::Array(items)
+            }
+            (_, Some(base_value)) => merge_tables(base_value, local_value),
+            (_, None) => local_value.clone(),
+        };
+        merged.insert(key.clone(), value);
+    }
+    if let Some(Value::Array(sections)) = merged.get_mut("sections") {
+        let order = string_list(local.get(SECTION_ORDER_KEY));
+        apply_order(sections, &order, |section| name_of(section, "name"));
+    }
+    Value::Table(merged)
+}
+
+/// The inverse of `merge_layers`: what the local file must contain so that
+/// merging it over `base` reproduces `effective`.
+pub fn local_overrides(effective: &Value, base: &Value) -> Value {
+    let (Some(effective_root), Some(base_root)) = (effective.as_table(), base.as_table()) else {
+        return effective.clone();
+    };
+
+    let mut local = Map::new();
+    let mut replaced = Vec::new();
+    for (key, value) in effective_root {
+        let diff = match (key.as_str(), base_root.get(key)) {
+            (INCLUDE_KEY | "schema_version", _) => Some(value.clone()),
+            ("sections", Some(base_sections)) => section_overrides(value, base_sections),
+            (_, Some(Value::Array(base_items))) => {
+                let items = value.as_array().map(Vec::as_slice).unwrap_or_default();
+                let added: Vec<Value> = items
+                    .iter()
+                    .filter(|item| !base_items.contains(item))
+                    .cloned()
+                    .collect();
+                if base_items.iter().chain(&added).eq(items) {
+                    (!added.is_empty()).then_some(Value::Array(added))
+                } else {
+                    replaced.push(Value::String(key.clone()));
+                    Some(value.clone())
+                }
+            }
+            (_, Some(base_value)) => table_overrides(value, base_value),
+            (_, None) => Some(value.clone()),
+        };
+        if let Some(diff) = diff {
+            local.insert(key.clone(), diff);
+        }
+    }
+    for (key, value) in base_root {
+        if key != "sections" && value.is_array() && !effective_root.contains_key(key) {
+            replaced.push(Value::String(key.clone()));
+            local.insert(key.clone(), Value::Array(Vec::new()));
+        }
+    }
+
+    let sections = |doc: &Value| names(doc.get("sections"), "name");
+    let (effective_sections, base_sections) = (sections(effective), sections(base));
+    if let Some(removed) = missing_names(&effective_sections, &base_sections) {
+        local.insert(REMOVED_SECTIONS_KEY.to_string(), removed);
+    }
+    if let Some(order) = order_override(&effective_sections, &base_sections) {
+        local.insert(SECTION_ORDER_KEY.to_string(), order);
+    }
+    let presets = |doc: &Value| -> Vec<String> {
+        doc.get("presets")
+            .and_then(Value::as_table)
+            .map(|presets| presets.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+    if let Some(removed) = missing_names(&presets(effective), &presets(base)) {
+        local.insert(REMOVED_PRESETS_KEY.to_string(), removed);
+    }
+    if !replaced.is_empty() {
+        local.insert(REPLACED_ARRAYS_KEY.to_string(), Value::Array(replaced));
+    }
+    Value::Table(local)
+}
+
+fn merge_tables(base: &Value, local: &Value) -> Value {
+    match (base.as_table(), local.as_table()) {
+        (Some(base_table), Some(local_table)) => {
+            let mut merged = base_table.clone();
+            for (key, value) in local_table {
+                let value = match merged.get(key) {
+                    Some(base_value) => merge_tables(base_value, value),
+                    None => value.clone(),
+                };
+                merged.insert(key.clone(), value);
+            }
+            Value::Table(merged)
+        }
+        _ => local.clone(),
+    }
+}
+
+fn table_overrides(effective: &Value, base: &Value) -> Option<Value> {
+    if effective == base {
+        return None;
+    }
+    let (Some(effective_table), Some(base_table)) = (effective.as_table(), base.as_table()) else {
+        return Some(effective.clone());
+    };
+    let diff: Map<String, Value> = effective_table
+        .iter()
+        .filter_map(|(key, value)| {
+            let diff = match base_table.get(key) {
+                Some(base_value) => table_overrides(value, base_value)?,
+                None => value.clone(),
+            };
+            Some((key.clone(), diff))
+        })
+        .collect();
+    (!diff.is_empty()).then_some(Value::Table(diff))
+}
+
+fn name_of<'a>(value: &'a Value, field: &str) -> Option<&'a str> {
+    value.get(field).and_then(Value::as_str).map(str::trim)
+}
+
+fn find_by<'a>(values: &'a [Value], field: &str, name: &str) -> Option<&'a Value> {
+    values
+        .iter()
+        .find(|value| name_of(value, field) == Some(name))
+}
+
+/// The `field` of every entry in the array `values`.
+fn names(values: Option<&Value>, field: &str) -> Vec<String> {
+    values
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|value| name_of(value, field))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// The `base` names missing from `effective`, as a `removed_*` list.
+fn missing_names(effective: &[String], base: &[String]) -> Option<Value> {
+    let missing: Vec<Value> = base
+        .iter()
+        .filter(|name| !effective.contains(name))
+        .cloned()
+        .map(Value::String)
+        .collect();
+    (!missing.is_empty()).then_some(Value::Array(missing))
+}
+
+/// `effective` as an `*_order` list, unless merging already yields that
+/// order: the base entries first, then the local additions.
+fn order_override(effective: &[String], base: &[String]) -> Option<Value> {
+    let merged = base
+        .iter()
+        .filter(|name| effective.contains(name))
+        .chain(effective.iter().filter(|name| !base.contains(name)));
+    if merged.eq(effective.iter()) {
+        return None;
+    }
+    Some(Value::Array(
+        effective.iter().cloned().map(Value::String).collect(),
+    ))
+}
+
+/// Moves the entries `order` names to the front in that order; the others
+/// keep their order after them.
+fn apply_order(values: &mut [Value], order: &[String], name: impl Fn(&Value) -> Option<&str>) {
+    if order.is_empty() {
+        return;
+    }
+    values.sort_by_key(|value| {
+        name(value)
+            .and_then(|name| order.iter().position(|wanted| wanted == name))
+            .unwrap_or(usize::MAX)
+    });
+}
+
+fn merge_sections(base: &Value, local: &Value) -> Value {
+    let base_sections = base.as_array().map(Vec::as_slice).unwrap_or_default();
+    let local_sections = local.as_array().map(Vec::as_slice).unwrap_or_default();
+
+    let mut merged: Vec<Value> = base_sections
+        .iter()
+        .map(|base_section| {
+            let local_section = name_of(base_section, "name")
+                .and_then(|name| find_by(local_sections, "name", name));
+            match local_section {
+                Some(local_section) => merge_section(base_section, local_section),
+                None => base_section.clone(),
+            }
+        })
+        .collect();
+    for local_section in local_sections {
+        let known = name_of(local_section, "name")
+            .is_some_and(|name| find_by(base_sections, "name", name).is_some());
+        if !known {
+            merged.push(local_section.clone());
+        }
+    }
+    Value::Array(merged)
+}
+
+fn merge_section(base: &Value, local: &Value) -> Value {
+    let (Some(base_table), Some(local_table)) = (base.as_table(), local.as_table()) else {
+        return local.clone();
+    };
+    let mut merged = base_table.clone();
+    for (key, value) in local_table {
+        if key != "items" && key != REMOVED_ITEMS_KEY && key != ITEM_ORDER_KEY {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    let removed = string_list(local.get(REMOVED_ITEMS_KEY));
+    let base_items: Vec<Value> = base
+        .get("items")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|item| !name_of(item, "key").is_some_and(|key| removed.iter().any(|r| r == key)))
+        .cloned()
+        .collect();
+    let local_items = local
+        .get("items")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut items: Vec<Value> = base_items
+        .iter()
+        .map(|base_item| {
+            let local_item =
+                name_of(base_item, "key").and_then(|key| find_by(local_items, "key", key));
+            match local_item {
+                Some(local_item) => merge_item(base_item, local_item),
+                None => base_item.clone(),
+            }
+        })
+        .collect();
+    for local_item in local_items {
+        let known = name_of(local_item, "key")
+            .is_some_and(|key| find_by(&base_items, "key", key).is_some());
+        if !known {
+            let mut item = local_item.clone();
+            if let Some(table) = item.as_table_mut() {
+                table.remove(REMOVED_CHOICES_KEY);
+                table.remove(CHOICE_ORDER_KEY);
+            }
+            items.push(item);
+        }
+    }
+    let order = string_list(local.get(ITEM_ORDER_KEY));
+    apply_order(&mut items, &order, |item| name_of(item, "key"));
+    merged.insert("items".to_string(), Value::Array(items));
+    Value::Table(merged)
+}
+
+fn merge_item(base: &Value, local: &Value) -> Value {
+    let (Some(base_table), Some(local_table)) = (base.as_table(), local.as_table()) else {
+        return local.clone();
+    };
+    let mut merged = base_table.clone();
+    for (key, value) in local_table {
+        if key != "choices" && key != REMOVED_CHOICES_KEY && key != CHOICE_ORDER_KEY {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    let removed = string_list(local.get(REMOVED_CHOICES_KEY));
+    let mut choices: Vec<Value> = string_list(base.get("choices"))
+        .into_iter()
+        .filter(|choice| !removed.contains(choice))
+        .map(Value::String)
+        .collect();
+    for choice in string_list(local.get("choices")) {
+        let value = Value::String(choice);
+        if !choices.contains(&value) {
+            choices.push(value);
+        }
+    }
+    let order = string_list(local.get(CHOICE_ORDER_KEY));
+    apply_order(&mut choices, &order, Value::as_str);
+    merged.insert("choices".to_string(), Value::Array(choices));
+    Value::Table(merged)
+}
+
+fn section_overrides(effective: &Value, base: &Value) -> Option<Value> {
+    let base_sections = base.as_array().map(Vec::as_slice).unwrap_or_default();
+    let mut sections = Vec::new();
+    for section in effective.as_array().into_iter().flatten() {
+        let base_section =
+            name_of(section, "name").and_then(|name| find_by(base_sections, "name", name));
+        let Some(base_section) = base_section else {
+            sections.push(section.clone());
+            continue;
+        };
+        let (Some(table), Some(base_table)) = (section.as_table(), base_section.as_table()) else {
+            continue;
+        };
+
+        let mut diff = Map::new();
+        for (key, value) in table {
+            if key != "items" && key != "name" && base_table.get(key) != Some(value) {
+                diff.insert(key.clone(), value.clone());
+            }
+        }
+        let base_items = base_section
+            .get("items")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let items: Vec<Value> = section
+            .get("items")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|item| {
+                let base_item =
+                    name_of(item, "key").and_then(|key| find_by(base_items, "key", key));
+                match base_item {
+                    Some(base_item) => item_overrides(item, base_item),
+                    None => Some(item.clone()),
+                }
+            })
+            .collect();
+        if !items.is_empty() {
+            diff.insert("items".to_string(), Value::Array(items));
+        }
+        let effective_keys = names(section.get("items"), "key");
+        let base_keys = names(base_section.get("items"), "key");
+        if let Some(removed) = missing_names(&effective_keys, &base_keys) {
+            diff.insert(REMOVED_ITEMS_KEY.to_string(), removed);
+        }
+        if let Some(order) = order_override(&effective_keys, &base_keys) {
+            diff.insert(ITEM_ORDER_KEY.to_string(), order);
+        }
+        if !diff.is_empty() {
+            diff.insert(
+                "name".to_string(),
+                table
+                    .get("name")
+                    .cloned()
+                    .unwrap_or(Value::String(String::new())),
+            );
+            sections.push(Value::Table(diff));
+        }
+    }
+    (!sections.is_empty()).then_some(Value::Array(sections))
+}
+
+fn item_overrides(effective: &Value, base: &Value) -> Option<Value> {
+    let (Some(table), Some(base_table)) = (effective.as_table(), base.as_table()) else {
+        return Some(effective.clone());
+    };
+    let mut diff = Map::new();
+    for (key, value) in table {
+        if key != "choices" && key != "key" && base_table.get(key) != Some(value) {
+            diff.insert(key.clone(), value.clone());
+        }
+    }
+
+    let choices = string_list(effective.get("choices"));
+    let base_choices = string_list(base.get("choices"));
+    let added: Vec<Value> = choices
+        .iter()
+        .filter(|choice| !base_choices.contains(choice))
+        .cloned()
+        .map(Value::String)
+        .collect();
+    let removed: Vec<Value> = base_choices
+        .iter()
+        .filter(|choice| !choices.contains(choice))
+        .cloned()
+        .map(Value::String)
+        .collect();
+    if !added.is_empty() {
+        diff.insert("choices".to_string(), Value::Array(added));
+    }
+    if !removed.is_empty() {
+        diff.insert(REMOVED_CHOICES_KEY.to_string(), Value::Array(removed));
+    }
+    if let Some(order) = order_override(&choices, &base_choices) {
+        diff.insert(CHOICE_ORDER_KEY.to_string(), order);
+    }
+
+    if diff.is_empty() {
+        return None;
+    }
+    diff.insert(
+        "key".to_string(),
+        table
+            .get("key")
+            .cloned()
+            .unwrap_or(Value::String(String::new())),
+    );
+    Some(Value::Table(diff))
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_overrides, merge_layers};
+    use crate::config_store::ConfigStore;
+    use crate::NO_SELECTION;
+    use std::fs;
+    use toml::Value;
+
+    fn parse(text: &str) -> Value {
+        toml::from_str(text).expect("valid toml")
+    }
+
+    #[test]
+    fn local_layer_merges_over_base_and_round_trips() {
+        let base = parse(
+            r#"
+[app]
+delimiter = ", "
+history_max_entries = 100
+
+[[sections]]
+name = "prompt"
+
+[[sections.items]]
+key = "subject"
+label = "被写体"
+choices = ["指定なし", "robot", "cat"]
+
+[[sections.items]]
+key = "style"
+label = "画風"
+choices = ["指定なし", "watercolor"]
+
+[[sections]]
+name = "negative"
+
+[[sections.items]]
+key = "avoid"
+choices = ["指定なし", "blur"]
+
+[[housekeeping]]
+name = "shared"
+contains = "logo"
+action = "tag"
+tag = "logo"
+
+[presets.shared.prompt]
+subject_selected = "robot"
+"#,
+        );
+        let local = parse(
+            r#"
+include = "base_config.txt"
+
+[app]
+history_max_entries = 50
+
+[[sections]]
+name = "prompt"
+
+[[sections.items]]
+key = "subject"
+choices = ["dragon"]
+removed_choices = ["cat"]
+
+[[sections.items]]
+key = "mood"
+label = "雰囲気"
+choices = ["指定なし", "calm"]
+
+[state.prompt]
+subject_selected = "dragon"
+"#,
+        );
+
+        let merged = merge_layers(&base, &local);
+        assert_eq!(merged["app"]["delimiter"].as_str(), Some(", "));
+        assert_eq!(merged["app"]["history_max_entries"].as_integer(), Some(50));
+        let items = merged["sections"][0]["items"].as_array().expect("items");
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0]["label"].as_str(), Some("被写体"));
+        assert_eq!(
+            items[0]["choices"],
+            Value::Array(
+                ["指定なし", "robot", "dragon"]
+                    .iter()
+                    .map(|v| Value::String(v.to_string()))
+                    .collect()
+            )
+        );
+        assert!(items[0].get("removed_choices").is_none());
+        assert_eq!(items[2]["key"].as_str(), Some("mood"));
+        assert_eq!(merged["housekeeping"].as_array().map(Vec::len), Some(1));
+
+        let overrides = local_overrides(&merged, &base);
+        assert!(overrides["app"].get("delimiter").is_none());
+        assert_eq!(overrides["include"].as_str(), Some("base_config.txt"));
+        let local_items = overrides["sections"][0]["items"].as_array().expect("items");
+        assert_eq!(
+            local_items.len(),
+            2,
+            "unchanged base item stays out of the local file"
+        );
+        assert_eq!(local_items[0]["choices"].as_array().map(Vec::len), Some(1));
+        assert!(local_items[0].get("label").is_none());
+        assert!(overrides.get("housekeeping").is_none());
+        assert_eq!(merge_layers(&base, &overrides), merged);
+
+        // Deleting, moving and editing base content is recorded, not lost.
+        let mut edited = merged.clone();
+        let root = edited.as_table_mut().expect("root");
+        let sections = root["sections"].as_array_mut().expect("sections");
+        sections.retain(|section| section["name"].as_str() != Some("negative"));
+        let items = sections[0]["items"].as_array_mut().expect("items");
+        items.retain(|item| item["key"].as_str() != Some("style"));
+        items.reverse();
+        items[1]["choices"]
+            .as_array_mut()
+            .expect("choices")
+            .swap(1, 2);
+        root["presets"].as_table_mut().expect("presets").clear();
+        root["housekeeping"][0]["tag"] = Value::String("brand".to_string());
+
+        let overrides = local_overrides(&edited, &base);
+        assert_eq!(overrides["removed_sections"][0].as_str(), Some("negative"));
+        assert_eq!(overrides["removed_presets"][0].as_str(), Some("shared"));
+        assert_eq!(
+            overrides["replaced_arrays"][0].as_str(),
+            Some("housekeeping")
+        );
+        let section = &overrides["sections"][0];
+        assert_eq!(section["removed_items"][0].as_str(), Some("style"));
+        assert_eq!(section["item_order"][0].as_str(), Some("mood"));
+        let rebuilt = merge_layers(&base, &overrides);
+        assert_eq!(rebuilt, edited);
+        assert_eq!(rebuilt["housekeeping"].as_array().map(Vec::len), Some(1));
+        assert!(rebuilt.get("removed_sections").is_none());
+
+        // The same through ConfigStore, which writes only the local layer.
+        let dir = std::env::temp_dir().join(format!("ipg_layer_edits_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("fixture dir");
+        let base_text = r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["a", "b", "c"]
+
+  [[sections.items]]
+  key = "style"
+  choices = ["oil"]
+
+[presets.shared.prompt]
+subject_selected = "a"
+"#;
+        fs::write(dir.join("base_config.txt"), base_text).expect("base write");
+        let path = dir.join("config.txt");
+        fs::write(&path, "include = \"base_config.txt\"\n").expect("local write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert!(store
+            .move_choice("prompt", "subject", "c", 1)
+            .expect("move"));
+        store
+            .reorder_items("prompt", &["style".to_string(), "subject".to_string()])
+            .expect("reorder");
+        store.delete_preset("shared").expect("delete preset");
+
+        let reloaded = ConfigStore::new(path).expect("reload store");
+        let items = reloaded.get_items("prompt");
+        assert_eq!(items[0].key, "style");
+        assert_eq!(items[1].choices, vec![NO_SELECTION, "c", "a", "b"]);
+        assert!(reloaded.preset_names().is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("base_config.txt")).expect("base read"),
+            base_text
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -1,12 +1,34 @@
 use anyhow::{anyhow, Context, Result};
 use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 use toml::map::Map;
 use toml::Value;
 
+use crate::config_backups::{backup_path, write_backup, CONFIG_BACKUP_LIMIT};
+use crate::config_exchange::{
+    merge_missing, parse_document, write_document, ExchangeFormat, MergeSummary,
+};
+use crate::config_layers::{load_base, local_overrides, merge_layers};
+use crate::history_store::{ArchiveGrouping, DedupeMode};
+use crate::housekeeping::HousekeepingRule;
+use crate::instance_lock::READ_ONLY_MESSAGE;
+use crate::migration::{backup_before_migration, run_migrations, Migration};
+use crate::renderer::FormatProfile;
 use crate::NO_SELECTION;
 
+pub const CONFIG_SCHEMA_VERSION: u32 = 1;
+/// Accepted in place of `schema_version`; saving writes `schema_version`.
+const CONFIG_VERSION_ALIAS: &str = "config_version";
+
+const CONFIG_MIGRATIONS: [Migration<Value>; 1] = [Migration {
+    from_version: 0,
+    description: "introduce schema_version",
+    apply: introduce_schema_version,
+}];
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ItemConfig {
     pub section_name: String,
@@ -15,42 +37,330 @@ pub struct ItemConfig {
     pub choices: Vec<String>,
     pub allow_free_text: bool,
     pub template: String,
+    /// Output formats this row is rendered in; empty means every format.
+    pub formats: Vec<String>,
+    /// Choices the dice picks from; empty means every choice.
+    pub random_pool: Vec<String>,
+    /// Midjourney parameter the value is passed as in the `midjourney`
+    /// format, like `ar` for `--ar 16:9`; empty for prompt text.
+    pub param: String,
+    /// Collapsible group the main UI shows the row under; empty for none.
+    pub group: String,
 }
 
 impl ItemConfig {
     pub fn item_id(&self) -> String {
         format!("{}:{}", self.section_name, self.key)
     }
+
+    pub fn participates_in(&self, output_format: &str) -> bool {
+        self.formats.is_empty() || self.formats.iter().any(|f| f == output_format)
+    }
+}
+
+/// A `[[sections]]` block, shown as one tab of the main UI.
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionConfig {
+    pub name: String,
+    pub label: String,
+    /// Wraps the section's rendered lines, `{value}`, in the combined prompt.
+    pub template: String,
+    pub items: Vec<ItemConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub kind: &'static str,
+    pub section: String,
+    pub key: String,
+    pub message: String,
+}
+
+/// `[app.startup]`: what to do every time the app starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartupActions {
+    /// Open `History.html` in the browser once the pages are written.
+    pub open_history: bool,
+    /// Start with cleared selections, as if `Reset` had been pressed.
+    pub new_session: bool,
+    /// Check that the translate/vision commands can be found.
+    pub check_integrations: bool,
+    /// Preset applied after `new_session`; empty for none.
+    pub preset: String,
+}
+
+/// `[features]`: subsystems that can be switched off as a whole. New,
+/// experimental ones ship off; the rest default to on as they always were.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Features {
+    /// `translate_command`, `vision_command` and `score_command`; while off
+    /// they read as not set up.
+    pub integrations: bool,
+    /// The HTTPS server for other machines (`history_server_lan_port`).
+    pub lan: bool,
+    /// The statistics dashboard (`Stats.html`).
+    pub analytics: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            integrations: true,
+            lan: false,
+            analytics: true,
+        }
+    }
+}
+
+pub const DEFAULT_OUTPUT_FORMAT: &str = "labeled";
+/// Confirmed free texts kept per item for the input's suggestions.
+pub const FREE_TEXT_HISTORY_LIMIT: usize = 20;
+/// Range of the per-item emphasis weight; 1.0 is no emphasis.
+pub const ITEM_WEIGHT_MIN: f64 = 0.1;
+pub const ITEM_WEIGHT_MAX: f64 = 2.0;
+
+const STATE_KEY_SUFFIXES: [&str; 5] = ["_selected", "_free_text", "_draft", "_weight", "_locked"];
+/// The `[state]` keys a preset captures; drafts and locks are left alone.
+const PRESET_KEY_SUFFIXES: [&str; 3] = ["_selected", "_free_text", "_weight"];
+/// The demo preset added with the onboarding sample history.
+pub const SAMPLE_PRESET_NAME: &str = "サンプル";
+/// Marks a preset as the demo one; overwriting it with `save_preset` drops the mark.
+const SAMPLE_PRESET_MARK: &str = "sample";
+
+/// `[app]` options changed by `update_app_settings`; `None` keeps the current
+/// value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppSettingsUpdate {
+    pub delimiter: Option<String>,
+    pub copy_debounce_sec: Option<f64>,
+    pub history_max_entries: Option<usize>,
+    pub confirm_delete: Option<bool>,
+    pub history_server_port: Option<u16>,
 }
 
 #[derive(Debug)]
 pub struct ConfigStore {
     pub path: PathBuf,
+    /// Effective config: the `include`d base with the local file layered on top.
     doc: Value,
+    /// Normalized base layer; `save` writes only what differs from it.
+    base: Option<Value>,
+    /// While temporary mode is on, the `[state]` table as last written to disk;
+    /// `save` writes this instead of the in-memory selections.
+    saved_state: Option<Value>,
+    /// Set while another instance owns the data dir; `save` refuses changes.
+    read_only: bool,
+    /// The file as this store last read or wrote it, so a reload that did not
+    /// rewrite it is not mistaken for an outside edit.
+    disk_text: RefCell<Option<String>>,
 }
 
 impl ConfigStore {
     pub fn new(path: PathBuf) -> Result<Self> {
+        Self::open(path, false)
+    }
+
+    /// Like `new`; `read_only` opens it in temporary mode and never writes,
+    /// so selections still work in memory while other changes fail to save.
+    pub fn open(path: PathBuf, read_only: bool) -> Result<Self> {
+        let (store, _) = Self::load(path, read_only)?;
+        if !read_only {
+            store.save()?;
+        }
+        Ok(store)
+    }
+
+    /// Reads and normalizes the file without writing it; also returns
+    /// whether a migration upgraded the document.
+    fn load(path: PathBuf, read_only: bool) -> Result<(Self, bool)> {
         if !path.exists() {
             return Err(anyhow!("config file not found: {}", path.display()));
         }
 
         let text = fs::read_to_string(&path)
             .with_context(|| format!("failed to read config: {}", path.display()))?;
-        let doc: Value = toml::from_str(&text)
-            .with_context(|| format!("failed to parse TOML: {}", path.display()))?;
+        let mut doc = parse_document(&text, ExchangeFormat::from_path(&path))
+            .with_context(|| format!("invalid config: {}", path.display()))?;
+
+        let version = config_schema_version(&doc);
+        if version < CONFIG_SCHEMA_VERSION && !read_only {
+            backup_before_migration(&path, version)?;
+        }
+        run_migrations(&mut doc, version, CONFIG_SCHEMA_VERSION, &CONFIG_MIGRATIONS)
+            .with_context(|| format!("failed to migrate config: {}", path.display()))?;
+
+        let base = load_base(&doc, &path)?.map(|(base_path, base)| {
+            let mut layer = Self {
+                path: base_path,
+                doc: base,
+                base: None,
+                saved_state: None,
+                read_only,
+                disk_text: RefCell::new(None),
+            };
+            layer.normalize_doc();
+            layer.doc
+        });
+        let doc = match &base {
+            Some(base) => merge_layers(base, &doc),
+            None => doc,
+        };
 
-        let mut store = Self { path, doc };
+        let mut store = Self {
+            path,
+            doc,
+            base,
+            saved_state: None,
+            read_only,
+            disk_text: RefCell::new(Some(text)),
+        };
         store.normalize_doc();
-        store.save()?;
-        Ok(store)
+        if read_only {
+            store.set_temporary_mode(true);
+        }
+        Ok((store, version < CONFIG_SCHEMA_VERSION))
     }
 
     pub fn save(&self) -> Result<()> {
-        let serialized = toml::to_string_pretty(&self.doc).context("failed to serialize TOML")?;
-        let text = move_app_table_to_top(&serialized);
+        let text = self.file_text()?;
+        // Unchanged content is not rewritten, so temporary-mode selections cause no disk churn.
+        if fs::read_to_string(&self.path).is_ok_and(|current| current == text) {
+            *self.disk_text.borrow_mut() = Some(text);
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(anyhow!(READ_ONLY_MESSAGE));
+        }
+        fs::write(&self.path, &text)
+            .with_context(|| format!("failed to write config: {}", self.path.display()))?;
+        *self.disk_text.borrow_mut() = Some(text);
+        Ok(())
+    }
+
+    /// Whether the file no longer holds what `save` would write, i.e. it was
+    /// edited outside the app. A file that cannot be read right now (an
+    /// editor replacing it) does not count as changed.
+    pub fn changed_on_disk(&self) -> bool {
+        let Ok(current) = fs::read_to_string(&self.path) else {
+            return false;
+        };
+        if self.disk_text.borrow().as_ref() == Some(&current) {
+            return false;
+        }
+        self.file_text().is_ok_and(|text| current != text)
+    }
+
+    /// `config.txt` as `save` writes it.
+    fn file_text(&self) -> Result<String> {
+        let mut doc = self.doc.clone();
+        if let (Some(saved_state), Some(root)) = (&self.saved_state, doc.as_table_mut()) {
+            root.insert("state".to_string(), saved_state.clone());
+        }
+        if let Some(base) = &self.base {
+            doc = local_overrides(&doc, base);
+        }
+        let format = ExchangeFormat::from_path(&self.path);
+        let serialized = write_document(&doc, format)?;
+        Ok(match format {
+            ExchangeFormat::Toml => move_app_table_to_top(&serialized),
+            ExchangeFormat::Json | ExchangeFormat::Yaml => serialized,
+        })
+    }
+
+    /// Re-reads the file after it was edited outside the app. Temporary-mode
+    /// selections, which are not on disk, survive; a file that no longer
+    /// parses leaves the current config untouched.
+    pub fn reload(&mut self) -> Result<()> {
+        // The file is left as edited; only a migration writes it back.
+        let (mut fresh, migrated) = Self::load(self.path.clone(), self.read_only)?;
+        if migrated && !self.read_only {
+            fresh.save()?;
+        }
+        if self.temporary_mode() {
+            fresh.set_temporary_mode(true);
+            fresh
+                .root_table_mut()
+                .insert("state".to_string(), self.current_state());
+        }
+        *self = fresh;
+        Ok(())
+    }
+
+    /// Replaces `config.txt` with the backup named `file` (see
+    /// `config_backups`), backing up the current file first so the restore
+    /// can be undone. Temporary mode stays on, with the restored selections.
+    pub fn restore_backup(&mut self, file: &str) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!(READ_ONLY_MESSAGE));
+        }
+        let source = backup_path(&self.path, file)?;
+        let text = fs::read_to_string(&source)
+            .with_context(|| format!("failed to read backup: {}", source.display()))?;
+        parse_document(&text, ExchangeFormat::from_path(&self.path))
+            .with_context(|| format!("backup is not a valid config: {file}"))?;
+
+        self.backup_before("restore")?;
         fs::write(&self.path, text)
-            .with_context(|| format!("failed to write config: {}", self.path.display()))
+            .with_context(|| format!("failed to write config: {}", self.path.display()))?;
+        let temporary = self.temporary_mode();
+        *self = Self::open(self.path.clone(), self.read_only)?;
+        self.set_temporary_mode(temporary);
+        Ok(())
+    }
+
+    /// Copies the file on disk into `config_backups/` before an operation
+    /// that deletes or overwrites part of it.
+    fn backup_before(&self, reason: &str) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        write_backup(&self.path, reason, CONFIG_BACKUP_LIMIT)?;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn temporary_mode(&self) -> bool {
+        self.saved_state.is_some()
+    }
+
+    /// Whether temporary mode holds selections that are not on disk yet.
+    pub fn has_unsaved_state(&self) -> bool {
+        self.saved_state
+            .as_ref()
+            .is_some_and(|saved| self.doc.get("state") != Some(saved))
+    }
+
+    /// In temporary mode selections, free text and drafts change only in memory
+    /// until `save_temporary_state`. Turning it off discards unsaved selections.
+    pub fn set_temporary_mode(&mut self, enabled: bool) {
+        match (enabled, self.saved_state.take()) {
+            (true, saved) => {
+                let current = self.current_state();
+                self.saved_state = Some(saved.unwrap_or(current));
+            }
+            (false, Some(saved)) => {
+                self.root_table_mut().insert("state".to_string(), saved);
+            }
+            (false, None) => {}
+        }
+    }
+
+    /// Writes the temporary-mode selections to disk and keeps the mode on.
+    pub fn save_temporary_state(&mut self) -> Result<()> {
+        if self.saved_state.is_some() {
+            self.saved_state = Some(self.current_state());
+        }
+        self.save()
+    }
+
+    fn current_state(&self) -> Value {
+        self.doc
+            .get("state")
+            .cloned()
+            .unwrap_or_else(|| Value::Table(Map::new()))
     }
 
     pub fn delimiter(&self) -> String {
@@ -61,6 +371,98 @@ impl ConfigStore {
             .unwrap_or_else(|| ", ".to_string())
     }
 
+    /// `[app] prompt_template`: the layout of the whole prompt, with `{key}`
+    /// for each item's value. Empty means the sections as the output format
+    /// lays them out.
+    pub fn prompt_template(&self) -> String {
+        self.app_table()
+            .and_then(|t| t.get("prompt_template"))
+            .and_then(Value::as_str)
+            .filter(|v| !v.trim().is_empty())
+            .map(ToOwned::to_owned)
+            .unwrap_or_default()
+    }
+
+    /// `[app] prompt_prefix`: boilerplate put before every prompt, such as
+    /// quality tags.
+    pub fn prompt_prefix(&self) -> String {
+        self.app_string("prompt_prefix")
+    }
+
+    /// `[app] prompt_suffix`: boilerplate put after every prompt.
+    pub fn prompt_suffix(&self) -> String {
+        self.app_string("prompt_suffix")
+    }
+
+    /// Empty text removes the setting.
+    pub fn set_prompt_affixes(&mut self, prefix: &str, suffix: &str) -> Result<()> {
+        let app = self.ensure_app_table_mut();
+        for (key, value) in [("prompt_prefix", prefix), ("prompt_suffix", suffix)] {
+            if value.trim().is_empty() {
+                app.remove(key);
+            } else {
+                app.insert(key.to_string(), Value::String(value.trim().to_string()));
+            }
+        }
+        self.save()
+    }
+
+    /// Writes the `[app]` options the settings dialog edits; a value that is
+    /// out of range fails with "invalid setting" before anything is changed.
+    pub fn update_app_settings(&mut self, update: &AppSettingsUpdate) -> Result<()> {
+        if update
+            .copy_debounce_sec
+            .is_some_and(|sec| !sec.is_finite() || sec < 0.0)
+        {
+            return Err(anyhow!(
+                "invalid setting: copy_debounce_sec must be 0 or more"
+            ));
+        }
+        if update.history_max_entries == Some(0) {
+            return Err(anyhow!(
+                "invalid setting: history_max_entries must be 1 or more"
+            ));
+        }
+        if update.history_server_port == Some(0) {
+            return Err(anyhow!(
+                "invalid setting: history_server_port must be between 1 and 65535"
+            ));
+        }
+
+        let app = self.ensure_app_table_mut();
+        if let Some(delimiter) = &update.delimiter {
+            app.insert("delimiter".to_string(), Value::String(delimiter.clone()));
+        }
+        if let Some(sec) = update.copy_debounce_sec {
+            app.insert("copy_debounce_sec".to_string(), Value::Float(sec));
+        }
+        if let Some(max_entries) = update.history_max_entries {
+            app.insert(
+                "history_max_entries".to_string(),
+                Value::Integer(i64::try_from(max_entries).unwrap_or(i64::MAX)),
+            );
+        }
+        if let Some(confirm) = update.confirm_delete {
+            app.insert("confirm_delete".to_string(), Value::Boolean(confirm));
+        }
+        if let Some(port) = update.history_server_port {
+            app.insert(
+                "history_server_port".to_string(),
+                Value::Integer(i64::from(port)),
+            );
+        }
+        self.save()
+    }
+
+    fn app_string(&self, key: &str) -> String {
+        self.app_table()
+            .and_then(|t| t.get(key))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .unwrap_or_default()
+            .to_string()
+    }
+
     pub fn confirm_delete(&self) -> bool {
         self.app_table()
             .and_then(|t| t.get("confirm_delete"))
@@ -85,6 +487,28 @@ impl ConfigStore {
             .unwrap_or(3000)
     }
 
+    /// Serve on `history_server_port` or not at all, instead of moving to the
+    /// next free port, so saved pages and bookmarks keep working.
+    pub fn strict_port(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("strict_port"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Port of the HTTPS server for other machines on the LAN; 0 keeps the
+    /// server on loopback only, as does `[features] lan = false`.
+    pub fn history_server_lan_port(&self) -> u16 {
+        if !self.features().lan {
+            return 0;
+        }
+        self.app_table()
+            .and_then(|t| t.get("history_server_lan_port"))
+            .and_then(value_to_i64)
+            .and_then(|v| u16::try_from(v).ok())
+            .unwrap_or(0)
+    }
+
     pub fn history_confirm_delete(&self) -> bool {
         self.app_table()
             .and_then(|t| t.get("history_confirm_delete"))
@@ -92,607 +516,3179 @@ impl ConfigStore {
             .unwrap_or(true)
     }
 
-    pub fn history_max_entries(&self) -> usize {
+    /// Age in days after which active entries are archived; 0 rotates by count only.
+    pub fn history_archive_after_days(&self) -> i64 {
         self.app_table()
-            .and_then(|t| t.get("history_max_entries"))
+            .and_then(|t| t.get("history_archive_after_days"))
+            .and_then(value_to_i64)
+            .filter(|v| *v >= 0)
+            .unwrap_or(0)
+    }
+
+    /// Daily archive files kept before past months are merged; 0 never compacts.
+    pub fn history_archive_compact_threshold(&self) -> usize {
+        self.app_table()
+            .and_then(|t| t.get("history_archive_compact_threshold"))
             .and_then(value_to_i64)
             .and_then(|v| usize::try_from(v).ok())
-            .filter(|v| *v > 0)
-            .unwrap_or(300)
+            .unwrap_or(0)
     }
 
-    pub fn get_items(&self, section_name: &str) -> Vec<ItemConfig> {
-        let mut items = Vec::new();
-        let sections = self
-            .doc
-            .as_table()
-            .and_then(|root| root.get("sections"))
-            .and_then(Value::as_array)
-            .cloned()
-            .unwrap_or_default();
+    /// Unknown values fall back to `day` and are reported by `lint`.
+    pub fn history_archive_grouping(&self) -> ArchiveGrouping {
+        self.app_table()
+            .and_then(|t| t.get("history_archive_grouping"))
+            .and_then(Value::as_str)
+            .and_then(ArchiveGrouping::parse)
+            .unwrap_or_default()
+    }
 
-        for section_value in sections {
-            let Some(section) = section_value.as_table() else {
-                continue;
-            };
-            let Some(name) = section.get("name").and_then(Value::as_str) else {
-                continue;
-            };
-            if name != section_name {
-                continue;
-            }
+    /// Total archive size limit in MB; 0 keeps every archive.
+    pub fn history_archive_max_mb(&self) -> u64 {
+        self.app_table()
+            .and_then(|t| t.get("history_archive_max_mb"))
+            .and_then(value_to_i64)
+            .and_then(|v| u64::try_from(v).ok())
+            .unwrap_or(0)
+    }
 
-            let Some(section_items) = section.get("items").and_then(Value::as_array) else {
-                continue;
-            };
-            for item_value in section_items {
-                let Some(item) = item_value.as_table() else {
-                    continue;
-                };
-                let key = item
-                    .get("key")
-                    .and_then(Value::as_str)
-                    .map(str::trim)
-                    .unwrap_or_default()
-                    .to_string();
-                if key.is_empty() {
-                    continue;
-                }
+    /// Cards per archive HTML page; 0 writes each archive day as one page.
+    pub fn history_archive_page_size(&self) -> usize {
+        self.app_table()
+            .and_then(|t| t.get("history_archive_page_size"))
+            .and_then(value_to_i64)
+            .and_then(|v| usize::try_from(v).ok())
+            .unwrap_or(0)
+    }
 
-                let label = item
-                    .get("label")
-                    .and_then(Value::as_str)
-                    .map(ToOwned::to_owned)
-                    .unwrap_or_else(|| key.clone());
+    /// Whether removing an image from an entry also deletes its file.
+    pub fn history_delete_image_files(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("history_delete_image_files"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
 
-                let template = item
-                    .get("template")
-                    .and_then(Value::as_str)
-                    .map(ToOwned::to_owned)
-                    .unwrap_or_else(|| "{value}".to_string());
+    /// Whether history JSON and images are encrypted at rest.
+    pub fn history_encryption(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("history_encryption"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
 
-                let allow_free_text = item
-                    .get("allow_free_text")
-                    .and_then(Value::as_bool)
-                    .unwrap_or(false);
+    /// Key file for the encrypted history; `None` uses `history.key` in the data dir.
+    pub fn history_encryption_key_file(&self) -> Option<String> {
+        self.app_table()
+            .and_then(|t| t.get("history_encryption_key_file"))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(ToOwned::to_owned)
+    }
 
-                let choices = normalize_choices_from_value(item.get("choices"));
+    pub fn history_max_entries(&self) -> usize {
+        self.app_table()
+            .and_then(|t| t.get("history_max_entries"))
+            .and_then(value_to_i64)
+            .and_then(|v| usize::try_from(v).ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(300)
+    }
 
-                items.push(ItemConfig {
-                    section_name: section_name.to_string(),
-                    key,
-                    label,
-                    choices,
-                    allow_free_text,
-                    template,
-                });
-            }
-        }
+    pub fn history_max_images(&self) -> usize {
+        self.app_table()
+            .and_then(|t| t.get("history_max_images"))
+            .and_then(value_to_i64)
+            .and_then(|v| usize::try_from(v).ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(4)
+    }
 
-        items
+    pub fn output_format(&self) -> String {
+        self.app_table()
+            .and_then(|t| t.get("output_format"))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .unwrap_or(DEFAULT_OUTPUT_FORMAT)
+            .to_string()
     }
 
-    pub fn add_choice(&mut self, section_name: &str, key: &str, value: &str) -> Result<bool> {
-        let normalized = value.trim();
-        if normalized.is_empty() || normalized == NO_SELECTION {
-            return Ok(false);
+    pub fn set_output_format(&mut self, output_format: &str) -> Result<()> {
+        let output_format = output_format.trim();
+        if output_format.is_empty() {
+            return Err(anyhow!("output format is empty"));
         }
+        self.ensure_app_table_mut().insert(
+            "output_format".to_string(),
+            Value::String(output_format.to_string()),
+        );
+        self.save()
+    }
 
-        let item = self
-            .find_item_table_mut(section_name, key)
-            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
-        let mut choices = normalize_choices_from_value(item.get("choices"));
-        if choices.iter().any(|c| c == normalized) {
-            return Ok(false);
+    /// The built-in format profiles plus every format named by an item's
+    /// `formats`.
+    pub fn output_formats(&self) -> Vec<String> {
+        let mut formats: Vec<String> = FormatProfile::ALL
+            .iter()
+            .map(|profile| profile.name().to_string())
+            .collect();
+        let declared = self
+            .all_items()
+            .into_iter()
+            .flat_map(|item| item.formats)
+            .chain(std::iter::once(self.output_format()));
+        for format in declared {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
         }
+        formats
+    }
 
-        choices.push(normalized.to_string());
-        item.insert("choices".to_string(), choices_to_value(&choices));
-        self.save()?;
-        Ok(true)
+    /// How many copies ahead the UI warns about rotation; 0 disables the warning.
+    pub fn history_rotation_warn_margin(&self) -> usize {
+        self.app_table()
+            .and_then(|t| t.get("history_rotation_warn_margin"))
+            .and_then(value_to_i64)
+            .and_then(|v| usize::try_from(v).ok())
+            .unwrap_or(5)
     }
 
-    pub fn remove_choice(&mut self, section_name: &str, key: &str, value: &str) -> Result<bool> {
-        let normalized = value.trim();
-        if normalized.is_empty() || normalized == NO_SELECTION {
-            return Ok(false);
-        }
+    /// Unknown values fall back to `off` and are reported by `lint`.
+    pub fn dedupe_history(&self) -> DedupeMode {
+        self.app_table()
+            .and_then(|t| t.get("dedupe_history"))
+            .and_then(Value::as_str)
+            .and_then(DedupeMode::parse)
+            .unwrap_or_default()
+    }
 
-        let item = self
-            .find_item_table_mut(section_name, key)
-            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
-        let choices = normalize_choices_from_value(item.get("choices"));
-        if !choices.iter().any(|c| c == normalized) {
-            return Ok(false);
-        }
+    /// Months archives are kept before they are exported and removed; 0 keeps them.
+    pub fn history_retention_months(&self) -> u32 {
+        self.app_table()
+            .and_then(|t| t.get("history_retention_months"))
+            .and_then(value_to_i64)
+            .and_then(|v| u32::try_from(v).ok())
+            .unwrap_or(0)
+    }
 
-        let filtered: Vec<String> = choices.into_iter().filter(|c| c != normalized).collect();
-        item.insert("choices".to_string(), choices_to_value(&filtered));
-        self.save()?;
-        Ok(true)
+    /// Folder for the retention exports; `None` uses `exports` in the data dir.
+    pub fn history_retention_export_dir(&self) -> Option<String> {
+        self.app_table()
+            .and_then(|t| t.get("history_retention_export_dir"))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(ToOwned::to_owned)
     }
 
-    pub fn get_item_state(&self, section_name: &str, key: &str) -> (String, String) {
-        let selected_key = format!("{}_selected", key);
-        let free_key = format!("{}_free_text", key);
+    /// Days deleted history entries stay in the trash before the purge job drops them.
+    pub fn history_trash_retention_days(&self) -> i64 {
+        self.app_table()
+            .and_then(|t| t.get("history_trash_retention_days"))
+            .and_then(value_to_i64)
+            .filter(|v| *v >= 0)
+            .unwrap_or(30)
+    }
 
-        let section_state = self
+    /// Optional vision/OCR command (program and arguments) run on screenshots;
+    /// empty when the feature is not set up.
+    pub fn vision_command(&self) -> Vec<String> {
+        self.integration_command("vision_command")
+    }
+
+    /// Optional translation command (program and arguments) that turns the
+    /// composed prompt into English; empty when the feature is not set up.
+    pub fn translate_command(&self) -> Vec<String> {
+        self.integration_command("translate_command")
+    }
+
+    /// Optional scoring command (program and arguments) that rates the
+    /// rendered prompt 0–100 before copy; empty when the feature is not set up.
+    pub fn score_command(&self) -> Vec<String> {
+        self.integration_command("score_command")
+    }
+
+    /// An integration command; empty while `[features] integrations` is off.
+    fn integration_command(&self, key: &str) -> Vec<String> {
+        if !self.features().integrations {
+            return Vec::new();
+        }
+        self.app_table()
+            .and_then(|t| t.get(key))
+            .and_then(Value::as_array)
+            .map(|args| {
+                args.iter()
+                    .filter_map(Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn features(&self) -> Features {
+        let defaults = Features::default();
+        let table = self
             .doc
             .as_table()
-            .and_then(|root| root.get("state"))
-            .and_then(Value::as_table)
-            .and_then(|state| state.get(section_name))
+            .and_then(|root| root.get("features"))
             .and_then(Value::as_table);
+        let flag = |key: &str, default: bool| {
+            table
+                .and_then(|t| t.get(key))
+                .and_then(Value::as_bool)
+                .unwrap_or(default)
+        };
+        Features {
+            integrations: flag("integrations", defaults.integrations),
+            lan: flag("lan", defaults.lan),
+            analytics: flag("analytics", defaults.analytics),
+        }
+    }
 
-        let selected = section_state
-            .and_then(|table| table.get(&selected_key))
-            .and_then(Value::as_str)
-            .map(str::trim)
-            .filter(|s| !s.is_empty())
-            .unwrap_or(NO_SELECTION)
-            .to_string();
+    pub fn mini_click_through(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("mini_click_through"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
 
-        let free_text = section_state
-            .and_then(|table| table.get(&free_key))
-            .and_then(Value::as_str)
-            .map(str::trim)
-            .unwrap_or_default()
-            .to_string();
+    /// Whether `GET /image` writes the entry's prompt into served PNGs as a
+    /// `parameters` text chunk (the key the Stable Diffusion web UI reads).
+    pub fn png_embed_parameters(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("png_embed_parameters"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
 
-        (selected, free_text)
+    /// Whether the dice favours values from well-rated history entries
+    /// (「良かった組み合わせ寄りでランダム」).
+    pub fn random_weighted(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("random_weighted"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
     }
 
-    pub fn set_item_state(
-        &mut self,
-        section_name: &str,
-        key: &str,
-        selected: &str,
-        free_text: &str,
-    ) -> Result<()> {
-        let selected_value = if selected.trim().is_empty() {
-            NO_SELECTION
-        } else {
-            selected.trim()
-        };
+    pub fn set_random_weighted(&mut self, enabled: bool) -> Result<()> {
+        self.ensure_app_table_mut()
+            .insert("random_weighted".to_string(), Value::Boolean(enabled));
+        self.save()
+    }
 
-        let section_table = self.ensure_section_state_mut(section_name);
-        section_table.insert(
-            format!("{}_selected", key),
-            Value::String(selected_value.to_string()),
-        );
-        section_table.insert(
-            format!("{}_free_text", key),
-            Value::String(free_text.trim().to_string()),
-        );
+    /// Whether the main UI lists each dropdown's choices most copied first
+    /// instead of in config order.
+    pub fn sort_choices_by_usage(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("sort_choices_by_usage"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
 
+    pub fn set_sort_choices_by_usage(&mut self, enabled: bool) -> Result<()> {
+        self.ensure_app_table_mut()
+            .insert("sort_choices_by_usage".to_string(), Value::Boolean(enabled));
         self.save()
     }
 
-    pub fn clear_section_state(&mut self, section_name: &str) -> Result<()> {
-        let state = self.ensure_state_table_mut();
-        state.insert(section_name.to_string(), Value::Table(Map::new()));
-        self.save()
+    pub fn onboarding_samples(&self) -> bool {
+        self.app_table()
+            .and_then(|t| t.get("onboarding_samples"))
+            .and_then(Value::as_bool)
+            .unwrap_or(true)
     }
 
-    fn normalize_doc(&mut self) {
-        if !self.doc.is_table() {
-            self.doc = Value::Table(Map::new());
+    pub fn startup_actions(&self) -> StartupActions {
+        let Some(startup) = self
+            .app_table()
+            .and_then(|t| t.get("startup"))
+            .and_then(Value::as_table)
+        else {
+            return StartupActions::default();
+        };
+        let flag = |key: &str| startup.get(key).and_then(Value::as_bool).unwrap_or(false);
+        StartupActions {
+            open_history: flag("open_history"),
+            new_session: flag("new_session"),
+            check_integrations: flag("check_integrations"),
+            preset: startup
+                .get("preset")
+                .and_then(Value::as_str)
+                .map(|name| name.trim().to_string())
+                .unwrap_or_default(),
         }
+    }
 
-        {
-            let app = self.ensure_app_table_mut();
-
-            if app.get("delimiter").and_then(Value::as_str).is_none() {
-                app.insert("delimiter".to_string(), Value::String(", ".to_string()));
-            }
-
-            if app.get("confirm_delete").and_then(Value::as_bool).is_none() {
-                app.insert("confirm_delete".to_string(), Value::Boolean(true));
+    /// Every section in file order with its items; blocks sharing a name are
+    /// one section, as in `get_items`.
+    pub fn sections(&self) -> Vec<SectionConfig> {
+        let mut sections: Vec<SectionConfig> = Vec::new();
+        let tables = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("sections"))
+            .and_then(Value::as_array);
+        for section in tables.into_iter().flatten().filter_map(Value::as_table) {
+            let Some(name) = section.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            if sections.iter().any(|known| known.name == name) {
+                continue;
             }
+            sections.push(SectionConfig {
+                name: name.to_string(),
+                label: section
+                    .get("label")
+                    .and_then(Value::as_str)
+                    .unwrap_or(name)
+                    .to_string(),
+                template: section
+                    .get("template")
+                    .and_then(Value::as_str)
+                    .unwrap_or("{value}")
+                    .to_string(),
+                items: self.get_items(name),
+            });
+        }
+        sections
+    }
 
-            let debounce = app
-                .get("copy_debounce_sec")
-                .and_then(value_to_f64)
-                .filter(|v| *v >= 0.0)
-                .unwrap_or(2.0);
-            app.insert("copy_debounce_sec".to_string(), Value::Float(debounce));
+    /// The items of every section, in the order the main UI shows them.
+    pub fn all_items(&self) -> Vec<ItemConfig> {
+        self.sections()
+            .into_iter()
+            .flat_map(|section| section.items)
+            .collect()
+    }
 
-            let port = app
-                .get("history_server_port")
-                .and_then(value_to_i64)
-                .filter(|v| (1..=65_535).contains(v))
-                .unwrap_or(3000);
-            app.insert("history_server_port".to_string(), Value::Integer(port));
+    /// The section's items, without those hidden with `enabled = false`.
+    pub fn get_items(&self, section_name: &str) -> Vec<ItemConfig> {
+        self.read_items(section_name, true)
+    }
 
-            if app
-                .get("history_confirm_delete")
-                .and_then(Value::as_bool)
-                .is_none()
-            {
-                app.insert("history_confirm_delete".to_string(), Value::Boolean(true));
-            }
+    /// The section's items hidden with `enabled = false`; their choices and
+    /// `[state]` stay as they were until they are enabled again.
+    pub fn disabled_items(&self, section_name: &str) -> Vec<ItemConfig> {
+        self.read_items(section_name, false)
+    }
 
-            let max_entries = app
-                .get("history_max_entries")
-                .and_then(value_to_i64)
-                .filter(|v| *v > 0)
-                .unwrap_or(300);
-            app.insert(
-                "history_max_entries".to_string(),
-                Value::Integer(max_entries),
-            );
-        }
+    fn read_items(&self, section_name: &str, enabled: bool) -> Vec<ItemConfig> {
+        let mut items = Vec::new();
+        let sections = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("sections"))
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
 
-        {
-            let sections = self.ensure_sections_array_mut();
-            for section_value in sections.iter_mut() {
-                if !section_value.is_table() {
-                    *section_value = Value::Table(Map::new());
-                }
-                let section = section_value
-                    .as_table_mut()
-                    .expect("section should be table after normalization");
+        for section_value in sections {
+            let Some(section) = section_value.as_table() else {
+                continue;
+            };
+            let Some(name) = section.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            if name != section_name {
+                continue;
+            }
 
-                let name = section
-                    .get("name")
+            let Some(section_items) = section.get("items").and_then(Value::as_array) else {
+                continue;
+            };
+            for item_value in section_items {
+                let Some(item) = item_value.as_table() else {
+                    continue;
+                };
+                let key = item
+                    .get("key")
                     .and_then(Value::as_str)
                     .map(str::trim)
-                    .filter(|s| !s.is_empty())
-                    .unwrap_or("prompt")
+                    .unwrap_or_default()
                     .to_string();
-                section.insert("name".to_string(), Value::String(name.clone()));
+                if key.is_empty() {
+                    continue;
+                }
+                let item_enabled = item.get("enabled").and_then(Value::as_bool).unwrap_or(true);
+                if item_enabled != enabled {
+                    continue;
+                }
 
-                let label = section
+                let label = item
                     .get("label")
                     .and_then(Value::as_str)
                     .map(ToOwned::to_owned)
-                    .unwrap_or_else(|| name.clone());
-                section.insert("label".to_string(), Value::String(label));
+                    .unwrap_or_else(|| key.clone());
 
-                let items_value = section
-                    .entry("items".to_string())
-                    .or_insert_with(|| Value::Array(Vec::new()));
-                if !items_value.is_array() {
-                    *items_value = Value::Array(Vec::new());
-                }
+                let template = item
+                    .get("template")
+                    .and_then(Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .unwrap_or_else(|| default_template(&label));
 
-                if let Some(items) = items_value.as_array_mut() {
-                    for item_value in items.iter_mut() {
-                        if !item_value.is_table() {
-                            *item_value = Value::Table(Map::new());
-                        }
-                        let item = item_value
-                            .as_table_mut()
-                            .expect("item should be table after normalization");
+                let allow_free_text = item
+                    .get("allow_free_text")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
 
-                        let key = item
-                            .get("key")
-                            .map(value_to_text)
+                let choices = normalize_choices_from_value(item.get("choices"));
+                let formats = item
+                    .get("formats")
+                    .and_then(Value::as_array)
+                    .map(|values| {
+                        values
+                            .iter()
+                            .filter_map(Value::as_str)
                             .map(|v| v.trim().to_string())
-                            .unwrap_or_default();
-                        item.insert("key".to_string(), Value::String(key.clone()));
-
-                        let label = item
-                            .get("label")
-                            .and_then(Value::as_str)
-                            .map(ToOwned::to_owned)
-                            .unwrap_or_else(|| key.clone());
-                        item.insert("label".to_string(), Value::String(label));
+                            .filter(|v| !v.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let random_pool = normalize_choices_from_value(item.get("random_pool"))
+                    .into_iter()
+                    .filter(|value| value != NO_SELECTION)
+                    .collect();
+                let param = item
+                    .get("param")
+                    .and_then(Value::as_str)
+                    .map(|v| v.trim().trim_start_matches('-').to_string())
+                    .unwrap_or_default();
+                let group = item
+                    .get("group")
+                    .and_then(Value::as_str)
+                    .map(|v| v.trim().to_string())
+                    .unwrap_or_default();
 
-                        let allow_free_text = item
-                            .get("allow_free_text")
-                            .and_then(Value::as_bool)
-                            .unwrap_or(true);
-                        item.insert(
-                            "allow_free_text".to_string(),
-                            Value::Boolean(allow_free_text),
-                        );
-
-                        let template = item
-                            .get("template")
-                            .and_then(Value::as_str)
-                            .map(ToOwned::to_owned)
-                            .unwrap_or_else(|| "{value}".to_string());
-                        item.insert("template".to_string(), Value::String(template));
-
-                        let choices = normalize_choices_from_value(item.get("choices"));
-                        item.insert("choices".to_string(), choices_to_value(&choices));
-                    }
-                }
+                items.push(ItemConfig {
+                    section_name: section_name.to_string(),
+                    key,
+                    label,
+                    choices,
+                    allow_free_text,
+                    template,
+                    formats,
+                    random_pool,
+                    param,
+                    group,
+                });
             }
         }
 
-        self.ensure_state_table_mut();
-        self.reorder_root_tables();
+        items
     }
 
-    fn app_table(&self) -> Option<&Map<String, Value>> {
-        self.doc
-            .as_table()
-            .and_then(|root| root.get("app"))
-            .and_then(Value::as_table)
+    /// Shows or hides the item; `enabled = false` is written to the item and
+    /// removed again when it is enabled.
+    pub fn set_item_enabled(&mut self, section_name: &str, key: &str, enabled: bool) -> Result<()> {
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        if enabled {
+            item.remove("enabled");
+        } else {
+            item.insert("enabled".to_string(), Value::Boolean(false));
+        }
+        self.save()
     }
 
-    fn root_table_mut(&mut self) -> &mut Map<String, Value> {
-        if !self.doc.is_table() {
-            self.doc = Value::Table(Map::new());
+    pub fn add_choice(&mut self, section_name: &str, key: &str, value: &str) -> Result<bool> {
+        let normalized = value.trim();
+        if normalized.is_empty() || normalized == NO_SELECTION {
+            return Ok(false);
         }
-        self.doc
-            .as_table_mut()
-            .expect("root should be table after normalization")
-    }
 
-    fn ensure_app_table_mut(&mut self) -> &mut Map<String, Value> {
-        let root = self.root_table_mut();
-        let app = root
-            .entry("app".to_string())
-            .or_insert_with(|| Value::Table(Map::new()));
-        if !app.is_table() {
-            *app = Value::Table(Map::new());
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        let mut choices = normalize_choices_from_value(item.get("choices"));
+        if choices.iter().any(|c| c == normalized) {
+            return Ok(false);
         }
-        app.as_table_mut()
-            .expect("app should be table after normalization")
+
+        choices.push(normalized.to_string());
+        item.insert("choices".to_string(), choices_to_value(&choices));
+        self.save()?;
+        Ok(true)
     }
 
-    fn ensure_sections_array_mut(&mut self) -> &mut Vec<Value> {
-        let root = self.root_table_mut();
-        let sections = root
-            .entry("sections".to_string())
-            .or_insert_with(|| Value::Array(Vec::new()));
-        if !sections.is_array() {
-            *sections = Value::Array(Vec::new());
+    pub fn remove_choice(&mut self, section_name: &str, key: &str, value: &str) -> Result<bool> {
+        let normalized = value.trim();
+        if normalized.is_empty() || normalized == NO_SELECTION {
+            return Ok(false);
         }
-        sections
-            .as_array_mut()
-            .expect("sections should be array after normalization")
-    }
+        self.backup_before("remove_choice")?;
 
-    fn ensure_state_table_mut(&mut self) -> &mut Map<String, Value> {
-        let root = self.root_table_mut();
-        let state = root
-            .entry("state".to_string())
-            .or_insert_with(|| Value::Table(Map::new()));
-        if !state.is_table() {
-            *state = Value::Table(Map::new());
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        let choices = normalize_choices_from_value(item.get("choices"));
+        if !choices.iter().any(|c| c == normalized) {
+            return Ok(false);
         }
-        state
-            .as_table_mut()
-            .expect("state should be table after normalization")
-    }
 
-    fn ensure_section_state_mut(&mut self, section_name: &str) -> &mut Map<String, Value> {
-        let state = self.ensure_state_table_mut();
-        let section = state
-            .entry(section_name.to_string())
-            .or_insert_with(|| Value::Table(Map::new()));
-        if !section.is_table() {
-            *section = Value::Table(Map::new());
+        let filtered: Vec<String> = choices.into_iter().filter(|c| c != normalized).collect();
+        item.insert("choices".to_string(), choices_to_value(&filtered));
+        if let Some(Value::Array(pool)) = item.get_mut("random_pool") {
+            pool.retain(|value| value.as_str() != Some(normalized));
+            if pool.is_empty() {
+                item.remove("random_pool");
+            }
         }
-        section
-            .as_table_mut()
-            .expect("section state should be table after normalization")
+        self.save()?;
+        Ok(true)
     }
 
-    fn reorder_root_tables(&mut self) {
-        let root = self.root_table_mut();
-        let mut reordered = Map::new();
+    /// Renames a choice where it stands, in `random_pool` too, and moves the
+    /// item's selection and free text still holding the old value over to
+    /// the new one. Returns `false` when the name is unchanged.
+    pub fn rename_choice(
+        &mut self,
+        section_name: &str,
+        key: &str,
+        value: &str,
+        new_value: &str,
+    ) -> Result<bool> {
+        let (old, new) = (value.trim(), new_value.trim());
+        if old.is_empty() || old == NO_SELECTION || new.is_empty() || new == NO_SELECTION {
+            return Err(anyhow!(
+                "{NO_SELECTION} and empty choices cannot be renamed"
+            ));
+        }
+        if old == new {
+            return Ok(false);
+        }
+        self.backup_before("rename_choice")?;
 
-        for key in ["app", "sections", "state"] {
-            if let Some(value) = root.remove(key) {
-                reordered.insert(key.to_string(), value);
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        let mut choices = normalize_choices_from_value(item.get("choices"));
+        if choices.iter().any(|c| c == new) {
+            return Err(anyhow!("choice already exists: {new}"));
+        }
+        let Some(slot) = choices.iter_mut().find(|c| *c == old) else {
+            return Err(anyhow!("choice not found: {old}"));
+        };
+        *slot = new.to_string();
+        item.insert("choices".to_string(), choices_to_value(&choices));
+        if let Some(Value::Array(pool)) = item.get_mut("random_pool") {
+            for member in pool.iter_mut() {
+                if member.as_str() == Some(old) {
+                    *member = Value::String(new.to_string());
+                }
             }
         }
 
-        let remaining_keys: Vec<String> = root.keys().cloned().collect();
-        for key in remaining_keys {
-            if let Some(value) = root.remove(&key) {
-                reordered.insert(key, value);
+        let section_state = self.ensure_section_state_mut(section_name);
+        for state_key in [format!("{key}_selected"), format!("{key}_free_text")] {
+            if section_state.get(&state_key).and_then(Value::as_str) == Some(old) {
+                section_state.insert(state_key, Value::String(new.to_string()));
             }
         }
+        if self.choice_usage(section_name, key).contains_key(old) {
+            let counts = self.choice_usage_table_mut(section_name, key);
+            if let Some(count) = counts.remove(old) {
+                counts.insert(new.to_string(), count);
+            }
+        }
+        self.save()?;
+        Ok(true)
+    }
 
-        *root = reordered;
+    /// Moves a choice to `new_index` in the dropdown. `指定なし` stays first,
+    /// so the index is clamped to the range after it. Returns `false` when
+    /// the choice is already there.
+    pub fn move_choice(
+        &mut self,
+        section_name: &str,
+        key: &str,
+        value: &str,
+        new_index: usize,
+    ) -> Result<bool> {
+        let value = value.trim();
+        if value == NO_SELECTION {
+            return Err(anyhow!("{NO_SELECTION} cannot be moved"));
+        }
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        let mut choices = normalize_choices_from_value(item.get("choices"));
+        let Some(index) = choices.iter().position(|c| c == value) else {
+            return Err(anyhow!("choice not found: {value}"));
+        };
+        let new_index = new_index.clamp(1, choices.len() - 1);
+        if index == new_index {
+            return Ok(false);
+        }
+        let moved = choices.remove(index);
+        choices.insert(new_index, moved);
+        item.insert("choices".to_string(), choices_to_value(&choices));
+        self.save()?;
+        Ok(true)
     }
 
-    fn find_item_table_mut(
+    /// Adds `value` to or removes it from the item's `random_pool`.
+    pub fn set_random_pool_member(
         &mut self,
         section_name: &str,
         key: &str,
-    ) -> Option<&mut Map<String, Value>> {
-        let sections = self.ensure_sections_array_mut();
-        for section_value in sections.iter_mut() {
-            let Some(section) = section_value.as_table_mut() else {
-                continue;
-            };
-            let Some(name) = section.get("name").and_then(Value::as_str) else {
-                continue;
-            };
-            if name != section_name {
-                continue;
-            }
+        value: &str,
+        enabled: bool,
+    ) -> Result<()> {
+        let normalized = value.trim();
+        if normalized.is_empty() || normalized == NO_SELECTION {
+            return Err(anyhow!("{NO_SELECTION} cannot be in the random pool"));
+        }
 
-            let Some(items) = section.get_mut("items").and_then(Value::as_array_mut) else {
+        let item = self
+            .find_item_table_mut(section_name, key)
+            .ok_or_else(|| anyhow!("item not found: {}.{}", section_name, key))?;
+        let choices = normalize_choices_from_value(item.get("choices"));
+        if !choices.iter().any(|c| c == normalized) {
+            return Err(anyhow!("choice not found: {normalized}"));
+        }
+        let mut pool: Vec<String> = normalize_choices_from_value(item.get("random_pool"))
+            .into_iter()
+            .filter(|v| v != NO_SELECTION && v != normalized)
+            .collect();
+        if enabled {
+            pool.push(normalized.to_string());
+        }
+        if pool.is_empty() {
+            item.remove("random_pool");
+        } else {
+            item.insert("random_pool".to_string(), choices_to_value(&pool));
+        }
+        self.save()
+    }
+
+    pub fn get_item_state(&self, section_name: &str, key: &str) -> (String, String) {
+        let selected_key = format!("{}_selected", key);
+        let free_key = format!("{}_free_text", key);
+
+        let section_state = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("state"))
+            .and_then(Value::as_table)
+            .and_then(|state| state.get(section_name))
+            .and_then(Value::as_table);
+
+        let selected = section_state
+            .and_then(|table| table.get(&selected_key))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .unwrap_or(NO_SELECTION)
+            .to_string();
+
+        let free_text = section_state
+            .and_then(|table| table.get(&free_key))
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .unwrap_or_default()
+            .to_string();
+
+        (selected, free_text)
+    }
+
+    /// Current value of every item in the section, keyed by item key: the
+    /// confirmed free text, else the selected choice. Unset items are omitted.
+    pub fn selections(&self, section_name: &str) -> BTreeMap<String, String> {
+        let mut selections = BTreeMap::new();
+        for item in self.get_items(section_name) {
+            let (selected, free_text) = self.get_item_state(section_name, &item.key);
+            let value = if !free_text.is_empty() {
+                free_text
+            } else if selected != NO_SELECTION && item.choices.contains(&selected) {
+                selected
+            } else {
                 continue;
             };
-            for item_value in items.iter_mut() {
-                let Some(item) = item_value.as_table_mut() else {
-                    continue;
-                };
-                if item.get("key").and_then(Value::as_str) == Some(key) {
-                    return Some(item);
-                }
-            }
+            selections.insert(item.key, value);
         }
+        selections
+    }
 
-        None
+    /// `selections` of every section in one map; item keys are unique across
+    /// sections (see `lint`).
+    pub fn all_selections(&self) -> BTreeMap<String, String> {
+        self.sections()
+            .iter()
+            .flat_map(|section| self.selections(&section.name))
+            .collect()
     }
-}
 
-fn normalize_choices_from_value(value: Option<&Value>) -> Vec<String> {
-    let mut normalized = Vec::new();
-    if let Some(Value::Array(items)) = value {
-        for item in items {
-            let text = value_to_text(item).trim().to_string();
-            if !text.is_empty() && !normalized.iter().any(|existing| existing == &text) {
-                normalized.push(text);
-            }
-        }
+    pub fn set_item_state(
+        &mut self,
+        section_name: &str,
+        key: &str,
+        selected: &str,
+        free_text: &str,
+    ) -> Result<()> {
+        let selected_value = if selected.trim().is_empty() {
+            NO_SELECTION
+        } else {
+            selected.trim()
+        };
+
+        let section_table = self.ensure_section_state_mut(section_name);
+        section_table.insert(
+            format!("{}_selected", key),
+            Value::String(selected_value.to_string()),
+        );
+        section_table.insert(
+            format!("{}_free_text", key),
+            Value::String(free_text.trim().to_string()),
+        );
+        section_table.remove(&format!("{}_draft", key));
+
+        self.save()
+    }
+
+    pub fn get_item_draft(&self, section_name: &str, key: &str) -> String {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("state"))
+            .and_then(Value::as_table)
+            .and_then(|state| state.get(section_name))
+            .and_then(Value::as_table)
+            .and_then(|table| table.get(&format!("{}_draft", key)))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// Free texts confirmed for the item, newest first. Kept in
+    /// `[free_text_history]` rather than `[state]`, so `Reset` leaves them.
+    pub fn free_text_history(&self, section_name: &str, key: &str) -> Vec<String> {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("free_text_history"))
+            .and_then(Value::as_table)
+            .and_then(|history| history.get(section_name))
+            .and_then(Value::as_table)
+            .and_then(|table| table.get(key))
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Moves `value` to the front of the item's free text history, keeping at
+    /// most `FREE_TEXT_HISTORY_LIMIT`.
+    pub fn remember_free_text(&mut self, section_name: &str, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        if value.is_empty() || value == NO_SELECTION {
+            return Ok(());
+        }
+        let mut values = self.free_text_history(section_name, key);
+        values.retain(|known| known != value);
+        values.insert(0, value.to_string());
+        values.truncate(FREE_TEXT_HISTORY_LIMIT);
+
+        let root = self.root_table_mut();
+        let history = root
+            .entry("free_text_history".to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !history.is_table() {
+            *history = Value::Table(Map::new());
+        }
+        let section = history
+            .as_table_mut()
+            .expect("free_text_history should be table after normalization")
+            .entry(section_name.to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !section.is_table() {
+            *section = Value::Table(Map::new());
+        }
+        section
+            .as_table_mut()
+            .expect("section history should be table after normalization")
+            .insert(
+                key.to_string(),
+                Value::Array(values.into_iter().map(Value::String).collect()),
+            );
+        self.save()
+    }
+
+    /// Groups of the section the main UI shows collapsed, from the root
+    /// `[collapsed_groups]` table.
+    pub fn collapsed_groups(&self, section_name: &str) -> Vec<String> {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("collapsed_groups"))
+            .and_then(Value::as_table)
+            .and_then(|groups| groups.get(section_name))
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn set_group_collapsed(
+        &mut self,
+        section_name: &str,
+        group: &str,
+        collapsed: bool,
+    ) -> Result<()> {
+        let group = group.trim();
+        if group.is_empty() {
+            return Err(anyhow!("group name is empty"));
+        }
+        let mut groups = self.collapsed_groups(section_name);
+        groups.retain(|known| known != group);
+        if collapsed {
+            groups.push(group.to_string());
+        }
+
+        let root = self.root_table_mut();
+        let table = root
+            .entry("collapsed_groups".to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !table.is_table() {
+            *table = Value::Table(Map::new());
+        }
+        let table = table
+            .as_table_mut()
+            .expect("collapsed_groups should be table after normalization");
+        if groups.is_empty() {
+            table.remove(section_name);
+        } else {
+            table.insert(
+                section_name.to_string(),
+                Value::Array(groups.into_iter().map(Value::String).collect()),
+            );
+        }
+        self.save()
+    }
+
+    /// How many copied prompts used each choice of the item, from the root
+    /// `[choice_usage.<section>.<key>]` table; unused choices are absent.
+    pub fn choice_usage(&self, section_name: &str, key: &str) -> BTreeMap<String, u64> {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("choice_usage"))
+            .and_then(Value::as_table)
+            .and_then(|usage| usage.get(section_name))
+            .and_then(Value::as_table)
+            .and_then(|section| section.get(key))
+            .and_then(Value::as_table)
+            .map(|counts| {
+                counts
+                    .iter()
+                    .filter_map(|(choice, count)| {
+                        let count = u64::try_from(count.as_integer()?).ok()?;
+                        (count > 0).then(|| (choice.clone(), count))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Counts one use of every item's current value that is one of its
+    /// choices; called when a prompt is copied. Free text that is not a
+    /// choice is not counted.
+    pub fn record_choice_usage(&mut self) -> Result<()> {
+        let mut used = Vec::new();
+        for section in self.sections() {
+            let selections = self.selections(&section.name);
+            for item in section.items {
+                if let Some(value) = selections.get(&item.key) {
+                    if item.choices.contains(value) {
+                        used.push((section.name.clone(), item.key, value.clone()));
+                    }
+                }
+            }
+        }
+        if used.is_empty() {
+            return Ok(());
+        }
+        for (section_name, key, value) in used {
+            let counts = self.choice_usage_table_mut(&section_name, &key);
+            let count = counts.get(&value).and_then(Value::as_integer).unwrap_or(0);
+            counts.insert(value, Value::Integer(count.max(0) + 1));
+        }
+        self.save()
+    }
+
+    fn choice_usage_table_mut(&mut self, section_name: &str, key: &str) -> &mut Map<String, Value> {
+        let mut table = self.root_table_mut();
+        for name in ["choice_usage", section_name, key] {
+            let entry = table
+                .entry(name.to_string())
+                .or_insert_with(|| Value::Table(Map::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Map::new());
+            }
+            table = entry
+                .as_table_mut()
+                .expect("choice_usage should be table after normalization");
+        }
+        table
+    }
+
+    /// Emphasis weight of the item, 1.0 unless set with `set_item_weight`.
+    pub fn get_item_weight(&self, section_name: &str, key: &str) -> f64 {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("state"))
+            .and_then(Value::as_table)
+            .and_then(|state| state.get(section_name))
+            .and_then(Value::as_table)
+            .and_then(|table| table.get(&format!("{}_weight", key)))
+            .and_then(|value| {
+                value
+                    .as_float()
+                    .or_else(|| value.as_integer().map(|v| v as f64))
+            })
+            .filter(|weight| weight.is_finite())
+            .map(|weight| weight.clamp(ITEM_WEIGHT_MIN, ITEM_WEIGHT_MAX))
+            .unwrap_or(1.0)
+    }
+
+    /// Stores the emphasis weight, clamped to `ITEM_WEIGHT_MIN..=ITEM_WEIGHT_MAX`
+    /// and rounded to hundredths; 1.0 removes the entry.
+    pub fn set_item_weight(&mut self, section_name: &str, key: &str, weight: f64) -> Result<()> {
+        if !weight.is_finite() {
+            return Err(anyhow!("weight must be a number"));
+        }
+        let weight = (weight.clamp(ITEM_WEIGHT_MIN, ITEM_WEIGHT_MAX) * 100.0).round() / 100.0;
+        let weight_key = format!("{}_weight", key);
+        let section_table = self.ensure_section_state_mut(section_name);
+        if weight == 1.0 {
+            section_table.remove(&weight_key);
+        } else {
+            section_table.insert(weight_key, Value::Float(weight));
+        }
+        self.save()
+    }
+
+    /// Whether `ランダム` leaves the item as it is.
+    pub fn get_item_locked(&self, section_name: &str, key: &str) -> bool {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("state"))
+            .and_then(Value::as_table)
+            .and_then(|state| state.get(section_name))
+            .and_then(Value::as_table)
+            .and_then(|table| table.get(&format!("{}_locked", key)))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    /// Only locked items are stored.
+    pub fn set_item_locked(&mut self, section_name: &str, key: &str, locked: bool) -> Result<()> {
+        let locked_key = format!("{}_locked", key);
+        let section_table = self.ensure_section_state_mut(section_name);
+        if locked {
+            section_table.insert(locked_key, Value::Boolean(true));
+        } else {
+            section_table.remove(&locked_key);
+        }
+        self.save()
+    }
+
+    /// Stores free text that was typed but not confirmed with Enter.
+    /// An empty draft removes the entry so confirmed state stays authoritative.
+    pub fn set_item_draft(&mut self, section_name: &str, key: &str, draft: &str) -> Result<()> {
+        let draft_key = format!("{}_draft", key);
+        let section_table = self.ensure_section_state_mut(section_name);
+        let changed = if draft.trim().is_empty() {
+            section_table.remove(&draft_key).is_some()
+        } else {
+            let next = Value::String(draft.to_string());
+            if section_table.get(&draft_key) == Some(&next) {
+                false
+            } else {
+                section_table.insert(draft_key, next);
+                true
+            }
+        };
+
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the section's items into the order of `keys`, the order they are
+    /// shown and rendered in; items not named keep their order after them.
+    pub fn reorder_items(&mut self, section_name: &str, keys: &[String]) -> Result<()> {
+        let mut found = false;
+        for section in self.ensure_sections_array_mut().iter_mut() {
+            let Some(section) = section.as_table_mut() else {
+                continue;
+            };
+            if section.get("name").and_then(Value::as_str) != Some(section_name) {
+                continue;
+            }
+            found = true;
+            if let Some(items) = section.get_mut("items").and_then(Value::as_array_mut) {
+                items.sort_by_key(|item| {
+                    let key = item.get("key").and_then(Value::as_str).map(str::trim);
+                    keys.iter()
+                        .position(|wanted| Some(wanted.as_str()) == key)
+                        .unwrap_or(usize::MAX)
+                });
+            }
+        }
+        if !found {
+            return Err(anyhow!("section not found: {section_name}"));
+        }
+        self.save()
+    }
+
+    /// Clears the saved selections of every section, as `Reset` does.
+    pub fn clear_selections(&mut self) -> Result<()> {
+        self.backup_before("reset")?;
+        for (_, section) in self.ensure_state_table_mut().iter_mut() {
+            *section = Value::Table(Map::new());
+        }
+        self.save()
+    }
+
+    /// The effective `[[sections]]` with their items, as the config export
+    /// writes them.
+    pub fn export_sections(&self) -> Vec<Value> {
+        self.doc
+            .get("sections")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Adds the sections, items and choices of `incoming` this config lacks
+    /// (see `config_exchange::merge_missing`); saves only when something was
+    /// added.
+    pub fn import_sections(&mut self, incoming: &[Value]) -> Result<MergeSummary> {
+        let mut sections = self.export_sections();
+        let summary = merge_missing(&mut sections, incoming);
+        if !summary.is_empty() {
+            self.backup_before("import")?;
+            *self.ensure_sections_array_mut() = sections;
+            self.save()?;
+        }
+        Ok(summary)
+    }
+
+    /// Names of the `[presets]`, sorted.
+    pub fn preset_names(&self) -> Vec<String> {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("presets"))
+            .and_then(Value::as_table)
+            .map(|presets| {
+                presets
+                    .iter()
+                    .filter(|(_, preset)| preset.is_table())
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Stores every item's selection, free text and weight as
+    /// `[presets.<name>.<section>]`, replacing a preset of the same name.
+    pub fn save_preset(&mut self, name: &str) -> Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("preset name is empty"));
+        }
+        let mut captured = Map::new();
+        for (section_name, section_state) in self.ensure_state_table_mut().iter() {
+            let Some(values) = section_state.as_table() else {
+                continue;
+            };
+            let values: Map<String, Value> = values
+                .iter()
+                .filter(|(state_key, _)| is_preset_key(state_key))
+                .map(|(state_key, value)| (state_key.clone(), value.clone()))
+                .collect();
+            if !values.is_empty() {
+                captured.insert(section_name.clone(), Value::Table(values));
+            }
+        }
+        self.presets_table_mut()
+            .insert(name.to_string(), Value::Table(captured));
+        self.save()
+    }
+
+    /// Replaces the selections, free texts and weights with the preset's;
+    /// items the preset does not mention go back to `指定なし`. Drafts are
+    /// dropped and locks kept.
+    pub fn apply_preset(&mut self, name: &str) -> Result<()> {
+        let name = name.trim();
+        let preset = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("presets"))
+            .and_then(Value::as_table)
+            .and_then(|presets| presets.get(name))
+            .and_then(Value::as_table)
+            .cloned()
+            .ok_or_else(|| anyhow!("preset not found: {name}"))?;
+        self.backup_before("apply_preset")?;
+
+        for (_, section_state) in self.ensure_state_table_mut().iter_mut() {
+            if let Some(values) = section_state.as_table_mut() {
+                values.retain(|state_key, _| {
+                    !is_preset_key(state_key) && !state_key.ends_with("_draft")
+                });
+            }
+        }
+        for (section_name, values) in preset {
+            let Value::Table(values) = values else {
+                continue;
+            };
+            let section_state = self.ensure_section_state_mut(&section_name);
+            for (state_key, value) in values {
+                if is_preset_key(&state_key) {
+                    section_state.insert(state_key, value);
+                }
+            }
+        }
+        self.save()
+    }
+
+    pub fn delete_preset(&mut self, name: &str) -> Result<()> {
+        let name = name.trim();
+        if !self.preset_names().iter().any(|known| known == name) {
+            return Err(anyhow!("preset not found: {name}"));
+        }
+        self.backup_before("delete_preset")?;
+        if self.presets_table_mut().remove(name).is_none() {
+            return Err(anyhow!("preset not found: {name}"));
+        }
+        self.save()
+    }
+
+    /// Adds `SAMPLE_PRESET_NAME`, selecting the first choice of every item in
+    /// the first section, unless a preset of that name exists. Returns
+    /// whether it was added.
+    pub fn seed_sample_preset(&mut self) -> Result<bool> {
+        if self
+            .preset_names()
+            .iter()
+            .any(|name| name == SAMPLE_PRESET_NAME)
+        {
+            return Ok(false);
+        }
+        let Some(section) = self.sections().into_iter().next() else {
+            return Ok(false);
+        };
+        let values: Map<String, Value> = section
+            .items
+            .iter()
+            .filter_map(|item| {
+                let choice = item.choices.iter().find(|choice| *choice != NO_SELECTION)?;
+                Some((
+                    format!("{}_selected", item.key),
+                    Value::String(choice.clone()),
+                ))
+            })
+            .collect();
+        if values.is_empty() {
+            return Ok(false);
+        }
+
+        let mut preset = Map::new();
+        preset.insert(SAMPLE_PRESET_MARK.to_string(), Value::Boolean(true));
+        preset.insert(section.name, Value::Table(values));
+        self.presets_table_mut()
+            .insert(SAMPLE_PRESET_NAME.to_string(), Value::Table(preset));
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Deletes the preset `seed_sample_preset` added, unless the user has
+    /// saved over it. Returns whether it was deleted.
+    pub fn delete_sample_preset(&mut self) -> Result<bool> {
+        let is_sample = self
+            .doc
+            .get("presets")
+            .and_then(|presets| presets.get(SAMPLE_PRESET_NAME))
+            .and_then(|preset| preset.get(SAMPLE_PRESET_MARK))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if !is_sample {
+            return Ok(false);
+        }
+        self.presets_table_mut().remove(SAMPLE_PRESET_NAME);
+        self.save()?;
+        Ok(true)
+    }
+
+    fn presets_table_mut(&mut self) -> &mut Map<String, Value> {
+        let root = self.root_table_mut();
+        let presets = root
+            .entry("presets".to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !presets.is_table() {
+            *presets = Value::Table(Map::new());
+        }
+        presets
+            .as_table_mut()
+            .expect("presets should be table after normalization")
+    }
+
+    /// Parses `[[housekeeping]]`; invalid rules are skipped and reported as errors.
+    pub fn housekeeping_rules(&self) -> (Vec<HousekeepingRule>, Vec<String>) {
+        let mut rules = Vec::new();
+        let mut errors = Vec::new();
+        let tables = self
+            .doc
+            .get("housekeeping")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for (index, table) in tables.iter().enumerate() {
+            match HousekeepingRule::from_toml(index, table) {
+                Ok(rule) => rules.push(rule),
+                Err(err) => errors.push(err.to_string()),
+            }
+        }
+        (rules, errors)
+    }
+
+    /// Reports duplicate keys, colliding labels and state entries without a matching item.
+    pub fn lint(&self) -> Vec<ConfigIssue> {
+        let items = self.all_item_keys();
+        let mut issues = Vec::new();
+
+        for message in self.housekeeping_rules().1 {
+            issues.push(ConfigIssue {
+                kind: "invalid_rule",
+                section: "housekeeping".to_string(),
+                key: String::new(),
+                message,
+            });
+        }
+
+        if let Some(value) = self
+            .app_table()
+            .and_then(|t| t.get("dedupe_history"))
+            .and_then(Value::as_str)
+        {
+            if DedupeMode::parse(value).is_none() {
+                issues.push(ConfigIssue {
+                    kind: "invalid_value",
+                    section: "app".to_string(),
+                    key: "dedupe_history".to_string(),
+                    message: format!(
+                        "dedupe_history `{value}` is not one of off / skip / bump / count"
+                    ),
+                });
+            }
+        }
+
+        if let Some(value) = self
+            .app_table()
+            .and_then(|t| t.get("history_archive_grouping"))
+            .and_then(Value::as_str)
+        {
+            if ArchiveGrouping::parse(value).is_none() {
+                issues.push(ConfigIssue {
+                    kind: "invalid_value",
+                    section: "app".to_string(),
+                    key: "history_archive_grouping".to_string(),
+                    message: format!(
+                        "history_archive_grouping `{value}` is not one of day / month"
+                    ),
+                });
+            }
+        }
+
+        for key in ["score_command", "translate_command", "vision_command"] {
+            let Some(value) = self.app_table().and_then(|t| t.get(key)) else {
+                continue;
+            };
+            let valid = value
+                .as_array()
+                .is_some_and(|args| args.iter().all(Value::is_str));
+            if !valid {
+                issues.push(ConfigIssue {
+                    kind: "invalid_value",
+                    section: "app".to_string(),
+                    key: key.to_string(),
+                    message: format!("{key} must be an array of strings (program and arguments)"),
+                });
+            }
+        }
+
+        for (index, (section, key, label)) in items.iter().enumerate() {
+            for (other_section, other_key, other_label) in &items[..index] {
+                if key == other_key {
+                    let message = if section == other_section {
+                        format!("key `{key}` is defined more than once in section `{section}`")
+                    } else {
+                        format!("key `{key}` is used in both `{other_section}` and `{section}`")
+                    };
+                    issues.push(ConfigIssue {
+                        kind: "duplicate_key",
+                        section: section.clone(),
+                        key: key.clone(),
+                        message,
+                    });
+                }
+                if label == other_label {
+                    issues.push(ConfigIssue {
+                        kind: "duplicate_label",
+                        section: section.clone(),
+                        key: key.clone(),
+                        message: format!(
+                            "label `{label}` is shared by `{other_section}:{other_key}` and `{section}:{key}`"
+                        ),
+                    });
+                }
+            }
+        }
+
+        for (section, state_key) in self.orphaned_state_keys() {
+            let message = if state_key.is_empty() {
+                format!("state for section `{section}` has no matching section")
+            } else {
+                format!("state `{section}.{state_key}` has no matching item")
+            };
+            issues.push(ConfigIssue {
+                kind: "orphaned_state",
+                section,
+                key: state_key,
+                message,
+            });
+        }
+
+        issues
+    }
+
+    pub fn cleanup_orphaned_state(&mut self) -> Result<usize> {
+        let orphaned = self.orphaned_state_keys();
+        if orphaned.is_empty() {
+            return Ok(0);
+        }
+        self.backup_before("cleanup")?;
+
+        let state = self.ensure_state_table_mut();
+        for (section, state_key) in &orphaned {
+            if state_key.is_empty() {
+                state.remove(section);
+            } else if let Some(table) = state.get_mut(section).and_then(Value::as_table_mut) {
+                table.remove(state_key);
+            }
+        }
+
+        self.save()?;
+        Ok(orphaned.len())
+    }
+
+    fn all_item_keys(&self) -> Vec<(String, String, String)> {
+        let mut keys = Vec::new();
+        let sections = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("sections"))
+            .and_then(Value::as_array);
+        for section in sections.into_iter().flatten().filter_map(Value::as_table) {
+            let Some(name) = section.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            let items = section.get("items").and_then(Value::as_array);
+            for item in items.into_iter().flatten().filter_map(Value::as_table) {
+                let key = item
+                    .get("key")
+                    .and_then(Value::as_str)
+                    .map(str::trim)
+                    .unwrap_or_default();
+                if key.is_empty() {
+                    continue;
+                }
+                let label = item.get("label").and_then(Value::as_str).unwrap_or(key);
+                keys.push((name.to_string(), key.to_string(), label.to_string()));
+            }
+        }
+        keys
+    }
+
+    /// `(section, state_key)` pairs; an empty key stands for a whole unknown section.
+    fn orphaned_state_keys(&self) -> Vec<(String, String)> {
+        let items = self.all_item_keys();
+        let mut orphaned = Vec::new();
+        let Some(state) = self
+            .doc
+            .as_table()
+            .and_then(|root| root.get("state"))
+            .and_then(Value::as_table)
+        else {
+            return orphaned;
+        };
+
+        for (section, section_state) in state {
+            if !items.iter().any(|(name, _, _)| name == section) {
+                orphaned.push((section.clone(), String::new()));
+                continue;
+            }
+            let Some(table) = section_state.as_table() else {
+                continue;
+            };
+            for state_key in table.keys() {
+                let known = STATE_KEY_SUFFIXES.iter().any(|suffix| {
+                    state_key.strip_suffix(suffix).is_some_and(|key| {
+                        items
+                            .iter()
+                            .any(|(name, item_key, _)| name == section && item_key == key)
+                    })
+                });
+                if !known {
+                    orphaned.push((section.clone(), state_key.clone()));
+                }
+            }
+        }
+
+        orphaned
+    }
+
+    fn normalize_doc(&mut self) {
+        if !self.doc.is_table() {
+            self.doc = Value::Table(Map::new());
+        }
+
+        {
+            let app = self.ensure_app_table_mut();
+
+            if app.get("delimiter").and_then(Value::as_str).is_none() {
+                app.insert("delimiter".to_string(), Value::String(", ".to_string()));
+            }
+
+            if app.get("confirm_delete").and_then(Value::as_bool).is_none() {
+                app.insert("confirm_delete".to_string(), Value::Boolean(true));
+            }
+
+            let debounce = app
+                .get("copy_debounce_sec")
+                .and_then(value_to_f64)
+                .filter(|v| *v >= 0.0)
+                .unwrap_or(2.0);
+            app.insert("copy_debounce_sec".to_string(), Value::Float(debounce));
+
+            let port = app
+                .get("history_server_port")
+                .and_then(value_to_i64)
+                .filter(|v| (1..=65_535).contains(v))
+                .unwrap_or(3000);
+            app.insert("history_server_port".to_string(), Value::Integer(port));
+
+            let lan_port = app
+                .get("history_server_lan_port")
+                .and_then(value_to_i64)
+                .filter(|v| (0..=65_535).contains(v))
+                .unwrap_or(0);
+            app.insert(
+                "history_server_lan_port".to_string(),
+                Value::Integer(lan_port),
+            );
+
+            for key in [
+                "history_archive_after_days",
+                "history_archive_compact_threshold",
+                "history_archive_max_mb",
+                "history_retention_months",
+            ] {
+                let value = app
+                    .get(key)
+                    .and_then(value_to_i64)
+                    .filter(|v| *v >= 0)
+                    .unwrap_or(0);
+                app.insert(key.to_string(), Value::Integer(value));
+            }
+
+            if app
+                .get("history_archive_grouping")
+                .and_then(Value::as_str)
+                .is_none()
+            {
+                app.insert(
+                    "history_archive_grouping".to_string(),
+                    Value::String("day".to_string()),
+                );
+            }
+
+            let archive_page_size = app
+                .get("history_archive_page_size")
+                .and_then(value_to_i64)
+                .filter(|v| *v >= 0)
+                .unwrap_or(0);
+            app.insert(
+                "history_archive_page_size".to_string(),
+                Value::Integer(archive_page_size),
+            );
+
+            if app
+                .get("history_confirm_delete")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("history_confirm_delete".to_string(), Value::Boolean(true));
+            }
+
+            if app
+                .get("history_delete_image_files")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert(
+                    "history_delete_image_files".to_string(),
+                    Value::Boolean(false),
+                );
+            }
+
+            if app
+                .get("history_encryption")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("history_encryption".to_string(), Value::Boolean(false));
+            }
+
+            let max_entries = app
+                .get("history_max_entries")
+                .and_then(value_to_i64)
+                .filter(|v| *v > 0)
+                .unwrap_or(300);
+            app.insert(
+                "history_max_entries".to_string(),
+                Value::Integer(max_entries),
+            );
+
+            let max_images = app
+                .get("history_max_images")
+                .and_then(value_to_i64)
+                .filter(|v| *v > 0)
+                .unwrap_or(4);
+            app.insert("history_max_images".to_string(), Value::Integer(max_images));
+
+            let warn_margin = app
+                .get("history_rotation_warn_margin")
+                .and_then(value_to_i64)
+                .filter(|v| *v >= 0)
+                .unwrap_or(5);
+            app.insert(
+                "history_rotation_warn_margin".to_string(),
+                Value::Integer(warn_margin),
+            );
+
+            if app.get("dedupe_history").and_then(Value::as_str).is_none() {
+                app.insert(
+                    "dedupe_history".to_string(),
+                    Value::String("off".to_string()),
+                );
+            }
+
+            let trash_retention = app
+                .get("history_trash_retention_days")
+                .and_then(value_to_i64)
+                .filter(|v| *v >= 0)
+                .unwrap_or(30);
+            app.insert(
+                "history_trash_retention_days".to_string(),
+                Value::Integer(trash_retention),
+            );
+
+            if app.get("output_format").and_then(Value::as_str).is_none() {
+                app.insert(
+                    "output_format".to_string(),
+                    Value::String(DEFAULT_OUTPUT_FORMAT.to_string()),
+                );
+            }
+
+            if app
+                .get("mini_click_through")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("mini_click_through".to_string(), Value::Boolean(false));
+            }
+
+            if app
+                .get("onboarding_samples")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("onboarding_samples".to_string(), Value::Boolean(true));
+            }
+
+            if app
+                .get("png_embed_parameters")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("png_embed_parameters".to_string(), Value::Boolean(false));
+            }
+
+            if app
+                .get("random_weighted")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("random_weighted".to_string(), Value::Boolean(false));
+            }
+
+            if app
+                .get("sort_choices_by_usage")
+                .and_then(Value::as_bool)
+                .is_none()
+            {
+                app.insert("sort_choices_by_usage".to_string(), Value::Boolean(false));
+            }
+
+            let startup = app
+                .entry("startup".to_string())
+                .or_insert_with(|| Value::Table(Map::new()));
+            if !startup.is_table() {
+                *startup = Value::Table(Map::new());
+            }
+            let startup = startup
+                .as_table_mut()
+                .expect("startup should be table after normalization");
+            for key in ["check_integrations", "new_session", "open_history"] {
+                if startup.get(key).and_then(Value::as_bool).is_none() {
+                    startup.insert(key.to_string(), Value::Boolean(false));
+                }
+            }
+        }
+
+        {
+            let defaults = Features::default();
+            let features = self
+                .root_table_mut()
+                .entry("features".to_string())
+                .or_insert_with(|| Value::Table(Map::new()));
+            if !features.is_table() {
+                *features = Value::Table(Map::new());
+            }
+            let features = features
+                .as_table_mut()
+                .expect("features should be table after normalization");
+            for (key, enabled) in [
+                ("analytics", defaults.analytics),
+                ("integrations", defaults.integrations),
+                ("lan", defaults.lan),
+            ] {
+                if features.get(key).and_then(Value::as_bool).is_none() {
+                    features.insert(key.to_string(), Value::Boolean(enabled));
+                }
+            }
+        }
+
+        {
+            let sections = self.ensure_sections_array_mut();
+            for section_value in sections.iter_mut() {
+                if !section_value.is_table() {
+                    *section_value = Value::Table(Map::new());
+                }
+                let section = section_value
+                    .as_table_mut()
+                    .expect("section should be table after normalization");
+
+                let name = section
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .unwrap_or("prompt")
+                    .to_string();
+                section.insert("name".to_string(), Value::String(name.clone()));
+
+                let label = section
+                    .get("label")
+                    .and_then(Value::as_str)
+                    .map(ToOwned::to_owned)
+                    .unwrap_or_else(|| name.clone());
+                section.insert("label".to_string(), Value::String(label));
+
+                let items_value = section
+                    .entry("items".to_string())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if !items_value.is_array() {
+                    *items_value = Value::Array(Vec::new());
+                }
+
+                if let Some(items) = items_value.as_array_mut() {
+                    for item_value in items.iter_mut() {
+                        if !item_value.is_table() {
+                            *item_value = Value::Table(Map::new());
+                        }
+                        let item = item_value
+                            .as_table_mut()
+                            .expect("item should be table after normalization");
+
+                        let key = item
+                            .get("key")
+                            .map(value_to_text)
+                            .map(|v| v.trim().to_string())
+                            .unwrap_or_default();
+                        item.insert("key".to_string(), Value::String(key.clone()));
+
+                        let label = item
+                            .get("label")
+                            .and_then(Value::as_str)
+                            .map(ToOwned::to_owned)
+                            .unwrap_or_else(|| key.clone());
+                        item.insert("label".to_string(), Value::String(label.clone()));
+
+                        let allow_free_text = item
+                            .get("allow_free_text")
+                            .and_then(Value::as_bool)
+                            .unwrap_or(true);
+                        item.insert(
+                            "allow_free_text".to_string(),
+                            Value::Boolean(allow_free_text),
+                        );
+
+                        let template = item
+                            .get("template")
+                            .and_then(Value::as_str)
+                            .map(ToOwned::to_owned)
+                            .unwrap_or_else(|| default_template(&label));
+                        item.insert("template".to_string(), Value::String(template));
+
+                        let choices = normalize_choices_from_value(item.get("choices"));
+                        item.insert("choices".to_string(), choices_to_value(&choices));
+                    }
+                }
+            }
+        }
+
+        self.ensure_state_table_mut();
+        let root = self.root_table_mut();
+        root.remove(CONFIG_VERSION_ALIAS);
+        root.insert(
+            "schema_version".to_string(),
+            Value::Integer(i64::from(CONFIG_SCHEMA_VERSION)),
+        );
+        self.reorder_root_tables();
+    }
+
+    /// `[variables]` entries usable as `{name}` in templates.
+    pub fn template_variables(&self) -> BTreeMap<String, String> {
+        self.doc
+            .get("variables")
+            .and_then(Value::as_table)
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        let text = match value {
+                            Value::String(text) => text.clone(),
+                            Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => {
+                                value.to_string()
+                            }
+                            _ => return None,
+                        };
+                        Some((key.clone(), text))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn app_table(&self) -> Option<&Map<String, Value>> {
+        self.doc
+            .as_table()
+            .and_then(|root| root.get("app"))
+            .and_then(Value::as_table)
+    }
+
+    fn root_table_mut(&mut self) -> &mut Map<String, Value> {
+        if !self.doc.is_table() {
+            self.doc = Value::Table(Map::new());
+        }
+        self.doc
+            .as_table_mut()
+            .expect("root should be table after normalization")
+    }
+
+    fn ensure_app_table_mut(&mut self) -> &mut Map<String, Value> {
+        let root = self.root_table_mut();
+        let app = root
+            .entry("app".to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !app.is_table() {
+            *app = Value::Table(Map::new());
+        }
+        app.as_table_mut()
+            .expect("app should be table after normalization")
+    }
+
+    fn ensure_sections_array_mut(&mut self) -> &mut Vec<Value> {
+        let root = self.root_table_mut();
+        let sections = root
+            .entry("sections".to_string())
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if !sections.is_array() {
+            *sections = Value::Array(Vec::new());
+        }
+        sections
+            .as_array_mut()
+            .expect("sections should be array after normalization")
+    }
+
+    fn ensure_state_table_mut(&mut self) -> &mut Map<String, Value> {
+        let root = self.root_table_mut();
+        let state = root
+            .entry("state".to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !state.is_table() {
+            *state = Value::Table(Map::new());
+        }
+        state
+            .as_table_mut()
+            .expect("state should be table after normalization")
+    }
+
+    fn ensure_section_state_mut(&mut self, section_name: &str) -> &mut Map<String, Value> {
+        let state = self.ensure_state_table_mut();
+        let section = state
+            .entry(section_name.to_string())
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !section.is_table() {
+            *section = Value::Table(Map::new());
+        }
+        section
+            .as_table_mut()
+            .expect("section state should be table after normalization")
+    }
+
+    fn reorder_root_tables(&mut self) {
+        let root = self.root_table_mut();
+        let mut reordered = Map::new();
+
+        for key in ["app", "sections", "state"] {
+            if let Some(value) = root.remove(key) {
+                reordered.insert(key.to_string(), value);
+            }
+        }
+
+        let remaining_keys: Vec<String> = root.keys().cloned().collect();
+        for key in remaining_keys {
+            if let Some(value) = root.remove(&key) {
+                reordered.insert(key, value);
+            }
+        }
+
+        *root = reordered;
+    }
+
+    fn find_item_table_mut(
+        &mut self,
+        section_name: &str,
+        key: &str,
+    ) -> Option<&mut Map<String, Value>> {
+        let sections = self.ensure_sections_array_mut();
+        for section_value in sections.iter_mut() {
+            let Some(section) = section_value.as_table_mut() else {
+                continue;
+            };
+            let Some(name) = section.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            if name != section_name {
+                continue;
+            }
+
+            let Some(items) = section.get_mut("items").and_then(Value::as_array_mut) else {
+                continue;
+            };
+            for item_value in items.iter_mut() {
+                let Some(item) = item_value.as_table_mut() else {
+                    continue;
+                };
+                if item.get("key").and_then(Value::as_str) == Some(key) {
+                    return Some(item);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// `schema_version`, or `config_version` when only that is written.
+fn config_schema_version(doc: &Value) -> u32 {
+    doc.get("schema_version")
+        .or_else(|| doc.get(CONFIG_VERSION_ALIAS))
+        .and_then(value_to_i64)
+        .and_then(|v| u32::try_from(v).ok())
+        .unwrap_or(0)
+}
+
+/// The line used before templates were rendered: `[label]：{value}`.
+fn default_template(label: &str) -> String {
+    format!(
+        "[{}]：{{value}}",
+        label.replace('{', "{{").replace('}', "}}")
+    )
+}
+
+fn introduce_schema_version(_doc: &mut Value) -> Result<()> {
+    // Version 0 files only lack the marker; normalize_doc stamps the current version.
+    Ok(())
+}
+
+fn normalize_choices_from_value(value: Option<&Value>) -> Vec<String> {
+    let mut normalized = Vec::new();
+    if let Some(Value::Array(items)) = value {
+        for item in items {
+            let text = value_to_text(item).trim().to_string();
+            if !text.is_empty() && !normalized.iter().any(|existing| existing == &text) {
+                normalized.push(text);
+            }
+        }
+    }
+
+    normalized.retain(|v| v != NO_SELECTION);
+    normalized.insert(0, NO_SELECTION.to_string());
+    normalized
+}
+
+fn is_preset_key(state_key: &str) -> bool {
+    PRESET_KEY_SUFFIXES
+        .iter()
+        .any(|suffix| state_key.ends_with(suffix))
+}
+
+fn choices_to_value(choices: &[String]) -> Value {
+    Value::Array(choices.iter().cloned().map(Value::String).collect())
+}
+
+fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::String(v) => v.clone(),
+        Value::Integer(v) => v.to_string(),
+        Value::Float(v) => v.to_string(),
+        Value::Boolean(v) => v.to_string(),
+        Value::Datetime(v) => v.to_string(),
+        Value::Array(v) => format!("{:?}", v),
+        Value::Table(v) => format!("{:?}", v),
+    }
+}
+
+fn value_to_f64(value: &Value) -> Option<f64> {
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|v| v as f64))
+        .or_else(|| value.as_str().and_then(|v| v.parse::<f64>().ok()))
+}
+
+fn value_to_i64(value: &Value) -> Option<i64> {
+    value
+        .as_integer()
+        .or_else(|| value.as_float().map(|v| v as i64))
+        .or_else(|| value.as_str().and_then(|v| v.parse::<i64>().ok()))
+}
+
+fn move_app_table_to_top(serialized: &str) -> String {
+    let ends_with_newline = serialized.ends_with('\n');
+    let lines: Vec<&str> = serialized.split('\n').collect();
+    let header_starts: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, line)| is_top_level_header_line(line).then_some(index))
+        .collect();
+
+    if header_starts.is_empty() {
+        return serialized.to_string();
+    }
+
+    let first_header = header_starts[0];
+    let mut app_block_range: Option<(usize, usize)> = None;
+    for (i, start) in header_starts.iter().enumerate() {
+        if lines[*start].trim() == "[app]" {
+            let end = header_starts.get(i + 1).copied().unwrap_or(lines.len());
+            app_block_range = Some((*start, end));
+            break;
+        }
+    }
+
+    let Some((app_start, app_end)) = app_block_range else {
+        return serialized.to_string();
+    };
+
+    if app_start == first_header {
+        return serialized.to_string();
+    }
+
+    let mut rebuilt: Vec<&str> = Vec::with_capacity(lines.len());
+    rebuilt.extend_from_slice(&lines[..first_header]);
+    rebuilt.extend_from_slice(&lines[app_start..app_end]);
+
+    for (i, start) in header_starts.iter().enumerate() {
+        let end = header_starts.get(i + 1).copied().unwrap_or(lines.len());
+        if *start == app_start {
+            continue;
+        }
+        rebuilt.extend_from_slice(&lines[*start..end]);
+    }
+
+    let mut output = rebuilt.join("\n");
+    if ends_with_newline {
+        output.push('\n');
+    }
+    output
+}
+
+fn is_top_level_header_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || !trimmed.starts_with('[') || !trimmed.ends_with(']') {
+        return false;
+    }
+    !trimmed.contains(" = ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AppSettingsUpdate, ConfigStore, Features, FREE_TEXT_HISTORY_LIMIT, ITEM_WEIGHT_MAX,
+        SAMPLE_PRESET_NAME,
+    };
+    use crate::config_backups::list_backups;
+    use crate::NO_SELECTION;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// In a folder of its own, which also holds the `config_backups` the
+    /// tests write.
+    fn fixture_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ipg_config_store_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("fixture dir");
+        dir.join(format!("{name}.toml"))
+    }
+
+    #[test]
+    fn normalizes_and_persists_choices() {
+        let path = fixture_path("normalize");
+        fs::write(
+            &path,
+            r#"
+[app]
+copy_debounce_sec = -1
+
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["robot", "", "指定なし", "robot", "cat"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let items = store.get_items("prompt");
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].choices[0], NO_SELECTION);
+        assert_eq!(items[0].choices[1], "robot");
+        assert_eq!(items[0].choices[2], "cat");
+
+        let added = store
+            .add_choice("prompt", "subject", "wolf")
+            .expect("add choice");
+        assert!(added);
+
+        let removed = store
+            .remove_choice("prompt", "subject", "cat")
+            .expect("remove choice");
+        assert!(removed);
+
+        let items2 = store.get_items("prompt");
+        assert_eq!(items2[0].choices, vec!["指定なし", "robot", "wolf"]);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn sections_keep_file_order_and_reset_together() {
+        let path = fixture_path("sections");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+label = "プロンプト"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "robot"]
+
+[[sections]]
+name = "negative"
+label = "ネガティブ"
+template = "Negative prompt: {value}"
+
+  [[sections.items]]
+  key = "avoid"
+  choices = ["指定なし", "blurry"]
+  template = "{value}"
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let sections = store.sections();
+        assert_eq!(
+            sections
+                .iter()
+                .map(|s| (s.name.as_str(), s.template.as_str(), s.items.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("prompt", "{value}", 1),
+                ("negative", "Negative prompt: {value}", 1)
+            ]
+        );
+        assert_eq!(store.all_items()[1].item_id(), "negative:avoid");
+
+        store
+            .set_item_state("prompt", "subject", "robot", "")
+            .expect("select subject");
+        store
+            .set_item_state("negative", "avoid", "blurry", "")
+            .expect("select avoid");
+        assert_eq!(store.all_selections().len(), 2);
+        store.clear_selections().expect("reset");
+        assert!(store.all_selections().is_empty());
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn filters_items_by_output_format() {
+        let path = fixture_path("output_format");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "robot"]
+
+  [[sections.items]]
+  key = "aspect"
+  choices = ["指定なし", "--ar 16:9"]
+  formats = ["midjourney"]
+  param = "--ar"
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert_eq!(store.output_format(), "labeled");
+        assert_eq!(
+            store.output_formats(),
+            vec!["labeled", "comma", "sdxl", "midjourney"]
+        );
+
+        let items = store.get_items("prompt");
+        assert!(items[0].participates_in("labeled"));
+        assert!(!items[1].participates_in("labeled"));
+        assert!(items[1].participates_in("midjourney"));
+        assert_eq!(items[1].param, "ar");
+
+        assert!(store.selections("prompt").is_empty());
+        store
+            .set_item_state("prompt", "subject", "robot", "")
+            .expect("select subject");
+        store
+            .set_item_state("prompt", "aspect", NO_SELECTION, "--ar 3:2")
+            .expect("free text aspect");
+        let selections = store.selections("prompt");
+        assert_eq!(selections.get("subject").map(String::as_str), Some("robot"));
+        assert_eq!(
+            selections.get("aspect").map(String::as_str),
+            Some("--ar 3:2")
+        );
+
+        store.set_output_format("midjourney").expect("set format");
+        assert!(store.set_output_format("  ").is_err());
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.output_format(), "midjourney");
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn item_weights_and_locks_are_stored_only_when_set() {
+        let path = fixture_path("item_weight");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "cat"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert_eq!(store.get_item_weight("prompt", "subject"), 1.0);
+        store
+            .set_item_weight("prompt", "subject", 1.333)
+            .expect("set weight");
+        assert_eq!(store.get_item_weight("prompt", "subject"), 1.33);
+        store
+            .set_item_weight("prompt", "subject", 9.0)
+            .expect("clamp weight");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(
+            reloaded.get_item_weight("prompt", "subject"),
+            ITEM_WEIGHT_MAX
+        );
+        assert!(reloaded.lint().is_empty(), "weights are known state");
+        assert!(store
+            .set_item_weight("prompt", "subject", f64::NAN)
+            .is_err());
+
+        store
+            .set_item_weight("prompt", "subject", 1.0)
+            .expect("reset weight");
+        assert!(!fs::read_to_string(&path)
+            .expect("read config")
+            .contains("subject_weight"));
+
+        assert!(!store.get_item_locked("prompt", "subject"));
+        store
+            .set_item_locked("prompt", "subject", true)
+            .expect("lock");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert!(reloaded.get_item_locked("prompt", "subject"));
+        assert!(reloaded.lint().is_empty(), "locks are known state");
+        store
+            .set_item_locked("prompt", "subject", false)
+            .expect("unlock");
+        assert!(!fs::read_to_string(&path)
+            .expect("read config")
+            .contains("subject_locked"));
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn reordered_items_are_saved_in_the_new_order() {
+        let path = fixture_path("reorder_items");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+
+  [[sections.items]]
+  key = "style"
+
+  [[sections.items]]
+  key = "lighting"
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .reorder_items("prompt", &["lighting".to_string(), "subject".to_string()])
+            .expect("reorder");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        let keys: Vec<String> = reloaded
+            .get_items("prompt")
+            .into_iter()
+            .map(|item| item.key)
+            .collect();
+        assert_eq!(keys, vec!["lighting", "subject", "style"]);
+        assert!(store.reorder_items("missing", &[]).is_err());
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn free_text_history_is_newest_first_and_survives_reset() {
+        let path = fixture_path("free_text_history");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  allow_free_text = true
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        for value in ["red fox", "blue bird", "red fox", "  "] {
+            store
+                .remember_free_text("prompt", "subject", value)
+                .expect("remember");
+        }
+        assert_eq!(
+            store.free_text_history("prompt", "subject"),
+            vec!["red fox", "blue bird"]
+        );
+        for n in 0..30 {
+            store
+                .remember_free_text("prompt", "subject", &format!("v{n}"))
+                .expect("remember");
+        }
+        store.clear_selections().expect("reset");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        let history = reloaded.free_text_history("prompt", "subject");
+        assert_eq!(history.len(), FREE_TEXT_HISTORY_LIMIT);
+        assert_eq!(history[0], "v29");
+        assert!(reloaded.get_items("prompt")[0].choices.len() <= 1);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn renamed_choice_keeps_its_place_selection_and_pool() {
+        let path = fixture_path("rename_choice");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "cat", "dog", "fox"]
+  random_pool = ["dog", "fox"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_state("prompt", "subject", "dog", "")
+            .expect("select");
+        assert!(store
+            .rename_choice("prompt", "subject", "dog", " puppy ")
+            .expect("rename"));
+        assert!(!store
+            .rename_choice("prompt", "subject", "puppy", "puppy")
+            .expect("same name"));
+        assert!(store
+            .rename_choice("prompt", "subject", "cat", "fox")
+            .is_err());
+        assert!(store
+            .rename_choice("prompt", "subject", "wolf", "lynx")
+            .is_err());
+
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        let item = &reloaded.get_items("prompt")[0];
+        assert_eq!(item.choices, vec!["指定なし", "cat", "puppy", "fox"]);
+        assert_eq!(item.random_pool, vec!["puppy", "fox"]);
+        assert_eq!(reloaded.get_item_state("prompt", "subject").0, "puppy");
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn moved_choice_stays_after_no_selection() {
+        let path = fixture_path("move_choice");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "cat", "dog", "fox"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert!(store
+            .move_choice("prompt", "subject", "fox", 1)
+            .expect("to top"));
+        assert!(store.move_choice("prompt", "subject", "cat", 0).is_ok());
+        assert!(!store
+            .move_choice("prompt", "subject", "dog", 99)
+            .expect("already last"));
+        assert!(store
+            .move_choice("prompt", "subject", NO_SELECTION, 2)
+            .is_err());
+
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(
+            reloaded.get_items("prompt")[0].choices,
+            vec!["指定なし", "cat", "fox", "dog"]
+        );
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn copied_choices_are_counted_and_follow_renames() {
+        let path = fixture_path("choice_usage");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "cat", "dog"]
+
+  [[sections.items]]
+  key = "place"
+  choices = ["指定なし", "park"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_state("prompt", "subject", "cat", "")
+            .expect("select");
+        store
+            .set_item_state("prompt", "place", NO_SELECTION, "beach")
+            .expect("typed");
+        store.record_choice_usage().expect("first copy");
+        store.record_choice_usage().expect("second copy");
+        store
+            .rename_choice("prompt", "subject", "cat", "kitten")
+            .expect("rename");
+
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(
+            reloaded.choice_usage("prompt", "subject"),
+            BTreeMap::from([("kitten".to_string(), 2)])
+        );
+        assert!(reloaded.choice_usage("prompt", "place").is_empty());
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn items_carry_their_group_and_collapsed_groups_persist() {
+        let path = fixture_path("groups");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "hair"
+  group = " 人物 "
+  choices = ["指定なし", "long"]
+
+  [[sections.items]]
+  key = "place"
+  choices = ["指定なし", "park"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let items = store.get_items("prompt");
+        assert_eq!(items[0].group, "人物");
+        assert_eq!(items[1].group, "");
+        store
+            .set_group_collapsed("prompt", "人物", true)
+            .expect("collapse");
+        store
+            .set_group_collapsed("prompt", "背景", true)
+            .expect("collapse");
+        store
+            .set_group_collapsed("prompt", "背景", false)
+            .expect("expand");
+        assert!(store.set_group_collapsed("prompt", " ", true).is_err());
+
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.collapsed_groups("prompt"), vec!["人物"]);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn disabled_items_are_skipped_but_keep_their_state() {
+        let path = fixture_path("disabled_items");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "cat"]
+
+  [[sections.items]]
+  key = "place"
+  choices = ["指定なし", "park"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_state("prompt", "place", "park", "")
+            .expect("select");
+        store
+            .set_item_enabled("prompt", "place", false)
+            .expect("disable");
+        assert_eq!(store.get_items("prompt").len(), 1);
+        assert_eq!(store.disabled_items("prompt")[0].key, "place");
+        assert!(!store.all_selections().contains_key("place"));
+        assert!(store.lint().is_empty());
+
+        store
+            .set_item_enabled("prompt", "place", true)
+            .expect("enable");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.get_items("prompt").len(), 2);
+        assert_eq!(reloaded.get_item_state("prompt", "place").0, "park");
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn presets_restore_selections_and_keep_locks() {
+        let path = fixture_path("presets");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  allow_free_text = true
+  choices = ["指定なし", "cat", "dog"]
+
+  [[sections.items]]
+  key = "place"
+  choices = ["指定なし", "park"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_state("prompt", "subject", "cat", "")
+            .expect("select");
+        store
+            .set_item_weight("prompt", "subject", 1.3)
+            .expect("weight");
+        store.save_preset(" portrait ").expect("save preset");
+        assert!(store.save_preset("  ").is_err());
+
+        store
+            .set_item_state("prompt", "subject", "dog", "")
+            .expect("select");
+        store
+            .set_item_state("prompt", "place", "park", "")
+            .expect("select");
+        store
+            .set_item_locked("prompt", "place", true)
+            .expect("lock");
+        store
+            .set_item_draft("prompt", "subject", "unsent")
+            .expect("draft");
+        store.apply_preset("portrait").expect("apply preset");
+        assert!(store.apply_preset("landscape").is_err());
+
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.preset_names(), vec!["portrait"]);
+        assert_eq!(reloaded.get_item_state("prompt", "subject").0, "cat");
+        assert_eq!(reloaded.get_item_weight("prompt", "subject"), 1.3);
+        assert_eq!(reloaded.get_item_draft("prompt", "subject"), "");
+        assert_eq!(reloaded.get_item_state("prompt", "place").0, NO_SELECTION);
+        assert!(reloaded.get_item_locked("prompt", "place"));
+
+        store.delete_preset("portrait").expect("delete preset");
+        assert!(store.delete_preset("portrait").is_err());
+        assert!(store.preset_names().is_empty());
+
+        assert!(store.seed_sample_preset().expect("seed sample"));
+        assert!(!store.seed_sample_preset().expect("seed again"));
+        store
+            .apply_preset(SAMPLE_PRESET_NAME)
+            .expect("apply sample");
+        assert_eq!(store.get_item_state("prompt", "subject").0, "cat");
+        assert_eq!(store.get_item_state("prompt", "place").0, "park");
+        assert!(store.delete_sample_preset().expect("delete sample"));
+        assert!(store.preset_names().is_empty());
+        store.seed_sample_preset().expect("seed sample");
+        store
+            .save_preset(SAMPLE_PRESET_NAME)
+            .expect("save over sample");
+        assert!(
+            !store.delete_sample_preset().expect("keep user preset"),
+            "a preset the user saved over is kept"
+        );
+        assert_eq!(store.preset_names(), vec![SAMPLE_PRESET_NAME]);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn keeps_app_table_before_sections_after_save() {
+        let path = fixture_path("app_order");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "robot"]
+
+[app]
+history_server_port = 3000
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_state("prompt", "subject", NO_SELECTION, "")
+            .expect("set state");
+
+        let saved = fs::read_to_string(&path).expect("read saved");
+        let app_pos = saved.find("[app]").expect("app exists");
+        let sections_pos = saved.find("[[sections]]").expect("sections exists");
+        assert!(
+            app_pos < sections_pos,
+            "[app] should be before [[sections]] after save"
+        );
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn stamps_schema_version_and_backs_up_legacy_config() {
+        let path = fixture_path("schema");
+        fs::write(
+            &path,
+            r#"
+[app]
+history_server_port = 3000
+"#,
+        )
+        .expect("fixture write");
+
+        ConfigStore::new(path.clone()).expect("load store");
+
+        let saved = fs::read_to_string(&path).expect("read saved");
+        assert!(saved.starts_with("schema_version = 1\n"));
+
+        let dir = path.parent().expect("temp dir");
+        let backup_prefix = format!(
+            "{}.v0.",
+            path.file_stem().and_then(|v| v.to_str()).expect("stem")
+        );
+        let backups: Vec<_> = fs::read_dir(dir)
+            .expect("list temp dir")
+            .filter_map(|item| item.ok())
+            .filter(|item| {
+                item.file_name()
+                    .to_string_lossy()
+                    .starts_with(&backup_prefix)
+            })
+            .collect();
+        assert!(!backups.is_empty(), "legacy config should be backed up");
+
+        ConfigStore::new(path.clone()).expect("reload store");
+        for backup in backups {
+            fs::remove_file(backup.path()).ok();
+        }
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn config_version_is_read_as_schema_version() {
+        let path = fixture_path("config_version");
+        let backups = |path: &std::path::Path| {
+            let prefix = format!(
+                "{}.v0.",
+                path.file_stem().and_then(|v| v.to_str()).expect("stem")
+            );
+            fs::read_dir(path.parent().expect("temp dir"))
+                .expect("list temp dir")
+                .filter_map(|item| item.ok())
+                .filter(|item| item.file_name().to_string_lossy().starts_with(&prefix))
+                .map(|item| item.path())
+                .collect::<Vec<_>>()
+        };
+        let legacy = r#"
+config_version = 0
+
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["cat"]
+"#;
+        fs::write(&path, legacy).expect("fixture write");
+
+        let store = ConfigStore::new(path.clone()).expect("migrate store");
+        assert_eq!(
+            store.get_items("prompt")[0].choices,
+            vec![NO_SELECTION, "cat"]
+        );
+        let saved = fs::read_to_string(&path).expect("read saved");
+        assert!(saved.starts_with("schema_version = 1\n"));
+        assert!(!saved.contains("config_version"));
+        let migrated = backups(&path);
+        assert_eq!(migrated.len(), 1, "the version 0 file is backed up");
+        assert_eq!(fs::read_to_string(&migrated[0]).expect("backup"), legacy);
+        fs::remove_file(&migrated[0]).ok();
+
+        fs::write(&path, "config_version = 1\n").expect("current write");
+        ConfigStore::new(path.clone()).expect("current store");
+        assert!(backups(&path).is_empty(), "a current file is not migrated");
+
+        fs::write(&path, "config_version = 9\n").expect("newer write");
+        let err = ConfigStore::new(path.clone()).expect_err("newer version");
+        assert!(format!("{err:#}").contains("unsupported schema version 9"));
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn lint_reports_conflicts_and_cleanup_removes_orphaned_state() {
+        let path = fixture_path("lint");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  label = "被写体"
+
+  [[sections.items]]
+  key = "tone"
+  label = "被写体"
+
+[[sections]]
+name = "negative"
+
+  [[sections.items]]
+  key = "subject"
+  label = "除外"
+
+[state.prompt]
+subject_selected = "指定なし"
+removed_selected = "old"
+
+[state.gone]
+subject_selected = "old"
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let kinds: Vec<&str> = store.lint().iter().map(|issue| issue.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "duplicate_label",
+                "duplicate_key",
+                "orphaned_state",
+                "orphaned_state"
+            ]
+        );
+
+        assert_eq!(store.cleanup_orphaned_state().expect("cleanup"), 2);
+        let kinds: Vec<&str> = store.lint().iter().map(|issue| issue.kind).collect();
+        assert_eq!(kinds, vec!["duplicate_label", "duplicate_key"]);
+        let (selected, _) = store.get_item_state("prompt", "subject");
+        assert_eq!(selected, NO_SELECTION);
+
+        fs::remove_file(path).ok();
     }
 
-    normalized.retain(|v| v != NO_SELECTION);
-    normalized.insert(0, NO_SELECTION.to_string());
-    normalized
-}
+    #[test]
+    fn vision_command_reads_argv_and_lints_wrong_shape() {
+        let path = fixture_path("vision");
+        fs::write(
+            &path,
+            "[app]\nvision_command = [\"ocr.exe\", \"--lang\", \"ja\", \"{image}\"]\n",
+        )
+        .expect("fixture write");
+        let store = ConfigStore::new(path.clone()).expect("load store");
+        assert_eq!(
+            store.vision_command(),
+            vec!["ocr.exe", "--lang", "ja", "{image}"]
+        );
+        assert!(store.lint().is_empty());
 
-fn choices_to_value(choices: &[String]) -> Value {
-    Value::Array(choices.iter().cloned().map(Value::String).collect())
-}
+        fs::write(
+            &path,
+            "[app]\ntranslate_command = [\"trans\", 1]\nvision_command = \"ocr.exe {image}\"\n",
+        )
+        .expect("fixture write");
+        let store = ConfigStore::new(path.clone()).expect("reload store");
+        assert!(store.vision_command().is_empty());
+        assert_eq!(store.translate_command(), vec!["trans"]);
+        let keys: Vec<String> = store.lint().into_iter().map(|issue| issue.key).collect();
+        assert_eq!(keys, vec!["translate_command", "vision_command"]);
 
-fn value_to_text(value: &Value) -> String {
-    match value {
-        Value::String(v) => v.clone(),
-        Value::Integer(v) => v.to_string(),
-        Value::Float(v) => v.to_string(),
-        Value::Boolean(v) => v.to_string(),
-        Value::Datetime(v) => v.to_string(),
-        Value::Array(v) => format!("{:?}", v),
-        Value::Table(v) => format!("{:?}", v),
+        fs::remove_file(path).ok();
     }
-}
-
-fn value_to_f64(value: &Value) -> Option<f64> {
-    value
-        .as_float()
-        .or_else(|| value.as_integer().map(|v| v as f64))
-        .or_else(|| value.as_str().and_then(|v| v.parse::<f64>().ok()))
-}
 
-fn value_to_i64(value: &Value) -> Option<i64> {
-    value
-        .as_integer()
-        .or_else(|| value.as_float().map(|v| v as i64))
-        .or_else(|| value.as_str().and_then(|v| v.parse::<i64>().ok()))
-}
+    #[test]
+    fn drafts_persist_until_item_state_is_confirmed() {
+        let path = fixture_path("drafts");
+        fs::write(
+            &path,
+            r#"
+[[sections]]
+name = "prompt"
 
-fn move_app_table_to_top(serialized: &str) -> String {
-    let ends_with_newline = serialized.ends_with('\n');
-    let lines: Vec<&str> = serialized.split('\n').collect();
-    let header_starts: Vec<usize> = lines
-        .iter()
-        .enumerate()
-        .filter_map(|(index, line)| is_top_level_header_line(line).then_some(index))
-        .collect();
+  [[sections.items]]
+  key = "subject"
+  choices = ["指定なし", "robot"]
+"#,
+        )
+        .expect("fixture write");
 
-    if header_starts.is_empty() {
-        return serialized.to_string();
-    }
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store
+            .set_item_draft("prompt", "subject", "blue ro")
+            .expect("set draft");
 
-    let first_header = header_starts[0];
-    let mut app_block_range: Option<(usize, usize)> = None;
-    for (i, start) in header_starts.iter().enumerate() {
-        if lines[*start].trim() == "[app]" {
-            let end = header_starts.get(i + 1).copied().unwrap_or(lines.len());
-            app_block_range = Some((*start, end));
-            break;
-        }
-    }
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.get_item_draft("prompt", "subject"), "blue ro");
 
-    let Some((app_start, app_end)) = app_block_range else {
-        return serialized.to_string();
-    };
+        store
+            .set_item_state("prompt", "subject", "blue robot", "blue robot")
+            .expect("confirm");
+        assert_eq!(store.get_item_draft("prompt", "subject"), "");
 
-    if app_start == first_header {
-        return serialized.to_string();
+        fs::remove_file(path).ok();
     }
 
-    let mut rebuilt: Vec<&str> = Vec::with_capacity(lines.len());
-    rebuilt.extend_from_slice(&lines[..first_header]);
-    rebuilt.extend_from_slice(&lines[app_start..app_end]);
+    #[test]
+    fn writes_only_local_overrides_over_included_base() {
+        let dir = std::env::temp_dir().join(format!("ipg_config_layers_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("fixture dir");
+        let base_path = dir.join("base_config.txt");
+        let base_text = r#"
+[app]
+history_max_entries = 100
 
-    for (i, start) in header_starts.iter().enumerate() {
-        let end = header_starts.get(i + 1).copied().unwrap_or(lines.len());
-        if *start == app_start {
-            continue;
-        }
-        rebuilt.extend_from_slice(&lines[*start..end]);
-    }
+[[sections]]
+name = "prompt"
 
-    let mut output = rebuilt.join("\n");
-    if ends_with_newline {
-        output.push('\n');
-    }
-    output
-}
+  [[sections.items]]
+  key = "subject"
+  label = "被写体"
+  choices = ["robot", "cat"]
+"#;
+        fs::write(&base_path, base_text).expect("base write");
+        let path = dir.join("config.txt");
+        fs::write(&path, "include = \"base_config.txt\"\n").expect("local write");
 
-fn is_top_level_header_line(line: &str) -> bool {
-    let trimmed = line.trim();
-    if trimmed.is_empty() || !trimmed.starts_with('[') || !trimmed.ends_with(']') {
-        return false;
-    }
-    !trimmed.contains(" = ")
-}
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert_eq!(store.history_max_entries(), 100);
+        assert!(store
+            .add_choice("prompt", "subject", "dragon")
+            .expect("add"));
+        assert!(store
+            .remove_choice("prompt", "subject", "cat")
+            .expect("remove"));
+        assert_eq!(
+            store.get_items("prompt")[0].choices,
+            vec![NO_SELECTION, "robot", "dragon"]
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::ConfigStore;
-    use crate::NO_SELECTION;
-    use std::fs;
-    use std::path::PathBuf;
+        assert_eq!(
+            fs::read_to_string(&base_path).expect("base read"),
+            base_text
+        );
+        let local = fs::read_to_string(&path).expect("local read");
+        assert!(!local.contains("被写体"));
+        assert!(!local.contains("history_max_entries"));
+
+        let reloaded = ConfigStore::new(path).expect("reload store");
+        assert_eq!(
+            reloaded.get_items("prompt")[0].choices,
+            vec![NO_SELECTION, "robot", "dragon"]
+        );
+        assert_eq!(reloaded.get_items("prompt")[0].label, "被写体");
 
-    fn fixture_path(name: &str) -> PathBuf {
-        let mut path = std::env::temp_dir();
-        path.push(format!(
-            "ipg_config_store_test_{}_{}.toml",
-            name,
-            std::process::id()
-        ));
-        path
+        fs::remove_dir_all(dir).ok();
     }
 
     #[test]
-    fn normalizes_and_persists_choices() {
-        let path = fixture_path("normalize");
+    fn temporary_mode_keeps_selections_in_memory_until_saved() {
+        let path = fixture_path("temporary_mode");
         fs::write(
             &path,
             r#"
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  choices = ["robot", "cat"]
+"#,
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let on_disk = fs::read_to_string(&path).expect("read config");
+        store.set_temporary_mode(true);
+        store
+            .set_item_state("prompt", "subject", "robot", "")
+            .expect("set state");
+        assert!(store.has_unsaved_state());
+        assert_eq!(store.get_item_state("prompt", "subject").0, "robot");
+        assert_eq!(fs::read_to_string(&path).expect("read config"), on_disk);
+
+        store.save_temporary_state().expect("save");
+        assert!(store.temporary_mode());
+        assert!(!store.has_unsaved_state());
+        let reloaded = ConfigStore::new(path.clone()).expect("reload");
+        assert_eq!(reloaded.get_item_state("prompt", "subject").0, "robot");
+
+        store
+            .set_item_state("prompt", "subject", "cat", "")
+            .expect("set state");
+        store.set_temporary_mode(false);
+        assert!(!store.temporary_mode());
+        assert_eq!(store.get_item_state("prompt", "subject").0, "robot");
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn reload_picks_up_outside_edits_and_keeps_temporary_selections() {
+        let path = fixture_path("reload");
+        let config = |delimiter: &str, choices: &str| {
+            format!(
+                r#"
 [app]
-copy_debounce_sec = -1
+delimiter = "{delimiter}"
 
 [[sections]]
 name = "prompt"
 
   [[sections.items]]
   key = "subject"
-  choices = ["robot", "", "指定なし", "robot", "cat"]
-"#,
+  choices = [{choices}]
+"#
+            )
+        };
+        fs::write(&path, config(", ", r#""robot", "cat""#)).expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        store.set_temporary_mode(true);
+        store
+            .set_item_state("prompt", "subject", "cat", "")
+            .expect("set state");
+
+        fs::write(&path, config(" / ", r#""robot", "cat", "dog""#)).expect("edit config");
+        store.reload().expect("reload");
+        assert_eq!(store.delimiter(), " / ");
+        assert!(store.temporary_mode());
+        assert_eq!(store.get_item_state("prompt", "subject").0, "cat");
+
+        fs::write(&path, "[app\n").expect("break config");
+        assert!(store.reload().is_err());
+        assert_eq!(
+            store.delimiter(),
+            " / ",
+            "a broken file keeps the old config"
+        );
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn app_settings_are_validated_and_written_back() {
+        let path = fixture_path("app_settings");
+        fs::write(
+            &path,
+            "[app]\ndelimiter = \", \"\nhistory_max_entries = 50\n",
         )
         .expect("fixture write");
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+
+        let invalid = store.update_app_settings(&AppSettingsUpdate {
+            delimiter: Some(" / ".to_string()),
+            history_max_entries: Some(0),
+            ..AppSettingsUpdate::default()
+        });
+        assert!(invalid.is_err());
+        assert_eq!(
+            store.delimiter(),
+            ", ",
+            "nothing changes on an invalid value"
+        );
+
+        store
+            .update_app_settings(&AppSettingsUpdate {
+                delimiter: Some("\n".to_string()),
+                copy_debounce_sec: Some(0.5),
+                confirm_delete: Some(false),
+                history_server_port: Some(3100),
+                ..AppSettingsUpdate::default()
+            })
+            .expect("update settings");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert_eq!(reloaded.delimiter(), "\n");
+        assert_eq!(reloaded.copy_debounce_sec(), 0.5);
+        assert!(!reloaded.confirm_delete());
+        assert_eq!(reloaded.history_server_port(), 3100);
+        assert_eq!(reloaded.history_max_entries(), 50, "unsent values are kept");
+
+        fs::remove_file(path).ok();
+    }
 
+    #[test]
+    fn destructive_changes_are_backed_up_and_can_be_restored() {
+        // A folder of its own, so other tests' backups are not listed.
+        let dir = fixture_path("backups").with_extension("");
+        fs::create_dir_all(&dir).expect("backup fixture dir");
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "[[sections]]\nname = \"prompt\"\n\n  [[sections.items]]\n  key = \"subject\"\n  choices = [\"cat\", \"dog\"]\n",
+        )
+        .expect("fixture write");
         let mut store = ConfigStore::new(path.clone()).expect("load store");
-        let items = store.get_items("prompt");
-        assert_eq!(items.len(), 1);
-        assert_eq!(items[0].choices[0], NO_SELECTION);
-        assert_eq!(items[0].choices[1], "robot");
-        assert_eq!(items[0].choices[2], "cat");
 
-        let added = store
-            .add_choice("prompt", "subject", "wolf")
+        assert!(store
+            .remove_choice("prompt", "subject", "dog")
+            .expect("remove choice"));
+        let backups = list_backups(&path).expect("list backups");
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].reason, "remove_choice");
+
+        store
+            .restore_backup(&backups[0].file)
+            .expect("restore backup");
+        assert!(store.get_items("prompt")[0]
+            .choices
+            .contains(&"dog".to_string()));
+        let backups = list_backups(&path).expect("list after restore");
+        assert_eq!(backups[0].reason, "restore", "the restore can be undone");
+        assert!(store.restore_backup("../config.toml").is_err());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn json_and_yaml_configs_load_and_save_in_their_own_format() {
+        let json_path = fixture_path("format").with_extension("json");
+        fs::write(
+            &json_path,
+            r#"{"app": {"delimiter": " / "}, "sections": [
+                {"name": "prompt", "items": [{"key": "subject", "label": "被写体", "choices": ["cat"]}]}
+            ]}"#,
+        )
+        .expect("json write");
+        let mut store = ConfigStore::new(json_path.clone()).expect("load json");
+        assert_eq!(store.delimiter(), " / ");
+        store
+            .add_choice("prompt", "subject", "dog")
             .expect("add choice");
-        assert!(added);
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json_path).expect("read json"))
+                .expect("still json");
+        assert_eq!(saved["sections"][0]["items"][0]["choices"][2], "dog");
 
-        let removed = store
-            .remove_choice("prompt", "subject", "cat")
-            .expect("remove choice");
-        assert!(removed);
+        let yaml_path = fixture_path("format").with_extension("yaml");
+        fs::write(
+            &yaml_path,
+            "app:\n  delimiter: \" | \"\nsections:\n  - name: prompt\n    items:\n      - key: subject\n        choices: [cat]\n",
+        )
+        .expect("yaml write");
+        let store = ConfigStore::new(yaml_path.clone()).expect("load yaml");
+        assert_eq!(store.delimiter(), " | ");
+        assert_eq!(
+            store.get_items("prompt")[0].choices,
+            vec![NO_SELECTION, "cat"]
+        );
+        let saved = fs::read_to_string(&yaml_path).expect("read yaml");
+        assert!(saved.contains("sections:"), "saved as YAML: {saved}");
+        assert!(!store.changed_on_disk());
 
-        let items2 = store.get_items("prompt");
-        assert_eq!(items2[0].choices, vec!["指定なし", "robot", "wolf"]);
+        fs::remove_file(json_path).ok();
+        fs::remove_file(yaml_path).ok();
+    }
+
+    #[test]
+    fn changed_on_disk_ignores_own_writes_and_reports_outside_edits() {
+        let path = fixture_path("changed_on_disk");
+        fs::write(
+            &path,
+            "[[sections]]\nname = \"prompt\"\n\n  [[sections.items]]\n  key = \"subject\"\n  choices = [\"cat\"]\n",
+        )
+        .expect("fixture write");
+
+        let mut store = ConfigStore::new(path.clone()).expect("load store");
+        assert!(
+            !store.changed_on_disk(),
+            "the normalized file was just saved"
+        );
+        store
+            .set_item_state("prompt", "subject", "cat", "")
+            .expect("set state");
+        store.set_temporary_mode(true);
+        store
+            .set_item_state("prompt", "subject", "指定なし", "")
+            .expect("temporary state");
+        assert!(!store.changed_on_disk());
+
+        let edited = fs::read_to_string(&path).expect("read config")
+            + "\n[[sections]]\nname = \"negative\"\n";
+        fs::write(&path, &edited).expect("edit config");
+        assert!(store.changed_on_disk());
+        store.reload().expect("reload");
+        assert!(!store.changed_on_disk());
+        assert_eq!(
+            fs::read_to_string(&path).expect("read config"),
+            edited,
+            "reloading does not rewrite the edited file"
+        );
+        assert_eq!(store.sections().len(), 2);
 
         fs::remove_file(path).ok();
     }
 
     #[test]
-    fn keeps_app_table_before_sections_after_save() {
-        let path = fixture_path("app_order");
+    fn random_pool_is_edited_and_follows_deleted_choices() {
+        let path = fixture_path("random_pool");
         fs::write(
             &path,
             r#"
@@ -701,26 +3697,72 @@ name = "prompt"
 
   [[sections.items]]
   key = "subject"
-  choices = ["指定なし", "robot"]
-
-[app]
-history_server_port = 3000
+  choices = ["指定なし", "cat", "dog", "draft idea"]
+  random_pool = ["cat"]
 "#,
         )
         .expect("fixture write");
 
         let mut store = ConfigStore::new(path.clone()).expect("load store");
+        let pool = |store: &ConfigStore| store.get_items("prompt")[0].random_pool.clone();
+        assert_eq!(pool(&store), ["cat"]);
         store
-            .set_item_state("prompt", "subject", NO_SELECTION, "")
-            .expect("set state");
+            .set_random_pool_member("prompt", "subject", "dog", true)
+            .expect("add dog");
+        assert_eq!(pool(&store), ["cat", "dog"]);
+        assert!(store
+            .set_random_pool_member("prompt", "subject", "missing", true)
+            .is_err());
 
-        let saved = fs::read_to_string(&path).expect("read saved");
-        let app_pos = saved.find("[app]").expect("app exists");
-        let sections_pos = saved.find("[[sections]]").expect("sections exists");
-        assert!(
-            app_pos < sections_pos,
-            "[app] should be before [[sections]] after save"
-        );
+        store
+            .remove_choice("prompt", "subject", "cat")
+            .expect("remove cat");
+        store
+            .set_random_pool_member("prompt", "subject", "dog", false)
+            .expect("drop dog");
+        let reloaded = ConfigStore::new(path.clone()).expect("reload store");
+        assert!(pool(&reloaded).is_empty());
+        assert!(!fs::read_to_string(&path)
+            .expect("read config")
+            .contains("random_pool"));
+
+        fs::remove_file(path).ok();
+    }
+    #[test]
+    fn disabled_features_read_as_unconfigured() {
+        let path = fixture_path("features");
+        fs::write(
+            &path,
+            r#"
+[app]
+history_server_lan_port = 3443
+translate_command = ["translate"]
+"#,
+        )
+        .expect("fixture write");
+
+        let store = ConfigStore::new(path.clone()).expect("load store");
+        assert_eq!(store.features(), Features::default());
+        assert_eq!(store.translate_command(), ["translate"]);
+        assert_eq!(store.history_server_lan_port(), 0, "lan ships disabled");
+
+        fs::write(
+            &path,
+            r#"
+[app]
+history_server_lan_port = 3443
+translate_command = ["translate"]
+
+[features]
+integrations = false
+lan = true
+"#,
+        )
+        .expect("fixture write");
+        let store = ConfigStore::new(path.clone()).expect("reload store");
+        assert!(store.features().analytics);
+        assert!(store.translate_command().is_empty());
+        assert_eq!(store.history_server_lan_port(), 3443);
 
         fs::remove_file(path).ok();
     }
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use serde::Serialize;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Probe file written and removed again to test the data dir.
+const PROBE_FILE_NAME: &str = ".ipg_diagnostics_probe";
+const PORT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Free space below which the disk check warns; uploads and backups need room.
+const LOW_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl DiagnosticCheck {
+    fn from_result(name: &'static str, result: Result<(CheckStatus, String)>) -> Self {
+        let (status, detail) = result.unwrap_or_else(|err| (CheckStatus::Fail, format!("{err:#}")));
+        Self {
+            name,
+            status,
+            detail,
+        }
+    }
+}
+
+/// Runs every check against the data dir and the profile's server port.
+pub fn run_diagnostics(data_dir: &Path, port: u16) -> Vec<DiagnosticCheck> {
+    vec![
+        DiagnosticCheck::from_result("データフォルダの書き込み", check_data_dir(data_dir)),
+        DiagnosticCheck::from_result("ディスクの空き容量", check_disk_free(data_dir)),
+        DiagnosticCheck::from_result("履歴サーバーのポート", check_port(port)),
+        DiagnosticCheck::from_result("クリップボード", check_clipboard()),
+        DiagnosticCheck::from_result("WebView2 Runtime", check_webview2()),
+    ]
+}
+
+/// Checks that the configured translate, vision and score commands can be
+/// started; commands that are not set up are left out.
+pub fn check_integrations(
+    translate: &[String],
+    vision: &[String],
+    score: &[String],
+) -> Vec<DiagnosticCheck> {
+    [
+        ("翻訳コマンド", translate),
+        ("画像認識コマンド", vision),
+        ("採点コマンド", score),
+    ]
+    .into_iter()
+    .filter_map(|(name, command)| {
+        let program = command.first()?;
+        Some(DiagnosticCheck::from_result(name, check_program(program)))
+    })
+    .collect()
+}
+
+/// Plain-text report to paste into a bug report: app and OS first, then one
+/// line per check.
+pub fn format_report(data_dir: &Path, port: u16, checks: &[DiagnosticCheck]) -> String {
+    let mut lines = vec![
+        "Image Prompt Generator 診断レポート".to_string(),
+        format!("version: {}", env!("CARGO_PKG_VERSION")),
+        format!("os: {} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("time: {}", Local::now().format("%Y-%m-%d %H:%M:%S")),
+        format!("data dir: {}", data_dir.display()),
+        format!("port: {port}"),
+        String::new(),
+    ];
+    for check in checks {
+        lines.push(format!(
+            "[{}] {}: {}",
+            check.status.label(),
+            check.name,
+            check.detail
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+fn check_data_dir(data_dir: &Path) -> Result<(CheckStatus, String)> {
+    let probe = data_dir.join(PROBE_FILE_NAME);
+    fs::write(&probe, b"ok").with_context(|| format!("書き込めません: {}", data_dir.display()))?;
+    let read_back = fs::read(&probe);
+    fs::remove_file(&probe)
+        .with_context(|| format!("テストファイルを削除できません: {}", probe.display()))?;
+    if read_back.ok().as_deref() != Some(b"ok".as_slice()) {
+        return Err(anyhow!(
+            "書き込んだ内容を読み戻せません: {}",
+            data_dir.display()
+        ));
+    }
+    Ok((CheckStatus::Ok, data_dir.display().to_string()))
+}
+
+fn check_port(port: u16) -> Result<(CheckStatus, String)> {
+    if port == 0 {
+        return Err(anyhow!("サーバーが起動していません"));
+    }
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    TcpStream::connect_timeout(&address, PORT_TIMEOUT)
+        .with_context(|| format!("{address} に接続できません"))?;
+    Ok((CheckStatus::Ok, format!("{address} に接続できます")))
+}
+
+fn check_program(program: &str) -> Result<(CheckStatus, String)> {
+    let path = find_program(program).ok_or_else(|| anyhow!("見つかりません: {program}"))?;
+    Ok((CheckStatus::Ok, path.display().to_string()))
+}
+
+/// Resolves `program` the way starting a process does: paths as given, bare
+/// names through `PATH` (and `PATHEXT` on Windows).
+fn find_program(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let extensions: Vec<String> = if cfg!(target_os = "windows") && path.extension().is_none() {
+        std::env::var("PATHEXT")
+            .unwrap_or_else(|_| ".EXE;.CMD;.BAT;.COM".to_string())
+            .split(';')
+            .map(ToOwned::to_owned)
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    let dirs = std::env::var_os("PATH")?;
+    std::env::split_paths(&dirs).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|extension| dir.join(format!("{program}{extension}")))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+fn check_disk_free(data_dir: &Path) -> Result<(CheckStatus, String)> {
+    let Some(free) = free_disk_bytes(data_dir)? else {
+        return Ok((
+            CheckStatus::Warn,
+            "Windows以外では確認できません".to_string(),
+        ));
+    };
+    Ok(disk_free_status(free))
+}
+
+fn disk_free_status(free: u64) -> (CheckStatus, String) {
+    let detail = format!("{:.1} GB", free as f64 / (1024.0 * 1024.0 * 1024.0));
+    if free < LOW_DISK_BYTES {
+        (CheckStatus::Warn, format!("{detail}（残りわずか）"))
+    } else {
+        (CheckStatus::Ok, detail)
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn free_disk_bytes(data_dir: &Path) -> Result<Option<u64>> {
+    use std::os::windows::ffi::OsStrExt;
+    let wide: Vec<u16> = data_dir
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut free = 0u64;
+    let ok = unsafe {
+        windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut free,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(anyhow!("空き容量を取得できません: {}", data_dir.display()));
+    }
+    Ok(Some(free))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn free_disk_bytes(_data_dir: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(target_os = "windows")]
+fn check_clipboard() -> Result<(CheckStatus, String)> {
+    clipboard_win::Clipboard::new_attempts(10).map_err(|err| {
+        anyhow!("クリップボードを開けません（他のアプリが使用中の可能性）: {err}")
+    })?;
+    Ok((CheckStatus::Ok, "利用できます".to_string()))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_clipboard() -> Result<(CheckStatus, String)> {
+    Ok((
+        CheckStatus::Warn,
+        "Windows以外では確認できません".to_string(),
+    ))
+}
+
+#[cfg(target_os = "windows")]
+fn check_webview2() -> Result<(CheckStatus, String)> {
+    let version = wry::webview_version()
+        .map_err(|err| anyhow!("WebView2 Runtime が見つかりません: {err}"))?;
+    Ok((CheckStatus::Ok, version))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_webview2() -> Result<(CheckStatus, String)> {
+    Ok((
+        CheckStatus::Warn,
+        "Windows以外では確認できません".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_integrations, disk_free_status, format_report, run_diagnostics, CheckStatus,
+    };
+    use std::fs;
+    use std::net::TcpListener;
+
+    #[test]
+    fn checks_data_dir_and_port_and_formats_report() {
+        let base = std::env::temp_dir().join(format!("ipg_diagnostics_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("create base");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+
+        let checks = run_diagnostics(&base, port);
+        assert_eq!(checks[0].status, CheckStatus::Ok);
+        assert_eq!(checks[2].status, CheckStatus::Ok);
+        assert_eq!(fs::read_dir(&base).expect("list").count(), 0);
+        let report = format_report(&base, port, &checks);
+        assert!(report.starts_with("Image Prompt Generator 診断レポート\n"));
+        assert!(report.contains(&format!("port: {port}\n")));
+        assert!(report.contains("[OK] データフォルダの書き込み: "));
+
+        let missing = run_diagnostics(&base.join("missing"), 0);
+        assert_eq!(missing[0].status, CheckStatus::Fail);
+        assert_eq!(missing[2].status, CheckStatus::Fail);
+        assert!(format_report(&base, 0, &missing).contains("[FAIL] 履歴サーバーのポート: "));
+        assert_eq!(disk_free_status(512 * 1024 * 1024).0, CheckStatus::Warn);
+        assert_eq!(disk_free_status(8 * 1024 * 1024 * 1024).0, CheckStatus::Ok);
+
+        let program = base.join("translate.exe");
+        fs::write(&program, b"").expect("write program");
+        let program = program.display().to_string();
+        let missing = base.join("missing.exe").display().to_string();
+        let integrations = check_integrations(&[program], &[missing], &[]);
+        assert_eq!(integrations[0].status, CheckStatus::Ok);
+        assert_eq!(integrations[1].status, CheckStatus::Fail);
+        assert!(check_integrations(&[], &[], &[]).is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+}
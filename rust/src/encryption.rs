@@ -0,0 +1,182 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// Prefix of every encrypted file. Plaintext history JSON and the supported
+/// image formats never start with it, so both can sit side by side.
+const MAGIC: &[u8] = b"IPGENC1\0";
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+/// Default key file, created in the data dir on first use.
+pub const KEY_FILE_NAME: &str = "history.key";
+/// Salt for the passphrase key; not secret, so it lives next to the data.
+pub const SALT_FILE_NAME: &str = "history.salt";
+/// Environment variable that switches the key source from the key file to a passphrase.
+pub const PASSPHRASE_ENV: &str = "IPG_HISTORY_PASSPHRASE";
+
+/// AES-256-GCM over whole files: `MAGIC`, a random 96-bit nonce, then the
+/// ciphertext with its tag.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: Aes256Gcm,
+}
+
+impl Cipher {
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        Self {
+            aead: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    /// Reads the base64 key in `path`, writing a new random key there when the
+    /// file does not exist yet.
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            let key = Aes256Gcm::generate_key(&mut OsRng);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create dir: {}", parent.display()))?;
+            }
+            fs::write(path, STANDARD.encode(key))
+                .with_context(|| format!("failed to write key file: {}", path.display()))?;
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read key file: {}", path.display()))?;
+        let key: [u8; 32] = STANDARD
+            .decode(text.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("key file is not a base64 256-bit key: {}", path.display()))?;
+        Ok(Self::from_key(&key))
+    }
+
+    /// Derives the key from `passphrase` with Argon2id and the salt in
+    /// `salt_path`, creating a random salt on first use.
+    pub fn from_passphrase(passphrase: &str, salt_path: &Path) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("passphrase is empty"));
+        }
+        if !salt_path.exists() {
+            if let Some(parent) = salt_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create dir: {}", parent.display()))?;
+            }
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            fs::write(salt_path, STANDARD.encode(salt))
+                .with_context(|| format!("failed to write salt: {}", salt_path.display()))?;
+        }
+        let text = fs::read_to_string(salt_path)
+            .with_context(|| format!("failed to read salt: {}", salt_path.display()))?;
+        let salt = STANDARD
+            .decode(text.trim())
+            .with_context(|| format!("salt is not base64: {}", salt_path.display()))?;
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|err| anyhow!("failed to derive key: {err}"))?;
+        Ok(Self::from_key(&key))
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .aead
+            .encrypt(&nonce, plain)
+            .map_err(|_| anyhow!("failed to encrypt"))?;
+        let mut output = Vec::with_capacity(MAGIC.len() + NONCE_LEN + sealed.len());
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&sealed);
+        Ok(output)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let body = data
+            .strip_prefix(MAGIC)
+            .filter(|body| body.len() >= NONCE_LEN)
+            .ok_or_else(|| anyhow!("data is not encrypted"))?;
+        let (nonce, sealed) = body.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| anyhow!("failed to decrypt: wrong key or damaged file"))
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Like `is_encrypted`, reading only the start of the file.
+pub fn is_encrypted_file(path: &Path) -> Result<bool> {
+    let mut head = Vec::with_capacity(MAGIC.len());
+    File::open(path)
+        .and_then(|file| file.take(MAGIC.len() as u64).read_to_end(&mut head))
+        .with_context(|| format!("failed to read: {}", path.display()))?;
+    Ok(is_encrypted(&head))
+}
+
+/// Reads `path`, decrypting it when it is encrypted. Encrypted files without a
+/// cipher are an error rather than garbage.
+pub fn read_file(path: &Path, cipher: Option<&Cipher>) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("failed to read: {}", path.display()))?;
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    let cipher = cipher.ok_or_else(|| {
+        anyhow!(
+            "{} is encrypted; set history_encryption = true with its key",
+            path.display()
+        )
+    })?;
+    cipher
+        .decrypt(&data)
+        .with_context(|| format!("failed to decrypt: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_encrypted, read_file, Cipher};
+    use std::fs;
+
+    #[test]
+    fn round_trips_with_key_file_and_passphrase() {
+        let base = std::env::temp_dir().join(format!("ipg_encryption_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("create base");
+
+        let cipher = Cipher::from_key_file(&base.join("history.key")).expect("new key");
+        let sealed = cipher.encrypt(b"client prompt").expect("encrypt");
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.windows(6).any(|w| w == b"client"));
+        let reloaded = Cipher::from_key_file(&base.join("history.key")).expect("same key");
+        assert_eq!(
+            reloaded.decrypt(&sealed).expect("decrypt"),
+            b"client prompt"
+        );
+
+        let salt = base.join("history.salt");
+        let derived = Cipher::from_passphrase("secret", &salt).expect("derive");
+        let sealed = derived.encrypt(b"{}").expect("encrypt");
+        let again = Cipher::from_passphrase("secret", &salt).expect("derive again");
+        assert_eq!(again.decrypt(&sealed).expect("decrypt"), b"{}");
+        let wrong = Cipher::from_passphrase("guess", &salt).expect("derive wrong");
+        assert!(wrong.decrypt(&sealed).is_err());
+
+        let file = base.join("history.json");
+        fs::write(&file, &sealed).expect("write");
+        assert!(read_file(&file, None).is_err());
+        assert_eq!(read_file(&file, Some(&again)).expect("read"), b"{}");
+        fs::write(&file, b"[]").expect("write plain");
+        assert_eq!(read_file(&file, None).expect("read plain"), b"[]");
+
+        fs::remove_dir_all(base).ok();
+    }
+}
@@ -1,44 +1,519 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::{Local, NaiveDateTime};
+use chrono::{Local, Months, NaiveDate, NaiveDateTime, TimeDelta};
 use html_escape::{encode_double_quoted_attribute, encode_text};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::BTreeMap;
-use std::fs;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{Seek, Write};
 use std::path::{Component, Path, PathBuf};
 
+use crate::api_version::API_VERSION;
+use crate::archive_index::{ArchiveIndex, ARCHIVE_INDEX_HTML};
+use crate::atomic_write::{backup_path, write_file_atomic};
+use crate::audit_log::{AuditLog, AuditRecord};
+use crate::backup::{collect_dir, read_backup_zip, write_backup_zip, BackupFile};
+use crate::encryption::{is_encrypted, is_encrypted_file, read_file, Cipher};
+use crate::housekeeping::{HousekeepingRule, PlannedAction, RuleAction};
+use crate::image_hash::{hamming_distance, parse_hash, perceptual_hash, ImageHashIndex};
+use crate::instance_lock::READ_ONLY_MESSAGE;
+use crate::migration::{backup_before_migration, run_migrations, Migration};
+use crate::png_text::{read_generation_metadata, GenerationMetadata};
+use crate::randomize::RatingWeights;
+use crate::smart_filters::{SmartFilter, SmartFilterStore};
+use crate::stats::{build_stats_html, compute_stats};
+use crate::thumbnails::{
+    encode_preview, encode_sized_thumbnail, remove_sized_thumbnails, sized_thumbnail_rel_path,
+    thumbnail_rel_path, write_thumbnail, PreviewIndex, THUMBS_DIR, THUMB_MAX_SIDE,
+};
+
+pub const HISTORY_SCHEMA_VERSION: u32 = 1;
+
+const HISTORY_MIGRATIONS: [Migration<Value>; 1] = [Migration {
+    from_version: 0,
+    description: "wrap entry array with schema_version",
+    apply: wrap_entry_array,
+}];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryEntry {
     pub id: String,
     pub ts: String,
     pub prompt: String,
     pub images: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub sample: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub locked: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+    /// 1–5 stars; 0 means unrated.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rating: u8,
+    /// How many times this prompt was copied under `DedupeMode::Count`.
+    #[serde(default = "default_count", skip_serializing_if = "is_single")]
+    pub count: u32,
+    /// Item key → chosen value at copy time, so the entry can be re-rendered
+    /// into another output format. Empty for entries saved before this existed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub selections: BTreeMap<String, String>,
+    /// English translation of `prompt` from `translate_command`, when one was shown at copy time.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub translation: String,
+    /// 0–100 from `score_command` for the copied prompt, when it was scored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<u8>,
+    /// Image path → generation info read from the uploaded PNG. Kept after the
+    /// image is detached so undo brings it back with the image.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub image_metadata: BTreeMap<String, GenerationMetadata>,
+}
+
+/// What `append_history` does when the same prompt is already in the active history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupeMode {
+    /// Always append a new entry.
+    #[default]
+    Off,
+    /// Keep the existing entry untouched.
+    Skip,
+    /// Move the existing entry to the top with a fresh id and timestamp.
+    Bump,
+    /// Increment the existing entry's `count`.
+    Count,
+}
+
+impl DedupeMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "skip" => Some(Self::Skip),
+            "bump" => Some(Self::Bump),
+            "count" => Some(Self::Count),
+            _ => None,
+        }
+    }
+}
+
+/// How rotated entries are grouped into archive files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArchiveGrouping {
+    /// One `History_YYYYMMDD.json` per day.
+    #[default]
+    Day,
+    /// One `History_YYYYMM.json` per month.
+    Month,
+}
+
+impl ArchiveGrouping {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "day" => Some(Self::Day),
+            "month" => Some(Self::Month),
+            _ => None,
+        }
+    }
+}
+
+/// Active-history headroom and the entries `rotate_if_needed` will archive next.
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationStatus {
+    pub active_entries: usize,
+    pub max_entries: usize,
+    pub remaining: usize,
+    pub pending_ids: Vec<String>,
+}
+
+/// What a history card shows besides the entry itself.
+struct CardView<'a> {
+    interactive: bool,
+    allow_delete: bool,
+    /// Cards on the active page offer `アーカイブ`, archive pages `アクティブに戻す`.
+    active_page: bool,
+    /// Entries the next copy moves to the archive.
+    pending_ids: &'a [String],
+    /// Blurred previews by image path, shown until the thumbnail loads.
+    previews: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "md" | "markdown" => Ok(Self::Markdown),
+            other => Err(anyhow!("unsupported export format: {other}")),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Markdown => "md",
+        }
+    }
+
+    fn render(self, entries: &[HistoryEntry]) -> String {
+        match self {
+            Self::Csv => export_csv(entries),
+            Self::Markdown => export_markdown(entries),
+        }
+    }
+}
+
+/// Outcome of `HistoryStore::remove_image`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageRemoval {
+    /// The entry has no such image.
+    NotAttached,
+    /// Detached from the entry; the file stays on disk.
+    Detached,
+    /// Detached and the file deleted (`history_delete_image_files`).
+    FileDeleted,
+}
+
+/// Result of `HistoryStore::export_rerendered`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RerenderExport {
+    pub content: String,
+    pub rendered: usize,
+    pub skipped: usize,
+}
+
+/// Inclusive date range over entry dates; open ends are unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportRange {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl ExportRange {
+    /// Accepts `YYYY-MM-DD` or `YYYYMMDD`; empty values leave that end open.
+    pub fn parse(from: Option<&str>, to: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            from: parse_export_date(from)?,
+            to: parse_export_date(to)?,
+        })
+    }
+
+    fn contains(&self, date_key: &str) -> bool {
+        let Ok(date) = NaiveDate::parse_from_str(date_key, "%Y%m%d") else {
+            return self.from.is_none() && self.to.is_none();
+        };
+        self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to)
+    }
+
+    /// Whether any day of an archive key (`YYYYMMDD` or `YYYYMM`) is in range.
+    fn overlaps(&self, archive_key: &str) -> bool {
+        if archive_key.len() != 6 {
+            return self.contains(archive_key);
+        }
+        let Ok(first) = NaiveDate::parse_from_str(&format!("{archive_key}01"), "%Y%m%d") else {
+            return self.from.is_none() && self.to.is_none();
+        };
+        let last = first
+            .checked_add_months(chrono::Months::new(1))
+            .and_then(|next| next.pred_opt())
+            .unwrap_or(first);
+        self.from.is_none_or(|from| last >= from) && self.to.is_none_or(|to| first <= to)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub renamed: usize,
+}
+
+/// A `GET /history` match and the history page that shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistorySearchHit {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    /// `History.html` or the `History_YYYYMMDD.html` archive page.
+    pub page: String,
+}
+
+/// A card for `GET /history/list` and mutation responses: the entry and the
+/// html the page inserts for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryListItem {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    pub html: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistoryList {
+    /// Newest first, starting at `offset`.
+    pub entries: Vec<HistoryListItem>,
+    pub offset: usize,
+    /// Every active entry.
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistorySearch {
+    /// Newest first, at most `limit` of them.
+    pub hits: Vec<HistorySearchHit>,
+    /// Every match, including those past the limit.
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub duplicates: usize,
+    pub images: usize,
+}
+
+/// What `enforce_retention` exported before removing it.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionExport {
+    pub zip_path: PathBuf,
+    pub archives: usize,
+    pub entries: usize,
+    /// Image files deleted because no remaining entry uses them.
+    pub images_deleted: usize,
+}
+
+#[derive(Serialize)]
+struct HistoryFile<'a> {
+    schema_version: u32,
+    entries: &'a [HistoryEntry],
 }
 
+/// A soft-deleted entry kept in `history.trash.json` until the retention window passes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    pub deleted_at: String,
+    /// File name the entry was deleted from (`history.json` or `History_YYYYMMDD.json`).
+    pub origin: String,
+}
+
+/// An attached image whose perceptual hash is close to the one searched for.
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarImage {
+    pub history_id: String,
+    pub ts: String,
+    pub prompt: String,
+    pub image_path: String,
+    pub distance: u32,
+}
+
+/// A reversible history mutation; `HistoryStore::undo` reverts the newest one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UndoOperation {
+    Delete {
+        history_id: String,
+    },
+    Overwrite {
+        history_id: String,
+        prompt: String,
+    },
+    Images {
+        history_id: String,
+        images: Vec<String>,
+    },
+}
+
+impl UndoOperation {
+    pub fn history_id(&self) -> &str {
+        match self {
+            Self::Delete { history_id }
+            | Self::Overwrite { history_id, .. }
+            | Self::Images { history_id, .. } => history_id,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Delete { .. } => "削除",
+            Self::Overwrite { .. } => "上書き",
+            Self::Images { .. } => "画像の変更",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrashFile {
+    schema_version: u32,
+    entries: Vec<TrashEntry>,
+}
+
+const SAMPLE_IMAGES_DIR: &str = "images/samples";
+
+const SAMPLE_ENTRIES: [(&str, &str, &[u8]); 3] = [
+    (
+        "[画像の向き]：横長\n[被写体]：AIロボット\n[設定]：ゴールデンアワー\n[トーン]：シネマティック",
+        "sample_01.png",
+        include_bytes!("../../assets/samples/sample_01.png"),
+    ),
+    (
+        "[画像の向き]：横長\n[被写体]：成人女性（日本人）\n[設定]：ブルーアワー\n[ライティング定番]：リムライト",
+        "sample_02.png",
+        include_bytes!("../../assets/samples/sample_02.png"),
+    ),
+    (
+        "[画像の向き]：縦長\n[背景]：白背景\n[スタイル]：フラットデザイン\n[制約]：文字なし",
+        "sample_03.png",
+        include_bytes!("../../assets/samples/sample_03.png"),
+    ),
+];
+
 pub struct HistoryStore {
     base_dir: PathBuf,
+    fresh: bool,
     max_active_entries: usize,
+    max_images_per_entry: usize,
+    rotation_warn_margin: usize,
+    trash_retention_days: i64,
+    dedupe_mode: DedupeMode,
+    delete_image_files: bool,
+    archive_page_size: usize,
+    archive_after_days: i64,
+    archive_grouping: ArchiveGrouping,
+    archive_max_bytes: u64,
+    archive_compact_threshold: usize,
+    retention_months: u32,
+    retention_export_dir: PathBuf,
     history_json_path: PathBuf,
+    trash_json_path: PathBuf,
     history_html_path: PathBuf,
     images_root: PathBuf,
+    audit: AuditLog,
+    smart_filters: SmartFilterStore,
+    image_hashes: ImageHashIndex,
+    image_previews: PreviewIndex,
+    /// Decrypts encrypted files on read; also encrypts writes while `encrypt` is set.
+    cipher: Option<Cipher>,
+    encrypt: bool,
+    /// Set while another instance owns the data dir; every write is refused.
+    read_only: bool,
+    undo_stack: VecDeque<UndoOperation>,
+    /// CRC32 of each history JSON file as this store last wrote or saw it, so
+    /// the file watcher can tell edits by other tools from its own writes.
+    known_files: RefCell<BTreeMap<PathBuf, u32>>,
+}
+
+/// A thumbnail to make, from `HistoryStore::sized_thumbnail`.
+pub struct ThumbnailJob {
+    source: PathBuf,
+    cached: Option<PathBuf>,
+    cipher: Option<Cipher>,
+    width: u32,
+}
+
+impl ThumbnailJob {
+    /// The cached thumbnail when it is newer than its image, otherwise a new
+    /// one, cached for next time. A failed cache write is reported and the
+    /// new thumbnail is still returned.
+    pub fn run(self) -> Result<Vec<u8>> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        if let Some(cached) = &self.cached {
+            // A cache older than its image was made before the file changed.
+            if modified(cached).is_some_and(|at| Some(at) >= modified(&self.source)) {
+                if let Ok(bytes) = fs::read(cached) {
+                    return Ok(bytes);
+                }
+            }
+        }
+
+        let bytes = read_file(&self.source, self.cipher.as_ref())
+            .with_context(|| format!("failed to read image: {}", self.source.display()))?;
+        let thumbnail = encode_sized_thumbnail(&bytes, self.width)?;
+        if let Some(cached) = &self.cached {
+            let written = cached
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| write_file_atomic(cached, &thumbnail, false));
+            if let Err(err) = written {
+                eprintln!(
+                    "サムネイルのキャッシュを保存できませんでした: {}: {err:#}",
+                    cached.display()
+                );
+            }
+        }
+        Ok(thumbnail)
+    }
 }
 
 impl HistoryStore {
     pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+    pub const DEFAULT_MAX_IMAGES_PER_ENTRY: usize = 4;
+    pub const DEFAULT_ROTATION_WARN_MARGIN: usize = 5;
+    pub const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+    pub const UNDO_LIMIT: usize = 20;
+    /// Cards `History.html` renders up front; the rest load on scroll.
+    pub const LIST_PAGE_SIZE: usize = 50;
     const ALLOWED_EXTENSIONS: [&'static str; 5] = [".png", ".jpg", ".jpeg", ".webp", ".gif"];
 
     pub fn new(base_dir: PathBuf, max_active_entries: usize) -> Result<Self> {
-        let resolved_max = if max_active_entries == 0 {
-            300
-        } else {
-            max_active_entries
-        };
+        Self::open(base_dir, max_active_entries, None, false)
+    }
+
+    /// Like `new`, but able to read a history encrypted with `cipher`. Call
+    /// `set_encryption` afterwards to encrypt or decrypt the files at rest.
+    /// `read_only` is for a data dir another instance has locked: reads work,
+    /// writes fail with `READ_ONLY_MESSAGE`, older files are migrated only in
+    /// memory and pages are left to the instance that owns them.
+    pub fn open(
+        base_dir: PathBuf,
+        max_active_entries: usize,
+        cipher: Option<Cipher>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let resolved_max = Self::resolve_max_entries(max_active_entries);
+        let fresh = !base_dir.join("history.json").exists();
         let store = Self {
+            fresh,
             history_json_path: base_dir.join("history.json"),
+            trash_json_path: base_dir.join("history.trash.json"),
             history_html_path: base_dir.join("History.html"),
             images_root: base_dir.join("images"),
+            retention_export_dir: base_dir.join("exports"),
+            audit: AuditLog::new(base_dir.join("audit.log")),
+            smart_filters: SmartFilterStore::new(base_dir.join("smart_filters.json")),
+            image_hashes: ImageHashIndex::new(base_dir.join("image_hashes.json")),
+            image_previews: PreviewIndex::new(base_dir.join("image_previews.json")),
             base_dir,
             max_active_entries: resolved_max,
+            max_images_per_entry: Self::DEFAULT_MAX_IMAGES_PER_ENTRY,
+            rotation_warn_margin: Self::DEFAULT_ROTATION_WARN_MARGIN,
+            trash_retention_days: Self::DEFAULT_TRASH_RETENTION_DAYS,
+            dedupe_mode: DedupeMode::Off,
+            delete_image_files: false,
+            archive_page_size: 0,
+            archive_after_days: 0,
+            archive_grouping: ArchiveGrouping::Day,
+            archive_max_bytes: 0,
+            archive_compact_threshold: 0,
+            retention_months: 0,
+            cipher,
+            encrypt: false,
+            read_only,
+            undo_stack: VecDeque::new(),
+            known_files: RefCell::new(BTreeMap::new()),
         };
         store.ensure_files()?;
         Ok(store)
@@ -48,1520 +523,6781 @@ impl HistoryStore {
         &self.history_html_path
     }
 
-    pub fn append_history(&mut self, prompt: &str) -> Result<HistoryEntry> {
-        let cleaned = prompt.trim();
-        if cleaned.is_empty() {
-            return Err(anyhow!("prompt is empty"));
-        }
+    /// Names this data folder, so a page opened from disk that looks for the
+    /// app on nearby ports does not settle on another profile's server.
+    pub fn store_id(&self) -> String {
+        format!(
+            "{:08x}",
+            crc32fast::hash(self.base_dir.to_string_lossy().as_bytes())
+        )
+    }
 
-        let mut entries = self.read_entries(&self.history_json_path)?;
-        let now = Local::now();
-        let entry_id = self.next_entry_id(now.naive_local(), &entries);
-        let entry = HistoryEntry {
-            id: entry_id,
-            ts: now.format("%Y-%m-%d %H:%M:%S").to_string(),
-            prompt: cleaned.to_string(),
-            images: Vec::new(),
-        };
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt
+    }
 
-        entries.push(entry.clone());
-        let kept_entries = self.rotate_if_needed(entries)?;
-        self.write_entries(&self.history_json_path, &kept_entries)?;
-        Ok(entry)
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
-    pub fn delete_history(&mut self, history_id: &str) -> Result<bool> {
-        let history_id = history_id.trim();
-        if history_id.is_empty() {
-            return Ok(false);
+    /// Whether `path` is `history.json` or a `History_*.json` archive.
+    pub fn is_history_json(path: &Path) -> bool {
+        path.file_name().is_some_and(|name| name == "history.json")
+            || archive_key_of(path).is_some()
+    }
+
+    /// Whether a history JSON file differs from what this store last wrote or
+    /// saw, i.e. another tool or a synced folder changed or removed it. The
+    /// current content becomes the known one, so each change is reported once.
+    pub fn take_external_change(&self, path: &Path) -> bool {
+        let mut known = self.known_files.borrow_mut();
+        match fs::read(path) {
+            Ok(bytes) => {
+                let hash = crc32fast::hash(&bytes);
+                known.insert(path.to_path_buf(), hash) != Some(hash)
+            }
+            Err(_) => known.remove(path).is_some(),
         }
+    }
 
-        let Some((target_path, entries, _)) = self.find_entry_container(history_id)? else {
-            return Ok(false);
-        };
+    fn remember_file(&self, path: &Path, bytes: &[u8]) {
+        self.known_files
+            .borrow_mut()
+            .insert(path.to_path_buf(), crc32fast::hash(bytes));
+    }
 
-        let filtered: Vec<HistoryEntry> = entries
-            .into_iter()
-            .filter(|entry| entry.id.trim() != history_id)
-            .collect();
-        self.write_entries(&target_path, &filtered)?;
-        Ok(true)
+    fn forget_file(&self, path: &Path) {
+        self.known_files.borrow_mut().remove(path);
     }
 
-    pub fn update_history_prompt(&mut self, history_id: &str, prompt: &str) -> Result<bool> {
-        let cleaned = prompt.trim();
-        if cleaned.is_empty() {
-            return Err(anyhow!("prompt is empty"));
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!(READ_ONLY_MESSAGE));
         }
+        Ok(())
+    }
 
-        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
-            return Ok(false);
-        };
+    /// Encrypts (or, when `enabled` is false, decrypts) the history JSON, trash,
+    /// images and the pre-migration and broken-file copies of the history in
+    /// place and keeps writing them that way. Thumbnails and placeholders would
+    /// leak the images, so encryption deletes them and stops making new ones.
+    /// Returns how many files were converted.
+    pub fn set_encryption(&mut self, enabled: bool) -> Result<usize> {
+        if enabled && self.cipher.is_none() {
+            return Err(anyhow!("history encryption needs a key"));
+        }
+        self.encrypt = enabled;
+        // The owning instance converts the files; reads handle either form.
+        if self.read_only {
+            return Ok(0);
+        }
 
-        entries[index].prompt = cleaned.to_string();
-        self.write_entries(&target_path, &entries)?;
-        Ok(true)
+        let mut files = vec![
+            self.history_json_path.clone(),
+            backup_path(&self.history_json_path),
+        ];
+        files.extend(self.list_archive_json_paths()?);
+        files.push(self.trash_json_path.clone());
+        files.push(backup_path(&self.trash_json_path));
+        files.extend(self.list_history_copy_paths()?);
+        files.extend(
+            collect_dir(&self.images_root, "images")?
+                .into_iter()
+                .map(|file| file.path),
+        );
+        let mut converted = 0;
+        for path in files.iter().filter(|path| path.is_file()) {
+            let raw =
+                fs::read(path).with_context(|| format!("failed to read: {}", path.display()))?;
+            if is_encrypted(&raw) == enabled {
+                continue;
+            }
+            let plain = read_file(path, self.cipher.as_ref())?;
+            let sealed = self.seal(&plain)?;
+            write_file_atomic(path, &sealed, false)?;
+            self.remember_file(path, &sealed);
+            converted += 1;
+        }
+
+        if enabled {
+            let thumbs = self.base_dir.join(THUMBS_DIR);
+            if thumbs.exists() {
+                fs::remove_dir_all(&thumbs)
+                    .with_context(|| format!("failed to delete thumbs: {}", thumbs.display()))?;
+            }
+            self.image_previews.clear()?;
+        }
+        Ok(converted)
     }
 
-    pub fn append_image(
-        &mut self,
+    /// Appends to the audit log once a change is on disk. The change already
+    /// happened, so a failed append is reported instead of failing it.
+    fn record_audit(
+        &self,
+        action: &str,
         history_id: &str,
-        source_name: &str,
-        content: &[u8],
-    ) -> Result<String> {
-        let ext = Path::new(source_name)
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| format!(".{}", e.to_lowercase()))
-            .ok_or_else(|| anyhow!("unsupported file extension"))?;
-
-        if !Self::ALLOWED_EXTENSIONS
-            .iter()
-            .any(|allowed| *allowed == ext)
-        {
-            return Err(anyhow!("unsupported file extension"));
+        before: Option<&str>,
+        after: Option<&str>,
+        detail: &str,
+    ) {
+        if let Err(err) = self.audit.record(action, history_id, before, after, detail) {
+            eprintln!("監査ログの書き込みに失敗しました: {err:#}");
         }
+    }
 
-        if content.len() > Self::MAX_IMAGE_BYTES {
-            return Err(anyhow!("file size exceeds 20MB"));
+    /// `bytes` as they should be written: encrypted while encryption is on.
+    fn seal(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match (&self.cipher, self.encrypt) {
+            (Some(cipher), true) => cipher.encrypt(bytes),
+            _ => Ok(bytes.to_vec()),
         }
+    }
 
-        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
-            return Err(anyhow!("history id not found"));
+    /// Decrypts a file from a backup archive, which may come from an encrypted store.
+    fn decrypt_backup_bytes(&self, name: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher.as_ref().ok_or_else(|| {
+            anyhow!("{name} in the backup is encrypted; enable history_encryption with its key")
+        })?;
+        cipher
+            .decrypt(bytes)
+            .with_context(|| format!("failed to decrypt backup file: {name}"))
+    }
+
+    fn open_backup_file(&self, name: &str, bytes: &[u8]) -> Result<String> {
+        let plain = if is_encrypted(bytes) {
+            self.decrypt_backup_bytes(name, bytes)?
+        } else {
+            bytes.to_vec()
         };
+        String::from_utf8(plain).map_err(|_| anyhow!("{name} is not UTF-8"))
+    }
 
-        let now = Local::now();
-        let month_dir = self
-            .images_root
-            .join(now.format("%Y").to_string())
-            .join(now.format("%m").to_string());
-        fs::create_dir_all(&month_dir)
-            .with_context(|| format!("failed to create images dir: {}", month_dir.display()))?;
+    fn read_text(&self, path: &Path) -> Result<String> {
+        String::from_utf8(read_file(path, self.cipher.as_ref())?)
+            .with_context(|| format!("file is not UTF-8: {}", path.display()))
+    }
 
-        let rel_path = self.next_image_rel_path(now.naive_local(), &month_dir, &ext);
-        let abs_path = self.base_dir.join(&rel_path);
-        fs::write(&abs_path, content)
-            .with_context(|| format!("failed to write image: {}", abs_path.display()))?;
+    /// Rating scores of the values the active and archived entries were copied
+    /// with, for weighted randomizing.
+    pub fn rating_weights(&self) -> Result<RatingWeights> {
+        Ok(RatingWeights::from_entries(
+            &self.entries_in_range(ExportRange::default())?,
+        ))
+    }
 
-        entries[index].images = vec![path_to_posix(&rel_path)];
-        self.write_entries(&target_path, &entries)?;
-        Ok(path_to_posix(&rel_path))
+    /// Writes `Stats.html` next to `History.html` from the active and archived
+    /// history; `choices` are the config keywords to count. Returns its path.
+    pub fn write_stats_html(&self, choices: &[String]) -> Result<PathBuf> {
+        let entries = self.entries_in_range(ExportRange::default())?;
+        let content = build_stats_html(&compute_stats(&entries, choices));
+        let path = self.base_dir.join("Stats.html");
+        fs::write(&path, content)
+            .with_context(|| format!("failed to write html: {}", path.display()))?;
+        Ok(path)
     }
 
-    pub fn read_image_blob(&self, image_path: &str) -> Result<(Vec<u8>, &'static str)> {
-        let cleaned = image_path.trim();
-        if cleaned.is_empty() {
-            return Err(anyhow!("image path is empty"));
+    fn resolve_max_entries(max_active_entries: usize) -> usize {
+        if max_active_entries == 0 {
+            300
+        } else {
+            max_active_entries
         }
+    }
 
-        let rel_path = Path::new(cleaned);
-        if rel_path.is_absolute() {
-            return Err(anyhow!("absolute image path is not allowed"));
-        }
-        if rel_path
-            .components()
-            .any(|part| matches!(part, Component::ParentDir | Component::CurDir))
-        {
-            return Err(anyhow!("invalid image path"));
-        }
+    /// Changes the active history size; the next append archives the overflow.
+    pub fn set_max_entries(&mut self, max_active_entries: usize) {
+        self.max_active_entries = Self::resolve_max_entries(max_active_entries);
+    }
 
-        let normalized = path_to_posix(rel_path);
-        if !normalized.starts_with("images/") {
-            return Err(anyhow!("image path is out of scope"));
-        }
+    /// Re-reads `history.json` after it was edited outside the app, failing if
+    /// it no longer parses, and returns its entry count. Entries are read from
+    /// disk on every use, so there is nothing else to refresh.
+    pub fn reload(&self) -> Result<usize> {
+        self.take_external_change(&self.history_json_path);
+        Ok(self.read_entries(&self.history_json_path)?.len())
+    }
 
-        let abs_path = self.base_dir.join(rel_path);
-        let bytes = fs::read(&abs_path)
-            .with_context(|| format!("failed to read image: {}", abs_path.display()))?;
-        Ok((bytes, image_content_type(rel_path)))
+    /// Caps the gallery size; once full, a new upload evicts the oldest image.
+    pub fn set_max_images_per_entry(&mut self, max_images: usize) {
+        self.max_images_per_entry = if max_images == 0 {
+            Self::DEFAULT_MAX_IMAGES_PER_ENTRY
+        } else {
+            max_images
+        };
     }
 
-    pub fn regenerate_html(&self, server_port: u16) -> Result<()> {
-        let entries = self.read_entries(&self.history_json_path)?;
-        let archive_date_keys = self.collect_archive_date_keys()?;
+    /// Warns once fewer than `margin` copies are left before rotation; 0 disables it.
+    pub fn set_rotation_warn_margin(&mut self, margin: usize) {
+        self.rotation_warn_margin = margin;
+    }
 
-        let content = self.build_history_html(
-            &entries,
-            "Prompt History",
-            true,
-            true,
-            server_port,
-            &archive_date_keys,
-        );
-        fs::write(&self.history_html_path, content).with_context(|| {
-            format!("failed to write html: {}", self.history_html_path.display())
-        })?;
+    /// Days a deleted entry stays restorable before `purge_trash` drops it.
+    pub fn set_trash_retention_days(&mut self, days: i64) {
+        self.trash_retention_days = days.max(0);
+    }
 
-        for date_key in archive_date_keys {
-            let archive_json = self.archive_json_path(&date_key);
-            let archive_entries = if archive_json.exists() {
-                self.read_entries(&archive_json)?
-            } else {
-                Vec::new()
-            };
-            let archive_content = self.build_history_html(
-                &archive_entries,
-                &format!("Prompt History Archive {}", date_key),
-                true,
-                true,
-                server_port,
-                &[],
-            );
-            let archive_html = self.archive_html_path(&date_key);
-            fs::write(&archive_html, archive_content)
-                .with_context(|| format!("failed to write html: {}", archive_html.display()))?;
-        }
+    pub fn set_dedupe_mode(&mut self, mode: DedupeMode) {
+        self.dedupe_mode = mode;
+    }
 
-        Ok(())
+    pub fn set_delete_image_files(&mut self, delete: bool) {
+        self.delete_image_files = delete;
     }
 
-    fn ensure_files(&self) -> Result<()> {
-        fs::create_dir_all(&self.base_dir)
-            .with_context(|| format!("failed to create base dir: {}", self.base_dir.display()))?;
-        fs::create_dir_all(&self.images_root).with_context(|| {
-            format!(
-                "failed to create images dir: {}",
-                self.images_root.display()
-            )
-        })?;
+    /// Splits each archive day into HTML pages of `size` cards; 0 keeps one page.
+    pub fn set_archive_page_size(&mut self, size: usize) {
+        self.archive_page_size = size;
+    }
 
-        if !self.history_json_path.exists() {
-            fs::write(&self.history_json_path, "[]").with_context(|| {
-                format!(
-                    "failed to init history file: {}",
-                    self.history_json_path.display()
-                )
-            })?;
-            return Ok(());
-        }
+    /// Also archives active entries older than `days` days; 0 rotates by count only.
+    pub fn set_archive_after_days(&mut self, days: i64) {
+        self.archive_after_days = days.max(0);
+    }
 
-        match self.read_entries(&self.history_json_path) {
-            Ok(entries) => self.write_entries(&self.history_json_path, &entries),
-            Err(_) => {
-                let now_tag = Local::now().format("%Y%m%d_%H%M%S").to_string();
-                let backup = self
-                    .base_dir
-                    .join(format!("history.broken.{}.json", now_tag));
-                fs::rename(&self.history_json_path, backup).with_context(|| {
-                    format!(
-                        "failed to backup broken history: {}",
-                        self.history_json_path.display()
-                    )
-                })?;
-                fs::write(&self.history_json_path, "[]").with_context(|| {
-                    format!(
-                        "failed to reset history file: {}",
-                        self.history_json_path.display()
-                    )
-                })
-            }
-        }
+    pub fn set_archive_grouping(&mut self, grouping: ArchiveGrouping) {
+        self.archive_grouping = grouping;
     }
 
-    fn archive_json_path(&self, date_key: &str) -> PathBuf {
-        self.base_dir.join(format!("History_{}.json", date_key))
+    /// Caps the total size of the archive JSON files; past it the oldest archive
+    /// files are dropped. 0 keeps every archive.
+    pub fn set_archive_max_bytes(&mut self, bytes: u64) {
+        self.archive_max_bytes = bytes;
     }
 
-    fn archive_html_path(&self, date_key: &str) -> PathBuf {
-        self.base_dir.join(format!("History_{}.html", date_key))
+    /// Daily archive files allowed before `compact_archives` merges past months;
+    /// 0 never compacts.
+    pub fn set_archive_compact_threshold(&mut self, threshold: usize) {
+        self.archive_compact_threshold = threshold;
     }
 
-    fn rotate_if_needed(&self, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>> {
-        let overflow = entries.len() as isize - self.max_active_entries as isize;
-        if overflow <= 0 {
-            return Ok(entries);
+    /// Archives older than `months` are exported to a ZIP in `export_dir`
+    /// (`exports` in the data dir when `None`) and then removed by
+    /// `enforce_retention`. 0 keeps every archive.
+    pub fn set_retention(&mut self, months: u32, export_dir: Option<PathBuf>) {
+        self.retention_months = months;
+        if let Some(dir) = export_dir {
+            self.retention_export_dir = self.base_dir.join(dir);
         }
+    }
 
-        let split_at = usize::try_from(overflow).unwrap_or(0);
-        let moving = entries[..split_at].to_vec();
-        let kept = entries[split_at..].to_vec();
+    /// Whether `history.json` was created by this store, i.e. the data dir is new.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
 
-        let mut grouped: BTreeMap<String, Vec<HistoryEntry>> = BTreeMap::new();
-        for entry in moving {
-            let date_key = self.date_key_from_entry(&entry);
-            grouped.entry(date_key).or_default().push(entry);
-        }
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
 
-        for (date_key, items) in grouped {
-            let json_path = self.archive_json_path(&date_key);
-            let existing = if json_path.exists() {
-                self.read_entries(&json_path)?
-            } else {
-                Vec::new()
-            };
+    pub fn seed_samples(&mut self) -> Result<usize> {
+        self.ensure_writable()?;
+        let samples_dir = self.base_dir.join(SAMPLE_IMAGES_DIR);
+        fs::create_dir_all(&samples_dir)
+            .with_context(|| format!("failed to create samples dir: {}", samples_dir.display()))?;
 
-            let mut merged_by_id: BTreeMap<String, HistoryEntry> = BTreeMap::new();
-            for entry in existing.into_iter().chain(items.into_iter()) {
-                merged_by_id.insert(entry.id.clone(), entry);
-            }
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        let now = Local::now();
+        for (prompt, file_name, bytes) in SAMPLE_ENTRIES {
+            let abs_path = samples_dir.join(file_name);
+            fs::write(&abs_path, self.seal(bytes)?)
+                .with_context(|| format!("failed to write image: {}", abs_path.display()))?;
 
-            let merged: Vec<HistoryEntry> = merged_by_id.into_values().collect();
-            self.write_entries(&json_path, &merged)?;
+            let entry_id = self.next_entry_id(now.naive_local(), &entries);
+            entries.push(HistoryEntry {
+                id: entry_id,
+                ts: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+                prompt: prompt.to_string(),
+                images: vec![format!("{SAMPLE_IMAGES_DIR}/{file_name}")],
+                sample: true,
+                locked: false,
+                tags: Vec::new(),
+                note: String::new(),
+                rating: 0,
+                count: 1,
+                selections: BTreeMap::new(),
+                translation: String::new(),
+                score: None,
+                image_metadata: BTreeMap::new(),
+            });
         }
 
-        Ok(kept)
+        let kept_entries = self.rotate_if_needed(entries)?;
+        self.write_entries(&self.history_json_path, &kept_entries)?;
+        Ok(SAMPLE_ENTRIES.len())
     }
 
-    fn find_entry_container(
-        &self,
-        history_id: &str,
-    ) -> Result<Option<(PathBuf, Vec<HistoryEntry>, usize)>> {
+    pub fn delete_samples(&mut self) -> Result<usize> {
         let mut sources = vec![self.history_json_path.clone()];
         sources.extend(self.list_archive_json_paths()?);
 
+        let mut removed = 0;
         for source in sources {
             if !source.exists() {
                 continue;
             }
-            let entries = self.read_entries(&source)?;
-            if let Some((index, _)) = entries
-                .iter()
-                .enumerate()
-                .find(|(_, entry)| entry.id.trim() == history_id)
-            {
-                return Ok(Some((source, entries, index)));
+            let (samples, kept): (Vec<HistoryEntry>, Vec<HistoryEntry>) = self
+                .read_entries(&source)?
+                .into_iter()
+                .partition(|entry| entry.sample && !entry.locked);
+            if samples.is_empty() {
+                continue;
+            }
+
+            self.write_entries(&source, &kept)?;
+            removed += samples.len();
+            for sample in &samples {
+                self.record_audit("delete", &sample.id, Some(&sample.prompt), None, "sample");
+            }
+            for image in samples.iter().flat_map(|entry| entry.images.iter()) {
+                if image.starts_with(&format!("{SAMPLE_IMAGES_DIR}/")) {
+                    let _ = fs::remove_file(self.base_dir.join(image));
+                    let _ = self.image_hashes.remove(image);
+                }
             }
         }
 
-        Ok(None)
+        Ok(removed)
     }
 
-    fn list_archive_json_paths(&self) -> Result<Vec<PathBuf>> {
-        let mut paths = Vec::new();
-        for item in fs::read_dir(&self.base_dir)
-            .with_context(|| format!("failed to list base dir: {}", self.base_dir.display()))?
-        {
-            let item = item?;
-            let path = item.path();
-            let Some(file_name) = path.file_name().and_then(|v| v.to_str()) else {
+    pub fn audit_records(&self, limit: usize) -> Result<Vec<AuditRecord>> {
+        self.audit.read_recent(limit)
+    }
+
+    pub fn smart_filters(&self) -> Result<Vec<SmartFilter>> {
+        self.smart_filters.list()
+    }
+
+    pub fn save_smart_filter(&mut self, filter: SmartFilter) -> Result<Vec<SmartFilter>> {
+        self.ensure_writable()?;
+        self.smart_filters.upsert(filter)
+    }
+
+    /// `None` when no filter has that name.
+    pub fn delete_smart_filter(&mut self, name: &str) -> Result<Option<Vec<SmartFilter>>> {
+        self.ensure_writable()?;
+        self.smart_filters.remove(name)
+    }
+
+    pub fn rotation_status(&self) -> Result<RotationStatus> {
+        let entries = self.read_entries(&self.history_json_path)?;
+        Ok(self.rotation_status_for(&entries))
+    }
+
+    /// Archives the entries the rotation warning lists, ahead of the next copies.
+    pub fn archive_pending(&mut self) -> Result<usize> {
+        let entries = self.read_entries(&self.history_json_path)?;
+        let pending = self.rotation_status_for(&entries).pending_ids.len();
+        if pending == 0 {
+            return Ok(0);
+        }
+
+        let kept = entries[pending..].to_vec();
+        self.archive_entries(entries[..pending].to_vec())?;
+        self.write_entries(&self.history_json_path, &kept)?;
+        Ok(pending)
+    }
+
+    /// Renders active and archived entries in `range`, oldest first.
+    pub fn export(&self, format: ExportFormat, range: ExportRange) -> Result<String> {
+        let entries = self.entries_in_range(range)?;
+        Ok(format.render(&entries))
+    }
+
+    /// Writes a ZIP of `history.json`, every `History_YYYYMMDD.json` archive and
+    /// the images folder, laid out as in the history folder.
+    pub fn write_backup<W: Write + Seek>(&self, writer: W) -> Result<W> {
+        write_backup_zip(&self.backup_files(None)?, writer)
+    }
+
+    /// What `write_backup` zips. With `staging`, the history JSON is copied
+    /// there first, so the list can be zipped after the store is unlocked
+    /// without later writes changing the backup; images are never rewritten
+    /// in place and are listed where they are.
+    pub fn backup_files(&self, staging: Option<&Path>) -> Result<Vec<BackupFile>> {
+        let mut files = Vec::new();
+        let mut json_paths = vec![self.history_json_path.clone()];
+        json_paths.extend(self.list_archive_json_paths()?);
+        for path in json_paths.into_iter().filter(|path| path.is_file()) {
+            let Some(name) = path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .map(str::to_string)
+            else {
                 continue;
             };
-            if !file_name.starts_with("History_") || !file_name.ends_with(".json") {
+            let path = match staging {
+                Some(dir) => {
+                    let copy = dir.join(&name);
+                    fs::copy(&path, &copy)
+                        .with_context(|| format!("failed to copy: {}", path.display()))?;
+                    copy
+                }
+                None => path,
+            };
+            files.push(BackupFile { name, path });
+        }
+        files.extend(collect_dir(&self.images_root, "images")?);
+        Ok(files)
+    }
+
+    /// Exports entries in `range` (limited to `history_ids` when non-empty) with
+    /// each prompt replaced by `render(entry)`. Entries it returns `None` for are
+    /// left out and counted as skipped. The stored history is never modified.
+    pub fn export_rerendered(
+        &self,
+        format: ExportFormat,
+        range: ExportRange,
+        history_ids: &[String],
+        render: impl Fn(&HistoryEntry) -> Option<String>,
+    ) -> Result<RerenderExport> {
+        let mut rendered = Vec::new();
+        let mut skipped = 0;
+        for mut entry in self.entries_in_range(range)? {
+            if !history_ids.is_empty() && !history_ids.contains(&entry.id) {
                 continue;
             }
-            let date_key = file_name
-                .strip_prefix("History_")
-                .and_then(|v| v.strip_suffix(".json"))
-                .unwrap_or_default();
-            if date_key.len() == 8 && date_key.chars().all(|ch| ch.is_ascii_digit()) {
-                paths.push(path);
+            match render(&entry) {
+                Some(prompt) => {
+                    entry.prompt = prompt;
+                    rendered.push(entry);
+                }
+                None => skipped += 1,
             }
         }
-        paths.sort_by(|a, b| b.cmp(a));
-        Ok(paths)
+        Ok(RerenderExport {
+            content: format.render(&rendered),
+            rendered: rendered.len(),
+            skipped,
+        })
     }
 
-    fn date_key_from_entry(&self, entry: &HistoryEntry) -> String {
-        if entry.id.len() >= 8 && entry.id.chars().take(8).all(|ch| ch.is_ascii_digit()) {
-            return entry.id[..8].to_string();
-        }
+    /// `limit` active entries from `offset`, newest first, as `History.html`
+    /// renders them.
+    pub fn list_entries(&self, offset: usize, limit: usize) -> Result<HistoryList> {
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        let rotation = self.rotation_status_for(&entries);
+        let previews = self.image_previews.load().unwrap_or_default();
+        let view = CardView {
+            interactive: true,
+            allow_delete: true,
+            active_page: true,
+            pending_ids: &rotation.pending_ids,
+            previews: &previews,
+        };
+        entries.sort_by(|a, b| b.id.cmp(&a.id));
+        let total = entries.len();
+        let entries = entries
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|entry| HistoryListItem {
+                html: self.render_card(&entry, &view),
+                entry,
+            })
+            .collect();
+        Ok(HistoryList {
+            entries,
+            offset,
+            total,
+        })
+    }
 
-        let digits: String = entry.ts.chars().filter(|ch| ch.is_ascii_digit()).collect();
-        if digits.len() >= 8 {
-            return digits[..8].to_string();
-        }
+    /// One entry's card as its page (active or archive) renders it, for
+    /// mutation responses; `None` when the id is unknown.
+    pub fn card(&self, history_id: &str) -> Result<Option<HistoryListItem>> {
+        let Some((source, mut entries, index)) = self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(None);
+        };
+        let active_page = source == self.history_json_path;
+        let rotation = active_page.then(|| self.rotation_status_for(&entries));
+        let previews = self.image_previews.load().unwrap_or_default();
+        let view = CardView {
+            interactive: true,
+            allow_delete: true,
+            active_page,
+            pending_ids: rotation.as_ref().map_or(&[], |r| r.pending_ids.as_slice()),
+            previews: &previews,
+        };
+        let entry = entries.swap_remove(index);
+        Ok(Some(HistoryListItem {
+            html: self.render_card(&entry, &view),
+            entry,
+        }))
+    }
 
-        Local::now().format("%Y%m%d").to_string()
+    /// Searches the active history and the archives in `range` for `query`
+    /// (case-insensitive, prompt or note). Archive files dated outside the range
+    /// are never read.
+    pub fn search(&self, range: ExportRange, query: &str, limit: usize) -> Result<HistorySearch> {
+        let filter = SmartFilter {
+            query: query.trim().to_string(),
+            ..SmartFilter::default()
+        };
+        let mut hits = Vec::new();
+        for source in self.sources_in_range(range)? {
+            let archive_date_key = (source != self.history_json_path)
+                .then(|| source.file_stem().and_then(|v| v.to_str()))
+                .flatten()
+                .and_then(|v| v.strip_prefix("History_"));
+            let entries = self.read_entries(&source)?;
+            hits.extend(
+                entries
+                    .iter()
+                    .filter(|entry| {
+                        range.contains(&self.date_key_from_entry(entry)) && filter.matches(entry)
+                    })
+                    .map(|entry| HistorySearchHit {
+                        entry: entry.clone(),
+                        page: match archive_date_key {
+                            Some(date_key) => self.archive_page_of(date_key, &entries, &entry.id),
+                            None => "History.html".to_string(),
+                        },
+                    }),
+            );
+        }
+        hits.sort_by(|a, b| b.entry.id.cmp(&a.entry.id));
+        let total = hits.len();
+        hits.truncate(limit);
+        Ok(HistorySearch { hits, total })
     }
 
-    fn collect_archive_date_keys(&self) -> Result<Vec<String>> {
-        let mut keys = Vec::new();
+    /// `history.json` plus the archive files whose date key falls in `range`.
+    fn sources_in_range(&self, range: ExportRange) -> Result<Vec<PathBuf>> {
+        let mut sources = vec![self.history_json_path.clone()];
         for path in self.list_archive_json_paths()? {
-            let Some(file_name) = path.file_name().and_then(|v| v.to_str()) else {
-                continue;
-            };
-            if let Some(date_key) = file_name
-                .strip_prefix("History_")
-                .and_then(|v| v.strip_suffix(".json"))
-            {
-                if date_key.len() == 8 && date_key.chars().all(|ch| ch.is_ascii_digit()) {
-                    keys.push(date_key.to_string());
-                }
+            if archive_key_of(&path).is_some_and(|key| range.overlaps(key)) {
+                sources.push(path);
             }
         }
-        keys.sort_by(|a, b| b.cmp(a));
-        Ok(keys)
+        sources.retain(|source| source.exists());
+        Ok(sources)
     }
 
-    fn read_entries(&self, source: &Path) -> Result<Vec<HistoryEntry>> {
-        let raw_text = fs::read_to_string(source)
-            .with_context(|| format!("failed to read json: {}", source.display()))?;
-        let raw: Value = serde_json::from_str(&raw_text)
-            .with_context(|| format!("failed to parse json: {}", source.display()))?;
-
-        let Some(array) = raw.as_array() else {
-            return Err(anyhow!("json is not an array: {}", source.display()));
-        };
+    fn entries_in_range(&self, range: ExportRange) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        for source in self.sources_in_range(range)? {
+            entries.extend(
+                self.read_entries(&source)?
+                    .into_iter()
+                    .filter(|entry| range.contains(&self.date_key_from_entry(entry))),
+            );
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
 
-        let mut normalized = Vec::new();
-        for item in array {
-            let Some(obj) = item.as_object() else {
-                continue;
-            };
+    /// Merges entries into their date archives. Entries whose id already exists with
+    /// the same timestamp and prompt are skipped; other id collisions get a fresh id.
+    pub fn import_entries(&mut self, entries: Vec<HistoryEntry>) -> Result<ImportSummary> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
 
-            let entry_id = obj
-                .get("id")
-                .and_then(Value::as_str)
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-            let ts = obj
-                .get("ts")
-                .and_then(Value::as_str)
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-            let prompt = obj
-                .get("prompt")
-                .and_then(Value::as_str)
-                .unwrap_or_default()
-                .trim()
-                .to_string();
+        let mut known = Vec::new();
+        for source in sources {
+            if source.exists() {
+                known.extend(self.read_entries(&source)?);
+            }
+        }
 
-            let mut images = Vec::new();
-            if let Some(raw_images) = obj.get("images").and_then(Value::as_array) {
-                for value in raw_images {
-                    if let Some(path) = value.as_str().map(str::trim).filter(|v| !v.is_empty()) {
-                        images.push(path.to_string());
-                    }
+        let mut summary = ImportSummary::default();
+        let mut incoming = Vec::new();
+        for mut entry in entries {
+            let ts = NaiveDateTime::parse_from_str(entry.ts.trim(), "%Y-%m-%d %H:%M:%S")
+                .unwrap_or_else(|_| Local::now().naive_local());
+            entry.ts = ts.format("%Y-%m-%d %H:%M:%S").to_string();
+            entry.id = entry.id.trim().to_string();
+            entry.sample = false;
+
+            if let Some(existing) = known.iter().find(|known| known.id == entry.id) {
+                if existing.ts == entry.ts && existing.prompt == entry.prompt {
+                    summary.duplicates += 1;
+                    continue;
                 }
+                entry.id = self.next_entry_id(ts, &known);
+                summary.renamed += 1;
+            } else if entry.id.is_empty() {
+                entry.id = self.next_entry_id(ts, &known);
+            }
+
+            known.push(entry.clone());
+            incoming.push(entry);
+        }
+
+        summary.imported = incoming.len();
+        if !incoming.is_empty() {
+            self.archive_entries(incoming.clone())?;
+        }
+        for entry in &incoming {
+            self.record_audit("create", &entry.id, None, Some(&entry.prompt), "import");
+        }
+        Ok(summary)
+    }
+
+    /// Restores a ZIP made by `write_backup`. The whole archive is validated first;
+    /// entries merge by id (ids already present are kept as they are) into the file
+    /// they were backed up from, and images are unpacked without overwriting.
+    pub fn restore_backup(&mut self, data: &[u8]) -> Result<RestoreSummary> {
+        self.ensure_writable()?;
+        let contents = read_backup_zip(data)?;
+        let mut backed_up = Vec::new();
+        for (name, bytes) in &contents.history_files {
+            let text = self.open_backup_file(name, bytes)?;
+            let entries =
+                parse_import_json(&text).with_context(|| format!("invalid backup file: {name}"))?;
+            backed_up.push((name == "history.json", entries));
+        }
+
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+        let mut known_ids = Vec::new();
+        for source in sources {
+            if source.exists() {
+                known_ids.extend(self.read_entries(&source)?.into_iter().map(|e| e.id));
             }
-            if images.len() > 1 {
-                if let Some(last) = images.last().cloned() {
-                    images = vec![last];
+        }
+
+        let mut summary = RestoreSummary::default();
+        let mut active = Vec::new();
+        let mut archived = Vec::new();
+        for (is_active, entries) in backed_up {
+            for entry in entries {
+                if entry.id.is_empty() || known_ids.contains(&entry.id) {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                known_ids.push(entry.id.clone());
+                if is_active {
+                    active.push(entry);
+                } else {
+                    archived.push(entry);
                 }
             }
+        }
 
-            if entry_id.is_empty() || ts.is_empty() || prompt.is_empty() {
+        for (relative, bytes) in &contents.images {
+            let target = self.base_dir.join(relative);
+            if target.exists() {
                 continue;
             }
-
-            normalized.push(HistoryEntry {
-                id: entry_id,
-                ts,
-                prompt,
-                images,
-            });
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create dir: {}", parent.display()))?;
+            }
+            let plain = if is_encrypted(bytes) {
+                self.decrypt_backup_bytes(&relative.to_string_lossy(), bytes)?
+            } else {
+                bytes.clone()
+            };
+            fs::write(&target, self.seal(&plain)?)
+                .with_context(|| format!("failed to write image: {}", target.display()))?;
+            summary.images += 1;
         }
 
-        Ok(normalized)
+        let restored: Vec<HistoryEntry> = active.iter().chain(&archived).cloned().collect();
+        if !active.is_empty() {
+            let mut entries = self.read_entries(&self.history_json_path)?;
+            entries.extend(active);
+            entries.sort_by(|a, b| a.id.cmp(&b.id));
+            let kept_entries = self.rotate_if_needed(entries)?;
+            self.write_entries(&self.history_json_path, &kept_entries)?;
+        }
+        if !archived.is_empty() {
+            self.archive_entries(archived)?;
+        }
+        for entry in &restored {
+            self.record_audit("create", &entry.id, None, Some(&entry.prompt), "restore");
+        }
+        summary.restored = restored.len();
+        Ok(summary)
     }
 
-    fn write_entries(&self, target: &Path, entries: &[HistoryEntry]) -> Result<()> {
-        let payload =
-            serde_json::to_string_pretty(entries).context("failed to serialize history json")?;
-        let tmp_name = format!(
-            "{}.tmp",
-            target
-                .file_name()
-                .and_then(|v| v.to_str())
-                .unwrap_or("history.json")
-        );
-        let tmp_path = target.with_file_name(tmp_name);
+    /// Dry run of the housekeeping rules: what `apply_housekeeping` would change.
+    pub fn plan_housekeeping(
+        &self,
+        rules: &[HousekeepingRule],
+        now: NaiveDateTime,
+    ) -> Result<Vec<PlannedAction>> {
+        self.run_housekeeping(rules, now, false)
+    }
 
-        fs::write(&tmp_path, payload)
-            .with_context(|| format!("failed to write temp json: {}", tmp_path.display()))?;
-        if target.exists() {
-            fs::remove_file(target)
-                .with_context(|| format!("failed to remove old json: {}", target.display()))?;
-        }
-        fs::rename(&tmp_path, target)
-            .with_context(|| format!("failed to replace json: {}", target.display()))
+    pub fn apply_housekeeping(
+        &mut self,
+        rules: &[HousekeepingRule],
+        now: NaiveDateTime,
+    ) -> Result<Vec<PlannedAction>> {
+        self.run_housekeeping(rules, now, true)
     }
 
-    fn next_entry_id(&self, now: NaiveDateTime, entries: &[HistoryEntry]) -> String {
-        let base = now.format("%Y%m%d_%H%M%S").to_string();
-        let prefix = format!("{}{}", base, "_");
-        let mut seq: i32 = 1;
+    fn run_housekeeping(
+        &self,
+        rules: &[HousekeepingRule],
+        now: NaiveDateTime,
+        apply: bool,
+    ) -> Result<Vec<PlannedAction>> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
 
-        for entry in entries {
-            if !entry.id.starts_with(&prefix) {
+        let mut planned = Vec::new();
+        for source in sources {
+            if !source.exists() {
                 continue;
             }
-            let parts: Vec<&str> = entry.id.split('_').collect();
-            if parts.len() != 3 {
+            let is_active = source == self.history_json_path;
+            let mut entries = self.read_entries(&source)?;
+            let mut archive_ids = Vec::new();
+            let mut trash_ids = Vec::new();
+            let mut changed = false;
+
+            for entry in entries.iter_mut() {
+                for rule in rules {
+                    if trash_ids.contains(&entry.id) {
+                        break;
+                    }
+                    if !rule.matches(entry, now) {
+                        continue;
+                    }
+                    let effective = match &rule.action {
+                        RuleAction::Tag(tag) => !entry.tags.contains(tag),
+                        RuleAction::Archive => is_active && !archive_ids.contains(&entry.id),
+                        RuleAction::Lock => !entry.locked,
+                        RuleAction::Trash => !entry.locked,
+                    };
+                    if !effective {
+                        continue;
+                    }
+
+                    match &rule.action {
+                        RuleAction::Tag(tag) => entry.tags.push(tag.clone()),
+                        RuleAction::Archive => archive_ids.push(entry.id.clone()),
+                        RuleAction::Lock => entry.locked = true,
+                        RuleAction::Trash => trash_ids.push(entry.id.clone()),
+                    }
+                    changed = true;
+                    planned.push(PlannedAction {
+                        history_id: entry.id.clone(),
+                        ts: entry.ts.clone(),
+                        prompt: entry.prompt.clone(),
+                        rule: rule.name.clone(),
+                        action: rule.action.clone(),
+                    });
+                }
+            }
+
+            if !apply || !changed {
                 continue;
             }
-            if let Ok(parsed) = parts[2].parse::<i32>() {
-                seq = seq.max(parsed + 1);
+            let (trashed, entries): (Vec<HistoryEntry>, Vec<HistoryEntry>) = entries
+                .into_iter()
+                .partition(|entry| trash_ids.contains(&entry.id));
+            if !trashed.is_empty() {
+                let mut trash = self.read_trash()?;
+                let deleted_at = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+                let origin = source
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                trash.extend(trashed.iter().map(|entry| TrashEntry {
+                    entry: entry.clone(),
+                    deleted_at: deleted_at.clone(),
+                    origin: origin.clone(),
+                }));
+                self.write_trash(&trash)?;
+            }
+            let (moving, kept): (Vec<HistoryEntry>, Vec<HistoryEntry>) = entries
+                .into_iter()
+                .partition(|entry| archive_ids.contains(&entry.id));
+            if !moving.is_empty() {
+                self.archive_entries(moving)?;
+            }
+            self.write_entries(&source, &kept)?;
+            for entry in &trashed {
+                self.record_audit(
+                    "delete",
+                    &entry.id,
+                    Some(&entry.prompt),
+                    None,
+                    "housekeeping",
+                );
             }
         }
 
-        format!("{base}_{seq:04}")
+        Ok(planned)
     }
 
-    fn next_image_rel_path(&self, now: NaiveDateTime, month_dir: &Path, ext: &str) -> PathBuf {
-        let base = now.format("%Y%m%d_%H%M%S").to_string();
-        let year = now.format("%Y").to_string();
-        let month = now.format("%m").to_string();
-        let mut seq = 1u32;
+    pub fn append_history(&mut self, prompt: &str) -> Result<HistoryEntry> {
+        let cleaned = prompt.trim();
+        if cleaned.is_empty() {
+            return Err(anyhow!("prompt is empty"));
+        }
 
-        loop {
-            let file_name = format!("{}_{:02}{}", base, seq, ext);
-            let abs_path = month_dir.join(&file_name);
-            if !abs_path.exists() {
-                return PathBuf::from("images")
-                    .join(year.clone())
-                    .join(month.clone())
-                    .join(file_name);
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        let now = Local::now();
+        if self.dedupe_mode != DedupeMode::Off {
+            let normalized = normalize_prompt(cleaned);
+            if let Some(index) = entries
+                .iter()
+                .rposition(|entry| normalize_prompt(&entry.prompt) == normalized)
+            {
+                return self.dedupe_existing(entries, index, now.naive_local());
             }
-            seq += 1;
         }
-    }
 
-    fn build_history_html(
-        &self,
-        entries: &[HistoryEntry],
-        title: &str,
-        interactive: bool,
-        allow_delete: bool,
-        server_port: u16,
-        archive_date_keys: &[String],
-    ) -> String {
-        let mut sorted_entries = entries.to_vec();
-        sorted_entries.sort_by(|a, b| b.id.cmp(&a.id));
-
-        let mut cards = Vec::new();
-        for entry in &sorted_entries {
-            let entry_id = encode_double_quoted_attribute(&entry.id).to_string();
-            let ts = encode_text(&entry.ts).to_string();
-            let prompt_html = encode_text(&entry.prompt).to_string();
-
-            let selected_image_path = entry.images.first().cloned().unwrap_or_default();
-            let selected_image_attr =
-                encode_double_quoted_attribute(&selected_image_path).to_string();
-            let has_image = !selected_image_path.is_empty();
-
-            let mut images_block = String::new();
-            if has_image {
-                let safe_path_attr =
-                    encode_double_quoted_attribute(&selected_image_path).to_string();
-                let safe_path_text = encode_text(&selected_image_path).to_string();
-                images_block.push_str(&format!(
-                    "<div class=\"image-item is-selected\" data-image-path=\"{}\"><a class=\"thumb-image-link\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\"><img class=\"thumb-image\" src=\"{}\" alt=\"history image\" loading=\"lazy\" /></a><a class=\"thumb-path\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a></div>",
-                    safe_path_attr, safe_path_attr, safe_path_attr, safe_path_attr, safe_path_text
-                ));
-            } else {
-                images_block.push_str("<span class=\"muted\">画像なし</span>");
-            }
+        let entry_id = self.next_entry_id(now.naive_local(), &entries);
+        let entry = HistoryEntry {
+            id: entry_id,
+            ts: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            prompt: cleaned.to_string(),
+            images: Vec::new(),
+            sample: false,
+            locked: false,
+            tags: Vec::new(),
+            note: String::new(),
+            rating: 0,
+            count: 1,
+            selections: BTreeMap::new(),
+            translation: String::new(),
+            score: None,
+            image_metadata: BTreeMap::new(),
+        };
 
-            let delete_btn = if interactive && allow_delete {
-                "<button class=\"btn delete-btn\">削除</button>"
-            } else {
-                ""
-            };
-            let image_copy_disabled = if has_image { "" } else { " disabled" };
+        entries.push(entry.clone());
+        let kept_entries = self.rotate_if_needed(entries)?;
+        self.write_entries(&self.history_json_path, &kept_entries)?;
+        self.record_audit("create", &entry.id, None, Some(&entry.prompt), "");
+        Ok(entry)
+    }
 
-            let upload_block = if interactive {
-                let upload_text = if has_image {
-                    "画像追加済み（差し替えはD＆Dまたはクリック）"
-                } else {
-                    "画像追加: ドラッグ&ドロップ または クリック"
-                };
-                let upload_state_class = if has_image {
-                    "has-image"
-                } else {
-                    "needs-image"
-                };
-                format!(
-                    "<section class=\"upload\" data-history-id=\"{}\"><div class=\"dropzone {}\">{}</div><input class=\"file-input\" type=\"file\" accept=\".png,.jpg,.jpeg,.webp,.gif\" /></section>",
-                    entry_id,
-                    upload_state_class,
-                    encode_text(upload_text)
-                )
-            } else {
-                String::new()
-            };
+    /// Applies the dedupe mode to `entries[index]`, the active entry that
+    /// matches a newly copied prompt.
+    fn dedupe_existing(
+        &mut self,
+        mut entries: Vec<HistoryEntry>,
+        index: usize,
+        now: NaiveDateTime,
+    ) -> Result<HistoryEntry> {
+        match self.dedupe_mode {
+            DedupeMode::Off | DedupeMode::Skip => Ok(entries.swap_remove(index)),
+            DedupeMode::Count => {
+                entries[index].count = entries[index].count.saturating_add(1);
+                self.write_entries(&self.history_json_path, &entries)?;
+                let entry = entries.swap_remove(index);
+                self.record_audit(
+                    "count",
+                    &entry.id,
+                    None,
+                    Some(&entry.prompt),
+                    &entry.count.to_string(),
+                );
+                Ok(entry)
+            }
+            DedupeMode::Bump => {
+                let next_id = self.next_entry_id(now, &entries);
+                let mut entry = entries.remove(index);
+                let previous_id = std::mem::replace(&mut entry.id, next_id);
+                entry.ts = now.format("%Y-%m-%d %H:%M:%S").to_string();
+                entries.push(entry.clone());
+                self.write_entries(&self.history_json_path, &entries)?;
+                // Undo entries keyed by the old id could no longer find it.
+                self.undo_stack
+                    .retain(|operation| operation.history_id() != previous_id);
+                self.record_audit("bump", &entry.id, None, Some(&entry.prompt), &previous_id);
+                Ok(entry)
+            }
+        }
+    }
 
-            cards.push(format!(
-                "<article class=\"entry\" data-history-id=\"{}\" data-has-image=\"{}\" data-selected-image=\"{}\"><header class=\"entry-header\"><span class=\"timestamp\">{}</span></header><div class=\"entry-body\"><section class=\"prompt-pane\"><div class=\"prompt-toolbar\"><button class=\"btn overwrite-btn\">上書き</button><button class=\"btn copy-btn\">コピー</button>{}</div><textarea class=\"prompt-editor\" spellcheck=\"false\">{}</textarea></section><section class=\"media-pane\">{}<section class=\"images\">{}</section><button class=\"btn image-copy-btn\"{}>画像をクリップボードにコピー</button></section></div></article>",
-                entry_id,
-                if has_image { "true" } else { "false" },
-                selected_image_attr,
-                ts,
-                delete_btn,
-                prompt_html,
-                upload_block,
-                images_block,
-                image_copy_disabled
-            ));
+    pub fn delete_history(&mut self, history_id: &str) -> Result<bool> {
+        let history_id = history_id.trim();
+        if history_id.is_empty() {
+            return Ok(false);
         }
 
-        let body_cards = if cards.is_empty() {
-            "<p class=\"empty\">履歴はまだありません。</p>".to_string()
-        } else {
-            cards.join("\n")
+        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
+            return Ok(false);
         };
+        ensure_unlocked(&entries[index])?;
+
+        let entry = entries.remove(index);
+        let before = entry.prompt.clone();
+        let mut trash = self.read_trash()?;
+        trash.push(TrashEntry {
+            entry,
+            deleted_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            origin: target_path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .unwrap_or("history.json")
+                .to_string(),
+        });
+        self.write_trash(&trash)?;
+        self.write_entries(&target_path, &entries)?;
+        self.record_audit("delete", history_id, Some(&before), None, "trash");
+        self.push_undo(UndoOperation::Delete {
+            history_id: history_id.to_string(),
+        });
+        Ok(true)
+    }
 
-        let archive_links = if archive_date_keys.is_empty() {
-            String::new()
-        } else {
-            let mut links = Vec::new();
-            for date_key in archive_date_keys {
-                let href = format!("History_{}.html", date_key);
-                links.push(format!(
-                    "<a class=\"archive-link\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a>",
-                    encode_double_quoted_attribute(&href),
-                    encode_text(&href)
-                ));
-            }
-            format!(
-                "<section class=\"archives\"><h2>Archives</h2><div class=\"archive-list\">{}</div></section>",
-                links.join("")
-            )
-        };
-        let runtime_notice = if allow_delete {
-            "<p class=\"runtime-note\">※このページの上書き・削除・画像追加・画像コピー機能は、アプリ起動中のみ使用できます。</p>"
-        } else {
-            ""
+    /// Trashed entries, most recently deleted first.
+    pub fn trash_entries(&self) -> Result<Vec<TrashEntry>> {
+        let mut trash = self.read_trash()?;
+        trash.reverse();
+        Ok(trash)
+    }
+
+    /// Moves a trashed entry back where it was deleted from. Entries deleted
+    /// from the active page go to their date archive once the page is full.
+    pub fn restore_history(&mut self, history_id: &str) -> Result<bool> {
+        let history_id = history_id.trim();
+        let mut trash = self.read_trash()?;
+        let Some(index) = trash
+            .iter()
+            .position(|item| item.entry.id.trim() == history_id)
+        else {
+            return Ok(false);
         };
+        if self.find_entry_container(history_id)?.is_some() {
+            return Err(anyhow!("history entry already exists"));
+        }
 
-        let interactive_script = if interactive {
-            INTERACTIVE_SCRIPT_TEMPLATE
-                .replace("__API_BASE__", &format!("http://127.0.0.1:{server_port}"))
+        let TrashEntry { entry, origin, .. } = trash.remove(index);
+        let prompt = entry.prompt.clone();
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        if origin == "history.json" && entries.len() < self.max_active_entries {
+            let position = entries.partition_point(|existing| existing.id < entry.id);
+            entries.insert(position, entry);
+            self.write_entries(&self.history_json_path, &entries)?;
         } else {
-            NON_INTERACTIVE_SCRIPT.to_string()
+            self.archive_entries(vec![entry])?;
+        }
+        self.write_trash(&trash)?;
+        self.record_audit("restore", history_id, None, Some(&prompt), &origin);
+        Ok(true)
+    }
+
+    /// Permanently drops trashed entries older than the retention window.
+    /// Image files stay on disk, as they always have on delete.
+    pub fn purge_trash(&mut self, now: NaiveDateTime) -> Result<usize> {
+        let trash = self.read_trash()?;
+        let retention = TimeDelta::days(self.trash_retention_days);
+        let (expired, kept): (Vec<TrashEntry>, Vec<TrashEntry>) =
+            trash.into_iter().partition(|item| {
+                NaiveDateTime::parse_from_str(&item.deleted_at, "%Y-%m-%d %H:%M:%S")
+                    .is_ok_and(|deleted_at| now - deleted_at >= retention)
+            });
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.write_trash(&kept)?;
+        for item in &expired {
+            self.record_audit("purge", &item.entry.id, Some(&item.entry.prompt), None, "");
+        }
+        Ok(expired.len())
+    }
+
+    /// Moves an active entry into its date archive ahead of rotation.
+    pub fn archive_entry(&mut self, history_id: &str) -> Result<bool> {
+        let history_id = history_id.trim();
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        let Some(index) = entries
+            .iter()
+            .position(|entry| entry.id.trim() == history_id)
+        else {
+            return Ok(false);
         };
 
-        let mut output = String::new();
-        output.push_str("<!doctype html>\n<html lang=\"ja\">\n<head>\n");
-        output.push_str("  <meta charset=\"utf-8\" />\n");
-        output.push_str(
-            "  <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />\n",
-        );
-        output.push_str("  <title>");
-        output.push_str(&encode_text(title));
-        output.push_str("</title>\n");
-        output.push_str(HISTORY_STYLE);
-        output.push_str("\n</head>\n<body>\n  <main class=\"wrap\">\n    <h1>");
-        output.push_str(&encode_text(title));
-        output.push_str("</h1>\n");
-        output.push_str(runtime_notice);
-        output.push_str("\n");
-        output.push_str(&archive_links);
-        output.push_str("\n");
-        output.push_str(&body_cards);
-        output.push_str("\n  </main>\n");
-        output.push_str(&interactive_script);
-        output.push_str("\n</body>\n</html>\n");
-        output
+        let entry = entries.remove(index);
+        self.archive_entries(vec![entry])?;
+        self.write_entries(&self.history_json_path, &entries)?;
+        Ok(true)
     }
-}
 
-fn path_to_posix(path: &Path) -> String {
-    path.components()
-        .map(|c| c.as_os_str().to_string_lossy().to_string())
-        .collect::<Vec<String>>()
-        .join("/")
-}
+    /// Pulls an archived entry back into the active set, keeping id order.
+    pub fn unarchive_entry(&mut self, history_id: &str) -> Result<bool> {
+        let history_id = history_id.trim();
+        let Some((source, mut archived, index)) = self.find_entry_container(history_id)? else {
+            return Ok(false);
+        };
+        if source == self.history_json_path {
+            return Err(anyhow!("history entry is already active"));
+        }
 
-fn image_content_type(path: &Path) -> &'static str {
-    match path
-        .extension()
-        .and_then(|v| v.to_str())
-        .map(|v| v.to_ascii_lowercase())
-        .as_deref()
-    {
-        Some("png") => "image/png",
-        Some("jpg") | Some("jpeg") => "image/jpeg",
-        Some("webp") => "image/webp",
-        Some("gif") => "image/gif",
-        _ => "application/octet-stream",
+        let mut entries = self.read_entries(&self.history_json_path)?;
+        if entries.len() >= self.max_active_entries {
+            return Err(anyhow!(
+                "active history is full ({} entries)",
+                self.max_active_entries
+            ));
+        }
+
+        let entry = archived.remove(index);
+        let position = entries.partition_point(|existing| existing.id < entry.id);
+        entries.insert(position, entry);
+        self.write_entries(&self.history_json_path, &entries)?;
+        self.write_entries(&source, &archived)?;
+        Ok(true)
     }
-}
 
-const HISTORY_STYLE: &str = r#"
-  <style>
-    :root {
-      --bg: #f6f6ef;
-      --panel: #ffffff;
-      --line: #1f2a44;
-      --accent: #cb4b16;
-      --accent-2: #174c7a;
-      --text: #1e1e1e;
-      --muted: #666;
+    pub fn update_history_prompt(&mut self, history_id: &str, prompt: &str) -> Result<bool> {
+        let cleaned = prompt.trim();
+        if cleaned.is_empty() {
+            return Err(anyhow!("prompt is empty"));
+        }
+
+        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
+            return Ok(false);
+        };
+        ensure_unlocked(&entries[index])?;
+
+        let before = std::mem::replace(&mut entries[index].prompt, cleaned.to_string());
+        self.write_entries(&target_path, &entries)?;
+        self.record_audit("update", history_id, Some(&before), Some(cleaned), "");
+        if before != cleaned {
+            self.push_undo(UndoOperation::Overwrite {
+                history_id: history_id.to_string(),
+                prompt: before,
+            });
+        }
+        Ok(true)
     }
-    * { box-sizing: border-box; }
-    body {
-      margin: 0;
-      color: var(--text);
-      background: radial-gradient(circle at 10% 10%, #fff8d8, transparent 35%), linear-gradient(180deg, #f7f5ec, #ece8d8);
-      font-family: "Yu Mincho", "Hiragino Mincho ProN", serif;
+
+    pub fn update_history_note(&mut self, history_id: &str, note: &str) -> Result<bool> {
+        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
+            return Ok(false);
+        };
+        ensure_unlocked(&entries[index])?;
+
+        entries[index].note = note.trim().to_string();
+        self.write_entries(&target_path, &entries)?;
+        let prompt = entries[index].prompt.as_str();
+        self.record_audit("note", history_id, Some(prompt), Some(prompt), "");
+        Ok(true)
     }
-    .wrap { max-width: 980px; margin: 32px auto; padding: 0 16px 32px; }
-    h1 { margin: 0 0 10px; font-size: 38px; letter-spacing: 0.04em; }
-    .runtime-note {
-      margin: 0 0 16px;
-      border: 1px solid #d8c78d;
-      background: #fff7dc;
-      color: #5c4a1f;
-      padding: 8px 10px;
-      font-family: "Yu Gothic UI", sans-serif;
-      font-size: 13px;
-      line-height: 1.5;
+
+    /// Attaches several images in order, checking every file first so a bad
+    /// one rejects the whole batch before anything is written.
+    pub fn append_images(
+        &mut self,
+        history_id: &str,
+        files: &[(String, Vec<u8>)],
+    ) -> Result<Vec<String>> {
+        self.ensure_writable()?;
+        let mut checked = Vec::new();
+        for (source_name, content) in files {
+            let ext = Self::check_image_upload(source_name, content)
+                .map_err(|err| anyhow!("{source_name}: {err}"))?;
+            checked.push((ext, content.as_slice()));
+        }
+        self.attach_images(history_id, &checked)
     }
-    h2 { margin: 0 0 8px; font-size: 20px; }
-    .archives {
-      margin: 0 0 16px;
-      border: 1px solid var(--line);
-      background: #fff;
-      padding: 10px;
+
+    /// The lower-cased `.ext` of an upload, if its type and size are accepted.
+    fn check_image_upload(source_name: &str, content: &[u8]) -> Result<String> {
+        let ext = Path::new(source_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| format!(".{}", e.to_lowercase()))
+            .ok_or_else(|| anyhow!("unsupported file extension"))?;
+
+        if !Self::ALLOWED_EXTENSIONS
+            .iter()
+            .any(|allowed| *allowed == ext)
+        {
+            return Err(anyhow!("unsupported file extension"));
+        }
+
+        if content.len() > Self::MAX_IMAGE_BYTES {
+            return Err(anyhow!("file size exceeds 20MB"));
+        }
+        Ok(ext)
     }
-    .archive-list { display: flex; gap: 8px; flex-wrap: wrap; }
-    .archive-link {
-      font-family: "Yu Gothic UI", sans-serif;
-      border: 1px solid var(--line);
-      padding: 4px 8px;
-      text-decoration: none;
-      color: var(--accent-2);
-      background: #f8f8f8;
-      font-size: 13px;
+
+    pub fn append_image(
+        &mut self,
+        history_id: &str,
+        source_name: &str,
+        content: &[u8],
+    ) -> Result<String> {
+        self.ensure_writable()?;
+        let ext = Self::check_image_upload(source_name, content)?;
+        let mut paths = self.attach_images(history_id, &[(ext, content)])?;
+        Ok(paths.remove(0))
     }
-    .entry {
-      border: 2px solid var(--line);
-      background: var(--panel);
-      margin-bottom: 16px;
-      padding: 12px;
-      box-shadow: 6px 6px 0 #d8d2bf;
-    }
-    .entry-header {
-      display: flex;
-      align-items: flex-start;
-      margin-bottom: 10px;
-    }
-    .entry-body {
-      display: grid;
-      grid-template-columns: minmax(0, 1fr) minmax(0, 1fr);
-      gap: 14px;
-      align-items: start;
+
+    /// Writes checked `(ext, content)` uploads and attaches them to the entry
+    /// with one history write and one undo step back to the gallery as it was.
+    fn attach_images(
+        &mut self,
+        history_id: &str,
+        uploads: &[(String, &[u8])],
+    ) -> Result<Vec<String>> {
+        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
+            return Err(anyhow!("history id not found"));
+        };
+        ensure_unlocked(&entries[index])?;
+
+        let now = Local::now();
+        let month_dir = self
+            .images_root
+            .join(now.format("%Y").to_string())
+            .join(now.format("%m").to_string());
+        fs::create_dir_all(&month_dir)
+            .with_context(|| format!("failed to create images dir: {}", month_dir.display()))?;
+
+        let mut image_paths = Vec::new();
+        let mut written = Vec::new();
+        let stored = uploads.iter().try_for_each(|(ext, content)| {
+            let rel_path = self.next_image_rel_path(now.naive_local(), &month_dir, ext);
+            let abs_path = self.base_dir.join(&rel_path);
+            fs::write(&abs_path, self.seal(content)?)
+                .with_context(|| format!("failed to write image: {}", abs_path.display()))?;
+            written.push(abs_path);
+            image_paths.push(path_to_posix(&rel_path));
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let images_before = entries[index].images.clone();
+        let stored = stored.and_then(|_| {
+            for ((ext, content), image_path) in uploads.iter().zip(&image_paths) {
+                if ext == ".png" {
+                    if let Some(metadata) = read_generation_metadata(content) {
+                        entries[index]
+                            .image_metadata
+                            .insert(image_path.clone(), metadata);
+                    }
+                }
+            }
+            let images = &mut entries[index].images;
+            images.extend(image_paths.iter().cloned());
+            // Only appends trim a gallery; reads keep every stored reference, so
+            // lowering the limit never orphans files on its own.
+            if images.len() > self.max_images_per_entry {
+                let overflow = images.len() - self.max_images_per_entry;
+                images.drain(..overflow);
+            }
+            self.write_entries(&target_path, &entries)
+        });
+        if let Err(err) = stored {
+            // Nothing refers to the files yet.
+            for path in &written {
+                let _ = fs::remove_file(path);
+            }
+            return Err(err);
+        }
+
+        let prompt = entries[index].prompt.as_str();
+        for ((_, content), image_path) in uploads.iter().zip(&image_paths) {
+            // Files that fail to decode simply never show up in similarity results
+            // and keep showing the original instead of a thumbnail.
+            if let Ok(hash) = perceptual_hash(content) {
+                self.image_hashes.insert(image_path, hash)?;
+            }
+            if !self.encrypt {
+                if let Some(thumbnail) = self.thumbnail_path(image_path) {
+                    let _ = write_thumbnail(&thumbnail, content);
+                }
+                if let Ok(preview) = encode_preview(content) {
+                    self.image_previews.insert(image_path, preview)?;
+                }
+            }
+            self.record_audit("upload", history_id, Some(prompt), Some(prompt), image_path);
+        }
+        self.push_undo(UndoOperation::Images {
+            history_id: history_id.to_string(),
+            images: images_before,
+        });
+        Ok(image_paths)
     }
-    .prompt-pane, .media-pane { min-width: 0; }
-    .media-pane {
-      display: flex;
-      flex-direction: column;
-      align-items: stretch;
+
+    /// Detaches one image from an entry. The file stays on disk (and the change can be
+    /// undone) unless `history_delete_image_files` is on and no other entry, archived
+    /// or trashed, still references it.
+    pub fn remove_image(&mut self, history_id: &str, image_path: &str) -> Result<ImageRemoval> {
+        let image_path = image_path.trim();
+        let Some((target_path, mut entries, index)) = self.find_entry_container(history_id)? else {
+            return Err(anyhow!("history id not found"));
+        };
+        ensure_unlocked(&entries[index])?;
+
+        let images = &mut entries[index].images;
+        let images_before = images.clone();
+        images.retain(|path| path != image_path);
+        if images.len() == images_before.len() {
+            return Ok(ImageRemoval::NotAttached);
+        }
+
+        self.write_entries(&target_path, &entries)?;
+        let prompt = entries[index].prompt.as_str();
+        self.record_audit(
+            "image_delete",
+            history_id,
+            Some(prompt),
+            Some(prompt),
+            image_path,
+        );
+
+        if self.delete_image_files && !self.image_referenced(image_path)? {
+            self.delete_image_file(image_path)?;
+            return Ok(ImageRemoval::FileDeleted);
+        }
+
+        self.push_undo(UndoOperation::Images {
+            history_id: history_id.to_string(),
+            images: images_before,
+        });
+        Ok(ImageRemoval::Detached)
     }
-    .timestamp { font-weight: 700; color: var(--accent-2); }
-    .btn {
-      border: 2px solid var(--line);
-      background: #fff;
-      color: var(--line);
-      padding: 6px 12px;
-      cursor: pointer;
-      font-weight: 700;
+
+    /// Deletes an image no entry references any more, with its thumbnail, preview and hash.
+    fn delete_image_file(&mut self, image_path: &str) -> Result<()> {
+        let abs_path = self.resolve_image_path(image_path)?;
+        if abs_path.exists() {
+            fs::remove_file(&abs_path)
+                .with_context(|| format!("failed to delete image: {}", abs_path.display()))?;
+        }
+        if let Some(thumbnail) = self.thumbnail_path(image_path).filter(|p| p.exists()) {
+            fs::remove_file(&thumbnail)
+                .with_context(|| format!("failed to delete thumbnail: {}", thumbnail.display()))?;
+        }
+        remove_sized_thumbnails(&self.base_dir.join(THUMBS_DIR), image_path)?;
+        self.image_previews.remove(image_path)?;
+        self.image_hashes.remove(image_path)?;
+        // Earlier image snapshots must not bring back a reference to the deleted file.
+        for operation in self.undo_stack.iter_mut() {
+            if let UndoOperation::Images { images, .. } = operation {
+                images.retain(|path| path != image_path);
+            }
+        }
+        Ok(())
     }
-    .btn:hover { background: #f4ede1; }
-    .btn:disabled {
-      cursor: not-allowed;
-      opacity: 0.55;
-      background: #f0eee7;
+
+    fn image_referenced(&self, image_path: &str) -> Result<bool> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+        for source in sources {
+            if source.exists()
+                && self
+                    .read_entries(&source)?
+                    .iter()
+                    .any(|entry| entry.images.iter().any(|path| path == image_path))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(self
+            .read_trash()?
+            .iter()
+            .any(|trashed| trashed.entry.images.iter().any(|path| path == image_path)))
     }
-    .btn.feedback-visible {
-      position: relative;
-      overflow: visible;
+
+    fn thumbnail_path(&self, image_path: &str) -> Option<PathBuf> {
+        thumbnail_rel_path(image_path).map(|rel| self.base_dir.join(rel))
     }
-    .btn.feedback-visible::after {
-      content: attr(data-feedback);
-      position: absolute;
-      left: 50%;
-      bottom: calc(100% + 10px);
-      transform: translateX(-50%);
-      background: #1f2a44;
-      color: #fff;
-      padding: 4px 8px;
-      border-radius: 4px;
-      font-size: 12px;
-      font-family: "Yu Gothic UI", sans-serif;
-      white-space: nowrap;
-      box-shadow: 0 2px 8px rgba(0, 0, 0, 0.22);
-      pointer-events: none;
-      z-index: 2;
+
+    /// Images attached to active or archived entries whose file exists but has
+    /// no thumbnail or placeholder yet, newest entries first.
+    pub fn missing_thumbnails(&self) -> Result<Vec<String>> {
+        if self.encrypt || self.read_only {
+            return Ok(Vec::new());
+        }
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+        let previews = self.image_previews.load()?;
+        let mut missing = Vec::new();
+        for source in sources.iter().filter(|source| source.exists()) {
+            let mut entries = self.read_entries(source)?;
+            entries.sort_by(|a, b| b.id.cmp(&a.id));
+            for image_path in entries.iter().flat_map(|entry| entry.images.iter()) {
+                let Some(thumbnail) = self.thumbnail_path(image_path) else {
+                    continue;
+                };
+                if (!thumbnail.exists() || !previews.contains_key(image_path))
+                    && !missing.contains(image_path)
+                    && self
+                        .resolve_image_path(image_path)
+                        .is_ok_and(|path| path.exists())
+                {
+                    missing.push(image_path.clone());
+                }
+            }
+        }
+        Ok(missing)
     }
-    .btn.feedback-visible::before {
-      content: "";
-      position: absolute;
-      left: 50%;
-      bottom: calc(100% + 4px);
-      width: 8px;
-      height: 8px;
-      transform: translateX(-50%) rotate(45deg);
-      background: #1f2a44;
-      pointer-events: none;
-      z-index: 1;
+
+    /// Writes the thumbnail and placeholder for one attached image from its
+    /// file on disk.
+    pub fn create_thumbnail(&self, image_path: &str) -> Result<()> {
+        let source = self.resolve_image_path(image_path)?;
+        let target = self
+            .thumbnail_path(image_path)
+            .ok_or_else(|| anyhow!("image path is out of scope"))?;
+        let bytes = read_file(&source, self.cipher.as_ref())?;
+        write_thumbnail(&target, &bytes)?;
+        self.image_previews
+            .insert(image_path, encode_preview(&bytes)?)
     }
-    .overwrite-btn { border-color: var(--accent-2); color: var(--accent-2); }
-    .delete-btn { border-color: var(--accent); color: var(--accent); }
-    .prompt-toolbar {
-      display: flex;
-      gap: 8px;
-      margin-bottom: 8px;
-      flex-wrap: wrap;
+
+    /// Where to make a WebP thumbnail of an attached image at most `width`
+    /// pixels wide. It is generated on first request and cached under
+    /// `thumbs/w{width}/`; encrypted or read-only stores generate it every
+    /// time instead. The job holds no borrow of the store, so the caller can
+    /// release its lock before `ThumbnailJob::run` decodes the image.
+    pub fn sized_thumbnail(&self, image_path: &str, width: u32) -> Result<ThumbnailJob> {
+        let source = self.resolve_image_path(image_path)?;
+        let cached = sized_thumbnail_rel_path(image_path, width)
+            .map(|rel| self.base_dir.join(rel))
+            .filter(|_| !self.encrypt && !self.read_only);
+        Ok(ThumbnailJob {
+            source,
+            cached,
+            cipher: self.cipher.clone(),
+            width,
+        })
     }
-    .prompt-editor {
-      width: 100%;
-      border-left: 4px solid var(--line);
-      padding: 8px 10px;
-      background: #fbfaf5;
-      font-family: "Yu Gothic UI", sans-serif;
-      font-size: 14px;
-      line-height: 1.5;
-      min-height: 156px;
-      resize: vertical;
-      white-space: pre-wrap;
-      word-break: break-word;
+
+    /// An entry from the active history or an archive.
+    pub fn find_entry(&self, history_id: &str) -> Result<Option<HistoryEntry>> {
+        Ok(self
+            .find_entry_container(history_id.trim())?
+            .map(|(_, mut entries, index)| entries.swap_remove(index)))
     }
-    .upload { margin-top: 0; }
-    .dropzone {
-      border: 2px dashed var(--line);
-      padding: 10px;
-      text-align: center;
-      cursor: pointer;
-      background: #fefcf3;
-      font-family: "Yu Gothic UI", sans-serif;
-      display: flex;
-      align-items: center;
-      justify-content: center;
+
+    /// Prompt of the newest entry (active history first, then archives) that
+    /// has `image_path` attached.
+    pub fn prompt_for_image(&self, image_path: &str) -> Result<Option<String>> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+        for source in sources {
+            if !source.exists() {
+                continue;
+            }
+            if let Some(entry) = self
+                .read_entries(&source)?
+                .into_iter()
+                .rev()
+                .find(|entry| entry.images.iter().any(|path| path == image_path))
+            {
+                return Ok(Some(entry.prompt));
+            }
+        }
+        Ok(None)
     }
-    .dropzone.needs-image { min-height: 96px; }
-    .dropzone.has-image { min-height: 0; }
-    .dropzone.dragover { background: #fff4d3; }
-    .file-input { display: none; }
-    .images {
-      margin-top: 10px;
-      display: flex;
-      flex-wrap: wrap;
-      gap: 8px;
-      font-family: "Yu Gothic UI", sans-serif;
+
+    /// The operation `undo` would revert next, if any.
+    pub fn last_undo(&self) -> Option<&UndoOperation> {
+        self.undo_stack.back()
     }
-    .image-item {
-      width: 100%;
-      display: flex;
-      flex-direction: column;
-      gap: 6px;
+
+    /// Reverts the newest tracked operation. It stays on the stack when the
+    /// revert fails (e.g. the entry was locked since), so it can be retried.
+    pub fn undo(&mut self) -> Result<Option<UndoOperation>> {
+        let Some(operation) = self.undo_stack.back().cloned() else {
+            return Ok(None);
+        };
+
+        let history_id = operation.history_id();
+        match &operation {
+            UndoOperation::Delete { .. } => {
+                if !self.restore_history(history_id)? {
+                    return Err(anyhow!("history id not found in trash"));
+                }
+            }
+            UndoOperation::Overwrite { prompt, .. } => {
+                let (target_path, mut entries, index) = self
+                    .find_entry_container(history_id)?
+                    .ok_or_else(|| anyhow!("history id not found"))?;
+                ensure_unlocked(&entries[index])?;
+                let before = std::mem::replace(&mut entries[index].prompt, prompt.clone());
+                self.write_entries(&target_path, &entries)?;
+                self.record_audit("update", history_id, Some(&before), Some(prompt), "undo");
+            }
+            UndoOperation::Images { images, .. } => {
+                let (target_path, mut entries, index) = self
+                    .find_entry_container(history_id)?
+                    .ok_or_else(|| anyhow!("history id not found"))?;
+                ensure_unlocked(&entries[index])?;
+                entries[index].images = images.clone();
+                self.write_entries(&target_path, &entries)?;
+                let prompt = entries[index].prompt.as_str();
+                self.record_audit(
+                    "image_restore",
+                    history_id,
+                    Some(prompt),
+                    Some(prompt),
+                    "undo",
+                );
+            }
+        }
+
+        self.undo_stack.pop_back();
+        Ok(Some(operation))
     }
-    .thumb-image-link {
-      display: block;
-      border: 1px solid var(--line);
-      background: #f8f8f8;
-      padding: 6px;
-      cursor: pointer;
+
+    fn push_undo(&mut self, operation: UndoOperation) {
+        if self.undo_stack.len() == Self::UNDO_LIMIT {
+            self.undo_stack.pop_front();
+        }
+        self.undo_stack.push_back(operation);
     }
-    .thumb-image {
-      display: block;
-      width: 100%;
-      max-height: 240px;
-      object-fit: contain;
-      background: #fff;
+
+    /// Locked entries reject prompt edits, deletion and image changes until unlocked.
+    pub fn set_locked(&mut self, history_id: &str, locked: bool) -> Result<bool> {
+        let Some((target_path, mut entries, index)) =
+            self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(false);
+        };
+
+        if entries[index].locked != locked {
+            entries[index].locked = locked;
+            self.write_entries(&target_path, &entries)?;
+        }
+        Ok(true)
     }
-    .thumb-path {
-      border: 1px solid var(--line);
-      padding: 4px 8px;
-      font-size: 12px;
-      text-decoration: none;
-      color: var(--accent-2);
-      background: #f8f8f8;
-      max-width: 100%;
-      overflow: hidden;
-      text-overflow: ellipsis;
-      white-space: nowrap;
+
+    /// Records the item selections a copied prompt was rendered from.
+    pub fn set_selections(
+        &mut self,
+        history_id: &str,
+        selections: BTreeMap<String, String>,
+    ) -> Result<bool> {
+        let Some((target_path, mut entries, index)) =
+            self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(false);
+        };
+
+        if entries[index].selections != selections {
+            entries[index].selections = selections;
+            self.write_entries(&target_path, &entries)?;
+        }
+        Ok(true)
     }
-    .image-item.is-selected .thumb-image-link,
-    .image-item.is-selected .thumb-path {
-      border-color: var(--accent-2);
-      box-shadow: inset 0 0 0 1px var(--accent-2);
+
+    /// Records the English translation shown next to a copied prompt.
+    pub fn set_translation(&mut self, history_id: &str, translation: &str) -> Result<bool> {
+        let Some((target_path, mut entries, index)) =
+            self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(false);
+        };
+
+        let translation = translation.trim();
+        if entries[index].translation != translation {
+            entries[index].translation = translation.to_string();
+            self.write_entries(&target_path, &entries)?;
+        }
+        Ok(true)
     }
-    .image-copy-btn {
-      margin-top: 10px;
-      align-self: flex-start;
-      position: relative;
-      overflow: visible;
+
+    /// Records the `score_command` score of a copied prompt.
+    pub fn set_score(&mut self, history_id: &str, score: u8) -> Result<bool> {
+        if score > 100 {
+            return Err(anyhow!("score must be between 0 and 100"));
+        }
+        let Some((target_path, mut entries, index)) =
+            self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(false);
+        };
+
+        if entries[index].score != Some(score) {
+            entries[index].score = Some(score);
+            self.write_entries(&target_path, &entries)?;
+        }
+        Ok(true)
     }
-    .image-copy-btn.copy-feedback::after {
-      content: "クリップボードにコピーしました";
-      position: absolute;
-      left: 50%;
-      bottom: calc(100% + 10px);
-      transform: translateX(-50%);
-      background: #1f2a44;
-      color: #fff;
-      padding: 4px 8px;
-      border-radius: 4px;
-      font-size: 12px;
-      font-family: "Yu Gothic UI", sans-serif;
-      white-space: nowrap;
-      box-shadow: 0 2px 8px rgba(0, 0, 0, 0.22);
-      pointer-events: none;
-      z-index: 2;
-    }
-    .image-copy-btn.copy-feedback::before {
-      content: "";
-      position: absolute;
-      left: 50%;
-      bottom: calc(100% + 4px);
-      width: 8px;
-      height: 8px;
-      transform: translateX(-50%) rotate(45deg);
-      background: #1f2a44;
-      pointer-events: none;
-      z-index: 1;
-    }
-    .muted { color: var(--muted); }
-    .empty { padding: 24px; border: 1px dashed var(--line); background: #fff; }
-    @media (max-width: 720px) {
-      h1 { font-size: 30px; }
-      .entry-body { grid-template-columns: minmax(0, 1fr); }
-      .prompt-editor { min-height: 0; }
-      .image-copy-btn { align-self: stretch; }
-    }
-  </style>
-"#;
 
-const INTERACTIVE_SCRIPT_TEMPLATE: &str = r#"
-  <script>
-    const API_BASE = "__API_BASE__";
-    const HISTORY_REVISION_POLL_MS = 1000;
-    let lastHistoryRevision = null;
-    let historyRevisionPolling = false;
-    async function parseApiResponse(res, fallback) {
-      let data = {};
-      try {
-        data = await res.json();
-      } catch (_) {
-        data = {};
-      }
-      if (!res.ok || !data.ok) {
-        throw new Error(data.error || fallback);
-      }
-      return data;
+    pub fn set_rating(&mut self, history_id: &str, rating: u8) -> Result<bool> {
+        if rating > 5 {
+            return Err(anyhow!("rating must be between 0 and 5"));
+        }
+        let Some((target_path, mut entries, index)) =
+            self.find_entry_container(history_id.trim())?
+        else {
+            return Ok(false);
+        };
+
+        if entries[index].rating != rating {
+            entries[index].rating = rating;
+            self.write_entries(&target_path, &entries)?;
+            let prompt = entries[index].prompt.as_str();
+            self.record_audit(
+                "rate",
+                history_id.trim(),
+                Some(prompt),
+                Some(prompt),
+                &rating.to_string(),
+            );
+        }
+        Ok(true)
     }
-    async function fetchHistoryRevision() {
-      const res = await fetch(`${API_BASE}/app/history-revision`, {
-        method: "GET",
-        cache: "no-store"
-      });
-      const data = await parseApiResponse(res, "history revision failed");
-      const revision = Number(data.revision);
-      if (!Number.isFinite(revision)) {
-        throw new Error("invalid history revision");
-      }
-      return revision;
+
+    /// Perceptual hash of an attached image, from the index when already known.
+    pub fn image_hash(&self, image_path: &str) -> Result<u64> {
+        if let Some(hash) = self
+            .image_hashes
+            .load()?
+            .get(image_path.trim())
+            .and_then(|value| parse_hash(value))
+        {
+            return Ok(hash);
+        }
+        let (bytes, _) = self.read_image_blob(image_path)?;
+        perceptual_hash(&bytes)
     }
-    async function pollHistoryRevision() {
-      if (historyRevisionPolling) {
-        return;
-      }
-      historyRevisionPolling = true;
-      try {
-        const revision = await fetchHistoryRevision();
-        if (lastHistoryRevision === null) {
-          lastHistoryRevision = revision;
-          return;
+
+    /// Attached images (active and archived) within `max_distance` bits of
+    /// `reference`, closest first. Hashes missing from the index (images added
+    /// before hashing existed) are computed once and cached.
+    pub fn similar_images(
+        &self,
+        reference: u64,
+        exclude_path: Option<&str>,
+        max_distance: u32,
+        limit: usize,
+    ) -> Result<Vec<SimilarImage>> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+        let mut hashes = self.image_hashes.load()?;
+        let mut indexed = false;
+
+        let mut results = Vec::new();
+        for source in sources {
+            if !source.exists() {
+                continue;
+            }
+            for entry in self.read_entries(&source)? {
+                for image_path in &entry.images {
+                    if exclude_path == Some(image_path.as_str()) {
+                        continue;
+                    }
+                    let hash = match hashes.get(image_path).and_then(|value| parse_hash(value)) {
+                        Some(hash) => hash,
+                        None => {
+                            let Ok(hash) = self
+                                .read_image_blob(image_path)
+                                .and_then(|(bytes, _)| perceptual_hash(&bytes))
+                            else {
+                                continue;
+                            };
+                            hashes.insert(image_path.clone(), format!("{hash:016x}"));
+                            indexed = true;
+                            hash
+                        }
+                    };
+                    let distance = hamming_distance(reference, hash);
+                    if distance <= max_distance {
+                        results.push(SimilarImage {
+                            history_id: entry.id.clone(),
+                            ts: entry.ts.clone(),
+                            prompt: entry.prompt.clone(),
+                            image_path: image_path.clone(),
+                            distance,
+                        });
+                    }
+                }
+            }
         }
-        if (revision !== lastHistoryRevision) {
-          location.reload();
-          return;
+        if indexed {
+            self.image_hashes.save(&hashes)?;
         }
-      } catch (_) {
-        // Ignore transient errors (e.g. app stopped) and keep current page state.
-      } finally {
-        historyRevisionPolling = false;
-      }
+
+        results.sort_by(|a, b| {
+            a.distance
+                .cmp(&b.distance)
+                .then(b.history_id.cmp(&a.history_id))
+        });
+        results.truncate(limit);
+        Ok(results)
     }
-    function getPromptValue(entry) {
-      const editor = entry.querySelector(".prompt-editor");
-      return editor ? editor.value : "";
+
+    pub fn read_image_blob(&self, image_path: &str) -> Result<(Vec<u8>, &'static str)> {
+        let abs_path = self.resolve_image_path(image_path)?;
+        let bytes = read_file(&abs_path, self.cipher.as_ref())?;
+        Ok((bytes, image_content_type(&abs_path)))
     }
-    async function copyPrompt(entry) {
-      const prompt = getPromptValue(entry);
-      await navigator.clipboard.writeText(prompt);
+
+    /// File metadata of an attached image, so HTTP caching can check it
+    /// without reading the image.
+    pub fn image_file_metadata(&self, image_path: &str) -> Result<fs::Metadata> {
+        let abs_path = self.resolve_image_path(image_path)?;
+        fs::metadata(&abs_path)
+            .with_context(|| format!("failed to read image: {}", abs_path.display()))
     }
-    async function overwritePrompt(historyId, prompt) {
-      const res = await fetch(`${API_BASE}/update`, {
-        method: "POST",
-        headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({ history_id: historyId, prompt })
-      });
-      return parseApiResponse(res, "update failed");
+
+    /// Whether an attached image is stored encrypted, in which case it can only
+    /// be read whole with `read_image_blob`.
+    pub fn is_image_encrypted(&self, image_path: &str) -> Result<bool> {
+        let abs_path = self.resolve_image_path(image_path)?;
+        is_encrypted_file(&abs_path)
+            .with_context(|| format!("failed to read image: {}", abs_path.display()))
     }
-    async function deleteEntry(historyId) {
-      if (!confirm("プロンプトを削除しますか？（画像は削除されません）")) {
-        return;
-      }
-      const res = await fetch(`${API_BASE}/delete`, {
-        method: "POST",
-        headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({ history_id: historyId })
-      });
-      await parseApiResponse(res, "delete failed");
-      location.reload();
+
+    /// File and content type of a plain (unencrypted) image, so the server can
+    /// stream it from disk instead of reading it into memory.
+    pub fn image_file(&self, image_path: &str) -> Result<(PathBuf, &'static str)> {
+        let abs_path = self.resolve_image_path(image_path)?;
+        let content_type = image_content_type(&abs_path);
+        Ok((abs_path, content_type))
     }
-    async function uploadFile(historyId, file) {
-      const form = new FormData();
-      form.append("history_id", historyId);
-      form.append("file", file);
-      const res = await fetch(`${API_BASE}/upload`, {
-        method: "POST",
-        body: form
-      });
-      return parseApiResponse(res, "upload failed");
+
+    /// A `thumbs/...` file as the pages reference it, for pages served over HTTP.
+    pub fn read_thumbnail_blob(&self, thumb_path: &str) -> Result<Vec<u8>> {
+        let rel_path = Path::new(thumb_path.trim());
+        if !thumb_path.starts_with(&format!("{THUMBS_DIR}/"))
+            || rel_path
+                .components()
+                .any(|part| !matches!(part, Component::Normal(_)))
+        {
+            return Err(anyhow!("thumbnail path is out of scope"));
+        }
+        let abs_path = self.base_dir.join(rel_path);
+        fs::read(&abs_path)
+            .with_context(|| format!("failed to read thumbnail: {}", abs_path.display()))
     }
-    async function fetchImageBlob(imagePath) {
-      const imageUrl = `${API_BASE}/image?path=${encodeURIComponent(imagePath)}`;
-      let res;
-      try {
-        res = await fetch(imageUrl, { cache: "no-store" });
-      } catch (_) {
-        throw new Error("アプリが起動していない可能性があります");
-      }
-      if (!res.ok) {
-        let message = "画像を取得できませんでした";
-        try {
-          const data = await res.json();
-          if (data && typeof data.error === "string" && data.error.trim() !== "") {
-            message = data.error;
-          }
-        } catch (_) {}
-        throw new Error(message);
-      }
-      return res.blob();
+
+    /// Maps a stored `images/...` path to its file, rejecting anything outside `images/`.
+    fn resolve_image_path(&self, image_path: &str) -> Result<PathBuf> {
+        let cleaned = image_path.trim();
+        if cleaned.is_empty() {
+            return Err(anyhow!("image path is empty"));
+        }
+
+        let rel_path = Path::new(cleaned);
+        if rel_path.is_absolute() {
+            return Err(anyhow!("absolute image path is not allowed"));
+        }
+        if rel_path
+            .components()
+            .any(|part| matches!(part, Component::ParentDir | Component::CurDir))
+        {
+            return Err(anyhow!("invalid image path"));
+        }
+
+        let normalized = path_to_posix(rel_path);
+        if !normalized.starts_with("images/") {
+            return Err(anyhow!("image path is out of scope"));
+        }
+
+        Ok(self.base_dir.join(rel_path))
     }
-    async function imageBlobFromApiBlob(sourceBlob) {
-      return new Promise((resolve, reject) => {
-        const objectUrl = URL.createObjectURL(sourceBlob);
-        const image = new Image();
-        image.onload = () => {
-          URL.revokeObjectURL(objectUrl);
-          const width = image.naturalWidth || image.width;
-          const height = image.naturalHeight || image.height;
-          if (!width || !height) {
-            reject(new Error("画像サイズを取得できませんでした"));
-            return;
-          }
-          const canvas = document.createElement("canvas");
-          canvas.width = width;
-          canvas.height = height;
-          const ctx = canvas.getContext("2d");
-          if (!ctx) {
-            reject(new Error("画像変換に失敗しました"));
-            return;
-          }
-          ctx.drawImage(image, 0, 0);
-          canvas.toBlob((blob) => {
-            if (!blob) {
-              reject(new Error("画像変換に失敗しました"));
-              return;
+
+    pub fn regenerate_html(&self, server_port: u16) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.encrypt {
+            return self.write_encrypted_stub(server_port);
+        }
+
+        let pages = self.render_pages(server_port)?;
+        for (name, content) in &pages {
+            let path = self.base_dir.join(name);
+            fs::write(&path, content)
+                .with_context(|| format!("failed to write html: {}", path.display()))?;
+        }
+        // Drop pages left over from a bigger day or a smaller page size.
+        for date_key in self.collect_archive_date_keys()? {
+            let mut stale = 1;
+            while pages
+                .iter()
+                .any(|(name, _)| *name == archive_page_name(&date_key, stale))
+            {
+                stale += 1;
             }
-            resolve(blob);
-          }, "image/png");
+            while self.archive_html_path(&date_key, stale).exists() {
+                let path = self.archive_html_path(&date_key, stale);
+                fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove html: {}", path.display()))?;
+                stale += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// One history page by file name (`History.html`, an archive page or
+    /// `ArchiveIndex.html`), rendered on request; `None` for unknown names.
+    /// Only the requested page is rendered, so serving a page costs one
+    /// archive read rather than a pass over every archive.
+    pub fn render_page(&self, name: &str, server_port: u16) -> Result<Option<String>> {
+        if name == "History.html" {
+            let smart_filters = self.smart_filters()?;
+            return self.render_main_page(server_port, &smart_filters).map(Some);
+        }
+        if name == ARCHIVE_INDEX_HTML {
+            let mut archive_index = ArchiveIndex::default();
+            for date_key in self.collect_archive_date_keys()? {
+                let archive_entries = self.sorted_archive_entries(&date_key)?;
+                for (index, chunk) in self.archive_chunks(&archive_entries).iter().enumerate() {
+                    let page_name = archive_page_name(&date_key, index + 1);
+                    for entry in chunk.iter() {
+                        archive_index.add(&self.date_key_from_entry(entry), &page_name, entry);
+                    }
+                }
+            }
+            return Ok(Some(archive_index.to_html()));
+        }
+        let Some((date_key, number)) = parse_archive_page_name(name) else {
+            return Ok(None);
         };
-        image.onerror = () => {
-          URL.revokeObjectURL(objectUrl);
-          reject(new Error("画像を取得できませんでした"));
+        if !self.archive_json_path(date_key).exists() {
+            return Ok(None);
+        }
+        let archive_entries = self.sorted_archive_entries(date_key)?;
+        let chunks = self.archive_chunks(&archive_entries);
+        let Some(chunk) = chunks.get(number - 1) else {
+            return Ok(None);
         };
-        image.src = objectUrl;
-      });
-    }
-    async function copyImageToClipboard(imagePath) {
-      if (
-        !navigator.clipboard ||
-        typeof navigator.clipboard.write !== "function" ||
-        typeof ClipboardItem === "undefined"
-      ) {
-        throw new Error("このブラウザは画像コピーに対応していません");
-      }
-      const sourceBlob = await fetchImageBlob(imagePath);
-      const pngBlob = await imageBlobFromApiBlob(sourceBlob);
-      await navigator.clipboard.write([new ClipboardItem({ "image/png": pngBlob })]);
+        let page = ArchivePage {
+            date_key,
+            number,
+            count: chunks.len(),
+        };
+        let smart_filters = self.smart_filters()?;
+        Ok(Some(self.render_archive_page(
+            chunk,
+            &page,
+            server_port,
+            &smart_filters,
+        )))
     }
-    function showImageCopyFeedback(button) {
-      if (!button) return;
-      button.classList.remove("copy-feedback");
-      if (button._copyFeedbackTimer) {
-        clearTimeout(button._copyFeedbackTimer);
-      }
-      void button.offsetWidth;
-      button.classList.add("copy-feedback");
-      button._copyFeedbackTimer = setTimeout(() => {
-        button.classList.remove("copy-feedback");
-        button._copyFeedbackTimer = null;
-      }, 1400);
+
+    /// `(file name, html)` of `History.html`, every archive page and `ArchiveIndex.html`.
+    fn render_pages(&self, server_port: u16) -> Result<Vec<(String, String)>> {
+        let smart_filters = self.smart_filters()?;
+        let mut pages = vec![(
+            "History.html".to_string(),
+            self.render_main_page(server_port, &smart_filters)?,
+        )];
+
+        let mut archive_index = ArchiveIndex::default();
+        for date_key in self.collect_archive_date_keys()? {
+            let archive_entries = self.sorted_archive_entries(&date_key)?;
+            let chunks = self.archive_chunks(&archive_entries);
+            for (index, chunk) in chunks.iter().enumerate() {
+                let page = ArchivePage {
+                    date_key: &date_key,
+                    number: index + 1,
+                    count: chunks.len(),
+                };
+                let page_name = archive_page_name(&date_key, page.number);
+                for entry in chunk.iter() {
+                    archive_index.add(&self.date_key_from_entry(entry), &page_name, entry);
+                }
+                pages.push((
+                    page_name,
+                    self.render_archive_page(chunk, &page, server_port, &smart_filters),
+                ));
+            }
+        }
+
+        pages.push((ARCHIVE_INDEX_HTML.to_string(), archive_index.to_html()));
+        Ok(pages)
     }
-    function showButtonFeedback(button, message) {
-      if (!button || !message) return;
-      button.dataset.feedback = message;
-      button.classList.remove("feedback-visible");
-      if (button._buttonFeedbackTimer) {
-        clearTimeout(button._buttonFeedbackTimer);
-      }
-      void button.offsetWidth;
-      button.classList.add("feedback-visible");
-      button._buttonFeedbackTimer = setTimeout(() => {
-        button.classList.remove("feedback-visible");
-        button.dataset.feedback = "";
-        button._buttonFeedbackTimer = null;
-      }, 1400);
+
+    fn render_main_page(&self, server_port: u16, smart_filters: &[SmartFilter]) -> Result<String> {
+        let entries = self.read_entries(&self.history_json_path)?;
+        let archive_date_keys = self.collect_archive_date_keys()?;
+        let rotation = self.rotation_status_for(&entries);
+        let trash = self.trash_entries()?;
+        Ok(self.build_history_html(
+            &entries,
+            "Prompt History",
+            true,
+            true,
+            server_port,
+            &archive_date_keys,
+            Some(&rotation),
+            &trash,
+            smart_filters,
+            None,
+        ))
     }
-    function syncUploadLabel(entry) {
-      const upload = entry.querySelector(".upload");
-      if (!upload) return;
-      const dropzone = upload.querySelector(".dropzone");
-      if (!dropzone) return;
-      const hasImage = entry.dataset.hasImage === "true";
-      dropzone.classList.toggle("has-image", hasImage);
-      dropzone.classList.toggle("needs-image", !hasImage);
-      dropzone.textContent = hasImage
-        ? "画像追加済み（差し替えはD＆Dまたはクリック）"
-        : "画像追加: ドラッグ&ドロップ または クリック";
+
+    fn render_archive_page(
+        &self,
+        chunk: &[HistoryEntry],
+        page: &ArchivePage,
+        server_port: u16,
+        smart_filters: &[SmartFilter],
+    ) -> String {
+        self.build_history_html(
+            chunk,
+            &format!("Prompt History Archive {}", page.date_key),
+            true,
+            true,
+            server_port,
+            &[],
+            None,
+            &[],
+            smart_filters,
+            Some(page),
+        )
     }
-    function setSelectedImage(entry, imagePath) {
-      entry.dataset.selectedImage = imagePath || "";
-      for (const item of entry.querySelectorAll(".image-item")) {
-        item.classList.toggle("is-selected", (item.dataset.imagePath || "") === entry.dataset.selectedImage);
-      }
-      const imageCopyBtn = entry.querySelector(".image-copy-btn");
-      if (imageCopyBtn) {
-        imageCopyBtn.disabled = !entry.dataset.selectedImage;
-      }
+
+    /// Entries of one archive file, newest first (the order of its pages).
+    fn sorted_archive_entries(&self, date_key: &str) -> Result<Vec<HistoryEntry>> {
+        let archive_json = self.archive_json_path(date_key);
+        let mut archive_entries = if archive_json.exists() {
+            self.read_entries(&archive_json)?
+        } else {
+            Vec::new()
+        };
+        archive_entries.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(archive_entries)
     }
-    function buildImageItem(imagePath) {
-      const wrapper = document.createElement("div");
-      wrapper.className = "image-item";
-      wrapper.dataset.imagePath = imagePath;
 
-      const imageLink = document.createElement("a");
-      imageLink.className = "thumb-image-link";
-      imageLink.href = imagePath;
-      imageLink.target = "_blank";
-      imageLink.rel = "noopener noreferrer";
+    /// An archive split into pages of `archive_page_size` (0 keeps one page).
+    fn archive_chunks<'a>(&self, entries: &'a [HistoryEntry]) -> Vec<&'a [HistoryEntry]> {
+        if self.archive_page_size == 0 {
+            vec![entries]
+        } else {
+            entries.chunks(self.archive_page_size).collect()
+        }
+    }
 
-      const img = document.createElement("img");
-      img.className = "thumb-image";
-      img.src = imagePath;
-      img.alt = "history image";
-      img.loading = "lazy";
-      imageLink.appendChild(img);
+    /// Pages on disk would hold the prompts in plain text, so an encrypted
+    /// store removes them and leaves a `History.html` that opens the pages the
+    /// app serves under `/pages/`.
+    fn write_encrypted_stub(&self, server_port: u16) -> Result<()> {
+        for item in fs::read_dir(&self.base_dir)
+            .with_context(|| format!("failed to list base dir: {}", self.base_dir.display()))?
+        {
+            let path = item?.path();
+            let Some(name) = path.file_name().and_then(|v| v.to_str()) else {
+                continue;
+            };
+            if name == ARCHIVE_INDEX_HTML
+                || (name.starts_with("History_") && name.ends_with(".html"))
+            {
+                fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove html: {}", path.display()))?;
+            }
+        }
+        let url = format!("http://127.0.0.1:{server_port}/pages/History.html");
+        let url_attr = encode_double_quoted_attribute(&url);
+        let content = format!(
+            "<!doctype html>\n<html lang=\"ja\">\n<head>\n  <meta charset=\"utf-8\" />\n  <meta http-equiv=\"refresh\" content=\"0; url={url_attr}\" />\n  <title>Prompt History</title>\n</head>\n<body>\n  <p>履歴は暗号化されています。アプリの起動中に <a href=\"{url_attr}\">{url_attr}</a> で表示できます。</p>\n</body>\n</html>\n"
+        );
+        fs::write(&self.history_html_path, content)
+            .with_context(|| format!("failed to write html: {}", self.history_html_path.display()))
+    }
 
-      const pathLink = document.createElement("a");
-      pathLink.className = "thumb-path";
-      pathLink.href = imagePath;
-      pathLink.target = "_blank";
-      pathLink.rel = "noopener noreferrer";
-      pathLink.textContent = imagePath;
+    fn ensure_files(&self) -> Result<()> {
+        if self.read_only {
+            return self.check_files();
+        }
+        fs::create_dir_all(&self.base_dir)
+            .with_context(|| format!("failed to create base dir: {}", self.base_dir.display()))?;
+        fs::create_dir_all(&self.images_root).with_context(|| {
+            format!(
+                "failed to create images dir: {}",
+                self.images_root.display()
+            )
+        })?;
 
-      wrapper.appendChild(imageLink);
-      wrapper.appendChild(pathLink);
-      return wrapper;
+        for archive_json in self.list_archive_json_paths()? {
+            self.migrate_file(&archive_json)?;
+        }
+
+        if !self.history_json_path.exists() {
+            return self
+                .write_entries(&self.history_json_path, &[])
+                .with_context(|| {
+                    format!(
+                        "failed to init history file: {}",
+                        self.history_json_path.display()
+                    )
+                });
+        }
+
+        self.migrate_file(&self.history_json_path)?;
+        // A wrong key is an error, never a broken file to reset.
+        self.read_text(&self.history_json_path)?;
+        match self.read_entries(&self.history_json_path) {
+            Ok(entries) => self.write_entries(&self.history_json_path, &entries),
+            Err(_) => {
+                let now_tag = Local::now().format("%Y%m%d_%H%M%S").to_string();
+                let backup = self
+                    .base_dir
+                    .join(format!("history.broken.{}.json", now_tag));
+                fs::rename(&self.history_json_path, backup).with_context(|| {
+                    format!(
+                        "failed to backup broken history: {}",
+                        self.history_json_path.display()
+                    )
+                })?;
+                // Fall back to the version before the last write when it still parses.
+                let recovered = self
+                    .read_entries(&backup_path(&self.history_json_path))
+                    .unwrap_or_default();
+                self.write_entries(&self.history_json_path, &recovered)
+                    .with_context(|| {
+                        format!(
+                            "failed to reset history file: {}",
+                            self.history_json_path.display()
+                        )
+                    })
+            }
+        }
     }
-    function renderUploadedImage(entry, imagePath) {
-      const images = entry.querySelector(".images");
-      images.innerHTML = "";
-      if (imagePath) {
-        entry.dataset.hasImage = "true";
-        entry.dataset.selectedImage = imagePath;
-        images.appendChild(buildImageItem(imagePath));
-      } else {
-        entry.dataset.hasImage = "false";
-        entry.dataset.selectedImage = "";
-        const muted = document.createElement("span");
-        muted.className = "muted";
-        muted.textContent = "画像なし";
-        images.appendChild(muted);
-      }
-      syncUploadLabel(entry);
-      setSelectedImage(entry, entry.dataset.selectedImage || "");
+
+    /// What `ensure_files` checks without writing, for a read-only store:
+    /// no file is newer than this build and the key opens the history.
+    fn check_files(&self) -> Result<()> {
+        let mut files = self.list_archive_json_paths()?;
+        files.push(self.history_json_path.clone());
+        for path in files.iter().filter(|path| path.exists()) {
+            let raw_text = self.read_text(path)?;
+            let Ok(raw) = serde_json::from_str::<Value>(&raw_text) else {
+                continue;
+            };
+            check_schema_version(&raw, path)?;
+        }
+        Ok(())
     }
-    for (const entry of document.querySelectorAll(".entry")) {
-      const historyId = entry.dataset.historyId;
-      const editor = entry.querySelector(".prompt-editor");
-      const overwriteBtn = entry.querySelector(".overwrite-btn");
-      const copyBtn = entry.querySelector(".copy-btn");
-      const deleteBtn = entry.querySelector(".delete-btn");
-      const imageCopyBtn = entry.querySelector(".image-copy-btn");
-      const images = entry.querySelector(".images");
-      const upload = entry.querySelector(".upload");
-      const dropzone = upload ? upload.querySelector(".dropzone") : null;
-      const fileInput = upload ? upload.querySelector(".file-input") : null;
 
-      if (overwriteBtn) {
-        overwriteBtn.addEventListener("click", async () => {
-          const currentPrompt = getPromptValue(entry);
-          try {
-            const data = await overwritePrompt(historyId, currentPrompt);
-            if (editor) {
-              editor.value = typeof data.prompt === "string" ? data.prompt : currentPrompt.trim();
-            }
-            showButtonFeedback(overwriteBtn, "編集した内容で上書きしました");
-          } catch (err) {
-            alert(`上書き失敗: ${err.message}`);
-          }
-        });
-      }
-      if (copyBtn) {
-        copyBtn.addEventListener("click", async () => {
-          try {
-            await copyPrompt(entry);
-            showButtonFeedback(copyBtn, "コピーしました");
-          } catch (err) {
-            alert(`コピー失敗: ${err.message}`);
-          }
-        });
-      }
-      if (deleteBtn) {
-        deleteBtn.addEventListener("click", async () => {
-          try {
-            await deleteEntry(historyId);
-          } catch (err) {
-            alert(`削除失敗: ${err.message}`);
-          }
-        });
-      }
-      if (imageCopyBtn) {
-        imageCopyBtn.addEventListener("click", async () => {
-          const imagePath = entry.dataset.selectedImage || "";
-          if (!imagePath) {
-            alert("コピー対象の画像がありません。");
-            return;
-          }
-          try {
-            await copyImageToClipboard(imagePath);
-            showImageCopyFeedback(imageCopyBtn);
-          } catch (err) {
-            alert(`画像コピー失敗: ${err.message}`);
-          }
-        });
-      }
-      if (images) {
-        images.addEventListener("click", (event) => {
-          const target = event.target;
-          if (!(target instanceof Element)) {
-            return;
-          }
-          const thumbLink = target.closest(".thumb-image-link");
-          if (!thumbLink || !images.contains(thumbLink)) {
-            return;
-          }
-          event.preventDefault();
-          const imageItem = thumbLink.closest(".image-item");
-          if (!imageItem) {
-            return;
-          }
-          setSelectedImage(entry, imageItem.dataset.imagePath || "");
+    /// Upgrades an older history file in place after backing it up.
+    /// Unparsable files are left alone; newer schema versions are an error
+    /// so they are never mistaken for broken files and reset.
+    fn migrate_file(&self, source: &Path) -> Result<()> {
+        let raw_text = self.read_text(source)?;
+        let Ok(raw) = serde_json::from_str::<Value>(&raw_text) else {
+            return Ok(());
+        };
+
+        let version = check_schema_version(&raw, source)?;
+        if version == HISTORY_SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        backup_before_migration(source, version)?;
+        let entries = self.read_entries(source)?;
+        self.write_entries(source, &entries)
+    }
+
+    fn archive_json_path(&self, date_key: &str) -> PathBuf {
+        self.base_dir.join(format!("History_{}.json", date_key))
+    }
+
+    fn archive_html_path(&self, date_key: &str, page: usize) -> PathBuf {
+        self.base_dir.join(archive_page_name(date_key, page))
+    }
+
+    /// Page of the archive HTML for `date_key` that shows `history_id`.
+    fn archive_page_of(
+        &self,
+        date_key: &str,
+        entries: &[HistoryEntry],
+        history_id: &str,
+    ) -> String {
+        let newer = entries
+            .iter()
+            .filter(|entry| entry.id.as_str() > history_id)
+            .count();
+        let page = newer.checked_div(self.archive_page_size).unwrap_or(0) + 1;
+        archive_page_name(date_key, page)
+    }
+
+    /// Archives the oldest entries past `max_active_entries`, plus any entry older
+    /// than `archive_after_days`, then compacts and enforces `archive_max_bytes`.
+    fn rotate_if_needed(&self, entries: Vec<HistoryEntry>) -> Result<Vec<HistoryEntry>> {
+        let overflow = entries.len().saturating_sub(self.max_active_entries);
+        let cutoff = (self.archive_after_days > 0).then(|| {
+            (Local::now().date_naive() - TimeDelta::days(self.archive_after_days))
+                .format("%Y%m%d")
+                .to_string()
         });
-      }
-      setSelectedImage(entry, entry.dataset.selectedImage || "");
-      if (!dropzone || !fileInput) {
-        continue;
-      }
-      syncUploadLabel(entry);
-      const handleFile = async (file) => {
-        if (!file) return;
-        try {
-          const data = await uploadFile(historyId, file);
-          renderUploadedImage(entry, data.image_path || "");
-        } catch (err) {
-          alert(`アップロード失敗: ${err.message}`);
-        } finally {
-          fileInput.value = "";
+        let (moving, kept): (Vec<_>, Vec<_>) =
+            entries.into_iter().enumerate().partition(|(index, entry)| {
+                *index < overflow
+                    || cutoff
+                        .as_ref()
+                        .is_some_and(|cutoff| self.date_key_from_entry(entry) < *cutoff)
+            });
+        if moving.is_empty() {
+            return Ok(kept.into_iter().map(|(_, entry)| entry).collect());
         }
-      };
-      dropzone.addEventListener("click", () => fileInput.click());
-      fileInput.addEventListener("change", async () => {
-        const file = fileInput.files && fileInput.files[0];
-        await handleFile(file);
-      });
-      dropzone.addEventListener("dragover", (event) => {
-        event.preventDefault();
-        dropzone.classList.add("dragover");
-      });
-      dropzone.addEventListener("dragleave", () => {
-        dropzone.classList.remove("dragover");
-      });
-      dropzone.addEventListener("drop", async (event) => {
-        event.preventDefault();
-        dropzone.classList.remove("dragover");
-        const file = event.dataTransfer && event.dataTransfer.files && event.dataTransfer.files[0];
-        await handleFile(file);
-      });
+
+        self.archive_entries(moving.into_iter().map(|(_, entry)| entry).collect())?;
+        self.compact_archives()?;
+        self.enforce_archive_size_limit()?;
+        Ok(kept.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Deletes the oldest archive files (and their HTML pages) until the archive
+    /// JSON fits `archive_max_bytes`. The newest archive is always kept.
+    fn enforce_archive_size_limit(&self) -> Result<()> {
+        if self.archive_max_bytes == 0 {
+            return Ok(());
+        }
+        let archives = self.list_archive_json_paths()?;
+        let mut total: u64 = archives
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum();
+        for path in archives.iter().skip(1).rev() {
+            if total <= self.archive_max_bytes {
+                break;
+            }
+            total -= fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+            fs::remove_file(path)
+                .with_context(|| format!("failed to delete archive: {}", path.display()))?;
+            self.forget_file(path);
+            let file_name = path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .unwrap_or_default();
+            if let Some(date_key) = archive_key_of(path) {
+                self.remove_archive_pages(date_key)?;
+            }
+            self.record_audit("archive_prune", "", None, None, file_name);
+        }
+        Ok(())
+    }
+
+    /// Exports the archives dated before the retention window (`retention_months`
+    /// back from `today`) to a ZIP that `POST /history/restore-backup` accepts,
+    /// then deletes them with their pages and the images only they used. The
+    /// ZIP is complete on disk before anything is deleted. Returns `None` when
+    /// nothing has expired, retention is off or the store is read-only.
+    pub fn enforce_retention(&mut self, today: NaiveDate) -> Result<Option<RetentionExport>> {
+        // The instance that owns the data dir does the exporting.
+        if self.retention_months == 0 || self.read_only {
+            return Ok(None);
+        }
+        let Some(cutoff) = today.checked_sub_months(Months::new(self.retention_months)) else {
+            return Ok(None);
+        };
+        let cutoff = cutoff.format("%Y%m%d").to_string();
+        // A month archive expires once its whole month is past the cutoff.
+        let expired: Vec<PathBuf> = self
+            .list_archive_json_paths()?
+            .into_iter()
+            .filter(|path| {
+                archive_key_of(path).is_some_and(|key| match key.len() {
+                    6 => key < &cutoff[..6],
+                    _ => key < cutoff.as_str(),
+                })
+            })
+            .collect();
+        let (Some(newest), Some(oldest)) = (
+            expired.first().and_then(|path| archive_key_of(path)),
+            expired.last().and_then(|path| archive_key_of(path)),
+        ) else {
+            return Ok(None);
+        };
+
+        let mut files = Vec::new();
+        let mut entries = 0;
+        let mut images = BTreeSet::new();
+        for path in &expired {
+            let archived = self.read_entries(path)?;
+            entries += archived.len();
+            images.extend(archived.into_iter().flat_map(|entry| entry.images));
+            if let Some(name) = path.file_name().and_then(|v| v.to_str()) {
+                files.push(BackupFile {
+                    name: name.to_string(),
+                    path: path.clone(),
+                });
+            }
+        }
+        for image in &images {
+            let path = self.resolve_image_path(image)?;
+            if path.is_file() {
+                files.push(BackupFile {
+                    name: image.clone(),
+                    path,
+                });
+            }
+        }
+
+        fs::create_dir_all(&self.retention_export_dir).with_context(|| {
+            format!(
+                "failed to create export dir: {}",
+                self.retention_export_dir.display()
+            )
+        })?;
+        // An archive edited back in after an earlier export expires again
+        // under the same dates; that export is kept and this one numbered.
+        let stem = format!("History_{oldest}-{newest}");
+        let zip_path = (1..)
+            .map(|n| match n {
+                1 => self.retention_export_dir.join(format!("{stem}.zip")),
+                n => self.retention_export_dir.join(format!("{stem}_{n}.zip")),
+            })
+            .find(|path| !path.exists())
+            .expect("an unused export name");
+        let partial_path = zip_path.with_extension("zip.partial");
+        let file = File::create(&partial_path)
+            .with_context(|| format!("failed to create export: {}", partial_path.display()))?;
+        write_backup_zip(&files, file)?
+            .sync_all()
+            .with_context(|| format!("failed to write export: {}", partial_path.display()))?;
+        fs::rename(&partial_path, &zip_path)
+            .with_context(|| format!("failed to write export: {}", zip_path.display()))?;
+
+        for path in &expired {
+            fs::remove_file(path)
+                .with_context(|| format!("failed to delete archive: {}", path.display()))?;
+            self.forget_file(path);
+            if let Some(date_key) = archive_key_of(path) {
+                self.remove_archive_pages(date_key)?;
+            }
+        }
+        let mut images_deleted = 0;
+        for image in &images {
+            if !self.image_referenced(image)? {
+                self.delete_image_file(image)?;
+                images_deleted += 1;
+            }
+        }
+        let zip_name = zip_path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default()
+            .to_string();
+        self.record_audit(
+            "archive_retention",
+            "",
+            None,
+            None,
+            &format!("{zip_name} ({} files)", expired.len()),
+        );
+        Ok(Some(RetentionExport {
+            zip_path,
+            archives: expired.len(),
+            entries,
+            images_deleted,
+        }))
+    }
+
+    /// Rotation drops the oldest entries first, so the next `margin` copies push
+    /// out the head of the active list.
+    fn rotation_status_for(&self, entries: &[HistoryEntry]) -> RotationStatus {
+        let remaining = self.max_active_entries.saturating_sub(entries.len());
+        let pending = if self.rotation_warn_margin == 0 {
+            0
+        } else {
+            (entries.len() + self.rotation_warn_margin)
+                .saturating_sub(self.max_active_entries)
+                .min(entries.len())
+        };
+        RotationStatus {
+            active_entries: entries.len(),
+            max_entries: self.max_active_entries,
+            remaining,
+            pending_ids: entries[..pending].iter().map(|e| e.id.clone()).collect(),
+        }
+    }
+
+    fn archive_entries(&self, moving: Vec<HistoryEntry>) -> Result<()> {
+        let mut grouped: BTreeMap<String, Vec<HistoryEntry>> = BTreeMap::new();
+        for entry in moving {
+            let mut date_key = self.date_key_from_entry(&entry);
+            if self.archive_grouping == ArchiveGrouping::Month {
+                date_key.truncate(6);
+            }
+            grouped.entry(date_key).or_default().push(entry);
+        }
+
+        for (date_key, items) in grouped {
+            self.merge_into_archive(&date_key, items)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge_into_archive(&self, date_key: &str, items: Vec<HistoryEntry>) -> Result<()> {
+        let json_path = self.archive_json_path(date_key);
+        let existing = if json_path.exists() {
+            self.read_entries(&json_path)?
+        } else {
+            Vec::new()
+        };
+
+        let mut merged_by_id: BTreeMap<String, HistoryEntry> = BTreeMap::new();
+        for entry in existing.into_iter().chain(items) {
+            merged_by_id.insert(entry.id.clone(), entry);
+        }
+
+        let merged: Vec<HistoryEntry> = merged_by_id.into_values().collect();
+        self.write_entries(&json_path, &merged)
+    }
+
+    /// Once there are more than `archive_compact_threshold` daily archives, merges
+    /// the days of past months into `History_YYYYMM.json` and removes the daily
+    /// JSON and HTML files. Returns how many daily files were merged.
+    pub fn compact_archives(&self) -> Result<usize> {
+        let day_keys: Vec<String> = self
+            .collect_archive_date_keys()?
+            .into_iter()
+            .filter(|key| key.len() == 8)
+            .collect();
+        if self.archive_compact_threshold == 0 || day_keys.len() <= self.archive_compact_threshold {
+            return Ok(0);
+        }
+
+        let current_month = Local::now().format("%Y%m").to_string();
+        let mut months: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in day_keys {
+            if key[..6] < *current_month {
+                months.entry(key[..6].to_string()).or_default().push(key);
+            }
+        }
+
+        let mut merged_files = 0;
+        for (month, days) in months {
+            let mut items = Vec::new();
+            for day in &days {
+                items.extend(self.read_entries(&self.archive_json_path(day))?);
+            }
+            self.merge_into_archive(&month, items)?;
+            for day in &days {
+                let json_path = self.archive_json_path(day);
+                fs::remove_file(&json_path).with_context(|| {
+                    format!("failed to delete archive: {}", json_path.display())
+                })?;
+                self.forget_file(&json_path);
+                self.remove_archive_pages(day)?;
+            }
+            merged_files += days.len();
+            self.record_audit(
+                "archive_compact",
+                "",
+                None,
+                None,
+                &format!("History_{month}.json ({} files)", days.len()),
+            );
+        }
+        Ok(merged_files)
+    }
+
+    fn remove_archive_pages(&self, date_key: &str) -> Result<()> {
+        let mut page = 1;
+        while self.archive_html_path(date_key, page).exists() {
+            let html = self.archive_html_path(date_key, page);
+            fs::remove_file(&html)
+                .with_context(|| format!("failed to delete html: {}", html.display()))?;
+            page += 1;
+        }
+        Ok(())
+    }
+
+    fn find_entry_container(
+        &self,
+        history_id: &str,
+    ) -> Result<Option<(PathBuf, Vec<HistoryEntry>, usize)>> {
+        let mut sources = vec![self.history_json_path.clone()];
+        sources.extend(self.list_archive_json_paths()?);
+
+        for source in sources {
+            if !source.exists() {
+                continue;
+            }
+            let entries = self.read_entries(&source)?;
+            if let Some((index, _)) = entries
+                .iter()
+                .enumerate()
+                .find(|(_, entry)| entry.id.trim() == history_id)
+            {
+                return Ok(Some((source, entries, index)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn list_archive_json_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for item in fs::read_dir(&self.base_dir)
+            .with_context(|| format!("failed to list base dir: {}", self.base_dir.display()))?
+        {
+            let path = item?.path();
+            if archive_key_of(&path).is_some() {
+                paths.push(path);
+            }
+        }
+        paths.sort_by(|a, b| b.cmp(a));
+        Ok(paths)
+    }
+
+    /// Copies of history files made before a migration
+    /// (`history.v0.<timestamp>.bak.json`) or when a broken file was set aside
+    /// (`history.broken.<timestamp>.json`).
+    fn list_history_copy_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for item in fs::read_dir(&self.base_dir)
+            .with_context(|| format!("failed to list base dir: {}", self.base_dir.display()))?
+        {
+            let path = item?.path();
+            let Some(name) = path.file_name().and_then(|v| v.to_str()) else {
+                continue;
+            };
+            let migrated = (name.starts_with("history.") || name.starts_with("History_"))
+                && name.ends_with(".bak.json");
+            if migrated || name.starts_with("history.broken.") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn date_key_from_entry(&self, entry: &HistoryEntry) -> String {
+        if entry.id.len() >= 8 && entry.id.chars().take(8).all(|ch| ch.is_ascii_digit()) {
+            return entry.id[..8].to_string();
+        }
+
+        let digits: String = entry.ts.chars().filter(|ch| ch.is_ascii_digit()).collect();
+        if digits.len() >= 8 {
+            return digits[..8].to_string();
+        }
+
+        Local::now().format("%Y%m%d").to_string()
+    }
+
+    fn collect_archive_date_keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for path in self.list_archive_json_paths()? {
+            if let Some(date_key) = archive_key_of(&path) {
+                keys.push(date_key.to_string());
+            }
+        }
+        keys.sort_by(|a, b| b.cmp(a));
+        Ok(keys)
+    }
+
+    fn read_entries(&self, source: &Path) -> Result<Vec<HistoryEntry>> {
+        let raw_text = self.read_text(source)?;
+        let mut raw: Value = serde_json::from_str(&raw_text)
+            .with_context(|| format!("failed to parse json: {}", source.display()))?;
+
+        let version = history_schema_version(&raw);
+        run_migrations(
+            &mut raw,
+            version,
+            HISTORY_SCHEMA_VERSION,
+            &HISTORY_MIGRATIONS,
+        )
+        .with_context(|| format!("failed to migrate json: {}", source.display()))?;
+
+        let Some(array) = raw.get("entries").and_then(Value::as_array) else {
+            return Err(anyhow!("json has no entries array: {}", source.display()));
+        };
+
+        let mut normalized = Vec::new();
+        for item in array {
+            let Some(obj) = item.as_object() else {
+                continue;
+            };
+
+            let entry_id = obj
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let ts = obj
+                .get("ts")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let prompt = obj
+                .get("prompt")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+
+            let mut images = Vec::new();
+            if let Some(raw_images) = obj.get("images").and_then(Value::as_array) {
+                for value in raw_images {
+                    if let Some(path) = value.as_str().map(str::trim).filter(|v| !v.is_empty()) {
+                        images.push(path.to_string());
+                    }
+                }
+            }
+
+            let sample = obj.get("sample").and_then(Value::as_bool).unwrap_or(false);
+            let locked = obj.get("locked").and_then(Value::as_bool).unwrap_or(false);
+            let tags: Vec<String> = obj
+                .get("tags")
+                .and_then(Value::as_array)
+                .map(|raw_tags| {
+                    raw_tags
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let rating = obj
+                .get("rating")
+                .and_then(Value::as_u64)
+                .map_or(0, |v| v.min(5) as u8);
+            let count = obj
+                .get("count")
+                .and_then(Value::as_u64)
+                .map_or(1, |v| u32::try_from(v).unwrap_or(u32::MAX).max(1));
+            let note = obj
+                .get("note")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let selections = parse_selections(obj.get("selections"));
+            let image_metadata = parse_image_metadata(obj.get("image_metadata"));
+            let translation = obj
+                .get("translation")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            let score = obj
+                .get("score")
+                .and_then(Value::as_u64)
+                .map(|v| v.min(100) as u8);
+
+            if entry_id.is_empty() || ts.is_empty() || prompt.is_empty() {
+                continue;
+            }
+
+            normalized.push(HistoryEntry {
+                id: entry_id,
+                ts,
+                prompt,
+                images,
+                sample,
+                locked,
+                tags,
+                note,
+                rating,
+                count,
+                selections,
+                translation,
+                score,
+                image_metadata,
+            });
+        }
+
+        Ok(normalized)
+    }
+
+    fn write_entries(&self, target: &Path, entries: &[HistoryEntry]) -> Result<()> {
+        let payload = serde_json::to_string_pretty(&HistoryFile {
+            schema_version: HISTORY_SCHEMA_VERSION,
+            entries,
+        })
+        .context("failed to serialize history json")?;
+        self.ensure_writable()?;
+        // Archives are written rarely and each one is its own copy of old
+        // entries; the active history keeps its previous version as `.bak`.
+        let keep_backup = target == self.history_json_path;
+        let sealed = self.seal(payload.as_bytes())?;
+        write_file_atomic(target, &sealed, keep_backup)?;
+        self.remember_file(target, &sealed);
+        Ok(())
+    }
+
+    fn read_trash(&self) -> Result<Vec<TrashEntry>> {
+        if !self.trash_json_path.exists() {
+            return Ok(Vec::new());
+        }
+        let raw_text = self.read_text(&self.trash_json_path)?;
+        let trash: TrashFile = serde_json::from_str(&raw_text)
+            .with_context(|| format!("failed to parse json: {}", self.trash_json_path.display()))?;
+        Ok(trash.entries)
+    }
+
+    fn write_trash(&self, entries: &[TrashEntry]) -> Result<()> {
+        let payload = serde_json::to_string_pretty(&TrashFile {
+            schema_version: HISTORY_SCHEMA_VERSION,
+            entries: entries.to_vec(),
+        })
+        .context("failed to serialize trash json")?;
+        self.ensure_writable()?;
+        write_file_atomic(&self.trash_json_path, &self.seal(payload.as_bytes())?, true)
+    }
+
+    fn next_entry_id(&self, now: NaiveDateTime, entries: &[HistoryEntry]) -> String {
+        let base = now.format("%Y%m%d_%H%M%S").to_string();
+        let prefix = format!("{}{}", base, "_");
+        let mut seq: i32 = 1;
+
+        for entry in entries {
+            if !entry.id.starts_with(&prefix) {
+                continue;
+            }
+            let parts: Vec<&str> = entry.id.split('_').collect();
+            if parts.len() != 3 {
+                continue;
+            }
+            if let Ok(parsed) = parts[2].parse::<i32>() {
+                seq = seq.max(parsed + 1);
+            }
+        }
+
+        format!("{base}_{seq:04}")
+    }
+
+    fn next_image_rel_path(&self, now: NaiveDateTime, month_dir: &Path, ext: &str) -> PathBuf {
+        let base = now.format("%Y%m%d_%H%M%S").to_string();
+        let year = now.format("%Y").to_string();
+        let month = now.format("%m").to_string();
+        let mut seq = 1u32;
+
+        loop {
+            let file_name = format!("{}_{:02}{}", base, seq, ext);
+            let abs_path = month_dir.join(&file_name);
+            if !abs_path.exists() {
+                return PathBuf::from("images")
+                    .join(year.clone())
+                    .join(month.clone())
+                    .join(file_name);
+            }
+            seq += 1;
+        }
+    }
+
+    /// One `<article class="entry">` card, as the pages render it and
+    /// `GET /history/list` returns it.
+    fn render_card(&self, entry: &HistoryEntry, view: &CardView) -> String {
+        let interactive = view.interactive;
+        let entry_id = encode_double_quoted_attribute(&entry.id).to_string();
+        let ts = encode_text(&entry.ts).to_string();
+        let prompt_html = encode_text(&entry.prompt).to_string();
+        let translation_block = if entry.translation.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<div class=\"translation\"><div class=\"translation-toolbar\"><span>English</span><button class=\"btn translation-copy-btn\">英語をコピー</button></div><pre class=\"translation-text\">{}</pre></div>",
+                encode_text(&entry.translation)
+            )
+        };
+
+        let selected_image_path = entry.images.last().cloned().unwrap_or_default();
+        let selected_image_attr = encode_double_quoted_attribute(&selected_image_path).to_string();
+        let has_image = !selected_image_path.is_empty();
+
+        let mut images_block = String::new();
+        for image_path in &entry.images {
+            let safe_path_attr = encode_double_quoted_attribute(image_path).to_string();
+            let safe_path_text = encode_text(image_path).to_string();
+            let selected_class = if *image_path == selected_image_path {
+                " is-selected"
+            } else {
+                ""
+            };
+            let image_similar_btn = if interactive {
+                "<button class=\"image-similar-btn\" title=\"似た画像を探す\">似</button>"
+            } else {
+                ""
+            };
+            let image_delete_btn = if interactive && !entry.locked {
+                "<button class=\"image-delete-btn\" title=\"この画像を削除\">×</button>"
+            } else {
+                ""
+            };
+            // Pages link the full image but show the thumbnail once it exists.
+            let thumb_src = thumbnail_rel_path(image_path)
+                .filter(|rel| self.base_dir.join(rel).exists())
+                .map(|rel| encode_double_quoted_attribute(&rel).to_string())
+                .unwrap_or_else(|| safe_path_attr.clone());
+            // With a placeholder the real source waits in data-src until the
+            // card scrolls near the viewport.
+            let img_tag = match view.previews.get(image_path) {
+                Some(preview) => format!(
+                    "<img class=\"thumb-image is-placeholder\" src=\"{}\" data-src=\"{}\" alt=\"history image\" />",
+                    encode_double_quoted_attribute(preview),
+                    thumb_src
+                ),
+                None => format!(
+                    "<img class=\"thumb-image\" src=\"{thumb_src}\" alt=\"history image\" loading=\"lazy\" />"
+                ),
+            };
+            let metadata_block = entry
+                .image_metadata
+                .get(image_path)
+                .map(image_metadata_html)
+                .unwrap_or_default();
+            images_block.push_str(&format!(
+                "<div class=\"image-item{}\" data-image-path=\"{}\"><a class=\"thumb-image-link\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a><div class=\"thumb-footer\"><a class=\"thumb-path\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a>{}{}</div>{}</div>",
+                selected_class,
+                safe_path_attr,
+                safe_path_attr,
+                img_tag,
+                safe_path_attr,
+                safe_path_text,
+                image_similar_btn,
+                image_delete_btn,
+                metadata_block
+            ));
+        }
+        if !has_image {
+            images_block.push_str("<span class=\"muted\">画像なし</span>");
+        }
+        let gallery_class = if entry.images.len() > 1 {
+            " gallery"
+        } else {
+            ""
+        };
+
+        let sample_badge = if entry.sample {
+            "<span class=\"sample-badge\">サンプル</span>"
+        } else {
+            ""
+        };
+        let tag_badges: String = entry
+            .tags
+            .iter()
+            .map(|tag| format!("<span class=\"tag-badge\">{}</span>", encode_text(tag)))
+            .collect();
+        let count_badge = if entry.count > 1 {
+            format!(
+                "<span class=\"count-badge\" title=\"同じプロンプトをコピーした回数\">×{}</span>",
+                entry.count
+            )
+        } else {
+            String::new()
+        };
+        let score_badge = entry
+            .score
+            .map(|score| {
+                format!("<span class=\"score-badge\" title=\"コピー時の採点（score_command）\">{score}点</span>")
+            })
+            .unwrap_or_default();
+        let pending_archive = view.pending_ids.contains(&entry.id);
+        let archive_badge = if pending_archive {
+            "<span class=\"archive-badge\">次にアーカイブ</span>"
+        } else {
+            ""
+        };
+
+        let delete_btn = if interactive && view.allow_delete {
+            "<button class=\"btn delete-btn\">削除</button>"
+        } else {
+            ""
+        };
+        let move_btn = match (interactive, view.active_page) {
+            (true, true) => "<button class=\"btn archive-btn\">アーカイブ</button>",
+            (true, false) => "<button class=\"btn unarchive-btn\">アクティブに戻す</button>",
+            _ => "",
+        };
+        let apply_btn = if interactive {
+            "<button class=\"btn apply-btn\" title=\"この履歴の選択内容をメイン画面に戻す\">この内容を復元</button>"
+        } else {
+            ""
+        };
+        let lock_btn = if interactive {
+            if entry.locked {
+                "<button class=\"lock-btn\" title=\"ロック中（クリックで解除）\">🔒</button>"
+            } else {
+                "<button class=\"lock-btn\" title=\"クリックでロック\">🔓</button>"
+            }
+        } else if entry.locked {
+            "<span class=\"lock-btn\" title=\"ロック中\">🔒</span>"
+        } else {
+            ""
+        };
+        let rating_widget = if interactive {
+            let stars: String = (1..=5u8)
+                .map(|value| {
+                    let filled = if value <= entry.rating { " filled" } else { "" };
+                    format!("<button class=\"star{filled}\" data-value=\"{value}\" title=\"{value}\">★</button>")
+                })
+                .collect();
+            format!("<span class=\"rating\">{stars}</span>")
+        } else if entry.rating > 0 {
+            format!(
+                "<span class=\"rating\">{}</span>",
+                "★".repeat(usize::from(entry.rating))
+            )
+        } else {
+            String::new()
+        };
+        let locked_class = match (entry.locked, pending_archive) {
+            (true, true) => " locked archive-next",
+            (true, false) => " locked",
+            (false, true) => " archive-next",
+            (false, false) => "",
+        };
+        let readonly_attr = if entry.locked { " readonly" } else { "" };
+        let image_copy_disabled = if has_image { "" } else { " disabled" };
+
+        let upload_block = if interactive {
+            let upload_text = upload_label(entry.images.len(), self.max_images_per_entry);
+            let upload_state_class = if has_image {
+                "has-image"
+            } else {
+                "needs-image"
+            };
+            format!(
+                "<section class=\"upload\" data-history-id=\"{}\"><div class=\"dropzone {}\" tabindex=\"0\" title=\"マウスを重ねて Ctrl+V でクリップボードの画像を貼り付け\">{}</div><input class=\"file-input\" type=\"file\" accept=\".png,.jpg,.jpeg,.webp,.gif\" multiple /></section>",
+                entry_id,
+                upload_state_class,
+                encode_text(&upload_text)
+            )
+        } else {
+            String::new()
+        };
+
+        format!(
+            "<article class=\"entry{}\" data-history-id=\"{}\" data-has-image=\"{}\" data-selected-image=\"{}\" data-max-images=\"{}\" data-locked=\"{}\" data-rating=\"{}\" data-ts=\"{}\" data-tags=\"{}\"><header class=\"entry-header\"><span class=\"timestamp\">{}</span>{}{}{}{}{}{}{}</header><div class=\"entry-body\"><section class=\"prompt-pane\"><div class=\"prompt-toolbar\"><button class=\"btn overwrite-btn\">上書き</button><button class=\"btn copy-btn\">コピー</button>{}{}{}</div><textarea class=\"prompt-editor\" spellcheck=\"false\"{}>{}</textarea>{}<textarea class=\"note-editor\" spellcheck=\"false\" placeholder=\"メモ（モデル・シードなど）\"{}>{}</textarea></section><section class=\"media-pane\">{}<section class=\"images{}\">{}</section><button class=\"btn image-copy-btn\"{}>画像をクリップボードにコピー</button></section></div></article>",
+            locked_class,
+            entry_id,
+            if has_image { "true" } else { "false" },
+            selected_image_attr,
+            self.max_images_per_entry,
+            entry.locked,
+            entry.rating,
+            encode_double_quoted_attribute(&entry.ts),
+            encode_double_quoted_attribute(&serde_json::to_string(&entry.tags).unwrap_or_default()),
+            ts,
+            rating_widget,
+            sample_badge,
+            tag_badges,
+            count_badge,
+            score_badge,
+            archive_badge,
+            lock_btn,
+            delete_btn,
+            move_btn,
+            apply_btn,
+            readonly_attr,
+            prompt_html,
+            translation_block,
+            readonly_attr,
+            encode_text(&entry.note),
+            upload_block,
+            gallery_class,
+            images_block,
+            image_copy_disabled
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build_history_html(
+        &self,
+        entries: &[HistoryEntry],
+        title: &str,
+        interactive: bool,
+        allow_delete: bool,
+        server_port: u16,
+        archive_date_keys: &[String],
+        rotation: Option<&RotationStatus>,
+        trash: &[TrashEntry],
+        smart_filters: &[SmartFilter],
+        page: Option<&ArchivePage>,
+    ) -> String {
+        let pending_ids: &[String] = rotation.map_or(&[], |r| r.pending_ids.as_slice());
+        // A missing or broken index only costs the blurred placeholders.
+        let previews = self.image_previews.load().unwrap_or_default();
+
+        let mut sorted_entries = entries.to_vec();
+        sorted_entries.sort_by(|a, b| b.id.cmp(&a.id));
+
+        let view = CardView {
+            interactive,
+            allow_delete,
+            active_page: rotation.is_some(),
+            pending_ids,
+            previews: &previews,
+        };
+        // The active page grows without bound, so it starts with one page of
+        // cards and fetches the rest from `GET /history/list` on scroll.
+        let shown = if interactive && view.active_page {
+            Self::LIST_PAGE_SIZE
+        } else {
+            sorted_entries.len()
+        };
+        let cards: Vec<String> = sorted_entries
+            .iter()
+            .take(shown)
+            .map(|entry| self.render_card(entry, &view))
+            .collect();
+        let load_more = if sorted_entries.len() > cards.len() {
+            format!(
+                "\n<div class=\"load-more\" data-offset=\"{}\" data-total=\"{}\">残り {} 件（アプリ起動中はスクロールで読み込みます）</div>",
+                cards.len(),
+                sorted_entries.len(),
+                sorted_entries.len() - cards.len()
+            )
+        } else {
+            String::new()
+        };
+
+        let page_nav = page.map(archive_page_nav).unwrap_or_default();
+        let body_cards = if cards.is_empty() {
+            "<p class=\"empty\">履歴はまだありません。</p>".to_string()
+        } else {
+            format!(
+                "{page_nav}<div class=\"sort-bar\"><span>並び順:</span><button class=\"sort-btn is-active\" data-sort=\"new\">新しい順</button><button class=\"sort-btn\" data-sort=\"rating\">評価順</button></div>\n<div class=\"entries\">\n{}\n</div>{load_more}{page_nav}",
+                cards.join("\n")
+            )
+        };
+
+        let archive_links = if archive_date_keys.is_empty() {
+            String::new()
+        } else {
+            let mut links = vec![format!(
+                "<a class=\"archive-link\" href=\"{ARCHIVE_INDEX_HTML}\" target=\"_blank\" rel=\"noopener noreferrer\">一覧（{ARCHIVE_INDEX_HTML}）</a>"
+            )];
+            for date_key in archive_date_keys {
+                let href = format!("History_{}.html", date_key);
+                links.push(format!(
+                    "<a class=\"archive-link\" href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a>",
+                    encode_double_quoted_attribute(&href),
+                    encode_text(&href)
+                ));
+            }
+            format!(
+                "<section class=\"archives\"><h2>Archives</h2><div class=\"archive-list\">{}</div></section>",
+                links.join("")
+            )
+        };
+        let samples_block = if interactive && entries.iter().any(|entry| entry.sample) {
+            "<section class=\"samples\"><span>サンプル履歴を表示しています。</span><button class=\"btn delete-samples-btn\">サンプルを削除</button></section>"
+        } else {
+            ""
+        };
+        let rotation_block = match rotation {
+            Some(status) if !status.pending_ids.is_empty() => {
+                let button = if interactive {
+                    "<button class=\"btn archive-now-btn\">今すぐアーカイブ</button>"
+                } else {
+                    ""
+                };
+                format!(
+                    "<section class=\"rotation-warning\"><span>アクティブ履歴 {}/{} 件。あと {} 件のコピーで古い順にアーカイブへ移動します（「次にアーカイブ」{} 件）。</span>{}</section>",
+                    status.active_entries,
+                    status.max_entries,
+                    status.remaining + 1,
+                    status.pending_ids.len(),
+                    button
+                )
+            }
+            _ => String::new(),
+        };
+        let export_block = if interactive && rotation.is_some() {
+            let api_base = format!("http://127.0.0.1:{server_port}");
+            format!(
+                "<section class=\"exports\"><span>書き出し:</span><a class=\"export-link\" href=\"{api_base}/history/export?format=csv\">CSV</a><a class=\"export-link\" href=\"{api_base}/history/export?format=md\">Markdown</a><a class=\"export-link\" href=\"{api_base}/history/backup\">バックアップをダウンロード</a><a class=\"export-link\" href=\"{api_base}/history/audit\" target=\"_blank\" rel=\"noopener noreferrer\">変更履歴</a><a class=\"export-link\" href=\"{api_base}/history/housekeeping\" target=\"_blank\" rel=\"noopener noreferrer\">整理ルール</a><label class=\"import-label\">読み込み（JSON / CSV）<input class=\"import-input\" type=\"file\" accept=\".json,.csv\" /></label><label class=\"restore-label\">バックアップから復元（ZIP）<input class=\"restore-backup-input\" type=\"file\" accept=\".zip\" /></label><span>別の出力形式で再出力:</span><button class=\"btn rerender-btn\" data-format=\"csv\">CSV</button><button class=\"btn rerender-btn\" data-format=\"md\">Markdown</button></section>"
+            )
+        } else {
+            String::new()
+        };
+        let trash_block = if interactive && !trash.is_empty() {
+            let items: String = trash
+                .iter()
+                .map(|item| {
+                    let excerpt: String = item.entry.prompt.chars().take(80).collect();
+                    format!(
+                        "<li class=\"trash-item\" data-history-id=\"{}\"><span class=\"timestamp\">{}</span><span class=\"trash-prompt\">{}</span><button class=\"btn restore-btn\">元に戻す</button></li>",
+                        encode_double_quoted_attribute(&item.entry.id),
+                        encode_text(&item.deleted_at),
+                        encode_text(&excerpt)
+                    )
+                })
+                .collect();
+            format!(
+                "<details class=\"trash\"><summary>ゴミ箱（{} 件・削除から {} 日後に完全削除）</summary><ul class=\"trash-list\">{}</ul></details>",
+                trash.len(),
+                self.trash_retention_days,
+                items
+            )
+        } else {
+            String::new()
+        };
+        let search_block = if interactive {
+            let chips: String = smart_filters
+                .iter()
+                .map(|filter| {
+                    format!(
+                        "<span class=\"smart-filter\" data-filter=\"{}\"><button class=\"smart-filter-apply\">{}</button><button class=\"smart-filter-delete\" title=\"保存した検索を削除\">×</button></span>",
+                        encode_double_quoted_attribute(&serde_json::to_string(filter).unwrap_or_default()),
+                        encode_text(&filter.name)
+                    )
+                })
+                .collect();
+            let rating_options: String = (1..=5)
+                .map(|value| format!("<option value=\"{value}\">★{value}以上</option>"))
+                .collect();
+            format!(
+                "<section class=\"search-panel\"><div class=\"search-fields\"><input class=\"search-query\" type=\"search\" placeholder=\"プロンプト・メモを検索\" /><input class=\"search-tags\" type=\"text\" placeholder=\"タグ（カンマ区切り）\" /><select class=\"search-rating\"><option value=\"0\">評価: 指定なし</option>{rating_options}</select><input class=\"search-from\" type=\"date\" title=\"この日以降\" /><span>〜</span><input class=\"search-to\" type=\"date\" title=\"この日以前\" /><select class=\"search-image\"><option value=\"\">画像: 指定なし</option><option value=\"true\">画像あり</option><option value=\"false\">画像なし</option></select><button class=\"btn search-save-btn\">条件を保存</button><button class=\"btn search-clear-btn\">クリア</button><button class=\"btn archive-search-btn\" title=\"文字と期間の条件でアーカイブを含む全履歴を検索\">アーカイブも検索</button><span class=\"search-count\"></span><label class=\"btn similar-upload\" title=\"スクリーンショットに似た画像を履歴から探す\">スクショで探す<input class=\"similar-file\" type=\"file\" accept=\"image/*\" hidden /></label><label class=\"btn vision-upload\" title=\"vision_command でスクリーンショットの文字・内容を読み取る\">スクショから読み取る<input class=\"vision-file\" type=\"file\" accept=\"image/*\" hidden /></label></div><div class=\"smart-filters\"><span>保存した検索:</span>{}</div><div class=\"vision-result\" hidden><textarea class=\"vision-text\" rows=\"4\"></textarea><div class=\"vision-actions\"><button class=\"btn vision-search-btn\">検索に使う</button><button class=\"btn vision-append-btn\">新しい履歴にする</button><button class=\"btn vision-close-btn\">閉じる</button></div></div><div class=\"archive-search-results\" hidden></div><div class=\"similar-results\" hidden></div></section>",
+                if chips.is_empty() {
+                    "<span class=\"muted\">なし</span>".to_string()
+                } else {
+                    chips
+                }
+            )
+        } else {
+            String::new()
+        };
+        let runtime_notice = if allow_delete {
+            "<p class=\"runtime-note\">※このページの上書き・削除・画像追加・画像コピー機能は、アプリ起動中のみ使用できます。</p>"
+        } else {
+            ""
+        };
+
+        let interactive_script = if interactive {
+            INTERACTIVE_SCRIPT_TEMPLATE
+                .replace("__API_BASE__", &format!("http://127.0.0.1:{server_port}"))
+                .replace("__STORE_ID__", &self.store_id())
+                .replace("__API_VERSION__", &API_VERSION.to_string())
+                .replace("__LIST_PAGE_SIZE__", &Self::LIST_PAGE_SIZE.to_string())
+                .replace("__THUMB_WIDTH__", &THUMB_MAX_SIDE.to_string())
+                .replace(
+                    "__DELETE_IMAGE_FILES__",
+                    if self.delete_image_files {
+                        "true"
+                    } else {
+                        "false"
+                    },
+                )
+        } else {
+            NON_INTERACTIVE_SCRIPT.to_string()
+        };
+
+        let mut output = String::new();
+        output.push_str("<!doctype html>\n<html lang=\"ja\">\n<head>\n");
+        output.push_str("  <meta charset=\"utf-8\" />\n");
+        output.push_str(
+            "  <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />\n",
+        );
+        output.push_str("  <title>");
+        output.push_str(&encode_text(title));
+        output.push_str("</title>\n");
+        output.push_str(HISTORY_STYLE);
+        output.push_str("\n</head>\n<body>\n  <main class=\"wrap\">\n    <h1>");
+        output.push_str(&encode_text(title));
+        output.push_str("</h1>\n");
+        output.push_str(runtime_notice);
+        output.push('\n');
+        output.push_str(samples_block);
+        output.push('\n');
+        output.push_str(&rotation_block);
+        output.push('\n');
+        output.push_str(&export_block);
+        output.push('\n');
+        output.push_str(&archive_links);
+        output.push('\n');
+        output.push_str(&trash_block);
+        output.push('\n');
+        output.push_str(&search_block);
+        output.push('\n');
+        output.push_str(&body_cards);
+        output.push_str("\n  </main>\n");
+        output.push_str(&interactive_script);
+        output.push_str(LAZY_IMAGE_SCRIPT);
+        output.push_str("\n</body>\n</html>\n");
+        output
+    }
+}
+
+/// One page of a split archive day.
+struct ArchivePage<'a> {
+    date_key: &'a str,
+    /// 1-based.
+    number: usize,
+    count: usize,
+}
+
+/// The archive key of a `History_YYYYMMDD.json` (day) or `History_YYYYMM.json`
+/// (month) file; `None` for any other file.
+fn archive_key_of(path: &Path) -> Option<&str> {
+    let key = path
+        .file_name()?
+        .to_str()?
+        .strip_prefix("History_")?
+        .strip_suffix(".json")?;
+    (matches!(key.len(), 6 | 8) && key.chars().all(|ch| ch.is_ascii_digit())).then_some(key)
+}
+
+/// `History_YYYYMMDD.html` for the first page, `History_YYYYMMDD_pN.html` after it,
+/// so links to an archive day keep pointing at its newest cards.
+fn archive_page_name(date_key: &str, page: usize) -> String {
+    if page <= 1 {
+        format!("History_{date_key}.html")
+    } else {
+        format!("History_{date_key}_p{page}.html")
+    }
+}
+
+/// Inverse of [`archive_page_name`]: `(date key, 1-based page)`.
+fn parse_archive_page_name(name: &str) -> Option<(&str, usize)> {
+    let stem = name.strip_prefix("History_")?.strip_suffix(".html")?;
+    let (key, number) = match stem.split_once("_p") {
+        Some((key, page)) => (key, page.parse::<usize>().ok().filter(|page| *page >= 2)?),
+        None => (stem, 1),
+    };
+    (matches!(key.len(), 6 | 8) && key.chars().all(|ch| ch.is_ascii_digit()))
+        .then_some((key, number))
+}
+
+fn archive_page_nav(page: &ArchivePage) -> String {
+    if page.count <= 1 {
+        return String::new();
+    }
+    let link = |number: usize, text: &str| {
+        format!(
+            "<a class=\"page-link\" href=\"{}\">{text}</a>",
+            encode_double_quoted_attribute(&archive_page_name(page.date_key, number))
+        )
+    };
+    let mut parts = Vec::new();
+    if page.number > 1 {
+        parts.push(link(page.number - 1, "← 前へ"));
+    }
+    for number in 1..=page.count {
+        if number == page.number {
+            parts.push(format!("<span class=\"page-current\">{number}</span>"));
+        } else {
+            parts.push(link(number, &number.to_string()));
+        }
+    }
+    if page.number < page.count {
+        parts.push(link(page.number + 1, "次へ →"));
+    }
+    format!("<nav class=\"page-nav\">{}</nav>", parts.join(""))
+}
+
+fn ensure_unlocked(entry: &HistoryEntry) -> Result<()> {
+    if entry.locked {
+        return Err(anyhow!("history entry is locked"));
+    }
+    Ok(())
+}
+
+fn history_schema_version(doc: &Value) -> u32 {
+    if doc.is_array() {
+        return 0;
+    }
+    doc.get("schema_version")
+        .and_then(Value::as_u64)
+        .and_then(|v| u32::try_from(v).ok())
+        .unwrap_or(0)
+}
+
+fn wrap_entry_array(doc: &mut Value) -> Result<()> {
+    if doc.is_array() {
+        *doc = json!({ "entries": doc.take() });
+    }
+    Ok(())
+}
+
+/// Reads a JSON array (or history file) or CSV export into entries for `import_entries`.
+pub fn parse_import_file(file_name: &str, data: &[u8]) -> Result<Vec<HistoryEntry>> {
+    let text = std::str::from_utf8(data).map_err(|_| anyhow!("import file is not UTF-8"))?;
+    let text = text.trim_start_matches('\u{feff}');
+    let is_csv = file_name.to_ascii_lowercase().ends_with(".csv")
+        || !text.trim_start().starts_with(['[', '{']);
+    let entries = if is_csv {
+        parse_import_csv(text)?
+    } else {
+        parse_import_json(text)?
+    };
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !entry.prompt.is_empty())
+        .collect())
+}
+
+fn parse_import_json(text: &str) -> Result<Vec<HistoryEntry>> {
+    let mut raw: Value = serde_json::from_str(text).context("failed to parse import json")?;
+    let version = history_schema_version(&raw);
+    run_migrations(
+        &mut raw,
+        version,
+        HISTORY_SCHEMA_VERSION,
+        &HISTORY_MIGRATIONS,
+    )?;
+    let Some(array) = raw.get("entries").and_then(Value::as_array) else {
+        return Err(anyhow!("import json has no entries"));
+    };
+
+    let text_field = |item: &Value, key: &str| {
+        item.get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    Ok(array
+        .iter()
+        .map(|item| HistoryEntry {
+            id: text_field(item, "id"),
+            ts: text_field(item, "ts"),
+            prompt: text_field(item, "prompt"),
+            images: item
+                .get("images")
+                .and_then(Value::as_array)
+                .map(|images| {
+                    images
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            sample: false,
+            locked: item.get("locked").and_then(Value::as_bool).unwrap_or(false),
+            tags: item
+                .get("tags")
+                .and_then(Value::as_array)
+                .map(|tags| {
+                    tags.iter()
+                        .filter_map(Value::as_str)
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            note: text_field(item, "note"),
+            rating: item
+                .get("rating")
+                .and_then(Value::as_u64)
+                .map_or(0, |v| v.min(5) as u8),
+            count: item
+                .get("count")
+                .and_then(Value::as_u64)
+                .map_or(1, |v| u32::try_from(v).unwrap_or(u32::MAX).max(1)),
+            selections: parse_selections(item.get("selections")),
+            translation: text_field(item, "translation"),
+            score: item
+                .get("score")
+                .and_then(Value::as_u64)
+                .map(|v| v.min(100) as u8),
+            image_metadata: parse_image_metadata(item.get("image_metadata")),
+        })
+        .collect())
+}
+
+fn parse_selections(value: Option<&Value>) -> BTreeMap<String, String> {
+    value
+        .and_then(Value::as_object)
+        .map(|map| {
+            map.iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Collapsible generation info shown under a thumbnail; mirrors `buildImageMetadata`
+/// in the page script.
+fn image_metadata_html(metadata: &GenerationMetadata) -> String {
+    let rows: String = [
+        ("プロンプト", &metadata.prompt),
+        ("ネガティブ", &metadata.negative_prompt),
+        ("設定", &metadata.settings),
+    ]
+    .iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(label, value)| format!("<dt>{label}</dt><dd>{}</dd>", encode_text(value)))
+    .collect();
+    format!(
+        "<details class=\"image-meta\"><summary>生成情報（{}）</summary><dl>{rows}</dl></details>",
+        encode_text(metadata.source_label())
+    )
+}
+
+/// Entries that do not parse as metadata are dropped rather than failing the file.
+fn parse_image_metadata(value: Option<&Value>) -> BTreeMap<String, GenerationMetadata> {
+    value
+        .and_then(Value::as_object)
+        .map(|map| {
+            map.iter()
+                .filter_map(|(path, value)| {
+                    Some((path.clone(), serde_json::from_value(value.clone()).ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_import_csv(text: &str) -> Result<Vec<HistoryEntry>> {
+    let mut rows = parse_csv_rows(text)?.into_iter();
+    let header = rows.next().ok_or_else(|| anyhow!("import csv is empty"))?;
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+    let Some(prompt_col) = column("prompt") else {
+        return Err(anyhow!("import csv has no prompt column"));
+    };
+    let (id_col, ts_col, images_col) = (column("id"), column("ts"), column("images"));
+    let (note_col, rating_col) = (column("note"), column("rating"));
+
+    let cell = |row: &[String], col: Option<usize>| {
+        col.and_then(|c| row.get(c))
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default()
+    };
+    Ok(rows
+        .map(|row| HistoryEntry {
+            id: cell(&row, id_col),
+            ts: cell(&row, ts_col),
+            prompt: cell(&row, Some(prompt_col)),
+            images: cell(&row, images_col)
+                .split('|')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+            sample: false,
+            locked: false,
+            tags: Vec::new(),
+            note: cell(&row, note_col),
+            rating: cell(&row, rating_col).parse::<u8>().map_or(0, |v| v.min(5)),
+            count: 1,
+            selections: BTreeMap::new(),
+            translation: String::new(),
+            score: None,
+            image_metadata: BTreeMap::new(),
+        })
+        .collect())
+}
+
+/// Minimal RFC 4180 reader: quoted fields may hold commas, `""` and newlines.
+fn parse_csv_rows(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if in_quotes {
+            match ch {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(ch),
+            }
+            continue;
+        }
+        match ch {
+            '"' => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(ch),
+        }
+    }
+    if in_quotes {
+        return Err(anyhow!("import csv has an unterminated quoted field"));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn parse_export_date(value: Option<&str>) -> Result<Option<NaiveDate>> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y%m%d"))
+        .map(Some)
+        .map_err(|_| anyhow!("invalid date: {value} (expected YYYY-MM-DD)"))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn export_csv(entries: &[HistoryEntry]) -> String {
+    // The BOM lets Excel detect UTF-8 so Japanese prompts open intact.
+    let mut output = String::from("\u{feff}id,ts,prompt,images,note,rating\r\n");
+    for entry in entries {
+        let fields = [
+            csv_field(&entry.id),
+            csv_field(&entry.ts),
+            csv_field(&entry.prompt),
+            csv_field(&entry.images.join(" | ")),
+            csv_field(&entry.note),
+            entry.rating.to_string(),
+        ];
+        output.push_str(&fields.join(","));
+        output.push_str("\r\n");
+    }
+    output
+}
+
+fn export_markdown(entries: &[HistoryEntry]) -> String {
+    let mut output = String::from("# Prompt History\n");
+    for entry in entries {
+        let mut fence = "```".to_string();
+        while entry.prompt.contains(&fence) || entry.translation.contains(&fence) {
+            fence.push('`');
+        }
+        output.push_str(&format!(
+            "\n## {} (`{}`){}\n\n{}text\n{}\n{}\n",
+            entry.ts,
+            entry.id,
+            if entry.rating > 0 {
+                format!(" {}", "★".repeat(usize::from(entry.rating)))
+            } else {
+                String::new()
+            },
+            fence,
+            entry.prompt,
+            fence
+        ));
+        if !entry.translation.is_empty() {
+            output.push_str(&format!(
+                "\n{}text\n{}\n{}\n",
+                fence, entry.translation, fence
+            ));
+        }
+        if !entry.note.is_empty() {
+            output.push('\n');
+            for line in entry.note.lines() {
+                output.push_str(&format!("> {line}\n"));
+            }
+        }
+        if !entry.images.is_empty() {
+            output.push('\n');
+            for image in &entry.images {
+                output.push_str(&format!("- {image}\n"));
+            }
+        }
+    }
+    output
+}
+
+fn upload_label(image_count: usize, max_images: usize) -> String {
+    if image_count == 0 {
+        "画像追加: ドラッグ&ドロップ・クリック・Ctrl+V".to_string()
+    } else if image_count >= max_images {
+        format!("画像 {image_count}/{max_images}（追加すると最も古い画像と差し替え）")
+    } else {
+        format!("画像 {image_count}/{max_images}（追加はD＆Dまたはクリック）")
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+fn is_single(value: &u32) -> bool {
+    *value <= 1
+}
+
+/// Collapses whitespace so reflowed copies of a prompt compare equal.
+/// The schema version of a parsed history file; newer than this build is an
+/// error, so such a file is never mistaken for a broken one and reset.
+fn check_schema_version(raw: &Value, source: &Path) -> Result<u32> {
+    let version = history_schema_version(raw);
+    if version > HISTORY_SCHEMA_VERSION {
+        return Err(anyhow!(
+            "unsupported schema version {} in {} (supported up to {})",
+            version,
+            source.display(),
+            HISTORY_SCHEMA_VERSION
+        ));
+    }
+    Ok(version)
+}
+
+fn normalize_prompt(prompt: &str) -> String {
+    prompt.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
+fn path_to_posix(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn image_content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|v| v.to_str())
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+const HISTORY_STYLE: &str = r#"
+  <style>
+    :root {
+      --bg: #f6f6ef;
+      --panel: #ffffff;
+      --line: #1f2a44;
+      --accent: #cb4b16;
+      --accent-2: #174c7a;
+      --text: #1e1e1e;
+      --muted: #666;
+    }
+    * { box-sizing: border-box; }
+    body {
+      margin: 0;
+      color: var(--text);
+      background: radial-gradient(circle at 10% 10%, #fff8d8, transparent 35%), linear-gradient(180deg, #f7f5ec, #ece8d8);
+      font-family: "Yu Mincho", "Hiragino Mincho ProN", serif;
+    }
+    .wrap { max-width: 980px; margin: 32px auto; padding: 0 16px 32px; }
+    h1 { margin: 0 0 10px; font-size: 38px; letter-spacing: 0.04em; }
+    .runtime-note {
+      margin: 0 0 16px;
+      border: 1px solid #d8c78d;
+      background: #fff7dc;
+      color: #5c4a1f;
+      padding: 8px 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+      line-height: 1.5;
+    }
+    h2 { margin: 0 0 8px; font-size: 20px; }
+    .archives {
+      margin: 0 0 16px;
+      border: 1px solid var(--line);
+      background: #fff;
+      padding: 10px;
+    }
+    .archive-list { display: flex; gap: 8px; flex-wrap: wrap; }
+    .archive-link {
+      font-family: "Yu Gothic UI", sans-serif;
+      border: 1px solid var(--line);
+      padding: 4px 8px;
+      text-decoration: none;
+      color: var(--accent-2);
+      background: #f8f8f8;
+      font-size: 13px;
+    }
+    .entry {
+      border: 2px solid var(--line);
+      background: var(--panel);
+      margin-bottom: 16px;
+      padding: 12px;
+      box-shadow: 6px 6px 0 #d8d2bf;
+    }
+    .entry-header {
+      display: flex;
+      align-items: flex-start;
+      margin-bottom: 10px;
+    }
+    .entry-body {
+      display: grid;
+      grid-template-columns: minmax(0, 1fr) minmax(0, 1fr);
+      gap: 14px;
+      align-items: start;
+    }
+    .prompt-pane, .media-pane { min-width: 0; }
+    .media-pane {
+      display: flex;
+      flex-direction: column;
+      align-items: stretch;
+    }
+    .timestamp { font-weight: 700; color: var(--accent-2); }
+    .sample-badge {
+      margin-left: 8px;
+      border: 1px solid var(--accent);
+      color: var(--accent);
+      padding: 0 6px;
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+    }
+    .lock-btn {
+      margin-left: auto;
+      border: none;
+      background: transparent;
+      cursor: pointer;
+      font-size: 18px;
+      line-height: 1;
+      padding: 0 2px;
+    }
+    .entry.locked { border-color: var(--accent-2); }
+    .entry.locked .prompt-editor { background: #f0eee7; }
+    .entry.locked .overwrite-btn,
+    .entry.locked .delete-btn,
+    .entry.locked .upload,
+    .entry.locked .image-delete-btn { display: none; }
+    .tag-badge {
+      margin-left: 8px;
+      border: 1px solid var(--muted);
+      color: var(--muted);
+      padding: 0 6px;
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+    }
+    .count-badge,
+    .score-badge {
+      margin-left: 8px;
+      color: var(--accent-2);
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+    }
+    .rating { margin-left: 10px; white-space: nowrap; color: var(--accent); }
+    .rating .star {
+      border: none;
+      background: transparent;
+      padding: 0 1px;
+      font-size: 16px;
+      color: #c9c3ad;
+      cursor: pointer;
+    }
+    .rating .star.filled { color: var(--accent); }
+    .entry[hidden] { display: none; }
+    .search-panel {
+      margin: 0 0 16px;
+      border: 1px solid var(--line);
+      background: #fff;
+      padding: 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .search-fields, .smart-filters { display: flex; flex-wrap: wrap; gap: 6px; align-items: center; }
+    .smart-filters { margin-top: 8px; }
+    .search-query { flex: 1 1 200px; }
+    .search-count { color: var(--muted); }
+    .smart-filter { display: inline-flex; border: 1px solid var(--accent-2); }
+    .smart-filter button { border: 0; background: #fff; color: var(--accent-2); cursor: pointer; padding: 2px 8px; }
+    .smart-filter .smart-filter-delete { border-left: 1px solid var(--accent-2); padding: 2px 6px; }
+    .similar-upload, .vision-upload { cursor: pointer; }
+    .vision-result { margin-top: 10px; border-top: 1px dashed var(--line); padding-top: 8px; }
+    .vision-text { width: 100%; box-sizing: border-box; font-family: inherit; }
+    .vision-actions { display: flex; gap: 6px; margin-top: 4px; }
+    .similar-results { margin-top: 10px; border-top: 1px dashed var(--line); padding-top: 8px; }
+    .archive-search-results { margin-top: 10px; border-top: 1px dashed var(--line); padding-top: 8px; }
+    .archive-search-list { margin: 0; padding: 0; list-style: none; max-height: 320px; overflow-y: auto; }
+    .archive-search-list li { display: flex; gap: 8px; align-items: baseline; padding: 3px 0; border-bottom: 1px solid var(--line); }
+    .archive-search-list .archive-search-prompt { flex: 1; font-size: 12px; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }
+    .similar-results-head { display: flex; gap: 8px; align-items: center; margin-bottom: 6px; }
+    .similar-list { display: flex; flex-wrap: wrap; gap: 8px; }
+    .similar-item {
+      width: 160px;
+      border: 1px solid var(--line);
+      padding: 4px;
+      display: flex;
+      flex-direction: column;
+      gap: 4px;
+    }
+    .similar-item img { width: 100%; height: 100px; object-fit: contain; background: #f8f8f8; cursor: pointer; }
+    .similar-item .similar-prompt { font-size: 12px; max-height: 3.6em; overflow: hidden; }
+    .similar-item .similar-meta { color: var(--muted); font-size: 11px; }
+    .similar-item button { align-self: flex-start; }
+    .entry.similar-hit { outline: 2px solid var(--accent-2); }
+    .sort-bar {
+      margin: 0 0 12px;
+      display: flex;
+      align-items: center;
+      gap: 8px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .sort-btn { border: 1px solid var(--line); background: #fff; padding: 2px 10px; cursor: pointer; }
+    .page-nav {
+      margin: 0 0 12px;
+      display: flex;
+      flex-wrap: wrap;
+      gap: 6px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .page-nav a, .page-nav span { border: 1px solid var(--line); background: #fff; padding: 2px 10px; }
+    .page-nav .page-current { background: var(--accent-2); color: #fff; }
+    .load-more {
+      margin: 12px 0;
+      padding: 12px;
+      border: 1px dashed var(--line);
+      color: var(--muted);
+      text-align: center;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .sort-btn.is-active { background: var(--line); color: #fff; }
+    .archive-badge {
+      margin-left: 8px;
+      border: 1px solid var(--accent-2);
+      color: var(--accent-2);
+      padding: 0 6px;
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+    }
+    .entry.archive-next { border-style: dashed; }
+    .rotation-warning {
+      margin: 0 0 16px;
+      border: 1px solid var(--accent-2);
+      background: #e9f1f8;
+      padding: 8px 10px;
+      display: flex;
+      align-items: center;
+      gap: 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .exports {
+      margin: 0 0 16px;
+      display: flex;
+      gap: 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .export-link { color: var(--accent-2); }
+    .trash {
+      margin: 0 0 16px;
+      border: 1px solid var(--line);
+      background: #fff;
+      padding: 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .trash summary { cursor: pointer; }
+    .undo-toast {
+      position: fixed;
+      left: 50%;
+      bottom: 24px;
+      transform: translateX(-50%);
+      display: flex;
+      gap: 12px;
+      align-items: center;
+      padding: 8px 14px;
+      background: var(--text);
+      color: #fff;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+      box-shadow: 0 4px 12px rgba(0, 0, 0, 0.25);
+      z-index: 20;
+    }
+    .undo-toast[hidden] { display: none; }
+    .trash-list { list-style: none; margin: 8px 0 0; padding: 0; }
+    .trash-item { display: flex; gap: 10px; align-items: center; padding: 4px 0; border-top: 1px dashed var(--line); }
+    .trash-prompt { flex: 1; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }
+    .import-label { margin-left: auto; color: var(--accent-2); cursor: pointer; text-decoration: underline; }
+    .import-input { display: none; }
+    .restore-label { color: var(--accent-2); cursor: pointer; text-decoration: underline; }
+    .restore-backup-input { display: none; }
+    .samples {
+      margin: 0 0 16px;
+      display: flex;
+      align-items: center;
+      gap: 10px;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+    }
+    .btn {
+      border: 2px solid var(--line);
+      background: #fff;
+      color: var(--line);
+      padding: 6px 12px;
+      cursor: pointer;
+      font-weight: 700;
+    }
+    .btn:hover { background: #f4ede1; }
+    .btn:disabled {
+      cursor: not-allowed;
+      opacity: 0.55;
+      background: #f0eee7;
+    }
+    .btn.feedback-visible {
+      position: relative;
+      overflow: visible;
+    }
+    .btn.feedback-visible::after {
+      content: attr(data-feedback);
+      position: absolute;
+      left: 50%;
+      bottom: calc(100% + 10px);
+      transform: translateX(-50%);
+      background: #1f2a44;
+      color: #fff;
+      padding: 4px 8px;
+      border-radius: 4px;
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+      white-space: nowrap;
+      box-shadow: 0 2px 8px rgba(0, 0, 0, 0.22);
+      pointer-events: none;
+      z-index: 2;
+    }
+    .btn.feedback-visible::before {
+      content: "";
+      position: absolute;
+      left: 50%;
+      bottom: calc(100% + 4px);
+      width: 8px;
+      height: 8px;
+      transform: translateX(-50%) rotate(45deg);
+      background: #1f2a44;
+      pointer-events: none;
+      z-index: 1;
+    }
+    .overwrite-btn { border-color: var(--accent-2); color: var(--accent-2); }
+    .delete-btn { border-color: var(--accent); color: var(--accent); }
+    .prompt-toolbar {
+      display: flex;
+      gap: 8px;
+      margin-bottom: 8px;
+      flex-wrap: wrap;
+    }
+    .prompt-editor {
+      width: 100%;
+      border-left: 4px solid var(--line);
+      padding: 8px 10px;
+      background: #fbfaf5;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 14px;
+      line-height: 1.5;
+      min-height: 156px;
+      resize: vertical;
+      white-space: pre-wrap;
+      word-break: break-word;
+    }
+    .translation {
+      margin-top: 6px;
+      border-left: 4px solid var(--accent-2);
+      padding: 6px 10px;
+      background: #f4f7f9;
+    }
+    .translation-toolbar {
+      display: flex;
+      gap: 8px;
+      align-items: center;
+      justify-content: space-between;
+      font-size: 12px;
+      color: var(--muted);
+    }
+    .translation-text {
+      margin: 4px 0 0;
+      font-family: "Segoe UI", sans-serif;
+      font-size: 13px;
+      line-height: 1.4;
+      white-space: pre-wrap;
+      word-break: break-word;
+    }
+    .note-editor {
+      width: 100%;
+      margin-top: 6px;
+      border-left: 4px solid var(--muted);
+      padding: 6px 10px;
+      background: #fbfaf5;
+      font-family: "Yu Gothic UI", sans-serif;
+      font-size: 13px;
+      line-height: 1.4;
+      min-height: 48px;
+      resize: vertical;
+    }
+    .note-editor.saved { border-left-color: var(--accent-2); }
+    .entry.locked .note-editor { background: #f0eee7; }
+    .upload { margin-top: 0; }
+    .dropzone {
+      border: 2px dashed var(--line);
+      padding: 10px;
+      text-align: center;
+      cursor: pointer;
+      background: #fefcf3;
+      font-family: "Yu Gothic UI", sans-serif;
+      display: flex;
+      align-items: center;
+      justify-content: center;
+    }
+    .dropzone.needs-image { min-height: 96px; }
+    .dropzone.has-image { min-height: 0; }
+    .dropzone.dragover { background: #fff4d3; }
+    .dropzone:focus { outline: 2px solid var(--accent-2); outline-offset: -2px; }
+    .file-input { display: none; }
+    .images {
+      margin-top: 10px;
+      display: flex;
+      flex-wrap: wrap;
+      gap: 8px;
+      font-family: "Yu Gothic UI", sans-serif;
+    }
+    .image-item {
+      width: 100%;
+      display: flex;
+      flex-direction: column;
+      gap: 6px;
+    }
+    .images.gallery .image-item { width: calc(50% - 4px); }
+    .thumb-footer {
+      display: flex;
+      gap: 6px;
+      min-width: 0;
+    }
+    .thumb-footer .thumb-path { flex: 1 1 auto; min-width: 0; }
+    .image-similar-btn {
+      border: 1px solid var(--accent-2);
+      color: var(--accent-2);
+      background: #fff;
+      cursor: pointer;
+      padding: 0 6px;
+    }
+    .image-delete-btn {
+      border: 1px solid var(--accent);
+      color: var(--accent);
+      background: #fff;
+      cursor: pointer;
+      font-weight: 700;
+      padding: 0 8px;
+    }
+    .thumb-image-link {
+      display: block;
+      border: 1px solid var(--line);
+      background: #f8f8f8;
+      padding: 6px;
+      cursor: pointer;
+    }
+    .thumb-image {
+      display: block;
+      width: 100%;
+      max-height: 240px;
+      object-fit: contain;
+      background: #fff;
+      transition: filter 0.2s ease;
+    }
+    .thumb-image.is-placeholder {
+      height: 240px;
+      filter: blur(8px);
+    }
+    .thumb-path {
+      border: 1px solid var(--line);
+      padding: 4px 8px;
+      font-size: 12px;
+      text-decoration: none;
+      color: var(--accent-2);
+      background: #f8f8f8;
+      max-width: 100%;
+      overflow: hidden;
+      text-overflow: ellipsis;
+      white-space: nowrap;
+    }
+    .image-item.is-selected .thumb-image-link,
+    .image-item.is-selected .thumb-path {
+      border-color: var(--accent-2);
+      box-shadow: inset 0 0 0 1px var(--accent-2);
+    }
+    .image-meta {
+      margin-top: 4px;
+      font-size: 12px;
+    }
+    .image-meta summary {
+      cursor: pointer;
+      color: var(--muted);
+    }
+    .image-meta dl {
+      margin: 4px 0 0;
+    }
+    .image-meta dt {
+      font-weight: 600;
+    }
+    .image-meta dd {
+      margin: 0 0 4px;
+      white-space: pre-wrap;
+      word-break: break-word;
+    }
+    .image-copy-btn {
+      margin-top: 10px;
+      align-self: flex-start;
+      position: relative;
+      overflow: visible;
+    }
+    .image-copy-btn.copy-feedback::after {
+      content: "クリップボードにコピーしました";
+      position: absolute;
+      left: 50%;
+      bottom: calc(100% + 10px);
+      transform: translateX(-50%);
+      background: #1f2a44;
+      color: #fff;
+      padding: 4px 8px;
+      border-radius: 4px;
+      font-size: 12px;
+      font-family: "Yu Gothic UI", sans-serif;
+      white-space: nowrap;
+      box-shadow: 0 2px 8px rgba(0, 0, 0, 0.22);
+      pointer-events: none;
+      z-index: 2;
+    }
+    .image-copy-btn.copy-feedback::before {
+      content: "";
+      position: absolute;
+      left: 50%;
+      bottom: calc(100% + 4px);
+      width: 8px;
+      height: 8px;
+      transform: translateX(-50%) rotate(45deg);
+      background: #1f2a44;
+      pointer-events: none;
+      z-index: 1;
+    }
+    .muted { color: var(--muted); }
+    .empty { padding: 24px; border: 1px dashed var(--line); background: #fff; }
+    @media (max-width: 720px) {
+      h1 { font-size: 30px; }
+      .entry-body { grid-template-columns: minmax(0, 1fr); }
+      .prompt-editor { min-height: 0; }
+      .image-copy-btn { align-self: stretch; }
+    }
+  </style>
+"#;
+
+const INTERACTIVE_SCRIPT_TEMPLATE: &str = r#"
+  <script>
+    const PAGE_API_BASE = "__API_BASE__";
+    let API_BASE = PAGE_API_BASE;
+    function useApiBase(base) {
+      document.querySelectorAll("a.export-link").forEach((link) => {
+        link.href = link.getAttribute("href").replace(API_BASE, base);
+      });
+      API_BASE = base;
+    }
+    // Opened from the LAN (HTTPS) server, the page talks to that server rather
+    // than to the viewing machine's own loopback address.
+    if (location.protocol === "https:") {
+      useApiBase(location.origin);
+    }
+    // A page opened from disk was written for the port the app had then. When
+    // the app has moved to a nearby port since, that is found by asking each
+    // for the data folder it serves.
+    const STORE_ID = "__STORE_ID__";
+    const PORT_PROBE_RANGE = 16;
+    const PORT_PROBE_TIMEOUT_MS = 1500;
+    async function probeApiBase(base) {
+      try {
+        const res = await fetch(`${base}/ping`, { signal: AbortSignal.timeout(PORT_PROBE_TIMEOUT_MS) });
+        const data = await res.json();
+        return data.ok && data.store_id === STORE_ID;
+      } catch (_) {
+        return false;
+      }
+    }
+    async function discoverApiBase() {
+      if (location.protocol !== "file:" || await probeApiBase(API_BASE)) return;
+      const written = Number(new URL(PAGE_API_BASE).port);
+      const candidates = [];
+      for (let offset = -PORT_PROBE_RANGE; offset <= PORT_PROBE_RANGE; offset += 1) {
+        const port = written + offset;
+        if (offset !== 0 && port > 0 && port < 65536) candidates.push(`http://127.0.0.1:${port}`);
+      }
+      const found = await Promise.all(candidates.map(probeApiBase));
+      const index = found.indexOf(true);
+      if (index >= 0) useApiBase(candidates[index]);
+    }
+    const DELETE_IMAGE_FILES = __DELETE_IMAGE_FILES__;
+    const API_VERSION = __API_VERSION__;
+    const THUMB_WIDTH = __THUMB_WIDTH__;
+    // A thumbnail sized for the card at the screen's pixel density, which the
+    // app generates once and caches. `width` is the displayed CSS width.
+    function thumbnailUrl(imagePath, width) {
+      const pixels = Math.ceil((width || THUMB_WIDTH) * (window.devicePixelRatio || 1));
+      return `${API_BASE}/image/thumb?path=${encodeURIComponent(imagePath)}&w=${pixels}`;
+    }
+    window.thumbnailUrl = thumbnailUrl;
+    let apiVersionNoticeShown = false;
+    async function apiFetch(url, options = {}) {
+      if (historySocket && options.method === "POST" && typeof options.body === "string" && url.startsWith(API_BASE)) {
+        const reply = await socketRequest(url.slice(API_BASE.length), JSON.parse(options.body));
+        return new Response(JSON.stringify(reply.body), {
+          status: reply.status,
+          headers: { "Content-Type": "application/json" }
+        });
+      }
+      const headers = new Headers(options.headers || {});
+      headers.set("X-IPG-API-Version", String(API_VERSION));
+      const res = await fetch(url, { ...options, headers });
+      const served = Number(res.headers.get("X-IPG-API-Version"));
+      if (served && served !== API_VERSION) showApiVersionNotice();
+      return res;
+    }
+    function showApiVersionNotice() {
+      if (apiVersionNoticeShown) return;
+      apiVersionNoticeShown = true;
+      const notice = document.createElement("p");
+      notice.className = "runtime-note";
+      notice.textContent = "アプリが更新されています。最新の表示と機能を使うには、このページを再読み込みしてください。";
+      document.querySelector(".wrap").prepend(notice);
+    }
+    let lastHistoryRevision = null;
+    async function parseApiResponse(res, fallback) {
+      let data = {};
+      try {
+        data = await res.json();
+      } catch (_) {
+        data = {};
+      }
+      if (!res.ok || !data.ok) {
+        throw new Error(data.error || fallback);
+      }
+      return data;
+    }
+    // Hides the controls of subsystems switched off in [features]; opened
+    // while the app is closed, the page keeps them all.
+    async function applyFeatures() {
+      try {
+        const data = await parseApiResponse(await apiFetch(`${API_BASE}/app/features`), "");
+        document.querySelectorAll(".vision-upload").forEach((control) => {
+          control.hidden = !data.features.integrations;
+        });
+      } catch (_) {
+        // The app is not running.
+      }
+    }
+    // /ws pushes revisions, uploads and deletions as they happen and carries
+    // this page's JSON saves; it reconnects every few seconds while the app is
+    // closed, and saves fall back to fetch meanwhile.
+    const SOCKET_RETRY_MS = 3000;
+    let historySocket = null;
+    let socketRequestId = 0;
+    const socketRequests = new Map();
+    function listenHistoryEvents() {
+      const ws = new WebSocket(`${API_BASE.replace(/^http/, "ws")}/ws`);
+      ws.addEventListener("message", (message) => {
+        let event;
+        try {
+          event = JSON.parse(message.data);
+        } catch (_) {
+          return;
+        }
+        if (event.type === "response") {
+          const pending = socketRequests.get(event.id);
+          if (pending) {
+            socketRequests.delete(event.id);
+            pending.resolve(event);
+          }
+        } else if (event.type === "hello") {
+          historySocket = ws;
+          if (event.api_version !== API_VERSION) showApiVersionNotice();
+          applyHistoryEvent({ type: "revision", revision: event.revision });
+        } else {
+          applyHistoryEvent(event);
+        }
+      });
+      ws.addEventListener("close", () => {
+        if (historySocket === ws) {
+          historySocket = null;
+        }
+        for (const pending of socketRequests.values()) {
+          pending.reject(new Error("connection closed"));
+        }
+        socketRequests.clear();
+        setTimeout(() => discoverApiBase().then(listenHistoryEvents), SOCKET_RETRY_MS);
+      });
+    }
+    function socketRequest(path, body) {
+      socketRequestId += 1;
+      const id = socketRequestId;
+      return new Promise((resolve, reject) => {
+        socketRequests.set(id, { resolve, reject });
+        historySocket.send(JSON.stringify({ id, method: "POST", path, body, api_version: API_VERSION }));
+      });
+    }
+    function applyHistoryEvent(event) {
+      if (event.type === "revision") {
+        if (lastHistoryRevision !== null && event.revision !== lastHistoryRevision) {
+          location.reload();
+          return;
+        }
+        lastHistoryRevision = event.revision;
+        return;
+      }
+      const entry = document.querySelector(`.entry[data-history-id="${CSS.escape(event.history_id || "")}"]`);
+      if (!entry) {
+        return;
+      }
+      if (event.type === "delete") {
+        entry.remove();
+        return;
+      }
+      const findItem = (imagePath) => Array.from(entry.querySelectorAll(".image-item"))
+        .find((el) => (el.dataset.imagePath || "") === imagePath);
+      if (event.type === "upload") {
+        for (const image of event.images || []) {
+          if (!findItem(image.image_path)) {
+            renderUploadedImage(entry, image.image_path, image.metadata || null);
+          }
+        }
+      } else if (event.type === "image_delete") {
+        const item = findItem(event.image_path);
+        if (item) {
+          item.remove();
+          syncImagesState(entry);
+        }
+      }
+    }
+    function getPromptValue(entry) {
+      const editor = entry.querySelector(".prompt-editor");
+      return editor ? editor.value : "";
+    }
+    async function copyPrompt(entry) {
+      const prompt = getPromptValue(entry);
+      await navigator.clipboard.writeText(prompt);
+    }
+    async function overwritePrompt(historyId, prompt) {
+      const res = await apiFetch(`${API_BASE}/update`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId, prompt })
+      });
+      return parseApiResponse(res, "update failed");
+    }
+    async function deleteEntry(historyId) {
+      if (!confirm("プロンプトをゴミ箱に移動しますか？（画像は削除されません）")) {
+        return;
+      }
+      const res = await apiFetch(`${API_BASE}/delete`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId })
+      });
+      await parseApiResponse(res, "delete failed");
+      sessionStorage.setItem(UNDO_TOAST_KEY, "ゴミ箱に移動しました");
+      location.reload();
+    }
+    async function deleteSamples() {
+      if (!confirm("サンプル履歴・サンプル画像・サンプルプリセットを削除しますか？")) {
+        return;
+      }
+      const res = await apiFetch(`${API_BASE}/delete-samples`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: "{}"
+      });
+      await parseApiResponse(res, "delete samples failed");
+      location.reload();
+    }
+    async function archivePending() {
+      const res = await apiFetch(`${API_BASE}/archive-now`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: "{}"
+      });
+      await parseApiResponse(res, "archive failed");
+      location.reload();
+    }
+    async function importHistory(file) {
+      const formData = new FormData();
+      formData.append("file", file);
+      const res = await apiFetch(`${API_BASE}/history/import`, {
+        method: "POST",
+        body: formData
+      });
+      return parseApiResponse(res, "import failed");
+    }
+    async function restoreBackup(file) {
+      const formData = new FormData();
+      formData.append("file", file);
+      const res = await apiFetch(`${API_BASE}/history/restore-backup`, {
+        method: "POST",
+        body: formData
+      });
+      return parseApiResponse(res, "restore failed");
+    }
+    async function rateEntry(historyId, rating) {
+      const res = await apiFetch(`${API_BASE}/history/rate`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId, rating })
+      });
+      return parseApiResponse(res, "rate failed");
+    }
+    function syncRating(entry, rating) {
+      entry.dataset.rating = String(rating);
+      for (const star of entry.querySelectorAll(".rating .star")) {
+        star.classList.toggle("filled", Number(star.dataset.value) <= rating);
+      }
+    }
+    const LIST_PAGE_SIZE = __LIST_PAGE_SIZE__;
+    let loadingEntries = null;
+    // Appends the next cards after `.load-more`'s offset from GET /history/list;
+    // resolves to false once nothing is left or the app is not running.
+    function loadMoreEntries() {
+      if (!loadingEntries) {
+        loadingEntries = fetchEntryPage().finally(() => {
+          loadingEntries = null;
+        });
+      }
+      return loadingEntries;
+    }
+    async function fetchEntryPage() {
+      const marker = document.querySelector(".load-more");
+      const container = document.querySelector(".entries");
+      if (!marker || !container) return false;
+      const offset = Number(marker.dataset.offset || 0);
+      let data;
+      try {
+        const res = await apiFetch(`${API_BASE}/history/list?offset=${offset}&limit=${LIST_PAGE_SIZE}`);
+        data = await parseApiResponse(res, "history list failed");
+      } catch (_) {
+        marker.textContent = `残り ${Number(marker.dataset.total || 0) - offset} 件はアプリ起動中のみ読み込めます`;
+        return false;
+      }
+      const template = document.createElement("template");
+      template.innerHTML = data.entries.map((item) => item.html).join("\n");
+      for (const card of Array.from(template.content.querySelectorAll(".entry"))) {
+        if (container.querySelector(`.entry[data-history-id="${CSS.escape(card.dataset.historyId)}"]`)) continue;
+        container.appendChild(card);
+        bindEntry(card);
+        if (window.observeLazyImages) window.observeLazyImages(card);
+      }
+      const next = offset + data.entries.length;
+      if (data.entries.length === 0 || next >= data.total) {
+        marker.remove();
+      } else {
+        marker.dataset.offset = String(next);
+        marker.dataset.total = String(data.total);
+        marker.textContent = `残り ${data.total - next} 件`;
+      }
+      if (localStorage.getItem("historySort") === "rating") sortEntries("rating");
+      if (document.querySelector(".search-panel")) applySearchFilter();
+      return marker.isConnected;
+    }
+    async function loadAllEntries() {
+      while (await loadMoreEntries()) {
+        // Keep going until every page is on screen.
+      }
+    }
+    function sortEntries(mode) {
+      const container = document.querySelector(".entries");
+      if (!container) {
+        return;
+      }
+      const cards = Array.from(container.querySelectorAll(":scope > .entry"));
+      cards.sort((a, b) => {
+        if (mode === "rating") {
+          const diff = Number(b.dataset.rating || 0) - Number(a.dataset.rating || 0);
+          if (diff !== 0) {
+            return diff;
+          }
+        }
+        return b.dataset.historyId.localeCompare(a.dataset.historyId);
+      });
+      for (const card of cards) {
+        container.appendChild(card);
+      }
+      for (const btn of document.querySelectorAll(".sort-btn")) {
+        btn.classList.toggle("is-active", btn.dataset.sort === mode);
+      }
+      localStorage.setItem("historySort", mode);
+    }
+    async function applyToMainUi(historyId) {
+      const res = await apiFetch(`${API_BASE}/app/apply-history`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId })
+      });
+      return parseApiResponse(res, "apply failed");
+    }
+    async function saveNote(historyId, note) {
+      const res = await apiFetch(`${API_BASE}/update-note`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId, note })
+      });
+      return parseApiResponse(res, "note update failed");
+    }
+    const SEARCH_FILTER_KEY = "historySearchFilter";
+    function readSearchFilter() {
+      const panel = document.querySelector(".search-panel");
+      const image = panel.querySelector(".search-image").value;
+      return {
+        name: "",
+        query: panel.querySelector(".search-query").value.trim(),
+        tags: panel.querySelector(".search-tags").value.split(",").map((tag) => tag.trim()).filter(Boolean),
+        min_rating: Number(panel.querySelector(".search-rating").value) || 0,
+        from: panel.querySelector(".search-from").value || null,
+        to: panel.querySelector(".search-to").value || null,
+        has_image: image === "" ? null : image === "true"
+      };
+    }
+    function writeSearchFilter(filter) {
+      const panel = document.querySelector(".search-panel");
+      panel.querySelector(".search-query").value = filter.query || "";
+      panel.querySelector(".search-tags").value = (filter.tags || []).join(", ");
+      panel.querySelector(".search-rating").value = String(filter.min_rating || 0);
+      panel.querySelector(".search-from").value = filter.from || "";
+      panel.querySelector(".search-to").value = filter.to || "";
+      const hasImage = filter.has_image;
+      panel.querySelector(".search-image").value = hasImage === true || hasImage === false ? String(hasImage) : "";
+    }
+    // Mirrors text_normalize::fold: full-width ASCII to half-width, katakana
+    // to hiragana, then lower case.
+    function foldSearchText(text) {
+      return Array.from(text, (ch) => {
+        const code = ch.codePointAt(0);
+        if (code >= 0xFF01 && code <= 0xFF5E) return String.fromCodePoint(code - 0xFEE0);
+        if (code === 0x3000) return " ";
+        if (code >= 0x30A1 && code <= 0x30F6) return String.fromCodePoint(code - 0x60);
+        return ch;
+      }).join("").toLowerCase();
+    }
+    // Mirrors SmartFilter::matches in smart_filters.rs; romaji spellings are
+    // matched by アーカイブも検索, which asks the server.
+    function entryMatchesFilter(entry, filter) {
+      if (filter.query) {
+        const needle = foldSearchText(filter.query);
+        const prompt = foldSearchText(entry.querySelector(".prompt-editor")?.value || "");
+        const note = foldSearchText(entry.querySelector(".note-editor")?.value || "");
+        if (!prompt.includes(needle) && !note.includes(needle)) return false;
+      }
+      const tags = JSON.parse(entry.dataset.tags || "[]");
+      if (!(filter.tags || []).every((tag) => tags.includes(tag))) return false;
+      if (Number(entry.dataset.rating || 0) < (filter.min_rating || 0)) return false;
+      const day = (entry.dataset.ts || "").slice(0, 10);
+      if (filter.from && day < filter.from) return false;
+      if (filter.to && day > filter.to) return false;
+      if (filter.has_image === true || filter.has_image === false) {
+        if ((entry.dataset.hasImage === "true") !== filter.has_image) return false;
+      }
+      return true;
+    }
+    function applySearchFilter() {
+      const filter = readSearchFilter();
+      sessionStorage.setItem(SEARCH_FILTER_KEY, JSON.stringify(filter));
+      const entries = document.querySelectorAll(".entry");
+      let shown = 0;
+      for (const entry of entries) {
+        entry.hidden = !entryMatchesFilter(entry, filter);
+        if (!entry.hidden) shown += 1;
+      }
+      const count = document.querySelector(".search-count");
+      count.textContent = shown === entries.length ? "" : `${shown} / ${entries.length} 件`;
+      // Filtering covers the whole active history, not just the loaded cards.
+      const active = filter.query || filter.tags.length || filter.min_rating || filter.from || filter.to || filter.has_image !== null;
+      if (active && document.querySelector(".load-more")) loadAllEntries();
+    }
+    async function saveSmartFilter(filter) {
+      const res = await apiFetch(`${API_BASE}/history/smart-filters`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(filter)
+      });
+      return parseApiResponse(res, "smart filter save failed");
+    }
+    async function deleteSmartFilter(name) {
+      const res = await apiFetch(`${API_BASE}/history/smart-filters`, {
+        method: "DELETE",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ name })
+      });
+      return parseApiResponse(res, "smart filter delete failed");
+    }
+    const UNDO_TOAST_KEY = "historyUndoToast";
+    const UNDO_TOAST_MS = 8000;
+    let undoToastTimer = null;
+    function showUndoToast(message) {
+      sessionStorage.setItem(UNDO_TOAST_KEY, message);
+      let toast = document.querySelector(".undo-toast");
+      if (!toast) {
+        toast = document.createElement("div");
+        toast.className = "undo-toast";
+        toast.setAttribute("role", "status");
+        const label = document.createElement("span");
+        label.className = "undo-label";
+        const undoBtn = document.createElement("button");
+        undoBtn.className = "btn undo-btn";
+        undoBtn.textContent = "元に戻す";
+        undoBtn.addEventListener("click", async () => {
+          try {
+            await undoLastOperation();
+          } catch (err) {
+            alert(`元に戻せませんでした: ${err.message}`);
+          }
+        });
+        toast.append(label, undoBtn);
+        document.body.appendChild(toast);
+      }
+      toast.querySelector(".undo-label").textContent = message;
+      toast.hidden = false;
+      if (undoToastTimer) {
+        clearTimeout(undoToastTimer);
+      }
+      undoToastTimer = setTimeout(() => {
+        toast.hidden = true;
+        sessionStorage.removeItem(UNDO_TOAST_KEY);
+        undoToastTimer = null;
+      }, UNDO_TOAST_MS);
+    }
+    async function undoLastOperation() {
+      const res = await apiFetch(`${API_BASE}/history/undo`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: "{}"
+      });
+      sessionStorage.removeItem(UNDO_TOAST_KEY);
+      await parseApiResponse(res, "undo failed");
+      location.reload();
+    }
+    async function moveEntry(action, historyId) {
+      const res = await apiFetch(`${API_BASE}/${action}`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId })
+      });
+      await parseApiResponse(res, `${action} failed`);
+    }
+    async function setEntryLocked(historyId, locked) {
+      const res = await apiFetch(`${API_BASE}/lock`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: historyId, locked })
+      });
+      return parseApiResponse(res, "lock failed");
+    }
+    function syncLockState(entry, locked) {
+      entry.dataset.locked = locked ? "true" : "false";
+      entry.classList.toggle("locked", locked);
+      const editor = entry.querySelector(".prompt-editor");
+      if (editor) {
+        editor.readOnly = locked;
+      }
+      const noteEditor = entry.querySelector(".note-editor");
+      if (noteEditor) {
+        noteEditor.readOnly = locked;
+      }
+      const lockBtn = entry.querySelector(".lock-btn");
+      if (lockBtn) {
+        lockBtn.textContent = locked ? "🔒" : "🔓";
+        lockBtn.title = locked ? "ロック中（クリックで解除）" : "クリックでロック";
+      }
+    }
+    async function uploadFiles(historyId, files) {
+      const form = new FormData();
+      form.append("history_id", historyId);
+      for (const file of files) {
+        form.append("file", file);
+      }
+      const res = await apiFetch(`${API_BASE}/upload`, {
+        method: "POST",
+        body: form
+      });
+      return parseApiResponse(res, "upload failed");
+    }
+    async function readScreenshot(file) {
+      const form = new FormData();
+      form.append("file", file);
+      const res = await apiFetch(`${API_BASE}/history/vision`, { method: "POST", body: form });
+      if (res.status === 501) {
+        throw new Error("config.txt の [app] に vision_command を設定してください");
+      }
+      const data = await parseApiResponse(res, "vision command failed");
+      return data.text || "";
+    }
+    async function appendHistory(prompt) {
+      const res = await apiFetch(`${API_BASE}/history/append`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ prompt })
+      });
+      const data = await parseApiResponse(res, "append failed");
+      return data.entry;
+    }
+    async function rerenderHistory(outputFormat, format) {
+      const filter = document.querySelector(".search-panel") ? readSearchFilter() : {};
+      const narrowed = Boolean(filter.query) || (filter.tags || []).length > 0 || filter.min_rating > 0
+        || filter.has_image === true || filter.has_image === false;
+      const historyIds = narrowed
+        ? Array.from(document.querySelectorAll(".entry:not([hidden])"), (entry) => entry.dataset.historyId)
+        : [];
+      const res = await apiFetch(`${API_BASE}/history/rerender`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+          output_format: outputFormat,
+          format,
+          from: filter.from || null,
+          to: filter.to || null,
+          history_ids: historyIds
+        })
+      });
+      return parseApiResponse(res, "rerender failed");
+    }
+    function downloadText(filename, content, type) {
+      const url = URL.createObjectURL(new Blob([content], { type }));
+      const link = document.createElement("a");
+      link.href = url;
+      link.download = filename;
+      document.body.appendChild(link);
+      link.click();
+      link.remove();
+      setTimeout(() => URL.revokeObjectURL(url), 1000);
+    }
+    let hoveredDropzone = null;
+    async function uploadClipboard(historyId, blob) {
+      const res = await apiFetch(`${API_BASE}/upload-clipboard?history_id=${encodeURIComponent(historyId)}`, {
+        method: "POST",
+        headers: { "Content-Type": "application/octet-stream" },
+        body: blob || new Blob([])
+      });
+      return parseApiResponse(res, "upload failed");
+    }
+    async function searchAllHistory() {
+      const filter = readSearchFilter();
+      const params = new URLSearchParams({ q: filter.query });
+      if (filter.from) params.set("from", filter.from);
+      if (filter.to) params.set("to", filter.to);
+      const res = await apiFetch(`${API_BASE}/history?${params}`);
+      const data = await parseApiResponse(res, "history search failed");
+      renderArchiveSearchResults(data.hits || [], data.total || 0);
+    }
+    function renderArchiveSearchResults(entries, total) {
+      const panel = document.querySelector(".archive-search-results");
+      if (!panel) return;
+      panel.replaceChildren();
+      panel.hidden = false;
+      const head = document.createElement("div");
+      head.className = "similar-results-head";
+      const title = document.createElement("strong");
+      title.textContent = total > entries.length
+        ? `アーカイブを含む検索結果: ${total} 件（新しい ${entries.length} 件を表示）`
+        : `アーカイブを含む検索結果: ${total} 件`;
+      const closeBtn = document.createElement("button");
+      closeBtn.className = "btn";
+      closeBtn.textContent = "閉じる";
+      closeBtn.addEventListener("click", () => {
+        panel.hidden = true;
+        panel.replaceChildren();
+      });
+      head.append(title, closeBtn);
+      panel.appendChild(head);
+      if (entries.length === 0) {
+        const empty = document.createElement("span");
+        empty.className = "muted";
+        empty.textContent = "一致する履歴はありません。";
+        panel.appendChild(empty);
+        return;
+      }
+      const list = document.createElement("ul");
+      list.className = "archive-search-list";
+      for (const entry of entries) {
+        const item = document.createElement("li");
+        const ts = document.createElement("span");
+        ts.className = "timestamp";
+        ts.textContent = entry.ts;
+        const promptText = document.createElement("span");
+        promptText.className = "archive-search-prompt";
+        promptText.textContent = entry.prompt;
+        promptText.title = entry.prompt;
+        const card = document.querySelector(`.entry[data-history-id="${CSS.escape(entry.id)}"]`);
+        const link = document.createElement("a");
+        link.className = "export-link";
+        link.href = entry.page;
+        link.textContent = card ? "表示" : entry.page;
+        link.addEventListener("click", (event) => {
+          if (!card) return;
+          event.preventDefault();
+          card.hidden = false;
+          card.scrollIntoView({ behavior: "smooth", block: "center" });
+          card.classList.add("similar-hit");
+          setTimeout(() => card.classList.remove("similar-hit"), 2000);
+        });
+        item.append(ts, promptText, link);
+        list.appendChild(item);
+      }
+      panel.appendChild(list);
+    }
+    async function findSimilarImages(imagePath, file) {
+      let res;
+      if (file) {
+        const form = new FormData();
+        form.append("file", file);
+        res = await apiFetch(`${API_BASE}/history/similar`, { method: "POST", body: form });
+      } else {
+        res = await apiFetch(`${API_BASE}/history/similar?path=${encodeURIComponent(imagePath)}`);
+      }
+      const data = await parseApiResponse(res, "similar image search failed");
+      renderSimilarResults(data.results || [], file ? file.name : imagePath);
+    }
+    function renderSimilarResults(results, source) {
+      const panel = document.querySelector(".similar-results");
+      if (!panel) return;
+      panel.replaceChildren();
+      panel.hidden = false;
+      const head = document.createElement("div");
+      head.className = "similar-results-head";
+      const title = document.createElement("strong");
+      title.textContent = `似た画像: ${source}（${results.length} 件）`;
+      const closeBtn = document.createElement("button");
+      closeBtn.className = "btn";
+      closeBtn.textContent = "閉じる";
+      closeBtn.addEventListener("click", () => {
+        panel.hidden = true;
+        panel.replaceChildren();
+      });
+      head.append(title, closeBtn);
+      panel.appendChild(head);
+      if (results.length === 0) {
+        const empty = document.createElement("span");
+        empty.className = "muted";
+        empty.textContent = "似た画像は見つかりませんでした。";
+        panel.appendChild(empty);
+        return;
+      }
+      const list = document.createElement("div");
+      list.className = "similar-list";
+      for (const result of results) {
+        const item = document.createElement("div");
+        item.className = "similar-item";
+        const img = document.createElement("img");
+        img.src = result.image_path;
+        img.alt = "similar image";
+        img.loading = "lazy";
+        const card = document.querySelector(`.entry[data-history-id="${CSS.escape(result.history_id)}"]`);
+        img.title = card ? "この履歴へ移動" : "アーカイブ内の履歴です";
+        img.addEventListener("click", () => {
+          if (!card) {
+            window.open(result.image_path, "_blank", "noopener");
+            return;
+          }
+          card.hidden = false;
+          card.scrollIntoView({ behavior: "smooth", block: "center" });
+          card.classList.add("similar-hit");
+          setTimeout(() => card.classList.remove("similar-hit"), 2000);
+        });
+        const prompt = document.createElement("div");
+        prompt.className = "similar-prompt";
+        prompt.textContent = result.prompt;
+        const meta = document.createElement("div");
+        meta.className = "similar-meta";
+        meta.textContent = `${result.ts}・距離 ${result.distance}${card ? "" : "・アーカイブ"}`;
+        const copyBtn = document.createElement("button");
+        copyBtn.className = "btn";
+        copyBtn.textContent = "プロンプトをコピー";
+        copyBtn.addEventListener("click", async () => {
+          await navigator.clipboard.writeText(result.prompt);
+          showImageCopyFeedback(copyBtn);
+        });
+        item.append(img, prompt, meta, copyBtn);
+        list.appendChild(item);
+      }
+      panel.appendChild(list);
+      panel.scrollIntoView({ behavior: "smooth", block: "nearest" });
+    }
+    async function fetchImageBlob(imagePath) {
+      const imageUrl = `${API_BASE}/image?path=${encodeURIComponent(imagePath)}`;
+      let res;
+      try {
+        res = await apiFetch(imageUrl, { cache: "no-store" });
+      } catch (_) {
+        throw new Error("アプリが起動していない可能性があります");
+      }
+      if (!res.ok) {
+        let message = "画像を取得できませんでした";
+        try {
+          const data = await res.json();
+          if (data && typeof data.error === "string" && data.error.trim() !== "") {
+            message = data.error;
+          }
+        } catch (_) {}
+        throw new Error(message);
+      }
+      return res.blob();
+    }
+    async function imageBlobFromApiBlob(sourceBlob) {
+      return new Promise((resolve, reject) => {
+        const objectUrl = URL.createObjectURL(sourceBlob);
+        const image = new Image();
+        image.onload = () => {
+          URL.revokeObjectURL(objectUrl);
+          const width = image.naturalWidth || image.width;
+          const height = image.naturalHeight || image.height;
+          if (!width || !height) {
+            reject(new Error("画像サイズを取得できませんでした"));
+            return;
+          }
+          const canvas = document.createElement("canvas");
+          canvas.width = width;
+          canvas.height = height;
+          const ctx = canvas.getContext("2d");
+          if (!ctx) {
+            reject(new Error("画像変換に失敗しました"));
+            return;
+          }
+          ctx.drawImage(image, 0, 0);
+          canvas.toBlob((blob) => {
+            if (!blob) {
+              reject(new Error("画像変換に失敗しました"));
+              return;
+            }
+            resolve(blob);
+          }, "image/png");
+        };
+        image.onerror = () => {
+          URL.revokeObjectURL(objectUrl);
+          reject(new Error("画像を取得できませんでした"));
+        };
+        image.src = objectUrl;
+      });
+    }
+    async function copyImageToClipboard(imagePath) {
+      if (
+        !navigator.clipboard ||
+        typeof navigator.clipboard.write !== "function" ||
+        typeof ClipboardItem === "undefined"
+      ) {
+        throw new Error("このブラウザは画像コピーに対応していません");
+      }
+      const sourceBlob = await fetchImageBlob(imagePath);
+      const pngBlob = await imageBlobFromApiBlob(sourceBlob);
+      await navigator.clipboard.write([new ClipboardItem({ "image/png": pngBlob })]);
+    }
+    function showImageCopyFeedback(button) {
+      if (!button) return;
+      button.classList.remove("copy-feedback");
+      if (button._copyFeedbackTimer) {
+        clearTimeout(button._copyFeedbackTimer);
+      }
+      void button.offsetWidth;
+      button.classList.add("copy-feedback");
+      button._copyFeedbackTimer = setTimeout(() => {
+        button.classList.remove("copy-feedback");
+        button._copyFeedbackTimer = null;
+      }, 1400);
+    }
+    function showButtonFeedback(button, message) {
+      if (!button || !message) return;
+      button.dataset.feedback = message;
+      button.classList.remove("feedback-visible");
+      if (button._buttonFeedbackTimer) {
+        clearTimeout(button._buttonFeedbackTimer);
+      }
+      void button.offsetWidth;
+      button.classList.add("feedback-visible");
+      button._buttonFeedbackTimer = setTimeout(() => {
+        button.classList.remove("feedback-visible");
+        button.dataset.feedback = "";
+        button._buttonFeedbackTimer = null;
+      }, 1400);
+    }
+    function uploadLabel(imageCount, maxImages) {
+      if (imageCount === 0) {
+        return "画像追加: ドラッグ&ドロップ・クリック・Ctrl+V";
+      }
+      if (imageCount >= maxImages) {
+        return `画像 ${imageCount}/${maxImages}（追加すると最も古い画像と差し替え）`;
+      }
+      return `画像 ${imageCount}/${maxImages}（追加はD＆Dまたはクリック）`;
+    }
+    function syncUploadLabel(entry) {
+      const upload = entry.querySelector(".upload");
+      if (!upload) return;
+      const dropzone = upload.querySelector(".dropzone");
+      if (!dropzone) return;
+      const hasImage = entry.dataset.hasImage === "true";
+      const imageCount = entry.querySelectorAll(".image-item").length;
+      const maxImages = Number(entry.dataset.maxImages) || 1;
+      dropzone.classList.toggle("has-image", hasImage);
+      dropzone.classList.toggle("needs-image", !hasImage);
+      dropzone.textContent = uploadLabel(imageCount, maxImages);
+    }
+    function setSelectedImage(entry, imagePath) {
+      entry.dataset.selectedImage = imagePath || "";
+      for (const item of entry.querySelectorAll(".image-item")) {
+        item.classList.toggle("is-selected", (item.dataset.imagePath || "") === entry.dataset.selectedImage);
+      }
+      const imageCopyBtn = entry.querySelector(".image-copy-btn");
+      if (imageCopyBtn) {
+        imageCopyBtn.disabled = !entry.dataset.selectedImage;
+      }
+    }
+    const METADATA_SOURCES = { a1111: "Stable Diffusion web UI", comfyui: "ComfyUI" };
+    function buildImageMetadata(metadata) {
+      const details = document.createElement("details");
+      details.className = "image-meta";
+      const summary = document.createElement("summary");
+      summary.textContent = "生成情報（" + (METADATA_SOURCES[metadata.source] || metadata.source) + "）";
+      const list = document.createElement("dl");
+      for (const [label, value] of [["プロンプト", metadata.prompt], ["ネガティブ", metadata.negative_prompt], ["設定", metadata.settings]]) {
+        if (!value) continue;
+        const term = document.createElement("dt");
+        term.textContent = label;
+        const text = document.createElement("dd");
+        text.textContent = value;
+        list.append(term, text);
+      }
+      details.append(summary, list);
+      return details;
+    }
+    function buildImageItem(imagePath, metadata) {
+      const wrapper = document.createElement("div");
+      wrapper.className = "image-item";
+      wrapper.dataset.imagePath = imagePath;
+
+      const imageLink = document.createElement("a");
+      imageLink.className = "thumb-image-link";
+      imageLink.href = imagePath;
+      imageLink.target = "_blank";
+      imageLink.rel = "noopener noreferrer";
+
+      const img = document.createElement("img");
+      img.className = "thumb-image";
+      img.addEventListener("error", () => { img.src = imagePath; }, { once: true });
+      img.src = thumbnailUrl(imagePath);
+      img.alt = "history image";
+      img.loading = "lazy";
+      imageLink.appendChild(img);
+
+      const pathLink = document.createElement("a");
+      pathLink.className = "thumb-path";
+      pathLink.href = imagePath;
+      pathLink.target = "_blank";
+      pathLink.rel = "noopener noreferrer";
+      pathLink.textContent = imagePath;
+
+      const similarBtn = document.createElement("button");
+      similarBtn.className = "image-similar-btn";
+      similarBtn.title = "似た画像を探す";
+      similarBtn.textContent = "似";
+
+      const deleteBtn = document.createElement("button");
+      deleteBtn.className = "image-delete-btn";
+      deleteBtn.title = "この画像を削除";
+      deleteBtn.textContent = "×";
+
+      const footer = document.createElement("div");
+      footer.className = "thumb-footer";
+      footer.appendChild(pathLink);
+      footer.appendChild(similarBtn);
+      footer.appendChild(deleteBtn);
+
+      wrapper.appendChild(imageLink);
+      wrapper.appendChild(footer);
+      if (metadata) {
+        wrapper.appendChild(buildImageMetadata(metadata));
+      }
+      return wrapper;
+    }
+    function syncImagesState(entry) {
+      const images = entry.querySelector(".images");
+      const items = images.querySelectorAll(".image-item");
+      for (const muted of images.querySelectorAll(".muted")) {
+        muted.remove();
+      }
+      if (items.length === 0) {
+        const muted = document.createElement("span");
+        muted.className = "muted";
+        muted.textContent = "画像なし";
+        images.appendChild(muted);
+      }
+      images.classList.toggle("gallery", items.length > 1);
+      entry.dataset.hasImage = items.length > 0 ? "true" : "false";
+      const paths = Array.from(items, (item) => item.dataset.imagePath || "");
+      if (!paths.includes(entry.dataset.selectedImage || "")) {
+        entry.dataset.selectedImage = paths.length > 0 ? paths[paths.length - 1] : "";
+      }
+      syncUploadLabel(entry);
+      setSelectedImage(entry, entry.dataset.selectedImage || "");
+    }
+    // Mutation responses carry the changed card as the page renders it, so the
+    // page swaps in that one card; without it, the caller patches the old one.
+    function swapCard(entry, card) {
+      if (!card || !card.html) return null;
+      const template = document.createElement("template");
+      template.innerHTML = card.html;
+      const next = template.content.querySelector(".entry");
+      if (!next) return null;
+      entry.replaceWith(next);
+      bindEntry(next);
+      if (window.observeLazyImages) window.observeLazyImages(next);
+      if (document.querySelector(".search-panel")) applySearchFilter();
+      return next;
+    }
+    function renderUploadedImage(entry, imagePath, metadata) {
+      if (!imagePath) return;
+      const images = entry.querySelector(".images");
+      images.appendChild(buildImageItem(imagePath, metadata));
+      const maxImages = Number(entry.dataset.maxImages) || 1;
+      const items = images.querySelectorAll(".image-item");
+      for (let i = 0; i < items.length - maxImages; i += 1) {
+        items[i].remove();
+      }
+      entry.dataset.selectedImage = imagePath;
+      syncImagesState(entry);
+    }
+    async function deleteImage(entry, imagePath) {
+      const question = DELETE_IMAGE_FILES
+        ? "この画像を削除しますか？（他の履歴で使われていなければ画像ファイルも削除され、元に戻せません）"
+        : "この画像を履歴から外しますか？（画像ファイルは削除されません）";
+      if (!confirm(question)) {
+        return;
+      }
+      const res = await apiFetch(`${API_BASE}/image/delete`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ history_id: entry.dataset.historyId, image_path: imagePath })
+      });
+      const data = await parseApiResponse(res, "image delete failed");
+      if (!data.file_deleted) {
+        showUndoToast("画像を外しました");
+      }
+      if (swapCard(entry, data.card)) {
+        return;
+      }
+      for (const item of entry.querySelectorAll(".image-item")) {
+        if ((item.dataset.imagePath || "") === imagePath) {
+          item.remove();
+        }
+      }
+      syncImagesState(entry);
+    }
+    const deleteSamplesBtn = document.querySelector(".delete-samples-btn");
+    if (deleteSamplesBtn) {
+      deleteSamplesBtn.addEventListener("click", async () => {
+        try {
+          await deleteSamples();
+        } catch (err) {
+          alert(`サンプル削除失敗: ${err.message}`);
+        }
+      });
+    }
+    for (const sortBtn of document.querySelectorAll(".sort-btn")) {
+      sortBtn.addEventListener("click", async () => {
+        sortEntries(sortBtn.dataset.sort);
+        if (sortBtn.dataset.sort === "rating") await loadAllEntries();
+      });
+    }
+    if (localStorage.getItem("historySort") === "rating") {
+      sortEntries("rating");
+      loadAllEntries();
+    }
+    const importInput = document.querySelector(".import-input");
+    if (importInput) {
+      importInput.addEventListener("change", async () => {
+        const file = importInput.files && importInput.files[0];
+        importInput.value = "";
+        if (!file) {
+          return;
+        }
+        try {
+          const data = await importHistory(file);
+          alert(`読み込み完了: ${data.imported}件（重複スキップ ${data.duplicates}件 / ID変更 ${data.renamed}件）\nアーカイブに追加しました。`);
+          location.reload();
+        } catch (err) {
+          alert(`読み込み失敗: ${err.message}`);
+        }
+      });
+    }
+    const restoreBackupInput = document.querySelector(".restore-backup-input");
+    if (restoreBackupInput) {
+      restoreBackupInput.addEventListener("change", async () => {
+        const file = restoreBackupInput.files && restoreBackupInput.files[0];
+        restoreBackupInput.value = "";
+        if (!file) {
+          return;
+        }
+        if (!confirm(`${file.name} から履歴を復元しますか？\n同じIDの履歴と既存の画像はそのまま残します。`)) {
+          return;
+        }
+        try {
+          const data = await restoreBackup(file);
+          alert(`復元完了: ${data.restored}件（既存IDのためスキップ ${data.duplicates}件 / 画像 ${data.images}件）`);
+          location.reload();
+        } catch (err) {
+          alert(`復元失敗: ${err.message}`);
+        }
+      });
+    }
+    for (const rerenderBtn of document.querySelectorAll(".rerender-btn")) {
+      rerenderBtn.addEventListener("click", async () => {
+        const outputFormat = prompt("再出力する出力形式（config.txt の formats の名前）", "");
+        if (!outputFormat || !outputFormat.trim()) {
+          return;
+        }
+        try {
+          const format = rerenderBtn.dataset.format;
+          const data = await rerenderHistory(outputFormat.trim(), format);
+          if (data.rendered === 0) {
+            alert(`再出力できる履歴がありません（選択内容が保存されていない履歴 ${data.skipped}件）。`);
+            return;
+          }
+          downloadText(data.filename, data.content, format === "csv" ? "text/csv" : "text/markdown");
+          if (data.skipped > 0) {
+            alert(`${data.rendered}件を再出力しました。選択内容が保存されていない ${data.skipped}件は含まれていません。`);
+          }
+        } catch (err) {
+          alert(`再出力失敗: ${err.message}`);
+        }
+      });
+    }
+    for (const item of document.querySelectorAll(".trash-item")) {
+      const restoreBtn = item.querySelector(".restore-btn");
+      restoreBtn.addEventListener("click", async () => {
+        try {
+          await moveEntry("history/restore", item.dataset.historyId);
+          location.reload();
+        } catch (err) {
+          alert(`元に戻せませんでした: ${err.message}`);
+        }
+      });
+    }
+    const archiveNowBtn = document.querySelector(".archive-now-btn");
+    if (archiveNowBtn) {
+      archiveNowBtn.addEventListener("click", async () => {
+        try {
+          await archivePending();
+        } catch (err) {
+          alert(`アーカイブ失敗: ${err.message}`);
+        }
+      });
+    }
+    function bindEntry(entry) {
+      const historyId = entry.dataset.historyId;
+      const editor = entry.querySelector(".prompt-editor");
+      const noteEditor = entry.querySelector(".note-editor");
+      const overwriteBtn = entry.querySelector(".overwrite-btn");
+      const copyBtn = entry.querySelector(".copy-btn");
+      const applyBtn = entry.querySelector(".apply-btn");
+      const translationCopyBtn = entry.querySelector(".translation-copy-btn");
+      const deleteBtn = entry.querySelector(".delete-btn");
+      const archiveBtn = entry.querySelector(".archive-btn");
+      const unarchiveBtn = entry.querySelector(".unarchive-btn");
+      const lockBtn = entry.querySelector("button.lock-btn");
+      const imageCopyBtn = entry.querySelector(".image-copy-btn");
+      const images = entry.querySelector(".images");
+      const upload = entry.querySelector(".upload");
+      const dropzone = upload ? upload.querySelector(".dropzone") : null;
+      const fileInput = upload ? upload.querySelector(".file-input") : null;
+
+      if (overwriteBtn) {
+        overwriteBtn.addEventListener("click", async () => {
+          const currentPrompt = getPromptValue(entry);
+          try {
+            const data = await overwritePrompt(historyId, currentPrompt);
+            const swapped = swapCard(entry, data.card);
+            if (!swapped && editor) {
+              editor.value = typeof data.prompt === "string" ? data.prompt : currentPrompt.trim();
+            }
+            showButtonFeedback(
+              swapped ? swapped.querySelector(".overwrite-btn") : overwriteBtn,
+              "編集した内容で上書きしました"
+            );
+            showUndoToast("上書きしました");
+          } catch (err) {
+            alert(`上書き失敗: ${err.message}`);
+          }
+        });
+      }
+      for (const star of entry.querySelectorAll(".rating .star")) {
+        star.addEventListener("click", async () => {
+          const value = Number(star.dataset.value);
+          const next = Number(entry.dataset.rating || 0) === value ? 0 : value;
+          try {
+            await rateEntry(historyId, next);
+            syncRating(entry, next);
+          } catch (err) {
+            alert(`評価の保存失敗: ${err.message}`);
+          }
+        });
+      }
+      if (noteEditor) {
+        noteEditor.addEventListener("change", async () => {
+          try {
+            await saveNote(historyId, noteEditor.value);
+            noteEditor.classList.add("saved");
+            setTimeout(() => noteEditor.classList.remove("saved"), 1200);
+          } catch (err) {
+            alert(`メモ保存失敗: ${err.message}`);
+          }
+        });
+      }
+      if (lockBtn) {
+        lockBtn.addEventListener("click", async () => {
+          const nextLocked = entry.dataset.locked !== "true";
+          try {
+            await setEntryLocked(historyId, nextLocked);
+            syncLockState(entry, nextLocked);
+          } catch (err) {
+            alert(`ロック変更失敗: ${err.message}`);
+          }
+        });
+      }
+      if (copyBtn) {
+        copyBtn.addEventListener("click", async () => {
+          try {
+            await copyPrompt(entry);
+            showButtonFeedback(copyBtn, "コピーしました");
+          } catch (err) {
+            alert(`コピー失敗: ${err.message}`);
+          }
+        });
+      }
+      if (applyBtn) {
+        applyBtn.addEventListener("click", async () => {
+          try {
+            const data = await applyToMainUi(historyId);
+            const skipped = data.skipped || [];
+            showButtonFeedback(applyBtn, "メイン画面に復元しました");
+            if (skipped.length > 0) {
+              alert(`選択肢にないため復元できなかった項目: ${skipped.join("、")}`);
+            }
+          } catch (err) {
+            alert(`復元失敗: ${err.message}`);
+          }
+        });
+      }
+      if (translationCopyBtn) {
+        translationCopyBtn.addEventListener("click", async () => {
+          try {
+            await navigator.clipboard.writeText(entry.querySelector(".translation-text").textContent);
+            showButtonFeedback(translationCopyBtn, "コピーしました");
+          } catch (err) {
+            alert(`コピー失敗: ${err.message}`);
+          }
+        });
+      }
+      if (deleteBtn) {
+        deleteBtn.addEventListener("click", async () => {
+          try {
+            await deleteEntry(historyId);
+          } catch (err) {
+            alert(`削除失敗: ${err.message}`);
+          }
+        });
+      }
+      if (archiveBtn) {
+        archiveBtn.addEventListener("click", async () => {
+          try {
+            await moveEntry("archive", historyId);
+            entry.remove();
+          } catch (err) {
+            alert(`アーカイブ失敗: ${err.message}`);
+          }
+        });
+      }
+      if (unarchiveBtn) {
+        unarchiveBtn.addEventListener("click", async () => {
+          try {
+            await moveEntry("unarchive", historyId);
+            entry.remove();
+          } catch (err) {
+            alert(`アクティブに戻せませんでした: ${err.message}`);
+          }
+        });
+      }
+      if (imageCopyBtn) {
+        imageCopyBtn.addEventListener("click", async () => {
+          const imagePath = entry.dataset.selectedImage || "";
+          if (!imagePath) {
+            alert("コピー対象の画像がありません。");
+            return;
+          }
+          try {
+            await copyImageToClipboard(imagePath);
+            showImageCopyFeedback(imageCopyBtn);
+          } catch (err) {
+            alert(`画像コピー失敗: ${err.message}`);
+          }
+        });
+      }
+      if (images) {
+        images.addEventListener("click", (event) => {
+          const target = event.target;
+          if (!(target instanceof Element)) {
+            return;
+          }
+          const imageSimilarBtn = target.closest(".image-similar-btn");
+          if (imageSimilarBtn && images.contains(imageSimilarBtn)) {
+            const imageItem = imageSimilarBtn.closest(".image-item");
+            const imagePath = imageItem ? imageItem.dataset.imagePath || "" : "";
+            findSimilarImages(imagePath, null).catch((err) => {
+              alert(`似た画像の検索失敗: ${err.message}`);
+            });
+            return;
+          }
+          const imageDeleteBtn = target.closest(".image-delete-btn");
+          if (imageDeleteBtn && images.contains(imageDeleteBtn)) {
+            const imageItem = imageDeleteBtn.closest(".image-item");
+            deleteImage(entry, imageItem ? imageItem.dataset.imagePath || "" : "").catch((err) => {
+              alert(`画像削除失敗: ${err.message}`);
+            });
+            return;
+          }
+          const thumbLink = target.closest(".thumb-image-link");
+          if (!thumbLink || !images.contains(thumbLink)) {
+            return;
+          }
+          event.preventDefault();
+          const imageItem = thumbLink.closest(".image-item");
+          if (!imageItem) {
+            return;
+          }
+          setSelectedImage(entry, imageItem.dataset.imagePath || "");
+        });
+      }
+      setSelectedImage(entry, entry.dataset.selectedImage || "");
+      if (!dropzone || !fileInput) {
+        return;
+      }
+      syncUploadLabel(entry);
+      const handleUpload = async (upload) => {
+        try {
+          const data = await upload();
+          const added = data.images || [{ image_path: data.image_path, metadata: data.metadata }];
+          if (!swapCard(entry, data.card)) {
+            for (const image of added) {
+              renderUploadedImage(entry, image.image_path || "", image.metadata || null);
+            }
+          }
+          showUndoToast(added.length > 1 ? `画像を${added.length}枚追加しました` : "画像を追加しました");
+        } catch (err) {
+          alert(`アップロード失敗: ${err.message}`);
+        } finally {
+          fileInput.value = "";
+        }
+      };
+      const handleFiles = async (files) => {
+        if (!files || !files.length) return;
+        await handleUpload(() => uploadFiles(historyId, Array.from(files)));
+      };
+      dropzone._pasteImage = (blob) => handleUpload(() => uploadClipboard(historyId, blob));
+      dropzone.addEventListener("pointerenter", () => {
+        hoveredDropzone = dropzone;
+      });
+      dropzone.addEventListener("pointerleave", () => {
+        if (hoveredDropzone === dropzone) hoveredDropzone = null;
+      });
+      dropzone.addEventListener("click", () => fileInput.click());
+      fileInput.addEventListener("change", async () => {
+        await handleFiles(fileInput.files);
+      });
+      dropzone.addEventListener("dragover", (event) => {
+        event.preventDefault();
+        dropzone.classList.add("dragover");
+      });
+      dropzone.addEventListener("dragleave", () => {
+        dropzone.classList.remove("dragover");
+      });
+      dropzone.addEventListener("drop", async (event) => {
+        event.preventDefault();
+        dropzone.classList.remove("dragover");
+        await handleFiles(event.dataTransfer && event.dataTransfer.files);
+      });
+    }
+    for (const entry of document.querySelectorAll(".entry")) {
+      bindEntry(entry);
+    }
+    const loadMoreMarker = document.querySelector(".load-more");
+    if (loadMoreMarker && "IntersectionObserver" in window) {
+      const observer = new IntersectionObserver(async (changes) => {
+        if (!changes.some((change) => change.isIntersecting)) return;
+        observer.unobserve(loadMoreMarker);
+        if (await loadMoreEntries() && loadMoreMarker.isConnected) {
+          // Re-observing reports the marker again if it is still in view.
+          observer.observe(loadMoreMarker);
+        }
+      }, { rootMargin: "600px 0px" });
+      observer.observe(loadMoreMarker);
+    }
+    document.addEventListener("paste", async (event) => {
+      const active = document.activeElement;
+      const dropzone = active && active.classList && active.classList.contains("dropzone") ? active : hoveredDropzone;
+      if (!dropzone || !dropzone._pasteImage) {
+        return;
+      }
+      if (active && active !== dropzone && (active.isContentEditable || ["INPUT", "TEXTAREA"].includes(active.tagName))) {
+        return;
+      }
+      event.preventDefault();
+      const items = Array.from((event.clipboardData && event.clipboardData.items) || []);
+      const imageItem = items.find((item) => item.kind === "file" && item.type.startsWith("image/"));
+      // Without an image in the paste event the app reads the clipboard itself.
+      await dropzone._pasteImage(imageItem ? imageItem.getAsFile() : null);
+    });
+    const searchPanel = document.querySelector(".search-panel");
+    if (searchPanel) {
+      for (const field of searchPanel.querySelectorAll(".search-fields input:not([type=file]), .search-fields select")) {
+        field.addEventListener("input", applySearchFilter);
+      }
+      const similarFile = searchPanel.querySelector(".similar-file");
+      similarFile.addEventListener("change", async () => {
+        const file = similarFile.files && similarFile.files[0];
+        if (!file) return;
+        try {
+          await findSimilarImages("", file);
+        } catch (err) {
+          alert(`似た画像の検索失敗: ${err.message}`);
+        } finally {
+          similarFile.value = "";
+        }
+      });
+      const visionFile = searchPanel.querySelector(".vision-file");
+      const visionResult = searchPanel.querySelector(".vision-result");
+      const visionText = visionResult.querySelector(".vision-text");
+      let visionScreenshot = null;
+      visionFile.addEventListener("change", async () => {
+        const file = visionFile.files && visionFile.files[0];
+        if (!file) return;
+        try {
+          visionText.value = await readScreenshot(file);
+          visionScreenshot = file;
+          visionResult.hidden = false;
+        } catch (err) {
+          alert(`読み取り失敗: ${err.message}`);
+        } finally {
+          visionFile.value = "";
+        }
+      });
+      visionResult.querySelector(".vision-search-btn").addEventListener("click", () => {
+        searchPanel.querySelector(".search-query").value = visionText.value.trim();
+        applySearchFilter();
+      });
+      visionResult.querySelector(".vision-append-btn").addEventListener("click", async () => {
+        const prompt = visionText.value.trim();
+        if (!prompt) {
+          alert("プロンプトが空です。");
+          return;
+        }
+        try {
+          const entry = await appendHistory(prompt);
+          if (visionScreenshot && entry) {
+            await uploadFiles(entry.id, [visionScreenshot]);
+          }
+          location.reload();
+        } catch (err) {
+          alert(`履歴の追加失敗: ${err.message}`);
+        }
+      });
+      visionResult.querySelector(".vision-close-btn").addEventListener("click", () => {
+        visionResult.hidden = true;
+        visionScreenshot = null;
+      });
+      searchPanel.querySelector(".archive-search-btn").addEventListener("click", async () => {
+        try {
+          await searchAllHistory();
+        } catch (err) {
+          alert(`検索失敗: ${err.message}`);
+        }
+      });
+      searchPanel.querySelector(".search-clear-btn").addEventListener("click", () => {
+        writeSearchFilter({});
+        applySearchFilter();
+      });
+      searchPanel.querySelector(".search-save-btn").addEventListener("click", async () => {
+        const name = prompt("保存する検索の名前", "");
+        if (!name || !name.trim()) {
+          return;
+        }
+        try {
+          await saveSmartFilter({ ...readSearchFilter(), name: name.trim() });
+          location.reload();
+        } catch (err) {
+          alert(`検索の保存失敗: ${err.message}`);
+        }
+      });
+      for (const chip of searchPanel.querySelectorAll(".smart-filter")) {
+        const filter = JSON.parse(chip.dataset.filter || "{}");
+        chip.querySelector(".smart-filter-apply").addEventListener("click", () => {
+          writeSearchFilter(filter);
+          applySearchFilter();
+        });
+        chip.querySelector(".smart-filter-delete").addEventListener("click", async () => {
+          if (!confirm(`保存した検索「${filter.name}」を削除しますか？`)) {
+            return;
+          }
+          try {
+            await deleteSmartFilter(filter.name);
+            chip.remove();
+          } catch (err) {
+            alert(`検索の削除失敗: ${err.message}`);
+          }
+        });
+      }
+      const savedFilter = sessionStorage.getItem(SEARCH_FILTER_KEY);
+      if (savedFilter) {
+        writeSearchFilter(JSON.parse(savedFilter));
+        applySearchFilter();
+      }
+    }
+    const pendingUndoToast = sessionStorage.getItem(UNDO_TOAST_KEY);
+    if (pendingUndoToast) {
+      showUndoToast(pendingUndoToast);
+    }
+    void discoverApiBase().then(() => {
+      listenHistoryEvents();
+      void applyFeatures();
+    });
+  </script>
+"#;
+
+const NON_INTERACTIVE_SCRIPT: &str = r#"
+  <script>
+    function getPromptValue(entry) {
+      const editor = entry.querySelector(".prompt-editor");
+      return editor ? editor.value : "";
+    }
+    async function copyPrompt(entry) {
+      const prompt = getPromptValue(entry);
+      await navigator.clipboard.writeText(prompt);
+    }
+    function showImageCopyFeedback(button) {
+      if (!button) return;
+      button.classList.remove("copy-feedback");
+      if (button._copyFeedbackTimer) {
+        clearTimeout(button._copyFeedbackTimer);
+      }
+      void button.offsetWidth;
+      button.classList.add("copy-feedback");
+      button._copyFeedbackTimer = setTimeout(() => {
+        button.classList.remove("copy-feedback");
+        button._copyFeedbackTimer = null;
+      }, 1400);
+    }
+    function showButtonFeedback(button, message) {
+      if (!button || !message) return;
+      button.dataset.feedback = message;
+      button.classList.remove("feedback-visible");
+      if (button._buttonFeedbackTimer) {
+        clearTimeout(button._buttonFeedbackTimer);
+      }
+      void button.offsetWidth;
+      button.classList.add("feedback-visible");
+      button._buttonFeedbackTimer = setTimeout(() => {
+        button.classList.remove("feedback-visible");
+        button.dataset.feedback = "";
+        button._buttonFeedbackTimer = null;
+      }, 1400);
+    }
+    async function imageBlobFromPath(imagePath) {
+      return new Promise((resolve, reject) => {
+        const image = new Image();
+        image.onload = () => {
+          const width = image.naturalWidth || image.width;
+          const height = image.naturalHeight || image.height;
+          if (!width || !height) {
+            reject(new Error("画像サイズを取得できませんでした"));
+            return;
+          }
+          const canvas = document.createElement("canvas");
+          canvas.width = width;
+          canvas.height = height;
+          const ctx = canvas.getContext("2d");
+          if (!ctx) {
+            reject(new Error("画像変換に失敗しました"));
+            return;
+          }
+          ctx.drawImage(image, 0, 0);
+          canvas.toBlob((blob) => {
+            if (!blob) {
+              reject(new Error("画像変換に失敗しました"));
+              return;
+            }
+            resolve(blob);
+          }, "image/png");
+        };
+        image.onerror = () => reject(new Error("画像を取得できませんでした"));
+        image.src = imagePath;
+      });
+    }
+    async function copyImageToClipboard(imagePath) {
+      if (
+        !navigator.clipboard ||
+        typeof navigator.clipboard.write !== "function" ||
+        typeof ClipboardItem === "undefined"
+      ) {
+        throw new Error("このブラウザは画像コピーに対応していません");
+      }
+      const pngBlob = await imageBlobFromPath(imagePath);
+      await navigator.clipboard.write([new ClipboardItem({ "image/png": pngBlob })]);
+    }
+    for (const button of document.querySelectorAll(".copy-btn")) {
+      button.addEventListener("click", async () => {
+        try {
+          const entry = button.closest(".entry");
+          if (!entry) return;
+          await copyPrompt(entry);
+          showButtonFeedback(button, "コピーしました");
+        } catch (err) {
+          alert(`コピー失敗: ${err.message}`);
+        }
+      });
+    }
+    for (const button of document.querySelectorAll(".translation-copy-btn")) {
+      button.addEventListener("click", async () => {
+        try {
+          const pane = button.closest(".translation");
+          if (!pane) return;
+          await navigator.clipboard.writeText(pane.querySelector(".translation-text").textContent);
+          showButtonFeedback(button, "コピーしました");
+        } catch (err) {
+          alert(`コピー失敗: ${err.message}`);
+        }
+      });
+    }
+    for (const button of document.querySelectorAll(".image-copy-btn")) {
+      button.addEventListener("click", async () => {
+        const entry = button.closest(".entry");
+        if (!entry) return;
+        const imagePath = entry.dataset.selectedImage || "";
+        if (!imagePath) {
+          alert("コピー対象の画像がありません。");
+          return;
+        }
+        try {
+          await copyImageToClipboard(imagePath);
+          showImageCopyFeedback(button);
+        } catch (err) {
+          alert(`画像コピー失敗: ${err.message}`);
+        }
+      });
+    }
+  </script>
+"#;
+
+const LAZY_IMAGE_SCRIPT: &str = r#"
+  <script>
+    (() => {
+      const images = document.querySelectorAll("img.is-placeholder[data-src]");
+      const reveal = (img) => {
+        const src = img.dataset.src;
+        if (!src) return;
+        delete img.dataset.src;
+        // Pages the app serves ask it for a thumbnail sized to the card and
+        // fall back to the stored one when it cannot answer.
+        const item = img.closest(".image-item");
+        const sources = window.thumbnailUrl && item
+          ? [window.thumbnailUrl(item.dataset.imagePath, img.clientWidth), src]
+          : [src];
+        const full = new Image();
+        const swap = () => {
+          img.src = full.src;
+          img.classList.remove("is-placeholder");
+        };
+        full.addEventListener("load", swap);
+        full.addEventListener("error", () => {
+          if (sources.length) {
+            full.src = sources.shift();
+          } else {
+            swap();
+          }
+        });
+        full.src = sources.shift();
+      };
+      if (!("IntersectionObserver" in window)) {
+        images.forEach(reveal);
+        window.observeLazyImages = (root) => root.querySelectorAll("img.is-placeholder[data-src]").forEach(reveal);
+        return;
+      }
+      const observer = new IntersectionObserver((changes) => {
+        for (const change of changes) {
+          if (!change.isIntersecting) continue;
+          observer.unobserve(change.target);
+          reveal(change.target);
+        }
+      }, { rootMargin: "400px 0px" });
+      images.forEach((img) => observer.observe(img));
+      // Cards loaded on scroll bring their own placeholders.
+      window.observeLazyImages = (root) => {
+        root.querySelectorAll("img.is-placeholder[data-src]").forEach((img) => observer.observe(img));
+      };
+    })();
+  </script>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        image_content_type, parse_import_file, ArchiveGrouping, DedupeMode, ExportFormat,
+        ExportRange, HistoryStore, ImageRemoval, HISTORY_SCHEMA_VERSION,
+    };
+    use crate::backup::read_backup_zip;
+    use crate::encryption::{is_encrypted, Cipher};
+    use crate::housekeeping::{HousekeepingRule, RuleAction};
+    use crate::thumbnails::{thumbnail_rel_path, THUMBS_DIR};
+    use chrono::{Local, NaiveDate, TimeDelta};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT_FIXTURE_ID: AtomicU64 = AtomicU64::new(1);
+
+    fn fixture_base() -> std::path::PathBuf {
+        let mut base = std::env::temp_dir();
+        let sequence = NEXT_FIXTURE_ID.fetch_add(1, Ordering::Relaxed);
+        base.push(format!(
+            "ipg_history_store_test_{}_{}",
+            std::process::id(),
+            sequence
+        ));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("mkdir fixture");
+        base
+    }
+
+    fn read_entries(path: &Path) -> Vec<Value> {
+        let raw = fs::read_to_string(path).expect("read history");
+        serde_json::from_str::<Value>(&raw)
+            .expect("parse history")
+            .get("entries")
+            .and_then(Value::as_array)
+            .expect("history entries array")
+            .to_vec()
+    }
+
+    fn find_entry<'a>(
+        entries: &'a [Value],
+        history_id: &str,
+    ) -> &'a serde_json::Map<String, Value> {
+        entries
+            .iter()
+            .find_map(|entry| {
+                let obj = entry.as_object()?;
+                let id = obj.get("id").and_then(Value::as_str).unwrap_or_default();
+                if id == history_id {
+                    Some(obj)
+                } else {
+                    None
+                }
+            })
+            .expect("entry exists")
+    }
+
+    #[test]
+    fn append_and_rotate_entries() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
+
+        store.append_history("a").expect("append a");
+        store.append_history("b").expect("append b");
+        store.append_history("c").expect("append c");
+
+        assert_eq!(read_entries(&base.join("history.json")).len(), 2);
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn rotates_old_entries_into_month_archives_within_size_limit() {
+        let base = fixture_base();
+        let old_entries: Vec<Value> = ["20200105", "20200120", "20200220"]
+            .iter()
+            .map(|day| {
+                json!({
+                    "id": format!("{day}_120000_0001"),
+                    "ts": format!("{}-{}-{} 12:00:00", &day[..4], &day[4..6], &day[6..]),
+                    "prompt": format!("old {day}"),
+                    "images": [],
+                })
+            })
+            .collect();
+        fs::write(
+            base.join("history.json"),
+            json!({ "schema_version": HISTORY_SCHEMA_VERSION, "entries": old_entries }).to_string(),
+        )
+        .expect("write history");
+        let mut store = HistoryStore::new(base.clone(), 10).expect("create store");
+        store.set_archive_after_days(30);
+        store.set_archive_grouping(ArchiveGrouping::Month);
+        store.set_archive_max_bytes(1);
+
+        store.append_history("today").expect("append");
+        assert_eq!(read_entries(&base.join("history.json")).len(), 1);
+        // Over the size limit only the newest archive survives.
+        assert_eq!(read_entries(&base.join("History_202002.json")).len(), 1);
+        assert!(!base.join("History_202001.json").exists());
+        let audit = fs::read_to_string(base.join("audit.log")).expect("audit log");
+        assert!(audit.contains("archive_prune") && audit.contains("History_202001.json"));
+
+        let february = ExportRange::parse(Some("2020-02-10"), Some("2020-03-31")).expect("range");
+        let hits = store.search(february, "old", 10).expect("search");
+        assert_eq!(hits.total, 1);
+        assert_eq!(hits.hits[0].page, "History_202002.html");
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn compacts_past_daily_archives_and_writes_archive_index() {
+        let base = fixture_base();
+        let days = ["20200105", "20200120", "20200220"];
+        for day in days {
+            let entry = json!({
+                "id": format!("{day}_120000_0001"),
+                "ts": format!("{}-{}-{} 12:00:00", &day[..4], &day[4..6], &day[6..]),
+                "prompt": format!("old {day}"),
+                "images": [],
+            });
+            fs::write(
+                base.join(format!("History_{day}.json")),
+                json!({ "schema_version": HISTORY_SCHEMA_VERSION, "entries": [entry] }).to_string(),
+            )
+            .expect("write archive");
+        }
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        store.regenerate_html(8765).expect("render days");
+        assert!(base.join("History_20200105.html").exists());
+
+        store.set_archive_compact_threshold(3);
+        assert_eq!(store.compact_archives().expect("under threshold"), 0);
+        store.set_archive_compact_threshold(2);
+        assert_eq!(store.compact_archives().expect("compact"), 3);
+        assert_eq!(read_entries(&base.join("History_202001.json")).len(), 2);
+        assert_eq!(read_entries(&base.join("History_202002.json")).len(), 1);
+        for day in days {
+            assert!(!base.join(format!("History_{day}.json")).exists());
+            assert!(!base.join(format!("History_{day}.html")).exists());
+        }
+
+        store.regenerate_html(8765).expect("render months");
+        let index = fs::read_to_string(base.join("ArchiveIndex.html")).expect("archive index");
+        assert!(index.contains("<a href=\"History_202001.html\">2020-01-20</a>"));
+        assert!(index.contains("<p>3日・3件</p>"));
+        let history = fs::read_to_string(base.join("History.html")).expect("history html");
+        assert!(history.contains("href=\"ArchiveIndex.html\""));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn delete_history_removes_active_entry() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+
+        let target = store
+            .append_history("delete target")
+            .expect("append target");
+        store.append_history("keep").expect("append keep");
+
+        assert!(
+            store.delete_history(&target.id).expect("delete active"),
+            "active history should be deleted"
+        );
+
+        let entries = read_entries(&base.join("history.json"));
+        assert!(
+            entries.iter().all(|entry| {
+                entry.get("id").and_then(Value::as_str).unwrap_or_default() != target.id
+            }),
+            "deleted entry should not remain in active history"
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn delete_history_removes_archive_entry() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
+
+        let archived = store
+            .append_history("archive delete target")
+            .expect("append archived");
+        store
+            .append_history("active latest")
+            .expect("append active");
+        let archive_json = base.join(format!("History_{}.json", &archived.id[..8]));
+        assert!(archive_json.exists(), "archive file should exist");
+
+        assert!(
+            store
+                .delete_history(&archived.id)
+                .expect("delete archive entry"),
+            "archive history should be deleted"
+        );
+
+        let archive_entries = read_entries(&archive_json);
+        assert!(
+            archive_entries.iter().all(|entry| {
+                entry.get("id").and_then(Value::as_str).unwrap_or_default() != archived.id
+            }),
+            "deleted entry should not remain in archive history"
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn delete_history_returns_false_for_missing_history_id() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
+        store.append_history("exists").expect("append");
+
+        let deleted = store
+            .delete_history("missing-id")
+            .expect("missing id should not error");
+        assert!(!deleted);
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn deleted_entries_go_to_trash_and_can_be_restored() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
+
+        let archived = store.append_history("archived").expect("append archived");
+        let active = store.append_history("active").expect("append active");
+        assert!(store.delete_history(&active.id).expect("delete active"));
+        assert!(store.delete_history(&archived.id).expect("delete archived"));
+
+        let trash = store.trash_entries().expect("trash entries");
+        assert_eq!(trash.len(), 2);
+        assert_eq!(trash[0].entry.id, archived.id);
+        assert_eq!(trash[1].origin, "history.json");
+        store.regenerate_html(8765).expect("regenerate html");
+        let html = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(html.contains("ゴミ箱（2 件"));
+
+        assert!(store.restore_history(&active.id).expect("restore active"));
+        assert!(store
+            .restore_history(&archived.id)
+            .expect("restore archived"));
+        assert!(!store.restore_history(&active.id).expect("already restored"));
+        let active_entries = read_entries(&base.join("history.json"));
+        assert_eq!(active_entries.len(), 1);
+        find_entry(&active_entries, &active.id);
+        let archive_json = base.join(format!("History_{}.json", &archived.id[..8]));
+        find_entry(&read_entries(&archive_json), &archived.id);
+
+        store.delete_history(&active.id).expect("delete again");
+        let now = Local::now().naive_local();
+        assert_eq!(store.purge_trash(now).expect("purge fresh"), 0);
+        assert_eq!(
+            store
+                .purge_trash(now + TimeDelta::days(31))
+                .expect("purge expired"),
+            1
+        );
+        assert!(store.trash_entries().expect("trash entries").is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn undo_reverts_overwrite_image_change_and_delete_newest_first() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("original").expect("append");
+        assert!(store.undo().expect("empty undo").is_none());
+
+        store
+            .update_history_prompt(&entry.id, "edited")
+            .expect("overwrite");
+        store
+            .append_image(&entry.id, "shot.png", b"png")
+            .expect("upload");
+        store.delete_history(&entry.id).expect("delete");
+        assert_eq!(store.last_undo().map(|op| op.label()), Some("削除"));
+
+        let undone = store.undo().expect("undo delete").expect("operation");
+        assert_eq!(undone.history_id(), entry.id);
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(
+            find_entry(&entries, &entry.id)["images"]
+                .as_array()
+                .map(Vec::len),
+            Some(1)
+        );
+
+        store.undo().expect("undo upload");
+        store.set_locked(&entry.id, true).expect("lock");
+        assert!(store.undo().is_err(), "locked entries reject undo");
+        store.set_locked(&entry.id, false).expect("unlock");
+        store.undo().expect("undo overwrite");
+
+        let entries = read_entries(&base.join("history.json"));
+        let restored = find_entry(&entries, &entry.id);
+        assert_eq!(restored["prompt"], "original");
+        assert_eq!(restored["images"].as_array().map(Vec::len), Some(0));
+        assert!(store.undo().expect("stack drained").is_none());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn pages_name_their_data_folder_for_port_discovery() {
+        let base = fixture_base();
+        let other = fixture_base();
+        let store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let other_store = HistoryStore::new(other.clone(), 5).expect("create other store");
+        assert_ne!(store.store_id(), other_store.store_id());
+        assert_eq!(
+            store.store_id(),
+            HistoryStore::new(base.clone(), 5)
+                .expect("reopen")
+                .store_id()
+        );
+
+        store.regenerate_html(8765).expect("render");
+        let page = fs::read_to_string(base.join("History.html")).expect("read page");
+        assert!(page.contains(&format!("const STORE_ID = \"{}\";", store.store_id())));
+        assert!(page.contains("const PAGE_API_BASE = \"http://127.0.0.1:8765\";"));
+
+        fs::remove_dir_all(base).ok();
+        fs::remove_dir_all(other).ok();
+    }
+
+    #[test]
+    fn dedupe_modes_skip_bump_or_count_repeated_prompts() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let history_json = base.join("history.json");
+        let first = store.append_history("a  cat\nin snow").expect("append");
+        store.append_history("other").expect("append other");
+
+        store.set_dedupe_mode(DedupeMode::Skip);
+        let skipped = store.append_history(" a cat in  snow ").expect("skip");
+        assert_eq!(skipped.id, first.id);
+        assert_eq!(read_entries(&history_json).len(), 2);
+
+        store.set_dedupe_mode(DedupeMode::Count);
+        store.append_history("a cat in snow").expect("count");
+        let counted = store.append_history("a cat in snow").expect("count again");
+        assert_eq!(counted.count, 3);
+        assert_eq!(
+            find_entry(&read_entries(&history_json), &first.id)["count"],
+            3
+        );
+
+        store.set_dedupe_mode(DedupeMode::Bump);
+        let bumped = store.append_history("a cat in snow").expect("bump");
+        assert_ne!(bumped.id, first.id);
+        let entries = read_entries(&history_json);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["id"], bumped.id.as_str());
+        assert_eq!(entries[1]["prompt"], "a  cat\nin snow");
+
+        store.set_dedupe_mode(DedupeMode::Off);
+        store
+            .append_history("a cat in snow")
+            .expect("append duplicate");
+        assert_eq!(read_entries(&history_json).len(), 3);
+        assert_eq!(DedupeMode::parse(" Bump "), Some(DedupeMode::Bump));
+        assert_eq!(DedupeMode::parse("merge"), None);
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn regenerate_html_includes_delete_button_in_archive_page() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
+
+        let archived = store
+            .append_history("archive delete available")
+            .expect("append archived");
+        store
+            .append_history("active latest")
+            .expect("append active");
+
+        store.regenerate_html(8765).expect("regenerate html");
+
+        let archive_html_path = base.join(format!("History_{}.html", &archived.id[..8]));
+        let archive_html = fs::read_to_string(&archive_html_path).expect("read archive html");
+        assert!(
+            archive_html.contains("<button class=\"btn delete-btn\">削除</button>"),
+            "archive html should include delete button markup"
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn history_page_renders_one_page_and_lists_the_rest() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 100).expect("create store");
+        let total = HistoryStore::LIST_PAGE_SIZE + 3;
+        let entries: Vec<_> = (0..total)
+            .map(|index| {
+                store
+                    .append_history(&format!("prompt {index}"))
+                    .expect("append")
+            })
+            .collect();
+
+        let page = store
+            .render_page("History.html", 8765)
+            .expect("render")
+            .expect("history page");
+        assert_eq!(
+            page.matches("<article class=\"entry").count(),
+            HistoryStore::LIST_PAGE_SIZE
+        );
+        assert!(page.contains(&format!(
+            "data-offset=\"{}\" data-total=\"{total}\"",
+            HistoryStore::LIST_PAGE_SIZE
+        )));
+
+        let list = store
+            .list_entries(HistoryStore::LIST_PAGE_SIZE, 10)
+            .expect("list");
+        assert_eq!(list.total, total);
+        let ids: Vec<&str> = list
+            .entries
+            .iter()
+            .map(|item| item.entry.id.as_str())
+            .collect();
+        let oldest: Vec<&str> = entries[..3]
+            .iter()
+            .rev()
+            .map(|entry| entry.id.as_str())
+            .collect();
+        assert_eq!(ids, oldest);
+        assert!(list.entries[0]
+            .html
+            .contains(&format!("data-history-id=\"{}\"", entries[2].id)));
+        assert!(store
+            .list_entries(total, 10)
+            .expect("list")
+            .entries
+            .is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn card_renders_the_entry_as_its_page_does() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 100).expect("create store");
+        let active = store.append_history("prompt a").expect("append a");
+        let archived = store.append_history("prompt b").expect("append b");
+        assert!(store.archive_entry(&archived.id).expect("archive"));
+        store
+            .update_history_prompt(&active.id, "prompt edited")
+            .expect("update");
+
+        let card = store.card(&active.id).expect("card").expect("active card");
+        assert_eq!(card.entry.prompt, "prompt edited");
+        assert!(card.html.contains("prompt edited</textarea>"));
+        assert!(card.html.contains("archive-btn"));
+
+        let card = store
+            .card(&archived.id)
+            .expect("card")
+            .expect("archived card");
+        assert!(card.html.contains("unarchive-btn"));
+        assert!(store.card("missing").expect("card").is_none());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn rotation_status_flags_oldest_entries_and_archive_pending_moves_them() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 4).expect("create store");
+        store.set_rotation_warn_margin(2);
+
+        let first = store.append_history("first").expect("append first");
+        assert!(store
+            .rotation_status()
+            .expect("status")
+            .pending_ids
+            .is_empty());
+
+        let second = store.append_history("second").expect("append second");
+        store.append_history("third").expect("append third");
+        let status = store.rotation_status().expect("status");
+        assert_eq!(status.remaining, 1);
+        assert_eq!(status.pending_ids, vec![first.id.clone()]);
+
+        store.regenerate_html(8765).expect("regenerate html");
+        let html = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(html.contains("archive-now-btn"));
+        assert!(html.contains("次にアーカイブ"));
+
+        store.append_history("fourth").expect("append fourth");
+        let status = store.rotation_status().expect("status");
+        assert_eq!(
+            status.pending_ids,
+            vec![first.id.clone(), second.id.clone()]
+        );
+
+        assert_eq!(store.archive_pending().expect("archive pending"), 2);
+        let active = read_entries(&base.join("history.json"));
+        assert_eq!(active.len(), 2);
+        let archive_json = base.join(format!("History_{}.json", &first.id[..8]));
+        let archived = read_entries(&archive_json);
+        assert_eq!(archived.len(), 2);
+        assert!(store
+            .rotation_status()
+            .expect("status")
+            .pending_ids
+            .is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn archive_entry_and_unarchive_entry_move_between_active_and_archive() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
+        let first = store.append_history("first").expect("append first");
+        let second = store.append_history("second").expect("append second");
+
+        assert!(store.archive_entry(&first.id).expect("archive first"));
+        assert!(!store.archive_entry(&first.id).expect("archive again"));
+        let archive_json = base.join(format!("History_{}.json", &first.id[..8]));
+        assert_eq!(read_entries(&archive_json).len(), 1);
+
+        store.append_history("third").expect("append third");
+        let err = store
+            .unarchive_entry(&first.id)
+            .expect_err("active history is full");
+        assert!(err.to_string().contains("is full"));
+
+        assert!(store.archive_entry(&second.id).expect("archive second"));
+        assert!(store.unarchive_entry(&first.id).expect("unarchive first"));
+        let active = read_entries(&base.join("history.json"));
+        let ids: Vec<&str> = active
+            .iter()
+            .filter_map(|entry| entry.get("id").and_then(Value::as_str))
+            .collect();
+        assert_eq!(ids.first().copied(), Some(first.id.as_str()));
+        assert_eq!(read_entries(&archive_json).len(), 1);
+        assert!(store
+            .unarchive_entry(&first.id)
+            .expect_err("already active")
+            .to_string()
+            .contains("already active"));
+
+        fs::remove_dir_all(base).ok();
     }
-    void pollHistoryRevision();
-    setInterval(() => {
-      void pollHistoryRevision();
-    }, HISTORY_REVISION_POLL_MS);
-  </script>
-"#;
 
-const NON_INTERACTIVE_SCRIPT: &str = r#"
-  <script>
-    function getPromptValue(entry) {
-      const editor = entry.querySelector(".prompt-editor");
-      return editor ? editor.value : "";
+    #[test]
+    fn export_renders_csv_and_markdown_within_range() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
+        let archived = store
+            .append_history("old, \"quoted\"\nline")
+            .expect("append archived");
+        let active = store.append_history("new prompt").expect("append active");
+
+        let csv = store
+            .export(ExportFormat::Csv, ExportRange::default())
+            .expect("export csv");
+        assert!(csv.starts_with("\u{feff}id,ts,prompt,images,note,rating\r\n"));
+        assert!(csv.contains(&format!(
+            "{},{},\"old, \"\"quoted\"\"\nline\",",
+            archived.id, archived.ts
+        )));
+        assert!(csv.find(&archived.id) < csv.find(&active.id));
+
+        let markdown = store
+            .export(ExportFormat::Markdown, ExportRange::default())
+            .expect("export markdown");
+        assert!(markdown.contains(&format!("## {} (`{}`)", active.ts, active.id)));
+        assert!(markdown.contains("```text\nnew prompt\n```"));
+
+        let range = ExportRange::parse(Some("2000-01-01"), Some("20000102")).expect("range");
+        let empty = store
+            .export(ExportFormat::Csv, range)
+            .expect("export range");
+        assert_eq!(empty, "\u{feff}id,ts,prompt,images,note,rating\r\n");
+        assert!(ExportRange::parse(Some("01/02/2000"), None).is_err());
+        assert!(ExportFormat::parse("xlsx").is_err());
+
+        fs::remove_dir_all(base).ok();
     }
-    async function copyPrompt(entry) {
-      const prompt = getPromptValue(entry);
-      await navigator.clipboard.writeText(prompt);
+
+    #[test]
+    fn import_entries_dedupes_renames_collisions_and_reads_exports() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let existing = store.append_history("existing prompt").expect("append");
+
+        let csv = store
+            .export(ExportFormat::Csv, ExportRange::default())
+            .expect("export csv");
+        let parsed = parse_import_file("history.csv", csv.as_bytes()).expect("parse csv");
+        assert_eq!(parsed.len(), 1);
+        let summary = store.import_entries(parsed).expect("import csv");
+        assert_eq!((summary.imported, summary.duplicates), (0, 1));
+
+        let json = format!(
+            r#"[{{"id":"{}","ts":"{}","prompt":"other prompt","images":["images/a.png"]}},
+               {{"id":"20240102_030405_0001","ts":"2024-01-02 03:04:05","prompt":"a, \"b\"\nc"}}]"#,
+            existing.id, existing.ts
+        );
+        let parsed = parse_import_file("history.json", json.as_bytes()).expect("parse json");
+        let summary = store.import_entries(parsed).expect("import json");
+        assert_eq!((summary.imported, summary.renamed), (2, 1));
+
+        let archived = read_entries(&base.join("History_20240102.json"));
+        assert_eq!(
+            archived[0].get("prompt").and_then(Value::as_str),
+            Some("a, \"b\"\nc")
+        );
+        let renamed = read_entries(&base.join(format!("History_{}.json", &existing.id[..8])));
+        assert_eq!(renamed.len(), 1);
+        assert_ne!(
+            renamed[0].get("id").and_then(Value::as_str),
+            Some(existing.id.as_str())
+        );
+        assert_eq!(read_entries(&base.join("history.json")).len(), 1);
+
+        let round_trip = store
+            .export(ExportFormat::Csv, ExportRange::default())
+            .expect("export all");
+        let reparsed = parse_import_file("all.csv", round_trip.as_bytes()).expect("reparse");
+        assert_eq!(reparsed.len(), 3);
+        assert!(parse_import_file("bad.csv", b"prompt\n\"open").is_err());
+
+        fs::remove_dir_all(base).ok();
     }
-    function showImageCopyFeedback(button) {
-      if (!button) return;
-      button.classList.remove("copy-feedback");
-      if (button._copyFeedbackTimer) {
-        clearTimeout(button._copyFeedbackTimer);
-      }
-      void button.offsetWidth;
-      button.classList.add("copy-feedback");
-      button._copyFeedbackTimer = setTimeout(() => {
-        button.classList.remove("copy-feedback");
-        button._copyFeedbackTimer = null;
-      }, 1400);
+
+    #[test]
+    fn search_spans_archives_and_limits_by_range() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let active = store.append_history("Blue sky").expect("append");
+        let archived = store.append_history("blue sea").expect("append");
+        store.append_history("red car").expect("append");
+        store.archive_entry(&archived.id).expect("archive");
+
+        let search = store
+            .search(ExportRange::default(), "BLUE", 1)
+            .expect("search");
+        assert_eq!(search.total, 2);
+        assert_eq!(search.hits.len(), 1);
+        let all = store
+            .search(ExportRange::default(), "blue", 10)
+            .expect("search all");
+        let pages: Vec<(&str, &str)> = all
+            .hits
+            .iter()
+            .map(|hit| (hit.entry.id.as_str(), hit.page.as_str()))
+            .collect();
+        let archive_page = format!("History_{}.html", &archived.id[..8]);
+        assert!(pages.contains(&(active.id.as_str(), "History.html")));
+        assert!(pages.contains(&(archived.id.as_str(), archive_page.as_str())));
+
+        let past = ExportRange::parse(Some("2000-01-01"), Some("2000-12-31")).expect("range");
+        assert_eq!(store.search(past, "", 10).expect("search past").total, 0);
+
+        fs::remove_dir_all(base).ok();
     }
-    function showButtonFeedback(button, message) {
-      if (!button || !message) return;
-      button.dataset.feedback = message;
-      button.classList.remove("feedback-visible");
-      if (button._buttonFeedbackTimer) {
-        clearTimeout(button._buttonFeedbackTimer);
-      }
-      void button.offsetWidth;
-      button.classList.add("feedback-visible");
-      button._buttonFeedbackTimer = setTimeout(() => {
-        button.classList.remove("feedback-visible");
-        button.dataset.feedback = "";
-        button._buttonFeedbackTimer = null;
-      }, 1400);
+
+    #[test]
+    fn backfills_thumbnails_for_legacy_images() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("with images").expect("append");
+        let fresh = store
+            .append_image(&entry.id, "fresh.png", &encode_png(|x, _| x as u8))
+            .expect("upload");
+        let legacy = store
+            .append_image(&entry.id, "legacy.png", &encode_png(|_, y| y as u8))
+            .expect("upload");
+        store
+            .append_image(&entry.id, "broken.png", b"png")
+            .expect("undecodable upload");
+        let thumb = |path: &str| base.join(thumbnail_rel_path(path).expect("thumb path"));
+        assert!(thumb(&fresh).exists());
+        // Simulate an image uploaded before thumbnails existed.
+        fs::remove_file(thumb(&legacy)).expect("drop thumbnail");
+
+        let missing = store.missing_thumbnails().expect("missing");
+        assert_eq!(missing.len(), 2);
+        assert!(missing.contains(&legacy));
+        store.create_thumbnail(&legacy).expect("backfill");
+        assert!(thumb(&legacy).exists());
+
+        store.regenerate_html(8765).expect("render");
+        let rendered = fs::read_to_string(base.join("History.html")).expect("read html");
+        let legacy_thumb = thumbnail_rel_path(&legacy).expect("thumb path");
+        assert!(rendered.contains(&format!("data-src=\"{legacy_thumb}\"")));
+        assert!(rendered.contains(&format!("href=\"{legacy}\"")));
+
+        fs::remove_dir_all(base).ok();
     }
-    async function imageBlobFromPath(imagePath) {
-      return new Promise((resolve, reject) => {
-        const image = new Image();
-        image.onload = () => {
-          const width = image.naturalWidth || image.width;
-          const height = image.naturalHeight || image.height;
-          if (!width || !height) {
-            reject(new Error("画像サイズを取得できませんでした"));
-            return;
-          }
-          const canvas = document.createElement("canvas");
-          canvas.width = width;
-          canvas.height = height;
-          const ctx = canvas.getContext("2d");
-          if (!ctx) {
-            reject(new Error("画像変換に失敗しました"));
-            return;
-          }
-          ctx.drawImage(image, 0, 0);
-          canvas.toBlob((blob) => {
-            if (!blob) {
-              reject(new Error("画像変換に失敗しました"));
-              return;
-            }
-            resolve(blob);
-          }, "image/png");
-        };
-        image.onerror = () => reject(new Error("画像を取得できませんでした"));
-        image.src = imagePath;
-      });
+
+    #[test]
+    fn renders_blurred_placeholders_until_images_scroll_in() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("with images").expect("append");
+        let image = store
+            .append_image(&entry.id, "image.png", &encode_png(|x, y| (x + y) as u8))
+            .expect("upload");
+        let broken = store
+            .append_image(&entry.id, "broken.png", b"png")
+            .expect("undecodable upload");
+        // Legacy data dirs have thumbnails but no placeholder index yet.
+        fs::remove_file(base.join("image_previews.json")).expect("drop previews");
+        assert_eq!(store.missing_thumbnails().expect("missing").len(), 2);
+        store.create_thumbnail(&image).expect("backfill");
+
+        store.regenerate_html(8765).expect("render");
+        let rendered = fs::read_to_string(base.join("History.html")).expect("read html");
+        let thumb = thumbnail_rel_path(&image).expect("thumb path");
+        assert!(rendered
+            .contains("<img class=\"thumb-image is-placeholder\" src=\"data:image/png;base64,"));
+        assert!(rendered.contains(&format!("data-src=\"{thumb}\"")));
+        assert!(rendered.contains(&format!(
+            "<img class=\"thumb-image\" src=\"{broken}\" alt=\"history image\" loading=\"lazy\" />"
+        )));
+        assert!(rendered.contains("new IntersectionObserver("));
+
+        fs::remove_dir_all(base).ok();
     }
-    async function copyImageToClipboard(imagePath) {
-      if (
-        !navigator.clipboard ||
-        typeof navigator.clipboard.write !== "function" ||
-        typeof ClipboardItem === "undefined"
-      ) {
-        throw new Error("このブラウザは画像コピーに対応していません");
-      }
-      const pngBlob = await imageBlobFromPath(imagePath);
-      await navigator.clipboard.write([new ClipboardItem({ "image/png": pngBlob })]);
+
+    #[test]
+    fn recovers_a_broken_history_from_its_backup() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        store.append_history("first").expect("append");
+        store.append_history("second").expect("append");
+        assert!(base.join("history.json.bak").exists());
+
+        fs::write(base.join("history.json"), "{ torn").expect("corrupt");
+        let store = HistoryStore::new(base.clone(), 5).expect("reopen");
+        let entries = store
+            .read_entries(&base.join("history.json"))
+            .expect("entries");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].prompt, "first");
+        assert!(fs::read_dir(&base)
+            .expect("list")
+            .filter_map(|item| item.ok())
+            .any(|item| item
+                .file_name()
+                .to_string_lossy()
+                .starts_with("history.broken.")));
+
+        fs::remove_dir_all(base).ok();
     }
-    for (const button of document.querySelectorAll(".copy-btn")) {
-      button.addEventListener("click", async () => {
-        try {
-          const entry = button.closest(".entry");
-          if (!entry) return;
-          await copyPrompt(entry);
-          showButtonFeedback(button, "コピーしました");
-        } catch (err) {
-          alert(`コピー失敗: ${err.message}`);
-        }
-      });
+
+    #[test]
+    fn tells_external_history_edits_from_its_own_writes() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let history_json = base.join("history.json");
+        assert!(HistoryStore::is_history_json(&history_json));
+        assert!(HistoryStore::is_history_json(
+            &base.join("History_20240102.json")
+        ));
+        assert!(!HistoryStore::is_history_json(
+            &base.join("history.json.bak")
+        ));
+        assert!(!HistoryStore::is_history_json(&base.join("History.html")));
+
+        store.append_history("in app").expect("append");
+        assert!(!store.take_external_change(&history_json));
+
+        let edited = fs::read_to_string(&history_json)
+            .expect("read history")
+            .replace("in app", "edited elsewhere");
+        fs::write(&history_json, edited).expect("edit");
+        assert!(store.take_external_change(&history_json));
+        assert!(!store.take_external_change(&history_json));
+
+        fs::remove_file(&history_json).expect("remove");
+        assert!(store.take_external_change(&history_json));
+        assert!(!store.take_external_change(&history_json));
+
+        fs::remove_dir_all(base).ok();
     }
-    for (const button of document.querySelectorAll(".image-copy-btn")) {
-      button.addEventListener("click", async () => {
-        const entry = button.closest(".entry");
-        if (!entry) return;
-        const imagePath = entry.dataset.selectedImage || "";
-        if (!imagePath) {
-          alert("コピー対象の画像がありません。");
-          return;
-        }
-        try {
-          await copyImageToClipboard(imagePath);
-          showImageCopyFeedback(button);
-        } catch (err) {
-          alert(`画像コピー失敗: ${err.message}`);
+
+    #[test]
+    fn encrypts_history_at_rest_and_serves_pages_decrypted() {
+        let base = fixture_base();
+        let cipher = Cipher::from_key(&[7; 32]);
+        let mut store =
+            HistoryStore::open(base.clone(), 5, Some(cipher.clone()), false).expect("create store");
+        let entry = store.append_history("secret prompt").expect("append");
+        let image = store
+            .append_image(&entry.id, "image.png", &encode_png(|x, _| x as u8))
+            .expect("upload");
+        assert!(base
+            .join(thumbnail_rel_path(&image).expect("thumb path"))
+            .exists());
+
+        assert!(store.set_encryption(true).expect("encrypt") >= 2);
+        let raw = fs::read(base.join("history.json")).expect("read history");
+        assert!(!raw.windows(6).any(|w| w == b"secret"));
+        assert!(is_encrypted(
+            &fs::read(base.join("history.json.bak")).expect("read backup")
+        ));
+        assert!(is_encrypted(
+            &fs::read(base.join(&image)).expect("read image")
+        ));
+        assert!(!base.join(THUMBS_DIR).exists());
+        let later = store
+            .append_history("another secret")
+            .expect("append encrypted");
+        assert_eq!(
+            store
+                .search(ExportRange::default(), "secret", 10)
+                .expect("search")
+                .total,
+            2
+        );
+        let (bytes, _) = store.read_image_blob(&image).expect("read image");
+        assert!(image::load_from_memory(&bytes).is_ok());
+
+        store.regenerate_html(8765).expect("render");
+        let stub = fs::read_to_string(base.join("History.html")).expect("read stub");
+        assert!(!stub.contains("secret"));
+        assert!(stub.contains("http://127.0.0.1:8765/pages/History.html"));
+        let page = store
+            .render_page("History.html", 8765)
+            .expect("render page")
+            .expect("page");
+        assert!(page.contains(&later.id));
+        assert!(store
+            .render_page("nothing.html", 8765)
+            .expect("render")
+            .is_none());
+
+        // Without the key the store refuses to open instead of resetting the history.
+        assert!(HistoryStore::new(base.clone(), 5).is_err());
+        assert!(
+            HistoryStore::open(base.clone(), 5, Some(Cipher::from_key(&[8; 32])), false).is_err()
+        );
+        assert!(is_encrypted(
+            &fs::read(base.join("history.json")).expect("read")
+        ));
+
+        store.set_encryption(false).expect("decrypt");
+        let plain = fs::read_to_string(base.join("history.json")).expect("read plain");
+        assert!(plain.contains("secret prompt"));
+        let store = HistoryStore::new(base.clone(), 5).expect("reopen plain");
+        assert_eq!(
+            store
+                .read_entries(&base.join("history.json"))
+                .expect("entries")
+                .len(),
+            2
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn encryption_seals_pre_migration_backups() {
+        let base = fixture_base();
+        fs::write(
+            base.join("history.json"),
+            r#"[{"id":"20260101_120000_0001","ts":"2026-01-01 12:00:00","prompt":"client secret","images":[]}]"#,
+        )
+        .expect("write legacy history");
+        let mut store =
+            HistoryStore::open(base.clone(), 5, Some(Cipher::from_key(&[7; 32])), false)
+                .expect("migrate store");
+        store.set_encryption(true).expect("encrypt");
+
+        let backups: Vec<_> = fs::read_dir(&base)
+            .expect("list base")
+            .flatten()
+            .map(|item| item.path())
+            .filter(|path| path.to_string_lossy().ends_with(".bak.json"))
+            .collect();
+        assert_eq!(backups.len(), 1, "the v0 file is backed up");
+        assert!(is_encrypted(&fs::read(&backups[0]).expect("read backup")));
+
+        store.set_encryption(false).expect("decrypt");
+        let plain = fs::read_to_string(&backups[0]).expect("read plain backup");
+        assert!(plain.contains("client secret"));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn splits_archive_days_into_linked_pages() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 10).expect("create store");
+        let ids: Vec<String> = (0..5)
+            .map(|index| {
+                store
+                    .append_history(&format!("archived {index}"))
+                    .expect("append")
+                    .id
+            })
+            .collect();
+        for id in &ids {
+            store.archive_entry(id).expect("archive");
         }
-      });
+        let date_key = &ids[0][..8];
+        let page = |number: usize| base.join(super::archive_page_name(date_key, number));
+
+        store.set_archive_page_size(2);
+        store.regenerate_html(8765).expect("render pages");
+        let first = fs::read_to_string(page(1)).expect("page 1");
+        let last = fs::read_to_string(page(3)).expect("page 3");
+        assert!(first.contains("archived 4") && first.contains("archived 3"));
+        assert!(!first.contains("archived 2"));
+        assert!(first.contains(&format!("href=\"History_{date_key}_p2.html\">次へ →")));
+        assert!(last.contains("archived 0"));
+        assert!(last.contains(&format!("href=\"History_{date_key}_p2.html\">← 前へ")));
+        let oldest = store
+            .search(ExportRange::default(), "archived 0", 10)
+            .expect("search");
+        assert_eq!(oldest.hits[0].page, format!("History_{date_key}_p3.html"));
+        let served = |name: String| store.render_page(&name, 8765).expect("render page");
+        assert_eq!(served(super::archive_page_name(date_key, 3)), Some(last));
+        assert!(served(super::archive_page_name(date_key, 4)).is_none());
+        assert!(served(format!("History_{date_key}_p1.html")).is_none());
+        let index = served(super::ARCHIVE_INDEX_HTML.to_string()).expect("index page");
+        assert_eq!(
+            Some(index),
+            fs::read_to_string(base.join(super::ARCHIVE_INDEX_HTML)).ok()
+        );
+
+        // Growing the page size removes pages that are no longer produced.
+        store.set_archive_page_size(0);
+        store.regenerate_html(8765).expect("render single page");
+        assert!(!page(2).exists() && !page(3).exists());
+        let single = fs::read_to_string(page(1)).expect("single page");
+        assert!(single.contains("archived 0") && !single.contains("page-nav\""));
+
+        fs::remove_dir_all(base).ok();
     }
-  </script>
-"#;
 
-#[cfg(test)]
-mod tests {
-    use super::{image_content_type, HistoryStore};
-    use serde_json::Value;
-    use std::fs;
-    use std::path::Path;
-    use std::sync::atomic::{AtomicU64, Ordering};
+    #[test]
+    fn restore_backup_merges_entries_by_id_and_unpacks_images() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let kept = store.append_history("kept prompt").expect("append");
+        let archived = store.append_history("archived prompt").expect("append");
+        let image_path = store
+            .append_image(&kept.id, "shot.png", b"png")
+            .expect("upload");
+        store.archive_entry(&archived.id).expect("archive");
+        let backup = store
+            .write_backup(std::io::Cursor::new(Vec::new()))
+            .expect("backup")
+            .into_inner();
+
+        let target_base = fixture_base();
+        let mut target = HistoryStore::new(target_base.clone(), 5).expect("create target");
+        let summary = target.restore_backup(&backup).expect("restore");
+        assert_eq!(
+            (summary.restored, summary.duplicates, summary.images),
+            (2, 0, 1)
+        );
+        assert_eq!(read_entries(&target_base.join("history.json")).len(), 1);
+        let archive_name = format!("History_{}.json", &archived.id[..8]);
+        assert_eq!(read_entries(&target_base.join(&archive_name)).len(), 1);
+        assert!(target_base.join(&image_path).is_file());
 
-    static NEXT_FIXTURE_ID: AtomicU64 = AtomicU64::new(1);
+        let again = target.restore_backup(&backup).expect("restore twice");
+        assert_eq!((again.restored, again.duplicates, again.images), (0, 2, 0));
+        assert!(target.restore_backup(b"not a zip").is_err());
 
-    fn fixture_base() -> std::path::PathBuf {
-        let mut base = std::env::temp_dir();
-        let sequence = NEXT_FIXTURE_ID.fetch_add(1, Ordering::Relaxed);
-        base.push(format!(
-            "ipg_history_store_test_{}_{}",
-            std::process::id(),
-            sequence
-        ));
-        let _ = fs::remove_dir_all(&base);
-        fs::create_dir_all(&base).expect("mkdir fixture");
-        base
+        fs::remove_dir_all(base).ok();
+        fs::remove_dir_all(target_base).ok();
     }
 
-    fn read_entries(path: &Path) -> Vec<Value> {
-        let raw = fs::read_to_string(path).expect("read history");
-        serde_json::from_str::<Value>(&raw)
-            .expect("parse history")
-            .as_array()
-            .expect("history array")
-            .to_vec()
+    #[test]
+    fn staged_backup_files_keep_the_history_as_it_was_listed() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        store.append_history("listed").expect("append");
+        let staging = crate::backup::StagingDir::create("history_store_test").expect("staging");
+        let files = store
+            .backup_files(Some(staging.path()))
+            .expect("backup files");
+        store.append_history("written after").expect("append later");
+
+        let history = files
+            .iter()
+            .find(|file| file.name == "history.json")
+            .expect("history listed");
+        assert!(history.path.starts_with(staging.path()));
+        let staged = read_entries(&history.path);
+        assert_eq!(staged.len(), 1);
+        assert_eq!(staged[0]["prompt"], "listed");
+
+        let dir = staging.path().to_path_buf();
+        drop(staging);
+        assert!(!dir.exists());
+        fs::remove_dir_all(base).ok();
     }
 
-    fn find_entry<'a>(
-        entries: &'a [Value],
-        history_id: &str,
-    ) -> &'a serde_json::Map<String, Value> {
-        entries
+    #[test]
+    fn housekeeping_plan_is_dry_run_and_apply_tags_and_archives() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let logo = store.append_history("flat logo mark").expect("append logo");
+        store.append_history("portrait").expect("append portrait");
+
+        let rules = vec![
+            HousekeepingRule {
+                name: "branding".to_string(),
+                contains: Some("logo".to_string()),
+                has_image: None,
+                older_than_days: None,
+                action: RuleAction::Tag("project:branding".to_string()),
+            },
+            HousekeepingRule {
+                name: "stale".to_string(),
+                contains: None,
+                has_image: Some(false),
+                older_than_days: Some(90),
+                action: RuleAction::Archive,
+            },
+        ];
+        let later = Local::now().naive_local() + TimeDelta::days(91);
+
+        let planned = store.plan_housekeeping(&rules, later).expect("plan");
+        assert_eq!(planned.len(), 3);
+        assert_eq!(read_entries(&base.join("history.json")).len(), 2);
+
+        let applied = store.apply_housekeeping(&rules, later).expect("apply");
+        assert_eq!(applied.len(), 3);
+        assert!(read_entries(&base.join("history.json")).is_empty());
+        let archived = read_entries(&base.join(format!("History_{}.json", &logo.id[..8])));
+        let tagged = archived
             .iter()
-            .find_map(|entry| {
-                let obj = entry.as_object()?;
-                let id = obj.get("id").and_then(Value::as_str).unwrap_or_default();
-                if id == history_id {
-                    Some(obj)
-                } else {
-                    None
-                }
-            })
-            .expect("entry exists")
+            .find(|entry| entry.get("id").and_then(Value::as_str) == Some(logo.id.as_str()))
+            .expect("tagged entry");
+        assert_eq!(tagged["tags"], serde_json::json!(["project:branding"]));
+
+        let again = store.plan_housekeeping(&rules, later).expect("plan again");
+        assert!(again.is_empty());
+
+        fs::remove_dir_all(base).ok();
     }
 
     #[test]
-    fn append_and_rotate_entries() {
+    fn housekeeping_trash_moves_unlocked_entries_to_trash() {
         let base = fixture_base();
-        let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
-
-        store.append_history("a").expect("append a");
-        store.append_history("b").expect("append b");
-        store.append_history("c").expect("append c");
-
-        let raw = fs::read_to_string(base.join("history.json")).expect("read active history");
-        let values: serde_json::Value = serde_json::from_str(&raw).expect("parse active history");
-        assert_eq!(values.as_array().expect("active array").len(), 2);
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let stale = store.append_history("old draft").expect("append stale");
+        let kept = store.append_history("keeper").expect("append kept");
+        store.set_locked(&kept.id, true).expect("lock");
+
+        let rules = vec![HousekeepingRule {
+            name: "cleanup".to_string(),
+            contains: None,
+            has_image: Some(false),
+            older_than_days: Some(90),
+            action: RuleAction::Trash,
+        }];
+        let later = Local::now().naive_local() + TimeDelta::days(91);
+
+        let applied = store.apply_housekeeping(&rules, later).expect("apply");
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].history_id, stale.id);
+        let remaining = read_entries(&base.join("history.json"));
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0]["id"], kept.id.as_str());
+
+        let trash = store.trash_entries().expect("trash");
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].entry.id, stale.id);
+        assert_eq!(trash[0].origin, "history.json");
+        assert!(store
+            .plan_housekeeping(&rules, later)
+            .expect("plan")
+            .is_empty());
 
         fs::remove_dir_all(base).ok();
     }
 
     #[test]
-    fn delete_history_removes_active_entry() {
+    fn update_history_note_keeps_prompt_and_round_trips_through_export() {
         let base = fixture_base();
         let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("a cat").expect("append");
 
-        let target = store.append_history("delete target").expect("append target");
-        store.append_history("keep").expect("append keep");
-
-        assert!(
-            store.delete_history(&target.id).expect("delete active"),
-            "active history should be deleted"
-        );
-
+        assert!(store
+            .update_history_note(&entry.id, "  model: sdxl, seed 42  ")
+            .expect("update note"));
         let entries = read_entries(&base.join("history.json"));
-        assert!(
-            entries.iter().all(|entry| {
-                entry
-                    .get("id")
-                    .and_then(Value::as_str)
-                    .unwrap_or_default()
-                    != target.id
-            }),
-            "deleted entry should not remain in active history"
-        );
+        let saved = find_entry(&entries, &entry.id);
+        assert_eq!(saved["note"], "model: sdxl, seed 42");
+        assert_eq!(saved["prompt"], "a cat");
+
+        let csv = store
+            .export(ExportFormat::Csv, ExportRange::default())
+            .expect("export csv");
+        let parsed = parse_import_file("notes.csv", csv.as_bytes()).expect("parse csv");
+        assert_eq!(parsed[0].note, "model: sdxl, seed 42");
+
+        store.set_locked(&entry.id, true).expect("lock");
+        assert!(store.update_history_note(&entry.id, "changed").is_err());
+        assert!(!store
+            .update_history_note("missing", "x")
+            .expect("missing id"));
 
         fs::remove_dir_all(base).ok();
     }
 
     #[test]
-    fn delete_history_removes_archive_entry() {
+    fn mutations_are_recorded_in_audit_log() {
         let base = fixture_base();
-        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
-
-        let archived = store
-            .append_history("archive delete target")
-            .expect("append archived");
-        store.append_history("active latest").expect("append active");
-        let archive_json = base.join(format!("History_{}.json", &archived.id[..8]));
-        assert!(archive_json.exists(), "archive file should exist");
-
-        assert!(
-            store
-                .delete_history(&archived.id)
-                .expect("delete archive entry"),
-            "archive history should be deleted"
-        );
-
-        let archive_entries = read_entries(&archive_json);
-        assert!(
-            archive_entries.iter().all(|entry| {
-                entry
-                    .get("id")
-                    .and_then(Value::as_str)
-                    .unwrap_or_default()
-                    != archived.id
-            }),
-            "deleted entry should not remain in archive history"
-        );
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("before").expect("append");
+        store
+            .update_history_prompt(&entry.id, "after")
+            .expect("update");
+        store
+            .append_image(&entry.id, "shot.png", b"png")
+            .expect("upload");
+        store.delete_history(&entry.id).expect("delete");
+
+        let records = store.audit_records(10).expect("audit records");
+        let actions: Vec<&str> = records.iter().map(|r| r.action.as_str()).collect();
+        assert_eq!(actions, vec!["delete", "upload", "update", "create"]);
+        assert_eq!(records[2].before, records[3].after);
+        assert_ne!(records[2].before, records[2].after);
+        assert!(records[1].detail.starts_with("images/"));
+        assert!(records
+            .iter()
+            .all(|r| r.who == "local" && r.history_id == entry.id));
 
         fs::remove_dir_all(base).ok();
     }
 
     #[test]
-    fn delete_history_returns_false_for_missing_history_id() {
+    fn audit_log_failure_does_not_fail_a_committed_change() {
         let base = fixture_base();
-        let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
-        store.append_history("exists").expect("append");
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        // A folder in the log's place makes every append fail.
+        fs::create_dir_all(base.join("audit.log")).expect("block audit log");
 
-        let deleted = store
-            .delete_history("missing-id")
-            .expect("missing id should not error");
-        assert!(!deleted);
+        let entry = store.append_history("kept").expect("append");
+        assert!(store.delete_history(&entry.id).expect("delete"));
+        assert!(store.undo().expect("undo").is_some());
+        assert!(store.find_entry(&entry.id).expect("find").is_some());
 
         fs::remove_dir_all(base).ok();
     }
 
     #[test]
-    fn regenerate_html_includes_delete_button_in_archive_page() {
+    fn set_rating_persists_stars_and_renders_sort_controls() {
         let base = fixture_base();
-        let mut store = HistoryStore::new(base.clone(), 1).expect("create store");
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("best prompt").expect("append");
 
-        let archived = store
-            .append_history("archive delete available")
-            .expect("append archived");
-        store.append_history("active latest").expect("append active");
+        assert!(store.set_rating(&entry.id, 4).expect("rate"));
+        assert!(store.set_rating(&entry.id, 6).is_err());
+        assert!(!store.set_rating("missing", 3).expect("missing id"));
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(find_entry(&entries, &entry.id)["rating"], 4);
 
         store.regenerate_html(8765).expect("regenerate html");
+        let html = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(html.contains("data-rating=\"4\""));
+        assert!(html.contains("data-sort=\"rating\""));
 
-        let archive_html_path = base.join(format!("History_{}.html", &archived.id[..8]));
-        let archive_html = fs::read_to_string(&archive_html_path).expect("read archive html");
-        assert!(
-            archive_html.contains("<button class=\"btn delete-btn\">削除</button>"),
-            "archive html should include delete button markup"
-        );
+        store.set_rating(&entry.id, 0).expect("clear rating");
+        let entries = read_entries(&base.join("history.json"));
+        assert!(find_entry(&entries, &entry.id).get("rating").is_none());
 
         fs::remove_dir_all(base).ok();
     }
@@ -1689,6 +7425,205 @@ mod tests {
         fs::remove_dir_all(base).ok();
     }
 
+    #[test]
+    fn seed_and_delete_samples_round_trip() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        assert!(store.is_fresh());
+        store.append_history("user prompt").expect("append");
+
+        assert_eq!(store.seed_samples().expect("seed samples"), 3);
+        assert!(base.join("images/samples/sample_01.png").exists());
+        assert_eq!(read_entries(&base.join("history.json")).len(), 4);
+
+        assert_eq!(store.delete_samples().expect("delete samples"), 3);
+        let remaining = read_entries(&base.join("history.json"));
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].get("sample").is_none());
+        assert!(!base.join("images/samples/sample_01.png").exists());
+
+        let reopened = HistoryStore::new(base.clone(), 5).expect("reopen store");
+        assert!(!reopened.is_fresh());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn append_image_keeps_gallery_up_to_limit_and_remove_image_detaches() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        store.set_max_images_per_entry(2);
+        let entry = store.append_history("gallery").expect("append");
+
+        let first = store
+            .append_image(&entry.id, "a.png", b"a")
+            .expect("append first");
+        let second = store
+            .append_image(&entry.id, "b.png", b"b")
+            .expect("append second");
+        let third = store
+            .append_image(&entry.id, "c.png", b"c")
+            .expect("append third");
+
+        let entries = read_entries(&base.join("history.json"));
+        let images = find_entry(&entries, &entry.id)
+            .get("images")
+            .cloned()
+            .expect("images");
+        assert_eq!(images, serde_json::json!([second, third]));
+        assert!(base.join(&first).exists(), "evicted file stays on disk");
+
+        assert_eq!(
+            store.remove_image(&entry.id, &second).expect("remove"),
+            ImageRemoval::Detached
+        );
+        assert_eq!(
+            store
+                .remove_image(&entry.id, &first)
+                .expect("remove missing"),
+            ImageRemoval::NotAttached
+        );
+        assert!(base.join(&second).exists(), "detached file stays on disk");
+        let entries = read_entries(&base.join("history.json"));
+        let images = find_entry(&entries, &entry.id)
+            .get("images")
+            .cloned()
+            .expect("images");
+        assert_eq!(images, serde_json::json!([third]));
+
+        let fourth = store
+            .append_image(&entry.id, "d.png", b"d")
+            .expect("append fourth");
+        store.set_max_images_per_entry(1);
+        assert!(store.set_rating(&entry.id, 3).expect("rewrite entry"));
+        let entries = read_entries(&base.join("history.json"));
+        let images = find_entry(&entries, &entry.id)
+            .get("images")
+            .cloned()
+            .expect("images");
+        assert_eq!(
+            images,
+            serde_json::json!([third, fourth]),
+            "a lower limit does not drop stored images on read"
+        );
+        let fifth = store
+            .append_image(&entry.id, "e.png", b"e")
+            .expect("append fifth");
+        let entries = read_entries(&base.join("history.json"));
+        let images = find_entry(&entries, &entry.id)
+            .get("images")
+            .cloned()
+            .expect("images");
+        assert_eq!(images, serde_json::json!([fifth]));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn legacy_array_history_is_migrated_with_backup() {
+        let base = fixture_base();
+        fs::write(
+            base.join("history.json"),
+            r#"[{"id":"20260101_120000_0001","ts":"2026-01-01 12:00:00","prompt":"legacy","images":[]}]"#,
+        )
+        .expect("write legacy history");
+        fs::write(
+            base.join("History_20251231.json"),
+            r#"[{"id":"20251231_120000_0001","ts":"2025-12-31 12:00:00","prompt":"old","images":[]}]"#,
+        )
+        .expect("write legacy archive");
+
+        HistoryStore::new(base.clone(), 5).expect("create store");
+
+        for name in ["history.json", "History_20251231.json"] {
+            let raw = fs::read_to_string(base.join(name)).expect("read migrated");
+            let doc: Value = serde_json::from_str(&raw).expect("parse migrated");
+            assert_eq!(doc.get("schema_version").and_then(Value::as_u64), Some(1));
+            assert_eq!(read_entries(&base.join(name)).len(), 1);
+        }
+        let backups = fs::read_dir(&base)
+            .expect("list base")
+            .filter_map(|item| item.ok())
+            .filter(|item| item.file_name().to_string_lossy().contains(".v0."))
+            .count();
+        assert_eq!(backups, 2, "each migrated file should be backed up");
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn read_only_store_migrates_legacy_history_in_memory() {
+        let base = fixture_base();
+        let legacy = r#"[{"id":"20260101_120000_0001","ts":"2026-01-01 12:00:00","prompt":"legacy","images":[]}]"#;
+        fs::write(base.join("history.json"), legacy).expect("write legacy history");
+
+        let store = HistoryStore::open(base.clone(), 5, None, true).expect("open read-only");
+        assert!(store
+            .find_entry("20260101_120000_0001")
+            .expect("find")
+            .is_some());
+        assert_eq!(
+            fs::read_to_string(base.join("history.json")).expect("read history"),
+            legacy
+        );
+        let names: Vec<String> = fs::read_dir(&base)
+            .expect("list base")
+            .filter_map(|item| item.ok())
+            .map(|item| item.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["history.json".to_string()]);
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn newer_history_schema_is_rejected_without_reset() {
+        let base = fixture_base();
+        let newer = r#"{"schema_version":99,"entries":[]}"#;
+        fs::write(base.join("history.json"), newer).expect("write newer history");
+
+        assert!(HistoryStore::new(base.clone(), 5).is_err());
+        assert_eq!(
+            fs::read_to_string(base.join("history.json")).expect("read history"),
+            newer
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn locked_entry_rejects_mutations_until_unlocked() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("final").expect("append");
+
+        assert!(store.set_locked(&entry.id, true).expect("lock"));
+        for err in [
+            store
+                .update_history_prompt(&entry.id, "drift")
+                .expect_err("update"),
+            store.delete_history(&entry.id).expect_err("delete"),
+            store
+                .append_image(&entry.id, "a.png", b"a")
+                .expect_err("append image"),
+        ] {
+            assert!(err.to_string().contains("locked"));
+        }
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(
+            find_entry(&entries, &entry.id).get("locked"),
+            Some(&Value::Bool(true))
+        );
+
+        assert!(store.set_locked(&entry.id, false).expect("unlock"));
+        assert!(store
+            .update_history_prompt(&entry.id, "revised")
+            .expect("update after unlock"));
+        assert!(!store.set_locked("missing-id", true).expect("missing id"));
+
+        fs::remove_dir_all(base).ok();
+    }
+
     #[test]
     fn image_content_type_returns_gif() {
         assert_eq!(
@@ -1697,13 +7632,385 @@ mod tests {
         );
     }
 
+    #[test]
+    fn batch_uploads_attach_in_order_or_not_at_all() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("batch").expect("append");
+
+        let bad = vec![
+            ("first.png".to_string(), b"one".to_vec()),
+            ("notes.txt".to_string(), b"two".to_vec()),
+        ];
+        let err = store
+            .append_images(&entry.id, &bad)
+            .expect_err("text file rejected");
+        assert!(err.to_string().starts_with("notes.txt: "));
+        let untouched = store.find_entry(&entry.id).expect("find").expect("entry");
+        assert!(untouched.images.is_empty());
+
+        let files = vec![
+            ("first.png".to_string(), b"one".to_vec()),
+            ("second.jpg".to_string(), b"two".to_vec()),
+        ];
+        let paths = store.append_images(&entry.id, &files).expect("attach");
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with(".png") && paths[1].ends_with(".jpg"));
+        let attached = store.find_entry(&entry.id).expect("find").expect("entry");
+        assert_eq!(attached.images, paths);
+
+        // One undo takes the whole batch back off.
+        store.undo().expect("undo").expect("undone");
+        let undone = store.find_entry(&entry.id).expect("find").expect("entry");
+        assert!(undone.images.is_empty());
+        assert!(store.last_undo().is_none());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn remove_image_deletes_unreferenced_files_when_enabled() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        store.set_delete_image_files(true);
+        let first = store.append_history("first").expect("append first");
+        let second = store.append_history("second").expect("append second");
+        let own = store
+            .append_image(&first.id, "own.png", b"own")
+            .expect("upload own");
+        let shared = store
+            .append_image(&first.id, "shared.png", b"shared")
+            .expect("upload shared");
+        let mut entries = read_entries(&base.join("history.json"));
+        for entry in entries.iter_mut() {
+            if entry["id"] == second.id.as_str() {
+                entry["images"] = serde_json::json!([shared.clone()]);
+            }
+        }
+        fs::write(
+            base.join("history.json"),
+            serde_json::json!({ "schema_version": 1, "entries": entries }).to_string(),
+        )
+        .expect("share image");
+        store.image_hashes.insert(&own, 1).expect("index own");
+        store.image_hashes.insert(&shared, 2).expect("index shared");
+
+        assert_eq!(
+            store
+                .remove_image(&first.id, &shared)
+                .expect("remove shared"),
+            ImageRemoval::Detached
+        );
+        assert!(base.join(&shared).exists(), "still used by another entry");
+        assert_eq!(
+            store.remove_image(&first.id, &own).expect("remove own"),
+            ImageRemoval::FileDeleted
+        );
+        assert!(!base.join(&own).exists());
+        let hashes = store.image_hashes.load().expect("load hashes");
+        assert!(!hashes.contains_key(&own) && hashes.contains_key(&shared));
+        // Deleting a file is not undoable, and undoing the earlier detach must not
+        // bring back a reference to the deleted file.
+        assert!(store.undo().expect("undo").is_some());
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(
+            find_entry(&entries, &first.id)["images"],
+            serde_json::json!([shared])
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn logs_the_copy_time_score_with_the_entry() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("[被写体]：猫").expect("append");
+        assert!(store.set_score(&entry.id, 87).expect("set score"));
+        assert!(store.set_score(&entry.id, 101).is_err());
+        assert!(!store.set_score("missing", 50).expect("missing entry"));
+
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(find_entry(&entries, &entry.id)["score"], 87);
+        store.regenerate_html(8765).expect("render");
+        let rendered = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(rendered.contains(">87点</span>"));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn stores_translation_next_to_prompt() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("[被写体]：猫").expect("append");
+        assert!(store
+            .set_translation(&entry.id, " [Subject]: cat \n")
+            .expect("set translation"));
+        assert!(!store
+            .set_translation("missing", "x")
+            .expect("missing entry"));
+
+        let entries = read_entries(&base.join("history.json"));
+        assert_eq!(
+            find_entry(&entries, &entry.id)["translation"],
+            "[Subject]: cat"
+        );
+        store.regenerate_html(8765).expect("render");
+        let rendered = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(rendered.contains("[Subject]: cat</pre>"));
+        let markdown = store
+            .export(ExportFormat::Markdown, ExportRange::default())
+            .expect("export");
+        assert!(markdown.contains("```text\n[Subject]: cat\n```"));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn reads_generation_metadata_from_uploaded_png() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("[被写体]：猫").expect("append");
+        let png = crate::png_text::embed_parameters(
+            &encode_png(|x, _| x as u8),
+            "a cat <b>\nNegative prompt: blurry\nSteps: 20, Seed: 42",
+        )
+        .expect("embed");
+        let image_path = store
+            .append_image(&entry.id, "webui.png", &png)
+            .expect("upload");
+        store
+            .append_image(&entry.id, "plain.png", &encode_png(|_, y| y as u8))
+            .expect("upload without metadata");
+
+        let entries = read_entries(&base.join("history.json"));
+        let metadata = &find_entry(&entries, &entry.id)["image_metadata"];
+        assert_eq!(metadata.as_object().map(|map| map.len()), Some(1));
+        assert_eq!(metadata[&image_path]["source"], "a1111");
+        assert_eq!(metadata[&image_path]["prompt"], "a cat <b>");
+        assert_eq!(metadata[&image_path]["negative_prompt"], "blurry");
+        assert_eq!(metadata[&image_path]["settings"], "Steps: 20, Seed: 42");
+
+        store.regenerate_html(8765).expect("render");
+        let rendered = fs::read_to_string(base.join("History.html")).expect("read html");
+        assert!(rendered.contains("生成情報（Stable Diffusion web UI）"));
+        assert!(rendered.contains("<dd>a cat &lt;b&gt;</dd>"));
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn export_rerendered_uses_selections_and_keeps_originals() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let legacy = store.append_history("old prompt").expect("append legacy");
+        let structured = store.append_history("[被写体]：robot").expect("append");
+        let selections = [("subject".to_string(), "robot".to_string())]
+            .into_iter()
+            .collect();
+        assert!(store
+            .set_selections(&structured.id, selections)
+            .expect("set selections"));
+        assert!(!store
+            .set_selections("missing", Default::default())
+            .expect("missing entry"));
+
+        let render = |entry: &super::HistoryEntry| {
+            let subject = entry.selections.get("subject")?;
+            Some(format!("{subject} --v 6"))
+        };
+        let export = store
+            .export_rerendered(ExportFormat::Csv, ExportRange::default(), &[], render)
+            .expect("rerender");
+        assert_eq!((export.rendered, export.skipped), (1, 1));
+        assert!(export.content.contains("robot --v 6"));
+        assert!(!export.content.contains("old prompt"));
+
+        let only_legacy = store
+            .export_rerendered(
+                ExportFormat::Markdown,
+                ExportRange::default(),
+                std::slice::from_ref(&legacy.id),
+                render,
+            )
+            .expect("rerender selected ids");
+        assert_eq!((only_legacy.rendered, only_legacy.skipped), (0, 1));
+
+        let entries = read_entries(&base.join("history.json"));
+        let stored = find_entry(&entries, &structured.id);
+        assert_eq!(
+            stored.get("prompt").and_then(Value::as_str),
+            Some("[被写体]：robot")
+        );
+        assert_eq!(stored["selections"]["subject"], "robot");
+        assert!(find_entry(&entries, &legacy.id).get("selections").is_none());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    fn encode_png(shade: impl Fn(u32, u32) -> u8) -> Vec<u8> {
+        let image = image::ImageBuffer::from_fn(128, 128, |x, y| image::Luma([shade(x, y)]));
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .expect("encode png");
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn sized_thumbnail_is_cached_and_served_when_the_cache_cannot_be_written() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let entry = store.append_history("thumbs").expect("append");
+        let image = store
+            .append_image(&entry.id, "shot.png", &encode_png(|x, _| x as u8))
+            .expect("upload");
+
+        let first = store
+            .sized_thumbnail(&image, 64)
+            .expect("job")
+            .run()
+            .expect("thumbnail");
+        let cached = base.join(super::sized_thumbnail_rel_path(&image, 64).expect("rel"));
+        assert_eq!(fs::read(&cached).expect("cached"), first);
+
+        // A file where the width folder should be makes the cache write fail.
+        fs::write(base.join(THUMBS_DIR).join("w32"), b"blocker").expect("block cache");
+        let served = store
+            .sized_thumbnail(&image, 32)
+            .expect("job")
+            .run()
+            .expect("thumbnail without cache");
+        assert!(!served.is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn similar_images_finds_visually_close_attachments_across_entries() {
+        let base = fixture_base();
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        let gradient = |x: u32, y: u32| ((x * 2 + y) % 256) as u8;
+        let look = store.append_history("sunset look").expect("append look");
+        let other = store.append_history("checker look").expect("append other");
+        let look_image = store
+            .append_image(&look.id, "look.png", &encode_png(gradient))
+            .expect("upload look");
+        store
+            .append_image(
+                &other.id,
+                "other.png",
+                &encode_png(|x, y| if (x / 16 + y / 16) % 2 == 0 { 230 } else { 20 }),
+            )
+            .expect("upload other");
+        store
+            .append_image(&other.id, "broken.png", b"png")
+            .expect("undecodable upload still attaches");
+        // Images attached before hashing existed are indexed on first search.
+        fs::remove_file(base.join("image_hashes.json")).expect("drop index");
+
+        let screenshot =
+            super::perceptual_hash(&encode_png(|x, y| gradient(x, y).saturating_add(15)))
+                .expect("hash screenshot");
+        let results = store
+            .similar_images(screenshot, None, 10, 20)
+            .expect("search");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].history_id, look.id);
+        assert_eq!(results[0].image_path, look_image);
+        assert_eq!(results[0].prompt, "sunset look");
+        assert!(base.join("image_hashes.json").exists(), "index rebuilt");
+
+        let by_path = store.image_hash(&look_image).expect("hash by path");
+        assert!(store
+            .similar_images(by_path, Some(&look_image), 10, 20)
+            .expect("search excluding self")
+            .is_empty());
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn retention_exports_expired_archives_before_removing_them() {
+        let base = fixture_base();
+        let export_dir = base.join("retention");
+        let mut store = HistoryStore::new(base.clone(), 5).expect("create store");
+        fs::create_dir_all(base.join("images")).expect("mkdir images");
+        fs::write(base.join("images/old.png"), b"png").expect("write image");
+        let json = r#"[{"id":"20200105_010203_0001","ts":"2020-01-05 01:02:03","prompt":"old","images":["images/old.png"]},
+               {"id":"20240701_010203_0001","ts":"2024-07-01 01:02:03","prompt":"recent"}]"#;
+        let parsed = parse_import_file("history.json", json.as_bytes()).expect("parse json");
+        store.import_entries(parsed).expect("import");
+        let today = NaiveDate::from_ymd_opt(2025, 6, 15).expect("date");
+
+        assert!(store.enforce_retention(today).expect("disabled").is_none());
+        store.set_retention(12, Some(export_dir.clone()));
+        let export = store
+            .enforce_retention(today)
+            .expect("enforce")
+            .expect("something expired");
+        assert_eq!(
+            (export.archives, export.entries, export.images_deleted),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            export.zip_path,
+            export_dir.join("History_20200105-20200105.zip")
+        );
+
+        let contents =
+            read_backup_zip(&fs::read(&export.zip_path).expect("read zip")).expect("parse zip");
+        assert_eq!(contents.history_files[0].0, "History_20200105.json");
+        assert_eq!(contents.images.len(), 1);
+        assert!(!base.join("History_20200105.json").exists());
+        assert!(!base.join("images/old.png").exists());
+        assert!(base.join("History_20240701.json").exists());
+        assert_eq!(
+            store.audit_records(1).expect("audit")[0].action,
+            "archive_retention"
+        );
+        assert!(store
+            .enforce_retention(today)
+            .expect("nothing left")
+            .is_none());
+
+        // The same archive imported again keeps the first export.
+        let again =
+            r#"[{"id":"20200105_010203_0002","ts":"2020-01-05 01:02:03","prompt":"again"}]"#;
+        let parsed = parse_import_file("history.json", again.as_bytes()).expect("parse again");
+        store.import_entries(parsed).expect("import again");
+        let second = store
+            .enforce_retention(today)
+            .expect("enforce again")
+            .expect("expired again");
+        assert_eq!(
+            second.zip_path,
+            export_dir.join("History_20200105-20200105_2.zip")
+        );
+        assert!(export.zip_path.is_file());
+
+        fs::remove_dir_all(base).ok();
+    }
+
     #[test]
     fn interactive_html_uses_png_clipboard_copy_and_accepts_gif_upload() {
         let base = fixture_base();
         let mut store = HistoryStore::new(base.clone(), 2).expect("create store");
         let entry = store.append_history("with image slot").expect("append");
         let entries = vec![entry];
-        let html = store.build_history_html(&entries, "Prompt History", true, true, 8765, &[]);
+        let html = store.build_history_html(
+            &entries,
+            "Prompt History",
+            true,
+            true,
+            8765,
+            &[],
+            None,
+            &[],
+            &[],
+            None,
+        );
 
         assert!(
             html.contains("accept=\".png,.jpg,.jpeg,.webp,.gif\""),
@@ -1717,6 +8024,11 @@ mod tests {
             html.contains("new ClipboardItem({ \"image/png\": pngBlob })"),
             "interactive html should copy images as PNG"
         );
+        assert!(
+            html.contains("/upload-clipboard?history_id=")
+                && html.contains("document.addEventListener(\"paste\""),
+            "interactive html should paste clipboard images into the hovered dropzone"
+        );
 
         fs::remove_dir_all(base).ok();
     }
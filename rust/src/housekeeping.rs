@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, TimeDelta};
+use html_escape::encode_text;
+use serde::Serialize;
+use toml::Value;
+
+use crate::history_store::HistoryEntry;
+
+/// How often the maintenance scheduler re-evaluates `[[housekeeping]]` rules.
+pub const HOUSEKEEPING_INTERVAL_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum RuleAction {
+    Tag(String),
+    Archive,
+    Lock,
+    Trash,
+}
+
+impl RuleAction {
+    pub fn label(&self) -> String {
+        match self {
+            Self::Tag(tag) => format!("タグ付け: {tag}"),
+            Self::Archive => "アーカイブ".to_string(),
+            Self::Lock => "ロック".to_string(),
+            Self::Trash => "ゴミ箱へ移動".to_string(),
+        }
+    }
+}
+
+/// One `[[housekeeping]]` table; every condition that is set must match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HousekeepingRule {
+    pub name: String,
+    pub contains: Option<String>,
+    pub has_image: Option<bool>,
+    pub older_than_days: Option<i64>,
+    pub action: RuleAction,
+}
+
+impl HousekeepingRule {
+    pub fn from_toml(index: usize, table: &Value) -> Result<Self> {
+        let text = |key: &str| {
+            table
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(ToOwned::to_owned)
+        };
+        let name = text("name").unwrap_or_else(|| format!("rule {}", index + 1));
+
+        let action = match text("action").as_deref() {
+            Some("tag") => RuleAction::Tag(
+                text("tag").ok_or_else(|| anyhow!("rule `{name}` needs a `tag` value"))?,
+            ),
+            Some("archive") => RuleAction::Archive,
+            Some("lock") => RuleAction::Lock,
+            Some("trash") => RuleAction::Trash,
+            Some(other) => return Err(anyhow!("rule `{name}` has unknown action `{other}`")),
+            None => return Err(anyhow!("rule `{name}` has no action")),
+        };
+
+        let older_than_days = match table.get("older_than_days") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .as_integer()
+                    .filter(|v| *v >= 0)
+                    .ok_or_else(|| anyhow!("rule `{name}` has invalid older_than_days"))?,
+            ),
+        };
+        let rule = Self {
+            contains: text("contains"),
+            has_image: table.get("has_image").and_then(Value::as_bool),
+            older_than_days,
+            action,
+            name,
+        };
+        if rule.contains.is_none() && rule.has_image.is_none() && rule.older_than_days.is_none() {
+            return Err(anyhow!("rule `{}` has no conditions", rule.name));
+        }
+        Ok(rule)
+    }
+
+    pub fn matches(&self, entry: &HistoryEntry, now: NaiveDateTime) -> bool {
+        if let Some(needle) = &self.contains {
+            if !entry.prompt.to_lowercase().contains(&needle.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(has_image) = self.has_image {
+            if entry.images.is_empty() == has_image {
+                return false;
+            }
+        }
+        if let Some(days) = self.older_than_days {
+            let Ok(ts) = NaiveDateTime::parse_from_str(&entry.ts, "%Y-%m-%d %H:%M:%S") else {
+                return false;
+            };
+            if now - ts < TimeDelta::days(days) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedAction {
+    pub history_id: String,
+    pub ts: String,
+    pub prompt: String,
+    pub rule: String,
+    pub action: RuleAction,
+}
+
+pub fn build_preview_html(
+    rules: &[HousekeepingRule],
+    planned: &[PlannedAction],
+    errors: &[String],
+) -> String {
+    let mut rows = String::new();
+    for item in planned {
+        let excerpt: String = item.prompt.chars().take(80).collect();
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            encode_text(&item.ts),
+            encode_text(&excerpt),
+            encode_text(&item.rule),
+            encode_text(&item.action.label())
+        ));
+    }
+
+    let mut rule_list = String::new();
+    for rule in rules {
+        rule_list.push_str(&format!(
+            "<li>{} → {}</li>",
+            encode_text(&rule.name),
+            encode_text(&rule.action.label())
+        ));
+    }
+    for error in errors {
+        rule_list.push_str(&format!("<li class=\"error\">{}</li>", encode_text(error)));
+    }
+
+    let body = if rules.is_empty() && errors.is_empty() {
+        "<p>`config.txt` に `[[housekeeping]]` ルールがありません。</p>".to_string()
+    } else if planned.is_empty() {
+        format!("<ul>{rule_list}</ul><p>現在、ルールに該当する履歴はありません。</p>")
+    } else {
+        format!(
+            "<ul>{rule_list}</ul><p>次回の整理で以下の {} 件が変更されます。</p><table><thead><tr><th>日時</th><th>プロンプト</th><th>ルール</th><th>操作</th></tr></thead><tbody>{rows}</tbody></table><button id=\"run\">今すぐ実行</button>",
+            planned.len()
+        )
+    };
+
+    format!(
+        r#"<!doctype html>
+<html lang="ja">
+<head>
+  <meta charset="utf-8" />
+  <title>履歴の整理ルール（プレビュー）</title>
+  <style>
+    body {{ margin: 24px; font-family: "Yu Gothic UI", sans-serif; font-size: 14px; color: #1e1e1e; background: #f7f5ec; }}
+    table {{ border-collapse: collapse; width: 100%; background: #fff; }}
+    th, td {{ border: 1px solid #1f2a44; padding: 4px 8px; text-align: left; vertical-align: top; }}
+    .error {{ color: #cb4b16; }}
+    button {{ margin-top: 12px; padding: 6px 12px; border: 2px solid #1f2a44; background: #fff; cursor: pointer; }}
+  </style>
+</head>
+<body>
+  <h1>履歴の整理ルール（プレビュー）</h1>
+  {body}
+  <script>
+    const run = document.getElementById("run");
+    if (run) {{
+      run.addEventListener("click", async () => {{
+        const res = await fetch("/history/housekeeping/run", {{ method: "POST" }});
+        const data = await res.json().catch(() => ({{}}));
+        if (!res.ok || !data.ok) {{
+          alert(`整理に失敗しました: ${{data.error || res.status}}`);
+          return;
+        }}
+        location.reload();
+      }});
+    }}
+  </script>
+</body>
+</html>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HousekeepingRule, RuleAction};
+    use crate::history_store::HistoryEntry;
+    use chrono::NaiveDateTime;
+
+    fn entry(prompt: &str, ts: &str, images: &[&str]) -> HistoryEntry {
+        HistoryEntry {
+            id: "20260101_000000_0001".to_string(),
+            ts: ts.to_string(),
+            prompt: prompt.to_string(),
+            images: images.iter().map(|v| v.to_string()).collect(),
+            sample: false,
+            locked: false,
+            tags: Vec::new(),
+            note: String::new(),
+            rating: 0,
+            count: 1,
+            selections: Default::default(),
+            translation: String::new(),
+            score: None,
+            image_metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn parses_rules_and_matches_all_conditions() {
+        let doc: toml::Value = toml::from_str(
+            r#"
+            [[housekeeping]]
+            name = "branding"
+            contains = "Logo"
+            action = "tag"
+            tag = "project:branding"
+
+            [[housekeeping]]
+            has_image = false
+            older_than_days = 90
+            action = "archive"
+
+            [[housekeeping]]
+            action = "explode"
+            contains = "x"
+            "#,
+        )
+        .expect("parse toml");
+        let tables = doc["housekeeping"].as_array().expect("rules");
+
+        let tag = HousekeepingRule::from_toml(0, &tables[0]).expect("tag rule");
+        assert_eq!(tag.action, RuleAction::Tag("project:branding".to_string()));
+        let stale = HousekeepingRule::from_toml(1, &tables[1]).expect("archive rule");
+        assert_eq!(stale.name, "rule 2");
+        assert!(HousekeepingRule::from_toml(2, &tables[2]).is_err());
+
+        let now =
+            NaiveDateTime::parse_from_str("2026-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").expect("now");
+        assert!(tag.matches(&entry("a logo mark", "2026-05-31 00:00:00", &[]), now));
+        assert!(!tag.matches(&entry("a poster", "2026-05-31 00:00:00", &[]), now));
+        assert!(stale.matches(&entry("old", "2026-01-01 00:00:00", &[]), now));
+        assert!(!stale.matches(&entry("old", "2026-01-01 00:00:00", &["a.png"]), now));
+        assert!(!stale.matches(&entry("recent", "2026-05-01 00:00:00", &[]), now));
+    }
+}
@@ -0,0 +1,238 @@
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use chrono::{DateTime, Utc};
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Browsers keep served files but ask again before each use; an unchanged
+/// file then costs a `304` instead of its bytes.
+pub const CACHE_CONTROL: &str = "private, no-cache";
+
+/// The part of a file to serve for a request's `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable range: the whole file with `200`.
+    Full,
+    /// Inclusive byte offsets, served with `206`.
+    Partial { start: u64, end: u64 },
+    /// The range starts past the end of the file: `416`.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// `Content-Range` of a `206` or `416` response for a `len`-byte file.
+    pub fn content_range(&self, len: u64) -> Option<HeaderValue> {
+        let value = match self {
+            Self::Full => return None,
+            Self::Partial { start, end } => format!("bytes {start}-{end}/{len}"),
+            Self::Unsatisfiable => format!("bytes */{len}"),
+        };
+        Some(HeaderValue::from_str(&value).expect("content range is ASCII"))
+    }
+}
+
+/// Validators of a file served over HTTP, taken from its metadata so checking
+/// them never reads the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    etag: String,
+    last_modified: SystemTime,
+}
+
+impl Validators {
+    /// `variant` tells apart responses that differ for the same file, like an
+    /// image served with the prompt embedded.
+    pub fn new(metadata: &Metadata, variant: Option<&str>) -> Self {
+        Self::from_parts(
+            metadata.len(),
+            metadata.modified().unwrap_or(UNIX_EPOCH),
+            variant,
+        )
+    }
+
+    fn from_parts(len: u64, modified: SystemTime, variant: Option<&str>) -> Self {
+        let nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let etag = match variant {
+            Some(variant) => format!(
+                "\"{len:x}-{nanos:x}-{:08x}\"",
+                crc32fast::hash(variant.as_bytes())
+            ),
+            None => format!("\"{len:x}-{nanos:x}\""),
+        };
+        Self {
+            etag,
+            last_modified: modified,
+        }
+    }
+
+    /// Whether the client's copy is current. `If-None-Match` wins over
+    /// `If-Modified-Since` when both are sent.
+    pub fn is_fresh(&self, request: &HeaderMap) -> bool {
+        if let Some(tags) = request
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+        {
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag);
+        }
+        let Some(since) = request
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        else {
+            return false;
+        };
+        // HTTP dates have whole seconds.
+        DateTime::<Utc>::from(self.last_modified).timestamp() <= since.timestamp()
+    }
+
+    /// The range to serve of a `len`-byte file. Only a single range is
+    /// honoured; several ranges, or an `If-Range` naming another version, get
+    /// the whole file as HTTP allows.
+    pub fn byte_range(&self, request: &HeaderMap, len: u64) -> ByteRange {
+        let Some(range) = request.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+            return ByteRange::Full;
+        };
+        if let Some(if_range) = request.get(header::IF_RANGE) {
+            if if_range.to_str().ok().map(str::trim) != Some(self.etag.as_str()) {
+                return ByteRange::Full;
+            }
+        }
+        parse_range(range, len)
+    }
+
+    /// `ETag`, `Last-Modified`, `Cache-Control` and `Accept-Ranges` for `200`,
+    /// `206` and `304`.
+    pub fn headers(&self) -> [(HeaderName, HeaderValue); 4] {
+        let last_modified = DateTime::<Utc>::from(self.last_modified)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        [
+            (
+                header::ETAG,
+                HeaderValue::from_str(&self.etag).expect("etag is ASCII"),
+            ),
+            (
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&last_modified).expect("HTTP date is ASCII"),
+            ),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(CACHE_CONTROL),
+            ),
+            (header::ACCEPT_RANGES, HeaderValue::from_static("bytes")),
+        ]
+    }
+}
+
+fn parse_range(value: &str, len: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // `bytes=-N`: the last N bytes.
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start,
+        end: end.min(len - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, ByteRange, Validators};
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn conditional_headers_match_the_served_validators() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let validators = Validators::from_parts(2048, modified, None);
+        let [(_, etag), (_, last_modified), _, _] = validators.headers();
+        assert_eq!(last_modified, "Tue, 14 Nov 2023 22:13:20 GMT");
+
+        let request = |name, value: &HeaderValue| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.clone());
+            headers
+        };
+        assert!(validators.is_fresh(&request(header::IF_NONE_MATCH, &etag)));
+        assert!(validators.is_fresh(&request(
+            header::IF_NONE_MATCH,
+            &HeaderValue::from_str(&format!("\"old\", W/{}", etag.to_str().unwrap())).unwrap()
+        )));
+        assert!(validators.is_fresh(&request(header::IF_MODIFIED_SINCE, &last_modified)));
+        assert!(!validators.is_fresh(&HeaderMap::new()));
+
+        // A changed prompt changes the embedded bytes, so the tag too.
+        let embedded = Validators::from_parts(2048, modified, Some("cat"));
+        assert!(!embedded.is_fresh(&request(header::IF_NONE_MATCH, &etag)));
+        let mut both = request(header::IF_NONE_MATCH, &etag);
+        both.insert(header::IF_MODIFIED_SINCE, last_modified);
+        assert!(!embedded.is_fresh(&both), "If-None-Match takes precedence");
+    }
+
+    #[test]
+    fn single_byte_ranges_are_clamped_to_the_file() {
+        let partial = |start, end| ByteRange::Partial { start, end };
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), partial(0, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-5", 1000), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+        assert_eq!(
+            partial(900, 999).content_range(1000).unwrap(),
+            "bytes 900-999/1000"
+        );
+
+        let validators = Validators::from_parts(1000, UNIX_EPOCH, None);
+        let [(_, etag), ..] = validators.headers();
+        let mut request = HeaderMap::new();
+        request.insert(header::RANGE, HeaderValue::from_static("bytes=10-19"));
+        request.insert(header::IF_RANGE, etag);
+        assert_eq!(validators.byte_range(&request, 1000), partial(10, 19));
+        request.insert(header::IF_RANGE, HeaderValue::from_static("\"old\""));
+        assert_eq!(
+            validators.byte_range(&request, 1000),
+            ByteRange::Full,
+            "a changed file is sent whole"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::atomic_write::write_file_atomic;
+
+/// Side length of the grayscale thumbnail the DCT runs on.
+const SAMPLE_SIZE: usize = 32;
+/// Side length of the low-frequency block that becomes the 64-bit hash.
+const HASH_SIZE: usize = 8;
+
+/// Default Hamming distance (out of 64 bits) under which two images count as similar.
+pub const DEFAULT_SIMILAR_DISTANCE: u32 = 10;
+
+/// DCT-based perceptual hash: robust to rescaling, recompression and small
+/// brightness changes, so a screenshot of an image hashes close to the original.
+pub fn perceptual_hash(bytes: &[u8]) -> Result<u64> {
+    let image = image::load_from_memory(bytes).context("failed to decode image")?;
+    let gray = image
+        .resize_exact(SAMPLE_SIZE as u32, SAMPLE_SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = gray.pixels().map(|p| f64::from(p.0[0])).collect();
+
+    let cosines: Vec<f64> = (0..HASH_SIZE)
+        .flat_map(|u| {
+            (0..SAMPLE_SIZE)
+                .map(move |x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * SAMPLE_SIZE) as f64).cos())
+        })
+        .collect();
+    let cosine = |u: usize, x: usize| cosines[u * SAMPLE_SIZE + x];
+
+    // Separable 2D DCT, keeping only the HASH_SIZE lowest frequencies per axis.
+    let mut rows = vec![0.0; SAMPLE_SIZE * HASH_SIZE];
+    for y in 0..SAMPLE_SIZE {
+        for u in 0..HASH_SIZE {
+            rows[y * HASH_SIZE + u] = (0..SAMPLE_SIZE)
+                .map(|x| pixels[y * SAMPLE_SIZE + x] * cosine(u, x))
+                .sum();
+        }
+    }
+    let mut low = Vec::with_capacity(HASH_SIZE * HASH_SIZE);
+    for v in 0..HASH_SIZE {
+        for u in 0..HASH_SIZE {
+            low.push(
+                (0..SAMPLE_SIZE)
+                    .map(|y| rows[y * HASH_SIZE + u] * cosine(v, y))
+                    .sum::<f64>(),
+            );
+        }
+    }
+
+    // The DC term only tracks overall brightness, so it stays out of the median.
+    let mut sorted = low[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    Ok(low
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value > median)
+        .fold(0u64, |hash, (bit, _)| hash | (1 << bit)))
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub fn format_hash(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+pub fn parse_hash(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim(), 16).ok()
+}
+
+/// `image_hashes.json`: image path → hex phash, filled at upload and on first search.
+pub struct ImageHashIndex {
+    path: PathBuf,
+}
+
+impl ImageHashIndex {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, String>> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read json: {}", self.path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse json: {}", self.path.display()))
+    }
+
+    pub fn save(&self, hashes: &BTreeMap<String, String>) -> Result<()> {
+        let payload =
+            serde_json::to_string_pretty(hashes).context("failed to serialize image hashes")?;
+        write_file_atomic(&self.path, payload.as_bytes(), false)
+    }
+
+    pub fn insert(&self, image_path: &str, hash: u64) -> Result<()> {
+        let mut hashes = self.load()?;
+        hashes.insert(image_path.to_string(), format_hash(hash));
+        self.save(&hashes)
+    }
+
+    pub fn remove(&self, image_path: &str) -> Result<()> {
+        let mut hashes = self.load()?;
+        if hashes.remove(image_path).is_some() {
+            self.save(&hashes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_hash, hamming_distance, parse_hash, perceptual_hash};
+    use image::{ImageBuffer, ImageFormat, Luma};
+    use std::io::Cursor;
+
+    fn encode(
+        width: u32,
+        height: u32,
+        shade: impl Fn(u32, u32) -> u8,
+        format: ImageFormat,
+    ) -> Vec<u8> {
+        let image = ImageBuffer::from_fn(width, height, |x, y| Luma([shade(x, y)]));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).expect("encode image");
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn similar_images_hash_close_and_different_images_far() {
+        let gradient = |x: u32, y: u32| ((x * 2 + y) % 256) as u8;
+        let original =
+            perceptual_hash(&encode(256, 256, gradient, ImageFormat::Png)).expect("hash");
+        let rescaled_brighter = perceptual_hash(&encode(
+            128,
+            128,
+            |x, y| gradient(x * 2, y * 2).saturating_add(20),
+            ImageFormat::Jpeg,
+        ))
+        .expect("hash rescaled");
+        let checker = perceptual_hash(&encode(
+            256,
+            256,
+            |x, y| if (x / 32 + y / 32) % 2 == 0 { 230 } else { 20 },
+            ImageFormat::Png,
+        ))
+        .expect("hash checker");
+
+        assert!(hamming_distance(original, rescaled_brighter) <= 6);
+        assert!(hamming_distance(original, checker) > 20);
+        assert_eq!(parse_hash(&format_hash(original)), Some(original));
+        assert!(perceptual_hash(b"not an image").is_err());
+    }
+}
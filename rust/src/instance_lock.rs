@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::Path;
+
+/// Lock file in the data dir, held for as long as an instance runs.
+pub const LOCK_FILE_NAME: &str = "app.lock";
+/// Error for writes refused because another instance owns the data dir.
+pub const READ_ONLY_MESSAGE: &str =
+    "読み取り専用: 別のインスタンスがこのデータフォルダを使用中のため保存できません";
+
+/// Exclusive OS lock on `app.lock`. The OS drops it when the process exits, so
+/// a crash never leaves a stale lock behind.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock on `data_dir`; `None` when another instance holds it.
+    pub fn acquire(data_dir: &Path) -> Result<Option<Self>> {
+        fs::create_dir_all(data_dir)
+            .with_context(|| format!("failed to create dir: {}", data_dir.display()))?;
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file: {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(err)) => {
+                Err(err).with_context(|| format!("failed to lock: {}", path.display()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InstanceLock;
+    use std::fs;
+
+    #[test]
+    fn second_instance_sees_the_lock_until_the_first_drops_it() {
+        let base = std::env::temp_dir().join(format!("ipg_instance_lock_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+
+        let first = InstanceLock::acquire(&base).expect("acquire");
+        assert!(first.is_some());
+        assert!(InstanceLock::acquire(&base).expect("try again").is_none());
+        drop(first);
+        assert!(InstanceLock::acquire(&base)
+            .expect("after release")
+            .is_some());
+
+        fs::remove_dir_all(base).ok();
+    }
+}
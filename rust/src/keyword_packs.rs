@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+use crate::config_exchange::{
+    merge_missing, parse_document, parse_sections, ExchangeFormat, MergeSummary,
+};
+
+/// Folder next to `config.txt` the pack browser lists.
+pub const PACKS_DIR: &str = "packs";
+
+/// `packs/` in the folder holding `config_path`.
+pub fn packs_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(PACKS_DIR)
+}
+
+/// A keyword pack: an optional `[pack]` table describing it and the
+/// `[[sections]]` with the items, labels and choices it adds, in the same
+/// layout as `config.txt` (TOML, JSON or YAML).
+#[derive(Debug, Clone)]
+pub struct KeywordPack {
+    pub info: PackInfo,
+    pub sections: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PackInfo {
+    /// File name under `packs/`; empty for a pack that was uploaded.
+    pub file: String,
+    /// `[pack] name`, or the file name without its extension.
+    pub name: String,
+    pub description: String,
+    pub author: String,
+    pub version: String,
+    pub items: usize,
+    pub choices: usize,
+}
+
+/// A pack listed by the browser, with what installing it would add now.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedPack {
+    #[serde(flatten)]
+    pub info: PackInfo,
+    pub pending: MergeSummary,
+}
+
+pub fn parse_pack(text: &str, format: ExchangeFormat) -> Result<KeywordPack> {
+    let sections = parse_sections(text, format)?;
+    let doc = parse_document(text, format)?;
+    let field = |key: &str| {
+        doc.get("pack")
+            .and_then(|pack| pack.get(key))
+            .and_then(Value::as_str)
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let items: Vec<&Value> = sections
+        .iter()
+        .filter_map(|section| section.get("items").and_then(Value::as_array))
+        .flatten()
+        .collect();
+    let choices = items
+        .iter()
+        .filter_map(|item| item.get("choices").and_then(Value::as_array))
+        .map(Vec::len)
+        .sum();
+    let info = PackInfo {
+        file: String::new(),
+        name: field("name"),
+        description: field("description"),
+        author: field("author"),
+        version: field("version"),
+        items: items.len(),
+        choices,
+    };
+    Ok(KeywordPack { info, sections })
+}
+
+/// Reads `packs/<file>`; `file` must be a plain `.toml`/`.txt`/`.json`/`.yaml`
+/// name in that folder.
+pub fn read_pack(packs_dir: &Path, file: &str) -> Result<KeywordPack> {
+    let file = file.trim();
+    // `C:x.toml` would resolve against the current folder of drive C:.
+    let plain = Path::new(file).file_name() == Some(OsStr::new(file));
+    if !plain || file.contains(['/', '\\', ':']) || file.starts_with('.') {
+        return Err(anyhow!("invalid pack file name: {file}"));
+    }
+    let path = packs_dir.join(file);
+    let format = pack_format(&path).ok_or_else(|| anyhow!("not a pack file: {file}"))?;
+    if !path.is_file() {
+        return Err(anyhow!("pack not found: {file}"));
+    }
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read pack: {}", path.display()))?;
+    let mut pack = parse_pack(&text, format).with_context(|| format!("invalid pack: {file}"))?;
+    pack.info.file = file.to_string();
+    if pack.info.name.is_empty() {
+        pack.info.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    Ok(pack)
+}
+
+/// Every readable pack in `packs_dir` by file name, with what it would add
+/// to `sections`; unreadable files are returned as messages.
+pub fn list_packs(packs_dir: &Path, sections: &[Value]) -> (Vec<ListedPack>, Vec<String>) {
+    let mut packs = Vec::new();
+    let mut errors = Vec::new();
+    let Ok(entries) = fs::read_dir(packs_dir) else {
+        return (packs, errors);
+    };
+    let mut files: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && pack_format(path).is_some())
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect();
+    files.sort();
+
+    for file in files {
+        match read_pack(packs_dir, &file) {
+            Ok(pack) => {
+                let pending = merge_missing(&mut sections.to_vec(), &pack.sections);
+                packs.push(ListedPack {
+                    info: pack.info,
+                    pending,
+                });
+            }
+            Err(err) => errors.push(format!("{err:#}")),
+        }
+    }
+    (packs, errors)
+}
+
+fn pack_format(path: &Path) -> Option<ExchangeFormat> {
+    let extension = path.extension()?.to_str()?;
+    if extension.is_empty() {
+        return None;
+    }
+    ExchangeFormat::parse(extension).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_packs, read_pack};
+    use std::fs;
+
+    #[test]
+    fn packs_are_listed_with_what_they_would_add() {
+        let dir = std::env::temp_dir().join(format!("ipg_keyword_packs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("fixture dir");
+        fs::write(
+            dir.join("animals.toml"),
+            r#"
+[pack]
+name = "動物セット"
+author = "community"
+
+[[sections]]
+name = "prompt"
+
+  [[sections.items]]
+  key = "subject"
+  label = "被写体"
+  choices = ["cat", "fox"]
+"#,
+        )
+        .expect("pack write");
+        fs::write(dir.join("broken.json"), "{").expect("broken write");
+        fs::write(dir.join("notes.md"), "not a pack").expect("other write");
+
+        let installed = toml::from_str::<toml::Value>(
+            "[[sections]]\nname = \"prompt\"\n[[sections.items]]\nkey = \"subject\"\nchoices = [\"cat\"]\n",
+        )
+        .expect("config")["sections"]
+            .as_array()
+            .cloned()
+            .expect("sections");
+        let (packs, errors) = list_packs(&dir, &installed);
+        assert_eq!(packs.len(), 1);
+        assert_eq!(packs[0].info.name, "動物セット");
+        assert_eq!(packs[0].info.file, "animals.toml");
+        assert_eq!(packs[0].info.choices, 2);
+        assert_eq!(packs[0].pending.choices_added, 1);
+        assert_eq!(errors.len(), 1);
+        assert!(read_pack(&dir, "../animals.toml").is_err());
+        assert!(read_pack(&dir, "C:animals.toml").is_err());
+        assert!(read_pack(&dir, "missing.toml").is_err());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,221 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use anyhow::{anyhow, Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::fs;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::atomic_write::write_file_atomic;
+
+/// Self-signed certificate for LAN mode, kept next to `config.txt`.
+pub const CERT_FILE_NAME: &str = "lan_cert.pem";
+/// Private key of `CERT_FILE_NAME`.
+pub const KEY_FILE_NAME: &str = "lan_key.pem";
+/// Pairing token other machines must present, next to `CERT_FILE_NAME`.
+pub const TOKEN_FILE_NAME: &str = "lan_token.txt";
+/// A client that has not finished the TLS handshake by then is dropped, so a
+/// stalled connection never holds up the others.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// LAN mode: HTTPS on every interface, next to the loopback HTTP server.
+#[derive(Debug, Clone)]
+pub struct LanConfig {
+    pub port: u16,
+    /// Folder holding `CERT_FILE_NAME` and `KEY_FILE_NAME`.
+    pub cert_dir: PathBuf,
+}
+
+/// Loads the certificate in `cert_dir`, creating it on first use. An existing
+/// certificate is reused so browsers keep the exception the user accepted;
+/// deleting both files makes the next start issue a new one.
+pub fn load_or_create_server_config(cert_dir: &Path) -> Result<Arc<ServerConfig>> {
+    let cert_path = cert_dir.join(CERT_FILE_NAME);
+    let key_path = cert_dir.join(KEY_FILE_NAME);
+    if !cert_path.exists() || !key_path.exists() {
+        let certified = rcgen::generate_simple_self_signed(subject_alt_names())
+            .context("failed to generate certificate")?;
+        write_file_atomic(
+            &key_path,
+            certified.key_pair.serialize_pem().as_bytes(),
+            false,
+        )?;
+        write_file_atomic(&cert_path, certified.cert.pem().as_bytes(), false)?;
+    }
+
+    let cert_pem = fs::read(&cert_path)
+        .with_context(|| format!("failed to read certificate: {}", cert_path.display()))?;
+    let key_pem = fs::read(&key_path)
+        .with_context(|| format!("failed to read private key: {}", key_path.display()))?;
+    let cert = CertificateDer::from_pem_slice(&cert_pem)
+        .map_err(|err| anyhow!("invalid certificate {}: {err}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_slice(&key_pem)
+        .map_err(|err| anyhow!("invalid private key {}: {err}", key_path.display()))?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("failed to configure TLS")?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .context("certificate and private key do not match")?;
+    Ok(Arc::new(config))
+}
+
+/// Loads the pairing token in `cert_dir`, creating a random one on first use.
+/// Deleting the file unpairs every device at the next start.
+pub fn load_or_create_pairing_token(cert_dir: &Path) -> Result<String> {
+    let path = cert_dir.join(TOKEN_FILE_NAME);
+    if path.exists() {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read pairing token: {}", path.display()))?;
+        if !text.trim().is_empty() {
+            return Ok(text.trim().to_string());
+        }
+    }
+    let mut bytes = [0u8; 24];
+    OsRng.fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    write_file_atomic(&path, token.as_bytes(), false)?;
+    Ok(token)
+}
+
+/// The address a device opens once to pair: the LAN server's root with the
+/// token in `?pair=`; `None` without a LAN address.
+pub fn pairing_url(port: u16, token: &str) -> Option<String> {
+    lan_address().map(|ip| format!("https://{}/?pair={token}", SocketAddr::new(ip, port)))
+}
+
+/// Names the certificate is issued for: loopback, this machine's host name and
+/// its current LAN address.
+fn subject_alt_names() -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    if let Some(host) = ["COMPUTERNAME", "HOSTNAME"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok())
+        .filter(|host| !host.is_empty())
+    {
+        names.push(host);
+    }
+    if let Some(ip) = lan_address() {
+        names.push(ip.to_string());
+    }
+    names
+}
+
+/// Address of the interface that routes outward, which is the one other
+/// machines on the LAN reach. Connecting a UDP socket sends nothing.
+pub fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).ok()?;
+    socket.connect(("8.8.8.8", 80)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+}
+
+/// Accepts TCP connections and completes their TLS handshakes in the
+/// background, so `axum::serve` only ever sees finished ones.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    /// Must be called inside the Tokio runtime that serves the listener.
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, handshaken) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let Ok((stream, addr)) = listener.accept().await else {
+                    // Out of file descriptors and the like; back off briefly.
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    // Browsers drop the first handshake against a self-signed
+                    // certificate until the user accepts it.
+                    if let Ok(Ok(stream)) =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        let _ = tx.send((stream, addr)).await;
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            handshaken,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(connection) => connection,
+            // The accept task only ends once this listener is gone.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load_or_create_pairing_token, load_or_create_server_config, CERT_FILE_NAME, KEY_FILE_NAME,
+        TOKEN_FILE_NAME,
+    };
+    use std::fs;
+
+    #[test]
+    fn certificate_and_pairing_token_are_created_once_and_reused() {
+        let base = std::env::temp_dir().join(format!("ipg_lan_tls_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("mkdir fixture");
+
+        load_or_create_server_config(&base).expect("create certificate");
+        let cert = fs::read(base.join(CERT_FILE_NAME)).expect("read certificate");
+        assert!(cert.starts_with(b"-----BEGIN CERTIFICATE-----"));
+        assert!(base.join(KEY_FILE_NAME).exists());
+
+        load_or_create_server_config(&base).expect("reuse certificate");
+        assert_eq!(
+            fs::read(base.join(CERT_FILE_NAME)).expect("reread certificate"),
+            cert
+        );
+
+        fs::write(base.join(KEY_FILE_NAME), b"not a key").expect("corrupt key");
+        assert!(load_or_create_server_config(&base).is_err());
+
+        let token = load_or_create_pairing_token(&base).expect("create token");
+        assert_eq!(token.len(), 48);
+        assert_eq!(
+            load_or_create_pairing_token(&base).expect("reuse token"),
+            token
+        );
+        fs::remove_file(base.join(TOKEN_FILE_NAME)).expect("unpair");
+        assert_ne!(
+            load_or_create_pairing_token(&base).expect("new token"),
+            token
+        );
+
+        fs::remove_dir_all(base).ok();
+    }
+}
@@ -1,8 +1,39 @@
+pub mod api_version;
+pub mod archive_index;
+pub mod atomic_write;
+pub mod audit_log;
+pub mod backup;
+pub mod config_backups;
+pub mod config_exchange;
+pub mod config_layers;
 pub mod config_store;
+pub mod diagnostics;
+pub mod encryption;
 pub mod history_store;
+pub mod housekeeping;
+pub mod http_cache;
+pub mod image_hash;
+pub mod instance_lock;
+pub mod keyword_packs;
+pub mod lan_tls;
 pub mod main_ui_html;
+pub mod migration;
 pub mod path_utils;
+pub mod png_text;
+pub mod profiles;
+pub mod prompt_parser;
+pub mod randomize;
+pub mod rate_limit;
 pub mod renderer;
+pub mod rest_api;
+pub mod scoring;
 pub mod server;
+pub mod smart_filters;
+pub mod stats;
+pub mod text_normalize;
+pub mod thumbnails;
+pub mod token_count;
+pub mod translation;
+pub mod vision;
 
 pub const NO_SELECTION: &str = "指定なし";
@@ -19,11 +19,17 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       --muted: #9ca2ad;
       --btn-bg: #2a2d33;
       --btn-line: #5b616d;
-      --grid-cols: 170px 320px 44px 1fr;
-      --grid-gap: 6px;
-      --ctrl-h: 26px;
-      --delete-h: 24px;
-      --font-sm: 12px;
+      /* Shrinks the fixed px sizes on very high DPI monitors, where the
+         logical viewport is small (see setHostScale). */
+      --ui-scale: 1;
+      --grid-cols: calc(170px * var(--ui-scale)) calc(320px * var(--ui-scale)) calc(44px * var(--ui-scale)) calc(44px * var(--ui-scale)) 1fr;
+      --grid-gap: calc(6px * var(--ui-scale));
+      --ctrl-h: calc(26px * var(--ui-scale));
+      --delete-h: calc(24px * var(--ui-scale));
+      --font-sm: calc(12px * var(--ui-scale));
+    }
+    :root[data-dpi="high"] {
+      --ui-scale: 0.875;
     }
     * { box-sizing: border-box; }
     body {
@@ -31,7 +37,7 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       color: var(--text);
       background: var(--bg);
       font-family: "Yu Gothic UI", "Hiragino Kaku Gothic ProN", sans-serif;
-      font-size: 14px;
+      font-size: calc(14px * var(--ui-scale));
     }
     .wrap {
       width: 100%;
@@ -59,6 +65,36 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       border-top: 1px solid #2f3137;
       padding-top: 4px;
     }
+    .section-tabs {
+      display: flex;
+      gap: 2px;
+      padding: 0 4px;
+      border-bottom: 1px solid #2f3137;
+    }
+    .section-tab {
+      height: var(--ctrl-h);
+      padding: 0 14px;
+      border: 1px solid transparent;
+      border-bottom: none;
+      border-radius: 5px 5px 0 0;
+      background: transparent;
+      color: var(--muted);
+      font-size: var(--font-sm);
+      cursor: pointer;
+    }
+    .section-tab.active {
+      border-color: var(--line);
+      background: var(--input-bg);
+      color: #ffffff;
+      font-weight: 600;
+    }
+    .section-preview {
+      flex: 0 0 auto;
+      min-height: 0;
+      max-height: 80px;
+      overflow: auto;
+      margin-top: 4px;
+    }
     .grid-header, .row {
       display: grid;
       grid-template-columns: var(--grid-cols);
@@ -68,7 +104,7 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
     .grid-header {
       color: #ffffff;
       font-weight: 600;
-      font-size: 15px;
+      font-size: calc(15px * var(--ui-scale));
       text-align: center;
       padding: 0 4px 2px;
       border-bottom: 1px solid #2f3137;
@@ -94,6 +130,57 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       padding: 0 2px;
       margin-bottom: 0;
     }
+    .row.excluded {
+      opacity: 0.45;
+    }
+    .choice-suggest {
+      grid-column: 1 / -1;
+      display: flex;
+      flex-wrap: wrap;
+      justify-content: flex-end;
+      gap: 4px;
+    }
+    .choice-suggest button {
+      border: 1px solid var(--btn-line);
+      background: var(--btn-bg);
+      color: #ffffff;
+      border-radius: 5px;
+      padding: 1px 8px;
+      cursor: pointer;
+      font-size: var(--font-sm);
+    }
+    .choice-suggest button:hover {
+      background: #343842;
+    }
+    .group-header {
+      display: flex;
+      align-items: center;
+      gap: 6px;
+      width: 100%;
+      margin: 4px 0 2px;
+      padding: 2px 6px;
+      border: none;
+      border-bottom: 1px solid #2f3137;
+      color: #ffffff;
+      background: transparent;
+      font-size: calc(13px * var(--ui-scale));
+      font-weight: 600;
+      text-align: left;
+      cursor: pointer;
+    }
+    .group-header .group-count {
+      color: var(--muted);
+      font-weight: 400;
+    }
+    .row.drop-before {
+      box-shadow: inset 0 2px 0 #c9a227;
+    }
+    .row.drop-after {
+      box-shadow: inset 0 -2px 0 #c9a227;
+    }
+    .label[draggable="true"] {
+      cursor: grab;
+    }
     .label {
       color: #ffffff;
       font-weight: 600;
@@ -105,6 +192,46 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       min-height: var(--ctrl-h);
       overflow-wrap: anywhere;
     }
+    .label-name {
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      gap: 4px;
+    }
+    .lock {
+      border: none;
+      background: none;
+      padding: 0;
+      cursor: pointer;
+      font-size: calc(11px * var(--ui-scale));
+      line-height: 1;
+      opacity: 0.35;
+    }
+    .lock.locked {
+      opacity: 1;
+    }
+    .label.weighted {
+      flex-direction: column;
+      gap: 2px;
+    }
+    .weight {
+      display: flex;
+      align-items: center;
+      gap: 4px;
+      width: 100%;
+      font-weight: 400;
+      font-size: calc(11px * var(--ui-scale));
+      color: #c9ccd3;
+    }
+    .weight input {
+      flex: 1;
+      min-width: 0;
+      height: auto;
+      padding: 0;
+    }
+    .weight.changed {
+      color: #ffd479;
+    }
     select, input, button {
       font: inherit;
     }
@@ -127,11 +254,19 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
     select:focus, input:focus {
       border-color: #6f8099;
     }
+    input.unconfirmed {
+      border-color: #c9a227;
+      border-style: dashed;
+    }
     input:disabled {
       background: #24262a;
       color: #7a8089;
     }
-    .delete {
+    .choice-tools {
+      display: flex;
+      gap: 2px;
+    }
+    .delete, .rename, .dice {
       width: 100%;
       height: var(--delete-h);
       border: 1px solid var(--input-line);
@@ -139,19 +274,42 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       color: #d9dee6;
       background: #2b2e34;
       cursor: pointer;
-      font-size: 9px;
+      font-size: calc(9px * var(--ui-scale));
       line-height: 1;
       padding: 0;
     }
-    .delete:disabled {
+    .dice {
+      font-size: calc(12px * var(--ui-scale));
+    }
+    .dice.pooled {
+      border-color: #c9a227;
+    }
+    .delete:disabled, .rename:disabled, .dice:disabled {
       opacity: 0.35;
       cursor: default;
     }
     .preview-title {
       margin: 0 0 2px;
-      font-size: 12px;
+      font-size: calc(12px * var(--ui-scale));
       color: #ffffff;
     }
+    .score-badge {
+      margin-left: 8px;
+      color: #f5c542;
+    }
+    .score-badge.pending {
+      color: var(--muted);
+    }
+    .token-count {
+      margin-left: 8px;
+      color: var(--muted);
+    }
+    .token-count.warn {
+      color: #f5c542;
+    }
+    .token-count.over {
+      color: #ff6b6b;
+    }
     .preview {
       min-height: 108px;
       border: 1px solid #5b5f67;
@@ -160,7 +318,7 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       white-space: pre-wrap;
       word-break: break-word;
       color: #ffffff;
-      font-size: 13px;
+      font-size: calc(13px * var(--ui-scale));
       line-height: 1.3;
     }
     .actions {
@@ -175,6 +333,139 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       gap: 6px;
       align-items: center;
     }
+    .presets {
+      display: inline-flex;
+      gap: 4px;
+      align-items: center;
+    }
+    .temporary-toggle,
+    .usage-toggle,
+    .random-toggle {
+      display: inline-flex;
+      align-items: center;
+      gap: 4px;
+      font-size: calc(12px * var(--ui-scale));
+      cursor: pointer;
+    }
+    body.temporary .preview {
+      border-style: dashed;
+    }
+    .previews {
+      display: grid;
+      grid-template-columns: 1fr;
+      gap: 6px;
+    }
+    body.translating .previews {
+      grid-template-columns: 1fr 1fr;
+    }
+    .translation-preview.pending {
+      color: var(--muted);
+    }
+    .parse-dialog {
+      width: min(720px, 92vw);
+      color: var(--text);
+      background: var(--panel);
+      border: 1px solid var(--line);
+      border-radius: 6px;
+      padding: 10px;
+    }
+    .parse-dialog::backdrop {
+      background: rgba(0, 0, 0, 0.5);
+    }
+    .parse-dialog textarea {
+      width: 100%;
+      color: var(--text);
+      background: var(--input-bg);
+      border: 1px solid var(--input-line);
+      font-size: calc(13px * var(--ui-scale));
+      resize: vertical;
+    }
+    .parse-dialog table {
+      width: 100%;
+      margin-top: 6px;
+      border-collapse: collapse;
+      font-size: calc(12px * var(--ui-scale));
+    }
+    .parse-dialog th,
+    .parse-dialog td {
+      border-bottom: 1px solid var(--line);
+      padding: 3px 6px;
+      text-align: left;
+    }
+    .parse-dialog tr.fuzzy td {
+      color: #f0c674;
+    }
+    .parse-leftovers {
+      margin-top: 6px;
+      color: var(--muted);
+      font-size: calc(12px * var(--ui-scale));
+    }
+    .choices-title {
+      font-size: calc(13px * var(--ui-scale));
+      font-weight: 600;
+    }
+    .parse-dialog tr.selected td {
+      color: #f0c674;
+    }
+    .parse-dialog td.choice-usage {
+      width: 1%;
+      white-space: nowrap;
+      color: var(--muted);
+      text-align: right;
+    }
+    .parse-dialog.dropping {
+      border-color: #f0c674;
+    }
+    .pack-meta {
+      color: var(--muted);
+    }
+    .choice-move {
+      width: 1%;
+      white-space: nowrap;
+    }
+    .choice-move button {
+      width: calc(26px * var(--ui-scale));
+      height: var(--delete-h);
+      margin-left: 2px;
+      border: 1px solid var(--input-line);
+      border-radius: 4px;
+      color: #d9dee6;
+      background: #2b2e34;
+      cursor: pointer;
+      padding: 0;
+    }
+    .choice-move button:disabled {
+      opacity: 0.35;
+      cursor: default;
+    }
+    .settings-field {
+      display: block;
+      margin-bottom: 8px;
+      font-size: calc(12px * var(--ui-scale));
+    }
+    .settings-field textarea {
+      margin-top: 2px;
+    }
+    .settings-grid {
+      display: grid;
+      grid-template-columns: repeat(3, 1fr);
+      gap: 6px;
+    }
+    .settings-field input[type="number"] {
+      display: block;
+      width: 100%;
+      margin-top: 2px;
+      color: var(--text);
+      background: var(--input-bg);
+      border: 1px solid var(--input-line);
+      font-size: calc(13px * var(--ui-scale));
+    }
+    .parse-actions {
+      margin-top: 6px;
+      display: flex;
+      gap: 6px;
+      justify-content: flex-end;
+    }
     .copy-wrap {
       position: relative;
       display: inline-flex;
@@ -190,7 +481,7 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       color: #ffffff;
       border-radius: 5px;
       padding: 3px 8px;
-      font-size: 11px;
+      font-size: calc(11px * var(--ui-scale));
       line-height: 1;
       white-space: nowrap;
       opacity: 0;
@@ -212,16 +503,99 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       font-weight: 500;
       padding: 0 10px;
       cursor: pointer;
-      font-size: 12px;
+      font-size: calc(12px * var(--ui-scale));
     }
     .btn:hover {
       background: #343842;
     }
+    .loading-overlay {
+      position: fixed;
+      inset: 0;
+      z-index: 100;
+      display: flex;
+      align-items: center;
+      justify-content: center;
+      background: var(--bg);
+      color: var(--muted);
+      font-size: calc(13px * var(--ui-scale));
+    }
+    .loading-overlay[hidden] {
+      display: none;
+    }
+    .read-only-notice {
+      margin-top: 4px;
+      color: #f0c36a;
+      font-size: calc(11px * var(--ui-scale));
+    }
     .status {
       margin-top: 4px;
       min-height: 16px;
       color: var(--muted);
-      font-size: 11px;
+      font-size: calc(11px * var(--ui-scale));
+    }
+    #exitMini {
+      display: none;
+    }
+    body.mini .wrap {
+      padding: 0;
+    }
+    body.mini .frame {
+      padding: 3px 4px;
+      flex-direction: row;
+      align-items: center;
+      gap: 4px;
+    }
+    body.mini .top-pane,
+    body.mini .preview-title,
+    body.mini #translationPane,
+    body.mini #copyTranslation,
+    body.mini .left-actions,
+    body.mini #randomizeAll,
+    body.mini .random-toggle,
+    body.mini #reset,
+    body.mini #outputFormat,
+    body.mini .presets,
+    body.mini .status {
+      display: none;
+    }
+    body.mini .bottom-pane {
+      flex: 1 1 auto;
+      border-top: none;
+      padding-top: 0;
+      display: flex;
+      align-items: center;
+      gap: 4px;
+      min-width: 0;
+    }
+    body.mini .previews {
+      display: block;
+      flex: 1 1 auto;
+      min-width: 0;
+    }
+    body.mini .preview {
+      flex: 1 1 auto;
+      min-height: 0;
+      max-height: 22px;
+      padding: 3px 6px;
+      overflow: hidden;
+      white-space: nowrap;
+      text-overflow: ellipsis;
+      cursor: move;
+    }
+    body.mini.mini-hover .preview {
+      max-height: none;
+      height: 100%;
+      white-space: pre-wrap;
+      overflow: auto;
+    }
+    body.mini .actions {
+      margin-top: 0;
+    }
+    body.mini .btn {
+      min-width: 0;
+    }
+    body.mini #exitMini {
+      display: inline-block;
     }
     @media (max-width: 900px) {
       .grid-header {
@@ -231,6 +605,9 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
         grid-template-columns: 1fr;
         gap: 4px;
       }
+      body.translating .previews {
+        grid-template-columns: 1fr;
+      }
       .actions {
         flex-direction: column;
         align-items: stretch;
@@ -246,46 +623,228 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
   </style>
 </head>
 <body>
+  <div id="loadingOverlay" class="loading-overlay" role="status" aria-live="polite">読み込み中…</div>
   <main class="wrap">
     <section class="frame">
       <section class="top-pane">
+        <div id="sectionTabs" class="section-tabs" role="tablist" hidden></div>
         <div class="grid-header">
           <div>項目名</div>
           <div>選択</div>
-          <div>削除</div>
+          <div>編集</div>
+          <div>ランダム</div>
           <div>自由入力</div>
         </div>
         <div id="rows"></div>
+        <div id="sectionPreview" class="preview section-preview" title="このタブの項目だけの出力" hidden></div>
       </section>
       <section class="bottom-pane">
-        <div class="preview-title">Preview</div>
-        <div id="preview" class="preview"></div>
+        <div class="previews">
+          <div>
+            <div class="preview-title">Preview<span id="tokenCount" class="token-count"></span><span id="scoreBadge" class="score-badge" hidden></span></div>
+            <div id="preview" class="preview"></div>
+          </div>
+          <div id="translationPane" hidden>
+            <div class="preview-title">English</div>
+            <div id="translationPreview" class="preview translation-preview"></div>
+          </div>
+        </div>
 
         <div class="actions">
           <div class="left-actions">
             <button id="openHistory" class="btn">履歴を開く</button>
+            <button id="openParse" class="btn" title="プロンプトを貼り付けて各項目の選択に戻す">読み込み</button>
+            <button id="openStats" class="btn" title="日別の件数・よく使うキーワードなどを Stats.html で表示">統計</button>
+            <button id="openDiagnostics" class="btn" title="書き込み権限・クリップボード・ポートなどを確認し、不具合報告用のレポートを作成">診断</button>
+            <button id="openSettings" class="btn" title="プロンプトの前後に付ける定型文などを設定">設定</button>
+            <button id="openItems" class="btn" title="使わない項目を削除せずに非表示にする">項目</button>
+            <button id="openPacks" class="btn" title="packs フォルダのキーワードパック（被写体・画風などのセット）を追加">パック</button>
+            <button id="enterMini" class="btn" hidden>ミニ表示</button>
+            <button id="openProfile" class="btn" hidden title="profiles フォルダの設定・履歴を別ウィンドウで開く">別プロファイル</button>
+            <button id="reloadConfig" class="btn" title="テキストエディタで編集した config.txt と history.json を読み直す（再起動は不要）">再読み込み</button>
+            <button id="cleanupConfig" class="btn" hidden title="項目に対応しない保存状態を削除">設定を整理</button>
+            <label class="temporary-toggle" title="選択の変更を config.txt に書き込まず、保存するまでこの画面だけで試す"><input id="temporaryMode" type="checkbox" />一時モード</label>
+            <button id="saveTemporary" class="btn" hidden title="一時モードの選択内容を config.txt に保存">保存</button>
+            <label class="usage-toggle" title="コピーしたプロンプトで使った回数の多い選択肢をドロップダウンの上に表示"><input id="sortByUsage" type="checkbox" />よく使う順</label>
           </div>
           <div class="right-actions">
+            <span class="presets">
+              <select id="presetSelect" title="プリセットを選ぶと保存した選択内容に切り替え"></select>
+              <button id="savePreset" class="btn" title="現在の選択・自由入力・重みをプリセットとして保存">保存</button>
+              <button id="deletePreset" class="btn" title="選んでいるプリセットを削除" disabled>削除</button>
+            </span>
+            <select id="outputFormat" title="出力形式（対象外の項目はコピーに含まれません）"></select>
+            <label class="random-toggle" title="評価の高い履歴で使った値ほど選ばれやすくする（★3以上の履歴が対象）"><input id="randomWeighted" type="checkbox" />良かった組み合わせ寄りでランダム</label>
+            <button id="randomizeAll" class="btn" title="🔒 で固定した項目以外をランダムに選択（候補を設定した項目は候補の中から）">ランダム</button>
             <button id="reset" class="btn">Reset</button>
+            <button id="exitMini" class="btn" title="通常表示に戻す">戻す</button>
+            <button id="copyTranslation" class="btn" hidden title="英語の翻訳をコピー（日本語と一緒に履歴へ保存）">Copy EN</button>
             <div class="copy-wrap">
               <button id="copy" class="btn">Copy</button>
               <div id="copyHover" class="copy-hover" role="status" aria-live="polite">コピーしました</div>
             </div>
           </div>
         </div>
+        <div id="readOnlyNotice" class="read-only-notice" hidden>読み取り専用: 別のインスタンスがこのデータフォルダを使用中です。選択の変更はこの画面だけに残り、履歴と設定は保存されません。</div>
         <div id="status" class="status"></div>
       </section>
     </section>
   </main>
 
+  <dialog id="parseDialog" class="parse-dialog">
+    <textarea id="parseInput" rows="5" placeholder="読み込むプロンプトを貼り付け（改行・カンマ区切り）"></textarea>
+    <div class="parse-actions">
+      <button id="parseRun" class="btn">解析</button>
+    </div>
+    <table id="parseResult" hidden>
+      <thead>
+        <tr><th>適用</th><th>項目</th><th>プロンプト中の語</th><th>設定する値</th><th>判定</th><th>選択肢に追加</th></tr>
+      </thead>
+      <tbody></tbody>
+    </table>
+    <div id="parseLeftovers" class="parse-leftovers" hidden></div>
+    <div class="parse-actions">
+      <button id="parseApply" class="btn" disabled>フォームに適用</button>
+      <button id="parseClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
+  <dialog id="settingsDialog" class="parse-dialog">
+    <label class="settings-field">プロンプトの前に付ける定型文（品質タグなど）
+      <textarea id="settingsPrefix" rows="2" placeholder="例: masterpiece, best quality"></textarea>
+    </label>
+    <label class="settings-field">プロンプトの後に付ける定型文
+      <textarea id="settingsSuffix" rows="2" placeholder="例: by {project}"></textarea>
+    </label>
+    <label class="settings-field">値の区切り（改行もそのまま使われます）
+      <textarea id="settingsDelimiter" rows="2" placeholder="例: , "></textarea>
+    </label>
+    <div class="settings-grid">
+      <label class="settings-field">同じプロンプトの連続コピーを無視する秒数
+        <input id="settingsDebounce" type="number" min="0" step="0.5" />
+      </label>
+      <label class="settings-field">履歴の件数（超えた分はアーカイブ）
+        <input id="settingsMaxEntries" type="number" min="1" step="1" />
+      </label>
+      <label class="settings-field">履歴サーバーのポート（再起動後に反映）
+        <input id="settingsPort" type="number" min="1" max="65535" step="1" />
+      </label>
+    </div>
+    <label class="settings-field"><input id="settingsConfirmDelete" type="checkbox" />選択肢を削除する前に確認する</label>
+    <div class="settings-field">項目と選択肢の共有（取り込みは足りない項目・選択肢を追加するだけで、既存の内容は変更・削除しません）
+      <div class="parse-actions">
+        <button id="exportToml" class="btn">TOMLで書き出し</button>
+        <button id="exportJson" class="btn">JSONで書き出し</button>
+        <button id="importConfig" class="btn">取り込み</button>
+        <input id="importConfigFile" type="file" accept=".toml,.json,.yaml,.yml,.txt" hidden />
+      </div>
+    </div>
+    <div class="settings-field">バックアップから戻す（選択肢の削除・リセット・取り込みなどの前に config_backups フォルダへ自動で保存）
+      <div class="parse-actions">
+        <select id="backupSelect"></select>
+        <button id="restoreBackup" class="btn" disabled>復元</button>
+      </div>
+    </div>
+    <div class="parse-actions">
+      <button id="settingsSave" class="btn">保存</button>
+      <button id="settingsClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
+  <dialog id="choicesDialog" class="parse-dialog">
+    <div id="choicesTitle" class="choices-title"></div>
+    <table id="choicesList">
+      <tbody></tbody>
+    </table>
+    <div class="parse-actions">
+      <button id="choicesClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
+  <dialog id="itemsDialog" class="parse-dialog">
+    <div class="choices-title">表示する項目（チェックを外すと非表示。選択肢と選択内容はそのまま残ります）</div>
+    <table id="itemsList">
+      <thead>
+        <tr><th>表示</th><th>セクション</th><th>項目</th></tr>
+      </thead>
+      <tbody></tbody>
+    </table>
+    <div class="parse-actions">
+      <button id="itemsClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
+  <dialog id="packsDialog" class="parse-dialog">
+    <div class="choices-title">キーワードパック（足りない項目・選択肢を追加するだけで、既存の内容は変更・削除しません）</div>
+    <div id="packsDir" class="parse-leftovers"></div>
+    <table id="packsList">
+      <thead>
+        <tr><th>パック</th><th>内容</th><th>追加される分</th><th></th></tr>
+      </thead>
+      <tbody></tbody>
+    </table>
+    <div id="packsErrors" class="parse-leftovers" hidden></div>
+    <div class="parse-actions">
+      <button id="installPackFile" class="btn" title="パックファイルを選んで追加（この画面へのドロップでも可）">ファイルから追加</button>
+      <input id="installPackInput" type="file" accept=".toml,.json,.yaml,.yml,.txt" hidden />
+      <button id="packsClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
+  <dialog id="diagnosticsDialog" class="parse-dialog">
+    <textarea id="diagnosticsReport" rows="12" readonly></textarea>
+    <div class="parse-actions">
+      <button id="diagnosticsRun" class="btn">再実行</button>
+      <button id="diagnosticsCopy" class="btn">レポートをコピー</button>
+      <button id="diagnosticsClose" class="btn">閉じる</button>
+    </div>
+  </dialog>
+
   <script>
     const NO_SELECTION = "指定なし";
+    const CLIP_CHUNK_TOKENS = 75;
+    const CLIP_TOKEN_LIMIT = 150;
     const state = {
       rows: [],
+      sections: [],
+      hidden_items: [],
+      presets: [],
       preview: "",
       confirm_delete: true,
+      output_format: "labeled",
+      output_formats: [],
+      temporary_mode: false,
+      unsaved_state: false,
+      translation_enabled: false,
+      translation: "",
+      translation_source: "",
+      score_enabled: false,
+      score: null,
+      score_source: "",
+      read_only: false,
+      random_weighted: false,
+      sort_by_usage: false,
+      emphasis_enabled: false,
+      token_count: 0,
     };
     let copyHoverTimer = null;
+    let miniMode = false;
+    const DRAFT_SAVE_DELAY_MS = 400;
+    const draftTimers = new Map();
+    const SUGGEST_DELAY_MS = 200;
+    const SUGGEST_LIMIT = 8;
+    const suggestTimers = new Map();
+    let draggedItemId = null;
+    let managedItemId = null;
+    const TRANSLATE_DELAY_MS = 500;
+    let translateTimer = null;
+    let scoreTimer = null;
+    let parsedMatches = [];
+    const MATCH_KIND_LABELS = {
+      exact: "一致",
+      fuzzy: "あいまい一致（要確認）",
+      free_text: "自由入力",
+    };
 
     function setStatus(message) {
       const status = document.getElementById("status");
@@ -308,6 +867,121 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       }, 1200);
     }
 
+    function hasHostIpc() {
+      return !!(window.ipc && typeof window.ipc.postMessage === "function");
+    }
+
+    function postHostMessage(message) {
+      if (!hasHostIpc()) {
+        return;
+      }
+      window.ipc.postMessage(JSON.stringify(message));
+    }
+
+    // Monitor scale factor from the desktop host: `?scale=` at load, then
+    // setHostScale whenever the window moves to a monitor with another scale.
+    // Plain browsers (LAN mode) pass none and keep the normal sizes.
+    const HIGH_DPI_SCALE = 2;
+    function setHostScale(scale) {
+      const value = Number(scale);
+      if (!Number.isFinite(value) || value <= 0) {
+        return;
+      }
+      document.documentElement.dataset.dpi = value >= HIGH_DPI_SCALE ? "high" : "normal";
+    }
+    window.setHostScale = setHostScale;
+    setHostScale(new URLSearchParams(location.search).get("scale") ?? 1);
+
+    function setMiniMode(enabled) {
+      miniMode = enabled;
+      document.body.classList.toggle("mini", enabled);
+      document.body.classList.remove("mini-hover");
+      postHostMessage({ type: "mini-mode", enabled });
+    }
+
+    // One /ws connection carries this window's saves and reports item state
+    // changed elsewhere: another window, or the history page ("この内容を復元").
+    // Saves fall back to fetch while it is down.
+    const SOCKET_RETRY_MS = 3000;
+    let socket = null;
+    let socketClientId = null;
+    let socketRequestId = 0;
+    const socketRequests = new Map();
+    let lastStateRevision = null;
+
+    function connectSocket() {
+      const ws = new WebSocket(`${location.protocol === "https:" ? "wss" : "ws"}://${location.host}/ws`);
+      ws.addEventListener("message", (message) => {
+        let data;
+        try {
+          data = JSON.parse(message.data);
+        } catch (_) {
+          return;
+        }
+        if (data.type === "response") {
+          const pending = socketRequests.get(data.id);
+          if (pending) {
+            socketRequests.delete(data.id);
+            pending.resolve(data);
+          }
+        } else if (data.type === "hello") {
+          socket = ws;
+          socketClientId = data.client_id;
+          void applyStateRevision(data.state_revision, null);
+        } else if (data.type === "state") {
+          void applyStateRevision(data.revision, data.origin);
+        } else if (data.type === "config_reload") {
+          void applyConfigReload(data.revision);
+        } else if (data.type === "config_error") {
+          setStatus(`config.txt の読み込みエラー（以前の設定のまま動作中）: ${data.error}`);
+        }
+      });
+      ws.addEventListener("close", () => {
+        if (socket === ws) {
+          socket = null;
+        }
+        for (const pending of socketRequests.values()) {
+          pending.reject(new Error("connection closed"));
+        }
+        socketRequests.clear();
+        setTimeout(connectSocket, SOCKET_RETRY_MS);
+      });
+    }
+
+    function socketRequest(path, body) {
+      socketRequestId += 1;
+      const id = socketRequestId;
+      return new Promise((resolve, reject) => {
+        socketRequests.set(id, { resolve, reject });
+        socket.send(JSON.stringify({ id, method: "POST", path, body }));
+      });
+    }
+
+    async function applyStateRevision(revision, origin) {
+      const changed = lastStateRevision !== null && revision !== lastStateRevision;
+      lastStateRevision = revision;
+      if (!changed || origin === socketClientId) {
+        return;
+      }
+      try {
+        applySnapshot(await apiGet("/app/init"));
+        setStatus("他の画面での変更を反映しました。");
+      } catch (_) {
+        // Keep the current form when the server is briefly unavailable.
+      }
+    }
+
+    async function applyConfigReload(revision) {
+      lastStateRevision = revision;
+      try {
+        applySnapshot(await apiGet("/app/init"));
+        await applyFeatures();
+        setStatus("config.txt の変更を反映しました。");
+      } catch (_) {
+        // Keep the current form when the server is briefly unavailable.
+      }
+    }
+
     async function apiGet(path) {
       const res = await fetch(path, { method: "GET" });
       const data = await res.json();
@@ -318,6 +992,13 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
     }
 
     async function apiPost(path, body) {
+      if (socket) {
+        const { status, body: data } = await socketRequest(path, body || {});
+        if (status >= 400 || !data || !data.ok) {
+          throw new Error((data && data.error) || "request failed");
+        }
+        return data;
+      }
       const res = await fetch(path, {
         method: "POST",
         headers: { "Content-Type": "application/json" },
@@ -330,36 +1011,449 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       return data;
     }
 
+    function markDraftState(input, row) {
+      const unconfirmed = input.value !== (row.free_text || "");
+      input.classList.toggle("unconfirmed", unconfirmed);
+      input.title = unconfirmed ? "未確定（Enterで確定）" : "";
+    }
+
+    function scheduleDraftSave(row, value) {
+      if (draftTimers.has(row.item_id)) {
+        clearTimeout(draftTimers.get(row.item_id));
+      }
+      draftTimers.set(row.item_id, setTimeout(async () => {
+        draftTimers.delete(row.item_id);
+        try {
+          await apiPost("/app/free-draft", { item_id: row.item_id, value });
+        } catch (err) {
+          setStatus(`下書き保存エラー: ${err.message}`);
+        }
+      }, DRAFT_SAVE_DELAY_MS));
+    }
+
+    // Choices of the item that match the typed text the way the server
+    // matches them (case, width, kana and romaji), offered as buttons.
+    function scheduleSuggest(row, value, box) {
+      clearTimeout(suggestTimers.get(row.item_id));
+      suggestTimers.set(row.item_id, setTimeout(async () => {
+        suggestTimers.delete(row.item_id);
+        box.innerHTML = "";
+        const query = value.trim();
+        if (query) {
+          try {
+            const params = new URLSearchParams({ q: query, item_id: row.item_id, limit: String(SUGGEST_LIMIT) });
+            const data = await apiGet(`/app/suggest?${params}`);
+            for (const suggestion of data.suggestions || []) {
+              const button = document.createElement("button");
+              button.type = "button";
+              button.textContent = suggestion.choice;
+              button.dataset.choice = suggestion.choice;
+              box.appendChild(button);
+            }
+          } catch (_) {
+            // Suggestions are optional; typing keeps working without them.
+          }
+        }
+        box.hidden = box.childElementCount === 0;
+      }, SUGGEST_DELAY_MS));
+    }
+
+    function cancelDraftSave(row) {
+      if (draftTimers.has(row.item_id)) {
+        clearTimeout(draftTimers.get(row.item_id));
+        draftTimers.delete(row.item_id);
+      }
+    }
+
     function applySnapshot(payload) {
       state.rows = payload.rows || [];
+      state.sections = payload.sections || [];
+      state.hidden_items = payload.hidden_items || [];
+      state.presets = payload.presets || [];
       state.preview = payload.preview || "";
       if (typeof payload.confirm_delete === "boolean") {
         state.confirm_delete = payload.confirm_delete;
       }
+      state.output_format = payload.output_format || state.output_format;
+      state.output_formats = payload.output_formats || state.output_formats;
+      state.temporary_mode = !!payload.temporary_mode;
+      state.unsaved_state = !!payload.unsaved_state;
+      state.translation_enabled = !!payload.translation_enabled;
+      state.score_enabled = !!payload.score_enabled;
+      state.read_only = !!payload.read_only;
+      state.random_weighted = !!payload.random_weighted;
+      state.sort_by_usage = !!payload.sort_by_usage;
+      state.emphasis_enabled = !!payload.emphasis_enabled;
+      state.token_count = payload.token_count || 0;
+      document.getElementById("randomWeighted").checked = state.random_weighted;
+      document.getElementById("sortByUsage").checked = state.sort_by_usage;
+      renderOutputFormats();
+      renderPresets();
+      renderTemporaryMode();
       render();
+      if (document.getElementById("choicesDialog").open) {
+        renderChoicesDialog();
+      }
+      if (document.getElementById("itemsDialog").open) {
+        renderItemsDialog();
+      }
+      scheduleTranslation();
+      scheduleScore();
     }
 
-    function render() {
-      const rowsRoot = document.getElementById("rows");
-      rowsRoot.innerHTML = "";
-
-      for (const row of state.rows) {
-        const wrapper = document.createElement("div");
-        wrapper.className = "row";
+    function currentTranslation() {
+      return state.translation_source === state.preview ? state.translation : "";
+    }
 
-        const label = document.createElement("div");
-        label.className = "label";
-        label.textContent = row.label;
+    function renderTranslation(pendingMessage) {
+      document.body.classList.toggle("translating", state.translation_enabled);
+      document.getElementById("translationPane").hidden = !state.translation_enabled;
+      document.getElementById("copyTranslation").hidden = !state.translation_enabled;
+      const pane = document.getElementById("translationPreview");
+      pane.classList.toggle("pending", !!pendingMessage);
+      pane.textContent = pendingMessage || currentTranslation();
+    }
 
-        const select = document.createElement("select");
-        for (const choice of row.choices) {
-          const option = document.createElement("option");
-          option.value = choice;
-          option.textContent = choice;
-          option.title = choice;
-          if (choice === row.selected) {
-            option.selected = true;
-          }
+    function scheduleTranslation() {
+      if (translateTimer) {
+        clearTimeout(translateTimer);
+        translateTimer = null;
+      }
+      if (!state.translation_enabled || !state.preview.trim() || currentTranslation()) {
+        renderTranslation("");
+        return;
+      }
+      renderTranslation("翻訳中…");
+      const source = state.preview;
+      translateTimer = setTimeout(async () => {
+        translateTimer = null;
+        try {
+          const data = await apiPost("/app/translate", { text: source });
+          if (state.preview !== source) {
+            return;
+          }
+          state.translation = data.text || "";
+          state.translation_source = source;
+          renderTranslation("");
+        } catch (err) {
+          if (state.preview === source) {
+            renderTranslation(`翻訳エラー: ${err.message}`);
+          }
+        }
+      }, TRANSLATE_DELAY_MS);
+    }
+
+    function renderScore(pendingMessage) {
+      const badge = document.getElementById("scoreBadge");
+      const score = state.score_source === state.preview ? state.score : null;
+      badge.hidden = !state.score_enabled || (!pendingMessage && score === null);
+      badge.classList.toggle("pending", !!pendingMessage);
+      badge.textContent = pendingMessage || `スコア ${score}`;
+    }
+
+    // Scores the preview with score_command; the server logs the score with the
+    // history entry when this exact prompt is copied.
+    function scheduleScore() {
+      if (scoreTimer) {
+        clearTimeout(scoreTimer);
+        scoreTimer = null;
+      }
+      if (!state.score_enabled || !state.preview.trim() || state.score_source === state.preview) {
+        renderScore("");
+        return;
+      }
+      renderScore("採点中…");
+      const source = state.preview;
+      scoreTimer = setTimeout(async () => {
+        scoreTimer = null;
+        try {
+          const data = await apiPost("/app/score", { text: source });
+          if (state.preview !== source) {
+            return;
+          }
+          state.score = typeof data.score === "number" ? data.score : null;
+          state.score_source = source;
+          renderScore("");
+        } catch (err) {
+          if (state.preview === source) {
+            renderScore(`採点エラー: ${err.message}`);
+          }
+        }
+      }, TRANSLATE_DELAY_MS);
+    }
+
+    function renderTemporaryMode() {
+      document.getElementById("temporaryMode").checked = state.temporary_mode;
+      document.body.classList.toggle("temporary", state.temporary_mode);
+      const saveBtn = document.getElementById("saveTemporary");
+      saveBtn.hidden = !state.temporary_mode || state.read_only;
+      saveBtn.disabled = !state.unsaved_state;
+      document.getElementById("temporaryMode").disabled = state.read_only;
+      document.getElementById("readOnlyNotice").hidden = !state.read_only;
+    }
+
+    function renderOutputFormats() {
+      const select = document.getElementById("outputFormat");
+      select.innerHTML = "";
+      for (const format of state.output_formats) {
+        const option = document.createElement("option");
+        option.value = format;
+        option.textContent = format;
+        option.selected = format === state.output_format;
+        select.appendChild(option);
+      }
+      select.hidden = state.output_formats.length < 2;
+    }
+
+    // The preset applied or saved last stays selected so it can be deleted.
+    let currentPreset = "";
+    function renderPresets() {
+      if (!state.presets.includes(currentPreset)) {
+        currentPreset = "";
+      }
+      const select = document.getElementById("presetSelect");
+      select.innerHTML = "";
+      const placeholder = document.createElement("option");
+      placeholder.value = "";
+      placeholder.textContent = state.presets.length > 0 ? "プリセット" : "プリセットなし";
+      select.appendChild(placeholder);
+      for (const name of state.presets) {
+        const option = document.createElement("option");
+        option.value = name;
+        option.textContent = name;
+        option.selected = name === currentPreset;
+        select.appendChild(option);
+      }
+      select.disabled = state.presets.length === 0;
+      document.getElementById("deletePreset").disabled = !currentPreset;
+    }
+
+    // With more than one [[sections]] block, each is a tab showing its own
+    // rows and output; the preview below combines them all.
+    let activeSection = "";
+    function renderSectionTabs(tabbed) {
+      const tabs = document.getElementById("sectionTabs");
+      tabs.innerHTML = "";
+      tabs.hidden = !tabbed;
+      if (!tabbed) {
+        return;
+      }
+      for (const section of state.sections) {
+        const tab = document.createElement("button");
+        const active = section.name === activeSection;
+        tab.className = active ? "section-tab active" : "section-tab";
+        tab.textContent = section.label;
+        tab.setAttribute("role", "tab");
+        tab.setAttribute("aria-selected", String(active));
+        tab.addEventListener("click", () => {
+          activeSection = section.name;
+          render();
+        });
+        tabs.appendChild(tab);
+      }
+    }
+
+    function clearDropMarks() {
+      for (const marked of document.querySelectorAll(".row.drop-before, .row.drop-after")) {
+        marked.classList.remove("drop-before", "drop-after");
+      }
+    }
+
+    function groupOf(row) {
+      if (!row.group) {
+        return null;
+      }
+      const section = state.sections.find((candidate) => candidate.name === row.section);
+      return ((section && section.groups) || []).find((group) => group.name === row.group) || null;
+    }
+
+    // Members of a group are shown together under one header, at the position
+    // of the group's first member, even when other rows sit between them.
+    function groupedRows(rows) {
+      const ordered = [];
+      const members = new Map();
+      for (const row of rows) {
+        const group = groupOf(row);
+        if (!group) {
+          ordered.push(row);
+          continue;
+        }
+        const key = `${row.section}\u0000${group.name}`;
+        if (!members.has(key)) {
+          members.set(key, []);
+          ordered.push(members.get(key));
+        }
+        members.get(key).push(row);
+      }
+      return ordered.flat();
+    }
+
+    function renderGroupHeader(sectionName, group) {
+      const header = document.createElement("button");
+      header.className = "group-header";
+      header.title = group.collapsed ? "クリックで展開" : "クリックで折りたたむ";
+      header.textContent = `${group.collapsed ? "▸" : "▾"} ${group.name}`;
+      const count = document.createElement("span");
+      count.className = "group-count";
+      count.textContent = `${group.items}項目`;
+      header.appendChild(count);
+      header.addEventListener("click", async () => {
+        try {
+          applySnapshot(await apiPost("/app/group-collapse", {
+            section: sectionName,
+            group: group.name,
+            collapsed: !group.collapsed,
+          }));
+          setStatus("");
+        } catch (err) {
+          setStatus(`保存エラー: ${err.message}`);
+        }
+      });
+      return header;
+    }
+
+    async function moveRow(itemId, target, after) {
+      const ids = state.rows
+        .filter((row) => row.section === target.section && row.item_id !== itemId)
+        .map((row) => row.item_id);
+      const at = ids.indexOf(target.item_id) + (after ? 1 : 0);
+      ids.splice(at, 0, itemId);
+      try {
+        const data = await apiPost("/app/reorder-items", {
+          section: target.section,
+          keys: ids.map((id) => id.slice(target.section.length + 1)),
+        });
+        applySnapshot(data);
+        setStatus("");
+      } catch (err) {
+        setStatus(`並べ替えエラー: ${err.message}`);
+      }
+    }
+
+    function renderWeight(row) {
+      const weight = document.createElement("label");
+      weight.className = row.weight === 1 ? "weight" : "weight changed";
+      weight.title = "強調の重み（1.00で強調なし。ダブルクリックで戻す）";
+      const slider = document.createElement("input");
+      slider.type = "range";
+      slider.min = "0.1";
+      slider.max = "2";
+      slider.step = "0.05";
+      slider.value = String(row.weight);
+      const value = document.createElement("span");
+      value.textContent = Number(row.weight).toFixed(2);
+      const save = async (next) => {
+        try {
+          applySnapshot(await apiPost("/app/item-weight", { item_id: row.item_id, weight: next }));
+          setStatus("");
+        } catch (err) {
+          setStatus(`保存エラー: ${err.message}`);
+        }
+      };
+      slider.addEventListener("input", () => {
+        value.textContent = Number(slider.value).toFixed(2);
+      });
+      slider.addEventListener("change", () => save(Number(slider.value)));
+      slider.addEventListener("dblclick", () => save(1));
+      weight.appendChild(slider);
+      weight.appendChild(value);
+      return weight;
+    }
+
+    function render() {
+      const rowsRoot = document.getElementById("rows");
+      rowsRoot.innerHTML = "";
+      const tabbed = state.sections.length > 1;
+      if (!state.sections.some((section) => section.name === activeSection)) {
+        activeSection = state.sections.length > 0 ? state.sections[0].name : "";
+      }
+      renderSectionTabs(tabbed);
+
+      let previousGroup = null;
+      for (const row of groupedRows(state.rows)) {
+        if (tabbed && row.section !== activeSection) {
+          continue;
+        }
+        const group = groupOf(row);
+        if (group !== previousGroup) {
+          previousGroup = group;
+          if (group) {
+            rowsRoot.appendChild(renderGroupHeader(row.section, group));
+          }
+        }
+        if (group && group.collapsed) {
+          continue;
+        }
+        const wrapper = document.createElement("div");
+        wrapper.className = row.excluded ? "row excluded" : "row";
+        if (row.excluded) {
+          wrapper.title = `出力形式「${state.output_format}」では出力されません`;
+        }
+
+        const label = document.createElement("div");
+        label.className = "label";
+        label.draggable = true;
+        label.title = "ドラッグで並べ替え";
+        label.addEventListener("dragstart", (event) => {
+          draggedItemId = row.item_id;
+          event.dataTransfer.effectAllowed = "move";
+          event.dataTransfer.setData("text/plain", row.item_id);
+        });
+        label.addEventListener("dragend", () => {
+          draggedItemId = null;
+          clearDropMarks();
+        });
+        wrapper.addEventListener("dragover", (event) => {
+          const dragged = state.rows.find((other) => other.item_id === draggedItemId);
+          if (!dragged || dragged === row || dragged.section !== row.section) {
+            return;
+          }
+          event.preventDefault();
+          const rect = wrapper.getBoundingClientRect();
+          const after = event.clientY > rect.top + rect.height / 2;
+          clearDropMarks();
+          wrapper.classList.add(after ? "drop-after" : "drop-before");
+        });
+        wrapper.addEventListener("dragleave", () => {
+          wrapper.classList.remove("drop-before", "drop-after");
+        });
+        wrapper.addEventListener("drop", (event) => {
+          event.preventDefault();
+          const after = wrapper.classList.contains("drop-after");
+          const itemId = draggedItemId;
+          draggedItemId = null;
+          clearDropMarks();
+          if (itemId) {
+            moveRow(itemId, row, after);
+          }
+        });
+        const name = document.createElement("span");
+        name.className = "label-name";
+        const lock = document.createElement("button");
+        lock.className = row.locked ? "lock locked" : "lock";
+        lock.textContent = row.locked ? "🔒" : "🔓";
+        lock.title = row.locked
+          ? "固定中: 「ランダム」で変わりません（クリックで解除）"
+          : "クリックで固定: 「ランダム」で変わらないようにする";
+        name.appendChild(lock);
+        name.appendChild(document.createTextNode(row.label));
+        label.appendChild(name);
+        if (state.emphasis_enabled && !row.excluded) {
+          label.classList.add("weighted");
+          label.appendChild(renderWeight(row));
+        }
+
+        const pool = row.random_pool || [];
+        const usage = row.usage || {};
+        const select = document.createElement("select");
+        for (const choice of orderedChoices(row)) {
+          const option = document.createElement("option");
+          option.value = choice;
+          option.textContent = pool.includes(choice) ? `🎲 ${choice}` : choice;
+          option.title = usage[choice] ? `${choice}（コピー ${usage[choice]}回）` : choice;
+          if (choice === row.selected) {
+            option.selected = true;
+          }
           select.appendChild(option);
         }
 
@@ -369,13 +1463,50 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
         del.title = "選択中のキーワードを削除";
         del.disabled = !row.selected || row.selected === NO_SELECTION;
 
+        const rename = document.createElement("button");
+        rename.className = "rename";
+        rename.textContent = "✏";
+        rename.title = "選択肢の名前変更・並べ替え";
+        rename.disabled = row.choices.length < 2;
+
+        const tools = document.createElement("div");
+        tools.className = "choice-tools";
+        tools.appendChild(rename);
+        tools.appendChild(del);
+
+        const dice = document.createElement("button");
+        dice.className = pool.includes(row.selected) ? "dice pooled" : "dice";
+        dice.textContent = "🎲";
+        dice.title = pool.length > 0
+          ? `候補 ${pool.length}件からランダムに選択（右クリック: 選択中の値を候補に追加・除外）`
+          : "ランダムに選択（右クリック: 選択中の値を候補に追加）";
+        dice.disabled = row.choices.length < 2 || row.locked;
+
         const input = document.createElement("input");
         input.type = "text";
         input.placeholder = "Enterで確定";
+        input.title = "Enter: 確定して選択肢に追加 / Shift+Enter: 選択肢に追加せずに使う";
         input.disabled = !row.allow_free_text;
-        input.value = row.free_text || "";
+        const recent = row.free_text_history || [];
+        let suggestions = null;
+        if (row.allow_free_text && recent.length > 0) {
+          suggestions = document.createElement("datalist");
+          suggestions.id = `freeTextHistory-${state.rows.indexOf(row)}`;
+          for (const value of recent) {
+            const option = document.createElement("option");
+            option.value = value;
+            suggestions.appendChild(option);
+          }
+          input.setAttribute("list", suggestions.id);
+        }
+        input.value = row.draft || row.free_text || "";
+        markDraftState(input, row);
+        const suggestBox = document.createElement("div");
+        suggestBox.className = "choice-suggest";
+        suggestBox.hidden = true;
 
         select.addEventListener("change", async () => {
+          cancelDraftSave(row);
           try {
             const data = await apiPost("/app/combo-change", {
               item_id: row.item_id,
@@ -410,16 +1541,84 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
           }
         });
 
+        rename.addEventListener("click", () => {
+          managedItemId = row.item_id;
+          renderChoicesDialog();
+          document.getElementById("choicesDialog").showModal();
+        });
+
+        lock.addEventListener("click", async () => {
+          try {
+            applySnapshot(await apiPost("/app/item-lock", { item_id: row.item_id, locked: !row.locked }));
+            setStatus("");
+          } catch (err) {
+            setStatus(`保存エラー: ${err.message}`);
+          }
+        });
+
+        dice.addEventListener("click", async () => {
+          cancelDraftSave(row);
+          try {
+            applySnapshot(await apiPost("/app/randomize", { item_id: row.item_id }));
+            setStatus("");
+          } catch (err) {
+            setStatus(`保存エラー: ${err.message}`);
+          }
+        });
+
+        dice.addEventListener("contextmenu", async (event) => {
+          event.preventDefault();
+          if (!select.value || select.value === NO_SELECTION) {
+            setStatus("候補に追加する値を選択してください");
+            return;
+          }
+          const enabled = !pool.includes(select.value);
+          try {
+            const data = await apiPost("/app/random-pool", {
+              item_id: row.item_id,
+              value: select.value,
+              enabled,
+            });
+            applySnapshot(data);
+            setStatus(enabled ? `${select.value}をランダムの候補に追加しました` : `${select.value}をランダムの候補から外しました`);
+          } catch (err) {
+            setStatus(`保存エラー: ${err.message}`);
+          }
+        });
+
+        input.addEventListener("input", () => {
+          markDraftState(input, row);
+          const draft = input.value === (row.free_text || "") ? "" : input.value;
+          scheduleDraftSave(row, draft);
+          scheduleSuggest(row, input.value, suggestBox);
+        });
+
+        suggestBox.addEventListener("click", async (event) => {
+          const choice = event.target.dataset && event.target.dataset.choice;
+          if (!choice) {
+            return;
+          }
+          cancelDraftSave(row);
+          try {
+            applySnapshot(await apiPost("/app/combo-change", { item_id: row.item_id, selected: choice }));
+            setStatus("");
+          } catch (err) {
+            setStatus(`保存エラー: ${err.message}`);
+          }
+        });
+
         input.addEventListener("keydown", async (event) => {
           if (event.key !== "Enter") {
             return;
           }
           event.preventDefault();
+          cancelDraftSave(row);
           try {
             const data = await apiPost("/app/free-confirm", {
               item_id: row.item_id,
               selected: select.value,
               value: input.value,
+              once: event.shiftKey,
             });
             applySnapshot(data);
             setStatus("");
@@ -430,23 +1629,109 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
 
         wrapper.appendChild(label);
         wrapper.appendChild(select);
-        wrapper.appendChild(del);
+        wrapper.appendChild(tools);
+        wrapper.appendChild(dice);
         wrapper.appendChild(input);
+        if (suggestions) {
+          wrapper.appendChild(suggestions);
+        }
+        wrapper.appendChild(suggestBox);
         rowsRoot.appendChild(wrapper);
       }
 
+      const sectionPreview = document.getElementById("sectionPreview");
+      const current = state.sections.find((section) => section.name === activeSection);
+      sectionPreview.hidden = !tabbed;
+      sectionPreview.textContent = tabbed && current ? current.preview : "";
       document.getElementById("preview").textContent = state.preview;
+      renderTokenCount();
+    }
+
+    function renderTokenCount() {
+      const badge = document.getElementById("tokenCount");
+      const count = state.token_count;
+      badge.textContent = state.preview ? `約${count}トークン` : "";
+      badge.classList.toggle("warn", count > CLIP_CHUNK_TOKENS && count <= CLIP_TOKEN_LIMIT);
+      badge.classList.toggle("over", count > CLIP_TOKEN_LIMIT);
+      if (count > CLIP_TOKEN_LIMIT) {
+        badge.title = `${CLIP_TOKEN_LIMIT}トークンを超えています。後ろの語は無視されることがあります`;
+      } else if (count > CLIP_CHUNK_TOKENS) {
+        badge.title = `${CLIP_CHUNK_TOKENS}トークンを超えたため、後ろの語の効きが弱くなることがあります`;
+      } else {
+        badge.title = "CLIPトークン数の目安";
+      }
+    }
+
+    function showConfigIssues(issues) {
+      const orphaned = issues.filter((issue) => issue.kind === "orphaned_state");
+      document.getElementById("cleanupConfig").hidden = orphaned.length === 0;
+      if (issues.length > 0) {
+        setStatus(`設定の警告 ${issues.length}件: ${issues[0].message}`);
+      }
     }
 
     async function init() {
+      const overlay = document.getElementById("loadingOverlay");
       try {
         const data = await apiGet("/app/init");
         applySnapshot(data);
       } catch (err) {
+        overlay.textContent = `起動エラー: ${err.message}`;
         setStatus(`起動エラー: ${err.message}`);
+        postHostMessage({ type: "ready" });
+        return;
+      }
+      overlay.hidden = true;
+      postHostMessage({ type: "ready" });
+      void pollPagesReady();
+      void applyFeatures();
+      try {
+        const report = await apiGet("/app/config/validate");
+        showConfigIssues(report.issues || []);
+      } catch (_) {
+        // Validation is advisory; the editor works without it.
       }
     }
 
+    // Hides the controls of subsystems switched off in [features].
+    async function applyFeatures() {
+      try {
+        const data = await apiGet("/app/features");
+        document.getElementById("openStats").hidden = !data.features.analytics;
+      } catch (_) {
+        // Without the list every control stays as built.
+      }
+    }
+
+    // The server writes the history pages in the background after startup;
+    // until then there is nothing to open.
+    async function pollPagesReady() {
+      const button = document.getElementById("openHistory");
+      const label = button.textContent;
+      button.disabled = true;
+      button.textContent = "履歴ページを生成中…";
+      for (;;) {
+        try {
+          const data = await apiGet("/app/ready");
+          if (data.ready) {
+            if (data.error) {
+              setStatus(`履歴ページの生成に失敗しました: ${data.error}`);
+            } else if ((data.warnings || []).length > 0) {
+              setStatus(`起動時の確認 ${data.warnings.length}件: ${data.warnings.join(" / ")}`);
+            } else if (data.lan_pairing_url) {
+              setStatus(`LAN接続用URL（他の端末で一度開いてください）: ${data.lan_pairing_url}`);
+            }
+            break;
+          }
+        } catch (_) {
+          // Retry while the server is briefly unavailable.
+        }
+        await new Promise((resolve) => setTimeout(resolve, PAGES_READY_POLL_MS));
+      }
+      button.disabled = false;
+      button.textContent = label;
+    }
+
     document.getElementById("openHistory").addEventListener("click", async () => {
       try {
         await apiPost("/app/open-history", {});
@@ -456,6 +1741,651 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       }
     });
 
+    function renderParseResult(data) {
+      parsedMatches = data.matches || [];
+      const table = document.getElementById("parseResult");
+      const body = table.querySelector("tbody");
+      body.innerHTML = "";
+      for (const [index, match] of parsedMatches.entries()) {
+        const tr = document.createElement("tr");
+        tr.className = match.kind === "fuzzy" ? "fuzzy" : "";
+        const check = document.createElement("input");
+        check.type = "checkbox";
+        check.checked = true;
+        check.dataset.index = String(index);
+        const cells = [match.label, match.token, match.value, MATCH_KIND_LABELS[match.kind] || match.kind];
+        const checkCell = document.createElement("td");
+        checkCell.appendChild(check);
+        tr.appendChild(checkCell);
+        for (const text of cells) {
+          const td = document.createElement("td");
+          td.textContent = text;
+          tr.appendChild(td);
+        }
+        // Values outside the choices stay free text unless this is ticked.
+        const addCell = document.createElement("td");
+        if (match.kind === "free_text") {
+          const add = document.createElement("input");
+          add.type = "checkbox";
+          add.className = "parse-add-choice";
+          add.dataset.index = String(index);
+          addCell.appendChild(add);
+        }
+        tr.appendChild(addCell);
+        body.appendChild(tr);
+      }
+      table.hidden = parsedMatches.length === 0;
+      const leftovers = data.leftovers || [];
+      const leftoverBox = document.getElementById("parseLeftovers");
+      leftoverBox.hidden = leftovers.length === 0 && parsedMatches.length > 0;
+      leftoverBox.textContent = leftovers.length > 0
+        ? `対応する項目が無い語（適用されません）: ${leftovers.join(" / ")}`
+        : "対応する項目が見つかりませんでした。";
+      document.getElementById("parseApply").disabled = parsedMatches.length === 0;
+    }
+
+    document.getElementById("openParse").addEventListener("click", () => {
+      renderParseResult({ matches: [], leftovers: [] });
+      document.getElementById("parseLeftovers").hidden = true;
+      document.getElementById("parseDialog").showModal();
+      document.getElementById("parseInput").focus();
+    });
+
+    document.getElementById("parseRun").addEventListener("click", async () => {
+      try {
+        const data = await apiPost("/app/parse-prompt", {
+          prompt: document.getElementById("parseInput").value,
+        });
+        renderParseResult(data);
+      } catch (err) {
+        setStatus(`解析失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("parseApply").addEventListener("click", async () => {
+      const items = [];
+      const rows = document.getElementById("parseResult").querySelectorAll("tbody tr");
+      for (const row of rows) {
+        const check = row.querySelector("td:first-child input");
+        if (!check.checked) {
+          continue;
+        }
+        const match = parsedMatches[Number(check.dataset.index)];
+        const add = row.querySelector(".parse-add-choice");
+        items.push({ item_id: match.item_id, value: match.value, add_choice: Boolean(add && add.checked) });
+      }
+      try {
+        const data = await apiPost("/app/apply-parsed", { items });
+        applySnapshot(data);
+        document.getElementById("parseDialog").close();
+        setStatus(`${items.length}項目を読み込みました。`);
+      } catch (err) {
+        setStatus(`適用失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("parseClose").addEventListener("click", () => {
+      document.getElementById("parseDialog").close();
+    });
+
+    document.getElementById("openStats").addEventListener("click", async () => {
+      try {
+        await apiPost("/app/open-stats", {});
+        setStatus("");
+      } catch (err) {
+        setStatus(`統計オープン失敗: ${err.message}`);
+      }
+    });
+
+    async function runDiagnostics() {
+      const report = document.getElementById("diagnosticsReport");
+      report.value = "診断中…";
+      try {
+        const data = await apiGet("/app/diagnostics");
+        report.value = data.report;
+        const failed = data.checks.filter((check) => check.status !== "ok").length;
+        setStatus(failed > 0 ? `診断: 要確認 ${failed}件` : "診断: 問題は見つかりませんでした。");
+      } catch (err) {
+        report.value = "";
+        setStatus(`診断失敗: ${err.message}`);
+      }
+    }
+
+    document.getElementById("openDiagnostics").addEventListener("click", () => {
+      document.getElementById("diagnosticsDialog").showModal();
+      runDiagnostics();
+    });
+
+    document.getElementById("diagnosticsRun").addEventListener("click", runDiagnostics);
+
+    document.getElementById("diagnosticsCopy").addEventListener("click", async () => {
+      const report = document.getElementById("diagnosticsReport");
+      try {
+        await navigator.clipboard.writeText(report.value);
+      } catch (_) {
+        report.select();
+        document.execCommand("copy");
+      }
+      setStatus("診断レポートをコピーしました。");
+    });
+
+    document.getElementById("diagnosticsClose").addEventListener("click", () => {
+      document.getElementById("diagnosticsDialog").close();
+    });
+
+    // Config order, or most copied first with ties keeping config order.
+    function orderedChoices(row) {
+      if (!state.sort_by_usage) {
+        return row.choices;
+      }
+      const usage = row.usage || {};
+      const [first, ...rest] = row.choices;
+      const ranked = rest
+        .map((choice, index) => ({ choice, index, count: usage[choice] || 0 }))
+        .sort((a, b) => b.count - a.count || a.index - b.index)
+        .map((entry) => entry.choice);
+      return [first, ...ranked];
+    }
+
+    function renderChoicesDialog() {
+      const row = state.rows.find((candidate) => candidate.item_id === managedItemId);
+      const body = document.getElementById("choicesList").querySelector("tbody");
+      body.innerHTML = "";
+      if (!row) {
+        document.getElementById("choicesDialog").close();
+        return;
+      }
+      document.getElementById("choicesTitle").textContent = state.sort_by_usage
+        ? `${row.label} の選択肢（「よく使う順」がオンの間、ドロップダウンはコピー回数順。同じ回数はこの順）`
+        : `${row.label} の選択肢（上ほどドロップダウンの先頭に表示）`;
+      const choices = row.choices.filter((choice) => choice !== NO_SELECTION);
+      choices.forEach((choice, position) => {
+        const tr = document.createElement("tr");
+        if (choice === row.selected) {
+          tr.className = "selected";
+        }
+        const name = document.createElement("td");
+        name.textContent = choice;
+        const count = document.createElement("td");
+        count.className = "choice-usage";
+        count.textContent = row.usage && row.usage[choice] ? `${row.usage[choice]}回` : "";
+        const tools = document.createElement("td");
+        tools.className = "choice-move";
+        const buttons = [
+          ["⤒", "先頭へ", 1, position === 0],
+          ["▲", "上へ", position, position === 0],
+          ["▼", "下へ", position + 2, position === choices.length - 1],
+        ];
+        for (const [text, title, index, disabled] of buttons) {
+          const button = document.createElement("button");
+          button.textContent = text;
+          button.title = title;
+          button.disabled = disabled;
+          button.addEventListener("click", () => moveChoice(row, choice, index));
+          tools.appendChild(button);
+        }
+        const rename = document.createElement("button");
+        rename.textContent = "✏";
+        rename.title = "名前を変更";
+        rename.addEventListener("click", () => renameChoice(row, choice));
+        tools.appendChild(rename);
+        tr.appendChild(name);
+        tr.appendChild(count);
+        tr.appendChild(tools);
+        body.appendChild(tr);
+      });
+    }
+
+    async function moveChoice(row, value, index) {
+      try {
+        applySnapshot(await apiPost("/app/move-choice", { item_id: row.item_id, value, index }));
+        setStatus("");
+      } catch (err) {
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    }
+
+    async function renameChoice(row, value) {
+      const next = prompt(`${value}の新しい名前`, value);
+      if (next === null || next.trim() === "" || next.trim() === value) {
+        return;
+      }
+      try {
+        const data = await apiPost("/app/rename-choice", {
+          item_id: row.item_id,
+          value,
+          new_value: next.trim(),
+        });
+        applySnapshot(data);
+        setStatus(`${value}を${next.trim()}に変更しました`);
+      } catch (err) {
+        setStatus(`変更エラー: ${err.message}`);
+      }
+    }
+
+    function renderItemsDialog() {
+      const body = document.getElementById("itemsList").querySelector("tbody");
+      body.innerHTML = "";
+      for (const section of state.sections) {
+        const items = [
+          ...state.rows.filter((row) => row.section === section.name).map((row) => ({ ...row, enabled: true })),
+          ...state.hidden_items.filter((item) => item.section === section.name).map((item) => ({ ...item, enabled: false })),
+        ];
+        for (const item of items) {
+          const tr = document.createElement("tr");
+          const toggleCell = document.createElement("td");
+          const toggle = document.createElement("input");
+          toggle.type = "checkbox";
+          toggle.checked = item.enabled;
+          toggle.addEventListener("change", async () => {
+            try {
+              applySnapshot(await apiPost("/app/item-enabled", { item_id: item.item_id, enabled: toggle.checked }));
+              setStatus(toggle.checked ? `${item.label}を表示しました` : `${item.label}を非表示にしました`);
+            } catch (err) {
+              toggle.checked = !toggle.checked;
+              setStatus(`保存エラー: ${err.message}`);
+            }
+          });
+          toggleCell.appendChild(toggle);
+          const sectionCell = document.createElement("td");
+          sectionCell.textContent = section.label;
+          const labelCell = document.createElement("td");
+          labelCell.textContent = item.label;
+          tr.appendChild(toggleCell);
+          tr.appendChild(sectionCell);
+          tr.appendChild(labelCell);
+          body.appendChild(tr);
+        }
+      }
+    }
+
+    document.getElementById("openItems").addEventListener("click", () => {
+      renderItemsDialog();
+      document.getElementById("itemsDialog").showModal();
+    });
+
+    async function loadPacks() {
+      const data = await apiGet("/app/packs");
+      document.getElementById("packsDir").textContent = `フォルダ: ${data.dir}`;
+      const body = document.getElementById("packsList").querySelector("tbody");
+      body.innerHTML = "";
+      if (!data.packs.length) {
+        const tr = document.createElement("tr");
+        const td = document.createElement("td");
+        td.colSpan = 4;
+        td.textContent = "パックがありません。.toml / .json のパックファイルをフォルダに置くか、この画面にドロップしてください。";
+        tr.appendChild(td);
+        body.appendChild(tr);
+      }
+      for (const pack of data.packs) {
+        const tr = document.createElement("tr");
+        const nameCell = document.createElement("td");
+        nameCell.textContent = pack.name;
+        const meta = [pack.version && `v${pack.version}`, pack.author].filter(Boolean).join(" / ");
+        if (meta) {
+          const span = document.createElement("div");
+          span.className = "pack-meta";
+          span.textContent = meta;
+          nameCell.appendChild(span);
+        }
+        nameCell.title = pack.description || pack.file;
+        const sizeCell = document.createElement("td");
+        sizeCell.textContent = `${pack.items}項目・${pack.choices}選択肢`;
+        const pendingCell = document.createElement("td");
+        const pending = pack.pending;
+        const nothing = !pending.sections_added && !pending.items_added && !pending.choices_added;
+        pendingCell.textContent = nothing ? "追加済み" : `${pending.items_added}項目・${pending.choices_added}選択肢`;
+        const actionCell = document.createElement("td");
+        const install = document.createElement("button");
+        install.className = "btn";
+        install.textContent = "追加";
+        install.disabled = nothing;
+        install.addEventListener("click", () => installPack({ file: pack.file }, pack.name));
+        actionCell.appendChild(install);
+        tr.appendChild(nameCell);
+        tr.appendChild(sizeCell);
+        tr.appendChild(pendingCell);
+        tr.appendChild(actionCell);
+        body.appendChild(tr);
+      }
+      const errors = document.getElementById("packsErrors");
+      errors.hidden = !data.errors.length;
+      errors.textContent = data.errors.map((message) => `読めないパック: ${message}`).join(" / ");
+    }
+
+    async function installPack(payload, name) {
+      try {
+        const data = await apiPost("/app/packs/install", payload);
+        applySnapshot(data);
+        const added = data.imported;
+        setStatus(`${data.pack.name || name}: ${added.sections_added}セクション・${added.items_added}項目・${added.choices_added}選択肢を追加しました`);
+        await loadPacks();
+      } catch (err) {
+        setStatus(`パックの追加エラー: ${err.message}`);
+      }
+    }
+
+    async function installPackFile(file) {
+      if (file) {
+        await installPack({ text: await file.text(), name: file.name }, file.name);
+      }
+    }
+
+    document.getElementById("openPacks").addEventListener("click", async () => {
+      try {
+        await loadPacks();
+        document.getElementById("packsDialog").showModal();
+      } catch (err) {
+        setStatus(`パック一覧の読み込み失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("installPackFile").addEventListener("click", () => {
+      document.getElementById("installPackInput").click();
+    });
+
+    document.getElementById("installPackInput").addEventListener("change", async (event) => {
+      const file = event.target.files[0];
+      event.target.value = "";
+      await installPackFile(file);
+    });
+
+    const packsDialog = document.getElementById("packsDialog");
+    packsDialog.addEventListener("dragover", (event) => {
+      event.preventDefault();
+      packsDialog.classList.add("dropping");
+    });
+    packsDialog.addEventListener("dragleave", () => {
+      packsDialog.classList.remove("dropping");
+    });
+    packsDialog.addEventListener("drop", async (event) => {
+      event.preventDefault();
+      packsDialog.classList.remove("dropping");
+      for (const file of event.dataTransfer.files) {
+        await installPackFile(file);
+      }
+    });
+
+    document.getElementById("packsClose").addEventListener("click", () => {
+      packsDialog.close();
+    });
+
+    document.getElementById("itemsClose").addEventListener("click", () => {
+      document.getElementById("itemsDialog").close();
+    });
+
+    document.getElementById("choicesClose").addEventListener("click", () => {
+      document.getElementById("choicesDialog").close();
+    });
+
+    document.getElementById("openSettings").addEventListener("click", async () => {
+      try {
+        const data = await apiGet("/app/settings");
+        document.getElementById("settingsPrefix").value = data.prompt_prefix || "";
+        document.getElementById("settingsSuffix").value = data.prompt_suffix || "";
+        document.getElementById("settingsDelimiter").value = data.delimiter;
+        document.getElementById("settingsDebounce").value = data.copy_debounce_sec;
+        document.getElementById("settingsMaxEntries").value = data.history_max_entries;
+        document.getElementById("settingsPort").value = data.history_server_port;
+        document.getElementById("settingsConfirmDelete").checked = data.confirm_delete;
+        await loadBackups();
+        document.getElementById("settingsDialog").showModal();
+      } catch (err) {
+        setStatus(`設定の読み込み失敗: ${err.message}`);
+      }
+    });
+
+    const BACKUP_REASONS = {
+      remove_choice: "選択肢の削除",
+      rename_choice: "選択肢の名前変更",
+      reset: "リセット",
+      import: "取り込み",
+      apply_preset: "プリセットの適用",
+      delete_preset: "プリセットの削除",
+      cleanup: "設定を整理",
+      restore: "復元",
+    };
+
+    async function loadBackups() {
+      const data = await apiGet("/app/config/backups");
+      const select = document.getElementById("backupSelect");
+      select.innerHTML = "";
+      for (const backup of data.backups) {
+        const option = document.createElement("option");
+        option.value = backup.file;
+        option.textContent = `${backup.created}（${BACKUP_REASONS[backup.reason] || backup.reason}の前）`;
+        select.appendChild(option);
+      }
+      if (!data.backups.length) {
+        const option = document.createElement("option");
+        option.value = "";
+        option.textContent = "バックアップはまだありません";
+        select.appendChild(option);
+      }
+      document.getElementById("restoreBackup").disabled = !data.backups.length;
+    }
+
+    document.getElementById("restoreBackup").addEventListener("click", async () => {
+      const select = document.getElementById("backupSelect");
+      const file = select.value;
+      if (!file) {
+        return;
+      }
+      const label = select.options[select.selectedIndex].textContent;
+      if (!confirm(`config.txt を ${label} の状態に戻しますか？\n今の内容もバックアップしてから戻します。`)) {
+        return;
+      }
+      try {
+        applySnapshot(await apiPost("/app/config/restore", { file }));
+        await applyFeatures();
+        document.getElementById("settingsDialog").close();
+        setStatus(`config.txt を ${label} の状態に戻しました。`);
+      } catch (err) {
+        setStatus(`復元エラー: ${err.message}`);
+      }
+    });
+
+    // An empty number field leaves that setting unchanged.
+    function settingsNumber(id) {
+      const value = document.getElementById(id).value.trim();
+      return value === "" ? undefined : Number(value);
+    }
+
+    document.getElementById("settingsSave").addEventListener("click", async () => {
+      try {
+        const data = await apiPost("/app/settings", {
+          prompt_prefix: document.getElementById("settingsPrefix").value,
+          prompt_suffix: document.getElementById("settingsSuffix").value,
+          delimiter: document.getElementById("settingsDelimiter").value,
+          copy_debounce_sec: settingsNumber("settingsDebounce"),
+          history_max_entries: settingsNumber("settingsMaxEntries"),
+          history_server_port: settingsNumber("settingsPort"),
+          confirm_delete: document.getElementById("settingsConfirmDelete").checked,
+        });
+        applySnapshot(data);
+        document.getElementById("settingsDialog").close();
+        setStatus(data.restart_required
+          ? "設定を保存しました。ポートの変更は再起動後に反映されます。"
+          : "設定を保存しました。");
+      } catch (err) {
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("exportToml").addEventListener("click", () => {
+      location.href = "/app/config/export?format=toml";
+    });
+
+    document.getElementById("exportJson").addEventListener("click", () => {
+      location.href = "/app/config/export?format=json";
+    });
+
+    document.getElementById("importConfig").addEventListener("click", () => {
+      document.getElementById("importConfigFile").click();
+    });
+
+    document.getElementById("importConfigFile").addEventListener("change", async (event) => {
+      const file = event.target.files[0];
+      event.target.value = "";
+      if (!file) {
+        return;
+      }
+      try {
+        const data = await apiPost("/app/config/import", { text: await file.text(), name: file.name });
+        applySnapshot(data);
+        const added = data.imported;
+        setStatus(`${file.name}: ${added.sections_added}セクション・${added.items_added}項目・${added.choices_added}選択肢を追加しました`);
+      } catch (err) {
+        setStatus(`取り込みエラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("settingsClose").addEventListener("click", () => {
+      document.getElementById("settingsDialog").close();
+    });
+
+    document.getElementById("reloadConfig").addEventListener("click", async () => {
+      try {
+        const data = await apiPost("/app/reload", {});
+        applySnapshot(data);
+        await applyFeatures();
+        setStatus("config.txt と履歴を読み直しました。");
+      } catch (err) {
+        setStatus(`再読み込み失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("cleanupConfig").addEventListener("click", async () => {
+      try {
+        const data = await apiPost("/app/config/cleanup", {});
+        setStatus(`不要な保存状態を${data.removed}件削除しました。`);
+        showConfigIssues(data.issues || []);
+      } catch (err) {
+        setStatus(`整理失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("enterMini").hidden = !hasHostIpc();
+    document.getElementById("enterMini").addEventListener("click", () => setMiniMode(true));
+    document.getElementById("exitMini").addEventListener("click", () => setMiniMode(false));
+    document.getElementById("openProfile").hidden = !hasHostIpc();
+    document.getElementById("openProfile").addEventListener("click", () => {
+      const name = prompt("新しいウィンドウで開くプロファイル名（初回は現在の設定をコピーして作成）", "");
+      if (!name || !name.trim()) {
+        return;
+      }
+      postHostMessage({ type: "open-profile", name: name.trim() });
+    });
+
+    document.body.addEventListener("mouseenter", () => {
+      if (!miniMode) {
+        return;
+      }
+      document.body.classList.add("mini-hover");
+      postHostMessage({ type: "mini-hover", hovered: true });
+    });
+
+    document.body.addEventListener("mouseleave", () => {
+      if (!miniMode) {
+        return;
+      }
+      document.body.classList.remove("mini-hover");
+      postHostMessage({ type: "mini-hover", hovered: false });
+    });
+
+    document.getElementById("preview").addEventListener("mousedown", (event) => {
+      if (!miniMode || event.button !== 0) {
+        return;
+      }
+      postHostMessage({ type: "drag-window" });
+    });
+
+    document.getElementById("presetSelect").addEventListener("change", async (event) => {
+      const name = event.target.value;
+      if (!name) {
+        currentPreset = "";
+        renderPresets();
+        return;
+      }
+      for (const row of state.rows) {
+        cancelDraftSave(row);
+      }
+      try {
+        currentPreset = name;
+        applySnapshot(await apiPost("/app/preset/apply", { name }));
+        setStatus(`プリセット「${name}」を適用しました`);
+      } catch (err) {
+        setStatus(`プリセット適用エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("savePreset").addEventListener("click", async () => {
+      const input = prompt("プリセット名（現在の選択・自由入力・重みを保存）", currentPreset);
+      const name = input === null ? "" : input.trim();
+      if (!name) {
+        return;
+      }
+      if (state.presets.includes(name) && !confirm(`プリセット「${name}」を上書きしますか？`)) {
+        return;
+      }
+      try {
+        currentPreset = name;
+        applySnapshot(await apiPost("/app/preset/save", { name }));
+        setStatus(`プリセット「${name}」を保存しました`);
+      } catch (err) {
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("deletePreset").addEventListener("click", async () => {
+      const name = currentPreset;
+      if (!name || !confirm(`プリセット「${name}」を削除しますか？`)) {
+        return;
+      }
+      try {
+        applySnapshot(await apiPost("/app/preset/delete", { name }));
+        setStatus(`プリセット「${name}」を削除しました`);
+      } catch (err) {
+        setStatus(`削除エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("outputFormat").addEventListener("change", async (event) => {
+      try {
+        const data = await apiPost("/app/output-format", { format: event.target.value });
+        applySnapshot(data);
+        setStatus("");
+      } catch (err) {
+        setStatus(`出力形式の変更失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("temporaryMode").addEventListener("change", async (event) => {
+      const enabled = event.target.checked;
+      if (!enabled && state.unsaved_state && !confirm("保存していない選択内容を破棄して一時モードを終了しますか？")) {
+        event.target.checked = true;
+        return;
+      }
+      try {
+        const data = await apiPost("/app/temporary-mode", { enabled });
+        applySnapshot(data);
+        setStatus(enabled ? "一時モード: 選択の変更は「保存」するまで config.txt に書き込みません。" : "");
+      } catch (err) {
+        event.target.checked = !enabled;
+        setStatus(`一時モードの切り替え失敗: ${err.message}`);
+      }
+    });
+
+    document.getElementById("saveTemporary").addEventListener("click", async () => {
+      try {
+        const data = await apiPost("/app/temporary-save", {});
+        applySnapshot(data);
+        setStatus("選択内容を保存しました。");
+      } catch (err) {
+        setStatus(`保存失敗: ${err.message}`);
+      }
+    });
+
     document.getElementById("reset").addEventListener("click", async () => {
       const ok = confirm("選択内容をリセットしてもよろしいですか？");
       if (!ok) {
@@ -470,25 +2400,75 @@ const MAIN_UI_HTML: &str = r#"<!doctype html>
       }
     });
 
-    document.getElementById("copy").addEventListener("click", async () => {
+    document.getElementById("randomWeighted").addEventListener("change", async (event) => {
+      const enabled = event.target.checked;
+      try {
+        applySnapshot(await apiPost("/app/random-weighted", { enabled }));
+        setStatus("");
+      } catch (err) {
+        event.target.checked = !enabled;
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("sortByUsage").addEventListener("change", async (event) => {
+      const enabled = event.target.checked;
+      try {
+        applySnapshot(await apiPost("/app/sort-by-usage", { enabled }));
+        setStatus("");
+      } catch (err) {
+        event.target.checked = !enabled;
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    });
+
+    document.getElementById("randomizeAll").addEventListener("click", async () => {
+      for (const row of state.rows) {
+        cancelDraftSave(row);
+      }
+      try {
+        applySnapshot(await apiPost("/app/randomize", {}));
+        setStatus("");
+      } catch (err) {
+        setStatus(`保存エラー: ${err.message}`);
+      }
+    });
+
+    async function copyPrompt(copyTranslation) {
       try {
         const prompt = state.preview || "";
-        if (!prompt.trim()) {
+        const translation = currentTranslation();
+        if (!prompt.trim() || (copyTranslation && !translation.trim())) {
           return;
         }
-        const data = await apiPost("/app/copy", { prompt });
+        const data = await apiPost("/app/copy", {
+          prompt,
+          translation,
+          copy_translation: copyTranslation,
+        });
         if (data.skipped) {
           setStatus("連続コピーは間引かれました。");
         } else {
-          setStatus("コピーしました。");
+          const rotation = data.rotation;
+          if (rotation && rotation.pending_ids.length > 0) {
+            setStatus(`コピーしました。履歴 ${rotation.active_entries}/${rotation.max_entries} 件：あと ${rotation.remaining + 1} 件で古い履歴がアーカイブされます（履歴ページの「今すぐアーカイブ」で先に移動できます）。`);
+          } else {
+            setStatus("コピーしました。");
+          }
           showCopyHover("コピーしました");
         }
       } catch (err) {
         setStatus(`コピー失敗: ${err.message}`);
       }
-    });
+    }
+
+    document.getElementById("copy").addEventListener("click", () => copyPrompt(false));
+    document.getElementById("copyTranslation").addEventListener("click", () => copyPrompt(true));
+
+    const PAGES_READY_POLL_MS = 500;
 
     init();
+    connectSocket();
   </script>
 </body>
 </html>
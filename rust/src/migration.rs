@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One upgrade step of an on-disk document, from `from_version` to `from_version + 1`.
+pub struct Migration<T> {
+    pub from_version: u32,
+    pub description: &'static str,
+    pub apply: fn(&mut T) -> Result<()>,
+}
+
+/// Applies the migrations in order until `doc` reaches `target_version`.
+pub fn run_migrations<T>(
+    doc: &mut T,
+    version: u32,
+    target_version: u32,
+    migrations: &[Migration<T>],
+) -> Result<u32> {
+    if version > target_version {
+        return Err(anyhow!(
+            "unsupported schema version {version} (supported up to {target_version})"
+        ));
+    }
+
+    let mut current = version;
+    while current < target_version {
+        let migration = migrations
+            .iter()
+            .find(|m| m.from_version == current)
+            .ok_or_else(|| anyhow!("no migration from schema version {current}"))?;
+        (migration.apply)(doc).with_context(|| {
+            format!(
+                "migration from schema version {current} failed: {}",
+                migration.description
+            )
+        })?;
+        current += 1;
+    }
+
+    Ok(current)
+}
+
+/// Copies `path` next to itself as `<stem>.v<version>.<timestamp>.bak.<ext>`.
+pub fn backup_before_migration(path: &Path, from_version: u32) -> Result<PathBuf> {
+    let stem = path.file_stem().and_then(|v| v.to_str()).unwrap_or("data");
+    let ext = path
+        .extension()
+        .and_then(|v| v.to_str())
+        .map(|v| format!(".{v}"))
+        .unwrap_or_default();
+    let now_tag = Local::now().format("%Y%m%d_%H%M%S").to_string();
+    let backup = path.with_file_name(format!("{stem}.v{from_version}.{now_tag}.bak{ext}"));
+
+    fs::copy(path, &backup).with_context(|| {
+        format!(
+            "failed to backup before migration: {} -> {}",
+            path.display(),
+            backup.display()
+        )
+    })?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_migrations, Migration};
+    use anyhow::Result;
+
+    fn push_one(doc: &mut Vec<u32>) -> Result<()> {
+        doc.push(1);
+        Ok(())
+    }
+
+    fn push_two(doc: &mut Vec<u32>) -> Result<()> {
+        doc.push(2);
+        Ok(())
+    }
+
+    const MIGRATIONS: [Migration<Vec<u32>>; 2] = [
+        Migration {
+            from_version: 1,
+            description: "push two",
+            apply: push_two,
+        },
+        Migration {
+            from_version: 0,
+            description: "push one",
+            apply: push_one,
+        },
+    ];
+
+    #[test]
+    fn runs_pending_migrations_in_version_order() {
+        let mut doc = Vec::new();
+        let version = run_migrations(&mut doc, 0, 2, &MIGRATIONS).expect("migrate");
+        assert_eq!(version, 2);
+        assert_eq!(doc, vec![1, 2]);
+
+        let mut partial = Vec::new();
+        run_migrations(&mut partial, 1, 2, &MIGRATIONS).expect("migrate partial");
+        assert_eq!(partial, vec![2]);
+    }
+
+    #[test]
+    fn rejects_newer_schema_versions() {
+        let mut doc = Vec::new();
+        let err = run_migrations(&mut doc, 3, 2, &MIGRATIONS).expect_err("newer version");
+        assert!(err.to_string().contains("unsupported schema version 3"));
+    }
+}
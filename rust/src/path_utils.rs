@@ -1,6 +1,9 @@
 use std::env;
 use std::path::{Path, PathBuf};
 
+/// Config file names looked for, in order; `config.txt` is TOML.
+pub const CONFIG_FILE_NAMES: [&str; 4] = ["config.txt", "config.json", "config.yaml", "config.yml"];
+
 pub fn get_base_dir() -> PathBuf {
     let exe_dir = env::current_exe()
         .ok()
@@ -32,19 +35,21 @@ pub fn resolve_config_path(raw: Option<String>, base_dir: &Path) -> PathBuf {
         return path;
     }
 
-    let candidates = [
-        base_dir.join("config.txt"),
-        base_dir.join("config").join("config.txt"),
-    ];
-    for path in candidates {
-        if path.exists() {
-            return path;
-        }
-    }
+    find_config(base_dir).unwrap_or_else(|| base_dir.join("config.txt"))
+}
 
-    base_dir.join("config.txt")
+/// The first existing config in `base_dir`, in the order of `config_candidates`.
+pub fn find_config(base_dir: &Path) -> Option<PathBuf> {
+    config_candidates(base_dir).find(|path| path.exists())
 }
 
 fn has_config_candidate(base_dir: &Path) -> bool {
-    base_dir.join("config.txt").exists() || base_dir.join("config").join("config.txt").exists()
+    find_config(base_dir).is_some()
+}
+
+/// Each name of `CONFIG_FILE_NAMES` in `base_dir`, then in `base_dir/config`.
+fn config_candidates(base_dir: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    [base_dir.to_path_buf(), base_dir.join("config")]
+        .into_iter()
+        .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
 }
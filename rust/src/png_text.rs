@@ -0,0 +1,388 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Text key the Stable Diffusion web UI (A1111) writes the generation prompt
+/// under and reads back in its PNG Info tab.
+pub const PARAMETERS_KEY: &str = "parameters";
+
+/// One chunk of a PNG stream, borrowed from the file bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub kind: [u8; 4],
+    pub data: &'a [u8],
+}
+
+/// Splits a PNG file into its chunks, stopping after `IEND`. CRCs are not
+/// checked; the chunks are only copied or searched for text.
+pub fn read_chunks(png: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    let mut rest = png
+        .strip_prefix(PNG_SIGNATURE)
+        .ok_or_else(|| anyhow!("not a PNG file"))?;
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(anyhow!("truncated PNG chunk"));
+        }
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        let data = rest
+            .get(8..8 + length)
+            .filter(|_| rest.len() >= 12 + length)
+            .ok_or_else(|| anyhow!("truncated PNG chunk"))?;
+        chunks.push(Chunk { kind, data });
+        rest = &rest[12 + length..];
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+    if chunks.first().is_none_or(|chunk| &chunk.kind != b"IHDR") {
+        return Err(anyhow!("PNG does not start with IHDR"));
+    }
+    Ok(chunks)
+}
+
+/// Keyword of a `tEXt` / `iTXt` chunk, if `chunk` is one.
+fn text_keyword<'a>(chunk: &Chunk<'a>) -> Option<&'a [u8]> {
+    if &chunk.kind != b"tEXt" && &chunk.kind != b"iTXt" {
+        return None;
+    }
+    chunk.data.split(|byte| *byte == 0).next()
+}
+
+/// Uncompressed `tEXt` / `iTXt` chunks as `(keyword, text)`, in file order.
+/// Compressed text (`zTXt`, compressed `iTXt`) is skipped.
+pub fn text_chunks(png: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut texts = Vec::new();
+    for chunk in read_chunks(png)? {
+        let Some(keyword) = text_keyword(&chunk) else {
+            continue;
+        };
+        let Some(rest) = chunk.data.get(keyword.len() + 1..) else {
+            continue;
+        };
+        let text = if &chunk.kind == b"tEXt" {
+            rest.iter().map(|byte| char::from(*byte)).collect()
+        } else {
+            // Compression flag and method, then language and translated keyword.
+            let [0, _, rest @ ..] = rest else {
+                continue;
+            };
+            let mut fields = rest.splitn(3, |byte| *byte == 0);
+            let (Some(_), Some(_), Some(text)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            String::from_utf8_lossy(text).into_owned()
+        };
+        texts.push((String::from_utf8_lossy(keyword).into_owned(), text));
+    }
+    Ok(texts)
+}
+
+/// Generation info a Stable Diffusion front end stored in an image.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationMetadata {
+    /// `a1111` or `comfyui`.
+    pub source: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub negative_prompt: String,
+    /// Sampler settings in the web UI's `Steps: 20, Sampler: ...` form.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub settings: String,
+}
+
+impl GenerationMetadata {
+    pub fn source_label(&self) -> &str {
+        match self.source.as_str() {
+            "a1111" => "Stable Diffusion web UI",
+            "comfyui" => "ComfyUI",
+            other => other,
+        }
+    }
+}
+
+/// Reads the A1111 `parameters` text, or ComfyUI's `prompt` graph (falling
+/// back to its `workflow` graph), from a PNG. `None` for other files or PNGs
+/// without either.
+pub fn read_generation_metadata(png: &[u8]) -> Option<GenerationMetadata> {
+    let texts = text_chunks(png).ok()?;
+    let find = |key: &str| {
+        texts
+            .iter()
+            .find(|(keyword, _)| keyword == key)
+            .map(|(_, text)| text.as_str())
+    };
+    if let Some(parameters) = find(PARAMETERS_KEY) {
+        return parse_a1111_parameters(parameters);
+    }
+    let graph = |key: &str| find(key).and_then(|text| serde_json::from_str::<Value>(text).ok());
+    graph("prompt")
+        .and_then(|graph| parse_comfyui_prompt(&graph))
+        .or_else(|| graph("workflow").and_then(|graph| parse_comfyui_workflow(&graph)))
+}
+
+/// Splits web UI parameters: prompt lines, then `Negative prompt:` lines,
+/// then a final `Steps: ...` settings line.
+fn parse_a1111_parameters(text: &str) -> Option<GenerationMetadata> {
+    let lines: Vec<&str> = text.trim().lines().collect();
+    let settings_at = lines
+        .iter()
+        .rposition(|line| line.starts_with("Steps: "))
+        .unwrap_or(lines.len());
+    let negative_at = lines[..settings_at]
+        .iter()
+        .position(|line| line.starts_with("Negative prompt:"))
+        .unwrap_or(settings_at);
+
+    let mut negative_lines = lines[negative_at..settings_at].to_vec();
+    if let Some(first) = negative_lines.first_mut() {
+        *first = first.trim_start_matches("Negative prompt:");
+    }
+    let metadata = GenerationMetadata {
+        source: "a1111".to_string(),
+        prompt: lines[..negative_at].join("\n").trim().to_string(),
+        negative_prompt: negative_lines.join("\n").trim().to_string(),
+        settings: lines[settings_at..].join("\n").trim().to_string(),
+    };
+    (!metadata.prompt.is_empty() || !metadata.settings.is_empty()).then_some(metadata)
+}
+
+/// Reads ComfyUI's API-format graph (`{node_id: {class_type, inputs}}`): the
+/// first sampler's positive/negative text nodes and its settings.
+fn parse_comfyui_prompt(graph: &Value) -> Option<GenerationMetadata> {
+    let nodes = graph.as_object()?;
+    let text_of = |link: Option<&Value>| -> String {
+        link.and_then(|link| link.get(0))
+            .and_then(|id| match id {
+                Value::String(id) => nodes.get(id),
+                other => nodes.get(&other.to_string()),
+            })
+            .and_then(|node| node.pointer("/inputs/text"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+
+    let sampler = nodes.values().find(|node| {
+        node.get("class_type")
+            .and_then(Value::as_str)
+            .is_some_and(|class| class.contains("KSampler"))
+    });
+    let Some(inputs) = sampler.and_then(|node| node.get("inputs")) else {
+        // No sampler: list every text prompt in the graph.
+        let prompt = nodes
+            .values()
+            .filter_map(|node| node.pointer("/inputs/text").and_then(Value::as_str))
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        return (!prompt.is_empty()).then(|| GenerationMetadata {
+            source: "comfyui".to_string(),
+            prompt,
+            ..GenerationMetadata::default()
+        });
+    };
+
+    let settings = [
+        ("steps", "Steps"),
+        ("sampler_name", "Sampler"),
+        ("scheduler", "Schedule type"),
+        ("cfg", "CFG scale"),
+        ("seed", "Seed"),
+        ("noise_seed", "Seed"),
+        ("denoise", "Denoising strength"),
+    ]
+    .iter()
+    .filter_map(|(key, label)| {
+        let value = match inputs.get(*key)? {
+            Value::String(text) => text.clone(),
+            Value::Number(number) => number.to_string(),
+            _ => return None,
+        };
+        Some(format!("{label}: {value}"))
+    })
+    .collect::<Vec<_>>()
+    .join(", ");
+    Some(GenerationMetadata {
+        source: "comfyui".to_string(),
+        prompt: text_of(inputs.get("positive")),
+        negative_prompt: text_of(inputs.get("negative")),
+        settings,
+    })
+}
+
+/// Reads ComfyUI's editor graph (`{nodes: [{type, widgets_values}]}`), which
+/// does not say which text node is negative; all prompts are listed in order.
+fn parse_comfyui_workflow(graph: &Value) -> Option<GenerationMetadata> {
+    let prompt = graph
+        .get("nodes")?
+        .as_array()?
+        .iter()
+        .filter(|node| node.get("type").and_then(Value::as_str) == Some("CLIPTextEncode"))
+        .filter_map(|node| node.pointer("/widgets_values/0").and_then(Value::as_str))
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!prompt.is_empty()).then(|| GenerationMetadata {
+        source: "comfyui".to_string(),
+        prompt,
+        ..GenerationMetadata::default()
+    })
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    output.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Returns a copy of `png` with `text` stored under the `parameters` key,
+/// replacing any previous one. Latin-1 text goes into a `tEXt` chunk; text
+/// with other characters (Japanese prompts) into an uncompressed UTF-8 `iTXt`
+/// chunk, the same way the web UI saves it.
+pub fn embed_parameters(png: &[u8], text: &str) -> Result<Vec<u8>> {
+    let chunks = read_chunks(png)?;
+    let (kind, data) = if text.chars().all(|ch| (ch as u32) < 0x100 && ch != '\0') {
+        let mut data = PARAMETERS_KEY.as_bytes().to_vec();
+        data.push(0);
+        data.extend(text.chars().map(|ch| ch as u8));
+        (*b"tEXt", data)
+    } else {
+        let mut data = PARAMETERS_KEY.as_bytes().to_vec();
+        // Null separator, no compression, method 0, empty language and
+        // translated keyword.
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        (*b"iTXt", data)
+    };
+
+    let mut output = Vec::with_capacity(png.len() + data.len() + 12);
+    output.extend_from_slice(PNG_SIGNATURE);
+    for (index, chunk) in chunks.iter().enumerate() {
+        if text_keyword(chunk) == Some(PARAMETERS_KEY.as_bytes()) {
+            continue;
+        }
+        write_chunk(&mut output, &chunk.kind, chunk.data);
+        if index == 0 {
+            write_chunk(&mut output, &kind, &data);
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{embed_parameters, read_chunks, read_generation_metadata, write_chunk};
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn sample_png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .expect("encode png");
+        bytes
+    }
+
+    #[test]
+    fn embeds_parameters_once_after_ihdr() {
+        let png = sample_png();
+        let ascii = embed_parameters(&png, "sunset glow, watercolor").expect("embed ascii");
+        let chunks = read_chunks(&ascii).expect("read back");
+        assert_eq!(&chunks[0].kind, b"IHDR");
+        assert_eq!(&chunks[1].kind, b"tEXt");
+        assert_eq!(chunks[1].data, b"parameters\0sunset glow, watercolor");
+
+        // Embedding again replaces the earlier chunk instead of adding one.
+        let japanese = embed_parameters(&ascii, "[被写体]：猫").expect("embed utf-8");
+        let chunks = read_chunks(&japanese).expect("read back");
+        let text_chunks: Vec<_> = chunks
+            .iter()
+            .filter(|chunk| &chunk.kind == b"tEXt" || &chunk.kind == b"iTXt")
+            .collect();
+        assert_eq!(text_chunks.len(), 1);
+        assert_eq!(&text_chunks[0].kind, b"iTXt");
+        assert!(text_chunks[0].data.ends_with("[被写体]：猫".as_bytes()));
+        assert_eq!(&chunks.last().expect("IEND").kind, b"IEND");
+
+        // The decoder validates CRCs, so the result must still load.
+        image::load_from_memory(&japanese).expect("decodes");
+        assert!(embed_parameters(b"GIF89a", "x").is_err());
+    }
+
+    /// `png` with one extra `tEXt` chunk before `IEND`.
+    fn with_text(png: &[u8], keyword: &str, text: &str) -> Vec<u8> {
+        let chunks = read_chunks(png).expect("chunks");
+        let mut output = png[..8].to_vec();
+        for chunk in &chunks {
+            if &chunk.kind == b"IEND" {
+                write_chunk(
+                    &mut output,
+                    b"tEXt",
+                    format!("{keyword}\0{text}").as_bytes(),
+                );
+            }
+            write_chunk(&mut output, &chunk.kind, chunk.data);
+        }
+        output
+    }
+
+    #[test]
+    fn reads_web_ui_and_comfyui_metadata() {
+        let png = sample_png();
+        assert_eq!(read_generation_metadata(&png), None);
+        assert_eq!(read_generation_metadata(b"not a png"), None);
+
+        let a1111 = with_text(
+            &png,
+            "parameters",
+            "masterpiece, cat\nsunset\nNegative prompt: blurry,\nlowres\nSteps: 20, Sampler: Euler a, CFG scale: 7, Seed: 42",
+        );
+        let metadata = read_generation_metadata(&a1111).expect("a1111");
+        assert_eq!(metadata.source, "a1111");
+        assert_eq!(metadata.prompt, "masterpiece, cat\nsunset");
+        assert_eq!(metadata.negative_prompt, "blurry,\nlowres");
+        assert_eq!(
+            metadata.settings,
+            "Steps: 20, Sampler: Euler a, CFG scale: 7, Seed: 42"
+        );
+
+        // The web UI stores non-Latin-1 prompts as UTF-8 iTXt, as embedding does.
+        let japanese = embed_parameters(&png, "[被写体]：猫").expect("embed");
+        let metadata = read_generation_metadata(&japanese).expect("itxt");
+        assert_eq!(metadata.prompt, "[被写体]：猫");
+        assert_eq!(metadata.settings, "");
+
+        let graph = r#"{
+            "3": {"class_type": "KSampler", "inputs": {"seed": 7, "steps": 25, "cfg": 6.5,
+                  "sampler_name": "euler", "scheduler": "normal", "positive": ["6", 0], "negative": ["7", 0]}},
+            "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a red fox"}},
+            "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "watermark"}}
+        }"#;
+        let metadata = read_generation_metadata(&with_text(&png, "prompt", graph)).expect("comfy");
+        assert_eq!(metadata.source, "comfyui");
+        assert_eq!(metadata.prompt, "a red fox");
+        assert_eq!(metadata.negative_prompt, "watermark");
+        assert_eq!(
+            metadata.settings,
+            "Steps: 25, Sampler: euler, Schedule type: normal, CFG scale: 6.5, Seed: 7"
+        );
+
+        let workflow = r#"{"nodes": [{"type": "CLIPTextEncode", "widgets_values": ["a blue bird"]},
+            {"type": "KSampler", "widgets_values": [1, "fixed"]}]}"#;
+        let metadata =
+            read_generation_metadata(&with_text(&png, "workflow", workflow)).expect("workflow");
+        assert_eq!(metadata.prompt, "a blue bird");
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config_store::ConfigStore;
+use crate::encryption::{Cipher, KEY_FILE_NAME, PASSPHRASE_ENV, SALT_FILE_NAME};
+use crate::history_store::HistoryStore;
+use crate::instance_lock::InstanceLock;
+use crate::lan_tls::LanConfig;
+use crate::path_utils::find_config;
+use crate::server::{AppServer, AppState};
+
+/// Folder under the base dir holding one sub-folder (config + history) per extra profile.
+pub const PROFILES_DIR: &str = "profiles";
+const MAX_PROFILE_NAME_CHARS: usize = 64;
+
+/// A running profile: its own config/history state and HTTP server on a distinct port.
+pub struct ProfileInstance {
+    pub state: Arc<AppState>,
+    pub server: AppServer,
+    /// Lock on the data dir; `None` when another instance holds it and this
+    /// one runs read-only. Keep it for as long as the server runs.
+    pub lock: Option<InstanceLock>,
+}
+
+/// Profile names become folder names, so they must be a single safe path segment.
+pub fn validate_profile_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("profile name is empty"));
+    }
+    if name.chars().count() > MAX_PROFILE_NAME_CHARS {
+        return Err(anyhow!(
+            "profile name is longer than {MAX_PROFILE_NAME_CHARS} characters"
+        ));
+    }
+    if name == "." || name == ".." || name.ends_with('.') {
+        return Err(anyhow!("invalid profile name: {name}"));
+    }
+    if name.chars().any(|ch| {
+        ch.is_control() || matches!(ch, '\\' | '/' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+    }) {
+        return Err(anyhow!(
+            "profile name contains a character not allowed in folder names"
+        ));
+    }
+    Ok(name.to_string())
+}
+
+pub fn profile_dir(base_dir: &Path, name: &str) -> PathBuf {
+    base_dir.join(PROFILES_DIR).join(name)
+}
+
+/// Creates the profile folder on first use and returns its config path. A
+/// config the profile already has is found like the main one, whatever its
+/// format; otherwise it is seeded from `template_config` (the main profile's
+/// config, under the same file name so a `config.json` stays JSON).
+pub fn prepare_profile(base_dir: &Path, name: &str, template_config: &Path) -> Result<PathBuf> {
+    let dir = profile_dir(base_dir, name);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create profile dir: {}", dir.display()))?;
+    if let Some(existing) = find_config(&dir) {
+        return Ok(existing);
+    }
+    let file_name = template_config
+        .file_name()
+        .unwrap_or_else(|| "config.txt".as_ref());
+    let config_path = dir.join(file_name);
+    fs::copy(template_config, &config_path).with_context(|| {
+        format!(
+            "failed to copy config: {} -> {}",
+            template_config.display(),
+            config_path.display()
+        )
+    })?;
+    Ok(config_path)
+}
+
+/// Key for the history files: a passphrase in `IPG_HISTORY_PASSPHRASE` wins,
+/// otherwise the key file. Salt and key are created only when encryption is
+/// turned on; existing ones are still loaded with encryption off, so the
+/// history can be decrypted back to plain files.
+fn history_cipher(data_dir: &Path, config: &ConfigStore) -> Result<Option<Cipher>> {
+    let salt_file = data_dir.join(SALT_FILE_NAME);
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if config.history_encryption() || salt_file.exists() {
+            return Cipher::from_passphrase(&passphrase, &salt_file).map(Some);
+        }
+    }
+    let key_file = config
+        .history_encryption_key_file()
+        .map(|path| data_dir.join(path))
+        .unwrap_or_else(|| data_dir.join(KEY_FILE_NAME));
+    if config.history_encryption() || key_file.exists() {
+        return Cipher::from_key_file(&key_file).map(Some);
+    }
+    Ok(None)
+}
+
+/// Applies the `history_*` settings of `config` to `history`, at startup and
+/// again when `POST /app/reload` re-reads the config. Encryption is left out:
+/// switching it converts files and needs the key, so it waits for a restart.
+pub fn apply_history_settings(history: &mut HistoryStore, config: &ConfigStore) {
+    history.set_max_entries(config.history_max_entries());
+    history.set_max_images_per_entry(config.history_max_images());
+    history.set_rotation_warn_margin(config.history_rotation_warn_margin());
+    history.set_trash_retention_days(config.history_trash_retention_days());
+    history.set_dedupe_mode(config.dedupe_history());
+    history.set_delete_image_files(config.history_delete_image_files());
+    history.set_archive_page_size(config.history_archive_page_size());
+    history.set_archive_after_days(config.history_archive_after_days());
+    history.set_archive_grouping(config.history_archive_grouping());
+    history.set_archive_compact_threshold(config.history_archive_compact_threshold());
+    history.set_archive_max_bytes(config.history_archive_max_mb().saturating_mul(1024 * 1024));
+    history.set_retention(
+        config.history_retention_months(),
+        config.history_retention_export_dir().map(PathBuf::from),
+    );
+}
+
+/// Loads a profile's config and history and starts its server. The server binds
+/// the configured port or the next free one, so profiles never share a port.
+pub fn launch_profile(data_dir: PathBuf, config_path: PathBuf) -> Result<ProfileInstance> {
+    let lock = InstanceLock::acquire(&data_dir)
+        .context("履歴機能エラー: データフォルダのロックに失敗しました")?;
+    let read_only = lock.is_none();
+    if read_only {
+        eprintln!(
+            "別のインスタンスが {} を使用中のため、読み取り専用で起動します",
+            data_dir.display()
+        );
+    }
+    let mut config = ConfigStore::open(config_path.clone(), read_only)
+        .with_context(|| format!("設定ファイルエラー: {}", config_path.display()))?;
+    let startup = config.startup_actions();
+    if startup.new_session {
+        if let Err(err) = config.clear_selections() {
+            eprintln!("起動時の選択リセットに失敗しました: {err}");
+        }
+    }
+    if !startup.preset.is_empty() {
+        if let Err(err) = config.apply_preset(&startup.preset) {
+            eprintln!(
+                "起動時のプリセット「{}」の適用に失敗しました: {err}",
+                startup.preset
+            );
+        }
+    }
+    let preferred_port = config.history_server_port();
+    let strict_port = config.strict_port();
+    let lan = match config.history_server_lan_port() {
+        0 => None,
+        port => Some(LanConfig {
+            port,
+            cert_dir: config_path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        }),
+    };
+
+    let cipher = history_cipher(&data_dir, &config)
+        .context("履歴機能エラー: 暗号鍵の読み込みに失敗しました")?;
+    let mut history_store =
+        HistoryStore::open(data_dir, config.history_max_entries(), cipher, read_only)
+            .context("履歴機能エラー: history store初期化に失敗しました")?;
+    history_store
+        .set_encryption(config.history_encryption())
+        .context("履歴機能エラー: 履歴の暗号化設定の適用に失敗しました")?;
+    apply_history_settings(&mut history_store, &config);
+    if history_store.is_fresh() && config.onboarding_samples() && !read_only {
+        if let Err(err) = history_store.seed_samples() {
+            eprintln!("サンプル履歴の追加に失敗しました: {err}");
+        }
+        if let Err(err) = config.seed_sample_preset() {
+            eprintln!("サンプルプリセットの追加に失敗しました: {err}");
+        }
+    }
+
+    // The server renders the history pages in the background once it runs.
+    let state = Arc::new(AppState::new(config, history_store));
+    let server = AppServer::start(state.clone(), preferred_port, strict_port, lan)
+        .context("履歴機能エラー: history server起動に失敗しました")?;
+
+    Ok(ProfileInstance {
+        state,
+        server,
+        lock,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        launch_profile, prepare_profile, profile_dir, validate_profile_name, ProfileInstance,
+    };
+    use crate::instance_lock::READ_ONLY_MESSAGE;
+    use std::fs;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The server writes the pages in the background after launch.
+    fn wait_for_pages(instance: &ProfileInstance) {
+        for _ in 0..500 {
+            if instance.state.pages_ready.load(Ordering::Relaxed) {
+                assert_eq!(*instance.state.startup_error.lock().expect("error"), None);
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("history pages were not rendered");
+    }
+
+    #[test]
+    fn profiles_get_their_own_folder_and_port() {
+        assert_eq!(validate_profile_name("  仕事用 ").expect("valid"), "仕事用");
+        for bad in ["", "..", "a/b", "a\\b", "con:", "name."] {
+            assert!(
+                validate_profile_name(bad).is_err(),
+                "{bad:?} should be rejected"
+            );
+        }
+
+        let base = std::env::temp_dir().join(format!("ipg_profiles_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        let template = base.join("config.txt");
+        fs::write(&template, "[app]\nhistory_server_port = 3900\n").expect("config write");
+
+        let config_path = prepare_profile(&base, "work", &template).expect("prepare");
+        assert_eq!(config_path, profile_dir(&base, "work").join("config.txt"));
+        fs::write(
+            &config_path,
+            "[app]\nhistory_server_port = 3900\n# edited\n",
+        )
+        .expect("edit");
+        prepare_profile(&base, "work", &template).expect("prepare again");
+        assert!(fs::read_to_string(&config_path)
+            .expect("read config")
+            .contains("# edited"));
+        // A profile switched to JSON keeps its config instead of a new copy.
+        let json_dir = profile_dir(&base, "json");
+        fs::create_dir_all(&json_dir).expect("json profile dir");
+        fs::write(json_dir.join("config.json"), "{}").expect("json config");
+        assert_eq!(
+            prepare_profile(&base, "json", &template).expect("prepare json"),
+            json_dir.join("config.json")
+        );
+        assert!(!json_dir.join("config.txt").exists());
+
+        let main = launch_profile(base.clone(), template).expect("launch main");
+        let work = launch_profile(profile_dir(&base, "work"), config_path).expect("launch work");
+        assert_ne!(main.server.port(), work.server.port());
+        wait_for_pages(&main);
+        wait_for_pages(&work);
+        assert!(profile_dir(&base, "work").join("History.html").is_file());
+        assert!(base.join("History.html").is_file());
+
+        drop(main);
+        drop(work);
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn startup_actions_reset_selections_and_check_integrations() {
+        let base =
+            std::env::temp_dir().join(format!("ipg_profiles_startup_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        let config_path = base.join("config.txt");
+        fs::write(
+            &config_path,
+            "[app]\nhistory_server_port = 3980\ntranslate_command = [\"ipg-missing-translator\"]\n\n[app.startup]\ncheck_integrations = true\nnew_session = true\n\n[state.prompt]\nsubject_selected = \"AIロボット\"\n",
+        )
+        .expect("config write");
+
+        let instance = launch_profile(base.clone(), config_path.clone()).expect("launch");
+        wait_for_pages(&instance);
+        let warnings = instance.state.startup_warnings.lock().expect("warnings");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("翻訳コマンド: "));
+        drop(warnings);
+        let saved = fs::read_to_string(&config_path).expect("read config");
+        assert!(!saved.contains("subject_selected"));
+        assert!(saved.contains(
+            "[app.startup]\ncheck_integrations = true\nnew_session = true\nopen_history = false\n"
+        ));
+
+        drop(instance);
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn busy_port_is_reported_or_fails_with_strict_port() {
+        let base = std::env::temp_dir().join(format!("ipg_profiles_port_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        let busy = std::net::TcpListener::bind(("127.0.0.1", 0)).expect("occupy a port");
+        let busy_port = busy.local_addr().expect("busy addr").port();
+        let config_path = base.join("config.txt");
+
+        fs::write(
+            &config_path,
+            format!("[app]\nhistory_server_port = {busy_port}\nstrict_port = true\n"),
+        )
+        .expect("config write");
+        let err = launch_profile(base.clone(), config_path.clone())
+            .err()
+            .expect("strict port in use");
+        assert!(format!("{err:#}").contains(&format!("port {busy_port} is in use")));
+
+        fs::write(
+            &config_path,
+            format!("[app]\nhistory_server_port = {busy_port}\n"),
+        )
+        .expect("config write");
+        let instance = launch_profile(base.clone(), config_path).expect("launch");
+        let port = instance.server.port();
+        assert_ne!(port, busy_port);
+        wait_for_pages(&instance);
+        let warnings = instance.state.startup_warnings.lock().expect("warnings");
+        assert!(warnings[0].starts_with(&format!(
+            "ポート {busy_port} は使用中のため、ポート {port} で起動しました"
+        )));
+        drop(warnings);
+        let page = fs::read_to_string(base.join("History.html")).expect("read page");
+        assert!(page.contains(&format!("http://127.0.0.1:{port}")));
+
+        drop(instance);
+        drop(busy);
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn second_instance_on_the_same_data_dir_runs_read_only() {
+        let base = std::env::temp_dir().join(format!("ipg_profiles_lock_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        let config_path = base.join("config.txt");
+        fs::write(&config_path, "[app]\nhistory_server_port = 3950\n").expect("config write");
+
+        let first = launch_profile(base.clone(), config_path.clone()).expect("launch first");
+        assert!(first.lock.is_some());
+        let config_before = fs::read_to_string(&config_path).expect("read config");
+        let second = launch_profile(base.clone(), config_path.clone()).expect("launch second");
+        assert!(second.lock.is_none());
+
+        {
+            let mut history = second.state.history.lock().expect("history");
+            assert!(history.is_read_only());
+            let err = history
+                .append_history("from second")
+                .expect_err("read-only");
+            assert_eq!(err.to_string(), READ_ONLY_MESSAGE);
+            let mut config = second.state.config.lock().expect("config");
+            assert!(config.is_read_only());
+            config
+                .set_item_draft("prompt", "subject", "draft")
+                .expect("selections stay in memory");
+            assert!(config.set_output_format("plain").is_err());
+        }
+        assert_eq!(
+            fs::read_to_string(&config_path).expect("read config"),
+            config_before
+        );
+        first
+            .state
+            .history
+            .lock()
+            .expect("history")
+            .append_history("from first")
+            .expect("owner writes");
+
+        drop(second);
+        drop(first);
+        let again = launch_profile(base.clone(), config_path).expect("launch again");
+        assert!(again.lock.is_some());
+        drop(again);
+        fs::remove_dir_all(base).ok();
+    }
+}
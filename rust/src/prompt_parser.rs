@@ -0,0 +1,323 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config_store::ItemConfig;
+use crate::text_normalize::fold;
+use crate::NO_SELECTION;
+
+/// Tokens shorter than this (after folding) only match exactly, so short
+/// words are not pulled towards an unrelated choice.
+const FUZZY_MIN_CHARS: usize = 3;
+
+/// How a token was mapped back to an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    /// Equal to a choice once case, width and kana type are ignored.
+    Exact,
+    /// Within a few typos of a choice.
+    Fuzzy,
+    /// Found under an item's template but not among its choices.
+    FreeText,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParsedMatch {
+    pub item_id: String,
+    pub label: String,
+    /// The text as it appeared in the prompt.
+    pub token: String,
+    /// The choice to select, or the free text to enter for `FreeText`.
+    pub value: String,
+    pub kind: MatchKind,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ParsedPrompt {
+    pub matches: Vec<ParsedMatch>,
+    /// Parts of the prompt that did not map to any item.
+    pub leftovers: Vec<String>,
+}
+
+/// Maps a prompt back to item choices. Lines rendered from an item template
+/// (`[被写体]：{value}`) go to that item; other lines are split on commas and
+/// each token is matched against every item's choices. Each item is filled
+/// at most once, first match wins.
+pub fn parse_prompt(prompt: &str, items: &[ItemConfig]) -> ParsedPrompt {
+    let mut parsed = ParsedPrompt::default();
+    for line in prompt
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if let Some((item, value)) = items
+            .iter()
+            .find_map(|item| strip_template(item, line).map(|value| (item, value)))
+        {
+            if parsed.has_item(item) || value.is_empty() {
+                parsed.leftovers.push(line.to_string());
+                continue;
+            }
+            match best_choice(item, &value) {
+                Some((choice, kind)) => parsed.push(item, &value, choice, kind),
+                None if item.allow_free_text => {
+                    parsed.push(item, &value, value.clone(), MatchKind::FreeText)
+                }
+                None => parsed.leftovers.push(line.to_string()),
+            }
+            continue;
+        }
+
+        for token in line
+            .split([',', '、', '，'])
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+        {
+            let best = items
+                .iter()
+                .filter(|item| !parsed.has_item(item))
+                .filter_map(|item| best_choice(item, token).map(|found| (item, found)))
+                .min_by_key(|(_, (_, kind))| *kind == MatchKind::Fuzzy);
+            match best {
+                Some((item, (choice, kind))) => parsed.push(item, token, choice, kind),
+                None => parsed.leftovers.push(token.to_string()),
+            }
+        }
+    }
+    parsed
+}
+
+/// The value each of `items` had when a history entry was copied: its stored
+/// selections (keyed by item key) when it has them, otherwise what
+/// `parse_prompt` recovers from the prompt text. Items the entry does not
+/// mention come back as `NO_SELECTION`, so restoring clears them.
+pub fn entry_values(
+    prompt: &str,
+    selections: &BTreeMap<String, String>,
+    items: &[ItemConfig],
+) -> Vec<String> {
+    if !selections.is_empty() {
+        return items
+            .iter()
+            .map(|item| {
+                selections
+                    .get(&item.key)
+                    .map(|value| value.trim())
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or(NO_SELECTION)
+                    .to_string()
+            })
+            .collect();
+    }
+
+    let parsed = parse_prompt(prompt, items);
+    items
+        .iter()
+        .map(|item| {
+            let item_id = item.item_id();
+            parsed
+                .matches
+                .iter()
+                .find(|m| m.item_id == item_id)
+                .map_or_else(|| NO_SELECTION.to_string(), |m| m.value.clone())
+        })
+        .collect()
+}
+
+impl ParsedPrompt {
+    fn has_item(&self, item: &ItemConfig) -> bool {
+        let item_id = item.item_id();
+        self.matches.iter().any(|m| m.item_id == item_id)
+    }
+
+    fn push(&mut self, item: &ItemConfig, token: &str, value: String, kind: MatchKind) {
+        self.matches.push(ParsedMatch {
+            item_id: item.item_id(),
+            label: item.label.clone(),
+            token: token.to_string(),
+            value,
+            kind,
+        });
+    }
+}
+
+/// The value part of `line` when it has the literal text around `{value}`
+/// in the item's template (other placeholders end the literal part).
+fn strip_template(item: &ItemConfig, line: &str) -> Option<String> {
+    let template = if item.template.is_empty() {
+        format!("[{}]：{{value}}", item.label)
+    } else {
+        item.template.clone()
+    };
+    let (before, after) = template.split_once("{value}")?;
+    let prefix = before.rsplit('}').next().unwrap_or_default();
+    let prefix = prefix.split('{').next().unwrap_or_default().trim_start();
+    let suffix = after.split('{').next().unwrap_or_default().trim_end();
+    if prefix.trim().is_empty() {
+        return None;
+    }
+
+    let folded: Vec<char> = fold(line).chars().collect();
+    let prefix: Vec<char> = fold(prefix).chars().collect();
+    let suffix: Vec<char> = fold(suffix).chars().collect();
+    // Positions below carry over to `line` only while folding is one char per char.
+    if folded.len() != line.chars().count()
+        || folded.len() < prefix.len() + suffix.len()
+        || !folded.starts_with(&prefix)
+        || !folded.ends_with(&suffix)
+    {
+        return None;
+    }
+    let value: String = line
+        .chars()
+        .skip(prefix.len())
+        .take(folded.len() - prefix.len() - suffix.len())
+        .collect();
+    Some(value.trim().to_string())
+}
+
+/// The closest choice of `item` to `token`, if it is an exact or fuzzy match.
+fn best_choice(item: &ItemConfig, token: &str) -> Option<(String, MatchKind)> {
+    let key = compact(token);
+    if key.is_empty() {
+        return None;
+    }
+    let mut best: Option<(usize, &String)> = None;
+    for choice in item.choices.iter().filter(|c| c.as_str() != NO_SELECTION) {
+        let distance = edit_distance(&key, &compact(choice));
+        if distance == 0 {
+            return Some((choice.clone(), MatchKind::Exact));
+        }
+        if best.is_none_or(|(best_distance, _)| distance < best_distance) {
+            best = Some((distance, choice));
+        }
+    }
+    let (distance, choice) = best?;
+    let length = key.chars().count();
+    let allowed = (length / 4).max(1);
+    (length >= FUZZY_MIN_CHARS && distance <= allowed).then(|| (choice.clone(), MatchKind::Fuzzy))
+}
+
+/// Folded text without whitespace, so "Blue  Sky" and "ｂｌｕｅｓｋｙ" compare equal.
+fn compact(text: &str) -> String {
+    fold(text)
+        .chars()
+        .filter(|ch| !ch.is_whitespace())
+        .collect()
+}
+
+/// Levenshtein distance over chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_values, parse_prompt, MatchKind};
+    use crate::config_store::ItemConfig;
+
+    fn item(key: &str, label: &str, choices: &[&str], template: &str) -> ItemConfig {
+        ItemConfig {
+            section_name: "prompt".to_string(),
+            key: key.to_string(),
+            label: label.to_string(),
+            choices: choices.iter().map(|c| c.to_string()).collect(),
+            allow_free_text: true,
+            template: template.to_string(),
+            formats: Vec::new(),
+            random_pool: Vec::new(),
+            param: String::new(),
+            group: String::new(),
+        }
+    }
+
+    #[test]
+    fn maps_templates_and_tokens_with_fuzzy_matching() {
+        let items = vec![
+            item(
+                "subject",
+                "被写体",
+                &["指定なし", "ロボット", "Cyberpunk City"],
+                "",
+            ),
+            item(
+                "tone",
+                "トーン",
+                &["指定なし", "sunset glow", "モノクロ"],
+                "tone: {value}",
+            ),
+            item(
+                "style",
+                "スタイル",
+                &["指定なし", "watercolor", "oil painting"],
+                "{value}",
+            ),
+        ];
+        let parsed = parse_prompt(
+            "[被写体]：ろぼっと\nTONE: Sunset  Glow\nwatercolour, masterpiece, ｏｉｌ ｐａｉｎｔｉｎｇ",
+            &items,
+        );
+
+        let summary: Vec<(&str, &str, MatchKind)> = parsed
+            .matches
+            .iter()
+            .map(|m| (m.item_id.as_str(), m.value.as_str(), m.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("prompt:subject", "ロボット", MatchKind::Exact),
+                ("prompt:tone", "sunset glow", MatchKind::Exact),
+                ("prompt:style", "watercolor", MatchKind::Fuzzy),
+            ]
+        );
+        assert_eq!(parsed.matches[2].token, "watercolour");
+        // The style item is already filled, so the second style token is left over.
+        assert_eq!(
+            parsed.leftovers,
+            vec!["masterpiece", "ｏｉｌ ｐａｉｎｔｉｎｇ"]
+        );
+
+        let free = parse_prompt("[被写体]：青い猫\n猫", &items);
+        assert_eq!(free.matches[0].kind, MatchKind::FreeText);
+        assert_eq!(free.matches[0].value, "青い猫");
+        assert_eq!(free.leftovers, vec!["猫"]);
+    }
+
+    #[test]
+    fn restores_entry_values_from_selections_or_prompt() {
+        let items = vec![
+            item("subject", "被写体", &["指定なし", "ロボット", "猫"], ""),
+            item("tone", "トーン", &["指定なし", "モノクロ"], ""),
+        ];
+        let selections = [("subject".to_string(), "猫".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            entry_values("[被写体]：ロボット", &selections, &items),
+            vec!["猫", "指定なし"]
+        );
+        assert_eq!(
+            entry_values(
+                "[被写体]：青い猫\n[トーン]：モノクロ",
+                &Default::default(),
+                &items
+            ),
+            vec!["青い猫", "モノクロ"]
+        );
+        assert_eq!(
+            entry_values("[トーン]：モノクロ", &Default::default(), &items),
+            vec!["指定なし", "モノクロ"]
+        );
+    }
+}
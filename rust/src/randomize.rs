@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config_store::ItemConfig;
+use crate::history_store::HistoryEntry;
+use crate::NO_SELECTION;
+
+/// xorshift64: enough for picking values, not for anything secret.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dice(u64);
+
+impl Dice {
+    pub fn from_clock() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9e37_79b9_7f4a_7c15);
+        Self::seeded(seed)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// One of `candidates`, or `None` when there are none.
+    pub fn pick<'a, T>(&mut self, candidates: &'a [T]) -> Option<&'a T> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = self.next_u64() % candidates.len() as u64;
+        candidates.get(index as usize)
+    }
+
+    /// One of `candidates`, each as likely as its `weight`; zero-weight ones never come up.
+    pub fn pick_weighted<'a, T>(
+        &mut self,
+        candidates: &'a [T],
+        weight: impl Fn(&T) -> u64,
+    ) -> Option<&'a T> {
+        let total: u64 = candidates.iter().map(&weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = self.next_u64() % total;
+        candidates.iter().find(|candidate| {
+            let weight = weight(candidate);
+            if roll < weight {
+                return true;
+            }
+            roll -= weight;
+            false
+        })
+    }
+}
+
+/// How well each `(item key, value)` did in the history: every entry rated ★3
+/// or more adds `rating - 2` to the values it was copied with.
+#[derive(Debug, Clone, Default)]
+pub struct RatingWeights(BTreeMap<(String, String), u64>);
+
+impl RatingWeights {
+    pub fn from_entries(entries: &[HistoryEntry]) -> Self {
+        let mut scores = BTreeMap::new();
+        for entry in entries.iter().filter(|entry| entry.rating >= 3) {
+            for (key, value) in &entry.selections {
+                *scores.entry((key.clone(), value.clone())).or_insert(0) +=
+                    u64::from(entry.rating - 2);
+            }
+        }
+        Self(scores)
+    }
+
+    /// `1 + score`, so values nobody rated still come up now and then.
+    pub fn weight(&self, key: &str, value: &str) -> u64 {
+        1 + self
+            .0
+            .get(&(key.to_string(), value.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Values the dice picks from for `item`: its `random_pool` when that names any
+/// current choice, otherwise every choice except `指定なし`.
+pub fn random_candidates(item: &ItemConfig) -> Vec<&str> {
+    let choices = item
+        .choices
+        .iter()
+        .map(String::as_str)
+        .filter(|choice| *choice != NO_SELECTION);
+    let pooled: Vec<&str> = choices
+        .clone()
+        .filter(|choice| item.random_pool.iter().any(|value| value == choice))
+        .collect();
+    if pooled.is_empty() {
+        choices.collect()
+    } else {
+        pooled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random_candidates, Dice, RatingWeights};
+    use crate::config_store::ItemConfig;
+    use crate::history_store::HistoryEntry;
+    use serde_json::json;
+
+    #[test]
+    fn picks_only_from_the_pool_when_one_is_set() {
+        let mut item = ItemConfig {
+            section_name: "prompt".to_string(),
+            key: "subject".to_string(),
+            label: "被写体".to_string(),
+            choices: ["指定なし", "猫", "犬", "試作"]
+                .map(ToOwned::to_owned)
+                .to_vec(),
+            allow_free_text: true,
+            template: String::new(),
+            formats: Vec::new(),
+            random_pool: Vec::new(),
+            param: String::new(),
+            group: String::new(),
+        };
+        assert_eq!(random_candidates(&item), ["猫", "犬", "試作"]);
+
+        item.random_pool = vec!["猫".to_string(), "犬".to_string(), "削除済み".to_string()];
+        assert_eq!(random_candidates(&item), ["猫", "犬"]);
+        let mut dice = Dice::seeded(42);
+        for _ in 0..50 {
+            let picked = dice.pick(&random_candidates(&item)).copied();
+            assert!(matches!(picked, Some("猫" | "犬")));
+        }
+
+        item.random_pool = vec!["削除済み".to_string()];
+        assert_eq!(random_candidates(&item).len(), 3);
+        assert_eq!(dice.pick::<&str>(&[]), None);
+    }
+    #[test]
+    fn weights_favour_values_from_well_rated_entries() {
+        let entry = |rating: u8, subject: &str| -> HistoryEntry {
+            serde_json::from_value(json!({
+                "id": "20250101_000000_000",
+                "ts": "2025-01-01 00:00:00",
+                "prompt": subject,
+                "images": [],
+                "rating": rating,
+                "selections": { "subject": subject },
+            }))
+            .expect("entry")
+        };
+        let weights = RatingWeights::from_entries(&[
+            entry(5, "猫"),
+            entry(4, "猫"),
+            entry(2, "犬"),
+            entry(0, "犬"),
+        ]);
+        assert_eq!(weights.weight("subject", "猫"), 6);
+        assert_eq!(weights.weight("subject", "犬"), 1);
+        assert_eq!(weights.weight("style", "猫"), 1);
+
+        let candidates = ["猫", "犬"];
+        let mut dice = Dice::seeded(7);
+        let cats = (0..700)
+            .filter(|_| {
+                dice.pick_weighted(&candidates, |value| weights.weight("subject", value))
+                    == Some(&"猫")
+            })
+            .count();
+        assert!((500..=700).contains(&cats), "{cats}");
+        assert_eq!(dice.pick_weighted(&candidates, |_| 0), None);
+    }
+}
@@ -0,0 +1,122 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+use crate::lan_tls::TlsListener;
+
+/// Requests a client may make back to back before the limit applies.
+pub const RATE_LIMIT_BURST: f64 = 20.0;
+/// Requests per second a client regains once it has used its burst; far more
+/// than anyone clicks, far less than a retry loop sends.
+pub const RATE_LIMIT_PER_SEC: f64 = 5.0;
+/// Buckets idle this long are full again and are dropped.
+const IDLE_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Address a request came from, over the loopback or the LAN listener; the
+/// server's `ConnectInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self(*stream.remote_addr())
+    }
+}
+
+/// Token bucket per client address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    burst: f64,
+    per_sec: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RATE_LIMIT_BURST, RATE_LIMIT_PER_SEC)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(burst: f64, per_sec: f64) -> Self {
+        Self {
+            burst,
+            per_sec,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one request from `ip`'s bucket, or returns how long until the
+    /// next one is allowed.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_EXPIRY);
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.per_sec,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bursts_are_allowed_then_refilled_per_client() {
+        let limiter = RateLimiter::new(3.0, 2.0);
+        let start = Instant::now();
+        let page = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let tablet = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+
+        for _ in 0..3 {
+            assert!(limiter.check(page, start).is_ok());
+        }
+        let wait = limiter.check(page, start).expect_err("burst used up");
+        assert_eq!(wait, Duration::from_millis(500));
+        assert!(
+            limiter.check(tablet, start).is_ok(),
+            "other clients unaffected"
+        );
+
+        assert!(limiter
+            .check(page, start + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .check(page, start + Duration::from_millis(500))
+            .is_err());
+        assert!(limiter
+            .check(page, start + Duration::from_secs(120))
+            .is_ok());
+    }
+}
@@ -1,50 +1,632 @@
+use chrono::Local;
 use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 
+use crate::randomize::Dice;
 use crate::NO_SELECTION;
 
+/// Prefix of environment variables that override `[variables]`, e.g. `IMAGE_PROMPT_PROJECT`.
+pub const VARIABLE_ENV_PREFIX: &str = "IMAGE_PROMPT_";
+
 #[derive(Debug, Clone, Serialize)]
 pub struct RenderEntry {
+    pub key: String,
     pub label: String,
     pub selected: String,
     pub free_text: String,
+    /// Empty means the legacy `[label]：{value}` line.
+    pub template: String,
+    /// Midjourney parameter the value is passed as, like `ar`; empty for
+    /// prompt text.
+    pub param: String,
+    /// Emphasis weight; 1.0 is none.
+    pub weight: f64,
 }
 
-pub fn render_prompt(entries: &[RenderEntry]) -> String {
-    let mut parts = Vec::new();
-    for entry in entries {
-        let free_text = entry.free_text.trim();
-        let selected = entry.selected.trim();
+impl RenderEntry {
+    /// Free text wins over the selection; `None` when neither says anything.
+    fn value(&self) -> Option<&str> {
+        let free_text = self.free_text.trim();
         let value = if free_text.is_empty() {
-            selected
+            self.selected.trim()
         } else {
             free_text
         };
-        if value.is_empty() || value == NO_SELECTION {
+        (!value.is_empty() && value != NO_SELECTION).then_some(value)
+    }
+
+    /// The value with its weight in the emphasis syntax of `profile`.
+    fn emphasized(&self, profile: FormatProfile) -> Option<String> {
+        let value = self.value()?;
+        if self.weight == 1.0 || !profile.emphasizes() {
+            return Some(value.to_string());
+        }
+        if profile == FormatProfile::Sdxl {
+            let weight = (self.weight * 100.0).round() / 100.0;
+            return Some(format!("({value}:{weight})"));
+        }
+        // Each pair of parentheses multiplies by 1.1, each of brackets divides.
+        let steps = (self.weight.ln() / EMPHASIS_STEP.ln()).round() as i32;
+        let (open, close) = if steps < 0 { ("[", "]") } else { ("(", ")") };
+        let depth = steps.unsigned_abs() as usize;
+        Some(format!(
+            "{}{value}{}",
+            open.repeat(depth),
+            close.repeat(depth)
+        ))
+    }
+}
+
+/// Weight of one level of `(value)` emphasis in Stable Diffusion prompts.
+const EMPHASIS_STEP: f64 = 1.1;
+
+/// Layout of the rendered prompt, picked by `[app] output_format`. Other
+/// format names only choose items through `formats` and render as `Labeled`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatProfile {
+    /// One `[label]：value` line per item, or the item's template.
+    Labeled,
+    /// The bare values joined by `[app] delimiter`.
+    Comma,
+    /// Like `Comma`, without the Midjourney parameter items.
+    Sdxl,
+    /// The values joined by `[app] delimiter`, then `--param value` for
+    /// every item with a `param`.
+    Midjourney,
+}
+
+impl FormatProfile {
+    pub const ALL: [FormatProfile; 4] = [
+        FormatProfile::Labeled,
+        FormatProfile::Comma,
+        FormatProfile::Sdxl,
+        FormatProfile::Midjourney,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FormatProfile::Labeled => "labeled",
+            FormatProfile::Comma => "comma",
+            FormatProfile::Sdxl => "sdxl",
+            FormatProfile::Midjourney => "midjourney",
+        }
+    }
+
+    /// Whether item weights are written into the prompt: `(value:1.3)` for
+    /// `Sdxl`, `((value))` for `Comma`.
+    pub fn emphasizes(self) -> bool {
+        matches!(self, FormatProfile::Comma | FormatProfile::Sdxl)
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name.trim())
+            .unwrap_or(FormatProfile::Labeled)
+    }
+}
+
+/// Values for `{name}` placeholders other than `{value}`.
+#[derive(Debug, Clone, Default)]
+pub struct RenderContext {
+    variables: BTreeMap<String, String>,
+    dice: Cell<Dice>,
+}
+
+impl RenderContext {
+    /// `variables` come from `[variables]`; the environment and `{date}` take precedence.
+    pub fn new(variables: BTreeMap<String, String>) -> Self {
+        let mut variables = variables;
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix(VARIABLE_ENV_PREFIX) {
+                variables.insert(name.to_ascii_lowercase(), value);
+            }
+        }
+        variables
+            .entry("profile".to_string())
+            .or_insert_with(|| "default".to_string());
+        variables.insert(
+            "date".to_string(),
+            Local::now().format("%Y-%m-%d").to_string(),
+        );
+
+        Self {
+            variables,
+            dice: Cell::new(Dice::from_clock()),
+        }
+    }
+
+    #[cfg(test)]
+    fn fixed(variables: &[(&str, &str)], seed: u64) -> Self {
+        Self {
+            variables: variables
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            dice: Cell::new(Dice::seeded(seed)),
+        }
+    }
+
+    fn random_in(&self, low: u64, high: u64) -> u64 {
+        let mut dice = self.dice.get();
+        let x = dice.next_u64();
+        self.dice.set(dice);
+        match (high - low).checked_add(1) {
+            Some(span) => low + x % span,
+            None => x,
+        }
+    }
+
+    fn resolve(&self, name: &str, value: &str) -> Option<String> {
+        if name == "value" {
+            return Some(value.to_string());
+        }
+        if let Some(range) = name.strip_prefix("random:") {
+            let (low, high) = range.split_once('-')?;
+            let low = low.trim().parse::<u64>().ok()?;
+            let high = high.trim().parse::<u64>().ok()?;
+            if low > high {
+                return None;
+            }
+            return Some(self.random_in(low, high).to_string());
+        }
+        self.variables.get(name).cloned()
+    }
+}
+
+/// Expands `{value}`, `{date}`, `{random:A-B}` and context variables in one pass.
+/// `{{` and `}}` produce literal braces; unknown placeholders are left as written.
+/// `{name|prefix}` adds `prefix` before the value, and drops both when the
+/// value is empty or unknown.
+/// Substituted text is never re-scanned, so braces inside values stay literal.
+pub fn expand_template(template: &str, value: &str, ctx: &RenderContext) -> String {
+    expand_placeholders(template, |name| ctx.resolve(name, value))
+}
+
+/// `expand_template` with the placeholder lookup left to `resolve`.
+fn expand_placeholders(template: &str, mut resolve: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::new();
+    let mut rest = template;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        output.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            output.push_str(&tail[..1]);
+            rest = &tail[2..];
             continue;
         }
-        parts.push(format!("[{}]：{}", entry.label, value));
+        if let Some(after) = tail.strip_prefix('}') {
+            output.push('}');
+            rest = after;
+            continue;
+        }
+
+        let Some(end) = tail.find('}') else {
+            output.push_str(tail);
+            return output;
+        };
+        let name = &tail[1..end];
+        if let Some((name, prefix)) = name.split_once('|') {
+            // `{name|prefix}`: the prefix and the value, or nothing at all.
+            match resolve(name.trim()) {
+                Some(resolved) if !resolved.is_empty() => {
+                    output.push_str(prefix);
+                    output.push_str(&resolved);
+                }
+                _ => {}
+            }
+        } else {
+            match resolve(name.trim()) {
+                Some(resolved) => output.push_str(&resolved),
+                None => output.push_str(&tail[..=end]),
+            }
+        }
+        rest = &tail[end + 1..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// One `[[sections]]` block rendered with `render_prompt`, before its section
+/// template places it in the combined prompt.
+#[derive(Debug, Clone)]
+pub struct SectionOutput {
+    /// `{value}` stands for `body`.
+    pub template: String,
+    pub body: String,
+}
+
+/// Joins the sections line by line, each wrapped in its template; sections
+/// with nothing selected are left out along with their template.
+pub fn combine_sections(sections: &[SectionOutput], ctx: &RenderContext) -> String {
+    sections
+        .iter()
+        .filter(|section| !section.body.is_empty())
+        .map(|section| expand_template(&section.template, &section.body, ctx))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn render_prompt(entries: &[RenderEntry], ctx: &RenderContext) -> String {
+    let mut parts = Vec::new();
+    for entry in entries {
+        let Some(value) = entry.value() else {
+            continue;
+        };
+        if entry.template.is_empty() {
+            parts.push(format!("[{}]：{}", entry.label, value));
+        } else {
+            parts.push(expand_placeholders(&entry.template, |name| match name {
+                "label" => Some(entry.label.clone()),
+                "key" => Some(entry.key.clone()),
+                _ => ctx.resolve(name, value),
+            }));
+        }
     }
     parts.join("\n")
 }
 
+/// Expands `[app] prompt_template`, where `{key}` stands for the value of the
+/// item with that key. A comma-separated part whose items are all unselected
+/// is left out with its comma, so `lighting: {lighting}` disappears instead of
+/// leaving `lighting: ` behind; parts without items always stay.
+/// Values carry their weight as `profile` writes it.
+pub fn render_prompt_template(
+    template: &str,
+    entries: &[RenderEntry],
+    profile: FormatProfile,
+    ctx: &RenderContext,
+) -> String {
+    let mut lines = Vec::new();
+    for line in template.lines() {
+        let mut parts = Vec::new();
+        let mut dropped_first = false;
+        for (index, part) in line.split(',').enumerate() {
+            let (mut uses_items, mut filled) = (false, false);
+            let expanded = expand_placeholders(part, |name| {
+                match entries.iter().find(|entry| entry.key == name) {
+                    Some(entry) => {
+                        uses_items = true;
+                        let value = entry.emphasized(profile);
+                        filled |= value.is_some();
+                        Some(value.unwrap_or_default())
+                    }
+                    None => ctx.resolve(name, ""),
+                }
+            });
+            if uses_items && !filled {
+                dropped_first |= index == 0;
+                continue;
+            }
+            parts.push(expanded);
+        }
+        if parts.is_empty() {
+            continue;
+        }
+        let joined = parts.join(",");
+        if dropped_first {
+            lines.push(joined.trim_start().to_string());
+        } else {
+            lines.push(joined);
+        }
+    }
+    lines.join("\n")
+}
+
+/// Puts `[app] prompt_prefix` and `prompt_suffix` around a finished prompt:
+/// on lines of their own for `Labeled`, joined by `delimiter` otherwise, with
+/// the suffix before Midjourney's `--` parameters. Both may use `{date}` and
+/// context variables. An empty prompt stays empty.
+pub fn wrap_prompt(
+    prompt: &str,
+    prefix: &str,
+    suffix: &str,
+    profile: FormatProfile,
+    delimiter: &str,
+    ctx: &RenderContext,
+) -> String {
+    if prompt.is_empty() || (prefix.is_empty() && suffix.is_empty()) {
+        return prompt.to_string();
+    }
+    let separator = match profile {
+        FormatProfile::Labeled => "\n",
+        _ => delimiter,
+    };
+    let (body, params) = match profile {
+        FormatProfile::Midjourney => match prompt.find("--") {
+            Some(0) => ("", prompt),
+            Some(pos) if prompt[..pos].ends_with(' ') => (prompt[..pos].trim_end(), &prompt[pos..]),
+            _ => (prompt, ""),
+        },
+        _ => (prompt, ""),
+    };
+    let parts: Vec<String> = [
+        expand_template(prefix, "", ctx),
+        body.to_string(),
+        expand_template(suffix, "", ctx),
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect();
+    let wrapped = parts.join(separator);
+    if params.is_empty() {
+        wrapped
+    } else {
+        format!("{wrapped} {params}")
+    }
+}
+
+/// Renders `entries` in the layout of `profile`; `delimiter` joins the values
+/// of every profile but `Labeled`.
+pub fn render_formatted(
+    entries: &[RenderEntry],
+    profile: FormatProfile,
+    delimiter: &str,
+    ctx: &RenderContext,
+) -> String {
+    let values = |with_params: bool| {
+        entries
+            .iter()
+            .filter(|entry| with_params || entry.param.is_empty())
+            .filter_map(|entry| entry.emphasized(profile))
+            .collect::<Vec<_>>()
+    };
+    match profile {
+        FormatProfile::Labeled => render_prompt(entries, ctx),
+        FormatProfile::Comma => values(true).join(delimiter),
+        FormatProfile::Sdxl => values(false).join(delimiter),
+        FormatProfile::Midjourney => {
+            let mut parts = vec![values(false).join(delimiter)];
+            for entry in entries.iter().filter(|entry| !entry.param.is_empty()) {
+                let Some(value) = entry.value() else {
+                    continue;
+                };
+                if value.starts_with("--") {
+                    parts.push(value.to_string());
+                } else {
+                    parts.push(format!("--{} {}", entry.param, value));
+                }
+            }
+            parts.retain(|part| !part.is_empty());
+            parts.join(" ")
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{render_prompt, RenderEntry};
+    use super::{
+        combine_sections, expand_template, render_formatted, render_prompt, render_prompt_template,
+        wrap_prompt, FormatProfile, RenderContext, RenderEntry, SectionOutput,
+    };
 
     #[test]
     fn render_uses_confirmed_free_text() {
-        let out = render_prompt(&[
-            RenderEntry {
-                label: "被写体".to_string(),
-                selected: "ロボット".to_string(),
-                free_text: "青いロボット".to_string(),
-            },
-            RenderEntry {
-                label: "向き".to_string(),
-                selected: "指定なし".to_string(),
-                free_text: "".to_string(),
-            },
-        ]);
+        let out = render_prompt(
+            &[
+                RenderEntry {
+                    key: "subject".to_string(),
+                    label: "被写体".to_string(),
+                    selected: "ロボット".to_string(),
+                    free_text: "青いロボット".to_string(),
+                    template: String::new(),
+                    param: String::new(),
+                    weight: 1.0,
+                },
+                RenderEntry {
+                    key: "pose".to_string(),
+                    label: "向き".to_string(),
+                    selected: "指定なし".to_string(),
+                    free_text: "".to_string(),
+                    template: String::new(),
+                    param: String::new(),
+                    weight: 1.0,
+                },
+            ],
+            &RenderContext::default(),
+        );
         assert_eq!(out, "[被写体]：青いロボット");
     }
+
+    #[test]
+    fn expands_context_variables_and_escapes_braces() {
+        let ctx = RenderContext::fixed(&[("project", "branding"), ("date", "2026-01-02")], 7);
+
+        assert_eq!(
+            expand_template("[{project}] {value} ({date})", "logo", &ctx),
+            "[branding] logo (2026-01-02)"
+        );
+        assert_eq!(
+            expand_template("{{value}} {value} }} {unknown}", "x", &ctx),
+            "{value} x } {unknown}"
+        );
+        assert_eq!(expand_template("{value}", "{project}", &ctx), "{project}");
+
+        for _ in 0..50 {
+            let n: u64 = expand_template("{random:1-3}", "", &ctx)
+                .parse()
+                .expect("random number");
+            assert!((1..=3).contains(&n));
+        }
+        assert_eq!(expand_template("{random:5-1}", "", &ctx), "{random:5-1}");
+        assert_eq!(expand_template("open {value", "x", &ctx), "open {value");
+    }
+
+    #[test]
+    fn sections_are_wrapped_and_empty_ones_dropped() {
+        let ctx = RenderContext::fixed(&[("project", "x")], 1);
+        let section = |template: &str, body: &str| SectionOutput {
+            template: template.to_string(),
+            body: body.to_string(),
+        };
+        let out = combine_sections(
+            &[
+                section("{value}", "[被写体]：猫\n[背景]：夕焼け"),
+                section("Camera: {value}", ""),
+                section("Negative prompt: {value}", "blurry {project}"),
+            ],
+            &ctx,
+        );
+        assert_eq!(
+            out,
+            "[被写体]：猫\n[背景]：夕焼け\nNegative prompt: blurry {project}"
+        );
+    }
+
+    #[test]
+    fn format_profiles_lay_out_the_same_selections() {
+        let ctx = RenderContext::fixed(&[], 1);
+        let entry = |label: &str, value: &str, param: &str| RenderEntry {
+            key: label.to_string(),
+            label: label.to_string(),
+            selected: value.to_string(),
+            free_text: String::new(),
+            template: String::new(),
+            param: param.to_string(),
+            weight: 1.0,
+        };
+        let entries = [
+            entry("被写体", "cat", ""),
+            entry("向き", "指定なし", ""),
+            entry("背景", "sunset", ""),
+            entry("比率", "16:9", "ar"),
+            entry("バージョン", "--v 6", "v"),
+        ];
+        let render =
+            |name: &str| render_formatted(&entries, FormatProfile::from_name(name), ", ", &ctx);
+
+        assert_eq!(
+            render("labeled"),
+            "[被写体]：cat\n[背景]：sunset\n[比率]：16:9\n[バージョン]：--v 6"
+        );
+        assert_eq!(render("comma"), "cat, sunset, 16:9, --v 6");
+        assert_eq!(render("sdxl"), "cat, sunset");
+        assert_eq!(render("midjourney"), "cat, sunset --ar 16:9 --v 6");
+        assert_eq!(
+            render("print"),
+            render("labeled"),
+            "unknown names render labeled"
+        );
+        assert_eq!(
+            render_formatted(&entries[3..], FormatProfile::Midjourney, ", ", &ctx),
+            "--ar 16:9 --v 6"
+        );
+    }
+
+    #[test]
+    fn prompt_template_drops_parts_of_unselected_items() {
+        let ctx = RenderContext::fixed(&[("project", "branding")], 1);
+        let entry = |key: &str, selected: &str, free_text: &str| RenderEntry {
+            key: key.to_string(),
+            label: key.to_string(),
+            selected: selected.to_string(),
+            free_text: free_text.to_string(),
+            template: String::new(),
+            param: String::new(),
+            weight: 1.0,
+        };
+        let entries = [
+            entry("subject", "cat", ""),
+            entry("style", "指定なし", ""),
+            entry("lighting", "指定なし", "rim light"),
+            entry("camera", "", ""),
+        ];
+        let render = |template: &str| {
+            render_prompt_template(template, &entries, FormatProfile::Labeled, &ctx)
+        };
+
+        assert_eq!(
+            render("masterpiece, {subject}, {style}, lighting: {lighting}"),
+            "masterpiece, cat, lighting: rim light"
+        );
+        assert_eq!(
+            render("{style}, {subject} by {camera}, {project}, {other}"),
+            "cat by , branding, {other}"
+        );
+        assert_eq!(
+            render("{camera}\n{subject}\n\nNegative: blurry"),
+            "cat\n\nNegative: blurry"
+        );
+    }
+
+    #[test]
+    fn item_templates_name_the_item_and_prefix_conditionally() {
+        let ctx = RenderContext::fixed(&[("project", "branding"), ("blank", "")], 1);
+        let out = render_prompt(
+            &[RenderEntry {
+                key: "subject".to_string(),
+                label: "被写体".to_string(),
+                selected: "cat".to_string(),
+                free_text: String::new(),
+                template: "{label} ({key}): {value|photo of }{project| for }{blank| x}{nope|?}"
+                    .to_string(),
+                param: String::new(),
+                weight: 1.0,
+            }],
+            &ctx,
+        );
+        assert_eq!(out, "被写体 (subject): photo of cat for branding");
+        assert_eq!(expand_template("{value|, }", "", &ctx), "");
+    }
+
+    #[test]
+    fn weights_follow_the_emphasis_syntax_of_the_profile() {
+        let ctx = RenderContext::fixed(&[], 1);
+        let entry = |value: &str, weight: f64| RenderEntry {
+            key: value.to_string(),
+            label: value.to_string(),
+            selected: value.to_string(),
+            free_text: String::new(),
+            template: String::new(),
+            param: String::new(),
+            weight,
+        };
+        let entries = [entry("cat", 1.3), entry("sunset", 1.0), entry("fog", 0.8)];
+        let render = |profile| render_formatted(&entries, profile, ", ", &ctx);
+
+        assert_eq!(render(FormatProfile::Sdxl), "(cat:1.3), sunset, (fog:0.8)");
+        assert_eq!(render(FormatProfile::Comma), "(((cat))), sunset, [[fog]]");
+        assert_eq!(render(FormatProfile::Midjourney), "cat, sunset, fog");
+        assert_eq!(
+            render(FormatProfile::Labeled),
+            "[cat]：cat\n[sunset]：sunset\n[fog]：fog"
+        );
+        assert_eq!(
+            render_prompt_template("best, {cat}", &entries, FormatProfile::Sdxl, &ctx),
+            "best, (cat:1.3)"
+        );
+    }
+
+    #[test]
+    fn prefix_and_suffix_wrap_the_prompt_in_each_profile() {
+        let ctx = RenderContext::fixed(&[("project", "branding")], 1);
+        let wrap = |prompt: &str, profile| {
+            wrap_prompt(prompt, "masterpiece", "by {project}", profile, ", ", &ctx)
+        };
+
+        assert_eq!(
+            wrap("[被写体]：猫", FormatProfile::Labeled),
+            "masterpiece\n[被写体]：猫\nby branding"
+        );
+        assert_eq!(
+            wrap("cat, sunset", FormatProfile::Sdxl),
+            "masterpiece, cat, sunset, by branding"
+        );
+        assert_eq!(
+            wrap("cat --ar 16:9 --v 6", FormatProfile::Midjourney),
+            "masterpiece, cat, by branding --ar 16:9 --v 6"
+        );
+        assert_eq!(
+            wrap("--ar 16:9", FormatProfile::Midjourney),
+            "masterpiece, by branding --ar 16:9"
+        );
+        assert_eq!(wrap("", FormatProfile::Comma), "");
+        assert_eq!(
+            wrap_prompt("cat", "", "", FormatProfile::Comma, ", ", &ctx),
+            "cat"
+        );
+    }
 }
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config_store::ItemConfig;
+use crate::history_store::HistoryEntry;
+
+/// Largest page `GET /api/v1/history` returns.
+pub const HISTORY_PAGE_LIMIT: usize = 500;
+/// Page size when the request names none.
+pub const DEFAULT_HISTORY_PAGE_SIZE: usize = 50;
+
+/// A history entry as the `/api/v1` endpoints return it.
+///
+/// Unlike `HistoryEntry`, every field is always present, so scripts can rely
+/// on the shape; v1 only ever adds fields.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryItem {
+    pub id: String,
+    pub ts: String,
+    pub prompt: String,
+    /// English translation shown at copy time; empty when there was none.
+    pub translation: String,
+    /// Item key → chosen value at copy time.
+    pub selections: BTreeMap<String, String>,
+    pub images: Vec<String>,
+    pub tags: Vec<String>,
+    pub note: String,
+    /// 1–5 stars; 0 means unrated.
+    pub rating: u8,
+    pub score: Option<u8>,
+    pub count: u32,
+    pub locked: bool,
+}
+
+impl From<HistoryEntry> for HistoryItem {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            id: entry.id,
+            ts: entry.ts,
+            prompt: entry.prompt,
+            translation: entry.translation,
+            selections: entry.selections,
+            images: entry.images,
+            tags: entry.tags,
+            note: entry.note,
+            rating: entry.rating,
+            score: entry.score,
+            count: entry.count,
+            locked: entry.locked,
+        }
+    }
+}
+
+/// `GET /api/v1/history`: newest first, active and archived history together.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryItem>,
+    pub offset: usize,
+    /// Every matching entry, including those outside this page.
+    pub total: usize,
+}
+
+/// A `[prompt]` item and its current state, for `GET /api/v1/config/items`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigItem {
+    /// `section:key`, as the `/app` endpoints take it.
+    pub item_id: String,
+    pub key: String,
+    pub label: String,
+    pub choices: Vec<String>,
+    pub allow_free_text: bool,
+    /// Output formats the item is rendered in; empty means every format.
+    pub formats: Vec<String>,
+    pub random_pool: Vec<String>,
+    /// Midjourney parameter the value is passed as; empty for prompt text.
+    pub param: String,
+    /// Group the main UI shows the item under; empty for none.
+    pub group: String,
+    pub selected: String,
+    pub free_text: String,
+}
+
+impl ConfigItem {
+    pub fn new(item: ItemConfig, selected: String, free_text: String) -> Self {
+        Self {
+            item_id: item.item_id(),
+            key: item.key,
+            label: item.label,
+            choices: item.choices,
+            allow_free_text: item.allow_free_text,
+            formats: item.formats,
+            random_pool: item.random_pool,
+            param: item.param,
+            group: item.group,
+            selected,
+            free_text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigItems {
+    pub output_format: String,
+    pub output_formats: Vec<String>,
+    pub items: Vec<ConfigItem>,
+}
+
+/// `POST /api/v1/prompt/render` body. Without `selections` the current item
+/// state is rendered; without `output_format`, the selected format is used.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RenderRequest {
+    #[serde(default)]
+    pub output_format: Option<String>,
+    /// Item key → value, like `HistoryItem::selections`.
+    #[serde(default)]
+    pub selections: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedPrompt {
+    pub prompt: String,
+    pub output_format: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryItem;
+    use crate::history_store::HistoryEntry;
+    use serde_json::json;
+
+    #[test]
+    fn history_items_always_carry_every_field() {
+        let entry: HistoryEntry = serde_json::from_value(json!({
+            "id": "20240101_120000_000",
+            "ts": "2024-01-01 12:00:00",
+            "prompt": "[被写体]：cat",
+            "images": [],
+        }))
+        .expect("entry");
+        let item = serde_json::to_value(HistoryItem::from(entry)).expect("serialize");
+        assert_eq!(
+            item,
+            json!({
+                "id": "20240101_120000_000",
+                "ts": "2024-01-01 12:00:00",
+                "prompt": "[被写体]：cat",
+                "translation": "",
+                "selections": {},
+                "images": [],
+                "tags": [],
+                "note": "",
+                "rating": 0,
+                "score": null,
+                "count": 1,
+                "locked": false,
+            })
+        );
+    }
+}
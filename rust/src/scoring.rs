@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+use crate::translation::text_command;
+use crate::vision::run_with_timeout;
+
+pub const SCORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the user-configured scoring command on the rendered prompt and returns
+/// the 0–100 score it prints.
+///
+/// The prompt is passed like `translate_command`'s (`{text}` or stdin). Only
+/// the first line of the output is read, so a command may explain the score
+/// on the lines after it.
+pub fn run_score_command(command: &[String], text: &str, timeout: Duration) -> Result<u8> {
+    let (program, args) = command
+        .split_first()
+        .filter(|(program, _)| !program.trim().is_empty())
+        .ok_or_else(|| anyhow!("score_command is not configured"))?;
+
+    let (process, input) = text_command(program, args, text);
+    let output = run_with_timeout(process, input, "score command", timeout)?;
+    parse_score(output.lines().next().unwrap_or_default())
+}
+
+fn parse_score(line: &str) -> Result<u8> {
+    let score: f64 = line
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("score command printed `{}`, not a number", line.trim()))?;
+    if !(0.0..=100.0).contains(&score) {
+        return Err(anyhow!("score {score} is outside 0-100"));
+    }
+    Ok(score.round() as u8)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::run_score_command;
+    use std::time::Duration;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_the_score_from_the_first_line() {
+        let timeout = Duration::from_secs(5);
+        let score = run_score_command(
+            &argv(&["sh", "-c", "wc -c | tr -d ' '; echo 'length only'"]),
+            "[被写体]：cat",
+            timeout,
+        )
+        .expect("stdin");
+        assert_eq!(score, 17);
+
+        let rounded = run_score_command(&argv(&["echo", "72.6"]), "猫", timeout).expect("rounded");
+        assert_eq!(rounded, 73);
+
+        for output in ["101", "good"] {
+            assert!(run_score_command(&argv(&["echo", output]), "猫", timeout).is_err());
+        }
+        assert!(run_score_command(&[], "猫", timeout).is_err());
+    }
+}
@@ -1,32 +1,174 @@
 use anyhow::{anyhow, Context, Result};
-use axum::extract::{DefaultBodyLimit, Multipart, Query, State};
-use axum::http::{header, HeaderValue, Method, StatusCode};
-use axum::response::{Html, IntoResponse};
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Query, Request, State};
+use axum::http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{Json, Router};
+use axum::{Extension, Json, Router};
+use chrono::Local;
+use futures_util::stream::{self, Stream, StreamExt};
+use futures_util::SinkExt;
+use notify::{RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::SeekFrom;
 use std::net::TcpListener;
-use std::path::Path;
-use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Instant;
-use tokio::sync::oneshot;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{broadcast, oneshot, watch, Notify};
+use tokio_util::io::ReaderStream;
+use tower::ServiceExt;
+use tower_http::compression::predicate::{NotForContentType, Predicate};
+use tower_http::compression::{CompressionLayer, DefaultPredicate};
 use tower_http::cors::CorsLayer;
 
-use crate::config_store::{ConfigStore, ItemConfig};
-use crate::history_store::HistoryStore;
+use crate::api_version::{
+    downgrade, needs_downgrade, negotiate, Negotiated, API_VERSION, API_VERSION_HEADER,
+};
+use crate::backup::{write_backup_zip, StagingDir, MAX_BACKUP_BYTES};
+use crate::config_backups::list_backups;
+use crate::config_exchange::{parse_sections, write_sections, ExchangeFormat};
+use crate::config_store::{AppSettingsUpdate, ConfigStore, ItemConfig, SectionConfig};
+use crate::diagnostics::{check_integrations, format_report, run_diagnostics, CheckStatus};
+use crate::history_store::{
+    parse_import_file, ExportFormat, ExportRange, HistoryStore, ImageRemoval, RetentionExport,
+};
+use crate::housekeeping::{build_preview_html, PlannedAction, HOUSEKEEPING_INTERVAL_SECS};
+use crate::http_cache::{ByteRange, Validators};
+use crate::image_hash::{perceptual_hash, DEFAULT_SIMILAR_DISTANCE};
+use crate::instance_lock::READ_ONLY_MESSAGE;
+use crate::keyword_packs::{list_packs, packs_dir, parse_pack, read_pack};
+use crate::lan_tls::{
+    load_or_create_pairing_token, load_or_create_server_config, pairing_url, LanConfig, TlsListener,
+};
 use crate::main_ui_html::build_main_ui_html;
-use crate::renderer::{render_prompt, RenderEntry};
+use crate::png_text::{self, GenerationMetadata};
+use crate::profiles::apply_history_settings;
+use crate::prompt_parser::{entry_values, parse_prompt};
+use crate::randomize::{random_candidates, Dice, RatingWeights};
+use crate::rate_limit::{ClientAddr, RateLimiter};
+use crate::renderer::{
+    combine_sections, render_formatted, render_prompt_template, wrap_prompt, FormatProfile,
+    RenderContext, RenderEntry, SectionOutput,
+};
+use crate::rest_api::{
+    ConfigItem, ConfigItems, HistoryItem, HistoryPage, RenderRequest, RenderedPrompt,
+    DEFAULT_HISTORY_PAGE_SIZE, HISTORY_PAGE_LIMIT,
+};
+use crate::scoring::{run_score_command, SCORE_TIMEOUT};
+use crate::smart_filters::SmartFilter;
+use crate::text_normalize::matches_loosely;
+use crate::thumbnails::{sized_thumb_width, BACKFILL_PAUSE, BACKFILL_START_DELAY, THUMB_MAX_SIDE};
+use crate::token_count::estimate_clip_tokens;
+use crate::translation::{run_translate_command, TRANSLATE_TIMEOUT};
+use crate::vision::{run_vision_command, VISION_TIMEOUT};
 use crate::NO_SELECTION;
 
+/// Quiet time after the last change to a history JSON file before the pages
+/// are regenerated, so a tool or sync client writing several files causes one
+/// regeneration.
+const HISTORY_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Events buffered per `GET /events` client before a slow one starts missing them.
+const HISTORY_EVENT_CAPACITY: usize = 64;
+/// How long a history page waits before reconnecting to `GET /events`.
+const HISTORY_EVENT_RETRY: Duration = Duration::from_secs(3);
+/// How long the render worker waits for more render requests before writing
+/// the history pages, so a burst of edits causes one regeneration.
+const RENDER_COALESCE: Duration = Duration::from_millis(100);
+/// Images one `POST /upload` may carry.
+const MAX_UPLOAD_FILES: usize = 8;
+
+/// What `GET /events` and `/ws` push to open pages.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HistoryEvent {
+    /// A change the page shows by reloading.
+    Revision {
+        revision: u64,
+    },
+    /// Images attached by one upload, in order.
+    Upload {
+        history_id: String,
+        images: Vec<UploadedImage>,
+    },
+    ImageDelete {
+        history_id: String,
+        image_path: String,
+    },
+    Delete {
+        history_id: String,
+    },
+    /// The main UI's item state changed. `origin` is the `/ws` client that
+    /// made the change, which already shows it.
+    State {
+        revision: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        origin: Option<u64>,
+    },
+    /// `config.txt` was edited outside the app and reloaded; `revision` is the
+    /// new `state_revision`.
+    ConfigReload {
+        revision: u64,
+    },
+    /// An outside edit left `config.txt` unreadable; the running config is kept.
+    ConfigError {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadedImage {
+    pub image_path: String,
+    pub metadata: Option<GenerationMetadata>,
+}
+
 pub struct AppState {
     pub config: Mutex<ConfigStore>,
     pub history: Mutex<HistoryStore>,
     pub copy_state: Mutex<CopyState>,
+    /// Last `(Japanese prompt, English translation)` pair, so re-rendering the
+    /// same preview does not run `translate_command` again.
+    pub last_translation: Mutex<Option<(String, String)>>,
+    /// Last `(prompt, score)` from `score_command`; a copy of that prompt is
+    /// logged with the score.
+    pub last_score: Mutex<Option<(String, u8)>>,
     pub server_port: AtomicU16,
+    /// Port of the LAN HTTPS server; 0 while LAN mode is off.
+    pub lan_port: AtomicU16,
+    /// Token the LAN server requires (see `require_lan_pairing`); unset while
+    /// LAN mode is off.
+    lan_token: OnceLock<String>,
     pub history_revision: AtomicU64,
+    /// Bumped on every item state change (see `STATE_CHANGE_PATHS`) so other
+    /// windows know to reload their snapshot.
+    pub state_revision: AtomicU64,
+    /// Set once the startup render of the history pages has finished, with
+    /// `startup_error` holding why it failed, if it did.
+    pub pages_ready: AtomicBool,
+    pub startup_error: Mutex<Option<String>>,
+    /// Failed `[app.startup]` checks, shown by the main UI.
+    pub startup_warnings: Mutex<Vec<String>>,
+    /// Feeds `GET /events`; `None` ends every stream when the server stops.
+    pub events: broadcast::Sender<Option<HistoryEvent>>,
+    /// Last id handed to a `/ws` connection.
+    ws_client_id: AtomicU64,
+    /// Wakes the render worker, the only writer of the history pages after
+    /// startup.
+    render_requested: Notify,
+    /// Set by a render request whose change open history pages should reload for.
+    reload_after_render: AtomicBool,
+    /// Throttles `RATE_LIMITED_PATHS` per client address.
+    rate_limiter: RateLimiter,
+    /// `HistoryStore::store_id`, answered by `GET /ping`.
+    store_id: String,
 }
 
 type ApiResponse = (StatusCode, Json<Value>);
@@ -38,6 +180,7 @@ pub struct CopyState {
 
 impl AppState {
     pub fn new(config: ConfigStore, history: HistoryStore) -> Self {
+        let store_id = history.store_id();
         Self {
             config: Mutex::new(config),
             history: Mutex::new(history),
@@ -45,9 +188,59 @@ impl AppState {
                 last_prompt: String::new(),
                 last_copy_time: None,
             }),
+            last_translation: Mutex::new(None),
+            last_score: Mutex::new(None),
             server_port: AtomicU16::new(0),
+            lan_port: AtomicU16::new(0),
+            lan_token: OnceLock::new(),
             history_revision: AtomicU64::new(0),
+            state_revision: AtomicU64::new(0),
+            pages_ready: AtomicBool::new(false),
+            startup_error: Mutex::new(None),
+            startup_warnings: Mutex::new(Vec::new()),
+            events: broadcast::channel(HISTORY_EVENT_CAPACITY).0,
+            ws_client_id: AtomicU64::new(0),
+            render_requested: Notify::new(),
+            reload_after_render: AtomicBool::new(false),
+            rate_limiter: RateLimiter::default(),
+            store_id,
+        }
+    }
+
+    /// Asks the render worker to regenerate the history pages and returns
+    /// without waiting for it. Requests made before the worker gets to them
+    /// are merged into one render; with `reload_pages`, open history pages
+    /// reload once the new HTML is written.
+    pub fn schedule_render(&self, reload_pages: bool) {
+        if reload_pages {
+            self.reload_after_render.store(true, Ordering::Relaxed);
         }
+        self.render_requested.notify_one();
+    }
+
+    /// Bumps `history_revision` and tells open history pages to reload.
+    pub fn bump_history_revision(&self) {
+        let revision = self.history_revision.fetch_add(1, Ordering::Relaxed) + 1;
+        self.publish(HistoryEvent::Revision { revision });
+    }
+
+    /// Bumps `state_revision` for a `config.txt` reload and tells every open
+    /// window to reload the main UI snapshot and features.
+    pub fn bump_config_revision(&self) {
+        let revision = self.state_revision.fetch_add(1, Ordering::Relaxed) + 1;
+        self.publish(HistoryEvent::ConfigReload { revision });
+    }
+
+    /// Bumps `state_revision` and tells open windows other than `origin` to
+    /// reload the main UI snapshot.
+    pub fn bump_state_revision(&self, origin: Option<u64>) {
+        let revision = self.state_revision.fetch_add(1, Ordering::Relaxed) + 1;
+        self.publish(HistoryEvent::State { revision, origin });
+    }
+
+    pub fn publish(&self, event: HistoryEvent) {
+        // No receivers just means no history page is open.
+        let _ = self.events.send(Some(event));
     }
 }
 
@@ -58,8 +251,17 @@ pub struct AppServer {
 }
 
 impl AppServer {
-    pub fn start(state: Arc<AppState>, preferred_port: u16) -> Result<Self> {
-        let listener = bind_listener(preferred_port)?;
+    /// Serves loopback HTTP on `preferred_port` or, unless `strict_port`, the
+    /// next free port and, with `lan`, HTTPS on every interface. Moving to
+    /// another port and a LAN server that cannot start are reported as startup
+    /// warnings rather than failing the app.
+    pub fn start(
+        state: Arc<AppState>,
+        preferred_port: u16,
+        strict_port: bool,
+        lan: Option<LanConfig>,
+    ) -> Result<Self> {
+        let listener = bind_listener(preferred_port, strict_port)?;
         let port = listener
             .local_addr()
             .context("failed to inspect server local address")?
@@ -69,6 +271,33 @@ impl AppServer {
             .context("failed to set listener non-blocking")?;
 
         state.server_port.store(port, Ordering::Relaxed);
+        if port != preferred_port {
+            // Pages on disk are rewritten for the new port at startup, and
+            // open ones look for it, but bookmarks of served pages go stale.
+            let warning = format!(
+                "ポート {preferred_port} は使用中のため、ポート {port} で起動しました（[app] strict_port = true でポートを固定できます）"
+            );
+            eprintln!("{warning}");
+            if let Ok(mut warnings) = state.startup_warnings.lock() {
+                warnings.push(warning);
+            }
+        }
+
+        let lan_listener = lan.and_then(|lan| match bind_lan_listener(&lan) {
+            Ok((listener, tls, token)) => {
+                state.lan_port.store(lan.port, Ordering::Relaxed);
+                let _ = state.lan_token.set(token);
+                Some((listener, tls))
+            }
+            Err(err) => {
+                let warning = format!("LAN公開（HTTPS）を開始できませんでした: {err:#}");
+                eprintln!("{warning}");
+                if let Ok(mut warnings) = state.startup_warnings.lock() {
+                    warnings.push(warning);
+                }
+                None
+            }
+        });
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
         let thread_handle = thread::spawn(move || {
@@ -85,11 +314,47 @@ impl AppServer {
                     Err(_) => return,
                 };
 
-                let app = build_router(state);
-                let server = axum::serve(listener, app).with_graceful_shutdown(async {
+                tokio::spawn(run_startup_tasks(state.clone()));
+                tokio::spawn(run_render_worker(state.clone()));
+                tokio::spawn(run_history_watcher(state.clone()));
+                tokio::spawn(run_config_watcher(state.clone()));
+                tokio::spawn(run_housekeeping_schedule(state.clone()));
+                tokio::spawn(run_thumbnail_backfill(state.clone()));
+                let (lan_stop_tx, mut lan_stop_rx) = watch::channel(());
+                let lan_server = {
+                    let state = state.clone();
+                    async move {
+                        let Some((listener, tls)) = lan_listener else {
+                            return;
+                        };
+                        let listener = match tokio::net::TcpListener::from_std(listener)
+                            .and_then(|listener| TlsListener::new(listener, tls))
+                        {
+                            Ok(listener) => listener,
+                            Err(err) => {
+                                eprintln!("LAN公開（HTTPS）を開始できませんでした: {err}");
+                                return;
+                            }
+                        };
+                        let app = build_lan_router(state);
+                        let app = app.into_make_service_with_connect_info::<ClientAddr>();
+                        let _ = axum::serve(listener, app)
+                            .with_graceful_shutdown(async move {
+                                let _ = lan_stop_rx.changed().await;
+                            })
+                            .await;
+                    }
+                };
+                let app =
+                    build_router(state.clone()).into_make_service_with_connect_info::<ClientAddr>();
+                let server = axum::serve(listener, app).with_graceful_shutdown(async move {
                     let _ = shutdown_rx.await;
+                    let _ = lan_stop_tx.send(());
+                    // Event streams never finish on their own and would hold
+                    // the graceful shutdown open.
+                    let _ = state.events.send(None);
                 });
-                let _ = server.await;
+                let _ = tokio::join!(server, lan_server);
             });
         });
 
@@ -123,18 +388,77 @@ impl Drop for AppServer {
 #[derive(Debug, Clone, Serialize)]
 struct UiRow {
     item_id: String,
+    /// Name of the section, i.e. the tab, the row is on.
+    section: String,
     label: String,
     choices: Vec<String>,
     allow_free_text: bool,
     selected: String,
     free_text: String,
+    draft: String,
+    excluded: bool,
+    random_pool: Vec<String>,
+    weight: f64,
+    /// `ランダム` for every row leaves this one as it is.
+    locked: bool,
+    /// Confirmed free texts, newest first, for the input's suggestions.
+    free_text_history: Vec<String>,
+    /// Copied prompts that used each choice; unused choices are absent.
+    usage: BTreeMap<String, u64>,
+    /// Collapsible group the row is under; empty for none.
+    group: String,
+}
+
+/// A tab of the main UI; `preview` is the section on its own.
+#[derive(Debug, Clone, Serialize)]
+struct UiSection {
+    name: String,
+    label: String,
+    preview: String,
+    /// Item groups in the order they first appear.
+    groups: Vec<UiGroup>,
+}
+
+/// An item hidden with `enabled = false`, listed so it can be shown again.
+#[derive(Debug, Clone, Serialize)]
+struct UiHiddenItem {
+    item_id: String,
+    section: String,
+    label: String,
+}
+
+/// A collapsible header of the main UI over the rows with the same `group`.
+#[derive(Debug, Clone, Serialize)]
+struct UiGroup {
+    name: String,
+    items: usize,
+    collapsed: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
-struct UiSnapshot {
+pub struct UiSnapshot {
     rows: Vec<UiRow>,
+    sections: Vec<UiSection>,
+    hidden_items: Vec<UiHiddenItem>,
+    /// Names of the saved presets, sorted.
+    presets: Vec<String>,
+    /// Every section combined: what is copied.
     preview: String,
     confirm_delete: bool,
+    output_format: String,
+    output_formats: Vec<String>,
+    temporary_mode: bool,
+    unsaved_state: bool,
+    translation_enabled: bool,
+    score_enabled: bool,
+    read_only: bool,
+    random_weighted: bool,
+    /// Dropdowns list the most copied choices first.
+    sort_by_usage: bool,
+    /// The output format writes item weights into the prompt.
+    emphasis_enabled: bool,
+    /// Estimated CLIP tokens of `preview`.
+    token_count: usize,
 }
 
 #[derive(Debug, Deserialize)]
@@ -148,22 +472,189 @@ struct HistoryUpdateReq {
     prompt: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct HistorySearchReq {
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryListReq {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiHistoryReq {
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryExportReq {
+    format: String,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// `GET /app/config/export?format=json`; TOML when no format is given.
+#[derive(Debug, Deserialize)]
+struct ConfigExportReq {
+    #[serde(default)]
+    format: String,
+}
+
+/// `POST /app/config/import`: a config or keyword pack as text; `format`
+/// is taken from the extension of the file `name`, or detected from the
+/// text, when left out.
+#[derive(Debug, Deserialize)]
+struct ConfigImportReq {
+    text: String,
+    #[serde(default)]
+    format: String,
+    #[serde(default)]
+    name: String,
+}
+
+/// `POST /app/config/restore`: a file name from `GET /app/config/backups`.
+#[derive(Debug, Deserialize)]
+struct ConfigRestoreReq {
+    file: String,
+}
+
+/// `POST /app/packs/install`: a pack in the `packs` folder by `file`, or a
+/// dropped pack file as `text` with its file `name`.
+#[derive(Debug, Deserialize)]
+struct PackInstallReq {
+    #[serde(default)]
+    file: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryRerenderReq {
+    output_format: String,
+    format: String,
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    history_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryAuditReq {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SmartFilterDeleteReq {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryMoveReq {
+    history_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryNoteReq {
+    history_id: String,
+    note: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryRateReq {
+    history_id: String,
+    rating: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryLockReq {
+    history_id: String,
+    locked: bool,
+}
+
 #[derive(Debug, Deserialize)]
 struct HistoryImageReq {
     path: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct HistoryThumbReq {
+    path: String,
+    /// Width in device pixels; rounded by `sized_thumb_width`.
+    #[serde(default)]
+    w: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadClipboardReq {
+    history_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryAppendReq {
+    prompt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistorySimilarReq {
+    path: String,
+    max_distance: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryImageDeleteReq {
+    history_id: String,
+    image_path: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct ComboChangeReq {
     item_id: String,
     selected: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct RandomizeReq {
+    /// One row; every unlocked row when absent.
+    #[serde(default)]
+    item_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomPoolReq {
+    item_id: String,
+    value: String,
+    enabled: bool,
+}
+
 #[derive(Debug, Deserialize)]
 struct FreeConfirmReq {
     item_id: String,
     selected: String,
     value: String,
+    /// Use the text without adding it to the choices.
+    #[serde(default)]
+    once: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FreeDraftReq {
+    item_id: String,
+    value: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -172,251 +663,4009 @@ struct DeleteChoiceReq {
     selected: String,
 }
 
+/// `POST /app/rename-choice`: renames `value` to `new_value` in place.
+#[derive(Debug, Deserialize)]
+struct RenameChoiceReq {
+    item_id: String,
+    value: String,
+    new_value: String,
+}
+
+/// `POST /app/move-choice`: moves `value` to `index` in the dropdown.
+#[derive(Debug, Deserialize)]
+struct MoveChoiceReq {
+    item_id: String,
+    value: String,
+    index: usize,
+}
+
+/// `POST /app/reorder-items`: the section's item keys in their new order.
+#[derive(Debug, Deserialize)]
+struct ReorderItemsReq {
+    section: String,
+    keys: Vec<String>,
+}
+
+/// `POST /app/group-collapse`: folds or unfolds a group of the section.
+#[derive(Debug, Deserialize)]
+struct GroupCollapseReq {
+    section: String,
+    group: String,
+    collapsed: bool,
+}
+
+/// `POST /app/settings`: only the fields sent are changed.
+#[derive(Debug, Deserialize)]
+struct SettingsReq {
+    #[serde(default)]
+    prompt_prefix: Option<String>,
+    #[serde(default)]
+    prompt_suffix: Option<String>,
+    #[serde(default)]
+    delimiter: Option<String>,
+    #[serde(default)]
+    copy_debounce_sec: Option<f64>,
+    #[serde(default)]
+    history_max_entries: Option<usize>,
+    #[serde(default)]
+    confirm_delete: Option<bool>,
+    #[serde(default)]
+    history_server_port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemLockReq {
+    item_id: String,
+    locked: bool,
+}
+
+/// `POST /app/preset/save`, `/apply` and `/delete`.
+#[derive(Debug, Deserialize)]
+struct PresetReq {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemEnabledReq {
+    item_id: String,
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemWeightReq {
+    item_id: String,
+    weight: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputFormatReq {
+    format: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TemporaryModeReq {
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomWeightedReq {
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SortByUsageReq {
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParsePromptReq {
+    prompt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyParsedReq {
+    items: Vec<ParsedItemReq>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyHistoryReq {
+    history_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParsedItemReq {
+    item_id: String,
+    value: String,
+    /// Adds a value that is not a choice yet to the item's choices; otherwise
+    /// it is only set as the item's free text.
+    #[serde(default)]
+    add_choice: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestReq {
+    #[serde(default)]
+    q: String,
+    /// `section:key` to search one item's choices; every item when omitted.
+    item_id: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Suggestion {
+    item_id: String,
+    label: String,
+    choice: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TranslateReq {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScoreReq {
+    text: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct CopyReq {
     prompt: String,
+    /// English translation shown beside the prompt; stored with the history entry.
+    #[serde(default)]
+    translation: String,
+    /// Put the translation on the clipboard instead of the Japanese prompt.
+    #[serde(default)]
+    copy_translation: bool,
 }
 
-fn build_router(state: Arc<AppState>) -> Router {
-    let port = state.server_port.load(Ordering::Relaxed);
+/// Origins allowed to call the API: the app's own pages and history pages
+/// opened from disk (`null`).
+fn allowed_origins(port: u16) -> [HeaderValue; 3] {
     let local_origin = HeaderValue::from_str(&format!("http://127.0.0.1:{port}"))
         .expect("127.0.0.1 origin should be valid");
     let localhost_origin = HeaderValue::from_str(&format!("http://localhost:{port}"))
         .expect("localhost origin should be valid");
+    [
+        HeaderValue::from_static("null"),
+        local_origin,
+        localhost_origin,
+    ]
+}
 
+fn build_router(state: Arc<AppState>) -> Router {
+    let port = state.server_port.load(Ordering::Relaxed);
     let cors = CorsLayer::new()
-        .allow_origin([
-            HeaderValue::from_static("null"),
-            local_origin,
-            localhost_origin,
+        .allow_origin(allowed_origins(port))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            HeaderName::from_static(API_VERSION_HEADER),
         ])
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers([header::CONTENT_TYPE]);
+        .expose_headers([HeaderName::from_static(API_VERSION_HEADER)]);
+    // HTML, JSON and CSS shrink several times over, which matters over the LAN.
+    // Images, event streams and ZIP backups are compressed already or must
+    // not be buffered, and a compressed 206 would no longer match its
+    // Content-Range, so they pass through as they are.
+    let compression = CompressionLayer::new().compress_when(
+        DefaultPredicate::new()
+            .and(NotForContentType::const_new("application/zip"))
+            .and(|status: StatusCode, _, _: &_, _: &_| status != StatusCode::PARTIAL_CONTENT),
+    );
 
     Router::new()
         .route("/", get(get_main_page))
         .route("/ping", get(get_ping))
         .route("/image", get(get_history_image))
+        .route("/image/thumb", get(get_history_thumbnail))
+        .route("/image/delete", post(post_delete_history_image))
+        .route("/pages/{*name}", get(get_history_page))
         .route("/delete", post(post_delete_history))
         .route("/update", post(post_update_history))
-        .route("/upload", post(post_upload_history))
+        .route("/update-note", post(post_update_history_note))
+        .route(
+            "/upload",
+            post(post_upload_history).layer(DefaultBodyLimit::max(
+                HistoryStore::MAX_IMAGE_BYTES * MAX_UPLOAD_FILES + 200_000,
+            )),
+        )
+        .route("/upload-clipboard", post(post_upload_clipboard))
+        .route("/lock", post(post_lock_history))
+        .route("/history/rate", post(post_rate_history))
+        .route("/delete-samples", post(post_delete_samples))
+        .route("/archive-now", post(post_archive_now))
+        .route("/archive", post(post_archive_history))
+        .route("/unarchive", post(post_unarchive_history))
+        .route("/history/restore", post(post_restore_history))
+        .route("/history/undo", post(post_undo_history))
+        .route(
+            "/history/smart-filters",
+            get(get_smart_filters)
+                .post(post_smart_filter)
+                .delete(delete_smart_filter),
+        )
+        .route(
+            "/history/similar",
+            get(get_similar_images).post(post_similar_images),
+        )
+        .route("/history/vision", post(post_history_vision))
+        .route("/history/append", post(post_history_append))
+        .route("/history", get(get_history_search))
+        .route("/history/list", get(get_history_list))
+        .route("/history/export", get(get_history_export))
+        .route("/history/backup", get(get_history_backup))
+        .route(
+            "/history/restore-backup",
+            post(post_restore_backup).layer(DefaultBodyLimit::max(MAX_BACKUP_BYTES)),
+        )
+        .route("/history/rerender", post(post_history_rerender))
+        .route("/history/import", post(post_history_import))
+        .route("/history/audit", get(get_history_audit))
+        .route("/history/housekeeping", get(get_housekeeping_preview))
+        .route("/history/housekeeping/run", post(post_housekeeping_run))
         .route("/app/init", get(get_app_init))
         .route("/app/history-revision", get(get_app_history_revision))
+        .route("/events", get(get_events))
+        .route("/ws", get(get_ws))
+        .route("/app/state-revision", get(get_app_state_revision))
+        .route("/app/ready", get(get_app_ready))
         .route("/app/combo-change", post(post_app_combo_change))
         .route("/app/free-confirm", post(post_app_free_confirm))
+        .route("/app/free-draft", post(post_app_free_draft))
         .route("/app/delete-choice", post(post_app_delete_choice))
+        .route("/app/rename-choice", post(post_app_rename_choice))
+        .route("/app/move-choice", post(post_app_move_choice))
         .route("/app/reset", post(post_app_reset))
+        .route("/app/randomize", post(post_app_randomize))
+        .route("/app/random-pool", post(post_app_random_pool))
+        .route("/app/item-weight", post(post_app_item_weight))
+        .route("/app/item-lock", post(post_app_item_lock))
+        .route("/app/item-enabled", post(post_app_item_enabled))
+        .route("/app/reorder-items", post(post_app_reorder_items))
+        .route("/app/preset/save", post(post_app_preset_save))
+        .route("/app/preset/apply", post(post_app_preset_apply))
+        .route("/app/preset/delete", post(post_app_preset_delete))
+        .route("/app/group-collapse", post(post_app_group_collapse))
+        .route(
+            "/app/settings",
+            get(get_app_settings).post(post_app_settings),
+        )
+        .route("/app/output-format", post(post_app_output_format))
+        .route("/app/random-weighted", post(post_app_random_weighted))
+        .route("/app/sort-by-usage", post(post_app_sort_by_usage))
+        .route("/app/temporary-mode", post(post_app_temporary_mode))
+        .route("/app/temporary-save", post(post_app_temporary_save))
+        .route("/app/parse-prompt", post(post_app_parse_prompt))
+        .route("/app/apply-parsed", post(post_app_apply_parsed))
+        .route("/app/apply-history", post(post_app_apply_history))
+        .route("/app/suggest", get(get_app_suggest))
+        .route("/app/translate", post(post_app_translate))
+        .route("/app/score", post(post_app_score))
         .route("/app/copy", post(post_app_copy))
         .route("/app/open-history", post(post_app_open_history))
+        .route("/app/open-stats", post(post_app_open_stats))
+        .route("/app/features", get(get_app_features))
+        .route("/app/config/validate", get(get_app_config_validate))
+        .route("/app/config/cleanup", post(post_app_config_cleanup))
+        .route("/app/config/export", get(get_app_config_export))
+        .route("/app/config/import", post(post_app_config_import))
+        .route("/app/config/backups", get(get_app_config_backups))
+        .route("/app/config/restore", post(post_app_config_restore))
+        .route("/app/packs", get(get_app_packs))
+        .route("/app/packs/install", post(post_app_packs_install))
+        .route("/app/reload", post(post_app_reload))
+        .route("/app/diagnostics", get(get_app_diagnostics))
+        .route("/api/v1/history", get(get_api_history))
+        .route("/api/v1/prompt/render", post(post_api_render_prompt))
+        .route("/api/v1/config/items", get(get_api_config_items))
         .layer(DefaultBodyLimit::max(
             HistoryStore::MAX_IMAGE_BYTES + 200_000,
         ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            publish_state_changes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit_mutation_rate,
+        ))
+        .layer(middleware::from_fn(negotiate_api_version))
         .layer(cors)
+        .layer(compression)
         .with_state(state)
 }
 
-async fn get_main_page() -> Html<String> {
-    Html(build_main_ui_html())
-}
-
-async fn get_ping() -> ApiResponse {
-    ok_json(json!({}))
+/// The router behind the LAN port: the same routes, but only for paired devices.
+fn build_lan_router(state: Arc<AppState>) -> Router {
+    build_router(state.clone()).layer(middleware::from_fn_with_state(state, require_lan_pairing))
 }
 
-async fn get_history_image(
-    State(state): State<Arc<AppState>>,
-    Query(payload): Query<HistoryImageReq>,
-) -> axum::response::Response {
-    let image_path = payload.path.trim().to_string();
-    if image_path.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "path is required").into_response();
-    }
+/// Marks a LAN request that presented the pairing token.
+#[derive(Clone, Copy)]
+struct LanPaired;
 
-    let image = {
-        let history = match state.history.lock() {
-            Ok(guard) => guard,
-            Err(_) => {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "history store lock error",
-                )
-                .into_response()
-            }
-        };
+/// Query parameter of the pairing URL the main UI shows.
+const LAN_PAIR_QUERY: &str = "pair";
+/// Cookie a paired browser keeps the token in.
+const LAN_TOKEN_COOKIE: &str = "ipg_lan_token";
+/// Header for scripts that send the token themselves.
+const LAN_TOKEN_HEADER: &str = "x-ipg-lan-token";
 
-        history.read_image_blob(&image_path)
+/// Lets through only LAN requests carrying the pairing token, in the cookie
+/// or `LAN_TOKEN_HEADER`. Opening the pairing URL stores the cookie and
+/// redirects to the page without the token.
+async fn require_lan_pairing(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = state.lan_token.get() else {
+        return err_json(StatusCode::FORBIDDEN, "LAN pairing is not set up").into_response();
     };
-
-    match image {
-        Ok((bytes, content_type)) => (
-            StatusCode::OK,
-            [(header::CONTENT_TYPE, content_type)],
-            bytes,
+    let offered = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(LAN_PAIR_QUERY)?.strip_prefix('='))
+    });
+    if offered.is_some_and(|offered| tokens_match(offered, token)) {
+        let cookie = format!(
+            "{LAN_TOKEN_COOKIE}={token}; Path=/; Max-Age=31536000; HttpOnly; Secure; SameSite=Strict"
+        );
+        let location = request.uri().path().to_string();
+        return (
+            StatusCode::SEE_OTHER,
+            [(header::LOCATION, location), (header::SET_COOKIE, cookie)],
         )
-            .into_response(),
-        Err(err) => {
-            let message = err.to_string();
-            let status = if message.contains("failed to read image") {
-                StatusCode::NOT_FOUND
-            } else {
-                StatusCode::BAD_REQUEST
-            };
-            err_json(status, &message).into_response()
-        }
+            .into_response();
+    }
+    if presented_lan_token(request.headers())
+        .is_some_and(|presented| tokens_match(presented, token))
+    {
+        request.extensions_mut().insert(LanPaired);
+        return next.run(request).await;
     }
+    err_json(
+        StatusCode::UNAUTHORIZED,
+        "this device is not paired; open the LAN pairing URL shown in the app",
+    )
+    .into_response()
 }
 
-async fn post_delete_history(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<HistoryDeleteReq>,
-) -> ApiResponse {
-    let history_id = payload.history_id.trim().to_string();
-    if history_id.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+fn presented_lan_token(headers: &axum::http::HeaderMap) -> Option<&str> {
+    if let Some(token) = headers.get(LAN_TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+        return Some(token);
     }
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            cookie
+                .trim()
+                .strip_prefix(LAN_TOKEN_COOKIE)?
+                .strip_prefix('=')
+        })
+}
 
-    let port = state.server_port.load(Ordering::Relaxed);
-    let removed = {
-        let mut history = match state.history.lock() {
+/// Compares without stopping at the first difference.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// `/app` routes that change the main UI's item state.
+const STATE_CHANGE_PATHS: &[&str] = &[
+    "/app/combo-change",
+    "/app/free-confirm",
+    "/app/free-draft",
+    "/app/delete-choice",
+    "/app/rename-choice",
+    "/app/move-choice",
+    "/app/reset",
+    "/app/randomize",
+    "/app/random-pool",
+    "/app/item-weight",
+    "/app/item-lock",
+    "/app/item-enabled",
+    "/app/reorder-items",
+    "/app/preset/save",
+    "/app/preset/apply",
+    "/app/preset/delete",
+    "/app/group-collapse",
+    "/app/settings",
+    "/app/output-format",
+    "/app/random-weighted",
+    "/app/sort-by-usage",
+    "/app/temporary-mode",
+    "/app/temporary-save",
+    "/app/apply-parsed",
+    "/app/apply-history",
+    "/app/config/cleanup",
+    "/app/config/import",
+    "/app/config/restore",
+    "/app/packs/install",
+    "/app/reload",
+];
+
+/// Routes that write history files and regenerate pages, limited per client
+/// so a script or a page stuck retrying cannot keep the disk busy.
+const RATE_LIMITED_PATHS: &[&str] = &["/delete", "/update", "/upload"];
+
+/// Sent by `/ws` with every request it forwards, so a state change can name
+/// the window that made it.
+const CLIENT_ID_HEADER: &str = "x-ipg-client-id";
+
+/// Bumps `state_revision` after a successful state change, whichever page or
+/// window it came from.
+async fn publish_state_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let changes_state =
+        request.method() == Method::POST && STATE_CHANGE_PATHS.contains(&request.uri().path());
+    let origin = request
+        .headers()
+        .get(CLIENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let response = next.run(request).await;
+    if changes_state && response.status().is_success() {
+        state.bump_state_revision(origin);
+    }
+    response
+}
+
+/// Answers `RATE_LIMITED_PATHS` with `429` and `Retry-After` once a client
+/// has used up its burst. Requests without a known address (from tests) pass.
+async fn limit_mutation_rate(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<ClientAddr>>()
+        .map(|ConnectInfo(ClientAddr(addr))| addr.ip());
+    if let Some(ip) = client.filter(|_| RATE_LIMITED_PATHS.contains(&request.uri().path())) {
+        if let Err(wait) = state.rate_limiter.check(ip, Instant::now()) {
+            let retry_after = wait.as_secs().max(1);
+            let mut response = err_json(
+                StatusCode::TOO_MANY_REQUESTS,
+                &format!("too many requests; retry in {retry_after}s"),
+            )
+            .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            return response;
+        }
+    }
+    next.run(request).await
+}
+
+/// Answers pages from a newer app with a reload hint, reshapes responses for
+/// older pages and stamps every response with `API_VERSION`.
+async fn negotiate_api_version(request: Request, next: Next) -> Response {
+    let requested = request
+        .headers()
+        .get(API_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let path = request.uri().path().to_string();
+    let mut response = match negotiate(requested) {
+        Negotiated::Current => next.run(request).await,
+        Negotiated::Legacy(version) => {
+            let response = next.run(request).await;
+            if needs_downgrade(version, &path) {
+                downgrade_response(version, &path, response).await
+            } else {
+                response
+            }
+        }
+        Negotiated::Unsupported(_) => err_json(
+            StatusCode::CONFLICT,
+            "このページは新しいバージョンのアプリで作成されています。アプリを更新してください。",
+        )
+        .into_response(),
+    };
+    response
+        .headers_mut()
+        .insert(API_VERSION_HEADER, HeaderValue::from(API_VERSION));
+    response
+}
+
+async fn downgrade_response(version: u32, path: &str, response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("response read failed: {err}"),
+            )
+            .into_response()
+        }
+    };
+    // Non-JSON bodies (errors from extractors, files) pass through unchanged.
+    let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    downgrade(version, path, &mut value);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(value.to_string()))
+}
+
+async fn get_main_page() -> Html<String> {
+    Html(build_main_ui_html())
+}
+
+/// `store_id` lets a history page opened from disk tell this app from another
+/// profile's while it looks for the port the app moved to.
+async fn get_ping(State(state): State<Arc<AppState>>) -> ApiResponse {
+    ok_json(json!({ "store_id": state.store_id }))
+}
+
+async fn get_history_image(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    Query(payload): Query<HistoryImageReq>,
+) -> axum::response::Response {
+    let image_path = payload.path.trim().to_string();
+    if image_path.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "path is required").into_response();
+    }
+
+    let embed_parameters = match state.config.lock() {
+        Ok(config) => config.png_embed_parameters(),
+        Err(_) => {
+            return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error").into_response()
+        }
+    };
+
+    let (path, content_type, validators, range, len) = {
+        let history = match state.history.lock() {
             Ok(guard) => guard,
             Err(_) => {
                 return err_json(
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "history store lock error",
                 )
+                .into_response()
             }
         };
 
-        match history.delete_history(&history_id) {
-            Ok(removed) => {
-                if !removed {
-                    return err_json(StatusCode::NOT_FOUND, "history id not found");
-                }
-                if let Err(err) = history.regenerate_html(port) {
-                    return err_json(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        &format!("delete failed: {err}"),
-                    );
+        let metadata = match history.image_file_metadata(&image_path) {
+            Ok(metadata) => metadata,
+            Err(err) => return image_error(&err),
+        };
+        // The embedded prompt is part of the response, so it is part of the tag.
+        let prompt = if embed_parameters && image_path.to_ascii_lowercase().ends_with(".png") {
+            history.prompt_for_image(&image_path).ok().flatten()
+        } else {
+            None
+        };
+        let validators = Validators::new(&metadata, prompt.as_deref());
+        if validators.is_fresh(&headers) {
+            return (StatusCode::NOT_MODIFIED, validators.headers()).into_response();
+        }
+        let encrypted = match history.is_image_encrypted(&image_path) {
+            Ok(encrypted) => encrypted,
+            Err(err) => return image_error(&err),
+        };
+
+        if prompt.is_some() || encrypted {
+            let image = history
+                .read_image_blob(&image_path)
+                .map(|(bytes, content_type)| {
+                    // Serve the file untouched when no entry or chunk can be found.
+                    let bytes = match prompt.filter(|_| content_type == "image/png") {
+                        Some(prompt) => {
+                            png_text::embed_parameters(&bytes, &prompt).unwrap_or(bytes)
+                        }
+                        None => bytes,
+                    };
+                    (bytes, content_type)
+                });
+            let (bytes, content_type) = match image {
+                Ok(image) => image,
+                Err(err) => return image_error(&err),
+            };
+            // Decrypted or embedded in memory, so the range is cut from the result.
+            let len = bytes.len() as u64;
+            let range = validators.byte_range(&headers, len);
+            let body = match range {
+                ByteRange::Full => Body::from(bytes),
+                ByteRange::Partial { start, end } => {
+                    Body::from(bytes[start as usize..=end as usize].to_vec())
                 }
-                removed
+                ByteRange::Unsatisfiable => return unsatisfiable_range(&validators, len),
+            };
+            return image_response(&validators, content_type, range, len, body);
+        }
+
+        let (path, content_type) = match history.image_file(&image_path) {
+            Ok(file) => file,
+            Err(err) => return image_error(&err),
+        };
+        let len = metadata.len();
+        let range = validators.byte_range(&headers, len);
+        (path, content_type, validators, range, len)
+    };
+
+    // Served as stored, so the file is streamed instead of read into memory.
+    let read_error = |err: std::io::Error| {
+        image_error(
+            &anyhow::Error::new(err).context(format!("failed to read image: {}", path.display())),
+        )
+    };
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => return read_error(err),
+    };
+    let body = match range {
+        ByteRange::Full => Body::from_stream(ReaderStream::new(file)),
+        ByteRange::Partial { start, end } => {
+            if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+                return read_error(err);
             }
-            Err(err) => {
+            Body::from_stream(ReaderStream::new(file.take(end - start + 1)))
+        }
+        ByteRange::Unsatisfiable => return unsatisfiable_range(&validators, len),
+    };
+    image_response(&validators, content_type, range, len, body)
+}
+
+/// `200`, or `206` for a partial range. `Content-Length` is set explicitly
+/// because a streamed body does not know its size.
+fn image_response(
+    validators: &Validators,
+    content_type: &'static str,
+    range: ByteRange,
+    len: u64,
+    body: Body,
+) -> Response {
+    let mut response = (
+        validators.headers(),
+        [(header::CONTENT_TYPE, content_type)],
+        body,
+    )
+        .into_response();
+    let body_len = match range {
+        ByteRange::Partial { start, end } => {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            if let Some(content_range) = range.content_range(len) {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_RANGE, content_range);
+            }
+            end - start + 1
+        }
+        _ => len,
+    };
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body_len));
+    response
+}
+
+fn unsatisfiable_range(validators: &Validators, len: u64) -> Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        validators.headers(),
+        [(
+            header::CONTENT_RANGE,
+            ByteRange::Unsatisfiable
+                .content_range(len)
+                .expect("unsatisfiable range"),
+        )],
+    )
+        .into_response()
+}
+
+/// A thumbnail of an attached image sized for the card showing it, generated
+/// and cached on first request.
+async fn get_history_thumbnail(
+    State(state): State<Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    Query(payload): Query<HistoryThumbReq>,
+) -> axum::response::Response {
+    let image_path = payload.path.trim().to_string();
+    if image_path.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "path is required").into_response();
+    }
+    let width = sized_thumb_width(payload.w.unwrap_or(THUMB_MAX_SIDE));
+
+    // Only the paths are looked up under the lock; decoding runs without it.
+    let (metadata, job) = {
+        let history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
                 return err_json(
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("delete failed: {err}"),
+                    "history store lock error",
+                )
+                .into_response()
+            }
+        };
+        let metadata = match history.image_file_metadata(&image_path) {
+            Ok(metadata) => metadata,
+            Err(err) => return image_error(&err),
+        };
+        match history.sized_thumbnail(&image_path, width) {
+            Ok(job) => (metadata, job),
+            Err(err) => return image_error(&err),
+        }
+    };
+    // Each width is its own response for the same source file.
+    let validators = Validators::new(&metadata, Some(&format!("w{width}")));
+    if validators.is_fresh(&headers) {
+        return (StatusCode::NOT_MODIFIED, validators.headers()).into_response();
+    }
+    let result = match tokio::task::spawn_blocking(move || job.run()).await {
+        Ok(result) => result,
+        Err(err) => {
+            return err_json(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()).into_response()
+        }
+    };
+    match result {
+        Ok(bytes) => (
+            StatusCode::OK,
+            validators.headers(),
+            [(header::CONTENT_TYPE, "image/webp")],
+            bytes,
+        )
+            .into_response(),
+        Err(err) => image_error(&err),
+    }
+}
+
+fn image_error(err: &anyhow::Error) -> Response {
+    let message = err.to_string();
+    let status = if message.contains("failed to read image") {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    err_json(status, &message).into_response()
+}
+
+/// History pages and their images for an encrypted store, which keeps neither
+/// in plain text on disk.
+async fn get_history_page(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let port = state.server_port.load(Ordering::Relaxed);
+    let history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+            .into_response()
+        }
+    };
+    // Unencrypted pages are opened from disk, except by other machines in LAN mode.
+    if !history.is_encrypted() && state.lan_port.load(Ordering::Relaxed) == 0 {
+        return err_json(
+            StatusCode::NOT_FOUND,
+            "pages are served only for encrypted history or in LAN mode",
+        )
+        .into_response();
+    }
+
+    if name.starts_with("images/") {
+        return match history.read_image_blob(&name) {
+            Ok((bytes, content_type)) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, content_type)],
+                bytes,
+            )
+                .into_response(),
+            Err(err) => err_json(StatusCode::NOT_FOUND, &err.to_string()).into_response(),
+        };
+    }
+    if name.starts_with("thumbs/") {
+        return match history.read_thumbnail_blob(&name) {
+            Ok(bytes) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, "image/webp")],
+                bytes,
+            )
+                .into_response(),
+            Err(err) => err_json(StatusCode::NOT_FOUND, &err.to_string()).into_response(),
+        };
+    }
+    match history.render_page(&name, port) {
+        Ok(Some(page)) => Html(page).into_response(),
+        Ok(None) => err_json(StatusCode::NOT_FOUND, "page not found").into_response(),
+        Err(err) => err_json(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()).into_response(),
+    }
+}
+
+const HISTORY_SEARCH_LIMIT: usize = 200;
+
+async fn get_history_search(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<HistorySearchReq>,
+) -> ApiResponse {
+    let range = match ExportRange::parse(payload.from.as_deref(), payload.to.as_deref()) {
+        Ok(range) => range,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let limit = payload
+        .limit
+        .unwrap_or(HISTORY_SEARCH_LIMIT)
+        .clamp(1, HISTORY_SEARCH_LIMIT);
+
+    let history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+    match history.search(range, &payload.q, limit) {
+        Ok(search) => ok_json(json!({
+            "truncated": search.total > search.hits.len(),
+            "hits": search.hits,
+            "total": search.total,
+        })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("history search failed: {err}"),
+        ),
+    }
+}
+
+const HISTORY_LIST_LIMIT: usize = 500;
+
+async fn get_history_list(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<HistoryListReq>,
+) -> ApiResponse {
+    let limit = payload
+        .limit
+        .unwrap_or(HistoryStore::LIST_PAGE_SIZE)
+        .clamp(1, HISTORY_LIST_LIMIT);
+    let result = tokio::task::spawn_blocking(move || {
+        let history = state
+            .history
+            .lock()
+            .map_err(|_| anyhow!("history store lock error"))?;
+        history.list_entries(payload.offset, limit)
+    })
+    .await;
+    match result {
+        Ok(Ok(list)) => ok_json(json!({
+            "entries": list.entries,
+            "offset": list.offset,
+            "total": list.total,
+        })),
+        Ok(Err(err)) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("history list failed: {err}"),
+        ),
+        Err(err) => err_json(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+/// `GET /api/v1/history`: history for scripts, in the stable `HistoryItem`
+/// shape, filtered like `GET /history` and paged with `offset` / `limit`.
+async fn get_api_history(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<ApiHistoryReq>,
+) -> ApiResponse {
+    let range = match ExportRange::parse(payload.from.as_deref(), payload.to.as_deref()) {
+        Ok(range) => range,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let limit = payload
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_PAGE_SIZE)
+        .clamp(1, HISTORY_PAGE_LIMIT);
+    let offset = payload.offset;
+    let result = tokio::task::spawn_blocking(move || {
+        let history = state
+            .history
+            .lock()
+            .map_err(|_| anyhow!("history store lock error"))?;
+        history.search(range, &payload.q, offset.saturating_add(limit))
+    })
+    .await;
+    match result {
+        Ok(Ok(search)) => ok_json(json!(HistoryPage {
+            entries: search
+                .hits
+                .into_iter()
+                .skip(offset)
+                .map(|hit| HistoryItem::from(hit.entry))
+                .collect(),
+            offset,
+            total: search.total,
+        })),
+        Ok(Err(err)) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("history list failed: {err}"),
+        ),
+        Err(err) => err_json(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+/// Streams a backup ZIP. Only the file list and a copy of the history JSON
+/// are taken under the lock; the ZIP is written to a temp file without it.
+async fn get_history_backup(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    let result = tokio::task::spawn_blocking(move || {
+        let staging = StagingDir::create("backup")?;
+        let mut files = {
+            let history = state
+                .history
+                .lock()
+                .map_err(|_| anyhow!("history store lock error"))?;
+            history.backup_files(Some(staging.path()))?
+        };
+        // An image deleted since the list was taken is left out.
+        files.retain(|file| file.path.is_file());
+        let zip_path = staging.path().join("history_backup.zip");
+        let zip_file = std::fs::File::create(&zip_path)
+            .with_context(|| format!("failed to create: {}", zip_path.display()))?;
+        write_backup_zip(&files, zip_file)?;
+        Ok::<_, anyhow::Error>((staging, zip_path))
+    })
+    .await;
+
+    let (staging, zip_path) = match result {
+        Ok(Ok(made)) => made,
+        Ok(Err(err)) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("backup failed: {err}"),
+            )
+            .into_response()
+        }
+        Err(_) => {
+            return err_json(StatusCode::INTERNAL_SERVER_ERROR, "backup task failed")
+                .into_response()
+        }
+    };
+    let file = match tokio::fs::File::open(&zip_path).await {
+        Ok(file) => file,
+        Err(err) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("backup failed: {err}"),
+            )
+            .into_response()
+        }
+    };
+    let length = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    // The staging dir goes once the body is dropped, after the file is closed.
+    let body = Body::from_stream(ReaderStream::new(file).map(move |chunk| {
+        let _ = &staging;
+        chunk
+    }));
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"history_backup_{}.zip\"",
+                    Local::now().format("%Y%m%d_%H%M%S")
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+async fn get_history_export(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<HistoryExportReq>,
+) -> axum::response::Response {
+    let format = match ExportFormat::parse(&payload.format) {
+        Ok(format) => format,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()).into_response(),
+    };
+    let range = match ExportRange::parse(payload.from.as_deref(), payload.to.as_deref()) {
+        Ok(range) => range,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()).into_response(),
+    };
+
+    let exported = {
+        let history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+                .into_response()
+            }
+        };
+
+        history.export(format, range)
+    };
+
+    match exported {
+        Ok(body) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"history_export.{}\"",
+                        format.extension()
+                    ),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("export failed: {err}"),
+        )
+        .into_response(),
+    }
+}
+
+async fn post_history_rerender(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryRerenderReq>,
+) -> ApiResponse {
+    let output_format = payload.output_format.trim().to_string();
+    if output_format.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "output_format is required");
+    }
+    let format = match ExportFormat::parse(&payload.format) {
+        Ok(format) => format,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let range = match ExportRange::parse(payload.from.as_deref(), payload.to.as_deref()) {
+        Ok(range) => range,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    let (sections, layout, ctx) = match state.config.lock() {
+        Ok(config) => (
+            config.sections(),
+            PromptLayout::new(&config, &output_format),
+            RenderContext::new(config.template_variables()),
+        ),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+
+    let exported = {
+        let history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
                 )
             }
+        };
+
+        history.export_rerendered(format, range, &payload.history_ids, |entry| {
+            if entry.selections.is_empty() {
+                return None;
+            }
+            let (_, prompt) = layout.render(&sections, &ctx, |item| {
+                selection_render_entry(item, &entry.selections)
+            });
+            (!prompt.is_empty()).then_some(prompt)
+        })
+    };
+
+    match exported {
+        Ok(export) => ok_json(json!({
+            "content": export.content,
+            "rendered": export.rendered,
+            "skipped": export.skipped,
+            "filename": format!("history_{output_format}.{}", format.extension()),
+        })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("rerender failed: {err}"),
+        ),
+    }
+}
+
+/// Regenerates the history pages on request from `AppState::schedule_render`,
+/// one render at a time, so handlers never write the HTML concurrently or wait
+/// for it.
+async fn run_render_worker(state: Arc<AppState>) {
+    loop {
+        state.render_requested.notified().await;
+        tokio::time::sleep(RENDER_COALESCE).await;
+        let task_state = state.clone();
+        let result = tokio::task::spawn_blocking(move || render_history_pages(&task_state)).await;
+        if let Ok(Err(err)) = result {
+            eprintln!("履歴ページの生成に失敗しました: {err}");
+        }
+    }
+}
+
+fn render_history_pages(state: &AppState) -> Result<()> {
+    // Taken before rendering, so a request arriving mid-render is kept for the
+    // next pass instead of being lost.
+    let reload_pages = state.reload_after_render.swap(false, Ordering::Relaxed);
+    state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?
+        .regenerate_html(state.server_port.load(Ordering::Relaxed))?;
+    if reload_pages {
+        state.bump_history_revision();
+    }
+    Ok(())
+}
+
+/// Maintenance scheduler: applies the housekeeping rules, purges expired
+/// trash and exports archives past the retention window at startup and then
+/// hourly.
+async fn run_housekeeping_schedule(state: Arc<AppState>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(HOUSEKEEPING_INTERVAL_SECS));
+    loop {
+        ticker.tick().await;
+        // The instance that owns the data dir does the maintenance.
+        if state
+            .history
+            .lock()
+            .is_ok_and(|history| history.is_read_only())
+        {
+            continue;
+        }
+        if let Err(err) = apply_housekeeping(&state) {
+            eprintln!("履歴の整理に失敗しました: {err}");
+        }
+        if let Err(err) = purge_trash(&state) {
+            eprintln!("ゴミ箱の整理に失敗しました: {err}");
+        }
+        if let Err(err) = enforce_retention(&state) {
+            eprintln!("保存期間を過ぎたアーカイブの書き出しに失敗しました: {err:#}");
+        }
+    }
+}
+
+/// Watches the data dir for `history.json` and `History_*.json` edits made by
+/// other tools or a synced folder, then regenerates the pages and bumps
+/// `history_revision` so open history pages reload. Writes made by the app
+/// itself are recognised and skipped.
+async fn run_history_watcher(state: Arc<AppState>) {
+    let data_dir = match state.history.lock() {
+        Ok(history) => history.base_dir().to_path_buf(),
+        Err(_) => return,
+    };
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        for path in event.paths {
+            if HistoryStore::is_history_json(&path) {
+                let _ = tx.send(path);
+            }
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("履歴ファイルの監視を開始できませんでした: {err}");
+            return;
+        }
+    };
+    if let Err(err) = watcher.watch(&data_dir, RecursiveMode::NonRecursive) {
+        eprintln!("履歴ファイルの監視を開始できませんでした: {err}");
+        return;
+    }
+
+    while let Some(path) = rx.recv().await {
+        let mut changed = BTreeSet::from([path]);
+        loop {
+            match tokio::time::timeout(HISTORY_WATCH_DEBOUNCE, rx.recv()).await {
+                Ok(Some(path)) => {
+                    changed.insert(path);
+                }
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        let task_state = state.clone();
+        let result =
+            tokio::task::spawn_blocking(move || reload_external_history(&task_state, &changed))
+                .await;
+        if let Ok(Err(err)) = result {
+            eprintln!("外部で変更された履歴の反映に失敗しました: {err:#}");
+        }
+    }
+}
+
+fn reload_external_history(state: &AppState, changed: &BTreeSet<PathBuf>) -> Result<()> {
+    let history = state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?;
+    let external = changed
+        .iter()
+        .filter(|path| history.take_external_change(path))
+        .count();
+    if external == 0 {
+        return Ok(());
+    }
+    state.schedule_render(true);
+    Ok(())
+}
+
+/// Watches `config.txt` for edits made in a text editor and applies them like
+/// `POST /app/reload` without the history re-read, then tells open windows to
+/// reload. Saves made by the app itself leave the file as the store would
+/// write it and are skipped.
+async fn run_config_watcher(state: Arc<AppState>) {
+    let config_path = match state.config.lock() {
+        Ok(config) => config.path.clone(),
+        Err(_) => return,
+    };
+    let Some(file_name) = config_path.file_name().map(|name| name.to_os_string()) else {
+        return;
+    };
+    let config_dir = match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+    // Editors often save by writing a temp file and renaming it over the
+    // original, so the folder is watched rather than the file.
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        // Reading the file to compare it reports access events of its own.
+        if event.kind.is_access() {
+            return;
+        }
+        if event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(file_name.as_os_str()))
+        {
+            let _ = tx.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("config.txt の監視を開始できませんでした: {err}");
+            return;
+        }
+    };
+    if let Err(err) = watcher.watch(&config_dir, RecursiveMode::NonRecursive) {
+        eprintln!("config.txt の監視を開始できませんでした: {err}");
+        return;
+    }
+
+    while rx.recv().await.is_some() {
+        loop {
+            match tokio::time::timeout(HISTORY_WATCH_DEBOUNCE, rx.recv()).await {
+                Ok(Some(())) => {}
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        let task_state = state.clone();
+        let result = tokio::task::spawn_blocking(move || reload_external_config(&task_state)).await;
+        if let Ok(Err(err)) = result {
+            eprintln!("外部で変更された config.txt の反映に失敗しました: {err:#}");
+            state.publish(HistoryEvent::ConfigError {
+                error: format!("{err:#}"),
+            });
+        }
+    }
+}
+
+fn reload_external_config(state: &AppState) -> Result<()> {
+    {
+        let mut config = state
+            .config
+            .lock()
+            .map_err(|_| anyhow!("config lock error"))?;
+        if !config.changed_on_disk() {
+            return Ok(());
+        }
+        config.reload()?;
+        let mut history = state
+            .history
+            .lock()
+            .map_err(|_| anyhow!("history store lock error"))?;
+        apply_history_settings(&mut history, &config);
+    }
+    // Cached translations and scores belong to the old commands.
+    if let Ok(mut last) = state.last_translation.lock() {
+        *last = None;
+    }
+    if let Ok(mut last) = state.last_score.lock() {
+        *last = None;
+    }
+    state.schedule_render(true);
+    state.bump_config_revision();
+    Ok(())
+}
+
+/// Writes the history pages once at startup, then runs the `[app.startup]`
+/// actions that need them. It runs here rather than before the window opens, so
+/// a large history no longer delays the main UI; the UI follows it through
+/// `GET /app/ready`.
+async fn run_startup_tasks(state: Arc<AppState>) {
+    let task_state = state.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let port = task_state.server_port.load(Ordering::Relaxed);
+        let history_path = {
+            let history = task_state
+                .history
+                .lock()
+                .map_err(|_| anyhow!("history store lock error"))?;
+            history.regenerate_html(port)?;
+            history.history_html_path().to_path_buf()
+        };
+        let (actions, translate, vision, score) = {
+            let config = task_state
+                .config
+                .lock()
+                .map_err(|_| anyhow!("config lock error"))?;
+            (
+                config.startup_actions(),
+                config.translate_command(),
+                config.vision_command(),
+                config.score_command(),
+            )
+        };
+        let mut warnings = Vec::new();
+        if actions.check_integrations {
+            for check in check_integrations(&translate, &vision, &score) {
+                if check.status != CheckStatus::Ok {
+                    warnings.push(format!("{}: {}", check.name, check.detail));
+                }
+            }
+        }
+        if actions.open_history && history_path.exists() {
+            if let Err(err) = open_file_in_browser(&history_path) {
+                warnings.push(format!("履歴ページを開けませんでした: {err}"));
+            }
+        }
+        Ok::<_, anyhow::Error>(warnings)
+    })
+    .await;
+    let (error, warnings) = match result {
+        Ok(Ok(warnings)) => (None, warnings),
+        Ok(Err(err)) => (Some(format!("{err:#}")), Vec::new()),
+        Err(_) => (Some("startup task failed".to_string()), Vec::new()),
+    };
+    if let Some(error) = &error {
+        eprintln!("履歴ページの生成に失敗しました: {error}");
+    }
+    for warning in &warnings {
+        eprintln!("起動時の確認: {warning}");
+    }
+    if let Ok(mut slot) = state.startup_error.lock() {
+        *slot = error;
+    }
+    if let Ok(mut slot) = state.startup_warnings.lock() {
+        slot.extend(warnings);
+    }
+    state.pages_ready.store(true, Ordering::Relaxed);
+}
+
+/// Creates thumbnails for images uploaded before thumbnails existed. Runs once,
+/// shortly after startup, one image at a time with a pause in between so it
+/// stays in the background; pages keep showing the originals until it is done.
+async fn run_thumbnail_backfill(state: Arc<AppState>) {
+    tokio::time::sleep(BACKFILL_START_DELAY).await;
+    if let Ok(Err(err)) = tokio::task::spawn_blocking(move || backfill_thumbnails(&state)).await {
+        eprintln!("サムネイルの作成に失敗しました: {err}");
+    }
+}
+
+fn backfill_thumbnails(state: &AppState) -> Result<usize> {
+    let missing = state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?
+        .missing_thumbnails()?;
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    let mut created = 0;
+    for image_path in missing {
+        thread::sleep(BACKFILL_PAUSE);
+        let history = state
+            .history
+            .lock()
+            .map_err(|_| anyhow!("history store lock error"))?;
+        // Undecodable files are skipped; they keep showing the original.
+        if history.create_thumbnail(&image_path).is_ok() {
+            created += 1;
+        }
+    }
+
+    if created > 0 {
+        state.schedule_render(true);
+    }
+    Ok(created)
+}
+
+fn purge_trash(state: &AppState) -> Result<usize> {
+    let purged = state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?
+        .purge_trash(Local::now().naive_local())?;
+    if purged > 0 {
+        state.schedule_render(true);
+    }
+    Ok(purged)
+}
+
+fn enforce_retention(state: &AppState) -> Result<Option<RetentionExport>> {
+    let export = state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?
+        .enforce_retention(Local::now().date_naive())?;
+    if export.is_some() {
+        state.schedule_render(true);
+    }
+    Ok(export)
+}
+
+fn apply_housekeeping(state: &AppState) -> Result<Vec<PlannedAction>> {
+    let (rules, _) = state
+        .config
+        .lock()
+        .map_err(|_| anyhow!("config lock error"))?
+        .housekeeping_rules();
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let applied = state
+        .history
+        .lock()
+        .map_err(|_| anyhow!("history store lock error"))?
+        .apply_housekeeping(&rules, Local::now().naive_local())?;
+    if !applied.is_empty() {
+        state.schedule_render(true);
+    }
+    Ok(applied)
+}
+
+async fn get_housekeeping_preview(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    let (rules, errors) = match state.config.lock() {
+        Ok(config) => config.housekeeping_rules(),
+        Err(_) => {
+            return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error").into_response()
+        }
+    };
+
+    let planned = {
+        let history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+                .into_response()
+            }
+        };
+        history.plan_housekeeping(&rules, Local::now().naive_local())
+    };
+
+    match planned {
+        Ok(planned) => Html(build_preview_html(&rules, &planned, &errors)).into_response(),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("housekeeping preview failed: {err}"),
+        )
+        .into_response(),
+    }
+}
+
+async fn post_housekeeping_run(State(state): State<Arc<AppState>>) -> ApiResponse {
+    match apply_housekeeping(&state) {
+        Ok(applied) => ok_json(json!({ "applied": applied })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("housekeeping failed: {err}"),
+        ),
+    }
+}
+
+async fn get_history_audit(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<HistoryAuditReq>,
+) -> ApiResponse {
+    let limit = payload.limit.unwrap_or(200).max(1);
+    let history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    match history.audit_records(limit) {
+        Ok(records) => ok_json(json!({ "records": records })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("audit read failed: {err}"),
+        ),
+    }
+}
+
+const SIMILAR_RESULT_LIMIT: usize = 20;
+
+fn similar_images_response(
+    state: &AppState,
+    reference: Result<u64>,
+    exclude_path: Option<&str>,
+    max_distance: u32,
+) -> ApiResponse {
+    let history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+    let reference = match reference {
+        Ok(hash) => hash,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    match history.similar_images(
+        reference,
+        exclude_path,
+        max_distance.min(64),
+        SIMILAR_RESULT_LIMIT,
+    ) {
+        Ok(results) => ok_json(json!({ "results": results })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("similar image search failed: {err}"),
+        ),
+    }
+}
+
+async fn get_similar_images(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<HistorySimilarReq>,
+) -> ApiResponse {
+    let image_path = payload.path.trim().to_string();
+    if image_path.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "path is required");
+    }
+    let reference = match state.history.lock() {
+        Ok(history) => history.image_hash(&image_path),
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    similar_images_response(
+        &state,
+        reference,
+        Some(&image_path),
+        payload.max_distance.unwrap_or(DEFAULT_SIMILAR_DISTANCE),
+    )
+}
+
+async fn post_similar_images(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let mut file_data = Vec::new();
+    let mut max_distance = DEFAULT_SIMILAR_DISTANCE;
+
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                let field_name = field.name().unwrap_or_default().to_string();
+                if field_name == "max_distance" {
+                    match field.text().await.ok().and_then(|v| v.trim().parse().ok()) {
+                        Some(value) => max_distance = value,
+                        None => return err_json(StatusCode::BAD_REQUEST, "invalid max_distance"),
+                    }
+                } else if field_name == "file" {
+                    match field.bytes().await {
+                        Ok(bytes) => file_data = bytes.to_vec(),
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
+        }
+    }
+
+    if file_data.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "file is required");
+    }
+
+    similar_images_response(&state, perceptual_hash(&file_data), None, max_distance)
+}
+
+async fn post_history_vision(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let mut file_data = Vec::new();
+
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                if field.name() == Some("file") {
+                    match field.bytes().await {
+                        Ok(bytes) => file_data = bytes.to_vec(),
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
+        }
+    }
+
+    if file_data.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "file is required");
+    }
+
+    let command = match state.config.lock() {
+        Ok(config) => config.vision_command(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config store lock error"),
+    };
+    if command.is_empty() {
+        return err_json(
+            StatusCode::NOT_IMPLEMENTED,
+            "vision_command is not configured",
+        );
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        run_vision_command(&command, &file_data, VISION_TIMEOUT)
+    })
+    .await;
+    match result {
+        Ok(Ok(text)) => ok_json(json!({ "text": text })),
+        Ok(Err(err)) => err_json(StatusCode::BAD_GATEWAY, &err.to_string()),
+        Err(_) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "vision command task failed",
+        ),
+    }
+}
+
+async fn post_history_append(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryAppendReq>,
+) -> ApiResponse {
+    let mut history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    let entry = match history.append_history(&payload.prompt) {
+        Ok(entry) => entry,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    state.schedule_render(true);
+
+    ok_json(json!({ "entry": entry }))
+}
+
+async fn get_smart_filters(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    match history.smart_filters() {
+        Ok(filters) => ok_json(json!({ "filters": filters })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("smart filter read failed: {err}"),
+        ),
+    }
+}
+
+async fn post_smart_filter(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SmartFilter>,
+) -> ApiResponse {
+    let filter = match payload.normalized() {
+        Ok(filter) => filter,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    let filters = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let filters = match history.save_smart_filter(filter) {
+            Ok(filters) => filters,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("smart filter save failed: {err}"),
+                )
+            }
+        };
+        state.schedule_render(false);
+        filters
+    };
+
+    ok_json(json!({ "filters": filters }))
+}
+
+async fn delete_smart_filter(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SmartFilterDeleteReq>,
+) -> ApiResponse {
+    let filters = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let filters = match history.delete_smart_filter(&payload.name) {
+            Ok(Some(filters)) => filters,
+            Ok(None) => return err_json(StatusCode::NOT_FOUND, "smart filter not found"),
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("smart filter delete failed: {err}"),
+                )
+            }
+        };
+        state.schedule_render(false);
+        filters
+    };
+
+    ok_json(json!({ "filters": filters }))
+}
+
+async fn post_history_import(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let mut file_name = String::from("import.json");
+    let mut file_data = Vec::new();
+
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                if field.name() == Some("file") {
+                    file_name = field
+                        .file_name()
+                        .map(ToOwned::to_owned)
+                        .unwrap_or_else(|| "import.json".to_string());
+                    match field.bytes().await {
+                        Ok(bytes) => file_data = bytes.to_vec(),
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
+        }
+    }
+
+    if file_data.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "file is required");
+    }
+    let entries = match parse_import_file(&file_name, &file_data) {
+        Ok(entries) => entries,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &format!("import failed: {err}")),
+    };
+
+    let summary = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let summary = match history.import_entries(entries) {
+            Ok(summary) => summary,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("import failed: {err}"),
+                )
+            }
+        };
+        state.schedule_render(true);
+        summary
+    };
+
+    ok_json(json!({
+        "imported": summary.imported,
+        "duplicates": summary.duplicates,
+        "renamed": summary.renamed,
+    }))
+}
+
+async fn post_restore_backup(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let mut file_data = Vec::new();
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                if field.name() == Some("file") {
+                    match field.bytes().await {
+                        Ok(bytes) => file_data = bytes.to_vec(),
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
+        }
+    }
+    if file_data.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "file is required");
+    }
+
+    let task_state = Arc::clone(&state);
+    let result = tokio::task::spawn_blocking(move || {
+        task_state
+            .history
+            .lock()
+            .map_err(|_| anyhow!("history store lock error"))?
+            .restore_backup(&file_data)
+    })
+    .await;
+
+    let summary = match result {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(err)) => {
+            return err_json(StatusCode::BAD_REQUEST, &format!("restore failed: {err}"))
+        }
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "restore task failed"),
+    };
+    state.schedule_render(true);
+
+    ok_json(json!({
+        "restored": summary.restored,
+        "duplicates": summary.duplicates,
+        "images": summary.images,
+    }))
+}
+
+async fn post_delete_history_image(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryImageDeleteReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+    let image_path = payload.image_path.trim().to_string();
+    if image_path.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "image_path is required");
+    }
+
+    let mut history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    let file_deleted = match history.remove_image(&history_id, &image_path) {
+        Ok(ImageRemoval::Detached) => false,
+        Ok(ImageRemoval::FileDeleted) => true,
+        Ok(ImageRemoval::NotAttached) => return err_json(StatusCode::NOT_FOUND, "image not found"),
+        Err(err) => {
+            let message = err.to_string();
+            return err_json(
+                history_error_status(&message),
+                &format!("image delete failed: {message}"),
+            );
+        }
+    };
+
+    state.schedule_render(false);
+    let card = history.card(&history_id).ok().flatten();
+    state.publish(HistoryEvent::ImageDelete {
+        history_id,
+        image_path,
+    });
+
+    ok_json(json!({ "file_deleted": file_deleted, "card": card }))
+}
+
+async fn post_delete_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryDeleteReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    let removed = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        match history.delete_history(&history_id) {
+            Ok(removed) => {
+                if !removed {
+                    return err_json(StatusCode::NOT_FOUND, "history id not found");
+                }
+                state.schedule_render(false);
+                removed
+            }
+            Err(err) => {
+                return err_json(
+                    history_error_status(&err.to_string()),
+                    &format!("delete failed: {err}"),
+                )
+            }
+        }
+    };
+
+    if removed {
+        state.publish(HistoryEvent::Delete { history_id });
+        ok_json(json!({}))
+    } else {
+        err_json(StatusCode::NOT_FOUND, "history id not found")
+    }
+}
+
+async fn post_update_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryUpdateReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    let prompt = payload.prompt.trim().to_string();
+    if prompt.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "prompt is required");
+    }
+
+    let (updated, card) = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        match history.update_history_prompt(&history_id, &prompt) {
+            Ok(updated) => {
+                if !updated {
+                    return err_json(StatusCode::NOT_FOUND, "history id not found");
+                }
+            }
+            Err(err) => {
+                return err_json(
+                    history_error_status(&err.to_string()),
+                    &format!("update failed: {err}"),
+                )
+            }
+        }
+
+        state.schedule_render(false);
+
+        (prompt, history.card(&history_id).ok().flatten())
+    };
+
+    ok_json(json!({ "prompt": updated, "card": card }))
+}
+
+async fn post_update_history_note(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryNoteReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+    let note = payload.note.trim().to_string();
+
+    let card = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        match history.update_history_note(&history_id, &note) {
+            Ok(true) => {}
+            Ok(false) => return err_json(StatusCode::NOT_FOUND, "history id not found"),
+            Err(err) => {
+                return err_json(
+                    history_error_status(&err.to_string()),
+                    &format!("note update failed: {err}"),
+                )
+            }
+        }
+
+        state.schedule_render(false);
+        history.card(&history_id).ok().flatten()
+    };
+
+    ok_json(json!({ "note": note, "card": card }))
+}
+
+async fn post_upload_history(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> ApiResponse {
+    let mut history_id = String::new();
+    let mut files = Vec::new();
+
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                let field_name = field.name().unwrap_or_default().to_string();
+                if field_name == "history_id" {
+                    match field.text().await {
+                        Ok(value) => history_id = value.trim().to_string(),
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid history_id"),
+                    }
+                } else if matches!(field_name.as_str(), "file" | "files" | "files[]") {
+                    if files.len() == MAX_UPLOAD_FILES {
+                        return err_json(
+                            StatusCode::BAD_REQUEST,
+                            &format!("too many files (max {MAX_UPLOAD_FILES})"),
+                        );
+                    }
+                    let file_name = field
+                        .file_name()
+                        .map(ToOwned::to_owned)
+                        .unwrap_or_else(|| "upload.bin".to_string());
+                    match field.bytes().await {
+                        Ok(bytes) if !bytes.is_empty() => files.push((file_name, bytes.to_vec())),
+                        Ok(_) => {}
+                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
+        }
+    }
+
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    if files.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "file is required");
+    }
+
+    attach_images(&state, &history_id, &files)
+}
+
+/// Attaches raw image bytes from a paste (request body) or, when the body is
+/// empty, the image currently on the system clipboard.
+async fn post_upload_clipboard(
+    State(state): State<Arc<AppState>>,
+    Query(payload): Query<UploadClipboardReq>,
+    body: axum::body::Bytes,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    let file_data = if body.is_empty() {
+        match read_clipboard_image() {
+            Ok(bytes) => bytes,
+            Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+        }
+    } else {
+        body.to_vec()
+    };
+    let extension = match image::guess_format(&file_data) {
+        Ok(image::ImageFormat::Png) => "png",
+        Ok(image::ImageFormat::Jpeg) => "jpg",
+        Ok(image::ImageFormat::WebP) => "webp",
+        Ok(image::ImageFormat::Gif) => "gif",
+        _ => return err_json(StatusCode::BAD_REQUEST, "unsupported image data"),
+    };
+
+    attach_images(
+        &state,
+        &history_id,
+        &[(format!("clipboard.{extension}"), file_data)],
+    )
+}
+
+/// Attaches uploads to one entry in order. The response describes the last
+/// image in `image_path`/`metadata`, every image in `images`, and the
+/// re-rendered card.
+fn attach_images(state: &AppState, history_id: &str, files: &[(String, Vec<u8>)]) -> ApiResponse {
+    let (image_paths, card) = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let image_paths = match history.append_images(history_id, files) {
+            Ok(paths) => paths,
+            Err(err) => {
+                let message = err.to_string();
+                if message.contains("not found") {
+                    return err_json(StatusCode::NOT_FOUND, &message);
+                }
+                if message.contains("locked") {
+                    return err_json(StatusCode::LOCKED, &message);
+                }
+                return err_json(StatusCode::BAD_REQUEST, &message);
+            }
+        };
+
+        state.schedule_render(false);
+
+        let card = history.card(history_id).ok().flatten();
+        (image_paths, card)
+    };
+
+    let images: Vec<UploadedImage> = image_paths
+        .into_iter()
+        .zip(files)
+        .map(|(image_path, (_, file_data))| {
+            // Same metadata the entry stored, so the page can show it without a reload.
+            let metadata = image_path
+                .ends_with(".png")
+                .then(|| png_text::read_generation_metadata(file_data))
+                .flatten();
+            UploadedImage {
+                image_path,
+                metadata,
+            }
+        })
+        .collect();
+    state.publish(HistoryEvent::Upload {
+        history_id: history_id.to_string(),
+        images: images.clone(),
+    });
+    let last = images.last().map(|image| json!(image)).unwrap_or_default();
+    ok_json(json!({
+        "image_path": last["image_path"],
+        "metadata": last["metadata"],
+        "images": images,
+        "card": card,
+    }))
+}
+
+async fn post_delete_samples(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let removed = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let removed = match history.delete_samples() {
+            Ok(removed) => removed,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("delete samples failed: {err}"),
+                )
+            }
+        };
+
+        state.schedule_render(false);
+
+        removed
+    };
+
+    let preset_removed = match state.config.lock() {
+        Ok(mut config) => match config.delete_sample_preset() {
+            Ok(removed) => removed,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("delete sample preset failed: {err}"),
+                )
+            }
+        },
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    if preset_removed {
+        state.bump_state_revision(None);
+    }
+
+    ok_json(json!({ "removed": removed, "preset_removed": preset_removed }))
+}
+
+async fn post_archive_now(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let archived = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let archived = match history.archive_pending() {
+            Ok(archived) => archived,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("archive failed: {err}"),
+                )
+            }
+        };
+
+        state.schedule_render(true);
+
+        archived
+    };
+
+    ok_json(json!({ "archived": archived }))
+}
+
+async fn post_archive_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryMoveReq>,
+) -> ApiResponse {
+    move_history_entry(&state, &payload.history_id, true)
+}
+
+async fn post_unarchive_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryMoveReq>,
+) -> ApiResponse {
+    move_history_entry(&state, &payload.history_id, false)
+}
+
+fn move_history_entry(state: &AppState, history_id: &str, to_archive: bool) -> ApiResponse {
+    let history_id = history_id.trim();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+    let action = if to_archive { "archive" } else { "unarchive" };
+
+    {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let moved = if to_archive {
+            history.archive_entry(history_id)
+        } else {
+            history.unarchive_entry(history_id)
+        };
+        match moved {
+            Ok(true) => {}
+            Ok(false) => return err_json(StatusCode::NOT_FOUND, "history id not found"),
+            Err(err) => {
+                let message = err.to_string();
+                return err_json(
+                    history_error_status(&message),
+                    &format!("{action} failed: {message}"),
+                );
+            }
+        }
+
+        state.schedule_render(true);
+    }
+
+    ok_json(json!({}))
+}
+
+async fn post_restore_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryMoveReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        match history.restore_history(&history_id) {
+            Ok(true) => {}
+            Ok(false) => return err_json(StatusCode::NOT_FOUND, "history id not found in trash"),
+            Err(err) => {
+                let message = err.to_string();
+                return err_json(
+                    history_error_status(&message),
+                    &format!("restore failed: {message}"),
+                );
+            }
+        }
+
+        state.schedule_render(true);
+    }
+
+    ok_json(json!({}))
+}
+
+async fn post_undo_history(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let undone = {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+
+        let undone = match history.undo() {
+            Ok(Some(operation)) => operation,
+            Ok(None) => return err_json(StatusCode::CONFLICT, "nothing to undo"),
+            Err(err) => {
+                let message = err.to_string();
+                return err_json(
+                    history_error_status(&message),
+                    &format!("undo failed: {message}"),
+                );
+            }
+        };
+
+        state.schedule_render(true);
+        undone
+    };
+
+    ok_json(json!({ "undone": undone, "label": undone.label() }))
+}
+
+async fn post_rate_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryRateReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+    if payload.rating > 5 {
+        return err_json(StatusCode::BAD_REQUEST, "rating must be between 0 and 5");
+    }
+
+    let mut history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    match history.set_rating(&history_id, payload.rating) {
+        Ok(true) => {}
+        Ok(false) => return err_json(StatusCode::NOT_FOUND, "history id not found"),
+        Err(err) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("rate failed: {err}"),
+            )
+        }
+    }
+
+    state.schedule_render(false);
+
+    ok_json(json!({
+        "rating": payload.rating,
+        "card": history.card(&history_id).ok().flatten(),
+    }))
+}
+
+async fn post_lock_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<HistoryLockReq>,
+) -> ApiResponse {
+    let history_id = payload.history_id.trim().to_string();
+    if history_id.is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
+    }
+
+    let mut history = match state.history.lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+
+    match history.set_locked(&history_id, payload.locked) {
+        Ok(true) => {}
+        Ok(false) => return err_json(StatusCode::NOT_FOUND, "history id not found"),
+        Err(err) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("lock failed: {err}"),
+            )
+        }
+    }
+
+    state.schedule_render(false);
+
+    ok_json(json!({
+        "locked": payload.locked,
+        "card": history.card(&history_id).ok().flatten(),
+    }))
+}
+
+async fn get_app_init(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let snapshot = {
+        let config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn get_app_history_revision(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let revision = state.history_revision.load(Ordering::Relaxed);
+    ok_json(json!({ "revision": revision }))
+}
+
+/// The current revision first, then every `HistoryEvent` until the server stops.
+async fn get_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+    let receiver = state.events.subscribe();
+    let current = HistoryEvent::Revision {
+        revision: state.history_revision.load(Ordering::Relaxed),
+    };
+    let updates = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(Some(event)) => return Some((event, receiver)),
+                // A page that fell behind still reloads on the next revision.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Ok(None) | Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::once(async move { current })
+        .chain(updates)
+        .map(|event| Event::default().retry(HISTORY_EVENT_RETRY).json_data(event));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// A request sent over `/ws`, answered like the same HTTP request.
+#[derive(Deserialize)]
+struct WsRequest {
+    id: u64,
+    #[serde(default = "default_ws_method")]
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Value,
+    #[serde(default)]
+    api_version: Option<u32>,
+}
+
+fn default_ws_method() -> String {
+    "POST".to_string()
+}
+
+/// One connection per window: pushes the same events as `GET /events` and
+/// takes API requests, so every open window stays in step without reloading.
+/// WebSockets skip CORS, so the origin is checked here.
+async fn get_ws(
+    State(state): State<Arc<AppState>>,
+    client: Option<Extension<ConnectInfo<ClientAddr>>>,
+    paired: Option<Extension<LanPaired>>,
+    headers: axum::http::HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let port = state.server_port.load(Ordering::Relaxed);
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        if !allowed_origins(port).contains(origin)
+            && !is_lan_origin(&state, paired.is_some(), origin)
+        {
+            return err_json(StatusCode::FORBIDDEN, "origin not allowed").into_response();
+        }
+    }
+    let client = client.map(|Extension(client)| client);
+    ws.on_upgrade(move |socket| run_ws_session(state, socket, client))
+}
+
+/// Pages served by the LAN server connect from their own `https://` origin,
+/// whatever address the other machine used to reach it. Only a paired request
+/// may use one; the trust comes from the token, not from the Host header.
+fn is_lan_origin(state: &AppState, paired: bool, origin: &HeaderValue) -> bool {
+    let lan_port = state.lan_port.load(Ordering::Relaxed);
+    if lan_port == 0 || !paired {
+        return false;
+    }
+    origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.strip_prefix("https://"))
+        .is_some_and(|host| host.ends_with(&format!(":{lan_port}")))
+}
+
+async fn run_ws_session(
+    state: Arc<AppState>,
+    socket: WebSocket,
+    client: Option<ConnectInfo<ClientAddr>>,
+) {
+    let client_id = state.ws_client_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let router = build_router(state.clone());
+    let (mut sink, mut incoming) = socket.split();
+
+    // Subscribed before the hello, so no change between the two is missed.
+    let receiver = state.events.subscribe();
+    let hello = json!({
+        "type": "hello",
+        "client_id": client_id,
+        "revision": state.history_revision.load(Ordering::Relaxed),
+        "state_revision": state.state_revision.load(Ordering::Relaxed),
+        "api_version": API_VERSION,
+    });
+    if sink.send(ws_json(&hello)).await.is_err() {
+        return;
+    }
+    // Pushes and replies queue up for one writer, so a slow request never
+    // holds up the events or the answers to requests sent after it.
+    let (outgoing, mut queue) = tokio::sync::mpsc::unbounded_channel::<Message>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = queue.recv().await {
+            if sink.send(message).await.is_err() {
+                return;
+            }
+        }
+    });
+    let pushes = tokio::spawn(push_ws_events(receiver, outgoing.clone()));
+
+    while let Some(Ok(message)) = incoming.next().await {
+        match message {
+            Message::Text(text) => {
+                let router = router.clone();
+                let outgoing = outgoing.clone();
+                tokio::spawn(async move {
+                    let reply = dispatch_ws_request(&router, client_id, client, &text).await;
+                    let _ = outgoing.send(ws_json(&reply));
+                });
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+        if outgoing.is_closed() {
+            break;
+        }
+    }
+    pushes.abort();
+    // Requests still running drop their replies once the writer is gone.
+    writer.abort();
+}
+
+async fn push_ws_events(
+    mut receiver: broadcast::Receiver<Option<HistoryEvent>>,
+    outgoing: tokio::sync::mpsc::UnboundedSender<Message>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(Some(event)) => {
+                if outgoing.send(ws_json(&json!(event))).is_err() {
+                    return;
+                }
+            }
+            // A window that fell behind still catches up on the next revision.
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Ok(None) | Err(broadcast::error::RecvError::Closed) => {
+                let _ = outgoing.send(Message::Close(None));
+                return;
+            }
+        }
+    }
+}
+
+fn ws_json(value: &Value) -> Message {
+    Message::Text(value.to_string().into())
+}
+
+/// Runs a `/ws` request through the router and wraps the JSON answer as
+/// `{"type": "response", "id", "status", "body"}`. The connection's address
+/// goes along, so forwarded requests share the client's rate limit.
+async fn dispatch_ws_request(
+    router: &Router,
+    client_id: u64,
+    client: Option<ConnectInfo<ClientAddr>>,
+    text: &str,
+) -> Value {
+    let request: WsRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(err) => {
+            return json!({
+                "type": "response",
+                "id": null,
+                "status": StatusCode::BAD_REQUEST.as_u16(),
+                "body": { "ok": false, "error": format!("invalid request: {err}") },
+            })
+        }
+    };
+    let mut builder = Request::builder()
+        .method(request.method.as_str())
+        .uri(&request.path)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(CLIENT_ID_HEADER, client_id);
+    if let Some(version) = request.api_version {
+        builder = builder.header(API_VERSION_HEADER, version);
+    }
+    if let Some(client) = client {
+        builder = builder.extension(client);
+    }
+    let (status, body) = match builder.body(Body::from(request.body.to_string())) {
+        Ok(http_request) => {
+            let response = match router.clone().oneshot(http_request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                .unwrap_or(Value::Null);
+            (status, body)
+        }
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            json!({ "ok": false, "error": format!("invalid request: {err}") }),
+        ),
+    };
+    json!({
+        "type": "response",
+        "id": request.id,
+        "status": status.as_u16(),
+        "body": body,
+    })
+}
+
+/// `POST /api/v1/prompt/render`: the prompt for the current item state, or for
+/// the given `selections`, in any configured output format. Nothing is saved.
+async fn post_api_render_prompt(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RenderRequest>,
+) -> ApiResponse {
+    let config = match state.config.lock() {
+        Ok(guard) => guard,
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let output_format = payload
+        .output_format
+        .unwrap_or_else(|| config.output_format());
+    if !config
+        .output_formats()
+        .iter()
+        .any(|format| format == &output_format)
+    {
+        return err_json(
+            StatusCode::BAD_REQUEST,
+            &format!("unknown output_format: {output_format}"),
+        );
+    }
+
+    let sections = config.sections();
+    let layout = PromptLayout::new(&config, &output_format);
+    let ctx = RenderContext::new(config.template_variables());
+    let (_, prompt) = match payload.selections {
+        Some(selections) => layout.render(&sections, &ctx, |item| {
+            selection_render_entry(item, &selections)
+        }),
+        None => layout.render(&sections, &ctx, |item| current_render_entry(&config, item)),
+    };
+    ok_json(json!(RenderedPrompt {
+        prompt,
+        output_format,
+    }))
+}
+
+/// `GET /api/v1/config/items`: the items of every section with their current state.
+async fn get_api_config_items(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let config = match state.config.lock() {
+        Ok(guard) => guard,
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let items = config
+        .all_items()
+        .into_iter()
+        .map(|item| {
+            let (selected, free_text) = current_item_state(&config, &item);
+            ConfigItem::new(item, selected, free_text)
+        })
+        .collect();
+    ok_json(json!(ConfigItems {
+        output_format: config.output_format(),
+        output_formats: config.output_formats(),
+        items,
+    }))
+}
+
+async fn get_app_state_revision(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let revision = state.state_revision.load(Ordering::Relaxed);
+    ok_json(json!({ "revision": revision }))
+}
+
+async fn get_app_ready(
+    State(state): State<Arc<AppState>>,
+    paired: Option<Extension<LanPaired>>,
+) -> ApiResponse {
+    let (Ok(error), Ok(warnings)) = (state.startup_error.lock(), state.startup_warnings.lock())
+    else {
+        return err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "startup state lock error",
+        );
+    };
+    ok_json(json!({
+        "ready": state.pages_ready.load(Ordering::Relaxed),
+        "error": *error,
+        "warnings": *warnings,
+        // Only for this PC's own window; paired devices already hold the token.
+        "lan_pairing_url": state
+            .lan_token
+            .get()
+            .filter(|_| paired.is_none())
+            .and_then(|token| pairing_url(state.lan_port.load(Ordering::Relaxed), token)),
+    }))
+}
+
+async fn post_app_combo_change(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ComboChangeReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let Some(item) = find_item(&config, &section, &key) else {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        };
+
+        let selected = payload.selected.trim();
+        let selected_value = if selected.is_empty() || !item.choices.iter().any(|c| c == selected) {
+            NO_SELECTION
+        } else {
+            selected
+        };
+
+        if let Err(err) = config.set_item_state(&section, &key, selected_value, "") {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_free_confirm(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<FreeConfirmReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let Some(item) = find_item(&config, &section, &key) else {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        };
+
+        let incoming = payload.value.trim().to_string();
+        if incoming.is_empty() || incoming == NO_SELECTION {
+            let selected = payload.selected.trim();
+            let selected_value =
+                if selected.is_empty() || !item.choices.iter().any(|c| c == selected) {
+                    NO_SELECTION
+                } else {
+                    selected
+                };
+            if let Err(err) = config.set_item_state(&section, &key, selected_value, "") {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+        } else {
+            if let Err(err) = config.remember_free_text(&section, &key, &incoming) {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+            let result = if payload.once {
+                config.set_item_state(&section, &key, &payload.selected, &incoming)
+            } else {
+                config
+                    .add_choice(&section, &key, &incoming)
+                    .and_then(|_| config.set_item_state(&section, &key, &incoming, &incoming))
+            };
+            if let Err(err) = result {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_free_draft(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<FreeDraftReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let mut config = match state.config.lock() {
+        Ok(guard) => guard,
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+
+    if find_item(&config, &section, &key).is_none() {
+        return err_json(StatusCode::NOT_FOUND, "item not found");
+    }
+
+    if let Err(err) = config.set_item_draft(&section, &key, &payload.value) {
+        return err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("save error: {err}"),
+        );
+    }
+
+    ok_json(json!({}))
+}
+
+async fn post_app_delete_choice(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DeleteChoiceReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let selected = payload.selected.trim();
+        if !selected.is_empty() && selected != NO_SELECTION {
+            match config.remove_choice(&section, &key, selected) {
+                Ok(removed) if removed => {
+                    let (_, free_text) = config.get_item_state(&section, &key);
+                    let next_free_text = if free_text == selected {
+                        String::new()
+                    } else {
+                        free_text
+                    };
+                    if let Err(err) =
+                        config.set_item_state(&section, &key, NO_SELECTION, &next_free_text)
+                    {
+                        return err_json(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &format!("save error: {err}"),
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    return err_json(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("delete error: {err}"),
+                    );
+                }
+            }
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_rename_choice(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RenameChoiceReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let Some(item) = find_item(&config, &section, &key) else {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        };
+        let value = payload.value.trim();
+        if value == NO_SELECTION || !item.choices.iter().any(|c| c == value) {
+            return err_json(StatusCode::BAD_REQUEST, "choice not found");
+        }
+        let new_value = payload.new_value.trim();
+        if new_value.is_empty() || new_value == NO_SELECTION {
+            return err_json(StatusCode::BAD_REQUEST, "new choice name is empty");
+        }
+        if new_value != value && item.choices.iter().any(|c| c == new_value) {
+            return err_json(StatusCode::CONFLICT, "choice already exists");
+        }
+
+        if let Err(err) = config.rename_choice(&section, &key, value, new_value) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_move_choice(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<MoveChoiceReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let Some(item) = find_item(&config, &section, &key) else {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        };
+        let value = payload.value.trim();
+        if value == NO_SELECTION || !item.choices.iter().any(|c| c == value) {
+            return err_json(StatusCode::BAD_REQUEST, "choice not found");
+        }
+
+        if let Err(err) = config.move_choice(&section, &key, value, payload.index) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_reset(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        if let Err(err) = config.clear_selections() {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_randomize(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RandomizeReq>,
+) -> ApiResponse {
+    let weighted = match state.config.lock() {
+        Ok(config) => config.random_weighted(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    // Reads every archive, so it runs before the config lock is taken.
+    let weights = if weighted {
+        let state = state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let history = state
+                .history
+                .lock()
+                .map_err(|_| anyhow!("history store lock error"))?;
+            history.rating_weights()
+        })
+        .await;
+        match result {
+            Ok(Ok(weights)) => weights,
+            Ok(Err(err)) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("history read error: {err}"),
+                )
+            }
+            Err(err) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        }
+    } else {
+        RatingWeights::default()
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let items = match &payload.item_id {
+            Some(item_id) => {
+                let (section, key) = match split_item_id(item_id) {
+                    Ok(pair) => pair,
+                    Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+                };
+                let Some(item) = find_item(&config, &section, &key) else {
+                    return err_json(StatusCode::NOT_FOUND, "item not found");
+                };
+                vec![item]
+            }
+            None => config
+                .all_items()
+                .into_iter()
+                .filter(|item| !config.get_item_locked(&item.section_name, &item.key))
+                .collect(),
+        };
+
+        let mut dice = Dice::from_clock();
+        for item in &items {
+            let candidates = random_candidates(item);
+            let picked = if weighted {
+                dice.pick_weighted(&candidates, |value| weights.weight(&item.key, value))
+            } else {
+                dice.pick(&candidates)
+            };
+            let Some(value) = picked.map(|v| v.to_string()) else {
+                continue;
+            };
+            if let Err(err) = config.set_item_state(&item.section_name, &item.key, &value, "") {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_random_pool(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RandomPoolReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let Some(item) = find_item(&config, &section, &key) else {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        };
+        let value = payload.value.trim();
+        if value == NO_SELECTION || !item.choices.iter().any(|c| c == value) {
+            return err_json(StatusCode::BAD_REQUEST, "choice not found");
+        }
+
+        if let Err(err) = config.set_random_pool_member(&section, &key, value, payload.enabled) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_item_weight(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ItemWeightReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        if find_item(&config, &section, &key).is_none() {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        }
+        if let Err(err) = config.set_item_weight(&section, &key, payload.weight) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_item_lock(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ItemLockReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        if find_item(&config, &section, &key).is_none() {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        }
+        if let Err(err) = config.set_item_locked(&section, &key, payload.locked) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_item_enabled(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ItemEnabledReq>,
+) -> ApiResponse {
+    let (section, key) = match split_item_id(&payload.item_id) {
+        Ok(pair) => pair,
+        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    };
+
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let hidden = config
+            .disabled_items(&section)
+            .iter()
+            .any(|item| item.key == key);
+        if !hidden && find_item(&config, &section, &key).is_none() {
+            return err_json(StatusCode::NOT_FOUND, "item not found");
+        }
+        if let Err(err) = config.set_item_enabled(&section, &key, payload.enabled) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_reorder_items(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ReorderItemsReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let section = payload.section.trim();
+        if !config.sections().iter().any(|known| known.name == section) {
+            return err_json(StatusCode::NOT_FOUND, "section not found");
+        }
+        if let Err(err) = config.reorder_items(section, &payload.keys) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_preset_save(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PresetReq>,
+) -> ApiResponse {
+    if payload.name.trim().is_empty() {
+        return err_json(StatusCode::BAD_REQUEST, "preset name is empty");
+    }
+    update_presets(&state, None, |config| config.save_preset(&payload.name))
+}
+
+async fn post_app_preset_apply(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PresetReq>,
+) -> ApiResponse {
+    update_presets(&state, Some(&payload.name), |config| {
+        config.apply_preset(&payload.name)
+    })
+}
+
+async fn post_app_preset_delete(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PresetReq>,
+) -> ApiResponse {
+    update_presets(&state, Some(&payload.name), |config| {
+        config.delete_preset(&payload.name)
+    })
+}
+
+/// Runs a preset change and answers with the new snapshot; `existing`
+/// names a preset the change needs, answered with a 404 when it is unknown.
+fn update_presets(
+    state: &AppState,
+    existing: Option<&str>,
+    change: impl FnOnce(&mut ConfigStore) -> Result<()>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        if let Some(name) = existing.map(str::trim) {
+            if !config.preset_names().iter().any(|known| known == name) {
+                return err_json(StatusCode::NOT_FOUND, &format!("preset not found: {name}"));
+            }
+        }
+        if let Err(err) = change(&mut config) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_group_collapse(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<GroupCollapseReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let section = payload.section.trim();
+        let group = payload.group.trim();
+        let Some(known) = config
+            .sections()
+            .into_iter()
+            .find(|known| known.name == section)
+        else {
+            return err_json(StatusCode::NOT_FOUND, "section not found");
+        };
+        if group.is_empty() || !known.items.iter().any(|item| item.group == group) {
+            return err_json(StatusCode::NOT_FOUND, "group not found");
+        }
+        if let Err(err) = config.set_group_collapsed(section, group, payload.collapsed) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+/// `GET /app/settings`: the `[app]` options the settings dialog edits.
+async fn get_app_settings(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let config = match state.config.lock() {
+        Ok(guard) => guard,
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    ok_json(json!({
+        "prompt_prefix": config.prompt_prefix(),
+        "prompt_suffix": config.prompt_suffix(),
+        "delimiter": config.delimiter(),
+        "copy_debounce_sec": config.copy_debounce_sec(),
+        "history_max_entries": config.history_max_entries(),
+        "confirm_delete": config.confirm_delete(),
+        "history_server_port": config.history_server_port(),
+        "running_port": state.server_port.load(Ordering::Relaxed),
+    }))
+}
+
+/// Saves the settings dialog. A new `history_max_entries` applies to the
+/// history store right away; a new port only after a restart, which the
+/// response reports as `restart_required`.
+async fn post_app_settings(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SettingsReq>,
+) -> ApiResponse {
+    let (snapshot, restart_required) = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        let configured_port = config.history_server_port();
+        let update = AppSettingsUpdate {
+            delimiter: payload.delimiter,
+            copy_debounce_sec: payload.copy_debounce_sec,
+            history_max_entries: payload.history_max_entries,
+            confirm_delete: payload.confirm_delete,
+            history_server_port: payload.history_server_port,
+        };
+        if let Err(err) = config.update_app_settings(&update) {
+            let status = if err.to_string().starts_with("invalid setting") {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            return err_json(status, &format!("save error: {err}"));
+        }
+        if update.history_max_entries.is_some() {
+            match state.history.lock() {
+                Ok(mut history) => apply_history_settings(&mut history, &config),
+                Err(_) => {
+                    return err_json(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "history store lock error",
+                    )
+                }
+            }
+        }
+
+        let prefix = payload
+            .prompt_prefix
+            .unwrap_or_else(|| config.prompt_prefix());
+        let suffix = payload
+            .prompt_suffix
+            .unwrap_or_else(|| config.prompt_suffix());
+        if let Err(err) = config.set_prompt_affixes(&prefix, &suffix) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+
+        let restart_required = config.history_server_port() != configured_port;
+        (build_ui_snapshot(&config), restart_required)
+    };
+
+    let (status, Json(mut body)) = ok_snapshot(snapshot);
+    body["restart_required"] = json!(restart_required);
+    (status, Json(body))
+}
+
+async fn post_app_output_format(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<OutputFormatReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        if let Err(err) = config.set_output_format(&payload.format) {
+            return err_json(StatusCode::BAD_REQUEST, &err.to_string());
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_random_weighted(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RandomWeightedReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        if let Err(err) = config.set_random_weighted(payload.enabled) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_sort_by_usage(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<SortByUsageReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        if let Err(err) = config.set_sort_choices_by_usage(payload.enabled) {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_temporary_mode(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<TemporaryModeReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        config.set_temporary_mode(payload.enabled);
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_temporary_save(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        if let Err(err) = config.save_temporary_state() {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("save error: {err}"),
+            );
+        }
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_parse_prompt(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ParsePromptReq>,
+) -> ApiResponse {
+    let items = match state.config.lock() {
+        Ok(config) => config.all_items(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let parsed = parse_prompt(&payload.prompt, &items);
+    ok_json(json!({ "matches": parsed.matches, "leftovers": parsed.leftovers }))
+}
+
+/// Applies the rows confirmed on the review screen: a value that is one of the
+/// item's choices is selected, anything else is confirmed as free text.
+async fn post_app_apply_parsed(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApplyParsedReq>,
+) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+
+        // Check every row first so a bad row does not leave the form half applied.
+        let mut rows = Vec::new();
+        for row in &payload.items {
+            let (section, key) = match split_item_id(&row.item_id) {
+                Ok(pair) => pair,
+                Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+            };
+            let Some(item) = find_item(&config, &section, &key) else {
+                return err_json(StatusCode::NOT_FOUND, "item not found");
+            };
+            let value = row.value.trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+            let is_choice = item.choices.contains(&value);
+            if !is_choice && !item.allow_free_text {
+                return err_json(
+                    StatusCode::BAD_REQUEST,
+                    &format!("{} does not allow free text", row.item_id),
+                );
+            }
+            rows.push((section, key, value, is_choice, row.add_choice));
+        }
+
+        // Unmatched text is free text, as when a history entry is restored;
+        // it only becomes a choice when the row asks for it.
+        for (section, key, value, is_choice, add_choice) in rows {
+            let saved = if is_choice {
+                config.set_item_state(&section, &key, &value, "")
+            } else if add_choice {
+                config
+                    .add_choice(&section, &key, &value)
+                    .and_then(|_| config.set_item_state(&section, &key, &value, &value))
+            } else {
+                config.set_item_state(&section, &key, NO_SELECTION, &value)
+            };
+            if let Err(err) = saved {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+        }
+
+        build_ui_snapshot(&config)
+    };
+
+    ok_snapshot(snapshot)
+}
+
+async fn post_app_apply_history(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ApplyHistoryReq>,
+) -> ApiResponse {
+    let entry = {
+        let history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+        match history.find_entry(&payload.history_id) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return err_json(StatusCode::NOT_FOUND, "history id not found"),
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("history read error: {err}"),
+                )
+            }
+        }
+    };
+
+    let (snapshot, skipped) = {
+        let mut config = match state.config.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        let items = config.all_items();
+        let values = entry_values(&entry.prompt, &entry.selections, &items);
+
+        let mut skipped = Vec::new();
+        for (item, value) in items.iter().zip(values) {
+            let (selected, free_text) = if item.choices.contains(&value) {
+                (value.as_str(), "")
+            } else if item.allow_free_text {
+                (NO_SELECTION, value.as_str())
+            } else {
+                skipped.push(item.label.clone());
+                continue;
+            };
+            if let Err(err) =
+                config.set_item_state(&item.section_name, &item.key, selected, free_text)
+            {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                );
+            }
+        }
+        (build_ui_snapshot(&config), skipped)
+    };
+
+    let (status, Json(mut body)) = ok_snapshot(snapshot);
+    body["skipped"] = json!(skipped);
+    (status, Json(body))
+}
+
+const SUGGEST_LIMIT: usize = 50;
+
+async fn get_app_suggest(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SuggestReq>,
+) -> ApiResponse {
+    let items = match state.config.lock() {
+        Ok(config) => config.all_items(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let item_id = query.item_id.as_deref().map(str::trim).unwrap_or_default();
+    let limit = query.limit.unwrap_or(SUGGEST_LIMIT).min(SUGGEST_LIMIT);
+
+    let suggestions: Vec<Suggestion> = items
+        .into_iter()
+        .filter(|item| item_id.is_empty() || item.item_id() == item_id)
+        .flat_map(|item| {
+            let id = item.item_id();
+            let label = item.label;
+            item.choices
+                .into_iter()
+                .filter(|choice| choice != NO_SELECTION && matches_loosely(choice, &query.q))
+                .map(move |choice| Suggestion {
+                    item_id: id.clone(),
+                    label: label.clone(),
+                    choice,
+                })
+        })
+        .take(limit)
+        .collect();
+
+    ok_json(json!({ "suggestions": suggestions }))
+}
+
+async fn post_app_translate(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<TranslateReq>,
+) -> ApiResponse {
+    let text = payload.text.trim().to_string();
+    if text.is_empty() {
+        return ok_json(json!({ "text": "" }));
+    }
+
+    let command = match state.config.lock() {
+        Ok(config) => config.translate_command(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    if command.is_empty() {
+        return err_json(
+            StatusCode::NOT_IMPLEMENTED,
+            "translate_command is not configured",
+        );
+    }
+    if let Ok(cache) = state.last_translation.lock() {
+        if let Some((source, translated)) = cache.as_ref() {
+            if *source == text {
+                return ok_json(json!({ "text": translated }));
+            }
+        }
+    }
+
+    let source = text.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        run_translate_command(&command, &source, TRANSLATE_TIMEOUT)
+    })
+    .await;
+    match result {
+        Ok(Ok(translated)) => {
+            if let Ok(mut cache) = state.last_translation.lock() {
+                *cache = Some((text, translated.clone()));
+            }
+            ok_json(json!({ "text": translated }))
+        }
+        Ok(Err(err)) => err_json(StatusCode::BAD_GATEWAY, &err.to_string()),
+        Err(_) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "translate command task failed",
+        ),
+    }
+}
+
+async fn post_app_score(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ScoreReq>,
+) -> ApiResponse {
+    let text = payload.text.trim().to_string();
+    if text.is_empty() {
+        return ok_json(json!({ "score": null }));
+    }
+
+    let command = match state.config.lock() {
+        Ok(config) => config.score_command(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    if command.is_empty() {
+        return err_json(
+            StatusCode::NOT_IMPLEMENTED,
+            "score_command is not configured",
+        );
+    }
+    if let Ok(cache) = state.last_score.lock() {
+        if let Some((source, score)) = cache.as_ref() {
+            if *source == text {
+                return ok_json(json!({ "score": score }));
+            }
+        }
+    }
+
+    let source = text.clone();
+    let result =
+        tokio::task::spawn_blocking(move || run_score_command(&command, &source, SCORE_TIMEOUT))
+            .await;
+    match result {
+        Ok(Ok(score)) => {
+            if let Ok(mut cache) = state.last_score.lock() {
+                *cache = Some((text, score));
+            }
+            ok_json(json!({ "score": score }))
         }
-    };
-
-    if removed {
-        ok_json(json!({}))
-    } else {
-        err_json(StatusCode::NOT_FOUND, "history id not found")
+        Ok(Err(err)) => err_json(StatusCode::BAD_GATEWAY, &err.to_string()),
+        Err(_) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "score command task failed",
+        ),
     }
 }
 
-async fn post_update_history(
+async fn post_app_copy(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<HistoryUpdateReq>,
+    Json(payload): Json<CopyReq>,
 ) -> ApiResponse {
-    let history_id = payload.history_id.trim().to_string();
-    if history_id.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
-    }
-
     let prompt = payload.prompt.trim().to_string();
-    if prompt.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "prompt is required");
+    let translation = payload.translation.trim().to_string();
+    let clipboard_text = if payload.copy_translation {
+        translation.clone()
+    } else {
+        prompt.clone()
+    };
+    if prompt.is_empty() || clipboard_text.is_empty() {
+        return ok_json(json!({ "skipped": true }));
     }
 
-    let port = state.server_port.load(Ordering::Relaxed);
-    let updated = {
-        let mut history = match state.history.lock() {
+    let (debounce, selections) = {
+        let config = match state.config.lock() {
             Ok(guard) => guard,
-            Err(_) => {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "history store lock error",
-                )
-            }
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
+        (config.copy_debounce_sec(), config.all_selections())
+    };
 
-        match history.update_history_prompt(&history_id, &prompt) {
-            Ok(updated) => {
-                if !updated {
-                    return err_json(StatusCode::NOT_FOUND, "history id not found");
+    let rotation = {
+        let mut copy_state = match state.copy_state.lock() {
+            Ok(guard) => guard,
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "copy state lock error"),
+        };
+
+        if copy_state.last_prompt == clipboard_text {
+            if let Some(last_copy) = copy_state.last_copy_time {
+                if last_copy.elapsed().as_secs_f64() <= debounce {
+                    return ok_json(json!({ "skipped": true }));
                 }
             }
-            Err(err) => {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("update failed: {err}"),
-                )
-            }
         }
 
-        if let Err(err) = history.regenerate_html(port) {
+        if let Err(err) = copy_to_system_clipboard(&clipboard_text) {
             return err_json(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                &format!("update failed: {err}"),
+                &format!("clipboard error: {err}"),
             );
         }
 
-        prompt
-    };
-
-    ok_json(json!({ "prompt": updated }))
-}
-
-async fn post_upload_history(
-    State(state): State<Arc<AppState>>,
-    mut multipart: Multipart,
-) -> ApiResponse {
-    let mut history_id = String::new();
-    let mut file_name = String::from("upload.bin");
-    let mut file_data = Vec::new();
+        let rotation = {
+            let mut history = match state.history.lock() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    return err_json(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "history store lock error",
+                    )
+                }
+            };
 
-    loop {
-        match multipart.next_field().await {
-            Ok(Some(field)) => {
-                let field_name = field.name().unwrap_or_default().to_string();
-                if field_name == "history_id" {
-                    match field.text().await {
-                        Ok(value) => history_id = value.trim().to_string(),
-                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid history_id"),
-                    }
-                } else if field_name == "file" {
-                    file_name = field
-                        .file_name()
-                        .map(ToOwned::to_owned)
-                        .unwrap_or_else(|| "upload.bin".to_string());
-                    match field.bytes().await {
-                        Ok(bytes) => file_data = bytes.to_vec(),
-                        Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid file"),
-                    }
+            let score = state
+                .last_score
+                .lock()
+                .ok()
+                .and_then(|cache| cache.clone())
+                .filter(|(source, _)| *source == prompt)
+                .map(|(_, score)| score);
+            let saved = history.append_history(&prompt).and_then(|entry| {
+                history.set_selections(&entry.id, selections)?;
+                if !translation.is_empty() {
+                    history.set_translation(&entry.id, &translation)?;
+                }
+                if let Some(score) = score {
+                    history.set_score(&entry.id, score)?;
                 }
+                Ok(())
+            });
+            if let Err(err) = saved {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("history save error: {err}"),
+                );
             }
-            Ok(None) => break,
-            Err(_) => return err_json(StatusCode::BAD_REQUEST, "invalid multipart request"),
-        }
-    }
-
-    if history_id.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "history_id is required");
-    }
+            state.schedule_render(true);
+            history.rotation_status().ok()
+        };
 
-    if file_data.is_empty() {
-        return err_json(StatusCode::BAD_REQUEST, "file is required");
+        copy_state.last_prompt = clipboard_text;
+        copy_state.last_copy_time = Some(Instant::now());
+        rotation
+    };
+    // The counts only order the dropdowns; failing to save them (e.g. a
+    // read-only config) does not undo the copy.
+    if let Ok(mut config) = state.config.lock() {
+        config.record_choice_usage().ok();
     }
 
-    if file_data.len() > HistoryStore::MAX_IMAGE_BYTES {
-        return err_json(StatusCode::BAD_REQUEST, "file size exceeds 20MB");
-    }
+    ok_json(json!({ "skipped": false, "rotation": rotation }))
+}
 
-    let port = state.server_port.load(Ordering::Relaxed);
-    let image_path = {
-        let mut history = match state.history.lock() {
+async fn post_app_open_history(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let path = {
+        let history = match state.history.lock() {
             Ok(guard) => guard,
             Err(_) => {
                 return err_json(
@@ -425,313 +4674,384 @@ async fn post_upload_history(
                 )
             }
         };
+        history.history_html_path().to_path_buf()
+    };
 
-        let image_path = match history.append_image(&history_id, &file_name, &file_data) {
-            Ok(path) => path,
-            Err(err) => {
-                let message = err.to_string();
-                if message.contains("not found") {
-                    return err_json(StatusCode::NOT_FOUND, &message);
-                }
-                return err_json(StatusCode::BAD_REQUEST, &message);
-            }
-        };
+    if !path.exists() {
+        return err_json(
+            StatusCode::NOT_FOUND,
+            &format!("History.html not found: {}", path.display()),
+        );
+    }
+
+    if let Err(err) = open_file_in_browser(&path) {
+        return err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("open history failed: {err}"),
+        );
+    }
+
+    ok_json(json!({}))
+}
 
-        if let Err(err) = history.regenerate_html(port) {
+async fn post_app_open_stats(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let choices: Vec<String> = match state.config.lock() {
+        Ok(config) if !config.features().analytics => {
+            return err_json(StatusCode::FORBIDDEN, "analytics is disabled in [features]")
+        }
+        Ok(config) => config
+            .all_items()
+            .into_iter()
+            .flat_map(|item| item.choices)
+            .collect(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let written = match state.history.lock() {
+        Ok(history) => history.write_stats_html(&choices),
+        Err(_) => {
             return err_json(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                &format!("upload failed: {err}"),
-            );
+                "history store lock error",
+            )
+        }
+    };
+    let path = match written {
+        Ok(path) => path,
+        Err(err) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("stats render error: {err}"),
+            )
         }
-
-        image_path
     };
 
-    ok_json(json!({ "image_path": image_path }))
+    if let Err(err) = open_file_in_browser(&path) {
+        return err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("open stats failed: {err}"),
+        );
+    }
+
+    ok_json(json!({}))
 }
 
-async fn get_app_init(State(state): State<Arc<AppState>>) -> ApiResponse {
-    let snapshot = {
+/// `[features]`, so the pages hide the controls of disabled subsystems.
+async fn get_app_features(State(state): State<Arc<AppState>>) -> ApiResponse {
+    match state.config.lock() {
+        Ok(config) => ok_json(json!({ "features": config.features() })),
+        Err(_) => err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    }
+}
+
+async fn get_app_config_validate(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let issues = {
         let config = match state.config.lock() {
             Ok(guard) => guard,
             Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
-        build_ui_snapshot(&config)
+        config.lint()
     };
 
-    ok_snapshot(snapshot)
-}
-
-async fn get_app_history_revision(State(state): State<Arc<AppState>>) -> ApiResponse {
-    let revision = state.history_revision.load(Ordering::Relaxed);
-    ok_json(json!({ "revision": revision }))
+    ok_json(json!({ "issues": issues }))
 }
 
-async fn post_app_combo_change(
+/// Downloads the `[[sections]]` (items and choices, no state) for sharing.
+async fn get_app_config_export(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<ComboChangeReq>,
-) -> ApiResponse {
-    let (section, key) = match split_item_id(&payload.item_id) {
-        Ok(pair) => pair,
-        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    Query(payload): Query<ConfigExportReq>,
+) -> axum::response::Response {
+    let format = match ExchangeFormat::parse(&payload.format) {
+        Ok(format) => format,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()).into_response(),
     };
-
-    let snapshot = {
-        let mut config = match state.config.lock() {
-            Ok(guard) => guard,
-            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
-        };
-
-        let Some(item) = find_item(&config, &section, &key) else {
-            return err_json(StatusCode::NOT_FOUND, "item not found");
-        };
-
-        let selected = payload.selected.trim();
-        let selected_value = if selected.is_empty() || !item.choices.iter().any(|c| c == selected) {
-            NO_SELECTION
-        } else {
-            selected
-        };
-
-        if let Err(err) = config.set_item_state(&section, &key, selected_value, "") {
-            return err_json(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                &format!("save error: {err}"),
-            );
+    let sections = match state.config.lock() {
+        Ok(config) => config.export_sections(),
+        Err(_) => {
+            return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error").into_response()
         }
-
-        build_ui_snapshot(&config)
     };
 
-    ok_snapshot(snapshot)
+    match write_sections(&sections, format) {
+        Ok(body) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"config_export.{}\"",
+                        format.extension()
+                    ),
+                ),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("export failed: {err}"),
+        )
+        .into_response(),
+    }
 }
 
-async fn post_app_free_confirm(
+/// Merges the sections of an exported config: adds missing sections, items
+/// and choices and never changes or deletes what is already there.
+async fn post_app_config_import(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<FreeConfirmReq>,
+    Json(payload): Json<ConfigImportReq>,
 ) -> ApiResponse {
-    let (section, key) = match split_item_id(&payload.item_id) {
-        Ok(pair) => pair,
-        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
+    let format = if payload.format.trim().is_empty() {
+        ExchangeFormat::detect_file(&payload.name, &payload.text)
+    } else {
+        match ExchangeFormat::parse(&payload.format) {
+            Ok(format) => format,
+            Err(err) => return err_json(StatusCode::BAD_REQUEST, &err.to_string()),
+        }
+    };
+    let incoming = match parse_sections(&payload.text, format) {
+        Ok(sections) => sections,
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &format!("import error: {err:#}")),
     };
 
-    let snapshot = {
+    let (summary, snapshot) = {
         let mut config = match state.config.lock() {
             Ok(guard) => guard,
             Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
-
-        let Some(item) = find_item(&config, &section, &key) else {
-            return err_json(StatusCode::NOT_FOUND, "item not found");
-        };
-
-        let incoming = payload.value.trim().to_string();
-        if incoming.is_empty() || incoming == NO_SELECTION {
-            let selected = payload.selected.trim();
-            let selected_value =
-                if selected.is_empty() || !item.choices.iter().any(|c| c == selected) {
-                    NO_SELECTION
-                } else {
-                    selected
-                };
-            if let Err(err) = config.set_item_state(&section, &key, selected_value, "") {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("save error: {err}"),
-                );
-            }
-        } else {
-            if let Err(err) = config.add_choice(&section, &key, &incoming) {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("save error: {err}"),
-                );
-            }
-            if let Err(err) = config.set_item_state(&section, &key, &incoming, &incoming) {
+        let summary = match config.import_sections(&incoming) {
+            Ok(summary) => summary,
+            Err(err) => {
                 return err_json(
                     StatusCode::INTERNAL_SERVER_ERROR,
                     &format!("save error: {err}"),
-                );
+                )
             }
-        }
-
-        build_ui_snapshot(&config)
+        };
+        (summary, build_ui_snapshot(&config))
     };
 
-    ok_snapshot(snapshot)
+    let (status, Json(mut body)) = ok_snapshot(snapshot);
+    body["imported"] = json!(summary);
+    (status, Json(body))
+}
+
+/// The `config_backups` written before destructive changes, newest first.
+async fn get_app_config_backups(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let config_path = match state.config.lock() {
+        Ok(config) => config.path.clone(),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    match list_backups(&config_path) {
+        Ok(backups) => ok_json(json!({ "backups": backups })),
+        Err(err) => err_json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("backup list error: {err:#}"),
+        ),
+    }
 }
 
-async fn post_app_delete_choice(
+/// Rolls `config.txt` back to a backup and applies it like `POST /app/reload`.
+async fn post_app_config_restore(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<DeleteChoiceReq>,
+    Json(payload): Json<ConfigRestoreReq>,
 ) -> ApiResponse {
-    let (section, key) = match split_item_id(&payload.item_id) {
-        Ok(pair) => pair,
-        Err(message) => return err_json(StatusCode::BAD_REQUEST, &message),
-    };
-
     let snapshot = {
         let mut config = match state.config.lock() {
             Ok(guard) => guard,
             Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
-
-        let selected = payload.selected.trim();
-        if !selected.is_empty() && selected != NO_SELECTION {
-            match config.remove_choice(&section, &key, selected) {
-                Ok(removed) if removed => {
-                    let (_, free_text) = config.get_item_state(&section, &key);
-                    let next_free_text = if free_text == selected {
-                        String::new()
-                    } else {
-                        free_text
-                    };
-                    if let Err(err) =
-                        config.set_item_state(&section, &key, NO_SELECTION, &next_free_text)
-                    {
-                        return err_json(
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            &format!("save error: {err}"),
-                        );
-                    }
-                }
-                Ok(_) => {}
-                Err(err) => {
-                    return err_json(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        &format!("delete error: {err}"),
-                    );
-                }
-            }
+        if let Err(err) = config.restore_backup(&payload.file) {
+            let message = err.to_string();
+            let status = if message.starts_with("backup not found") {
+                StatusCode::NOT_FOUND
+            } else if message.starts_with("invalid backup name")
+                || message.starts_with("backup is not a valid config")
+            {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            return err_json(status, &format!("restore error: {err:#}"));
         }
-
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "history store lock error",
+                )
+            }
+        };
+        apply_history_settings(&mut history, &config);
         build_ui_snapshot(&config)
     };
-
+    // Cached translations and scores belong to the old commands.
+    if let Ok(mut last) = state.last_translation.lock() {
+        *last = None;
+    }
+    if let Ok(mut last) = state.last_score.lock() {
+        *last = None;
+    }
+    state.schedule_render(true);
     ok_snapshot(snapshot)
 }
 
-async fn post_app_reset(State(state): State<Arc<AppState>>) -> ApiResponse {
-    let snapshot = {
-        let mut config = match state.config.lock() {
-            Ok(guard) => guard,
-            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
-        };
-
-        if let Err(err) = config.clear_section_state("prompt") {
-            return err_json(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                &format!("save error: {err}"),
-            );
-        }
-
-        build_ui_snapshot(&config)
+/// Lists the keyword packs in the `packs` folder next to `config.txt`, each
+/// with what installing it would add now.
+async fn get_app_packs(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let (dir, sections) = match state.config.lock() {
+        Ok(config) => (packs_dir(&config.path), config.export_sections()),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
     };
+    let (packs, errors) = list_packs(&dir, &sections);
 
-    ok_snapshot(snapshot)
+    ok_json(json!({
+        "dir": dir.to_string_lossy(),
+        "packs": packs,
+        "errors": errors,
+    }))
 }
 
-async fn post_app_copy(
+/// Installs a keyword pack with the add-only merge of the config import.
+async fn post_app_packs_install(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CopyReq>,
+    Json(payload): Json<PackInstallReq>,
 ) -> ApiResponse {
-    let prompt = payload.prompt.trim().to_string();
-    if prompt.is_empty() {
-        return ok_json(json!({ "skipped": true }));
-    }
+    let dir = match state.config.lock() {
+        Ok(config) => packs_dir(&config.path),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    let pack = if !payload.file.trim().is_empty() {
+        read_pack(&dir, &payload.file)
+    } else if !payload.text.trim().is_empty() {
+        let format = ExchangeFormat::detect_file(&payload.name, &payload.text);
+        parse_pack(&payload.text, format)
+    } else {
+        return err_json(StatusCode::BAD_REQUEST, "file or text is required");
+    };
+    let pack = match pack {
+        Ok(pack) => pack,
+        Err(err) if err.to_string().starts_with("pack not found") => {
+            return err_json(StatusCode::NOT_FOUND, &err.to_string())
+        }
+        Err(err) => return err_json(StatusCode::BAD_REQUEST, &format!("pack error: {err:#}")),
+    };
 
-    let debounce = {
-        let config = match state.config.lock() {
+    let (summary, snapshot) = {
+        let mut config = match state.config.lock() {
             Ok(guard) => guard,
             Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
-        config.copy_debounce_sec()
+        let summary = match config.import_sections(&pack.sections) {
+            Ok(summary) => summary,
+            Err(err) => {
+                return err_json(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("save error: {err}"),
+                )
+            }
+        };
+        (summary, build_ui_snapshot(&config))
     };
 
-    {
-        let mut copy_state = match state.copy_state.lock() {
+    let (status, Json(mut body)) = ok_snapshot(snapshot);
+    body["imported"] = json!(summary);
+    body["pack"] = json!(pack.info);
+    (status, Json(body))
+}
+
+/// Re-reads `config.txt` and `history.json` after they were edited in a text
+/// editor, re-applies the history settings and rebuilds the pages and the UI
+/// snapshot. A config that fails to parse is reported and the running one kept.
+async fn post_app_reload(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let snapshot = {
+        let mut config = match state.config.lock() {
             Ok(guard) => guard,
-            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "copy state lock error"),
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
         };
-
-        if copy_state.last_prompt == prompt {
-            if let Some(last_copy) = copy_state.last_copy_time {
-                if last_copy.elapsed().as_secs_f64() <= debounce {
-                    return ok_json(json!({ "skipped": true }));
-                }
-            }
-        }
-
-        if let Err(err) = copy_to_system_clipboard(&prompt) {
-            return err_json(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                &format!("clipboard error: {err}"),
-            );
+        if let Err(err) = config.reload() {
+            return err_json(StatusCode::BAD_REQUEST, &format!("config error: {err:#}"));
         }
-
-        let port = state.server_port.load(Ordering::Relaxed);
-        {
-            let mut history = match state.history.lock() {
-                Ok(guard) => guard,
-                Err(_) => {
-                    return err_json(
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "history store lock error",
-                    )
-                }
-            };
-
-            if let Err(err) = history.append_history(&prompt) {
-                return err_json(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("history save error: {err}"),
-                );
-            }
-            if let Err(err) = history.regenerate_html(port) {
+        let mut history = match state.history.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
                 return err_json(
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    &format!("history render error: {err}"),
-                );
+                    "history store lock error",
+                )
             }
+        };
+        apply_history_settings(&mut history, &config);
+        if let Err(err) = history.reload() {
+            return err_json(StatusCode::BAD_REQUEST, &format!("history error: {err:#}"));
         }
-
-        copy_state.last_prompt = prompt;
-        copy_state.last_copy_time = Some(Instant::now());
-        state.history_revision.fetch_add(1, Ordering::Relaxed);
+        build_ui_snapshot(&config)
+    };
+    // Cached translations and scores belong to the old commands.
+    if let Ok(mut last) = state.last_translation.lock() {
+        *last = None;
     }
-
-    ok_json(json!({ "skipped": false }))
+    if let Ok(mut last) = state.last_score.lock() {
+        *last = None;
+    }
+    state.schedule_render(true);
+    ok_snapshot(snapshot)
 }
 
-async fn post_app_open_history(State(state): State<Arc<AppState>>) -> ApiResponse {
-    let path = {
-        let history = match state.history.lock() {
+async fn post_app_config_cleanup(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let (removed, issues) = {
+        let mut config = match state.config.lock() {
             Ok(guard) => guard,
-            Err(_) => {
+            Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+        };
+        let removed = match config.cleanup_orphaned_state() {
+            Ok(removed) => removed,
+            Err(err) => {
                 return err_json(
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    "history store lock error",
+                    &format!("save error: {err}"),
                 )
             }
         };
-        history.history_html_path().to_path_buf()
+        (removed, config.lint())
     };
 
-    if !path.exists() {
-        return err_json(
-            StatusCode::NOT_FOUND,
-            &format!("History.html not found: {}", path.display()),
-        );
-    }
+    ok_json(json!({ "removed": removed, "issues": issues }))
+}
 
-    if let Err(err) = open_file_in_browser(&path) {
-        return err_json(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            &format!("open history failed: {err}"),
-        );
+/// Self-test for bug reports: the checks plus a copy-pastable text report.
+async fn get_app_diagnostics(State(state): State<Arc<AppState>>) -> ApiResponse {
+    let port = state.server_port.load(Ordering::Relaxed);
+    let data_dir = match state.history.lock() {
+        Ok(history) => history.base_dir().to_path_buf(),
+        Err(_) => {
+            return err_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "history store lock error",
+            )
+        }
+    };
+    let (translate, vision, score) = match state.config.lock() {
+        Ok(config) => (
+            config.translate_command(),
+            config.vision_command(),
+            config.score_command(),
+        ),
+        Err(_) => return err_json(StatusCode::INTERNAL_SERVER_ERROR, "config lock error"),
+    };
+    // The port check connects back to this server, so keep it off the runtime.
+    let result = tokio::task::spawn_blocking(move || {
+        let mut checks = run_diagnostics(&data_dir, port);
+        checks.extend(check_integrations(&translate, &vision, &score));
+        let report = format_report(&data_dir, port, &checks);
+        (checks, report)
+    })
+    .await;
+    match result {
+        Ok((checks, report)) => ok_json(json!({ "checks": checks, "report": report })),
+        Err(_) => err_json(StatusCode::INTERNAL_SERVER_ERROR, "diagnostics task failed"),
     }
-
-    ok_json(json!({}))
 }
 
 fn ok_json(payload: Value) -> ApiResponse {
@@ -755,8 +5075,22 @@ fn ok_snapshot(snapshot: UiSnapshot) -> ApiResponse {
         Json(json!({
             "ok": true,
             "rows": snapshot.rows,
+            "sections": snapshot.sections,
+            "hidden_items": snapshot.hidden_items,
+            "presets": snapshot.presets,
             "preview": snapshot.preview,
             "confirm_delete": snapshot.confirm_delete,
+            "output_format": snapshot.output_format,
+            "output_formats": snapshot.output_formats,
+            "temporary_mode": snapshot.temporary_mode,
+            "unsaved_state": snapshot.unsaved_state,
+            "translation_enabled": snapshot.translation_enabled,
+            "score_enabled": snapshot.score_enabled,
+            "read_only": snapshot.read_only,
+            "random_weighted": snapshot.random_weighted,
+            "sort_by_usage": snapshot.sort_by_usage,
+            "emphasis_enabled": snapshot.emphasis_enabled,
+            "token_count": snapshot.token_count,
         })),
     )
 }
@@ -771,36 +5105,219 @@ fn err_json(status: StatusCode, message: &str) -> ApiResponse {
     )
 }
 
-fn build_ui_snapshot(config: &ConfigStore) -> UiSnapshot {
+fn history_error_status(message: &str) -> StatusCode {
+    if message.contains("locked") || message.contains(READ_ONLY_MESSAGE) {
+        StatusCode::LOCKED
+    } else if message.contains("not found") {
+        StatusCode::NOT_FOUND
+    } else if message.contains("already active")
+        || message.contains("already exists")
+        || message.contains("is full")
+    {
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Rows, preview and flags the main UI renders from the current config.
+pub fn build_ui_snapshot(config: &ConfigStore) -> UiSnapshot {
     let mut rows = Vec::new();
-    let mut render_entries = Vec::new();
+    let output_format = config.output_format();
+    let layout = PromptLayout::new(config, &output_format);
+    let ctx = RenderContext::new(config.template_variables());
+    let section_configs = config.sections();
 
-    for item in config.get_items("prompt") {
-        let (mut selected, free_text) = config.get_item_state(&item.section_name, &item.key);
-        if !item.choices.iter().any(|choice| choice == &selected) {
-            selected = NO_SELECTION.to_string();
+    for section in &section_configs {
+        for item in &section.items {
+            let (selected, free_text) = current_item_state(config, item);
+            rows.push(UiRow {
+                item_id: item.item_id(),
+                section: section.name.clone(),
+                label: item.label.clone(),
+                choices: item.choices.clone(),
+                allow_free_text: item.allow_free_text,
+                selected,
+                free_text,
+                draft: config.get_item_draft(&item.section_name, &item.key),
+                excluded: !item.participates_in(&output_format),
+                random_pool: item.random_pool.clone(),
+                weight: config.get_item_weight(&item.section_name, &item.key),
+                locked: config.get_item_locked(&item.section_name, &item.key),
+                free_text_history: config.free_text_history(&item.section_name, &item.key),
+                usage: config.choice_usage(&item.section_name, &item.key),
+                group: item.group.clone(),
+            });
         }
-
-        render_entries.push(RenderEntry {
-            label: item.label.clone(),
-            selected: selected.clone(),
-            free_text: free_text.clone(),
-        });
-
-        rows.push(UiRow {
+    }
+    let hidden_items = section_configs
+        .iter()
+        .flat_map(|section| config.disabled_items(&section.name))
+        .map(|item| UiHiddenItem {
             item_id: item.item_id(),
+            section: item.section_name,
             label: item.label,
-            choices: item.choices,
-            allow_free_text: item.allow_free_text,
-            selected,
-            free_text,
-        });
-    }
+        })
+        .collect();
+    let (bodies, preview) = layout.render(&section_configs, &ctx, |item| {
+        current_render_entry(config, item)
+    });
+    let sections = section_configs
+        .into_iter()
+        .zip(bodies)
+        .map(|(section, body)| UiSection {
+            groups: section_groups(config, &section),
+            name: section.name,
+            label: section.label,
+            preview: body,
+        })
+        .collect();
 
     UiSnapshot {
         rows,
-        preview: render_prompt(&render_entries),
+        sections,
+        hidden_items,
+        presets: config.preset_names(),
+        token_count: estimate_clip_tokens(&preview),
+        preview,
         confirm_delete: config.confirm_delete(),
+        output_formats: config.output_formats(),
+        output_format,
+        temporary_mode: config.temporary_mode(),
+        unsaved_state: config.has_unsaved_state(),
+        translation_enabled: !config.translate_command().is_empty(),
+        score_enabled: !config.score_command().is_empty(),
+        read_only: config.is_read_only(),
+        random_weighted: config.random_weighted(),
+        sort_by_usage: config.sort_choices_by_usage(),
+        emphasis_enabled: layout.profile.emphasizes(),
+    }
+}
+
+fn section_groups(config: &ConfigStore, section: &SectionConfig) -> Vec<UiGroup> {
+    let collapsed = config.collapsed_groups(&section.name);
+    let mut groups: Vec<UiGroup> = Vec::new();
+    for item in section.items.iter().filter(|item| !item.group.is_empty()) {
+        match groups.iter_mut().find(|group| group.name == item.group) {
+            Some(group) => group.items += 1,
+            None => groups.push(UiGroup {
+                name: item.group.clone(),
+                items: 1,
+                collapsed: collapsed.contains(&item.group),
+            }),
+        }
+    }
+    groups
+}
+
+/// The saved selection and free text of `item`; a selection no longer among
+/// the choices reads as `NO_SELECTION`.
+fn current_item_state(config: &ConfigStore, item: &ItemConfig) -> (String, String) {
+    let (mut selected, free_text) = config.get_item_state(&item.section_name, &item.key);
+    if !item.choices.iter().any(|choice| choice == &selected) {
+        selected = NO_SELECTION.to_string();
+    }
+    (selected, free_text)
+}
+
+/// The current state of `item`, to render.
+fn current_render_entry(config: &ConfigStore, item: &ItemConfig) -> RenderEntry {
+    let (selected, free_text) = current_item_state(config, item);
+    RenderEntry {
+        key: item.key.clone(),
+        label: item.label.clone(),
+        selected,
+        free_text,
+        template: item.template.clone(),
+        param: item.param.clone(),
+        weight: config.get_item_weight(&item.section_name, &item.key),
+    }
+}
+
+/// `item`'s value among stored `selections`, to render.
+fn selection_render_entry(item: &ItemConfig, selections: &BTreeMap<String, String>) -> RenderEntry {
+    RenderEntry {
+        key: item.key.clone(),
+        label: item.label.clone(),
+        selected: selections
+            .get(&item.key)
+            .cloned()
+            .unwrap_or_else(|| NO_SELECTION.to_string()),
+        free_text: String::new(),
+        template: item.template.clone(),
+        param: item.param.clone(),
+        weight: 1.0,
+    }
+}
+
+/// How the sections become the final prompt: the output format's profile and
+/// the items that take part in it, `[app] prompt_template`, and the prefix and
+/// suffix around it.
+struct PromptLayout {
+    output_format: String,
+    profile: FormatProfile,
+    delimiter: String,
+    prompt_template: String,
+    prefix: String,
+    suffix: String,
+}
+
+impl PromptLayout {
+    fn new(config: &ConfigStore, output_format: &str) -> Self {
+        Self {
+            output_format: output_format.to_string(),
+            profile: FormatProfile::from_name(output_format),
+            delimiter: config.delimiter(),
+            prompt_template: config.prompt_template(),
+            prefix: config.prompt_prefix(),
+            suffix: config.prompt_suffix(),
+        }
+    }
+
+    /// Each section's body and the combined prompt. `entry` gives an item's
+    /// values; items outside the output format render as unselected.
+    fn render(
+        &self,
+        sections: &[SectionConfig],
+        ctx: &RenderContext,
+        entry: impl Fn(&ItemConfig) -> RenderEntry,
+    ) -> (Vec<String>, String) {
+        let mut all_entries = Vec::new();
+        let mut outputs = Vec::new();
+        for section in sections {
+            let entries: Vec<RenderEntry> = section
+                .items
+                .iter()
+                .map(|item| {
+                    let mut entry = entry(item);
+                    if !item.participates_in(&self.output_format) {
+                        entry.selected = NO_SELECTION.to_string();
+                        entry.free_text.clear();
+                    }
+                    entry
+                })
+                .collect();
+            outputs.push(SectionOutput {
+                template: section.template.clone(),
+                body: render_formatted(&entries, self.profile, &self.delimiter, ctx),
+            });
+            all_entries.extend(entries);
+        }
+        let prompt = if self.prompt_template.is_empty() {
+            combine_sections(&outputs, ctx)
+        } else {
+            render_prompt_template(&self.prompt_template, &all_entries, self.profile, ctx)
+        };
+        let prompt = wrap_prompt(
+            &prompt,
+            &self.prefix,
+            &self.suffix,
+            self.profile,
+            &self.delimiter,
+            ctx,
+        );
+        let bodies = outputs.into_iter().map(|output| output.body).collect();
+        (bodies, prompt)
     }
 }
 
@@ -825,7 +5342,15 @@ fn find_item(config: &ConfigStore, section: &str, key: &str) -> Option<ItemConfi
         .find(|item| item.key == key)
 }
 
-fn bind_listener(preferred_port: u16) -> Result<TcpListener> {
+/// Binds `preferred_port` or, unless `strict`, one of the 199 ports after it.
+fn bind_listener(preferred_port: u16, strict: bool) -> Result<TcpListener> {
+    if strict {
+        return TcpListener::bind(("127.0.0.1", preferred_port)).with_context(|| {
+            format!(
+                "port {preferred_port} is in use by another program; close it or change history_server_port (strict_port = true)"
+            )
+        });
+    }
     for offset in 0..200u16 {
         let port = preferred_port.saturating_add(offset);
         if port == 0 {
@@ -840,6 +5365,18 @@ fn bind_listener(preferred_port: u16) -> Result<TcpListener> {
     Err(anyhow!("failed to bind server port"))
 }
 
+/// Unlike the loopback port, the LAN port is fixed: other machines bookmark it.
+fn bind_lan_listener(lan: &LanConfig) -> Result<(TcpListener, Arc<rustls::ServerConfig>, String)> {
+    let tls = load_or_create_server_config(&lan.cert_dir)?;
+    let token = load_or_create_pairing_token(&lan.cert_dir)?;
+    let listener = TcpListener::bind(("0.0.0.0", lan.port))
+        .with_context(|| format!("failed to bind LAN port {}", lan.port))?;
+    listener
+        .set_nonblocking(true)
+        .context("failed to set listener non-blocking")?;
+    Ok((listener, tls, token))
+}
+
 #[cfg(target_os = "windows")]
 fn copy_to_system_clipboard(text: &str) -> Result<()> {
     clipboard_win::set_clipboard_string(text)
@@ -851,6 +5388,25 @@ fn copy_to_system_clipboard(_text: &str) -> Result<()> {
     Ok(())
 }
 
+/// Reads the clipboard bitmap (e.g. a fresh screenshot) and re-encodes it as PNG.
+#[cfg(target_os = "windows")]
+fn read_clipboard_image() -> Result<Vec<u8>> {
+    let bitmap = clipboard_win::get_clipboard(clipboard_win::formats::Bitmap)
+        .map_err(|err| anyhow!("clipboard has no image: {err}"))?;
+    let image = image::load_from_memory_with_format(&bitmap, image::ImageFormat::Bmp)
+        .context("failed to decode clipboard image")?;
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageFormat::Png)
+        .context("failed to encode clipboard image")?;
+    Ok(png.into_inner())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_clipboard_image() -> Result<Vec<u8>> {
+    Err(anyhow!("clipboard image is only available on Windows"))
+}
+
 #[cfg(target_os = "windows")]
 fn to_wide_null(value: &std::ffi::OsStr) -> Vec<u16> {
     use std::os::windows::ffi::OsStrExt;
@@ -890,3 +5446,216 @@ fn open_file_in_browser(path: &Path) -> Result<()> {
 fn open_file_in_browser(_path: &Path) -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{build_lan_router, build_router, is_lan_origin, AppState};
+    use crate::config_store::ConfigStore;
+    use crate::history_store::HistoryStore;
+    use axum::body::Body;
+    use axum::http::{header, HeaderValue, Request, StatusCode};
+    use axum::Router;
+    use std::fs;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    /// Status, `Content-Type` and `Content-Encoding` of `GET uri` sent with
+    /// `Accept-Encoding: encoding` and any extra `headers`.
+    async fn fetch(
+        router: &Router,
+        uri: &str,
+        encoding: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> (StatusCode, String, Option<String>) {
+        let mut request = Request::get(uri).header(header::ACCEPT_ENCODING, encoding);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let response = router
+            .clone()
+            .oneshot(request.body(Body::empty()).expect("request"))
+            .await
+            .expect("response");
+        let text = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        (
+            response.status(),
+            text(header::CONTENT_TYPE).unwrap_or_default(),
+            text(header::CONTENT_ENCODING),
+        )
+    }
+
+    #[test]
+    fn compresses_pages_and_json_but_not_ranges_zips_or_event_streams() {
+        let base = std::env::temp_dir().join(format!("ipg_server_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        fs::write(
+            base.join("config.txt"),
+            "[[sections]]\nname = \"prompt\"\n[[sections.items]]\nkey = \"subject\"\nchoices = [\"cat\"]\n",
+        )
+        .expect("config write");
+        let config = ConfigStore::new(base.join("config.txt")).expect("config");
+        let mut history = HistoryStore::new(base.join("history"), 10).expect("history");
+        let entry = history.append_history("compressed prompt").expect("append");
+        let mut gif = b"GIF89a".to_vec();
+        gif.resize(4096, 0);
+        let image = history
+            .append_image(&entry.id, "image.gif", &gif)
+            .expect("upload");
+        let router = build_router(Arc::new(AppState::new(config, history)));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        runtime.block_on(async {
+            let (status, content_type, encoding) = fetch(&router, "/", "gzip", &[]).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(content_type.starts_with("text/html"));
+            assert_eq!(encoding.as_deref(), Some("gzip"));
+            let (_, content_type, encoding) = fetch(&router, "/history/list", "br", &[]).await;
+            assert!(content_type.starts_with("application/json"));
+            assert_eq!(encoding.as_deref(), Some("br"));
+            let (_, _, encoding) = fetch(&router, "/", "identity", &[]).await;
+            assert_eq!(encoding, None, "only compressed when asked for");
+
+            let image_uri = format!("/image?path={image}");
+            let range = [(header::RANGE, "bytes=0-99")];
+            let (status, _, encoding) = fetch(&router, &image_uri, "gzip, br", &range).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(encoding, None, "ranges are served as stored");
+            let (status, content_type, encoding) =
+                fetch(&router, "/history/backup", "gzip, br", &[]).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(content_type, "application/zip");
+            assert_eq!(encoding, None);
+            let (_, content_type, encoding) = fetch(&router, "/events", "gzip, br", &[]).await;
+            assert!(content_type.starts_with("text/event-stream"));
+            assert_eq!(encoding, None, "events must not be buffered");
+        });
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn applied_parse_rows_add_choices_only_when_asked() {
+        let base = std::env::temp_dir().join(format!("ipg_server_parsed_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        fs::write(
+            base.join("config.txt"),
+            "[[sections]]\nname = \"prompt\"\n[[sections.items]]\nkey = \"subject\"\nallow_free_text = true\nchoices = [\"cat\"]\n[[sections.items]]\nkey = \"style\"\nallow_free_text = true\nchoices = [\"flat\"]\n",
+        )
+        .expect("config write");
+        let config = ConfigStore::new(base.join("config.txt")).expect("config");
+        let history = HistoryStore::new(base.join("history"), 10).expect("history");
+        let state = Arc::new(AppState::new(config, history));
+        let router = build_router(state.clone());
+
+        let body = r#"{"items": [
+            {"item_id": "prompt:subject", "value": "fox"},
+            {"item_id": "prompt:style", "value": "ink", "add_choice": true}
+        ]}"#;
+        let request = Request::post("/app/apply-parsed")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("request");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        let response = runtime.block_on(router.oneshot(request)).expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let config = state.config.lock().expect("config");
+        let choices = |key: &str| {
+            config
+                .all_items()
+                .into_iter()
+                .find(|item| item.key == key)
+                .expect("item")
+                .choices
+        };
+        assert!(!choices("subject").contains(&"fox".to_string()));
+        assert_eq!(
+            config.get_item_state("prompt", "subject"),
+            (crate::NO_SELECTION.to_string(), "fox".to_string())
+        );
+        assert!(choices("style").contains(&"ink".to_string()));
+        assert_eq!(config.get_item_state("prompt", "style").0, "ink");
+        drop(config);
+
+        fs::remove_dir_all(base).ok();
+    }
+
+    #[test]
+    fn lan_router_requires_the_pairing_token() {
+        let base = std::env::temp_dir().join(format!("ipg_server_lan_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).expect("fixture dir");
+        fs::write(base.join("config.txt"), "").expect("config write");
+        let config = ConfigStore::new(base.join("config.txt")).expect("config");
+        let history = HistoryStore::new(base.join("history"), 10).expect("history");
+        let state = Arc::new(AppState::new(config, history));
+        state.lan_port.store(3443, Ordering::Relaxed);
+        state.lan_token.set("secret".to_string()).expect("token");
+        let router = build_lan_router(state.clone());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        runtime.block_on(async {
+            let (status, _, _) = fetch(&router, "/app/ready", "identity", &[]).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let (status, _, _) = fetch(&router, "/?pair=wrong", "identity", &[]).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            let forged_host = [(header::HOST, "192.168.0.2:3443")];
+            let (status, _, _) = fetch(&router, "/app/ready", "identity", &forged_host).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let response = router
+                .clone()
+                .oneshot(
+                    Request::get("/?pair=secret")
+                        .body(Body::empty())
+                        .expect("request"),
+                )
+                .await
+                .expect("response");
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+            assert_eq!(response.headers()[header::LOCATION], "/");
+            let cookie = response.headers()[header::SET_COOKIE]
+                .to_str()
+                .expect("cookie")
+                .to_string();
+            assert!(cookie.starts_with("ipg_lan_token=secret;"));
+            assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=Strict"));
+
+            let paired = [(header::COOKIE, "theme=dark; ipg_lan_token=secret")];
+            let (status, _, _) = fetch(&router, "/app/ready", "identity", &paired).await;
+            assert_eq!(status, StatusCode::OK);
+            let scripted = [(header::HeaderName::from_static("x-ipg-lan-token"), "secret")];
+            let (status, _, _) = fetch(&router, "/app/ready", "identity", &scripted).await;
+            assert_eq!(status, StatusCode::OK);
+        });
+
+        let origin = HeaderValue::from_static("https://192.168.0.2:3443");
+        assert!(is_lan_origin(&state, true, &origin));
+        assert!(!is_lan_origin(&state, false, &origin), "unpaired");
+        assert!(!is_lan_origin(
+            &state,
+            true,
+            &HeaderValue::from_static("http://192.168.0.2:3443")
+        ));
+
+        fs::remove_dir_all(base).ok();
+    }
+}
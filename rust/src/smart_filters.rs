@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::history_store::HistoryEntry;
+use crate::text_normalize::matches_loosely;
+
+/// A named history search. Every condition that is set must match;
+/// `History.html` applies the same rules client-side, except that only the
+/// server search also matches the query across kana and romaji.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmartFilter {
+    pub name: String,
+    /// Substring of the prompt or note, ignoring case, width, kana type and
+    /// romaji spelling (see `text_normalize::matches_loosely`).
+    #[serde(default)]
+    pub query: String,
+    /// Entries must carry all of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub min_rating: u8,
+    /// Inclusive `YYYY-MM-DD` bounds on the entry timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_image: Option<bool>,
+}
+
+impl SmartFilter {
+    /// Trims fields and rejects filters that could never be applied.
+    pub fn normalized(mut self) -> Result<Self> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() {
+            return Err(anyhow!("filter name is empty"));
+        }
+        if self.min_rating > 5 {
+            return Err(anyhow!("min_rating must be between 0 and 5"));
+        }
+        self.query = self.query.trim().to_string();
+        self.tags = self
+            .tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        for date in [&mut self.from, &mut self.to] {
+            let trimmed = date.as_deref().map(str::trim).unwrap_or_default();
+            if trimmed.is_empty() {
+                *date = None;
+                continue;
+            }
+            NaiveDate::parse_from_str(trimmed, "%Y-%m-%d")
+                .map_err(|_| anyhow!("invalid date: {trimmed} (expected YYYY-MM-DD)"))?;
+            *date = Some(trimmed.to_string());
+        }
+        Ok(self)
+    }
+
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        // An empty query matches everything.
+        if !matches_loosely(&entry.prompt, &self.query)
+            && !matches_loosely(&entry.note, &self.query)
+        {
+            return false;
+        }
+        if !self.tags.iter().all(|tag| entry.tags.contains(tag)) {
+            return false;
+        }
+        if entry.rating < self.min_rating {
+            return false;
+        }
+        let day = entry.ts.get(..10).unwrap_or_default();
+        if self.from.as_deref().is_some_and(|from| day < from) {
+            return false;
+        }
+        if self.to.as_deref().is_some_and(|to| day > to) {
+            return false;
+        }
+        if let Some(has_image) = self.has_image {
+            if entry.images.is_empty() == has_image {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SmartFilterFile {
+    schema_version: u32,
+    filters: Vec<SmartFilter>,
+}
+
+/// `smart_filters.json` next to the history files, kept in save order.
+pub struct SmartFilterStore {
+    path: PathBuf,
+}
+
+impl SmartFilterStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn list(&self) -> Result<Vec<SmartFilter>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read json: {}", self.path.display()))?;
+        let file: SmartFilterFile = serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse json: {}", self.path.display()))?;
+        Ok(file.filters)
+    }
+
+    /// Replaces the filter with the same name, or appends a new one.
+    pub fn upsert(&self, filter: SmartFilter) -> Result<Vec<SmartFilter>> {
+        let filter = filter.normalized()?;
+        let mut filters = self.list()?;
+        match filters
+            .iter_mut()
+            .find(|existing| existing.name == filter.name)
+        {
+            Some(existing) => *existing = filter,
+            None => filters.push(filter),
+        }
+        self.write(&filters)?;
+        Ok(filters)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<Option<Vec<SmartFilter>>> {
+        let mut filters = self.list()?;
+        let before = filters.len();
+        filters.retain(|filter| filter.name != name.trim());
+        if filters.len() == before {
+            return Ok(None);
+        }
+        self.write(&filters)?;
+        Ok(Some(filters))
+    }
+
+    fn write(&self, filters: &[SmartFilter]) -> Result<()> {
+        let payload = serde_json::to_string_pretty(&SmartFilterFile {
+            schema_version: 1,
+            filters: filters.to_vec(),
+        })
+        .context("failed to serialize smart filters")?;
+        fs::write(&self.path, payload)
+            .with_context(|| format!("failed to write json: {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SmartFilter, SmartFilterStore};
+    use crate::history_store::HistoryEntry;
+    use std::fs;
+
+    fn entry(ts: &str, rating: u8, tags: &[&str], images: &[&str]) -> HistoryEntry {
+        HistoryEntry {
+            id: "20260101_000000_0001".to_string(),
+            ts: ts.to_string(),
+            prompt: "Client A poster".to_string(),
+            images: images.iter().map(|v| v.to_string()).collect(),
+            sample: false,
+            locked: false,
+            tags: tags.iter().map(|v| v.to_string()).collect(),
+            note: "seed 42".to_string(),
+            rating,
+            count: 1,
+            selections: Default::default(),
+            translation: String::new(),
+            score: None,
+            image_metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn matches_all_conditions_and_persists_by_name() {
+        let filter = SmartFilter {
+            name: " client A ".to_string(),
+            query: "client a".to_string(),
+            tags: vec!["project:a".to_string(), " ".to_string()],
+            min_rating: 4,
+            from: Some("2026-03-01".to_string()),
+            to: Some(String::new()),
+            has_image: Some(true),
+        }
+        .normalized()
+        .expect("valid filter");
+        assert_eq!(filter.name, "client A");
+        assert_eq!(filter.tags, vec!["project:a"]);
+        assert_eq!(filter.to, None);
+
+        let hit = entry("2026-03-01 10:00:00", 5, &["project:a"], &["a.png"]);
+        assert!(filter.matches(&hit));
+        assert!(!filter.matches(&entry("2026-02-28 23:59:59", 5, &["project:a"], &["a.png"])));
+        assert!(!filter.matches(&entry("2026-03-02 00:00:00", 3, &["project:a"], &["a.png"])));
+        assert!(!filter.matches(&entry("2026-03-02 00:00:00", 5, &[], &["a.png"])));
+        assert!(!filter.matches(&entry("2026-03-02 00:00:00", 5, &["project:a"], &[])));
+        let by_note = SmartFilter {
+            name: "seed".to_string(),
+            query: "SEED".to_string(),
+            ..SmartFilter::default()
+        };
+        assert!(by_note.matches(&hit));
+        let by_reading = SmartFilter {
+            name: "reading".to_string(),
+            query: "ｃｌｉｅｎｔ ａ".to_string(),
+            ..SmartFilter::default()
+        };
+        assert!(by_reading.matches(&hit));
+
+        assert!(SmartFilter::default().normalized().is_err());
+        let bad_date = SmartFilter {
+            name: "x".to_string(),
+            from: Some("2026/03/01".to_string()),
+            ..SmartFilter::default()
+        };
+        assert!(bad_date.normalized().is_err());
+
+        let base = std::env::temp_dir().join(format!("ipg_smart_filters_{}", std::process::id()));
+        fs::create_dir_all(&base).expect("create base");
+        let store = SmartFilterStore::new(base.join("smart_filters.json"));
+        assert!(store.list().expect("empty list").is_empty());
+        store.upsert(filter.clone()).expect("save");
+        store.upsert(by_note).expect("save second");
+        let renamed = SmartFilter {
+            min_rating: 3,
+            ..filter
+        };
+        let filters = store.upsert(renamed).expect("update");
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0].min_rating, 3);
+        assert!(store.remove("missing").expect("remove missing").is_none());
+        let filters = store.remove("seed").expect("remove").expect("removed");
+        assert_eq!(filters.len(), 1);
+        assert_eq!(store.list().expect("reload").len(), 1);
+
+        fs::remove_dir_all(base).ok();
+    }
+}
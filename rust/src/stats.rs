@@ -0,0 +1,204 @@
+use html_escape::encode_text;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::history_store::HistoryEntry;
+use crate::NO_SELECTION;
+
+/// How many keywords the dashboard lists.
+const TOP_KEYWORDS: usize = 20;
+
+/// Aggregates shown on `Stats.html`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryStats {
+    pub total: usize,
+    /// `(YYYY-MM-DD, prompts)` in date order.
+    pub per_day: Vec<(String, usize)>,
+    /// `(choice, prompts containing it)`, most used first.
+    pub keywords: Vec<(String, usize)>,
+    pub average_prompt_chars: f64,
+    pub with_images: usize,
+}
+
+impl HistoryStats {
+    pub fn image_rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.with_images as f64 / self.total as f64
+        }
+    }
+}
+
+/// Counts prompts per day and how often each config choice appears in them.
+/// A choice counts once per entry, whether it was picked or typed.
+pub fn compute_stats(entries: &[HistoryEntry], choices: &[String]) -> HistoryStats {
+    // The same word can be a choice of several items; count it once.
+    let keywords: BTreeSet<&str> = choices
+        .iter()
+        .map(|choice| choice.trim())
+        .filter(|choice| !choice.is_empty() && *choice != NO_SELECTION)
+        .collect();
+    let mut per_day: BTreeMap<String, usize> = BTreeMap::new();
+    let mut keyword_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut total_chars = 0;
+    let mut with_images = 0;
+
+    for entry in entries {
+        let day: String = entry.ts.chars().take(10).collect();
+        *per_day.entry(day).or_default() += 1;
+        total_chars += entry.prompt.chars().count();
+        if !entry.images.is_empty() {
+            with_images += 1;
+        }
+        for keyword in &keywords {
+            if entry.prompt.contains(keyword) {
+                *keyword_counts.entry(keyword).or_default() += 1;
+            }
+        }
+    }
+
+    let mut keywords: Vec<(String, usize)> = keyword_counts
+        .into_iter()
+        .map(|(choice, count)| (choice.to_string(), count))
+        .collect();
+    keywords.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    keywords.truncate(TOP_KEYWORDS);
+
+    HistoryStats {
+        total: entries.len(),
+        per_day: per_day.into_iter().collect(),
+        keywords,
+        average_prompt_chars: if entries.is_empty() {
+            0.0
+        } else {
+            total_chars as f64 / entries.len() as f64
+        },
+        with_images,
+    }
+}
+
+pub fn build_stats_html(stats: &HistoryStats) -> String {
+    let bar_rows = |rows: &[(String, usize)]| {
+        let max = rows
+            .iter()
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        rows.iter()
+            .map(|(label, count)| {
+                format!(
+                    "<tr><th>{}</th><td><span class=\"bar\" style=\"width: {:.1}%\"></span></td><td class=\"count\">{count}</td></tr>",
+                    encode_text(label),
+                    *count as f64 * 100.0 / max as f64
+                )
+            })
+            .collect::<String>()
+    };
+
+    let body = if stats.total == 0 {
+        "<p>履歴はまだありません。</p>".to_string()
+    } else {
+        let keywords = if stats.keywords.is_empty() {
+            "<p>config.txt の選択肢を含む履歴はありません。</p>".to_string()
+        } else {
+            format!("<table>{}</table>", bar_rows(&stats.keywords))
+        };
+        format!(
+            "<section class=\"summary\"><div><span>履歴</span><strong>{}件</strong></div><div><span>平均の長さ</span><strong>{:.0}文字</strong></div><div><span>画像あり</span><strong>{:.0}%</strong><small>{}件</small></div></section><h2>日別のプロンプト数</h2><table>{}</table><h2>よく使うキーワード</h2>{keywords}",
+            stats.total,
+            stats.average_prompt_chars,
+            stats.image_rate() * 100.0,
+            stats.with_images,
+            bar_rows(&stats.per_day)
+        )
+    };
+
+    format!(
+        r#"<!doctype html>
+<html lang="ja">
+<head>
+  <meta charset="utf-8" />
+  <title>履歴の統計</title>
+  <style>
+    body {{ margin: 24px; font-family: "Yu Gothic UI", sans-serif; font-size: 14px; color: #1e1e1e; background: #f7f5ec; }}
+    .summary {{ display: flex; gap: 12px; flex-wrap: wrap; }}
+    .summary div {{ border: 2px solid #1f2a44; background: #fff; padding: 8px 16px; min-width: 140px; }}
+    .summary span {{ display: block; color: #5a5a5a; font-size: 12px; }}
+    .summary strong {{ font-size: 22px; color: #174c7a; }}
+    .summary small {{ margin-left: 6px; color: #5a5a5a; }}
+    h2 {{ margin-top: 24px; font-size: 16px; }}
+    table {{ border-collapse: collapse; width: 100%; max-width: 760px; background: #fff; }}
+    th, td {{ border: 1px solid #1f2a44; padding: 4px 8px; text-align: left; }}
+    th {{ width: 180px; font-weight: 500; white-space: nowrap; }}
+    td.count {{ width: 64px; text-align: right; }}
+    .bar {{ display: block; height: 12px; background: #174c7a; }}
+  </style>
+</head>
+<body>
+  <h1>履歴の統計</h1>
+  {body}
+</body>
+</html>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_stats_html, compute_stats};
+    use crate::history_store::HistoryEntry;
+
+    fn entry(ts: &str, prompt: &str, images: &[&str]) -> HistoryEntry {
+        HistoryEntry {
+            id: "20260101_000000_0001".to_string(),
+            ts: ts.to_string(),
+            prompt: prompt.to_string(),
+            images: images.iter().map(|v| v.to_string()).collect(),
+            sample: false,
+            locked: false,
+            tags: Vec::new(),
+            note: String::new(),
+            rating: 0,
+            count: 1,
+            selections: Default::default(),
+            translation: String::new(),
+            score: None,
+            image_metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn counts_days_keywords_length_and_images() {
+        let entries = vec![
+            entry("2026-01-01 09:00:00", "猫 夕焼け", &["images/a.png"]),
+            entry("2026-01-01 10:00:00", "猫", &[]),
+            entry("2026-01-03 10:00:00", "ロボット 猫 猫", &[]),
+        ];
+        let choices = ["指定なし", "猫", "夕焼け", "ロボット", "海", "猫"]
+            .map(String::from)
+            .to_vec();
+        let stats = compute_stats(&entries, &choices);
+
+        assert_eq!(stats.total, 3);
+        assert_eq!(
+            stats.per_day,
+            vec![("2026-01-01".to_string(), 2), ("2026-01-03".to_string(), 1)]
+        );
+        assert_eq!(
+            stats.keywords,
+            vec![
+                ("猫".to_string(), 3),
+                ("ロボット".to_string(), 1),
+                ("夕焼け".to_string(), 1)
+            ]
+        );
+        assert!((stats.average_prompt_chars - 14.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stats.with_images, 1);
+
+        let html = build_stats_html(&stats);
+        assert!(html.contains("3件"));
+        assert!(html.contains("33%"));
+        assert!(build_stats_html(&compute_stats(&[], &choices)).contains("履歴はまだありません"));
+    }
+}
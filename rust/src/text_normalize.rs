@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Romaji spellings (Hepburn and kunrei-shiki, plus IME-style small kana)
+/// and the hiragana they stand for.
+#[rustfmt::skip]
+const ROMAJI: &[(&str, &str)] = &[
+    ("a", "あ"), ("i", "い"), ("u", "う"), ("e", "え"), ("o", "お"),
+    ("ka", "か"), ("ki", "き"), ("ku", "く"), ("ke", "け"), ("ko", "こ"),
+    ("ca", "か"), ("cu", "く"), ("co", "こ"),
+    ("ga", "が"), ("gi", "ぎ"), ("gu", "ぐ"), ("ge", "げ"), ("go", "ご"),
+    ("sa", "さ"), ("shi", "し"), ("si", "し"), ("su", "す"), ("se", "せ"), ("so", "そ"),
+    ("za", "ざ"), ("ji", "じ"), ("zi", "じ"), ("zu", "ず"), ("ze", "ぜ"), ("zo", "ぞ"),
+    ("ta", "た"), ("chi", "ち"), ("ti", "ち"), ("tsu", "つ"), ("tu", "つ"), ("te", "て"), ("to", "と"),
+    ("da", "だ"), ("di", "ぢ"), ("du", "づ"), ("de", "で"), ("do", "ど"),
+    ("na", "な"), ("ni", "に"), ("nu", "ぬ"), ("ne", "ね"), ("no", "の"),
+    ("ha", "は"), ("hi", "ひ"), ("fu", "ふ"), ("hu", "ふ"), ("he", "へ"), ("ho", "ほ"),
+    ("ba", "ば"), ("bi", "び"), ("bu", "ぶ"), ("be", "べ"), ("bo", "ぼ"),
+    ("pa", "ぱ"), ("pi", "ぴ"), ("pu", "ぷ"), ("pe", "ぺ"), ("po", "ぽ"),
+    ("ma", "ま"), ("mi", "み"), ("mu", "む"), ("me", "め"), ("mo", "も"),
+    ("ya", "や"), ("yu", "ゆ"), ("yo", "よ"),
+    ("ra", "ら"), ("ri", "り"), ("ru", "る"), ("re", "れ"), ("ro", "ろ"),
+    ("la", "ら"), ("li", "り"), ("lu", "る"), ("le", "れ"), ("lo", "ろ"),
+    ("wa", "わ"), ("wi", "うぃ"), ("we", "うぇ"), ("wo", "を"),
+    ("kya", "きゃ"), ("kyu", "きゅ"), ("kyo", "きょ"),
+    ("gya", "ぎゃ"), ("gyu", "ぎゅ"), ("gyo", "ぎょ"),
+    ("sha", "しゃ"), ("shu", "しゅ"), ("she", "しぇ"), ("sho", "しょ"),
+    ("sya", "しゃ"), ("syu", "しゅ"), ("syo", "しょ"),
+    ("ja", "じゃ"), ("ju", "じゅ"), ("je", "じぇ"), ("jo", "じょ"),
+    ("jya", "じゃ"), ("jyu", "じゅ"), ("jyo", "じょ"),
+    ("zya", "じゃ"), ("zyu", "じゅ"), ("zyo", "じょ"),
+    ("cha", "ちゃ"), ("chu", "ちゅ"), ("che", "ちぇ"), ("cho", "ちょ"),
+    ("tya", "ちゃ"), ("tyu", "ちゅ"), ("tyo", "ちょ"),
+    ("nya", "にゃ"), ("nyu", "にゅ"), ("nyo", "にょ"),
+    ("hya", "ひゃ"), ("hyu", "ひゅ"), ("hyo", "ひょ"),
+    ("bya", "びゃ"), ("byu", "びゅ"), ("byo", "びょ"),
+    ("pya", "ぴゃ"), ("pyu", "ぴゅ"), ("pyo", "ぴょ"),
+    ("mya", "みゃ"), ("myu", "みゅ"), ("myo", "みょ"),
+    ("rya", "りゃ"), ("ryu", "りゅ"), ("ryo", "りょ"),
+    ("fa", "ふぁ"), ("fi", "ふぃ"), ("fe", "ふぇ"), ("fo", "ふぉ"),
+    ("thi", "てぃ"), ("dhi", "でぃ"), ("twu", "とぅ"),
+    ("va", "ゔぁ"), ("vi", "ゔぃ"), ("vu", "ゔ"), ("ve", "ゔぇ"), ("vo", "ゔぉ"),
+    ("xa", "ぁ"), ("xi", "ぃ"), ("xu", "ぅ"), ("xe", "ぇ"), ("xo", "ぉ"),
+    ("xya", "ゃ"), ("xyu", "ゅ"), ("xyo", "ょ"), ("xtu", "っ"), ("ltu", "っ"),
+];
+
+fn romaji_table() -> &'static HashMap<&'static str, &'static str> {
+    static TABLE: OnceLock<HashMap<&'static str, &'static str>> = OnceLock::new();
+    TABLE.get_or_init(|| ROMAJI.iter().copied().collect())
+}
+
+/// Folds full-width ASCII to half-width, katakana to hiragana and letters to
+/// lower case, so "ＲＯＢＯＴ" / "robot" and "ロボット" / "ろぼっと" compare equal.
+pub fn fold(text: &str) -> String {
+    text.chars()
+        .map(|ch| match ch {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(ch as u32 - 0xFEE0).unwrap_or(ch),
+            '\u{3000}' => ' ',
+            '\u{30A1}'..='\u{30F6}' => char::from_u32(ch as u32 - 0x60).unwrap_or(ch),
+            _ => ch,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn is_vowel(ch: char) -> bool {
+    matches!(ch, 'a' | 'i' | 'u' | 'e' | 'o')
+}
+
+/// Converts runs of lower-case romaji in already folded text to hiragana.
+/// Letters that do not form a syllable (English words, a trailing consonant)
+/// are kept as they are.
+pub fn romaji_to_kana(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let table = romaji_table();
+    let mut output = String::with_capacity(text.len());
+    let mut index = 0;
+    while index < chars.len() {
+        let ch = chars[index];
+        let next = chars.get(index + 1).copied();
+        if !ch.is_ascii_lowercase() {
+            // A dash right after converted kana is a long vowel mark ("ra-men").
+            let after_kana = output
+                .chars()
+                .last()
+                .is_some_and(|last| ('\u{3041}'..='\u{3096}').contains(&last));
+            output.push(if ch == '-' && after_kana { 'ー' } else { ch });
+            index += 1;
+            continue;
+        }
+
+        if ch == 'n' && next.is_none_or(|next| !is_vowel(next) && next != 'y') {
+            output.push('ん');
+            let after = chars.get(index + 2).copied();
+            let doubled =
+                next == Some('n') && after.is_none_or(|after| !is_vowel(after) && after != 'y');
+            index += if doubled || next == Some('\'') { 2 } else { 1 };
+            continue;
+        }
+        // A doubled consonant ("tt", "kk", or "tch") is a small tsu.
+        if !is_vowel(ch)
+            && ch != 'n'
+            && (next == Some(ch) || (ch == 't' && next == Some('c')))
+            && chars
+                .get(index + 1..)
+                .is_some_and(|rest| starts_syllable(rest, table))
+        {
+            output.push('っ');
+            index += 1;
+            continue;
+        }
+
+        let matched = (1..=3).rev().find_map(|len| {
+            let syllable: String = chars.get(index..index + len)?.iter().collect();
+            table.get(syllable.as_str()).map(|kana| (len, *kana))
+        });
+        match matched {
+            Some((len, kana)) => {
+                output.push_str(kana);
+                index += len;
+            }
+            None => {
+                output.push(ch);
+                index += 1;
+            }
+        }
+    }
+    output
+}
+
+fn starts_syllable(chars: &[char], table: &HashMap<&str, &str>) -> bool {
+    (1..=3).any(|len| {
+        chars
+            .get(..len)
+            .is_some_and(|prefix| table.contains_key(prefix.iter().collect::<String>().as_str()))
+    })
+}
+
+/// Reading-based key: folded text with romaji spelled out in hiragana.
+pub fn search_key(text: &str) -> String {
+    romaji_to_kana(&fold(text))
+}
+
+/// Substring match that ignores case, character width, hiragana/katakana and
+/// romaji spelling, so "robotto" finds "ロボット".
+pub fn matches_loosely(haystack: &str, needle: &str) -> bool {
+    let needle = fold(needle.trim());
+    if needle.is_empty() {
+        return true;
+    }
+    let haystack = fold(haystack);
+    if haystack.contains(&needle) {
+        return true;
+    }
+    let has_ascii = |text: &str| text.chars().any(|ch| ch.is_ascii_lowercase());
+    if !has_ascii(&needle) && !has_ascii(&haystack) {
+        return false;
+    }
+    romaji_to_kana(&haystack).contains(&romaji_to_kana(&needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold, matches_loosely, search_key};
+
+    #[test]
+    fn matches_across_kana_width_and_romaji() {
+        assert_eq!(fold("ＲＯＢＯＴ ロボット"), "robot ろぼっと");
+        assert_eq!(search_key("robotto"), "ろぼっと");
+        assert_eq!(search_key("konnichiha"), "こんにちは");
+        assert_eq!(search_key("shinbun kissaten"), "しんぶん きっさてん");
+        assert_eq!(search_key("ra-men"), "らーめん");
+        assert_eq!(search_key("matcha"), "まっちゃ");
+
+        assert!(matches_loosely("[被写体]：ロボット", "robotto"));
+        assert!(matches_loosely("[被写体]：ロボット", "ろぼっと"));
+        assert!(matches_loosely("[被写体]：robotto", "ロボット"));
+        assert!(matches_loosely("Cyberpunk City", "cyber"));
+        assert!(matches_loosely("ＳＵＮＳＥＴ", "sunset"));
+        assert!(matches_loosely("anything", "  "));
+        assert!(!matches_loosely("[被写体]：猫", "robotto"));
+        assert!(!matches_loosely("ロボット", "ねこ"));
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::atomic_write::write_file_atomic;
+
+/// Directory under the data dir that mirrors `images/` with small WebP copies.
+pub const THUMBS_DIR: &str = "thumbs";
+/// Longest side of a thumbnail; two columns of cards never show images wider.
+pub const THUMB_MAX_SIDE: u32 = 320;
+/// Widths `GET /image/thumb` serves are rounded up to a multiple of this, so
+/// cards of slightly different sizes share one cached file.
+pub const SIZED_THUMB_STEP: u32 = 64;
+/// Widest thumbnail `GET /image/thumb` serves; wider requests get this size.
+pub const SIZED_THUMB_MAX_WIDTH: u32 = 2048;
+/// Longest side of the inline placeholder; the page blurs it up to card size.
+pub const PREVIEW_MAX_SIDE: u32 = 16;
+/// Wait after startup before backfilling, so the first page loads go first.
+pub const BACKFILL_START_DELAY: Duration = Duration::from_secs(10);
+/// Pause between two backfilled thumbnails so the background pass never
+/// competes with uploads or page rendering for long.
+pub const BACKFILL_PAUSE: Duration = Duration::from_millis(200);
+
+/// `thumbs/2026/01/x.webp` for `images/2026/01/x.png`; `None` for paths
+/// outside `images/`.
+pub fn thumbnail_rel_path(image_path: &str) -> Option<String> {
+    let rest = image_path.strip_prefix("images/")?;
+    let stem = Path::new(rest).with_extension("webp");
+    Some(format!(
+        "{THUMBS_DIR}/{}",
+        stem.to_string_lossy().replace('\\', "/")
+    ))
+}
+
+/// The width `GET /image/thumb` serves for a requested one.
+pub fn sized_thumb_width(requested: u32) -> u32 {
+    requested
+        .clamp(1, SIZED_THUMB_MAX_WIDTH)
+        .div_ceil(SIZED_THUMB_STEP)
+        * SIZED_THUMB_STEP
+}
+
+/// `thumbs/w640/2026/01/x.webp` for `images/2026/01/x.png` at width 640; the
+/// `w` prefix keeps these apart from the year folders of `thumbnail_rel_path`.
+pub fn sized_thumbnail_rel_path(image_path: &str, width: u32) -> Option<String> {
+    let rel = thumbnail_rel_path(image_path)?;
+    let rest = rel.strip_prefix(&format!("{THUMBS_DIR}/"))?;
+    Some(format!("{THUMBS_DIR}/w{width}/{rest}"))
+}
+
+/// Deletes every cached size of one image from `thumbs_dir`.
+pub fn remove_sized_thumbnails(thumbs_dir: &Path, image_path: &str) -> Result<()> {
+    let Some(rel) = thumbnail_rel_path(image_path) else {
+        return Ok(());
+    };
+    let Ok(dirs) = fs::read_dir(thumbs_dir) else {
+        return Ok(());
+    };
+    let rest = rel.strip_prefix(&format!("{THUMBS_DIR}/")).unwrap_or(&rel);
+    for dir in dirs.flatten() {
+        if !dir.file_name().to_string_lossy().starts_with('w') {
+            continue;
+        }
+        let target = dir.path().join(rest);
+        if target.exists() {
+            fs::remove_file(&target)
+                .with_context(|| format!("failed to delete thumbnail: {}", target.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Downscales an image to fit `THUMB_MAX_SIDE` and encodes it as lossless WebP.
+/// Images already that small are re-encoded as they are.
+pub fn encode_thumbnail(bytes: &[u8]) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes).context("failed to decode image")?;
+    let image = if image.width() > THUMB_MAX_SIDE || image.height() > THUMB_MAX_SIDE {
+        image.resize(THUMB_MAX_SIDE, THUMB_MAX_SIDE, FilterType::Triangle)
+    } else {
+        image
+    };
+    encode_webp(image)
+}
+
+/// Like `encode_thumbnail`, but `width` pixels wide whatever the height.
+/// Narrower images are never scaled up.
+pub fn encode_sized_thumbnail(bytes: &[u8], width: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes).context("failed to decode image")?;
+    let image = if image.width() > width {
+        image.resize(width, u32::MAX, FilterType::Triangle)
+    } else {
+        image
+    };
+    encode_webp(image)
+}
+
+fn encode_webp(image: DynamicImage) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    image
+        .to_rgba8()
+        .write_with_encoder(WebPEncoder::new_lossless(&mut output))
+        .map_err(|err| anyhow!("failed to encode thumbnail: {err}"))?;
+    Ok(output)
+}
+
+/// Writes the thumbnail for `bytes` to `target`, creating parent directories.
+pub fn write_thumbnail(target: &Path, bytes: &[u8]) -> Result<()> {
+    let thumbnail = encode_thumbnail(bytes)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create thumbs dir: {}", parent.display()))?;
+    }
+    fs::write(target, thumbnail)
+        .with_context(|| format!("failed to write thumbnail: {}", target.display()))
+}
+
+/// A `data:` URI with a tiny PNG of the image, small enough to inline in
+/// every card of a history page.
+pub fn encode_preview(bytes: &[u8]) -> Result<String> {
+    let image = image::load_from_memory(bytes).context("failed to decode image")?;
+    let mut output = Vec::new();
+    image
+        .resize(PREVIEW_MAX_SIDE, PREVIEW_MAX_SIDE, FilterType::Triangle)
+        .to_rgb8()
+        .write_with_encoder(PngEncoder::new(&mut output))
+        .map_err(|err| anyhow!("failed to encode preview: {err}"))?;
+    Ok(format!("data:image/png;base64,{}", STANDARD.encode(output)))
+}
+
+/// `image_previews.json`: image path → placeholder data URI, filled at upload
+/// and by the thumbnail backfill.
+pub struct PreviewIndex {
+    path: PathBuf,
+}
+
+impl PreviewIndex {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, String>> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read json: {}", self.path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse json: {}", self.path.display()))
+    }
+
+    pub fn save(&self, previews: &BTreeMap<String, String>) -> Result<()> {
+        let payload =
+            serde_json::to_string_pretty(previews).context("failed to serialize image previews")?;
+        write_file_atomic(&self.path, payload.as_bytes(), false)
+    }
+
+    pub fn insert(&self, image_path: &str, preview: String) -> Result<()> {
+        let mut previews = self.load()?;
+        previews.insert(image_path.to_string(), preview);
+        self.save(&previews)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("failed to delete json: {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, image_path: &str) -> Result<()> {
+        let mut previews = self.load()?;
+        if previews.remove(image_path).is_some() {
+            self.save(&previews)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_preview, encode_sized_thumbnail, encode_thumbnail, sized_thumb_width,
+        sized_thumbnail_rel_path, thumbnail_rel_path, THUMB_MAX_SIDE,
+    };
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn maps_paths_and_shrinks_to_webp() {
+        assert_eq!(
+            thumbnail_rel_path("images/2026/01/20260101_000000_01.png").as_deref(),
+            Some("thumbs/2026/01/20260101_000000_01.webp")
+        );
+        assert_eq!(thumbnail_rel_path("other/x.png"), None);
+
+        let mut png = Vec::new();
+        RgbImage::new(1280, 640)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .expect("encode png");
+        let thumbnail = encode_thumbnail(&png).expect("thumbnail");
+        assert_eq!(
+            image::guess_format(&thumbnail).expect("format"),
+            ImageFormat::WebP
+        );
+        let decoded = image::load_from_memory(&thumbnail).expect("decode thumbnail");
+        assert_eq!((decoded.width(), decoded.height()), (THUMB_MAX_SIDE, 160));
+        assert!(encode_thumbnail(b"png").is_err());
+
+        let preview = encode_preview(&png).expect("preview");
+        assert!(preview.starts_with("data:image/png;base64,"));
+        assert!(
+            preview.len() < 1024,
+            "preview stays tiny: {}",
+            preview.len()
+        );
+    }
+
+    #[test]
+    fn sized_thumbnails_round_widths_and_never_upscale() {
+        assert_eq!(sized_thumb_width(300), 320);
+        assert_eq!(sized_thumb_width(0), 64);
+        assert_eq!(sized_thumb_width(100_000), 2048);
+        assert_eq!(
+            sized_thumbnail_rel_path("images/2026/01/x.png", 640).as_deref(),
+            Some("thumbs/w640/2026/01/x.webp")
+        );
+
+        let mut png = Vec::new();
+        RgbImage::new(1280, 2560)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .expect("encode png");
+        let decoded = |bytes: Vec<u8>| image::load_from_memory(&bytes).expect("decode");
+        let sized = decoded(encode_sized_thumbnail(&png, 640).expect("sized"));
+        assert_eq!((sized.width(), sized.height()), (640, 1280));
+        let wide = decoded(encode_sized_thumbnail(&png, 2048).expect("wide"));
+        assert_eq!(wide.width(), 1280);
+    }
+}
@@ -0,0 +1,50 @@
+/// Tokens in one CLIP text-encoder chunk; Stable Diffusion UIs start a new
+/// chunk after this many, which weakens the words past the boundary.
+pub const CLIP_CHUNK_TOKENS: usize = 75;
+/// Two chunks; prompts beyond this are usually trimmed or ignored.
+pub const CLIP_TOKEN_LIMIT: usize = 2 * CLIP_CHUNK_TOKENS;
+
+/// Letters a common English word covers in one CLIP token; longer words are
+/// split into more.
+const LETTERS_PER_TOKEN: usize = 7;
+
+/// Estimates the CLIP tokens of `prompt` without the BPE vocabulary: a word
+/// counts one token per `LETTERS_PER_TOKEN` letters, and each digit,
+/// punctuation mark and non-ASCII character (Japanese included) one token.
+/// Close enough to warn about the 75/150 token limits.
+pub fn estimate_clip_tokens(prompt: &str) -> usize {
+    let mut tokens = 0;
+    let mut word_len: usize = 0;
+    for ch in prompt.chars() {
+        if ch.is_ascii_alphabetic() {
+            word_len += 1;
+            continue;
+        }
+        tokens += word_len.div_ceil(LETTERS_PER_TOKEN);
+        word_len = 0;
+        if !ch.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens + word_len.div_ceil(LETTERS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_clip_tokens, CLIP_CHUNK_TOKENS};
+
+    #[test]
+    fn words_digits_punctuation_and_kana_are_counted() {
+        assert_eq!(estimate_clip_tokens(""), 0);
+        assert_eq!(estimate_clip_tokens("a cat, sunset"), 4);
+        assert_eq!(estimate_clip_tokens("masterpiece"), 2);
+        assert_eq!(estimate_clip_tokens("(cat:1.3)"), 7);
+        assert_eq!(estimate_clip_tokens("[被写体]：猫"), 7);
+        assert_eq!(
+            estimate_clip_tokens(&"cat, ".repeat(40)),
+            2 * 40,
+            "a long list passes a chunk"
+        );
+        assert!(estimate_clip_tokens(&"cat, ".repeat(40)) > CLIP_CHUNK_TOKENS);
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::vision::run_with_timeout;
+
+/// Replaced with the Japanese prompt in `translate_command` arguments.
+pub const TEXT_PLACEHOLDER: &str = "{text}";
+pub const TRANSLATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the user-configured translation command on the composed prompt and
+/// returns the English text it prints.
+///
+/// The prompt replaces `{text}` in the arguments; when no argument contains
+/// the placeholder it is written to the command's stdin instead, which keeps
+/// multi-line prompts intact.
+pub fn run_translate_command(command: &[String], text: &str, timeout: Duration) -> Result<String> {
+    let (program, args) = command
+        .split_first()
+        .filter(|(program, _)| !program.trim().is_empty())
+        .ok_or_else(|| anyhow!("translate_command is not configured"))?;
+
+    let (process, input) = text_command(program, args, text);
+    run_with_timeout(process, input, "translate command", timeout)
+}
+
+/// `program` with `text` in place of `{text}` in `args`, or as the stdin to
+/// write when no argument has the placeholder.
+pub fn text_command(program: &str, args: &[String], text: &str) -> (Command, Option<Vec<u8>>) {
+    let mut process = Command::new(program);
+    let input = if args.iter().any(|arg| arg.contains(TEXT_PLACEHOLDER)) {
+        process.args(args.iter().map(|arg| arg.replace(TEXT_PLACEHOLDER, text)));
+        None
+    } else {
+        process.args(args);
+        Some(text.as_bytes().to_vec())
+    };
+    (process, input)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::run_translate_command;
+    use std::time::Duration;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn passes_prompt_by_placeholder_or_stdin() {
+        let timeout = Duration::from_secs(5);
+        let piped = run_translate_command(&argv(&["tr", "a-z", "A-Z"]), "[被写体]：cat\n", timeout)
+            .expect("stdin");
+        assert_eq!(piped, "[被写体]：CAT");
+
+        let replaced = run_translate_command(
+            &argv(&["sh", "-c", "printf 'EN: %s\\n' \"$0\"", "{text}"]),
+            "猫",
+            timeout,
+        )
+        .expect("placeholder replaced");
+        assert_eq!(replaced, "EN: 猫");
+
+        assert!(run_translate_command(&[], "猫", timeout).is_err());
+        let failed = run_translate_command(&argv(&["sh", "-c", "exit 2"]), "猫", timeout)
+            .expect_err("non-zero exit");
+        assert!(failed.to_string().contains("translate command failed"));
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Replaced with the screenshot path in `vision_command` arguments.
+pub const IMAGE_PLACEHOLDER: &str = "{image}";
+pub const VISION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs the user-configured vision/OCR command on a screenshot and returns
+/// the text it prints (recognized prompt text or an image description).
+///
+/// The image is written to a temporary file whose path replaces `{image}`;
+/// when no argument contains the placeholder the path is appended instead.
+pub fn run_vision_command(command: &[String], image: &[u8], timeout: Duration) -> Result<String> {
+    let (program, args) = command
+        .split_first()
+        .filter(|(program, _)| !program.trim().is_empty())
+        .ok_or_else(|| anyhow!("vision_command is not configured"))?;
+
+    let extension = image::guess_format(image)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("png");
+    let image_path = temp_image_path(extension);
+    fs::write(&image_path, image)
+        .with_context(|| format!("failed to write image: {}", image_path.display()))?;
+
+    let image_arg = image_path.to_string_lossy();
+    let mut command = Command::new(program);
+    if args.iter().any(|arg| arg.contains(IMAGE_PLACEHOLDER)) {
+        command.args(
+            args.iter()
+                .map(|arg| arg.replace(IMAGE_PLACEHOLDER, &image_arg)),
+        );
+    } else {
+        command.args(args).arg(&image_path);
+    }
+    let result = run_with_timeout(command, None, "vision command", timeout);
+    fs::remove_file(&image_path).ok();
+    result
+}
+
+fn temp_image_path(extension: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    std::env::temp_dir().join(format!(
+        "ipg_vision_{}_{nanos}.{extension}",
+        std::process::id()
+    ))
+}
+
+/// Runs `command`, feeding `input` to its stdin, and returns its trimmed
+/// stdout. `name` labels the command in error messages.
+pub(crate) fn run_with_timeout(
+    mut command: Command,
+    input: Option<Vec<u8>>,
+    name: &str,
+    timeout: Duration,
+) -> Result<String> {
+    command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let mut child = command.spawn().with_context(|| {
+        format!(
+            "failed to start {name}: {}",
+            command.get_program().to_string_lossy()
+        )
+    })?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        // Written on its own thread; closing stdin afterwards signals end of input.
+        thread::spawn(move || stdin.write_all(&input).ok());
+    }
+    // Drain pipes on their own threads so a chatty command cannot block on a full pipe.
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stdout.read_to_end(&mut buffer).map(|_| buffer)
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).map(|_| buffer)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("failed to wait for {name}"))?
+        {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!(
+                "{name} timed out after {} seconds",
+                timeout.as_secs_f64()
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_| anyhow!("{name} output reader panicked"))?
+        .with_context(|| format!("failed to read {name} output"))?;
+    let stderr = stderr_reader
+        .join()
+        .map(|result| result.unwrap_or_default())
+        .unwrap_or_default();
+    if !status.success() {
+        let detail = String::from_utf8_lossy(&stderr).trim().to_string();
+        return Err(anyhow!("{name} failed ({status}): {detail}"));
+    }
+
+    let text = String::from_utf8_lossy(&stdout).trim().to_string();
+    if text.is_empty() {
+        return Err(anyhow!("{name} returned no text"));
+    }
+    Ok(text)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::run_vision_command;
+    use std::time::Duration;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn runs_command_on_screenshot_and_reports_failures() {
+        let timeout = Duration::from_secs(5);
+        let text = run_vision_command(
+            &argv(&["sh", "-c", "printf '  %s\\n' \"$(cat \"$0\")\"", "{image}"]),
+            b"masterpiece, 1girl",
+            timeout,
+        )
+        .expect("placeholder replaced");
+        assert_eq!(text, "masterpiece, 1girl");
+
+        let appended = run_vision_command(&argv(&["cat"]), b"sunset", timeout)
+            .expect("path appended without placeholder");
+        assert_eq!(appended, "sunset");
+
+        assert!(run_vision_command(&[], b"x", timeout).is_err());
+        let failed =
+            run_vision_command(&argv(&["sh", "-c", "echo boom >&2; exit 3"]), b"x", timeout)
+                .expect_err("non-zero exit");
+        assert!(failed.to_string().contains("boom"));
+        assert!(run_vision_command(&argv(&["true"]), b"x", timeout).is_err());
+        let slow = run_vision_command(
+            &argv(&["sh", "-c", "sleep 5"]),
+            b"x",
+            Duration::from_millis(200),
+        )
+        .expect_err("timeout");
+        assert!(slow.to_string().contains("timed out"));
+    }
+}
@@ -1,62 +1,100 @@
-use anyhow::{anyhow, Context, Result};
-use image_prompt_generator::config_store::ConfigStore;
-use image_prompt_generator::history_store::HistoryStore;
+use anyhow::{Context, Result};
+use image_prompt_generator::instance_lock::InstanceLock;
 use image_prompt_generator::path_utils::{get_base_dir, resolve_config_path};
-use image_prompt_generator::server::{AppServer, AppState};
+use image_prompt_generator::profiles::{
+    launch_profile, prepare_profile, profile_dir, validate_profile_name, ProfileInstance,
+};
+use image_prompt_generator::server::AppServer;
 use raw_window_handle::{HasWindowHandle, RawWindowHandle};
+use std::collections::HashMap;
 use std::env;
 use std::os::windows::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::time::{Duration, Instant};
 use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows_sys::Win32::UI::Input::KeyboardAndMouse::{GetAsyncKeyState, VK_CONTROL};
+use windows_sys::Win32::UI::WindowsAndMessaging::{
+    LoadImageW, MessageBoxW, SendMessageW, ICON_BIG, ICON_SMALL, IMAGE_ICON, LR_DEFAULTSIZE,
+    LR_LOADFROMFILE, LR_SHARED, MB_ICONERROR, MB_OK, WM_SETICON,
+};
 use winit::application::ApplicationHandler;
-use winit::dpi::LogicalSize;
+use winit::dpi::{LogicalSize, PhysicalPosition};
 use winit::event::WindowEvent;
-use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy};
 use winit::platform::windows::EventLoopBuilderExtWindows;
-use winit::window::{Window, WindowId};
-use windows_sys::Win32::UI::WindowsAndMessaging::{
-    ICON_BIG, ICON_SMALL, IMAGE_ICON, LR_DEFAULTSIZE, LR_LOADFROMFILE, LR_SHARED, LoadImageW,
-    SendMessageW, WM_SETICON,
-};
+use winit::window::{Window, WindowId, WindowLevel};
 use wry::{WebView, WebViewBuilder};
 
+const APP_TITLE: &str = "Image Prompt Generator";
+const DEFAULT_WINDOW_SIZE: LogicalSize<f64> = LogicalSize::new(1120.0, 760.0);
+const MINI_WIDTH: f64 = 460.0;
+const MINI_COLLAPSED_HEIGHT: f64 = 36.0;
+const MINI_EXPANDED_HEIGHT: f64 = 180.0;
+const CLICK_THROUGH_POLL: Duration = Duration::from_millis(100);
+/// Windows stay hidden until the main UI has its first snapshot; this shows
+/// them anyway if the page never reports back.
+const SHOW_WINDOW_TIMEOUT: Duration = Duration::from_secs(3);
+
 struct Args {
     config: Option<String>,
 }
 
-pub fn run() -> Result<()> {
-    let args = parse_args();
-    let base_dir = get_base_dir();
-    let config_path = resolve_config_path(args.config, &base_dir);
+/// Messages posted by the main UI through `window.ipc.postMessage`.
+#[derive(Debug, Clone)]
+enum UserEvent {
+    MiniMode(bool),
+    MiniHover(bool),
+    DragWindow,
+    OpenProfile(String),
+    Ready,
+}
 
-    let config = ConfigStore::new(config_path.clone())
-        .with_context(|| format!("設定ファイルエラー: {}", config_path.display()))?;
-    let preferred_port = config.history_server_port();
-    let history_max_entries = config.history_max_entries();
+/// A `UserEvent` tagged with the window whose webview sent it.
+#[derive(Debug, Clone)]
+struct HostMessage {
+    window_id: WindowId,
+    event: UserEvent,
+}
 
-    let history_store = HistoryStore::new(base_dir.clone(), history_max_entries)
-        .context("履歴機能エラー: history store初期化に失敗しました")?;
+struct MiniState {
+    restore_size: LogicalSize<f64>,
+    restore_position: Option<PhysicalPosition<i32>>,
+    hovered: bool,
+    hittest: bool,
+}
 
-    let state = Arc::new(AppState::new(config, history_store));
-    let server = AppServer::start(state.clone(), preferred_port)
-        .context("履歴機能エラー: history server起動に失敗しました")?;
+/// A profile started before the event loop could create its window.
+struct PendingProfile {
+    data_dir: PathBuf,
+    title: String,
+    instance: ProfileInstance,
+}
 
-    {
-        let history_regen = state
-            .history
-            .lock()
-            .map_err(|_| anyhow!("history lock error"))?;
-        history_regen
-            .regenerate_html(server.port())
-            .context("履歴機能エラー: initial History.html生成に失敗しました")?;
-    }
+pub fn run() -> Result<()> {
+    let args = parse_args();
+    let base_dir = get_base_dir();
+    let config_path = resolve_config_path(args.config, &base_dir);
 
-    let url = format!("http://127.0.0.1:{}/", server.port());
+    // The release build has no console, so a failed start would otherwise
+    // just look like the app never opened.
+    let instance = match launch_profile(base_dir.clone(), config_path.clone()) {
+        Ok(instance) => instance,
+        Err(err) => {
+            show_error_dialog(&format!("{err:#}"));
+            return Err(err);
+        }
+    };
     let trace_enabled = is_win_dpi_trace_enabled();
     let event_loop = build_event_loop().context("failed to create event loop")?;
 
-    let mut app = DesktopApp::new(url, server, trace_enabled);
+    let proxy = event_loop.create_proxy();
+
+    let mut app = DesktopApp::new(base_dir.clone(), config_path, proxy, trace_enabled);
+    app.pending.push(PendingProfile {
+        data_dir: base_dir,
+        title: APP_TITLE.to_string(),
+        instance,
+    });
     event_loop
         .run_app(&mut app)
         .context("event loop terminated unexpectedly")?;
@@ -64,64 +102,234 @@ pub fn run() -> Result<()> {
     Ok(())
 }
 
-struct DesktopApp {
-    url: String,
-    window: Option<Window>,
-    webview: Option<WebView>,
-    server: Option<AppServer>,
+/// One window with its webview and the profile server it talks to. Fields drop
+/// in order, so the webview goes before its window, then the server, and the
+/// data dir lock is released last.
+struct ProfileWindow {
+    data_dir: PathBuf,
+    webview: WebView,
+    window: Window,
+    server: AppServer,
+    _lock: Option<InstanceLock>,
+    mini: Option<MiniState>,
+    mini_click_through: bool,
     last_logical_size: LogicalSize<f64>,
+    /// Set while the window is still hidden waiting for the UI's `ready`.
+    show_deadline: Option<Instant>,
+}
+
+impl ProfileWindow {
+    fn show(&mut self) {
+        if self.show_deadline.take().is_some() {
+            self.window.set_visible(true);
+        }
+    }
+
+    fn enter_mini_mode(&mut self) {
+        if self.mini.is_some() {
+            return;
+        }
+
+        self.mini = Some(MiniState {
+            restore_size: self.last_logical_size,
+            restore_position: self.window.outer_position().ok(),
+            hovered: false,
+            hittest: true,
+        });
+        self.window.set_decorations(false);
+        self.window.set_resizable(false);
+        self.window.set_window_level(WindowLevel::AlwaysOnTop);
+        let _ = self
+            .window
+            .request_inner_size(LogicalSize::new(MINI_WIDTH, MINI_COLLAPSED_HEIGHT));
+    }
+
+    fn exit_mini_mode(&mut self) {
+        let Some(mini) = self.mini.take() else {
+            return;
+        };
+
+        let _ = self.window.set_cursor_hittest(true);
+        self.window.set_window_level(WindowLevel::Normal);
+        self.window.set_decorations(true);
+        self.window.set_resizable(true);
+        let _ = self.window.request_inner_size(mini.restore_size);
+        if let Some(position) = mini.restore_position {
+            self.window.set_outer_position(position);
+        }
+    }
+
+    fn set_mini_hover(&mut self, hovered: bool) {
+        let Some(mini) = self.mini.as_mut() else {
+            return;
+        };
+        if mini.hovered == hovered {
+            return;
+        }
+        mini.hovered = hovered;
+        let height = if hovered {
+            MINI_EXPANDED_HEIGHT
+        } else {
+            MINI_COLLAPSED_HEIGHT
+        };
+        let _ = self
+            .window
+            .request_inner_size(LogicalSize::new(MINI_WIDTH, height));
+    }
+
+    /// With click-through enabled the strip ignores the mouse unless Ctrl is held,
+    /// so painting underneath is never interrupted.
+    fn sync_click_through(&mut self) -> bool {
+        if !self.mini_click_through {
+            return false;
+        }
+        let Some(mini) = self.mini.as_mut() else {
+            return false;
+        };
+
+        let interactive = is_control_key_down();
+        if mini.hittest != interactive {
+            let _ = self.window.set_cursor_hittest(interactive);
+            mini.hittest = interactive;
+        }
+        true
+    }
+}
+
+struct DesktopApp {
+    base_dir: PathBuf,
+    /// Main profile's config; copied into a profile folder the first time it is opened.
+    config_path: PathBuf,
+    pending: Vec<PendingProfile>,
+    windows: HashMap<WindowId, ProfileWindow>,
+    proxy: EventLoopProxy<HostMessage>,
     trace_enabled: bool,
 }
 
 impl DesktopApp {
-    fn new(url: String, server: AppServer, trace_enabled: bool) -> Self {
+    fn new(
+        base_dir: PathBuf,
+        config_path: PathBuf,
+        proxy: EventLoopProxy<HostMessage>,
+        trace_enabled: bool,
+    ) -> Self {
         Self {
-            url,
-            window: None,
-            webview: None,
-            server: Some(server),
-            last_logical_size: LogicalSize::new(1120.0, 760.0),
+            base_dir,
+            config_path,
+            pending: Vec::new(),
+            windows: HashMap::new(),
+            proxy,
             trace_enabled,
         }
     }
 
-    fn init_window(&mut self, event_loop: &ActiveEventLoop) -> Result<()> {
-        if self.window.is_some() {
-            return Ok(());
-        }
+    fn open_window(&mut self, event_loop: &ActiveEventLoop, profile: PendingProfile) -> Result<()> {
+        let PendingProfile {
+            data_dir,
+            title,
+            instance,
+        } = profile;
+        let mini_click_through = instance
+            .state
+            .config
+            .lock()
+            .map(|config| config.mini_click_through())
+            .unwrap_or(false);
 
         let attrs = Window::default_attributes()
-            .with_title("Image Prompt Generator")
-            .with_inner_size(self.last_logical_size);
+            .with_title(title)
+            .with_inner_size(DEFAULT_WINDOW_SIZE)
+            .with_visible(false);
 
         let window = event_loop
             .create_window(attrs)
             .context("failed to create main window")?;
         apply_window_icon(&window, self.trace_enabled);
 
+        let window_id = window.id();
+        let proxy = self.proxy.clone();
+        // The page sizes its layout from the monitor scale (see `setHostScale`).
+        let url = format!(
+            "http://127.0.0.1:{}/?scale={:.2}",
+            instance.server.port(),
+            window.scale_factor()
+        );
         let webview = WebViewBuilder::new()
-            .with_url(&self.url)
+            .with_url(&url)
+            .with_ipc_handler(move |request| {
+                if let Some(event) = parse_ipc_message(request.body()) {
+                    let _ = proxy.send_event(HostMessage { window_id, event });
+                }
+            })
             .build(&window)
             .context("failed to build webview")?;
 
-        self.last_logical_size = window.inner_size().to_logical(window.scale_factor());
-        self.webview = Some(webview);
-        self.window = Some(window);
+        let last_logical_size = window.inner_size().to_logical(window.scale_factor());
+        self.windows.insert(
+            window_id,
+            ProfileWindow {
+                data_dir,
+                webview,
+                window,
+                server: instance.server,
+                _lock: instance.lock,
+                mini: None,
+                mini_click_through,
+                last_logical_size,
+                show_deadline: Some(Instant::now() + SHOW_WINDOW_TIMEOUT),
+            },
+        );
         Ok(())
     }
 
-    fn shutdown_server(&mut self) {
-        if let Some(mut server) = self.server.take() {
-            server.stop();
+    /// Opens `name` from `profiles/<name>/` in a new window with its own server,
+    /// or focuses the window that already shows it.
+    fn open_profile(&mut self, event_loop: &ActiveEventLoop, name: &str) -> Result<()> {
+        let name = validate_profile_name(name)?;
+        let data_dir = profile_dir(&self.base_dir, &name);
+        if let Some(open) = self.windows.values().find(|open| open.data_dir == data_dir) {
+            open.window.focus_window();
+            return Ok(());
+        }
+
+        let config_path = prepare_profile(&self.base_dir, &name, &self.config_path)?;
+        let instance = launch_profile(data_dir.clone(), config_path)?;
+        self.open_window(
+            event_loop,
+            PendingProfile {
+                data_dir,
+                title: format!("{APP_TITLE} - {name}"),
+                instance,
+            },
+        )
+    }
+
+    fn close_window(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId) {
+        if let Some(mut closed) = self.windows.remove(&window_id) {
+            closed.server.stop();
+        }
+        if self.windows.is_empty() {
+            event_loop.exit();
+        }
+    }
+
+    fn shutdown_servers(&mut self) {
+        self.pending.clear();
+        for (_, mut open) in self.windows.drain() {
+            open.server.stop();
         }
     }
 }
 
-impl ApplicationHandler for DesktopApp {
+impl ApplicationHandler<HostMessage> for DesktopApp {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        if let Err(err) = self.init_window(event_loop) {
-            eprintln!("{err}");
-            self.shutdown_server();
+        for profile in std::mem::take(&mut self.pending) {
+            if let Err(err) = self.open_window(event_loop, profile) {
+                eprintln!("{err}");
+            }
+        }
+        if self.windows.is_empty() {
+            self.shutdown_servers();
             event_loop.exit();
         }
     }
@@ -129,53 +337,99 @@ impl ApplicationHandler for DesktopApp {
     fn window_event(
         &mut self,
         event_loop: &ActiveEventLoop,
-        _window_id: WindowId,
+        window_id: WindowId,
         event: WindowEvent,
     ) {
+        if let WindowEvent::CloseRequested = event {
+            self.close_window(event_loop, window_id);
+            return;
+        }
+        let trace_enabled = self.trace_enabled;
+        let Some(open) = self.windows.get_mut(&window_id) else {
+            return;
+        };
         match event {
-            WindowEvent::CloseRequested => {
-                self.shutdown_server();
-                event_loop.exit();
-            }
             WindowEvent::Resized(new_size) => {
-                if let Some(scale_factor) = self.window.as_ref().map(Window::scale_factor) {
-                    self.last_logical_size = new_size.to_logical(scale_factor);
-                    if self.trace_enabled {
-                        eprintln!(
-                            "[dpi-trace] event=Resized physical={}x{} logical={:.2}x{:.2} scale_factor={scale_factor:.4}",
-                            new_size.width,
-                            new_size.height,
-                            self.last_logical_size.width,
-                            self.last_logical_size.height
-                        );
-                    }
+                if open.mini.is_some() {
+                    return;
+                }
+                let scale_factor = open.window.scale_factor();
+                open.last_logical_size = new_size.to_logical(scale_factor);
+                if trace_enabled {
+                    eprintln!(
+                        "[dpi-trace] event=Resized physical={}x{} logical={:.2}x{:.2} scale_factor={scale_factor:.4}",
+                        new_size.width,
+                        new_size.height,
+                        open.last_logical_size.width,
+                        open.last_logical_size.height
+                    );
                 }
             }
             WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
-                if self.trace_enabled {
-                    if let Some(window) = self.window.as_ref() {
-                        let physical = window.inner_size();
-                        let logical = physical.to_logical::<f64>(scale_factor);
-                        eprintln!(
-                            "[dpi-trace] event=ScaleFactorChanged physical={}x{} logical={:.2}x{:.2} scale_factor={scale_factor:.4}",
-                            physical.width,
-                            physical.height,
-                            logical.width,
-                            logical.height
-                        );
-                    } else {
-                        eprintln!(
-                            "[dpi-trace] event=ScaleFactorChanged scale_factor={scale_factor:.4} (window unavailable)"
-                        );
-                    }
+                let _ = open.webview.evaluate_script(&format!(
+                    "window.setHostScale && window.setHostScale({scale_factor})"
+                ));
+                if trace_enabled {
+                    let physical = open.window.inner_size();
+                    let logical = physical.to_logical::<f64>(scale_factor);
+                    eprintln!(
+                        "[dpi-trace] event=ScaleFactorChanged physical={}x{} logical={:.2}x{:.2} scale_factor={scale_factor:.4}",
+                        physical.width,
+                        physical.height,
+                        logical.width,
+                        logical.height
+                    );
                 }
             }
             _ => {}
         }
     }
 
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, message: HostMessage) {
+        if let UserEvent::OpenProfile(name) = &message.event {
+            if let Err(err) = self.open_profile(event_loop, name) {
+                eprintln!("プロファイルを開けませんでした: {err}");
+            }
+            return;
+        }
+        let Some(open) = self.windows.get_mut(&message.window_id) else {
+            return;
+        };
+        match message.event {
+            UserEvent::MiniMode(true) => open.enter_mini_mode(),
+            UserEvent::MiniMode(false) => open.exit_mini_mode(),
+            UserEvent::MiniHover(hovered) => open.set_mini_hover(hovered),
+            UserEvent::DragWindow => {
+                let _ = open.window.drag_window();
+            }
+            UserEvent::Ready => open.show(),
+            UserEvent::OpenProfile(_) => {}
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        let mut wake_at: Option<Instant> = None;
+        for open in self.windows.values_mut() {
+            if open.show_deadline.is_some_and(|deadline| deadline <= now) {
+                open.show();
+            }
+            if let Some(deadline) = open.show_deadline {
+                wake_at = Some(wake_at.map_or(deadline, |wake_at| wake_at.min(deadline)));
+            }
+            if open.sync_click_through() {
+                let poll_at = now + CLICK_THROUGH_POLL;
+                wake_at = Some(wake_at.map_or(poll_at, |wake_at| wake_at.min(poll_at)));
+            }
+        }
+        match wake_at {
+            Some(wake_at) => event_loop.set_control_flow(ControlFlow::WaitUntil(wake_at)),
+            None => event_loop.set_control_flow(ControlFlow::Wait),
+        }
+    }
+
     fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
-        self.shutdown_server();
+        self.shutdown_servers();
     }
 }
 
@@ -194,8 +448,38 @@ fn parse_args() -> Args {
     Args { config }
 }
 
-fn build_event_loop() -> Result<EventLoop<()>> {
-    let mut builder = EventLoop::builder();
+fn parse_ipc_message(body: &str) -> Option<UserEvent> {
+    let message: serde_json::Value = serde_json::from_str(body).ok()?;
+    match message.get("type").and_then(serde_json::Value::as_str)? {
+        "mini-mode" => Some(UserEvent::MiniMode(
+            message
+                .get("enabled")
+                .and_then(serde_json::Value::as_bool)?,
+        )),
+        "mini-hover" => Some(UserEvent::MiniHover(
+            message
+                .get("hovered")
+                .and_then(serde_json::Value::as_bool)?,
+        )),
+        "drag-window" => Some(UserEvent::DragWindow),
+        "ready" => Some(UserEvent::Ready),
+        "open-profile" => Some(UserEvent::OpenProfile(
+            message
+                .get("name")
+                .and_then(serde_json::Value::as_str)?
+                .to_string(),
+        )),
+        _ => None,
+    }
+}
+
+fn is_control_key_down() -> bool {
+    // The high-order bit of GetAsyncKeyState reports whether the key is currently held.
+    unsafe { GetAsyncKeyState(i32::from(VK_CONTROL)) < 0 }
+}
+
+fn build_event_loop() -> Result<EventLoop<HostMessage>> {
+    let mut builder = EventLoop::<HostMessage>::with_user_event();
     // Use app manifest for DPI mode and avoid duplicating process-wide DPI setup here.
     builder.with_dpi_aware(false);
     builder.build().map_err(Into::into)
@@ -263,6 +547,19 @@ fn apply_window_icon(window: &Window, trace_enabled: bool) {
     }
 }
 
+fn show_error_dialog(message: &str) {
+    let text = message.encode_utf16().chain([0]).collect::<Vec<u16>>();
+    let caption = APP_TITLE.encode_utf16().chain([0]).collect::<Vec<u16>>();
+    unsafe {
+        MessageBoxW(
+            core::ptr::null_mut(),
+            text.as_ptr(),
+            caption.as_ptr(),
+            MB_OK | MB_ICONERROR,
+        );
+    }
+}
+
 fn hwnd_from_window(window: &Window) -> Option<*mut core::ffi::c_void> {
     let handle = window.window_handle().ok()?;
     match handle.as_raw() {
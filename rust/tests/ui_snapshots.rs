@@ -0,0 +1,118 @@
+//! Snapshot tests for the generated HTML: the main UI and a history page
+//! rendered from a fixed synthetic history are compared with the files in
+//! `rust/tests/snapshots/`, so refactors of the HTML-generating code cannot
+//! change the pages unnoticed.
+//!
+//! After an intended change, regenerate the snapshots with
+//! `UPDATE_UI_SNAPSHOTS=1 cargo test --test ui_snapshots` and review the diff.
+
+use image_prompt_generator::history_store::{HistoryStore, HISTORY_SCHEMA_VERSION};
+use image_prompt_generator::main_ui_html::build_main_ui_html;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const UPDATE_ENV: &str = "UPDATE_UI_SNAPSHOTS";
+/// Port baked into the history page; any fixed value keeps it stable.
+const SERVER_PORT: u16 = 8765;
+
+fn snapshot_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("rust/tests/snapshots")
+}
+
+/// Compares `actual` with the stored snapshot, or rewrites it when
+/// `UPDATE_UI_SNAPSHOTS` is set. A mismatch leaves `<name>.actual` next to the
+/// snapshot for diffing.
+fn assert_snapshot(name: &str, actual: &str) {
+    let path = snapshot_dir().join(name);
+    let actual_path = snapshot_dir().join(format!("{name}.actual"));
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        fs::create_dir_all(snapshot_dir()).expect("create snapshot dir");
+        fs::write(&path, actual).expect("write snapshot");
+        let _ = fs::remove_file(&actual_path);
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!("missing snapshot {name}; run with {UPDATE_ENV}=1 to create it")
+    });
+    if expected == actual {
+        let _ = fs::remove_file(&actual_path);
+        return;
+    }
+    fs::write(&actual_path, actual).expect("write actual snapshot");
+    let line = expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+    panic!(
+        "{name} differs from its snapshot from line {}; compare with {} or rerun with {UPDATE_ENV}=1",
+        line + 1,
+        actual_path.display()
+    );
+}
+
+/// A history covering the card variants the page renders differently:
+/// translated, tagged, rated, noted, locked and with an attached image.
+fn synthetic_history(dir: &Path) {
+    let entries = json!([
+        {
+            "id": "20250301_090000_0001",
+            "ts": "2025-03-01 09:00:00",
+            "prompt": "[被写体]：猫\n[背景]：夕焼け",
+            "translation": "cat, sunset",
+            "selections": {"subject": "猫", "background": "夕焼け"},
+            "tags": ["favorite", "cat"],
+            "rating": 5,
+            "count": 3,
+        },
+        {
+            "id": "20250302_120000_0001",
+            "ts": "2025-03-02 12:00:00",
+            "prompt": "[被写体]：ロボット <script>alert(1)</script>",
+            "note": "escape check & notes",
+            "locked": true,
+        },
+        {
+            "id": "20250303_180000_0001",
+            "ts": "2025-03-03 18:00:00",
+            "prompt": "[被写体]：水彩の街並み",
+            "images": ["images/sample.png"],
+            "score": 72,
+        },
+    ]);
+    let payload = json!({
+        "schema_version": HISTORY_SCHEMA_VERSION,
+        "entries": entries,
+    });
+    fs::write(
+        dir.join("history.json"),
+        serde_json::to_string_pretty(&payload).expect("serialize history"),
+    )
+    .expect("write history.json");
+}
+
+#[test]
+fn main_ui_matches_snapshot() {
+    assert_snapshot("main_ui.html", &build_main_ui_html());
+}
+
+#[test]
+fn history_page_matches_snapshot() {
+    let dir = std::env::temp_dir().join(format!("ipg_ui_snapshots_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create fixture dir");
+    synthetic_history(&dir);
+
+    let store = HistoryStore::new(dir.clone(), 50).expect("open store");
+    let page = store
+        .render_page("History.html", SERVER_PORT)
+        .expect("render page")
+        .expect("History.html exists");
+    let page = page
+        .replace(&dir.display().to_string(), "<DATA_DIR>")
+        .replace(&store.store_id(), "<STORE_ID>");
+    assert_snapshot("History.html", &page);
+
+    fs::remove_dir_all(dir).ok();
+}